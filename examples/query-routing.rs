@@ -62,7 +62,7 @@ async fn main() {
     let my_svc = Arc::new(srv);
 
     let udpsocket = UdpSocket::bind("[::1]:8053").await.unwrap();
-    let buf = Arc::new(VecBufSource);
+    let buf = Arc::new(VecBufSource::default());
     let srv = DgramServer::new(udpsocket, buf.clone(), my_svc.clone());
     let udp_join_handle = tokio::spawn(async move { srv.run().await });
 
@@ -73,7 +73,7 @@ async fn main() {
     v6socket.set_reuseaddr(true).unwrap();
     v6socket.bind("[::1]:8053".parse().unwrap()).unwrap();
     let v6listener = v6socket.listen(1024).unwrap();
-    let buf = Arc::new(VecBufSource);
+    let buf = Arc::new(VecBufSource::default());
     let srv = StreamServer::new(v6listener, buf.clone(), my_svc.clone());
     let tcp_join_handle = tokio::spawn(async move { srv.run().await });
 
@@ -14,6 +14,11 @@
 //! Also try AXFR, e.g.:
 //!
 //!   dig @127.0.0.1 -p 8053 AXFR example.com
+//!
+//! Pass the path of a file as the only argument to keep the DNS cookie
+//! server secret in it across restarts, e.g.:
+//!
+//!   cargo run --example serve-zone --all-features -- cookie-state
 
 use core::future::{ready, Future};
 use core::pin::Pin;
@@ -29,7 +34,7 @@ use std::time::Duration;
 use octseq::Octets;
 use rand::distributions::Alphanumeric;
 use rand::Rng;
-use tokio::net::{TcpListener, UdpSocket};
+use tokio::net::TcpListener;
 use tracing_subscriber::EnvFilter;
 
 use domain::base::iana::{Class, Rcode};
@@ -38,9 +43,11 @@ use domain::base::net::IpAddr;
 use domain::base::{Name, Rtype, Serial, ToName, Ttl};
 use domain::net::server::buf::VecBufSource;
 use domain::net::server::dgram::DgramServer;
-use domain::net::server::message::Request;
+use domain::net::server::message::{Request, RequestQuestions};
 #[cfg(feature = "siphasher")]
-use domain::net::server::middleware::cookies::CookiesMiddlewareSvc;
+use domain::net::server::middleware::cookies::{
+    CookieState, CookiesMiddlewareSvc,
+};
 use domain::net::server::middleware::edns::EdnsMiddlewareSvc;
 use domain::net::server::middleware::mandatory::MandatoryMiddlewareSvc;
 use domain::net::server::middleware::notify::{
@@ -51,6 +58,7 @@ use domain::net::server::middleware::xfr::{
     XfrData, XfrDataProvider, XfrDataProviderError, XfrMiddlewareSvc,
 };
 use domain::net::server::service::{CallResult, ServiceResult};
+use domain::net::server::sock::bind_reuse_port;
 use domain::net::server::stream::StreamServer;
 use domain::net::server::util::{mk_builder_for_target, service_fn};
 use domain::tsig::{Algorithm, Key, KeyName};
@@ -60,6 +68,24 @@ use domain::zonetree::{
 };
 use domain::zonetree::{Zone, ZoneTree};
 
+/// Creates or truncates a file readable and writable only by its owner.
+///
+/// The DNS cookie state contains the server secret, so it must not be
+/// readable by others. An existing file keeps its permissions.
+#[cfg(feature = "siphasher")]
+fn create_private_file(
+    path: impl AsRef<std::path::Path>,
+) -> std::io::Result<std::fs::File> {
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options.open(path)
+}
+
 #[tokio::main()]
 async fn main() {
     // Initialize tracing based logging. Override with env var RUST_LOG, e.g.
@@ -118,7 +144,27 @@ async fn main() {
     let svc = service_fn(my_service, zones.clone());
 
     #[cfg(feature = "siphasher")]
-    let svc = CookiesMiddlewareSvc::<Vec<u8>, _, _>::with_random_secret(svc);
+    let svc = {
+        // Keep the DNS cookie server secret across restarts if asked to so
+        // that clients holding a server cookie aren't answered with
+        // BADCOOKIE.
+        let svc =
+            CookiesMiddlewareSvc::<Vec<u8>, _, _>::with_random_secret(svc);
+        if let Some(state_path) = std::env::args_os().nth(1) {
+            match std::fs::File::open(&state_path).and_then(CookieState::load)
+            {
+                Ok(state) => svc.restore_state(state),
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+                Err(err) => eprintln!("Ignoring DNS cookie state: {err}"),
+            }
+            if let Err(err) = create_private_file(&state_path)
+                .and_then(|file| svc.state().save(file))
+            {
+                eprintln!("Failed to save DNS cookie state: {err}");
+            }
+        }
+        svc
+    };
     let svc = EdnsMiddlewareSvc::<Vec<u8>, _, _>::new(svc);
     let svc = XfrMiddlewareSvc::<Vec<u8>, _, _, _>::new(
         svc,
@@ -130,20 +176,21 @@ async fn main() {
     let svc = TsigMiddlewareSvc::new(svc, key_store);
     let svc = Arc::new(svc);
 
-    let sock = UdpSocket::bind(&addr).await.unwrap();
-    let sock = Arc::new(sock);
-    let mut udp_metrics = vec![];
+    // Give each server its own socket, and thus its own receive queue,
+    // where the platform supports it.
     let num_cores = std::thread::available_parallelism().unwrap().get();
-    for _i in 0..num_cores {
+    let socks = bind_reuse_port(addr.parse().unwrap(), num_cores).unwrap();
+    let mut udp_metrics = vec![];
+    for sock in socks {
         let udp_srv =
-            DgramServer::new(sock.clone(), VecBufSource, svc.clone());
+            DgramServer::new(sock, VecBufSource::default(), svc.clone());
         let metrics = udp_srv.metrics();
         udp_metrics.push(metrics);
         tokio::spawn(async move { udp_srv.run().await });
     }
 
     let sock = TcpListener::bind(addr).await.unwrap();
-    let tcp_srv = StreamServer::new(sock, VecBufSource, svc);
+    let tcp_srv = StreamServer::new(sock, VecBufSource::default(), svc);
     let tcp_metrics = tcp_srv.metrics();
 
     tokio::spawn(async move { tcp_srv.run().await });
@@ -244,7 +291,20 @@ fn my_service(
     request: Request<Vec<u8>>,
     zones: Arc<ZoneTree>,
 ) -> ServiceResult<Vec<u8>> {
-    let question = request.message().sole_question().unwrap();
+    // Standard queries have exactly one question, but a request may also
+    // have none or, with some non-standard protocols, several. Handle these
+    // deliberately rather than panicking on them.
+    let question = match request.questions() {
+        Ok(RequestQuestions::Sole(question)) => question,
+        Ok(RequestQuestions::None) if request.is_server_cookie_query() => {
+            // Querying for a server cookie is answered by the cookies
+            // middleware when enabled. Without it we don't support cookies
+            // and RFC 7873 section 5.4 says to answer with FORMERR.
+            return mk_formerr_response(&request);
+        }
+        Ok(RequestQuestions::None | RequestQuestions::Multiple(_))
+        | Err(_) => return mk_formerr_response(&request),
+    };
     let zone = zones
         .find_zone(question.qname(), question.qclass())
         .map(|zone| zone.read());
@@ -254,7 +314,7 @@ fn my_service(
             let qtype = question.qtype();
             zone.query(qname, qtype).unwrap()
         }
-        None => Answer::new(Rcode::NXDOMAIN),
+        None => Answer::refused(),
     };
 
     let builder = mk_builder_for_target();
@@ -262,6 +322,12 @@ fn my_service(
     Ok(CallResult::new(additional))
 }
 
+fn mk_formerr_response(request: &Request<Vec<u8>>) -> ServiceResult<Vec<u8>> {
+    let builder = mk_builder_for_target();
+    let answer = builder.start_answer(request.message(), Rcode::FORMERR)?;
+    Ok(CallResult::new(answer.additional()))
+}
+
 #[derive(Copy, Clone, Default, Debug)]
 struct DemoNotifyTarget;
 
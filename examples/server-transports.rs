@@ -666,7 +666,7 @@ async fn main() {
     //    dig +short -4 @127.0.0.1 -p 8053 A google.com
 
     let udpsocket = UdpSocket::bind("127.0.0.1:8053").await.unwrap();
-    let buf = Arc::new(VecBufSource);
+    let buf = Arc::new(VecBufSource::default());
     let srv = DgramServer::new(udpsocket, buf.clone(), name_into_ip_svc);
     let udp_join_handle = tokio::spawn(async move { srv.run().await });
 
@@ -681,7 +681,7 @@ async fn main() {
     v4socket.set_reuseaddr(true).unwrap();
     v4socket.bind("127.0.0.1:8053".parse().unwrap()).unwrap();
     let v4listener = v4socket.listen(1024).unwrap();
-    let buf = Arc::new(VecBufSource);
+    let buf = Arc::new(VecBufSource::default());
     let srv = StreamServer::new(v4listener, buf.clone(), query_svc.clone());
     let srv = srv.with_pre_connect_hook(|stream| {
         // Demonstrate one way without having access to the code that creates
@@ -81,6 +81,12 @@ impl<'a, Octs: AsRef<[u8]>> fmt::Display for DigPrinter<'a, Octs> {
                         ExtendedError(extendederror) => {
                             writeln!(f, "; EDE: {}", extendederror)?
                         }
+                        ReportChannel(rchannel) => {
+                            writeln!(f, "; REPORT-CHANNEL: {}", rchannel)?
+                        }
+                        ZoneVersion(zoneversion) => {
+                            writeln!(f, "; ZONEVERSION: {}", zoneversion)?
+                        }
                         Other(other) => {
                             writeln!(f, "; {}", other.code())?;
                         }
@@ -162,6 +162,25 @@ int_enum! {
     /// [draft-bellis-dnsop-edns-tags]: https://datatracker.ietf.org/doc/draft-bellis-dnsop-edns-tags/
     (SERVER_TAG => 17, "EDNS-Server-Tag")
 
+    /// Report-Channel (18).
+    ///
+    /// The Report-Channel option allows an authoritative server to advertise
+    /// the domain of an agent to which a resolver can report errors it
+    /// encountered while resolving names in the server's zones. The option is
+    /// defined in [RFC 9567].
+    ///
+    /// [RFC 9567]: https://tools.ietf.org/html/rfc9567
+    (REPORT_CHANNEL => 18, "Report-Channel")
+
+    /// ZONEVERSION (19).
+    ///
+    /// The ZONEVERSION option allows a client to ask an authoritative server
+    /// for the version of the zone an answer was taken from, e.g. the serial
+    /// number of its SOA record. The option is defined in [RFC 9660].
+    ///
+    /// [RFC 9660]: https://tools.ietf.org/html/rfc9660
+    (ZONEVERSION => 19, "ZONEVERSION")
+
     /// DeviceID (26946).
     ///
     /// Ths option is used by the [Cisco Umbrella network device API].
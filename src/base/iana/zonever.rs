@@ -0,0 +1,50 @@
+//! ZONEVERSION types.
+
+//------------ ZoneVersionType -----------------------------------------------
+
+int_enum! {
+    /// ZONEVERSION types.
+    ///
+    /// These values are used in the ZONEVERSION EDNS option to specify how
+    /// the version of a zone is expressed. The option and its registry are
+    /// defined in [RFC 9660].
+    ///
+    /// For the currently registered values see the [IANA registration].
+    ///
+    /// [RFC 9660]: https://tools.ietf.org/html/rfc9660
+    /// [IANA registration]: https://www.iana.org/assignments/dns-parameters/dns-parameters.xhtml#zoneversion-type-values
+    =>
+    ZoneVersionType, u8;
+
+    /// The version is the serial number of the zone's SOA record.
+    ///
+    /// The version is encoded as a 32 bit unsigned integer in network
+    /// byte order.
+    (SOA_SERIAL => 0, "SOA-SERIAL")
+}
+
+int_enum_str_with_decimal!(ZoneVersionType, u8, "unknown zone version type");
+int_enum_zonefile_fmt_with_decimal!(ZoneVersionType);
+
+//============ Tests =========================================================
+
+#[cfg(test)]
+mod test {
+    #[cfg(feature = "serde")]
+    #[test]
+    fn ser_de() {
+        use super::ZoneVersionType;
+        use serde_test::{assert_tokens, Configure, Token};
+
+        assert_tokens(
+            &ZoneVersionType::SOA_SERIAL.readable(),
+            &[Token::Str("SOA-SERIAL")],
+        );
+        assert_tokens(&ZoneVersionType(100).readable(), &[Token::U8(100)]);
+        assert_tokens(
+            &ZoneVersionType::SOA_SERIAL.compact(),
+            &[Token::U8(0)],
+        );
+        assert_tokens(&ZoneVersionType(100).compact(), &[Token::U8(100)]);
+    }
+}
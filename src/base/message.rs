@@ -1300,6 +1300,78 @@ where
         Some(record.into_any_record())
     }
 }
+
+//------------ debug_validate_message ----------------------------------------
+
+/// Checks that a built message parses back correctly.
+///
+/// This is intended for code that builds a message by rebuilding another
+/// message, e.g. to remove or add records, in order to catch bugs in the
+/// rebuilding early.
+///
+/// Every question and record in the message is parsed, all known record
+/// types including their data. The records counted in the header must make
+/// up the whole message and there must be at most one OPT record which must
+/// be in the additional section.
+///
+/// As the rebuilt message may contain data received from elsewhere, a
+/// failed check is returned as an error for the caller to report rather
+/// than treated as a bug. The checks are only made if debug assertions are
+/// enabled. Otherwise this function always returns `Ok(())`.
+#[allow(unused_variables)]
+pub fn debug_validate_message<Octs: Octets + ?Sized>(
+    msg: &Message<Octs>,
+) -> Result<(), InvalidMessage> {
+    #[cfg(debug_assertions)]
+    return validate_message(msg);
+
+    #[cfg(not(debug_assertions))]
+    Ok(())
+}
+
+/// Parses the whole message.
+///
+/// See [`debug_validate_message`].
+#[cfg(debug_assertions)]
+fn validate_message<Octs: Octets + ?Sized>(
+    msg: &Message<Octs>,
+) -> Result<(), InvalidMessage> {
+    use crate::rdata::AllRecordData;
+
+    let mut questions = msg.question();
+    for question in &mut questions {
+        question.map_err(|_| InvalidMessage::Question)?;
+    }
+
+    let mut section = questions.answer().ok();
+    let mut num_opt = 0;
+    for name in ["answer", "authority", "additional"] {
+        let Some(mut records) = section else {
+            return Err(InvalidMessage::MissingSection(name));
+        };
+        for record in &mut records {
+            let record = record.map_err(|_| InvalidMessage::Record(name))?;
+            if record.rtype() == Rtype::OPT {
+                if name != "additional" {
+                    return Err(InvalidMessage::MisplacedOpt(name));
+                }
+                num_opt += 1;
+            }
+            record
+                .into_any_record::<AllRecordData<_, ParsedName<_>>>()
+                .map_err(|_| InvalidMessage::RecordData(name))?;
+        }
+        if name == "additional" && records.parser.remaining() != 0 {
+            return Err(InvalidMessage::TrailingData);
+        }
+        section = records.next_section().ok().flatten();
+    }
+    if num_opt > 1 {
+        return Err(InvalidMessage::MultipleOpt);
+    }
+    Ok(())
+}
+
 //============ Error Types ===================================================
 
 //------------ ShortMessage --------------------------------------------------
@@ -1357,6 +1429,66 @@ impl fmt::Display for CopyRecordsError {
 #[cfg(feature = "std")]
 impl std::error::Error for CopyRecordsError {}
 
+//------------ InvalidMessage ------------------------------------------------
+
+/// A built message did not parse back correctly.
+///
+/// See [`debug_validate_message`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum InvalidMessage {
+    /// A question could not be parsed.
+    Question,
+
+    /// The named section could not be found.
+    MissingSection(&'static str),
+
+    /// A record in the named section could not be parsed.
+    Record(&'static str),
+
+    /// The data of a record in the named section could not be parsed.
+    RecordData(&'static str),
+
+    /// There is an OPT record in the named section.
+    MisplacedOpt(&'static str),
+
+    /// There is more than one OPT record.
+    MultipleOpt,
+
+    /// There is data beyond the records counted in the header.
+    TrailingData,
+}
+
+//--- Display and Error
+
+impl fmt::Display for InvalidMessage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            InvalidMessage::Question => f.write_str("invalid question"),
+            InvalidMessage::MissingSection(name) => {
+                write!(f, "no {name} section")
+            }
+            InvalidMessage::Record(name) => {
+                write!(f, "invalid {name} record")
+            }
+            InvalidMessage::RecordData(name) => {
+                write!(f, "invalid {name} record data")
+            }
+            InvalidMessage::MisplacedOpt(name) => {
+                write!(f, "OPT record in the {name} section")
+            }
+            InvalidMessage::MultipleOpt => {
+                f.write_str("more than one OPT record")
+            }
+            InvalidMessage::TrailingData => {
+                f.write_str("data beyond the counted records")
+            }
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for InvalidMessage {}
+
 //============ Testing =======================================================
 
 #[cfg(test)]
@@ -1494,4 +1626,22 @@ mod test {
             assert_eq!(0, msg.header_counts().arcount());
         }
     }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn debug_validate_valid_message() {
+        assert_eq!(debug_validate_message(&get_test_message()), Ok(()));
+    }
+
+    #[test]
+    #[cfg(all(feature = "std", debug_assertions))]
+    fn debug_validate_wrong_count() {
+        let mut msg = get_test_message().into_octets();
+        crate::base::header::HeaderCounts::for_message_slice_mut(&mut msg)
+            .set_nscount(0);
+        assert_eq!(
+            debug_validate_message(&Message::from_octets(msg).unwrap()),
+            Err(InvalidMessage::TrailingData)
+        );
+    }
 }
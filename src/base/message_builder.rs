@@ -1790,6 +1790,20 @@ impl<'a, Target: Composer + ?Sized> OptBuilder<'a, Target> {
         self.opt_header_mut().set_dnssec_ok(value)
     }
 
+    /// Returns the EDNS flags of the OPT header.
+    ///
+    /// The flags contain the DNSSEC OK (DO) bit as the most significant bit
+    /// and the currently unassigned Z bits.
+    #[must_use]
+    pub fn flags(&self) -> u16 {
+        self.opt_header().flags()
+    }
+
+    /// Sets the EDNS flags of the OPT header, including the DO bit.
+    pub fn set_flags(&mut self, flags: u16) {
+        self.opt_header_mut().set_flags(flags)
+    }
+
     /// Returns a reference to the full OPT header.
     fn opt_header(&self) -> &OptHeader {
         OptHeader::for_record_slice(&self.target.as_ref()[self.start..])
@@ -1807,6 +1821,160 @@ impl<'a, Target: Composer + ?Sized> OptBuilder<'a, Target> {
     }
 }
 
+//------------ OptOverrides --------------------------------------------------
+
+/// The OPT record fields to change when rebuilding an OPT record.
+///
+/// This is used with [`rebuild_opt`] to describe which fields of the
+/// rebuilt OPT record should differ from the source record. All fields not
+/// explicitly overridden are kept.
+#[derive(Clone, Copy, Debug)]
+pub struct OptOverrides {
+    /// The UDP payload size to use instead of that of the source.
+    udp_payload_size: Option<u16>,
+
+    /// The extended rcode to use instead of that of the source.
+    rcode: Option<OptRcode>,
+
+    /// The EDNS version to use instead of that of the source.
+    version: Option<u8>,
+
+    /// The EDNS flags to use instead of those of the source.
+    flags: Option<u16>,
+
+    /// The DO bit to use instead of that of the source.
+    dnssec_ok: Option<bool>,
+
+    /// Whether to copy the options of the source.
+    keep_options: bool,
+}
+
+impl OptOverrides {
+    /// Creates a value that overrides nothing.
+    #[must_use]
+    pub fn new() -> Self {
+        OptOverrides {
+            udp_payload_size: None,
+            rcode: None,
+            version: None,
+            flags: None,
+            dnssec_ok: None,
+            keep_options: true,
+        }
+    }
+
+    /// Overrides the UDP payload size.
+    #[must_use]
+    pub fn with_udp_payload_size(mut self, value: u16) -> Self {
+        self.udp_payload_size = Some(value);
+        self
+    }
+
+    /// Overrides the rcode.
+    ///
+    /// Because the rcode is split between the message header and the OPT
+    /// record, this also sets the rcode in the message header.
+    #[must_use]
+    pub fn with_rcode(mut self, rcode: OptRcode) -> Self {
+        self.rcode = Some(rcode);
+        self
+    }
+
+    /// Overrides the EDNS version.
+    #[must_use]
+    pub fn with_version(mut self, version: u8) -> Self {
+        self.version = Some(version);
+        self
+    }
+
+    /// Overrides all EDNS flags, including the DO bit.
+    ///
+    /// An override via [`with_dnssec_ok`][Self::with_dnssec_ok] is applied
+    /// on top of this.
+    #[must_use]
+    pub fn with_flags(mut self, flags: u16) -> Self {
+        self.flags = Some(flags);
+        self
+    }
+
+    /// Overrides the DNSSEC OK (DO) bit, keeping the other flags.
+    #[must_use]
+    pub fn with_dnssec_ok(mut self, value: bool) -> Self {
+        self.dnssec_ok = Some(value);
+        self
+    }
+
+    /// Drops the options of the source, keeping only the header fields.
+    #[must_use]
+    pub fn without_options(mut self) -> Self {
+        self.keep_options = false;
+        self
+    }
+}
+
+//--- Default
+
+impl Default for OptOverrides {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+//------------ rebuild_opt ---------------------------------------------------
+
+/// Rebuilds an OPT record from an existing one.
+///
+/// Replaces the content of `builder` with `source`, preserving all fields
+/// encoded in the TTL of the OPT record, i.e., the upper bits of the
+/// extended rcode, the EDNS version, the DO bit and the Z flags, as well as
+/// the UDP payload size and, unless dropped via `overrides`, the options.
+/// Then applies the changes given via `overrides`.
+///
+/// The lower bits of the rcode live in the message header and are left
+/// alone unless the rcode is overridden. As a result, a message that has
+/// its header copied from the source message also keeps the full extended
+/// rcode.
+///
+/// Options can be added to the rebuilt record by pushing them to `builder`
+/// afterwards.
+pub fn rebuild_opt<Octs, Target>(
+    source: &OptRecord<Octs>,
+    builder: &mut OptBuilder<'_, Target>,
+    overrides: &OptOverrides,
+) -> Result<(), Target::AppendError>
+where
+    Octs: AsRef<[u8]>,
+    Target: Composer + ?Sized,
+{
+    if overrides.keep_options {
+        builder.clone_from(source)?;
+    } else {
+        let header = *Header::for_message_slice(builder.target.as_ref());
+        let opt_header = builder.opt_header_mut();
+        opt_header.set_udp_payload_size(source.udp_payload_size());
+        opt_header.set_rcode(source.rcode(header));
+        opt_header.set_version(source.version());
+        opt_header.set_flags(source.flags());
+    }
+
+    if let Some(value) = overrides.udp_payload_size {
+        builder.set_udp_payload_size(value);
+    }
+    if let Some(rcode) = overrides.rcode {
+        builder.set_rcode(rcode);
+    }
+    if let Some(version) = overrides.version {
+        builder.set_version(version);
+    }
+    if let Some(flags) = overrides.flags {
+        builder.set_flags(flags);
+    }
+    if let Some(value) = overrides.dnssec_ok {
+        builder.set_dnssec_ok(value);
+    }
+    Ok(())
+}
+
 //------------ StreamTarget --------------------------------------------------
 
 /// A builder target for sending messages on stream transports.
@@ -1822,6 +1990,13 @@ impl<'a, Target: Composer + ?Sized> OptBuilder<'a, Target> {
 /// interface for building the actual message. Whenever data is pushed to that
 /// builder interface, the type will update the length value.
 ///
+/// The length value and the message share a single buffer, with the length
+/// value directly in front of the message. The complete frame for a stream
+/// transport is thus available as one contiguous slice via
+/// [`as_stream_slice`] and can be sent with a single write without the need
+/// for vectored I/O or copying. The message alone, for datagram transports,
+/// is available via [`as_dgram_slice`].
+///
 /// Because the length is 16 bits long, the assembled message can be at most
 /// 65536 octets long, independently of the maximum length the underlying
 /// builder allows.
@@ -1896,7 +2071,9 @@ impl<Target: AsRef<[u8]>> StreamTarget<Target> {
     /// Returns an octets slice of the message for stream transports.
     ///
     /// The slice will start with the length octets and can be send as is
-    /// through a stream transport such as TCP.
+    /// through a stream transport such as TCP. As the length octets are
+    /// stored in front of the message in the same buffer, no copying is
+    /// necessary to produce the slice.
     pub fn as_stream_slice(&self) -> &[u8] {
         self.target.as_ref()
     }
@@ -2748,6 +2925,28 @@ mod test {
         assert_eq!(rr.data(), &A::from_octets(192, 0, 2, 1));
     }
 
+    #[test]
+    fn stream_target_slices() {
+        let mut msg = MessageBuilder::from_target(StreamTarget::new_vec())
+            .unwrap()
+            .question();
+        msg.push((Name::<Vec<u8>>::root(), Rtype::A)).unwrap();
+        let target = msg.finish();
+
+        // The stream slice is the length followed by the message, the
+        // datagram slice is the message only.
+        let stream = target.as_stream_slice();
+        let dgram = target.as_dgram_slice();
+        assert_eq!(dgram.len(), 17);
+        assert_eq!(stream[..2], 17u16.to_be_bytes());
+        assert_eq!(&stream[2..], dgram);
+        assert_eq!(target.as_ref(), dgram);
+
+        // Both are views into the same buffer.
+        assert_eq!(stream[2..].as_ptr(), dgram.as_ptr());
+        assert_eq!(stream.as_ptr(), target.as_target().as_ptr());
+    }
+
     #[cfg(feature = "heapless")]
     #[test]
     fn exceed_limits() {
@@ -2808,6 +3007,81 @@ mod test {
         assert_eq!(opts.next(), Some(Ok(nsid)));
     }
 
+    #[test]
+    fn rebuild_opt_round_trip() {
+        // Build a source message with an OPT record using every field.
+        let nsid = opt::nsid::Nsid::from_octets(&b"example"[..]).unwrap();
+        let mut source = MessageBuilder::new_vec().additional();
+        source
+            .opt(|o| {
+                o.set_udp_payload_size(4096);
+                o.set_rcode(OptRcode::BADVERS);
+                o.set_version(1);
+                o.set_flags(0x8000 | 0x1234);
+                o.push(&nsid)
+            })
+            .unwrap();
+        let source = Message::from_octets(source.finish()).unwrap();
+        let source_opt = source.opt().unwrap();
+
+        // Rebuilds the OPT record into a message with the source header.
+        let rebuild = |overrides: OptOverrides| {
+            let mut msg = MessageBuilder::new_vec();
+            *msg.header_mut() = source.header();
+            let mut msg = msg.additional();
+            msg.opt(|o| rebuild_opt(&source_opt, o, &overrides))
+                .unwrap();
+            Message::from_octets(msg.finish()).unwrap()
+        };
+
+        // Without overrides, everything is preserved.
+        let msg = rebuild(OptOverrides::new());
+        let opt = msg.opt().unwrap();
+        assert_eq!(opt.udp_payload_size(), 4096);
+        assert_eq!(opt.rcode(msg.header()), OptRcode::BADVERS);
+        assert_eq!(opt.version(), 1);
+        assert_eq!(opt.flags(), 0x8000 | 0x1234);
+        assert!(opt.dnssec_ok());
+        assert_eq!(opt.opt().first(), Some(nsid));
+        assert_eq!(opt.as_record(), source_opt.as_record());
+
+        // Overrides change only the fields given.
+        let msg = rebuild(
+            OptOverrides::new()
+                .with_dnssec_ok(false)
+                .with_udp_payload_size(1232),
+        );
+        let opt = msg.opt().unwrap();
+        assert_eq!(opt.udp_payload_size(), 1232);
+        assert_eq!(opt.rcode(msg.header()), OptRcode::BADVERS);
+        assert_eq!(opt.version(), 1);
+        assert_eq!(opt.flags(), 0x1234);
+        assert_eq!(opt.opt().first(), Some(nsid));
+
+        let msg = rebuild(
+            OptOverrides::new()
+                .with_rcode(OptRcode::SERVFAIL)
+                .with_version(0)
+                .with_flags(0)
+                .without_options(),
+        );
+        let opt = msg.opt().unwrap();
+        assert_eq!(opt.udp_payload_size(), 4096);
+        assert_eq!(msg.header().rcode(), Rcode::SERVFAIL);
+        assert_eq!(opt.rcode(msg.header()), OptRcode::SERVFAIL);
+        assert_eq!(opt.version(), 0);
+        assert_eq!(opt.flags(), 0);
+        assert!(opt.opt().is_empty());
+
+        // Dropping the options keeps the header fields.
+        let msg = rebuild(OptOverrides::new().without_options());
+        let opt = msg.opt().unwrap();
+        assert_eq!(opt.rcode(msg.header()), OptRcode::BADVERS);
+        assert_eq!(opt.version(), 1);
+        assert_eq!(opt.flags(), 0x8000 | 0x1234);
+        assert!(opt.opt().is_empty());
+    }
+
     fn create_compressed<T: Composer>(target: T) -> T
     where
         T::AppendError: fmt::Debug,
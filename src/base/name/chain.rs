@@ -28,6 +28,10 @@ use octseq::builder::{
 /// A chain on an uncertain name is special in that the second name is only
 /// used if the uncertain name is relative.
 ///
+/// The length of a chain is checked when it is created. Since both names
+/// already limit their labels to 63 octets, composing a chain never
+/// produces a name longer than 255 octets or a label longer than 63 octets.
+///
 /// [`RelativeName::chain`]: super::RelativeName::chain
 #[derive(Clone, Debug)]
 #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
@@ -114,6 +118,21 @@ where
     pub fn fmt_with_dot(&self) -> impl fmt::Display + '_ {
         DisplayWithDot(self)
     }
+
+    /// Returns the number of labels in the chained name.
+    ///
+    /// For a chain resulting in an absolute name, this includes the root
+    /// label.
+    pub fn label_count(&self) -> usize {
+        self.iter_labels().count()
+    }
+
+    /// Returns the length of the chained name in its wire format.
+    ///
+    /// This is the number of octets produced when composing the chain.
+    pub fn octet_len(&self) -> usize {
+        usize::from(self.compose_len())
+    }
 }
 
 impl<L, R> Chain<L, R> {
@@ -470,6 +489,63 @@ mod test {
         assert_eq!(left.chain(six_abs).unwrap().compose_len(), 251);
     }
 
+    /// Tests that a chain at the size limit composes into a valid name.
+    #[test]
+    fn compose_at_limit() {
+        use crate::base::name::NameBuilder;
+        use std::vec::Vec;
+
+        let label = [b'a'; 63];
+        let mut builder = NameBuilder::new_vec();
+        for _ in 0..3 {
+            builder.append_label(&label).unwrap();
+        }
+        let left = builder.finish();
+        assert_eq!(left.len(), 192);
+
+        let right = Name::vec_from_str(&"b".repeat(61)).unwrap();
+        assert_eq!(right.len(), 63);
+
+        let chain = left.clone().chain(right).unwrap();
+        let mut buf = Vec::new();
+        infallible(chain.compose(&mut buf));
+        assert_eq!(buf.len(), 255);
+        let name = Name::from_octets(buf).unwrap();
+        assert!(name.iter_labels().all(|label| label.len() <= 63));
+
+        let right = Name::vec_from_str(&"b".repeat(62)).unwrap();
+        assert_eq!(left.chain(right).err(), Some(LongChainError(())));
+    }
+
+    /// Tests that label_count and octet_len match the labels of the chain.
+    #[test]
+    fn label_count_and_octet_len() {
+        use std::vec::Vec;
+
+        let w = RelativeName::from_octets(b"\x03www".as_ref()).unwrap();
+        let ecr =
+            Name::from_octets(b"\x07example\x03com\x00".as_ref()).unwrap();
+
+        let chain = w.clone().chain(ecr).unwrap();
+        assert_eq!(chain.label_count(), 4);
+        assert_eq!(chain.label_count(), chain.iter_labels().count());
+        assert_eq!(chain.octet_len(), 17);
+        assert_eq!(
+            chain.octet_len(),
+            chain
+                .iter_labels()
+                .map(|label| usize::from(label.compose_len()))
+                .sum::<usize>()
+        );
+        let mut buf = Vec::new();
+        infallible(chain.compose(&mut buf));
+        assert_eq!(chain.octet_len(), buf.len());
+
+        let rel = w.chain(RelativeName::empty_ref()).unwrap();
+        assert_eq!(rel.label_count(), 1);
+        assert_eq!(rel.octet_len(), 4);
+    }
+
     /// Checks the impl of ToLabelIter: iter_labels and compose_len.
     #[test]
     fn to_label_iter_impl() {
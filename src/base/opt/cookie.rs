@@ -442,11 +442,14 @@ impl ServerCookie {
         if parser.remaining() < 8 {
             return Err(ParseError::form_error("short server cookie"))
         }
+        if parser.remaining() > 32 {
+            return Err(ParseError::form_error("long server cookie"))
+        }
         let mut res = Array::new();
-        res.resize_raw(parser.remaining()).map_err(|_| {
+        res.append_slice(parser.peek_all()).map_err(|_| {
             ParseError::form_error("long server cookie")
         })?;
-        parser.parse_buf(res.as_slice_mut())?;
+        parser.advance_to_end();
         Ok(Self(res))
     }
 
@@ -661,6 +664,21 @@ mod test {
     #[allow(unused_imports)]
     use super::*;
 
+    #[test]
+    fn parse_lengths() {
+        fn parse(len: usize) -> Result<Cookie, ParseError> {
+            let data = [0u8; 41];
+            Cookie::parse(&mut Parser::from_ref(&data[..len]))
+        }
+
+        assert!(parse(4).is_err());
+        assert!(parse(8).unwrap().server().is_none());
+        assert!(parse(12).is_err());
+        assert_eq!(parse(16).unwrap().server().unwrap().as_ref().len(), 8);
+        assert_eq!(parse(40).unwrap().server().unwrap().as_ref().len(), 32);
+        assert!(parse(41).is_err());
+    }
+
     /// Tests from Appendix A of RFC 9018.
     #[cfg(all(feature = "siphasher", feature = "std"))]
     mod standard_server {
@@ -34,7 +34,9 @@ opt_types! {
     keytag::{KeyTag<Octs>};
     nsid::{Nsid<Octs>};
     padding::{Padding<Octs>};
+    rchannel::{ReportChannel<Name>};
     subnet::{ClientSubnet};
+    zoneversion::{ZoneVersion<Octs>};
 }
 
 //============ Module Content ================================================
@@ -508,6 +510,20 @@ impl OptHeader {
         }
     }
 
+    /// Returns the EDNS flags of the OPT header.
+    ///
+    /// The flags contain the DNSSEC OK (DO) bit as the most significant bit
+    /// and the currently unassigned Z bits.
+    #[must_use]
+    pub fn flags(&self) -> u16 {
+        u16::from_be_bytes(self.inner[7..9].try_into().unwrap())
+    }
+
+    /// Sets the EDNS flags of the OPT header, including the DO bit.
+    pub fn set_flags(&mut self, flags: u16) {
+        self.inner[7..9].copy_from_slice(&flags.to_be_bytes())
+    }
+
     pub fn compose<Target: OctetsBuilder + ?Sized>(
         self,
         target: &mut Target,
@@ -630,6 +646,14 @@ impl<Octs> OptRecord<Octs> {
         }
     }
 
+    /// Returns the EDNS flags.
+    ///
+    /// The flags contain the DNSSEC OK (DO) bit as the most significant bit
+    /// and the currently unassigned Z bits.
+    pub fn flags(&self) -> u16 {
+        self.flags
+    }
+
     /// Returns a reference to the raw options.
     pub fn opt(&self) -> &Opt<Octs> {
         &self.data
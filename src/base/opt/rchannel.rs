@@ -0,0 +1,386 @@
+//! EDNS option to signal where DNS errors should be reported.
+//!
+//! The option in this module – [`ReportChannel<Name>`] – allows an
+//! authoritative server to tell a validating resolver the domain of an agent
+//! to which it can report errors encountered while resolving names from the
+//! server's zones.
+//!
+//! The option is defined in [RFC 9567](https://tools.ietf.org/html/rfc9567).
+
+use super::super::iana::OptionCode;
+use super::super::message_builder::OptBuilder;
+use super::super::name::{Name, ToName};
+use super::super::wire::{Composer, ParseError};
+use super::{ComposeOptData, Opt, OptData, ParseOptData};
+use core::cmp::Ordering;
+use core::{fmt, hash, mem};
+use octseq::builder::{OctetsBuilder, ShortBuf};
+use octseq::octets::{Octets, OctetsFrom};
+use octseq::parse::Parser;
+
+//------------ ReportChannel ------------------------------------------------
+
+/// Option data for the Report-Channel option.
+///
+/// The Report-Channel option is included by an authoritative server in its
+/// responses to advertise the agent domain, i.e., the domain under which a
+/// validating resolver can send reports about errors encountered while
+/// resolving names in the server's zones. The agent domain is an absolute
+/// domain name that must not be the root. It is always encoded in
+/// uncompressed wire format.
+#[derive(Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[repr(transparent)]
+pub struct ReportChannel<Name: ?Sized> {
+    /// The domain of the reporting agent.
+    agent_domain: Name,
+}
+
+impl ReportChannel<()> {
+    /// The option code for this option.
+    pub(super) const CODE: OptionCode = OptionCode::REPORT_CHANNEL;
+}
+
+impl<Name: ?Sized> ReportChannel<Name> {
+    /// Creates new Report-Channel option data for the given agent domain.
+    ///
+    /// Returns an error if the agent domain is the root.
+    pub fn new(agent_domain: Name) -> Result<Self, RootAgentDomain>
+    where
+        Name: ToName + Sized,
+    {
+        RootAgentDomain::check(&agent_domain)?;
+        Ok(Self::new_unchecked(agent_domain))
+    }
+
+    /// Creates a reference to Report-Channel option data from a reference to
+    /// the agent domain.
+    ///
+    /// Returns an error if the agent domain is the root.
+    pub fn new_ref(agent_domain: &Name) -> Result<&Self, RootAgentDomain>
+    where
+        Name: ToName,
+    {
+        RootAgentDomain::check(agent_domain)?;
+        // SAFETY: ReportChannel has repr(transparent)
+        Ok(unsafe { mem::transmute::<&Name, &Self>(agent_domain) })
+    }
+
+    /// Creates new Report-Channel option data without checking the agent
+    /// domain.
+    ///
+    /// The caller must ensure that the agent domain is not the root.
+    fn new_unchecked(agent_domain: Name) -> Self
+    where
+        Name: Sized,
+    {
+        ReportChannel { agent_domain }
+    }
+
+    /// Returns a reference to the agent domain.
+    ///
+    /// The agent domain is the domain under which errors should be
+    /// reported.
+    pub fn agent_domain(&self) -> &Name {
+        &self.agent_domain
+    }
+
+    /// Converts the value into the agent domain.
+    pub fn into_agent_domain(self) -> Name
+    where
+        Name: Sized,
+    {
+        self.agent_domain
+    }
+}
+
+impl<Octs: AsRef<[u8]>> ReportChannel<Name<Octs>> {
+    /// Parses Report-Channel option data from its wire format.
+    pub fn parse<'a, Src: Octets<Range<'a> = Octs> + ?Sized>(
+        parser: &mut Parser<'a, Src>,
+    ) -> Result<Self, ParseError> {
+        Ok(Self::new(Name::parse(parser)?)?)
+    }
+}
+
+//--- OctetsFrom
+
+impl<Name, SrcName> OctetsFrom<ReportChannel<SrcName>> for ReportChannel<Name>
+where
+    Name: OctetsFrom<SrcName>,
+{
+    type Error = Name::Error;
+
+    fn try_octets_from(
+        src: ReportChannel<SrcName>,
+    ) -> Result<Self, Self::Error> {
+        Name::try_octets_from(src.agent_domain).map(Self::new_unchecked)
+    }
+}
+
+//--- PartialEq and Eq
+
+impl<Name, OtherName> PartialEq<ReportChannel<OtherName>>
+    for ReportChannel<Name>
+where
+    Name: ToName,
+    OtherName: ToName,
+{
+    fn eq(&self, other: &ReportChannel<OtherName>) -> bool {
+        self.agent_domain().name_eq(other.agent_domain())
+    }
+}
+
+impl<Name: ToName> Eq for ReportChannel<Name> {}
+
+//--- PartialOrd and Ord
+
+impl<Name, OtherName> PartialOrd<ReportChannel<OtherName>>
+    for ReportChannel<Name>
+where
+    Name: ToName,
+    OtherName: ToName,
+{
+    fn partial_cmp(
+        &self,
+        other: &ReportChannel<OtherName>,
+    ) -> Option<Ordering> {
+        Some(self.agent_domain().name_cmp(other.agent_domain()))
+    }
+}
+
+impl<Name: ToName> Ord for ReportChannel<Name> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.agent_domain().name_cmp(other.agent_domain())
+    }
+}
+
+//--- Hash
+
+impl<Name: hash::Hash> hash::Hash for ReportChannel<Name> {
+    fn hash<H: hash::Hasher>(&self, state: &mut H) {
+        self.agent_domain().hash(state)
+    }
+}
+
+//--- OptData
+
+impl<Name> OptData for ReportChannel<Name> {
+    fn code(&self) -> OptionCode {
+        OptionCode::REPORT_CHANNEL
+    }
+}
+
+impl<'a, Octs> ParseOptData<'a, Octs> for ReportChannel<Name<Octs::Range<'a>>>
+where
+    Octs: Octets,
+{
+    fn parse_option(
+        code: OptionCode,
+        parser: &mut Parser<'a, Octs>,
+    ) -> Result<Option<Self>, ParseError> {
+        if code == OptionCode::REPORT_CHANNEL {
+            Self::parse(parser).map(Some)
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+impl<Name: ToName> ComposeOptData for ReportChannel<Name> {
+    fn compose_len(&self) -> u16 {
+        self.agent_domain.compose_len()
+    }
+
+    fn compose_option<Target: OctetsBuilder + ?Sized>(
+        &self,
+        target: &mut Target,
+    ) -> Result<(), Target::AppendError> {
+        self.agent_domain.compose(target)
+    }
+}
+
+//--- Display and Debug
+
+impl<Name: fmt::Display> fmt::Display for ReportChannel<Name> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.agent_domain)
+    }
+}
+
+impl<Name: fmt::Display> fmt::Debug for ReportChannel<Name> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ReportChannel")
+            .field("agent_domain", &format_args!("{}", self.agent_domain))
+            .finish()
+    }
+}
+
+//--- Extended Opt and OptBuilder
+
+impl<Octs: Octets> Opt<Octs> {
+    /// Returns the first Report-Channel option if present.
+    ///
+    /// The Report-Channel option allows a server to advertise the domain
+    /// under which errors should be reported.
+    pub fn report_channel(
+        &self,
+    ) -> Option<ReportChannel<Name<Octs::Range<'_>>>> {
+        self.first()
+    }
+}
+
+impl<'a, Target: Composer> OptBuilder<'a, Target> {
+    /// Appends the Report-Channel option.
+    ///
+    /// The Report-Channel option allows a server to advertise the domain
+    /// under which errors should be reported.
+    ///
+    /// The method fails if the agent domain is the root or if target runs
+    /// out of space.
+    pub fn report_channel(
+        &mut self,
+        agent_domain: impl ToName,
+    ) -> Result<(), BuildReportChannelError> {
+        self.push(&ReportChannel::new(agent_domain)?)?;
+        Ok(())
+    }
+}
+
+//============ Error Types ===================================================
+
+//------------ RootAgentDomain -----------------------------------------------
+
+/// The agent domain of a Report-Channel option is the root.
+///
+/// [RFC 9567] forbids the root as the agent domain.
+///
+/// [RFC 9567]: https://tools.ietf.org/html/rfc9567
+#[derive(Clone, Copy, Debug)]
+pub struct RootAgentDomain(());
+
+impl RootAgentDomain {
+    #[must_use]
+    pub fn as_str(self) -> &'static str {
+        "root agent domain"
+    }
+
+    pub fn check(agent_domain: &(impl ToName + ?Sized)) -> Result<(), Self> {
+        // Only the root consists of a single octet in wire format.
+        if agent_domain.compose_len() == 1 {
+            Err(Self(()))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl From<RootAgentDomain> for ParseError {
+    fn from(src: RootAgentDomain) -> Self {
+        ParseError::form_error(src.as_str())
+    }
+}
+
+impl fmt::Display for RootAgentDomain {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for RootAgentDomain {}
+
+//------------ BuildReportChannelError ---------------------------------------
+
+/// An error happened while appending a Report-Channel option.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum BuildReportChannelError {
+    /// The agent domain is the root.
+    RootAgentDomain,
+
+    /// The underlying octets builder ran out of buffer space.
+    ShortBuf,
+}
+
+impl From<RootAgentDomain> for BuildReportChannelError {
+    fn from(_: RootAgentDomain) -> Self {
+        Self::RootAgentDomain
+    }
+}
+
+impl<T: Into<ShortBuf>> From<T> for BuildReportChannelError {
+    fn from(_: T) -> Self {
+        Self::ShortBuf
+    }
+}
+
+//--- Display and Error
+
+impl fmt::Display for BuildReportChannelError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::RootAgentDomain => RootAgentDomain(()).fmt(f),
+            Self::ShortBuf => ShortBuf.fmt(f),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for BuildReportChannelError {}
+
+//============ Testing ======================================================
+
+#[cfg(test)]
+#[cfg(all(feature = "std", feature = "bytes"))]
+mod test {
+    use super::super::test::test_option_compose_parse;
+    use super::*;
+    use crate::base::MessageBuilder;
+    use core::str::FromStr;
+    use std::vec::Vec;
+
+    #[test]
+    #[allow(clippy::redundant_closure)] // lifetimes ...
+    fn report_channel_compose_parse() {
+        test_option_compose_parse(
+            &ReportChannel::new(
+                Name::<Vec<u8>>::from_str("a01.agent-domain.example")
+                    .unwrap(),
+            )
+            .unwrap(),
+            |parser| ReportChannel::parse(parser),
+        );
+    }
+
+    #[test]
+    fn report_channel_wire_format() {
+        let data = ReportChannel::new(
+            Name::<Vec<u8>>::from_str("agent.example").unwrap(),
+        )
+        .unwrap();
+        let mut buf = Vec::new();
+        data.compose_option(&mut buf).unwrap();
+        assert_eq!(buf, b"\x05agent\x07example\x00");
+        assert_eq!(data.code(), OptionCode::REPORT_CHANNEL);
+        assert_eq!(u16::from(OptionCode::REPORT_CHANNEL), 18);
+    }
+
+    #[test]
+    fn root_agent_domain_is_rejected() {
+        assert!(ReportChannel::new(Name::<Vec<u8>>::root()).is_err());
+        assert!(ReportChannel::new_ref(Name::root_slice()).is_err());
+
+        let mut parser = Parser::from_ref(b"\x00".as_ref());
+        assert!(ReportChannel::parse(&mut parser).is_err());
+
+        let mut builder = MessageBuilder::new_vec().additional();
+        let res = builder.opt(|opt| {
+            assert_eq!(
+                opt.report_channel(Name::root_slice()),
+                Err(BuildReportChannelError::RootAgentDomain)
+            );
+            Ok(())
+        });
+        assert!(res.is_ok());
+    }
+}
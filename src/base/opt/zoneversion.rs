@@ -0,0 +1,423 @@
+//! EDNS option for signalling the version of a zone.
+//!
+//! The option in this module – [`ZoneVersion<Octs>`] – allows a client to
+//! ask an authoritative server for the version of the zone an answer was
+//! taken from. This is useful for debugging, e.g. to check whether all
+//! servers of a zone serve the same version of it.
+//!
+//! The option is defined in [RFC 9660](https://tools.ietf.org/html/rfc9660).
+
+use super::super::iana::{OptionCode, ZoneVersionType};
+use super::super::message_builder::OptBuilder;
+use super::super::serial::Serial;
+use super::super::wire::{Compose, Composer, Parse, ParseError};
+use super::{
+    BuildDataError, ComposeOptData, LongOptData, Opt, OptData, ParseOptData,
+};
+use core::cmp::Ordering;
+use core::{fmt, hash};
+use octseq::builder::OctetsBuilder;
+use octseq::octets::{Octets, OctetsFrom};
+use octseq::parse::Parser;
+
+//------------ ZoneVersion ---------------------------------------------------
+
+/// Option data for the ZONEVERSION option.
+///
+/// A client includes an empty ZONEVERSION option in its query to ask for the
+/// version of the zone the answer is taken from. An authoritative server
+/// supporting the option includes it in its response with the number of
+/// labels of the zone's apex name, which identifies the zone, the type of
+/// the version and the version itself. For the only type currently defined,
+/// [`ZoneVersionType::SOA_SERIAL`], the version is the serial number of the
+/// zone's SOA record.
+///
+/// The option and details about its use are defined in
+/// [RFC 9660](https://tools.ietf.org/html/rfc9660).
+#[derive(Clone, Copy)]
+pub struct ZoneVersion<Octs> {
+    /// The version, if present.
+    version: Option<Version<Octs>>,
+}
+
+/// The content of a non-empty ZONEVERSION option.
+#[derive(Clone, Copy)]
+struct Version<Octs> {
+    /// The number of labels of the zone's apex name, excluding the root.
+    label_count: u8,
+
+    /// The type of the version.
+    version_type: ZoneVersionType,
+
+    /// The version itself.
+    version: Octs,
+}
+
+impl ZoneVersion<()> {
+    /// The option code for this option.
+    pub(super) const CODE: OptionCode = OptionCode::ZONEVERSION;
+}
+
+impl<Octs> ZoneVersion<Octs> {
+    /// Creates an empty ZONEVERSION option as sent in queries.
+    #[must_use]
+    pub fn empty() -> Self {
+        ZoneVersion { version: None }
+    }
+
+    /// Creates a ZONEVERSION option with the given version.
+    ///
+    /// The `label_count` is the number of labels of the zone's apex name,
+    /// not counting the root label. The function returns an error if
+    /// `version` is longer than 65,533 octets.
+    pub fn from_octets(
+        label_count: u8,
+        version_type: ZoneVersionType,
+        version: Octs,
+    ) -> Result<Self, LongOptData>
+    where
+        Octs: AsRef<[u8]>,
+    {
+        LongOptData::check_len(version.as_ref().len() + 2)?;
+        Ok(ZoneVersion {
+            version: Some(Version {
+                label_count,
+                version_type,
+                version,
+            }),
+        })
+    }
+
+    /// Parses a value from its wire format.
+    pub fn parse<'a, Src: Octets<Range<'a> = Octs> + ?Sized>(
+        parser: &mut Parser<'a, Src>,
+    ) -> Result<Self, ParseError> {
+        if parser.remaining() == 0 {
+            return Ok(Self::empty());
+        }
+        let label_count = u8::parse(parser)?;
+        let version_type = ZoneVersionType::parse(parser)?;
+        let len = parser.remaining();
+        Ok(ZoneVersion {
+            version: Some(Version {
+                label_count,
+                version_type,
+                version: parser.parse_octets(len)?,
+            }),
+        })
+    }
+
+    /// Returns whether the option is empty, i.e., a request for the version.
+    pub fn is_empty(&self) -> bool {
+        self.version.is_none()
+    }
+
+    /// Returns the number of labels of the zone's apex name, if present.
+    ///
+    /// The root label is not counted.
+    pub fn label_count(&self) -> Option<u8> {
+        self.version.as_ref().map(|v| v.label_count)
+    }
+
+    /// Returns the type of the version, if present.
+    pub fn version_type(&self) -> Option<ZoneVersionType> {
+        self.version.as_ref().map(|v| v.version_type)
+    }
+
+    /// Returns the octets of the version, if present.
+    pub fn version(&self) -> Option<&Octs> {
+        self.version.as_ref().map(|v| &v.version)
+    }
+
+    /// Returns the SOA serial if the option contains one.
+    pub fn serial(&self) -> Option<Serial>
+    where
+        Octs: AsRef<[u8]>,
+    {
+        let version = self.version.as_ref()?;
+        if version.version_type != ZoneVersionType::SOA_SERIAL {
+            return None;
+        }
+        let serial: [u8; 4] = version.version.as_ref().try_into().ok()?;
+        Some(Serial(u32::from_be_bytes(serial)))
+    }
+
+    /// Returns the version as an octets slice, if present.
+    fn as_slice(&self) -> Option<(u8, ZoneVersionType, &[u8])>
+    where
+        Octs: AsRef<[u8]>,
+    {
+        self.version
+            .as_ref()
+            .map(|v| (v.label_count, v.version_type, v.version.as_ref()))
+    }
+}
+
+impl ZoneVersion<[u8; 4]> {
+    /// Creates a ZONEVERSION option with the serial of a zone's SOA record.
+    ///
+    /// The `label_count` is the number of labels of the zone's apex name,
+    /// not counting the root label.
+    #[must_use]
+    pub fn soa_serial(label_count: u8, serial: Serial) -> Self {
+        ZoneVersion {
+            version: Some(Version {
+                label_count,
+                version_type: ZoneVersionType::SOA_SERIAL,
+                version: serial.into_int().to_be_bytes(),
+            }),
+        }
+    }
+}
+
+//--- OctetsFrom
+
+impl<Octs, SrcOcts> OctetsFrom<ZoneVersion<SrcOcts>> for ZoneVersion<Octs>
+where
+    Octs: OctetsFrom<SrcOcts>,
+{
+    type Error = Octs::Error;
+
+    fn try_octets_from(
+        src: ZoneVersion<SrcOcts>,
+    ) -> Result<Self, Self::Error> {
+        Ok(ZoneVersion {
+            version: match src.version {
+                Some(v) => Some(Version {
+                    label_count: v.label_count,
+                    version_type: v.version_type,
+                    version: Octs::try_octets_from(v.version)?,
+                }),
+                None => None,
+            },
+        })
+    }
+}
+
+//--- PartialEq and Eq
+
+impl<Octs, Other> PartialEq<ZoneVersion<Other>> for ZoneVersion<Octs>
+where
+    Octs: AsRef<[u8]>,
+    Other: AsRef<[u8]>,
+{
+    fn eq(&self, other: &ZoneVersion<Other>) -> bool {
+        self.as_slice() == other.as_slice()
+    }
+}
+
+impl<Octs: AsRef<[u8]>> Eq for ZoneVersion<Octs> {}
+
+//--- PartialOrd and Ord
+
+impl<Octs, Other> PartialOrd<ZoneVersion<Other>> for ZoneVersion<Octs>
+where
+    Octs: AsRef<[u8]>,
+    Other: AsRef<[u8]>,
+{
+    fn partial_cmp(&self, other: &ZoneVersion<Other>) -> Option<Ordering> {
+        self.as_slice().partial_cmp(&other.as_slice())
+    }
+}
+
+impl<Octs: AsRef<[u8]>> Ord for ZoneVersion<Octs> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.as_slice().cmp(&other.as_slice())
+    }
+}
+
+//--- Hash
+
+impl<Octs: AsRef<[u8]>> hash::Hash for ZoneVersion<Octs> {
+    fn hash<H: hash::Hasher>(&self, state: &mut H) {
+        self.as_slice().hash(state)
+    }
+}
+
+//--- OptData
+
+impl<Octs> OptData for ZoneVersion<Octs> {
+    fn code(&self) -> OptionCode {
+        OptionCode::ZONEVERSION
+    }
+}
+
+impl<'a, Octs: Octets> ParseOptData<'a, Octs>
+    for ZoneVersion<Octs::Range<'a>>
+{
+    fn parse_option(
+        code: OptionCode,
+        parser: &mut Parser<'a, Octs>,
+    ) -> Result<Option<Self>, ParseError> {
+        if code == OptionCode::ZONEVERSION {
+            Self::parse(parser).map(Some)
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+impl<Octs: AsRef<[u8]>> ComposeOptData for ZoneVersion<Octs> {
+    fn compose_len(&self) -> u16 {
+        match self.as_slice() {
+            Some((_, _, version)) => {
+                u16::try_from(version.len() + 2).expect("long option data")
+            }
+            None => 0,
+        }
+    }
+
+    fn compose_option<Target: OctetsBuilder + ?Sized>(
+        &self,
+        target: &mut Target,
+    ) -> Result<(), Target::AppendError> {
+        if let Some((label_count, version_type, version)) = self.as_slice() {
+            label_count.compose(target)?;
+            version_type.compose(target)?;
+            target.append_slice(version)?;
+        }
+        Ok(())
+    }
+}
+
+//--- Display and Debug
+
+impl<Octs: AsRef<[u8]>> fmt::Display for ZoneVersion<Octs> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let Some((label_count, version_type, version)) = self.as_slice()
+        else {
+            return Ok(());
+        };
+        write!(f, "{} {} ", label_count, version_type)?;
+        match self.serial() {
+            Some(serial) => write!(f, "{}", serial),
+            None => {
+                for v in version {
+                    write!(f, "{:02X}", *v)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl<Octs: AsRef<[u8]>> fmt::Debug for ZoneVersion<Octs> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "ZoneVersion({})", self)
+    }
+}
+
+//--- Serialize
+
+#[cfg(feature = "serde")]
+impl<Octs> serde::Serialize for ZoneVersion<Octs>
+where
+    Octs: AsRef<[u8]> + octseq::serde::SerializeOctets,
+{
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        use serde::ser::SerializeStruct;
+
+        struct Version<'a, Octs>(&'a Octs);
+
+        impl<Octs> serde::Serialize for Version<'_, Octs>
+        where
+            Octs: octseq::serde::SerializeOctets,
+        {
+            fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+            where
+                S: serde::Serializer,
+            {
+                self.0.serialize_octets(serializer)
+            }
+        }
+
+        let mut s = serializer.serialize_struct("ZoneVersion", 3)?;
+        s.serialize_field("label_count", &self.label_count())?;
+        s.serialize_field("version_type", &self.version_type())?;
+        s.serialize_field("version", &self.version().map(Version))?;
+        s.end()
+    }
+}
+
+//--- Extended Opt and OptBuilder
+
+impl<Octs: Octets> Opt<Octs> {
+    /// Returns the first ZONEVERSION option if present.
+    ///
+    /// In a query, the option is empty and asks the server to include the
+    /// version of the zone in its response. In a response, it contains that
+    /// version.
+    pub fn zone_version(&self) -> Option<ZoneVersion<Octs::Range<'_>>> {
+        self.first()
+    }
+}
+
+impl<'a, Target: Composer> OptBuilder<'a, Target> {
+    /// Appends a ZONEVERSION option with the given version.
+    ///
+    /// The `label_count` is the number of labels of the zone's apex name,
+    /// not counting the root label.
+    pub fn zone_version(
+        &mut self,
+        label_count: u8,
+        version_type: ZoneVersionType,
+        version: &(impl AsRef<[u8]> + ?Sized),
+    ) -> Result<(), BuildDataError> {
+        Ok(self.push(&ZoneVersion::from_octets(
+            label_count,
+            version_type,
+            version.as_ref(),
+        )?)?)
+    }
+
+    /// Appends an empty ZONEVERSION option.
+    ///
+    /// If included by a client, the option asks the server to include the
+    /// version of the zone the answer is taken from in its response.
+    pub fn client_zone_version(&mut self) -> Result<(), Target::AppendError> {
+        self.push(&ZoneVersion::<[u8; 0]>::empty())
+    }
+}
+
+//============ Testing ======================================================
+
+#[cfg(test)]
+#[cfg(all(feature = "std", feature = "bytes"))]
+mod test {
+    use super::super::test::test_option_compose_parse;
+    use super::*;
+
+    #[test]
+    #[allow(clippy::redundant_closure)] // lifetimes ...
+    fn zone_version_compose_parse() {
+        test_option_compose_parse(
+            &ZoneVersion::<[u8; 0]>::empty(),
+            |parser| ZoneVersion::parse(parser),
+        );
+        test_option_compose_parse(
+            &ZoneVersion::soa_serial(2, Serial(2024010101)),
+            |parser| ZoneVersion::parse(parser),
+        );
+        test_option_compose_parse(
+            &ZoneVersion::from_octets(
+                1,
+                ZoneVersionType::from_int(200),
+                b"abc",
+            )
+            .unwrap(),
+            |parser| ZoneVersion::parse(parser),
+        );
+    }
+
+    #[test]
+    fn soa_serial_encoding() {
+        let opt = ZoneVersion::soa_serial(2, Serial(0x0102_0304));
+        let mut buf = std::vec::Vec::new();
+        opt.compose_option(&mut buf).unwrap();
+        assert_eq!(buf, [2, 0, 1, 2, 3, 4]);
+        assert_eq!(opt.serial(), Some(Serial(0x0102_0304)));
+    }
+}
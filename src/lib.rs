@@ -66,6 +66,10 @@
 //!
 //! * `bytes`: Enables using the types `Bytes` and `BytesMut` from the
 //!   [bytes](https://github.com/tokio-rs/bytes) crate as octet sequences.
+//! * `chaos`: fault injection for testing the resilience of clients via the
+//!   `net::server::middleware::chaos` module. This must never be enabled in
+//!   production builds. This feature enables the unstable
+//!   `unstable-server-transport` feature.
 //! * `chrono`: Adds the [chrono](https://github.com/chronotope/chrono)
 //!   crate as a dependency. This adds support for generating serial numbers
 //!   from time stamps.
@@ -101,6 +105,14 @@
 //!   sequences.
 //! * `std`: support for the Rust std library. This feature is enabled by
 //!   default.
+//! * `test-util`: helpers for testing code that produces DNS messages in
+#![cfg_attr(feature = "test-util", doc = "  the [test_util]")]
+#![cfg_attr(not(feature = "test-util"), doc = "  the test_util")]
+//!   module. This feature enables the `std` feature.
+//! * `tls`: serving DNS over TLS via the
+//!   `net::server::sock::RustlsTcpListener` using the
+//!   [tokio-rustls](https://github.com/rustls/tokio-rustls) crate. This
+//!   feature enables the unstable `unstable-server-transport` feature.
 //! * `tsig`: support for signing and validating message exchanges via TSIG
 //!   signatures. This enables the
 #![cfg_attr(feature = "tsig", doc = "  [tsig]")]
@@ -169,6 +181,7 @@ pub mod rdata;
 pub mod resolv;
 pub mod sign;
 pub mod stelline;
+pub mod test_util;
 pub mod tsig;
 pub mod utils;
 pub mod validate;
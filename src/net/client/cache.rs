@@ -27,6 +27,7 @@
 //! or not.
 
 use crate::base::iana::{Class, Opcode, OptRcode, Rtype};
+use crate::base::message::debug_validate_message;
 use crate::base::name::ToName;
 use crate::base::{
     Header, Message, MessageBuilder, Name, ParsedName, StaticCompressor, Ttl,
@@ -49,6 +50,7 @@ use std::sync::Arc;
 use std::time::Duration;
 use std::vec::Vec;
 use tokio::time::Instant;
+use tracing::warn;
 
 /// Configuration limit for the maximum number of entries in the cache.
 const MAX_CACHE_ENTRIES: DefMinMax<u64> =
@@ -1073,6 +1075,9 @@ where
             .expect(
                 "Message should be able to parse output from MessageBuilder",
             );
+    if let Err(err) = debug_validate_message(&msg) {
+        warn!("Rebuilt message is invalid: {err}");
+    }
     Ok(msg)
 }
 
@@ -1138,12 +1143,15 @@ fn remove_dnssec(
     }
 
     let result = target.as_builder().clone();
-    Ok(
+    let msg =
         Message::<Bytes>::from_octets(result.finish().into_target().into())
             .expect(
                 "Message should be able to parse output from MessageBuilder",
-            ),
-    )
+            );
+    if let Err(err) = debug_validate_message(&msg) {
+        warn!("Rebuilt message is invalid: {err}");
+    }
+    Ok(msg)
 }
 
 /// Check if a type is a DNSSEC type that needs to be removed.
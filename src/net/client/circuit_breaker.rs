@@ -0,0 +1,542 @@
+//! A circuit breaker provided as a pass through transport.
+//!
+//! This module provides a transport that keeps track of failures of an
+//! upstream transport. Once the upstream has failed a configurable number of
+//! times in a row it is considered to be failing and the circuit is _opened_:
+//! requests fail immediately with [Error::CircuitOpen] instead of being sent
+//! to the upstream and having to wait for it to time out.
+//!
+//! After a cooldown period the circuit becomes _half-open_ and a single
+//! request is let through to probe the upstream. If the probe succeeds the
+//! circuit is _closed_ again and requests flow normally. If the probe fails
+//! the circuit is opened again for another cooldown period.
+//!
+//! Only errors returned by the upstream transport count as failures. A
+//! response is a success regardless of its RCODE.
+//!
+//! The current state of the circuit can be inspected using
+//! [Connection::state]. Clones of a [Connection] share the same circuit.
+//!
+//! This transport is intended to be combined with other transports, e.g.
+//! by wrapping each upstream of a [redundant][super::redundant] transport in
+//! a circuit breaker.
+
+#![warn(missing_docs)]
+#![warn(clippy::missing_docs_in_private_items)]
+
+use std::boxed::Box;
+use std::fmt::{Debug, Formatter};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use bytes::Bytes;
+use tokio::time::Instant;
+use tracing::{debug, trace};
+
+use crate::base::Message;
+use crate::net::client::request::{
+    ComposeRequest, Error, GetResponse, SendRequest,
+};
+use crate::utils::config::DefMinMax;
+
+/// Limit on the number of consecutive failures that opens the circuit.
+///
+/// The value has to be between one and 1,000 with a default of five. These
+/// values are guesses at something reasonable.
+const FAILURE_THRESHOLD: DefMinMax<u32> = DefMinMax::new(5, 1, 1_000);
+
+/// Limit on the amount of time the circuit stays open before probing.
+///
+/// The value has to be between one second and one hour with a default of 30
+/// seconds. These values are guesses at something reasonable.
+const COOLDOWN: DefMinMax<Duration> = DefMinMax::new(
+    Duration::from_secs(30),
+    Duration::from_secs(1),
+    Duration::from_secs(60 * 60),
+);
+
+//------------ Config ---------------------------------------------------------
+
+/// Configuration of a circuit breaker.
+#[derive(Clone, Debug)]
+pub struct Config {
+    /// The number of consecutive failures that opens the circuit.
+    failure_threshold: u32,
+
+    /// The amount of time the circuit stays open before probing.
+    cooldown: Duration,
+}
+
+impl Config {
+    /// Creates a new config with default values.
+    ///
+    /// The default values are documented at the relevant set_* methods.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Set the number of consecutive failures that opens the circuit.
+    ///
+    /// The value has to be at least one, at most 1,000 and the default is
+    /// five.
+    pub fn set_failure_threshold(&mut self, value: u32) {
+        self.failure_threshold = FAILURE_THRESHOLD.limit(value)
+    }
+
+    /// Set the amount of time the circuit stays open before a probe request
+    /// is let through.
+    ///
+    /// The value has to be at least one second, at most 3,600 seconds (one
+    /// hour) and the default is 30 seconds.
+    pub fn set_cooldown(&mut self, value: Duration) {
+        self.cooldown = COOLDOWN.limit(value)
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            failure_threshold: FAILURE_THRESHOLD.default(),
+            cooldown: COOLDOWN.default(),
+        }
+    }
+}
+
+//------------ State ----------------------------------------------------------
+
+/// The state of a circuit breaker.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum State {
+    /// Requests are sent to the upstream.
+    Closed,
+
+    /// Requests fail immediately without being sent to the upstream.
+    Open,
+
+    /// A single probe request is sent to the upstream, other requests fail
+    /// immediately.
+    HalfOpen,
+}
+
+//------------ Connection -----------------------------------------------------
+
+/// A connection that stops sending requests to a failing upstream.
+///
+/// See the [module documentation][self] for more information.
+#[derive(Clone)]
+pub struct Connection<Upstream> {
+    /// Upstream transport to use for requests.
+    upstream: Upstream,
+
+    /// The circuit shared by all clones of this connection.
+    circuit: Arc<Mutex<Circuit>>,
+
+    /// The configuration of this connection.
+    config: Config,
+}
+
+impl<Upstream> Connection<Upstream> {
+    /// Create a new connection with default configuration parameters.
+    ///
+    /// Note that Upstream needs to implement [SendRequest]
+    /// (and Send/Sync) to be useful.
+    pub fn new(upstream: Upstream) -> Self {
+        Self::with_config(upstream, Default::default())
+    }
+
+    /// Create a new connection with specified configuration parameters.
+    ///
+    /// Note that Upstream needs to implement [SendRequest]
+    /// (and Send/Sync) to be useful.
+    pub fn with_config(upstream: Upstream, config: Config) -> Self {
+        Self {
+            upstream,
+            circuit: Default::default(),
+            config,
+        }
+    }
+
+    /// Returns the current state of the circuit.
+    pub fn state(&self) -> State {
+        self.circuit.lock().unwrap().state
+    }
+}
+
+impl<Upstream> Debug for Connection<Upstream> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), core::fmt::Error> {
+        f.debug_struct("Connection")
+            .field("circuit", &self.circuit)
+            .field("config", &self.config)
+            .finish()
+    }
+}
+
+//------------ SendRequest ----------------------------------------------------
+
+impl<CR, Upstream> SendRequest<CR> for Connection<Upstream>
+where
+    CR: ComposeRequest + 'static,
+    Upstream: SendRequest<CR> + Send + Sync,
+{
+    fn send_request(
+        &self,
+        request_msg: CR,
+    ) -> Box<dyn GetResponse + Send + Sync> {
+        let admission = self.circuit.lock().unwrap().admit(&self.config);
+        let state = match admission {
+            Some(is_probe) => RequestState::Upstream {
+                request: self.upstream.send_request(request_msg),
+                is_probe,
+            },
+            None => {
+                trace!("Circuit open, rejecting request");
+                RequestState::Rejected
+            }
+        };
+        Box::new(Request {
+            state,
+            circuit: self.circuit.clone(),
+            config: self.config.clone(),
+        })
+    }
+}
+
+//------------ Request --------------------------------------------------------
+
+/// The state of a request that is executed.
+pub struct Request {
+    /// State of the request.
+    state: RequestState,
+
+    /// The circuit of the connection.
+    circuit: Arc<Mutex<Circuit>>,
+
+    /// The configuration of the connection.
+    config: Config,
+}
+
+impl Request {
+    /// This is the implementation of the get_response method.
+    ///
+    /// This function is cancel safe.
+    async fn get_response_impl(&mut self) -> Result<Message<Bytes>, Error> {
+        let RequestState::Upstream { request, is_probe } = &mut self.state
+        else {
+            return Err(Error::CircuitOpen);
+        };
+
+        let res = request.get_response().await;
+        let is_probe = *is_probe;
+        self.state = RequestState::Done;
+
+        let mut circuit = self.circuit.lock().unwrap();
+        match &res {
+            Ok(_) => circuit.record_success(is_probe),
+            Err(err) => {
+                trace!("Upstream request failed: {err}");
+                circuit.record_failure(is_probe, &self.config)
+            }
+        }
+
+        res
+    }
+}
+
+impl Debug for Request {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), core::fmt::Error> {
+        f.debug_struct("Request")
+            .field("fut", &format_args!("_"))
+            .finish()
+    }
+}
+
+impl GetResponse for Request {
+    fn get_response(
+        &mut self,
+    ) -> Pin<
+        Box<
+            dyn Future<Output = Result<Message<Bytes>, Error>>
+                + Send
+                + Sync
+                + '_,
+        >,
+    > {
+        Box::pin(self.get_response_impl())
+    }
+}
+
+impl Drop for Request {
+    fn drop(&mut self) {
+        // A probe that is abandoned before it completed must not leave the
+        // circuit waiting for its outcome forever.
+        if let RequestState::Upstream { is_probe: true, .. } = self.state {
+            self.circuit.lock().unwrap().probe_in_flight = false;
+        }
+    }
+}
+
+//------------ RequestState ---------------------------------------------------
+
+/// States of a request.
+enum RequestState {
+    /// The request was rejected because the circuit is open.
+    Rejected,
+
+    /// The request was passed to the upstream.
+    Upstream {
+        /// The upstream request.
+        request: Box<dyn GetResponse + Send + Sync>,
+
+        /// Whether this request is the probe of a half-open circuit.
+        is_probe: bool,
+    },
+
+    /// The outcome of the upstream request has been recorded.
+    Done,
+}
+
+//------------ Circuit --------------------------------------------------------
+
+/// The state shared by all requests sent via a connection.
+#[derive(Debug)]
+struct Circuit {
+    /// The current state.
+    state: State,
+
+    /// The number of failures since the last success.
+    consecutive_failures: u32,
+
+    /// When the circuit was last opened.
+    opened_at: Instant,
+
+    /// Whether a probe request is currently outstanding.
+    probe_in_flight: bool,
+}
+
+impl Circuit {
+    /// Decide whether a new request may be sent to the upstream.
+    ///
+    /// Returns `None` if the request must be rejected, otherwise whether the
+    /// request is the probe of a half-open circuit.
+    fn admit(&mut self, config: &Config) -> Option<bool> {
+        match self.state {
+            State::Closed => Some(false),
+            State::Open if self.opened_at.elapsed() < config.cooldown => None,
+            State::Open | State::HalfOpen if !self.probe_in_flight => {
+                trace!("Circuit half-open, probing upstream");
+                self.state = State::HalfOpen;
+                self.probe_in_flight = true;
+                Some(true)
+            }
+            State::Open | State::HalfOpen => None,
+        }
+    }
+
+    /// Record a successful upstream request.
+    fn record_success(&mut self, is_probe: bool) {
+        self.consecutive_failures = 0;
+        if is_probe {
+            debug!("Probe succeeded, closing circuit");
+            self.state = State::Closed;
+            self.probe_in_flight = false;
+        }
+    }
+
+    /// Record a failed upstream request.
+    fn record_failure(&mut self, is_probe: bool, config: &Config) {
+        if is_probe {
+            debug!("Probe failed, reopening circuit");
+            self.open();
+            return;
+        }
+
+        if self.state == State::Closed {
+            self.consecutive_failures += 1;
+            if self.consecutive_failures >= config.failure_threshold {
+                debug!(
+                    "{} consecutive upstream failures, opening circuit",
+                    self.consecutive_failures
+                );
+                self.open();
+            }
+        }
+    }
+
+    /// Open the circuit.
+    fn open(&mut self) {
+        self.state = State::Open;
+        self.opened_at = Instant::now();
+        self.probe_in_flight = false;
+    }
+}
+
+impl Default for Circuit {
+    fn default() -> Self {
+        Self {
+            state: State::Closed,
+            consecutive_failures: 0,
+            opened_at: Instant::now(),
+            probe_in_flight: false,
+        }
+    }
+}
+
+//============ Tests =========================================================
+
+#[cfg(test)]
+mod tests {
+    use core::future::ready;
+    use core::pin::Pin;
+    use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+    use std::boxed::Box;
+    use std::future::Future;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use std::vec::Vec;
+
+    use bytes::Bytes;
+
+    use crate::base::{Message, MessageBuilder, Name, Rtype};
+    use crate::net::client::request::{
+        Error, GetResponse, RequestMessage, SendRequest,
+    };
+
+    use super::{Config, Connection, State};
+
+    #[tokio::test(start_paused = true)]
+    async fn opens_after_consecutive_failures() {
+        let upstream = MockUpstream::default();
+        let conn = mk_connection(&upstream);
+
+        upstream.set_failing(true);
+        for _ in 0..3 {
+            assert_eq!(conn.state(), State::Closed);
+            assert!(query(&conn).await.is_err());
+        }
+        assert_eq!(conn.state(), State::Open);
+        assert_eq!(upstream.num_requests(), 3);
+
+        // Requests now fail fast without reaching the upstream.
+        assert!(matches!(query(&conn).await, Err(Error::CircuitOpen)));
+        assert_eq!(upstream.num_requests(), 3);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn success_resets_failure_count() {
+        let upstream = MockUpstream::default();
+        let conn = mk_connection(&upstream);
+
+        for failing in [true, true, false, true, true] {
+            upstream.set_failing(failing);
+            let _ = query(&conn).await;
+        }
+        assert_eq!(conn.state(), State::Closed);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn half_open_probe() {
+        let upstream = MockUpstream::default();
+        let conn = mk_connection(&upstream);
+
+        upstream.set_failing(true);
+        for _ in 0..3 {
+            let _ = query(&conn).await;
+        }
+        assert_eq!(conn.state(), State::Open);
+
+        // A failing probe after the cooldown reopens the circuit.
+        tokio::time::advance(Duration::from_secs(10)).await;
+        assert!(matches!(query(&conn).await, Err(Error::StreamReadTimeout)));
+        assert_eq!(conn.state(), State::Open);
+        assert!(matches!(query(&conn).await, Err(Error::CircuitOpen)));
+
+        // While a probe is outstanding other requests fail fast.
+        tokio::time::advance(Duration::from_secs(10)).await;
+        upstream.set_failing(false);
+        let mut probe = conn.send_request(mk_request());
+        assert_eq!(conn.state(), State::HalfOpen);
+        assert!(matches!(query(&conn).await, Err(Error::CircuitOpen)));
+
+        // A successful probe closes the circuit.
+        assert!(probe.get_response().await.is_ok());
+        assert_eq!(conn.state(), State::Closed);
+        assert!(query(&conn).await.is_ok());
+    }
+
+    //------------ Helper functions ------------------------------------------
+
+    fn mk_connection(upstream: &MockUpstream) -> Connection<MockUpstream> {
+        let mut config = Config::new();
+        config.set_failure_threshold(3);
+        config.set_cooldown(Duration::from_secs(10));
+        Connection::with_config(upstream.clone(), config)
+    }
+
+    fn mk_request() -> RequestMessage<Vec<u8>> {
+        let mut msg = MessageBuilder::new_vec().question();
+        msg.push((Name::vec_from_str("example.com").unwrap(), Rtype::A))
+            .unwrap();
+        RequestMessage::new(msg).unwrap()
+    }
+
+    async fn query(
+        conn: &Connection<MockUpstream>,
+    ) -> Result<Message<Bytes>, Error> {
+        conn.send_request(mk_request()).get_response().await
+    }
+
+    //------------ MockUpstream -----------------------------------------------
+
+    /// An upstream that either always fails or always succeeds.
+    #[derive(Clone, Debug, Default)]
+    struct MockUpstream {
+        failing: Arc<AtomicBool>,
+        num_requests: Arc<AtomicUsize>,
+    }
+
+    impl MockUpstream {
+        fn set_failing(&self, failing: bool) {
+            self.failing.store(failing, Ordering::SeqCst);
+        }
+
+        fn num_requests(&self) -> usize {
+            self.num_requests.load(Ordering::SeqCst)
+        }
+    }
+
+    impl SendRequest<RequestMessage<Vec<u8>>> for MockUpstream {
+        fn send_request(
+            &self,
+            _request_msg: RequestMessage<Vec<u8>>,
+        ) -> Box<dyn GetResponse + Send + Sync> {
+            self.num_requests.fetch_add(1, Ordering::SeqCst);
+            Box::new(MockGetResponse(self.failing.load(Ordering::SeqCst)))
+        }
+    }
+
+    #[derive(Debug)]
+    struct MockGetResponse(bool);
+
+    impl GetResponse for MockGetResponse {
+        fn get_response(
+            &mut self,
+        ) -> Pin<
+            Box<
+                dyn Future<Output = Result<Message<Bytes>, Error>>
+                    + Send
+                    + Sync
+                    + '_,
+            >,
+        > {
+            let res = if self.0 {
+                Err(Error::StreamReadTimeout)
+            } else {
+                Ok(MessageBuilder::new_bytes().into_message())
+            };
+            Box::pin(ready(res))
+        }
+    }
+}
@@ -23,6 +23,9 @@
 //!   as upstream transports.
 //! * [cache] This is a simple message cache provided as a pass through
 //!   transport. The cache works with any of the other transports.
+//! * [circuit_breaker] This transport stops sending requests to a failing
+//!   upstream for a while, failing them immediately instead. It works with
+//!   any of the other transports.
 #![cfg_attr(feature = "tsig", doc = "* [tsig]:")]
 #![cfg_attr(not(feature = "tsig",), doc = "* tsig:")]
 //!   This is a TSIG request signer and response verifier provided as a
@@ -220,6 +223,7 @@
 #![warn(clippy::missing_docs_in_private_items)]
 
 pub mod cache;
+pub mod circuit_breaker;
 pub mod dgram;
 pub mod dgram_stream;
 pub mod multi_stream;
@@ -1,6 +1,8 @@
 //! Constructing and sending requests.
 use crate::base::iana::{Opcode, Rcode};
-use crate::base::message::{CopyRecordsError, ShortMessage};
+use crate::base::message::{
+    debug_validate_message, CopyRecordsError, ShortMessage,
+};
 use crate::base::message_builder::{
     AdditionalBuilder, MessageBuilder, PushError,
 };
@@ -10,7 +12,7 @@ use crate::base::{
     Header, Message, Rtype, StaticCompressor, UnknownRecordData,
 };
 use bytes::Bytes;
-use octseq::Octets;
+use octseq::{Octets, OctetsFrom};
 use std::boxed::Box;
 use std::fmt::Debug;
 use std::future::Future;
@@ -18,7 +20,7 @@ use std::pin::Pin;
 use std::sync::Arc;
 use std::vec::Vec;
 use std::{error, fmt};
-use tracing::trace;
+use tracing::{trace, warn};
 
 #[cfg(feature = "tsig")]
 use crate::tsig;
@@ -238,6 +240,35 @@ impl<Octs: AsRef<[u8]> + Debug + Octets> RequestMessage<Octs> {
         })
     }
 
+    /// Create a new RequestMessage object that sends an existing message.
+    ///
+    /// Unlike [`new()`], which discards any OPT record in the message, this
+    /// keeps the OPT record of the message, i.e. the UDP payload size, EDNS
+    /// version, flags such as DNSSEC OK and all EDNS options are sent as
+    /// they appear in the message. This is useful to forward a received
+    /// request upstream. Note though that some options, e.g. cookies, are
+    /// specific to a client and server pair and may need to be removed
+    /// before forwarding.
+    ///
+    /// The header of the message, including all of its flags, is kept as
+    /// is. This includes the message ID. However, transports will usually
+    /// replace the ID with one of their own in order to match responses to
+    /// requests. A response received via such a transport will carry the ID
+    /// set by the transport and should have its ID set back to that of the
+    /// original message before it is relayed to the original sender.
+    ///
+    /// [`new()`]: Self::new
+    pub fn from_message(
+        msg: impl Into<Message<Octs>>,
+    ) -> Result<Self, Error> {
+        let mut req = Self::new(msg)?;
+        if let Some(opt) = req.msg.opt() {
+            let opt = OptRecord::from_record(opt.as_record());
+            req.opt = Some(OptRecord::octets_from(opt));
+        }
+        Ok(req)
+    }
+
     /// Returns a mutable reference to the OPT record.
     ///
     /// Adds one if necessary.
@@ -313,6 +344,9 @@ impl<Octs: AsRef<[u8]> + Debug + Octets> RequestMessage<Octs> {
         let msg = Message::from_octets(result.finish().into_target()).expect(
             "Message should be able to parse output from MessageBuilder",
         );
+        if let Err(err) = debug_validate_message(&msg) {
+            warn!("Rebuilt message is invalid: {err}");
+        }
         Ok(msg)
     }
 }
@@ -521,6 +555,9 @@ impl<Octs: AsRef<[u8]> + Debug + Octets> RequestMessageMulti<Octs> {
         let msg = Message::from_octets(result.finish().into_target()).expect(
             "Message should be able to parse output from MessageBuilder",
         );
+        if let Err(err) = debug_validate_message(&msg) {
+            warn!("Rebuilt message is invalid: {err}");
+        }
         Ok(msg)
     }
 }
@@ -684,6 +721,10 @@ pub enum Error {
     /// No transport available to transmit request.
     NoTransportAvailable,
 
+    /// The request was rejected because the upstream is considered to be
+    /// failing.
+    CircuitOpen,
+
     /// An error happened in the datagram transport.
     Dgram(Arc<super::dgram::QueryError>),
 
@@ -778,6 +819,9 @@ impl fmt::Display for Error {
             Error::NoTransportAvailable => {
                 write!(f, "no transport available")
             }
+            Error::CircuitOpen => {
+                write!(f, "circuit breaker open, upstream is failing")
+            }
             Error::Dgram(err) => fmt::Display::fmt(err, f),
 
             #[cfg(feature = "unstable-server-transport")]
@@ -823,6 +867,7 @@ impl error::Error for Error {
             Error::StreamUnexpectedEndOfData => None,
             Error::WrongReplyForQuery => None,
             Error::NoTransportAvailable => None,
+            Error::CircuitOpen => None,
             Error::Dgram(err) => Some(err),
 
             #[cfg(feature = "unstable-server-transport")]
@@ -836,3 +881,48 @@ impl error::Error for Error {
         }
     }
 }
+
+//============ Tests =========================================================
+
+#[cfg(test)]
+mod tests {
+    use std::vec::Vec;
+
+    use crate::base::opt::TcpKeepalive;
+    use crate::base::{MessageBuilder, Name, Rtype};
+
+    use super::{ComposeRequest, RequestMessage};
+
+    #[test]
+    fn from_message_keeps_header_and_opt() {
+        let mut msg = MessageBuilder::new_vec();
+        msg.header_mut().set_id(4321);
+        msg.header_mut().set_rd(true);
+        msg.header_mut().set_cd(true);
+        let mut msg = msg.question();
+        msg.push((Name::<Vec<u8>>::root(), Rtype::SOA)).unwrap();
+        let mut msg = msg.additional();
+        msg.opt(|opt| {
+            opt.set_udp_payload_size(1232);
+            opt.set_dnssec_ok(true);
+            opt.push(&TcpKeepalive::new(None))
+        })
+        .unwrap();
+        let msg = msg.into_message();
+
+        let req = RequestMessage::from_message(msg.clone()).unwrap();
+        assert!(req.dnssec_ok());
+        let sent = req.to_message().unwrap();
+        assert_eq!(sent.header(), msg.header());
+        assert_eq!(sent.header_counts().arcount(), 1);
+
+        let opt = sent.opt().unwrap();
+        assert_eq!(opt.udp_payload_size(), 1232);
+        assert!(opt.dnssec_ok());
+        assert_eq!(opt.opt().iter::<TcpKeepalive>().count(), 1);
+
+        // Without from_message() the OPT record is dropped.
+        let req = RequestMessage::new(msg).unwrap();
+        assert!(req.to_message().unwrap().opt().is_none());
+    }
+}
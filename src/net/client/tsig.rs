@@ -58,9 +58,9 @@ use std::vec::Vec;
 
 use bytes::Bytes;
 use octseq::Octets;
-use tracing::trace;
+use tracing::{trace, warn};
 
-use crate::base::message::CopyRecordsError;
+use crate::base::message::{debug_validate_message, CopyRecordsError};
 use crate::base::message_builder::AdditionalBuilder;
 use crate::base::wire::Composer;
 use crate::base::Message;
@@ -607,6 +607,9 @@ where
         let msg = Message::from_octets(target.into_target()).expect(
             "Message should be able to parse output from MessageBuilder",
         );
+        if let Err(err) = debug_validate_message(&msg) {
+            warn!("Rebuilt message is invalid: {err}");
+        }
         Ok(msg)
     }
 
@@ -683,6 +686,9 @@ where
         let msg = Message::from_octets(target.into_target()).expect(
             "Message should be able to parse output from MessageBuilder",
         );
+        if let Err(err) = debug_validate_message(&msg) {
+            warn!("Rebuilt message is invalid: {err}");
+        }
         Ok(msg)
     }
 
@@ -38,6 +38,16 @@
 //! no upstream cache, those requests will go over the upstream transport
 //! twice. One solution to that is to create a new type of cache that only
 //! caches DS and DNSKEY records and insert that upstream of the validator.
+//!
+//! # Logging
+//!
+//! The outcome of validating each response is logged via [tracing] at debug
+//! level, and the steps leading to it at trace level, using the target
+//! `domain::validator`. They can thus be filtered separately from log
+//! messages of the upstream transports, e.g. with
+//! `RUST_LOG=domain::validator=debug` when using an `EnvFilter`.
+//!
+//! [tracing]: https://docs.rs/tracing
 
 //! # Example
 //! ```rust,no_run
@@ -87,8 +97,10 @@
 //! }
 //! ```
 
-use crate::base::iana::Rcode;
-use crate::base::opt::{AllOptData, ExtendedError};
+use crate::base::iana::{OptRcode, Rcode};
+use crate::base::message::debug_validate_message;
+use crate::base::message_builder::{rebuild_opt, OptOverrides};
+use crate::base::opt::ExtendedError;
 use crate::base::{
     Message, MessageBuilder, ParsedName, Rtype, StaticCompressor,
 };
@@ -97,6 +109,7 @@ use crate::net::client::request::{
     ComposeRequest, Error, GetResponse, RequestMessage, SendRequest,
 };
 use crate::rdata::AllRecordData;
+use crate::validator::context::Error as ValidatorError;
 use crate::validator::context::{ValidationContext, ValidationState};
 use bytes::Bytes;
 use std::boxed::Box;
@@ -106,6 +119,13 @@ use std::marker::PhantomData;
 use std::pin::Pin;
 use std::sync::Arc;
 use std::vec::Vec;
+use tracing::{debug, trace, warn};
+
+/// The target of log messages about validation.
+///
+/// Allows filtering these messages separately from those of the transport
+/// the validator uses upstream.
+const LOG_TARGET: &str = "domain::validator";
 
 //------------ Config ---------------------------------------------------------
 
@@ -285,6 +305,12 @@ where
                         self.request_msg.header_mut().set_cd(true);
                     }
 
+                    trace!(
+                        target: LOG_TARGET,
+                        dnssec_ok = self.dnssec_ok,
+                        cd = self.cd,
+                        "Sending request upstream"
+                    );
                     let request =
                         self.upstream.send_request(self.request_msg.clone());
                     self.state = RequestState::GetResponse(request);
@@ -295,6 +321,10 @@ where
                     let response_msg = request.get_response().await?;
 
                     if self.cd {
+                        trace!(
+                            target: LOG_TARGET,
+                            "Checking disabled by request, not validating"
+                        );
                         if self.dnssec_ok {
                             // Clear the AD flag if it is clear. Check if CD
                             // is set. If either AD is set or CD is clear then
@@ -330,8 +360,23 @@ where
                 RequestState::Validate(response_msg) => {
                     let res = self.vc.validate_msg(response_msg).await;
                     return match res {
-                        Err(err) => Err(Error::Validation(err)),
+                        Err(ValidatorError::Upstream(err)) => {
+                            // Pass on the original error so the caller can
+                            // tell that retrying may help.
+                            debug!(target: LOG_TARGET, "Upstream request failed during validation: {err}");
+                            Err((*err).clone())
+                        }
+                        Err(err) => {
+                            debug!(target: LOG_TARGET, "Validation failed: {err}");
+                            Err(Error::Validation(err))
+                        }
                         Ok((state, opt_ede)) => {
+                            debug!(
+                                target: LOG_TARGET,
+                                ?state,
+                                ede = ?opt_ede,
+                                "Validated response"
+                            );
                             match state {
                                 ValidationState::Secure => {
                                     // Check the state of the DO flag to see
@@ -547,26 +592,25 @@ fn remove_dnssec(
     if let Some(opt) = opt {
         target
             .opt(|ob| {
-                ob.set_dnssec_ok(false);
-                // XXX something is missing ob.set_rcode(opt.rcode());
-                ob.set_udp_payload_size(opt.udp_payload_size());
-                ob.set_version(opt.version());
-                for o in opt.opt().iter() {
-                    let x: AllOptData<_, _> = o.expect("should not fail");
-                    ob.push(&x)?;
-                }
-                Ok(())
+                rebuild_opt(
+                    &opt,
+                    ob,
+                    &OptOverrides::new().with_dnssec_ok(false),
+                )
             })
             .expect("should not fail");
     }
 
     let result = target.as_builder().clone();
-    Ok(
+    let msg =
         Message::<Bytes>::from_octets(result.finish().into_target().into())
             .expect(
                 "Message should be able to parse output from MessageBuilder",
-            ),
-    )
+            );
+    if let Err(err) = debug_validate_message(&msg) {
+        warn!("Rebuilt message is invalid: {err}");
+    }
+    Ok(msg)
 }
 
 /// Check if a type is a DNSSEC type that needs to be removed.
@@ -630,16 +674,8 @@ fn add_opt(
     if let Some(opt) = msg.opt() {
         target
             .opt(|ob| {
-                ob.set_dnssec_ok(opt.dnssec_ok());
-                // XXX something is missing ob.set_rcode(opt.rcode());
-                ob.set_udp_payload_size(opt.udp_payload_size());
-                ob.set_version(opt.version());
-                for o in opt.opt().iter() {
-                    let x: AllOptData<_, _> = o.expect("should not fail");
-                    ob.push(&x).expect("should not fail");
-                }
-                ob.push(&ede).expect("should not fail");
-                Ok(())
+                rebuild_opt(&opt, ob, &OptOverrides::new())?;
+                ob.push(&ede)
             })
             .expect("should not fail");
     }
@@ -649,6 +685,9 @@ fn add_opt(
         result.finish().into_target().octets_into(),
     )
     .expect("Message should be able to parse output from MessageBuilder");
+    if let Err(err) = debug_validate_message(&msg) {
+        warn!("Rebuilt message is invalid: {err}");
+    }
     Ok(msg)
 }
 
@@ -677,20 +716,22 @@ fn serve_fail(
     if let Some(opt) = msg.opt() {
         target
             .opt(|ob| {
-                ob.set_dnssec_ok(opt.dnssec_ok());
-                // XXX something is missing ob.set_rcode(opt.rcode());
-                ob.set_udp_payload_size(opt.udp_payload_size());
-                ob.set_version(opt.version());
-                for o in opt.opt().iter() {
-                    let x: AllOptData<_, _> = o.expect("should not fail");
-                    ob.push(&x).expect("should not fail");
-                }
+                // The header rcode was changed to SERVFAIL above, make sure
+                // the extended rcode bits of the source don't alter it.
+                rebuild_opt(
+                    &opt,
+                    ob,
+                    &OptOverrides::new().with_rcode(OptRcode::SERVFAIL),
+                )?;
                 if let Some(ede) = opt_ede {
-                    ob.push(&ede).expect("should not fail");
+                    ob.push(&ede)?;
                 }
                 Ok(())
             })
             .expect("should not fail");
+    } else if let Some(ede) = opt_ede {
+        // The reason for the failure is worth an OPT record of its own.
+        target.opt(|ob| ob.push(&ede)).expect("should not fail");
     }
 
     let result = target.as_builder().clone();
@@ -698,5 +739,8 @@ fn serve_fail(
         result.finish().into_target().octets_into(),
     )
     .expect("Message should be able to parse output from MessageBuilder");
+    if let Err(err) = debug_validate_message(&msg) {
+        warn!("Rebuilt message is invalid: {err}");
+    }
     Ok(msg)
 }
@@ -2,9 +2,13 @@
 
 #![cfg(test)]
 
+use std::boxed::Box;
 use std::fs::File;
+use std::future::{ready, Future};
 use std::path::PathBuf;
+use std::pin::Pin;
 use std::string::ToString;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use std::sync::Mutex;
 use std::time::Duration;
@@ -21,12 +25,20 @@ use rstest::rstest;
 use tracing::instrument;
 
 // use domain::net::client::clock::{Clock, FakeClock};
+use crate::base::iana::{ExtendedErrorCode, Rcode};
+use crate::base::opt::ExtendedError;
 use crate::base::scan::IterScanner;
+use crate::base::{Message, MessageBuilder, Name, Rtype};
+use crate::net::client::request::{
+    ComposeRequest, Error, GetResponse, RequestMessage, SendRequest,
+};
 use crate::net::client::{multi_stream, validator};
 use crate::rdata::dnssec::Timestamp;
 use crate::validator::anchor::TrustAnchors;
-use crate::validator::context::ValidationContext;
+use crate::validator::context::Config as ValidatorConfig;
+use crate::validator::context::{ValidationContext, ValidationState};
 
+use bytes::Bytes;
 use lazy_static::lazy_static;
 
 lazy_static! {
@@ -34,13 +46,13 @@ lazy_static! {
 }
 
 #[allow(clippy::await_holding_lock)]
-async fn async_test_validator(filename: &str) {
+async fn async_test_validator(filename: &str, reload: bool) {
     let _locked = LOCK.lock().unwrap();
 
     let file = File::open(filename).unwrap();
     let stelline = parse_file(&file, filename);
 
-    let ta = parse_server_config(&stelline.config);
+    let (ta, config) = parse_server_config(&stelline.config);
 
     let step_value = Arc::new(CurrStepValue::new());
     let multi_conn = Connect::new(stelline.clone(), step_value.clone());
@@ -49,7 +61,18 @@ async fn async_test_validator(filename: &str) {
         ms_tran.run().await;
     });
 
-    let vc = Arc::new(ValidationContext::new(ta, ms.clone()));
+    let vc = if reload {
+        // Start without trust anchors and only provide them via a reload.
+        let vc = ValidationContext::with_config(
+            TrustAnchors::empty(),
+            ms.clone(),
+            config,
+        );
+        vc.reload_trust_anchors(ta);
+        Arc::new(vc)
+    } else {
+        Arc::new(ValidationContext::with_config(ta, ms.clone(), config))
+    };
 
     // let clock = FakeClock::new();
     let validator = validator::Connection::new(ms, vc); //_with_time(ms, clock.clone());
@@ -63,12 +86,189 @@ async fn async_test_validator(filename: &str) {
 async fn validator_test_all(
     #[files("test-data/validator/*.rpl")] rpl_file: PathBuf,
 ) {
-    async_test_validator(rpl_file.to_str().unwrap()).await;
+    async_test_validator(rpl_file.to_str().unwrap(), false).await;
 }
 
-fn parse_server_config(config: &Config) -> TrustAnchors {
+#[tokio::test(start_paused = true)]
+async fn validator_test_reloaded_trust_anchors() {
+    async_test_validator("test-data/validator/val_adbit.rpl", true).await;
+}
+
+#[allow(clippy::await_holding_lock)]
+#[tokio::test(start_paused = true)]
+async fn validator_test_unsupported_algorithm() {
+    let _locked = LOCK.lock().unwrap();
+
+    let filename = "test-data/validator/val_unknown_algorithm_insecure.rpl";
+    let file = File::open(filename).unwrap();
+    let stelline = parse_file(&file, filename);
+
+    let (ta, config) = parse_server_config(&stelline.config);
+
+    let step_value = Arc::new(CurrStepValue::new());
+    let multi_conn = Connect::new(stelline.clone(), step_value.clone());
+    let (ms, ms_tran) = multi_stream::Connection::new(multi_conn);
+    tokio::spawn(async move {
+        ms_tran.run().await;
+    });
+
+    let vc = Arc::new(ValidationContext::with_config(ta, ms.clone(), config));
+    let validator = validator::Connection::new(ms.clone(), vc.clone());
+
+    // The answers are insecure rather than bogus.
+    do_client_simple(&stelline, &step_value, validator).await;
+
+    // And the reason is given in an extended error.
+    let mut msg = MessageBuilder::new_vec().question();
+    msg.push((Name::vec_from_str("test.").unwrap(), Rtype::SOA))
+        .unwrap();
+    let mut req = RequestMessage::new(msg).unwrap();
+    req.set_dnssec_ok(true);
+    let mut reply = ms.send_request(req).get_response().await.unwrap();
+    let (state, ede) = vc.validate_msg(&mut reply).await.unwrap();
+    assert_eq!(state, ValidationState::Insecure);
+    assert_eq!(
+        ede.unwrap().code(),
+        ExtendedErrorCode::UNSUPPORTED_DNSKEY_ALGORITHM
+    );
+}
+
+#[allow(clippy::await_holding_lock)]
+#[tokio::test(start_paused = true)]
+async fn validator_test_bogus_answer_is_servfail() {
+    let _locked = LOCK.lock().unwrap();
+
+    // The RRSIG of the root NS RRset has been tampered with.
+    let filename = "test-data/validator/val_minimal_badrrsigsignature.rpl";
+    let file = File::open(filename).unwrap();
+    let stelline = parse_file(&file, filename);
+
+    let (ta, config) = parse_server_config(&stelline.config);
+
+    let step_value = Arc::new(CurrStepValue::new());
+    let multi_conn = Connect::new(stelline.clone(), step_value.clone());
+    let (ms, ms_tran) = multi_stream::Connection::new(multi_conn);
+    tokio::spawn(async move {
+        ms_tran.run().await;
+    });
+
+    let vc = Arc::new(ValidationContext::with_config(ta, ms.clone(), config));
+    let validator = validator::Connection::new(ms, vc);
+
+    let mut msg = MessageBuilder::new_vec().question();
+    msg.push((Name::root_vec(), Rtype::NS)).unwrap();
+    let mut req = RequestMessage::new(msg).unwrap();
+    req.set_dnssec_ok(true);
+    let reply = validator.send_request(req).get_response().await.unwrap();
+
+    assert_eq!(reply.header().rcode(), Rcode::SERVFAIL);
+    assert!(!reply.header().ad());
+    assert_eq!(reply.header_counts().ancount(), 0);
+    let question = reply.sole_question().unwrap();
+    assert_eq!(question.qname(), &Name::root_vec());
+    assert_eq!(question.qtype(), Rtype::NS);
+    let opt = reply.opt().unwrap();
+    let ede = opt.opt().first::<ExtendedError<_>>().unwrap();
+    assert_eq!(ede.code(), ExtendedErrorCode::DNSSEC_BOGUS);
+}
+
+#[allow(clippy::await_holding_lock)]
+#[tokio::test(start_paused = true)]
+async fn validator_test_upstream_failure_is_error() {
+    /// Times out DNSKEY requests while `failing` is set.
+    #[derive(Clone)]
+    struct FlakyUpstream<U> {
+        inner: U,
+        failing: Arc<AtomicBool>,
+    }
+
+    impl<U: SendRequest<RequestMessage<Vec<u8>>>>
+        SendRequest<RequestMessage<Vec<u8>>> for FlakyUpstream<U>
+    {
+        fn send_request(
+            &self,
+            request_msg: RequestMessage<Vec<u8>>,
+        ) -> Box<dyn GetResponse + Send + Sync> {
+            let msg = request_msg.to_message().unwrap();
+            let qtype = msg.sole_question().unwrap().qtype();
+            if qtype == Rtype::DNSKEY && self.failing.load(Ordering::SeqCst) {
+                Box::new(TimedOut)
+            } else {
+                self.inner.send_request(request_msg)
+            }
+        }
+    }
+
+    #[derive(Debug)]
+    struct TimedOut;
+
+    impl GetResponse for TimedOut {
+        fn get_response(
+            &mut self,
+        ) -> Pin<
+            Box<
+                dyn Future<Output = Result<Message<Bytes>, Error>>
+                    + Send
+                    + Sync
+                    + '_,
+            >,
+        > {
+            Box::pin(ready(Err(Error::StreamReadTimeout)))
+        }
+    }
+
+    let _locked = LOCK.lock().unwrap();
+
+    let filename = "test-data/validator/val_minimal_badrrsigsignature.rpl";
+    let file = File::open(filename).unwrap();
+    let stelline = parse_file(&file, filename);
+
+    let (ta, config) = parse_server_config(&stelline.config);
+
+    let step_value = Arc::new(CurrStepValue::new());
+    let multi_conn = Connect::new(stelline.clone(), step_value.clone());
+    let (ms, ms_tran) = multi_stream::Connection::new(multi_conn);
+    tokio::spawn(async move {
+        ms_tran.run().await;
+    });
+
+    let failing = Arc::new(AtomicBool::new(true));
+    let upstream = FlakyUpstream {
+        inner: ms.clone(),
+        failing: failing.clone(),
+    };
+    let vc = Arc::new(ValidationContext::with_config(ta, upstream, config));
+    let validator = validator::Connection::new(ms, vc);
+
+    let request = || {
+        let mut msg = MessageBuilder::new_vec().question();
+        msg.push((Name::root_vec(), Rtype::NS)).unwrap();
+        let mut req = RequestMessage::new(msg).unwrap();
+        req.set_dnssec_ok(true);
+        req
+    };
+
+    // The failure to fetch the DNSKEY is passed on as is.
+    let res = validator.send_request(request()).get_response().await;
+    assert!(matches!(res, Err(Error::StreamReadTimeout)));
+
+    // It was not mistaken for bogus data, so trying again validates.
+    failing.store(false, Ordering::SeqCst);
+    let reply = validator
+        .send_request(request())
+        .get_response()
+        .await
+        .unwrap();
+    assert_eq!(reply.header().rcode(), Rcode::SERVFAIL);
+    let opt = reply.opt().unwrap();
+    let ede = opt.opt().first::<ExtendedError<_>>().unwrap();
+    assert_eq!(ede.code(), ExtendedErrorCode::DNSSEC_BOGUS);
+}
+
+fn parse_server_config(config: &Config) -> (TrustAnchors, ValidatorConfig) {
     let mut in_server_block = false;
     let mut ta = TrustAnchors::empty();
+    let mut vc_config = ValidatorConfig::new();
 
     for line in config.lines() {
         if line.starts_with("server:") {
@@ -107,6 +307,14 @@ fn parse_server_config(config: &Config) -> TrustAnchors {
                     ("trust-anchor", a) => {
                         ta.add_u8(a.trim_matches('"').as_bytes()).unwrap();
                     }
+                    ("val-max-upstream-queries", v) => {
+                        vc_config
+                            .set_max_upstream_queries(v.parse().unwrap());
+                    }
+                    ("val-max-validation-depth", v) => {
+                        vc_config
+                            .set_max_validation_depth(v.parse().unwrap());
+                    }
                     _ => {
                         eprintln!("Ignoring unknown server setting '{setting}' with value: {value}");
                     }
@@ -115,5 +323,5 @@ fn parse_server_config(config: &Config) -> TrustAnchors {
         }
     }
 
-    ta
+    (ta, vc_config)
 }
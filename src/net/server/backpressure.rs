@@ -0,0 +1,65 @@
+//! Pausing the reading of requests at the request of a service.
+//!
+//! See [`ServiceFeedback::ApplyBackpressure`].
+//!
+//! [`ServiceFeedback::ApplyBackpressure`]:
+//!     super::service::ServiceFeedback::ApplyBackpressure
+use core::time::Duration;
+
+use std::sync::Mutex;
+
+use tokio::sync::Notify;
+use tokio::time::Instant;
+use tracing::debug;
+
+/// The longest time that reading requests will be paused for in response to
+/// a single backpressure request.
+pub const MAX_BACKPRESSURE_PAUSE: Duration = Duration::from_secs(5);
+
+//------------ Backpressure --------------------------------------------------
+
+/// Tracks until when the reading of requests should be paused.
+///
+/// Shared between the loop reading requests and the tasks processing the
+/// requests it read.
+#[derive(Debug, Default)]
+pub struct Backpressure {
+    /// The instant until which reading should be paused, if any.
+    until: Mutex<Option<Instant>>,
+
+    /// Notifies the reading loop that the pause was extended.
+    extended: Notify,
+}
+
+impl Backpressure {
+    /// Pause reading until the given instant.
+    ///
+    /// The pause is limited to [`MAX_BACKPRESSURE_PAUSE`] from now. If
+    /// reading is already paused for longer, this has no effect.
+    pub fn apply(&self, until: Instant) {
+        let until = until.min(Instant::now() + MAX_BACKPRESSURE_PAUSE);
+        let mut cur = self.until.lock().unwrap();
+        if cur.map_or(true, |cur| cur < until) {
+            debug!(
+                "Applying backpressure for {:?}",
+                until.saturating_duration_since(Instant::now())
+            );
+            *cur = Some(until);
+            self.extended.notify_one();
+        }
+    }
+
+    /// Returns the instant until which reading should be paused, if any.
+    pub fn paused_until(&self) -> Option<Instant> {
+        let mut cur = self.until.lock().unwrap();
+        if matches!(*cur, Some(until) if until <= Instant::now()) {
+            *cur = None;
+        }
+        *cur
+    }
+
+    /// Wait until the pause is extended.
+    pub async fn extended(&self) {
+        self.extended.notified().await
+    }
+}
@@ -1,9 +1,13 @@
 //! Buffer types and allocation strategies.
 use core::ops::Deref;
+use core::sync::atomic::{AtomicUsize, Ordering};
 
-use std::sync::Arc;
+use std::io;
+use std::sync::{Arc, Mutex};
 use std::vec::Vec;
 
+use tokio::io::ReadBuf;
+
 //----------- BufSource -----------------------------------------------------
 
 /// A source for creating new buffers.
@@ -22,6 +26,45 @@ pub trait BufSource {
 
     /// Creates a buffer large enough to hold the specified number of bytes.
     fn create_sized(&self, size: usize) -> Self::Output;
+
+    /// Creates a buffer with the default properties and fills it.
+    ///
+    /// The given closure is passed a [`ReadBuf`] over the memory of the new
+    /// buffer which it should fill, e.g. by reading a datagram from a
+    /// socket. The result of the closure is returned together with the
+    /// buffer. If the closure fails the buffer is [recycled] and the error
+    /// is returned.
+    ///
+    /// The returned buffer holds at least the bytes filled by the closure.
+    /// The default implementation fills a buffer created by
+    /// [`create_buf`], which is returned in full. Sources can override this
+    /// to avoid initializing memory that will be overwritten anyway, see
+    /// [`UninitBufSource`].
+    ///
+    /// [recycled]: Self::recycle
+    /// [`create_buf`]: Self::create_buf
+    fn create_filled<T, F>(&self, fill: F) -> io::Result<(Self::Output, T)>
+    where
+        F: FnOnce(&mut ReadBuf<'_>) -> io::Result<T>,
+    {
+        let mut buf = self.create_buf();
+        match fill(&mut ReadBuf::new(buf.as_mut())) {
+            Ok(res) => Ok((buf, res)),
+            Err(err) => {
+                self.recycle(buf);
+                Err(err)
+            }
+        }
+    }
+
+    /// Hands a buffer back to the source once the server is done with it.
+    ///
+    /// Sources that keep a pool of buffers can use this to reuse the
+    /// buffer for a later request instead of allocating a new one. The
+    /// default implementation simply drops the buffer.
+    fn recycle(&self, buf: Self::Output) {
+        let _ = buf;
+    }
 }
 
 impl<T: BufSource> BufSource for Arc<T> {
@@ -34,22 +77,416 @@ impl<T: BufSource> BufSource for Arc<T> {
     fn create_sized(&self, size: usize) -> Self::Output {
         Arc::deref(self).create_sized(size)
     }
+
+    fn create_filled<R, F>(&self, fill: F) -> io::Result<(Self::Output, R)>
+    where
+        F: FnOnce(&mut ReadBuf<'_>) -> io::Result<R>,
+    {
+        Arc::deref(self).create_filled(fill)
+    }
+
+    fn recycle(&self, buf: Self::Output) {
+        Arc::deref(self).recycle(buf)
+    }
 }
 
 //----------- VecBufSource --------------------------------------------------
 
+/// The default size of the buffers created by the buffer sources in this
+/// module.
+const DEFAULT_BUF_SIZE: usize = 1024;
+
 /// A source for creating [`Vec<u8>`] based buffers.
-#[derive(Clone)]
-pub struct VecBufSource;
+///
+/// Buffers created with the default properties are 1024 bytes long, which
+/// is too small to receive datagrams larger than that. Use
+/// [`VecBufSource::with_capacity`] to create larger buffers, e.g. when
+/// advertising a larger EDNS(0) UDP payload size.
+#[derive(Clone, Debug)]
+pub struct VecBufSource {
+    /// The size of buffers created with the default properties.
+    buf_size: usize,
+}
+
+impl VecBufSource {
+    /// Creates a source creating buffers of the given size by default.
+    #[must_use]
+    pub const fn with_capacity(buf_size: usize) -> Self {
+        Self { buf_size }
+    }
+
+    /// The size of buffers created with the default properties.
+    #[must_use]
+    pub fn buf_size(&self) -> usize {
+        self.buf_size
+    }
+}
+
+//--- Default
+
+impl Default for VecBufSource {
+    fn default() -> Self {
+        Self::with_capacity(DEFAULT_BUF_SIZE)
+    }
+}
+
+//--- BufSource
 
 impl BufSource for VecBufSource {
     type Output = Vec<u8>;
 
     fn create_buf(&self) -> Self::Output {
-        vec![0; 1024]
+        vec![0; self.buf_size]
+    }
+
+    fn create_sized(&self, size: usize) -> Self::Output {
+        vec![0; size]
+    }
+}
+
+//----------- UninitBufSource ----------------------------------------------
+
+/// A source for [`Vec<u8>`] based buffers that skips zero-initialization.
+///
+/// Buffers created by [`BufSource::create_filled`] are allocated without
+/// initializing their memory and are truncated to the bytes actually filled
+/// in, so the memory that was never written to is never exposed. This saves
+/// zeroing the whole buffer for each received datagram only for most of it
+/// to be overwritten or ignored.
+///
+/// Buffers created via [`BufSource::create_buf`] and
+/// [`BufSource::create_sized`] are still zero-initialized as the caller
+/// may read from them before writing to them.
+#[derive(Clone, Debug)]
+pub struct UninitBufSource {
+    /// The capacity of buffers created with the default properties.
+    buf_size: usize,
+}
+
+impl UninitBufSource {
+    /// Creates a new source creating buffers of the given size.
+    #[must_use]
+    pub fn new(buf_size: usize) -> Self {
+        Self { buf_size }
+    }
+}
+
+//--- Default
+
+impl Default for UninitBufSource {
+    fn default() -> Self {
+        Self::new(DEFAULT_BUF_SIZE)
+    }
+}
+
+//--- BufSource
+
+impl BufSource for UninitBufSource {
+    type Output = Vec<u8>;
+
+    fn create_buf(&self) -> Self::Output {
+        vec![0; self.buf_size]
     }
 
     fn create_sized(&self, size: usize) -> Self::Output {
         vec![0; size]
     }
+
+    fn create_filled<T, F>(&self, fill: F) -> io::Result<(Self::Output, T)>
+    where
+        F: FnOnce(&mut ReadBuf<'_>) -> io::Result<T>,
+    {
+        let mut buf = Vec::with_capacity(self.buf_size);
+        let start = buf.as_ptr();
+        let mut read_buf = ReadBuf::uninit(buf.spare_capacity_mut());
+        let res = fill(&mut read_buf)?;
+
+        // The closure could have replaced the read buffer with one over
+        // different memory, in which case nothing of ours was filled.
+        let filled = read_buf.filled();
+        let len = if filled.as_ptr() == start {
+            filled.len()
+        } else {
+            0
+        };
+
+        // SAFETY: `ReadBuf` guarantees that its filled part has been
+        // initialized and can't be made to claim otherwise without using
+        // unsafe code. As we checked above that it refers to the start of
+        // our spare capacity, the first `len` bytes of the vec are
+        // initialized and `len` doesn't exceed its capacity.
+        unsafe { buf.set_len(len) };
+        Ok((buf, res))
+    }
+}
+
+//----------- PooledBufSource -----------------------------------------------
+
+/// The default maximum number of idle buffers kept by a [`PooledBufSource`].
+const DEFAULT_MAX_POOLED: usize = 1024;
+
+/// A source of [`Vec<u8>`] based buffers that reuses recycled buffers.
+///
+/// Buffers handed back via [`BufSource::recycle`] are kept in a pool and
+/// returned by later calls to [`BufSource::create_buf`], avoiding an
+/// allocation per request. At most [`max_pooled`] idle buffers are kept,
+/// any further recycled buffers are dropped.
+///
+/// Reused buffers are not zeroed, they may still contain data from the
+/// request they were previously used for.
+///
+/// Clones share the same pool.
+///
+/// [`max_pooled`]: Self::with_max_pooled
+#[derive(Clone, Debug)]
+pub struct PooledBufSource {
+    /// The pool and its metrics, shared between clones.
+    inner: Arc<PoolInner>,
+}
+
+#[derive(Debug)]
+struct PoolInner {
+    /// The size of the buffers created by [`BufSource::create_buf`].
+    buf_size: usize,
+
+    /// The maximum number of idle buffers to keep.
+    max_pooled: usize,
+
+    /// The idle buffers available for reuse.
+    pool: Mutex<Vec<Vec<u8>>>,
+
+    /// The number of buffers that had to be newly allocated.
+    num_allocated: AtomicUsize,
+
+    /// The number of buffers that were taken from the pool.
+    num_reused: AtomicUsize,
+}
+
+impl PooledBufSource {
+    /// Creates a new source creating buffers of the given size.
+    #[must_use]
+    pub fn new(buf_size: usize) -> Self {
+        Self::with_max_pooled(buf_size, DEFAULT_MAX_POOLED)
+    }
+
+    /// Creates a new source keeping at most `max_pooled` idle buffers.
+    #[must_use]
+    pub fn with_max_pooled(buf_size: usize, max_pooled: usize) -> Self {
+        Self {
+            inner: Arc::new(PoolInner {
+                buf_size,
+                max_pooled,
+                pool: Mutex::new(Vec::new()),
+                num_allocated: AtomicUsize::new(0),
+                num_reused: AtomicUsize::new(0),
+            }),
+        }
+    }
+
+    /// Allocates up to `count` buffers and adds them to the pool.
+    ///
+    /// This avoids allocating buffers while serving the first requests.
+    /// The pool is not filled beyond its maximum number of idle buffers. The
+    /// buffers are counted as [newly allocated][Self::num_allocated].
+    pub fn prewarm(&self, count: usize) {
+        let Ok(mut pool) = self.inner.pool.lock() else {
+            return;
+        };
+        let count =
+            count.min(self.inner.max_pooled.saturating_sub(pool.len()));
+        for _ in 0..count {
+            pool.push(vec![0; self.inner.buf_size]);
+        }
+        self.inner.num_allocated.fetch_add(count, Ordering::Relaxed);
+    }
+
+    /// The number of buffers that had to be newly allocated.
+    pub fn num_allocated(&self) -> usize {
+        self.inner.num_allocated.load(Ordering::Relaxed)
+    }
+
+    /// The number of buffers that were reused from the pool.
+    pub fn num_reused(&self) -> usize {
+        self.inner.num_reused.load(Ordering::Relaxed)
+    }
+
+    /// The number of idle buffers currently in the pool.
+    pub fn num_pooled(&self) -> usize {
+        self.inner.pool.lock().map_or(0, |pool| pool.len())
+    }
+
+    /// Takes a buffer of the given size from the pool or allocates one.
+    fn take(&self, size: usize) -> Vec<u8> {
+        if size <= self.inner.buf_size {
+            let pooled =
+                self.inner.pool.lock().ok().and_then(|mut pool| pool.pop());
+            if let Some(mut buf) = pooled {
+                self.inner.num_reused.fetch_add(1, Ordering::Relaxed);
+                buf.resize(size, 0);
+                return buf;
+            }
+        }
+        self.inner.num_allocated.fetch_add(1, Ordering::Relaxed);
+        vec![0; size]
+    }
+}
+
+//--- Default
+
+impl Default for PooledBufSource {
+    fn default() -> Self {
+        Self::new(DEFAULT_BUF_SIZE)
+    }
+}
+
+//--- BufSource
+
+impl BufSource for PooledBufSource {
+    type Output = Vec<u8>;
+
+    fn create_buf(&self) -> Self::Output {
+        self.take(self.inner.buf_size)
+    }
+
+    fn create_sized(&self, size: usize) -> Self::Output {
+        self.take(size)
+    }
+
+    fn recycle(&self, buf: Self::Output) {
+        // Only keep buffers that can be reused without reallocating.
+        if buf.capacity() < self.inner.buf_size {
+            return;
+        }
+        if let Ok(mut pool) = self.inner.pool.lock() {
+            if pool.len() < self.inner.max_pooled {
+                pool.push(buf);
+            }
+        }
+    }
+}
+
+//============ Tests =========================================================
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::vec::Vec;
+
+    use super::{BufSource, PooledBufSource, UninitBufSource, VecBufSource};
+
+    //------------ Tests -----------------------------------------------------
+
+    #[test]
+    fn recycled_buffers_are_reused() {
+        let source = PooledBufSource::new(512);
+
+        for _ in 0..100 {
+            let buf = source.create_buf();
+            assert_eq!(buf.len(), 512);
+            source.recycle(buf);
+        }
+
+        assert_eq!(source.num_allocated(), 1);
+        assert_eq!(source.num_reused(), 99);
+        assert_eq!(source.num_pooled(), 1);
+
+        // Smaller buffers are served from the pool, larger ones are not.
+        let buf = source.create_sized(100);
+        assert_eq!(buf.len(), 100);
+        source.recycle(buf);
+        let buf = source.create_buf();
+        assert_eq!(buf.len(), 512);
+        assert_eq!(source.num_allocated(), 1);
+        let big = source.create_sized(4096);
+        assert_eq!(big.len(), 4096);
+        assert_eq!(source.num_allocated(), 2);
+
+        // Clones share the pool.
+        source.clone().recycle(buf);
+        assert_eq!(source.num_pooled(), 1);
+    }
+
+    #[test]
+    fn short_fill_exposes_only_filled_bytes() {
+        let source = UninitBufSource::new(512);
+        let (buf, res) = source
+            .create_filled(|buf| {
+                assert_eq!(buf.remaining(), 512);
+                buf.put_slice(b"short");
+                Ok(buf.filled().len())
+            })
+            .unwrap();
+        assert_eq!(res, 5);
+        assert_eq!(buf, b"short");
+        assert!(buf.capacity() >= 512);
+
+        // Replacing the read buffer doesn't fool us into exposing memory.
+        let (buf, ()) = source
+            .create_filled(|buf| {
+                let other = Vec::leak(vec![0u8; 16]);
+                *buf = tokio::io::ReadBuf::new(other);
+                buf.put_slice(b"elsewhere");
+                Ok(())
+            })
+            .unwrap();
+        assert!(buf.is_empty());
+
+        let err = source
+            .create_filled::<(), _>(|_| Err(io::ErrorKind::WouldBlock.into()))
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+
+        // Other sources return the whole, initialized, buffer.
+        let (buf, ()) = VecBufSource::default()
+            .create_filled(|buf| {
+                buf.put_slice(b"short");
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(buf.len(), 1024);
+        assert_eq!(&buf[..5], b"short");
+    }
+
+    #[test]
+    fn vec_buf_source_size_is_configurable() {
+        assert_eq!(VecBufSource::default().create_buf().len(), 1024);
+        assert_eq!(VecBufSource::default().buf_size(), 1024);
+        assert_eq!(
+            VecBufSource::with_capacity(4096).create_buf().len(),
+            4096
+        );
+        assert_eq!(VecBufSource::default().create_sized(100).len(), 100);
+    }
+
+    #[test]
+    fn pool_size_is_bounded() {
+        let source = PooledBufSource::with_max_pooled(64, 2);
+        let bufs: [_; 3] = core::array::from_fn(|_| source.create_buf());
+        bufs.into_iter().for_each(|buf| source.recycle(buf));
+        assert_eq!(source.num_pooled(), 2);
+
+        // Too small buffers are never pooled.
+        let source = PooledBufSource::new(64);
+        source.recycle(VecBufSource::default().create_sized(32));
+        assert_eq!(source.num_pooled(), 0);
+    }
+
+    #[test]
+    fn prewarmed_buffers_are_reused() {
+        let source = PooledBufSource::with_max_pooled(64, 3);
+        source.prewarm(2);
+        assert_eq!(source.num_pooled(), 2);
+        assert_eq!(source.num_allocated(), 2);
+
+        // Prewarming doesn't exceed the maximum number of idle buffers.
+        source.prewarm(5);
+        assert_eq!(source.num_pooled(), 3);
+        assert_eq!(source.num_allocated(), 3);
+
+        let bufs: [_; 3] = core::array::from_fn(|_| source.create_buf());
+        assert!(bufs.iter().all(|buf| buf.len() == 64));
+        assert_eq!(source.num_allocated(), 3);
+        assert_eq!(source.num_reused(), 3);
+        assert_eq!(source.num_pooled(), 0);
+    }
 }
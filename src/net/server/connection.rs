@@ -1,5 +1,5 @@
 //! Support for stream based connections.
-use core::ops::{ControlFlow, Deref};
+use core::ops::ControlFlow;
 use core::time::Duration;
 
 use std::fmt::Display;
@@ -14,7 +14,7 @@ use tokio::io::{
     AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadHalf, WriteHalf,
 };
 use tokio::sync::mpsc::error::TrySendError;
-use tokio::sync::{mpsc, watch};
+use tokio::sync::{broadcast, mpsc};
 use tokio::time::Instant;
 use tokio::time::{sleep_until, timeout};
 use tracing::Level;
@@ -24,15 +24,19 @@ use crate::base::message_builder::AdditionalBuilder;
 use crate::base::wire::Composer;
 use crate::base::{Message, StreamTarget};
 use crate::net::server::buf::BufSource;
-use crate::net::server::message::Request;
+use crate::net::server::message::{CancellationToken, Request};
 use crate::net::server::metrics::ServerMetrics;
-use crate::net::server::service::{Service, ServiceFeedback};
-use crate::net::server::util::to_pcap_text;
+use crate::net::server::service::{CallResult, Service, ServiceFeedback};
+use crate::net::server::util::{
+    call_with_deadline, compress_response, mk_error_response,
+    mk_timeout_response, to_pcap_text, CompressionMode,
+};
 use crate::utils::config::DefMinMax;
 
+use super::backpressure::Backpressure;
 use super::message::{NonUdpTransportContext, TransportSpecificContext};
 use super::stream::Config as ServerConfig;
-use super::ServerCommand;
+use super::{CommandReceiver, ServerCommand};
 
 /// Limit on the amount of time to allow between client requests.
 ///
@@ -84,6 +88,14 @@ const RESPONSE_WRITE_TIMEOUT: DefMinMax<Duration> = DefMinMax::new(
 /// becomes available.
 const MAX_QUEUED_RESPONSES: DefMinMax<usize> = DefMinMax::new(10, 0, 1024);
 
+/// Limit on the size of a DNS request message read from the client.
+///
+/// The value has to be between 12 bytes, the size of a DNS message header,
+/// and 65,535 bytes, the largest size that can be expressed by the two byte
+/// length prefix of a DNS message on a stream. The default is 65,535 bytes,
+/// i.e. no limit.
+const MAX_REQUEST_SIZE: DefMinMax<u16> = DefMinMax::new(65535, 12, 65535);
+
 //----------- Config ---------------------------------------------------------
 
 /// Configuration for a stream server connection.
@@ -115,6 +127,15 @@ pub struct Config {
 
     /// Limit on the number of DNS responses queued for writing to the client.
     max_queued_responses: usize,
+
+    /// Limit on the size of a DNS request message read from the client.
+    max_request_size: u16,
+
+    /// When to compress responses.
+    compression_mode: CompressionMode,
+
+    /// The time allowed for processing a request, if limited.
+    request_timeout: Option<Duration>,
 }
 
 impl Config {
@@ -194,6 +215,84 @@ impl Config {
     pub fn set_max_queued_responses(&mut self, value: usize) {
         self.max_queued_responses = value;
     }
+
+    /// Set the limit on the size of a DNS request message read from the
+    /// client.
+    ///
+    /// The value has to be between 12 bytes, the size of a DNS message
+    /// header, and 65,535 bytes. The default value is 65,535 bytes, i.e. no
+    /// limit.
+    ///
+    /// Each request is read into a buffer sized according to the length that
+    /// precedes the message on the stream before it is parsed. The length is
+    /// checked against this limit before the buffer is allocated. If it
+    /// exceeds the limit the connection is closed with
+    /// [`CloseReason::MessageTooLarge`] once any pending responses have been
+    /// written, as the client would otherwise first have to be allowed to
+    /// send the entire oversized message.
+    ///
+    /// Messages are not parsed incrementally as they arrive because name
+    /// compression allows any part of a message to refer back to any earlier
+    /// part, so the whole message is needed to parse it anyway. Note though
+    /// that a buffer only exists while a message is being received and
+    /// processed and is only as large as that message, so idle connections
+    /// do not hold on to a buffer. Lowering this limit therefore mainly
+    /// protects against clients deliberately sending large messages, e.g.
+    /// many clients each sending a large UPDATE very slowly. Queries are
+    /// rarely larger than a few hundred bytes, but the limit should be set
+    /// with care if large UPDATE or NOTIFY messages, or queries with large
+    /// EDNS options, are expected.
+    ///
+    /// # Reconfigure
+    ///
+    /// On [`StreamServer::reconfigure`] the new limit only applies to
+    /// messages whose length has not yet been read.
+    ///
+    /// [`StreamServer::reconfigure`]:
+    ///     super::stream::StreamServer::reconfigure()
+    pub fn set_max_request_size(&mut self, value: u16) {
+        self.max_request_size = value;
+    }
+
+    /// Sets when to apply domain name compression to responses.
+    ///
+    /// The default is [`CompressionMode::Never`], i.e. responses are sent
+    /// exactly as produced by the [`Service`].
+    ///
+    /// # Reconfigure
+    ///
+    /// On [`StreamServer::reconfigure`] any responses currently being
+    /// written will NOT be affected, the new mode will only apply to
+    /// responses that start being sent after the mode is changed.
+    ///
+    /// [`StreamServer::reconfigure`]:
+    ///     super::stream::StreamServer::reconfigure()
+    pub fn set_compression_mode(&mut self, value: CompressionMode) {
+        self.compression_mode = value;
+    }
+
+    /// Set the time allowed for processing a request.
+    ///
+    /// If set, the [deadline] of each request is set to the time it was
+    /// received plus this timeout. If the [`Service`] has not produced a
+    /// response by then, its processing of the request is aborted and the
+    /// client is sent a SERVFAIL response with an Extended DNS Error instead
+    /// of having to wait for its own timeout. Middleware can override the
+    /// deadline of individual requests.
+    ///
+    /// The default value is `None`, i.e. processing time is not limited.
+    ///
+    /// # Reconfigure
+    ///
+    /// On [`StreamServer::reconfigure`] only requests received after this
+    /// setting is changed will use the new value.
+    ///
+    /// [deadline]: super::message::RequestDeadline
+    /// [`StreamServer::reconfigure`]:
+    ///     super::stream::StreamServer::reconfigure()
+    pub fn set_request_timeout(&mut self, value: Option<Duration>) {
+        self.request_timeout = value;
+    }
 }
 
 //--- Default
@@ -204,6 +303,9 @@ impl Default for Config {
             idle_timeout: IDLE_TIMEOUT.default(),
             response_write_timeout: RESPONSE_WRITE_TIMEOUT.default(),
             max_queued_responses: MAX_QUEUED_RESPONSES.default(),
+            max_request_size: MAX_REQUEST_SIZE.default(),
+            compression_mode: CompressionMode::default(),
+            request_timeout: None,
         }
     }
 }
@@ -252,11 +354,11 @@ where
 
     /// The reader for consuming from the queue of responses waiting to be
     /// written back to the client.
-    result_q_rx: mpsc::Receiver<AdditionalBuilder<StreamTarget<Svc::Target>>>,
+    result_q_rx: mpsc::Receiver<QueuedResponse<Svc::Target>>,
 
     /// The writer for pushing ready responses onto the queue waiting
     /// to be written back the client.
-    result_q_tx: mpsc::Sender<AdditionalBuilder<StreamTarget<Svc::Target>>>,
+    result_q_tx: mpsc::Sender<QueuedResponse<Svc::Target>>,
 
     /// A [`Service`] for handling received requests and generating responses.
     service: Svc,
@@ -266,6 +368,13 @@ where
 
     /// [`ServerMetrics`] describing the status of the server.
     metrics: Arc<ServerMetrics>,
+
+    /// Whether the [`Service`] asked for reading requests to be paused.
+    backpressure: Arc<Backpressure>,
+
+    /// Cancelled when the connection is closed, aborting the processing of
+    /// any requests received on it that is still in progress.
+    cancellation: CancellationToken,
 }
 
 /// Creation
@@ -334,6 +443,8 @@ where
             service,
             idle_timer,
             metrics,
+            backpressure: Default::default(),
+            cancellation: CancellationToken::new(),
         }
     }
 }
@@ -346,7 +457,7 @@ where
     Buf: BufSource + Send + Sync + Clone + 'static,
     Buf::Output: Octets + Send + Sync + Unpin,
     Svc: Service<Buf::Output> + Clone + Send + Sync + 'static,
-    Svc::Target: Composer + Send,
+    Svc::Target: Composer + Default + Send,
     Svc::Stream: Send,
 {
     /// Start reading requests and writing responses to the stream.
@@ -357,13 +468,15 @@ where
     /// connections will also see the [`ServerCommand::Shutdown`] signal and
     /// shutdown and flush any pending writes to the output stream.
     ///
-    /// Any requests received after the shutdown signal or requests still
-    /// in-flight will continue processing and then fail to queue the response
-    /// for writing.
+    /// When the connection is closed the [`CancellationToken`] of any
+    /// requests still in-flight is cancelled, aborting their processing.
+    ///
+    /// Returns the reason the connection was closed.
     pub async fn run(
         mut self,
-        command_rx: watch::Receiver<ServerCommand<ServerConfig>>,
-    ) where
+        command_rx: CommandReceiver<ServerConfig>,
+    ) -> CloseReason
+    where
         Svc::Future: Send,
     {
         self.metrics.inc_num_connections();
@@ -371,7 +484,7 @@ where
         // Flag that we have to decrease the metric count on Drop.
         self.active = true;
 
-        self.run_until_error(command_rx).await;
+        self.run_until_error(command_rx).await
     }
 }
 
@@ -383,15 +496,15 @@ where
     Buf: BufSource + Send + Sync + Clone + 'static,
     Buf::Output: Octets + Send + Sync + Unpin,
     Svc: Service<Buf::Output> + Clone + Send + Sync + 'static,
-    Svc::Target: Composer + Send,
+    Svc::Target: Composer + Default + Send,
     Svc::Future: Send,
     Svc::Stream: Send,
 {
     /// Connection handler main loop.
     async fn run_until_error(
         mut self,
-        mut command_rx: watch::Receiver<ServerCommand<ServerConfig>>,
-    ) {
+        mut command_rx: CommandReceiver<ServerConfig>,
+    ) -> CloseReason {
         // SAFETY: This unwrap is safe because we always put a Some value into
         // self.stream_rx in [`Self::with_config`] above (and thus also in
         // [`Self::new`] which calls [`Self::with_config`]).
@@ -400,22 +513,37 @@ where
         let mut dns_msg_receiver =
             DnsMessageReceiver::new(self.buf.clone(), stream_rx);
 
-        'outer: loop {
+        let reason = 'outer: loop {
             // Create a read future that will survive when other
             // tokio::select! branches resolve before the branch awaiting this
             // future resolves. This ensures that in-progress non-cancel-safe
             // reads do not get cancelled. This works because it
             // avoids creating a new future each time as would happen if we
             // called transceive() in a tokio::select! branch.
-            let msg_recv = dns_msg_receiver.recv();
+            let max_request_size = self.config.load().max_request_size;
+            let msg_recv = dns_msg_receiver.recv(max_request_size.into());
             tokio::pin!(msg_recv);
 
             'inner: loop {
+                let paused_until = self.backpressure.paused_until();
+
                 let res = tokio::select! {
                     biased;
 
-                    res = command_rx.changed() => {
-                        self.process_server_command(res, &mut command_rx)
+                    res = command_rx.recv() => {
+                        self.process_server_command(res)
+                    }
+
+                    // Re-evaluate whether to read when the service asks for
+                    // reading to be paused.
+                    _ = self.backpressure.extended() => {
+                        Ok(())
+                    }
+
+                    // Resume reading once the pause has ended.
+                    _ = sleep_until(paused_until.unwrap_or_else(Instant::now)), if paused_until.is_some() => {
+                        trace!("Resuming reading after backpressure");
+                        Ok(())
                     }
 
                     res = self.result_q_rx.recv() => {
@@ -426,7 +554,7 @@ where
                         self.process_dns_idle_timeout()
                     }
 
-                    res = &mut msg_recv => {
+                    res = &mut msg_recv, if paused_until.is_none() => {
                         let res = self.process_read_request(res).await;
                         if res.is_ok() {
                             // Set up to receive another message
@@ -439,62 +567,57 @@ where
 
                 if let Err(err) = res {
                     match err {
-                        ConnectionEvent::DisconnectWithoutFlush => {
-                            break 'outer;
+                        ConnectionEvent::DisconnectWithoutFlush(reason) => {
+                            break 'outer reason;
                         }
-                        ConnectionEvent::DisconnectWithFlush => {
+                        ConnectionEvent::DisconnectWithFlush(reason) => {
                             self.flush_write_queue().await;
-                            break 'outer;
+                            break 'outer reason;
                         }
                     }
                 }
             }
-        }
+        };
 
         trace!("Shutting down the write stream.");
         if let Err(err) = self.stream_tx.shutdown().await {
             warn!("Error while shutting down the write stream: {err}");
         }
-        trace!("Connection terminated.");
+        trace!("Connection terminated: {reason}");
 
         #[cfg(test)]
         if dns_msg_receiver.cancelled() {
             panic!("Async not-cancel-safe code was cancelled");
         }
+
+        reason
     }
 
     /// Decide what to do with a received [`ServerCommand`].
     fn process_server_command(
         &mut self,
-        res: Result<(), watch::error::RecvError>,
-        command_rx: &mut watch::Receiver<ServerCommand<ServerConfig>>,
+        res: Result<ServerCommand<ServerConfig>, broadcast::error::RecvError>,
     ) -> Result<(), ConnectionEvent> {
-        // If the parent server no longer exists but was not cleanly shutdown
-        // then the command channel will be closed and attempting to check for
-        // a new command will fail. Advise the caller to break the connection
-        // and cleanup if such a problem occurs.
-        res.map_err(|_err| ConnectionEvent::DisconnectWithFlush)?;
-
-        // Get the changed command.
-        let lock = command_rx.borrow_and_update();
-        let command = lock.deref();
+        let command = match res {
+            Ok(command) => command,
+
+            // If the parent server no longer exists but was not cleanly
+            // shutdown then the command channel will be closed and
+            // attempting to check for a new command will fail. Advise the
+            // caller to break the connection and cleanup if such a problem
+            // occurs.
+            Err(_err) => {
+                return Err(ConnectionEvent::DisconnectWithFlush(
+                    CloseReason::Shutdown,
+                ))
+            }
+        };
 
         // And process it.
         match command {
-            ServerCommand::Init => {
-                // The initial "Init" value in the watch channel is never
-                // actually seen because changed() is required to return true
-                // before we call borrow_and_update() but the initial value in
-                // the channel, Init, is not considered a "change". So the
-                // only way to end up here would be if we somehow wrongly
-                // placed another ServerCommand::Init value into the watch
-                // channel after the initial one.
-                unreachable!()
-            }
-
             ServerCommand::CloseConnection => {
                 // TODO: Should we flush in this case or not?
-                return Err(ConnectionEvent::DisconnectWithFlush);
+                return Err(ConnectionEvent::DisconnectWithFlush(CloseReason::Requested));
             }
 
             ServerCommand::Reconfigure(ServerConfig {
@@ -513,10 +636,12 @@ where
                 // mechanism to signal to us that we should adjust the point
                 // at which we will consider the connectin to be idle and thus
                 // potentially worthy of timing out.
-                self.config.store(Arc::new(*connection_config));
+                self.config.store(Arc::new(connection_config));
             }
 
-            ServerCommand::Shutdown => {
+            ServerCommand::Shutdown
+            | ServerCommand::Terminate
+            | ServerCommand::Drain(_) => {
                 // The parent server has been shutdown. Close this connection
                 // but ensure that we write any pending responses to the
                 // stream first.
@@ -525,7 +650,7 @@ where
                 // complete before shutting down? And if so how should we
                 // respond to any requests received in the meantime? Should we
                 // even stop reading from the stream?
-                return Err(ConnectionEvent::DisconnectWithFlush);
+                return Err(ConnectionEvent::DisconnectWithFlush(CloseReason::Shutdown));
             }
         }
 
@@ -542,10 +667,9 @@ where
         trace!("Stop queueing up new results.");
         self.result_q_rx.close();
         trace!("Process already queued results.");
-        while let Some(response) = self.result_q_rx.recv().await {
+        while let Some(queued) = self.result_q_rx.recv().await {
             trace!("Processing queued result.");
-            if let Err(err) = self.process_queued_result(Some(response)).await
-            {
+            if let Err(err) = self.process_queued_result(Some(queued)).await {
                 warn!("Error while processing queued result: {err}");
             } else {
                 trace!("Result processed");
@@ -557,7 +681,7 @@ where
     /// Process a single queued response.
     async fn process_queued_result(
         &mut self,
-        response: Option<AdditionalBuilder<StreamTarget<Svc::Target>>>,
+        queued: Option<QueuedResponse<Svc::Target>>,
     ) -> Result<(), ConnectionEvent> {
         // If we failed to read the results of requests processed by the
         // service because the queue holding those results is empty and can no
@@ -565,32 +689,50 @@ where
         // the input stream because we will not be able to access the result
         // of processing the request. I'm not sure when this could happen,
         // perhaps if we were dropped?
-        let Some(response) = response else {
+        let Some((response, received_at)) = queued else {
             trace!("Disconnecting due to failed response queue read.");
-            return Err(ConnectionEvent::DisconnectWithFlush);
+            return Err(ConnectionEvent::DisconnectWithFlush(
+                CloseReason::Error,
+            ));
         };
 
         trace!(
             "Writing queued response with id {} to stream",
             response.header().id()
         );
-        self.write_response_to_stream(response.finish()).await
+        self.write_response_to_stream(response.finish(), received_at)
+            .await
     }
 
     /// Write a response back to the caller over the network stream.
+    ///
+    /// If `received_at` is given the time since then is recorded as the
+    /// latency of the request.
     async fn write_response_to_stream(
         &mut self,
         msg: StreamTarget<Svc::Target>,
+        received_at: Option<Instant>,
     ) -> Result<(), ConnectionEvent> {
+        let compressed = compress_response(
+            msg.as_dgram_slice(),
+            self.config.load().compression_mode,
+        );
+        let (dgram_slice, stream_slice) = match &compressed {
+            Some(compressed) => {
+                (compressed.as_dgram_slice(), compressed.as_stream_slice())
+            }
+            None => (msg.as_dgram_slice(), msg.as_stream_slice()),
+        };
+
         if enabled!(Level::TRACE) {
-            let bytes = msg.as_dgram_slice();
+            let bytes = dgram_slice;
             let pcap_text = to_pcap_text(bytes, bytes.len());
             trace!(addr = %self.addr, pcap_text, "Sending response");
         }
 
         match timeout(
             self.config.load().response_write_timeout,
-            self.stream_tx.write_all(msg.as_stream_slice()),
+            self.stream_tx.write_all(stream_slice),
         )
         .await
         {
@@ -599,14 +741,21 @@ where
                     "Write timed out (>{:?})",
                     self.config.load().response_write_timeout
                 );
-                return Err(ConnectionEvent::DisconnectWithoutFlush);
+                return Err(ConnectionEvent::DisconnectWithoutFlush(
+                    CloseReason::Error,
+                ));
             }
             Ok(Err(err)) => {
                 error!("Write error: {err}");
-                return Err(ConnectionEvent::DisconnectWithoutFlush);
+                return Err(ConnectionEvent::DisconnectWithoutFlush(
+                    CloseReason::Error,
+                ));
             }
             Ok(Ok(_)) => {
                 self.metrics.inc_num_sent_responses();
+                if let Some(received_at) = received_at {
+                    self.metrics.record_latency(received_at.elapsed());
+                }
             }
         }
 
@@ -629,7 +778,9 @@ where
             .idle_timer
             .idle_timeout_expired(self.config.load().idle_timeout)
         {
-            Err(ConnectionEvent::DisconnectWithoutFlush)
+            Err(ConnectionEvent::DisconnectWithoutFlush(
+                CloseReason::IdleTimeout,
+            ))
         } else {
             Ok(())
         }
@@ -663,7 +814,9 @@ where
                         tracing::warn!(
                             "Failed while parsing request message: {err}"
                         );
-                        return Err(ConnectionEvent::DisconnectWithoutFlush);
+                        return Err(ConnectionEvent::DisconnectWithoutFlush(
+                            CloseReason::Error,
+                        ));
                     }
 
                     // https://datatracker.ietf.org/doc/html/rfc1035#section-4.1.1
@@ -686,29 +839,75 @@ where
                             msg,
                             ctx,
                             (),
-                        );
+                        )
+                        .with_cancellation_token(self.cancellation.clone());
+                        if let Some(timeout) =
+                            self.config.load().request_timeout
+                        {
+                            request
+                                .deadline()
+                                .set(Some(received_at + timeout));
+                        }
 
                         let svc = self.service.clone();
                         let result_q_tx = self.result_q_tx.clone();
                         let metrics = self.metrics.clone();
                         let config = self.config.clone();
+                        let backpressure = self.backpressure.clone();
 
                         trace!(
                             "Spawning task to handle new message with id {}",
                             request.message().header().id()
                         );
-                        tokio::spawn(async move {
-                            let request_id = request.message().header().id();
+                        let request_id = request.message().header().id();
+                        let cancellation = self.cancellation.clone();
+                        let process = async move {
                             trace!(
                                 "Calling service for request id {request_id}"
                             );
-                            let mut stream = svc.call(request).await;
+                            let msg = request.message().clone();
+                            let Some(mut stream) =
+                                call_with_deadline(&svc, request).await
+                            else {
+                                debug!("Processing of request id {request_id} timed out, answering with SERVFAIL");
+                                let response = mk_timeout_response(&msg);
+                                match result_q_tx
+                                    .try_send((response, Some(received_at)))
+                                {
+                                    Ok(()) => {
+                                        metrics.set_num_pending_writes(
+                                            result_q_tx.max_capacity()
+                                                - result_q_tx.capacity(),
+                                        );
+                                    }
+                                    Err(err) => {
+                                        error!("Unable to queue message for sending: {err}");
+                                    }
+                                }
+                                return;
+                            };
                             let mut in_transaction = false;
 
+                            // Only the first response to a request counts
+                            // towards the request latency.
+                            let mut received_at = Some(received_at);
+
                             trace!("Awaiting service call results for request id {request_id}");
-                            while let Some(Ok(call_result)) =
-                                stream.next().await
-                            {
+                            while let Some(item) = stream.next().await {
+                                // Answer a failed service call with the
+                                // RCODE of the error and stop processing the
+                                // request.
+                                let (call_result, failed) = match item {
+                                    Ok(call_result) => (call_result, false),
+                                    Err(err) => {
+                                        debug!("Service failed for request id {request_id}: {err}, answering with {}", err.rcode());
+                                        let response = mk_error_response(
+                                            &msg,
+                                            err.rcode().into(),
+                                        );
+                                        (CallResult::new(response), true)
+                                    }
+                                };
                                 trace!("Processing service call result for request id {request_id}");
                                 let (response, feedback) =
                                     call_result.into_inner();
@@ -741,12 +940,20 @@ where
                                         ServiceFeedback::EndTransaction => {
                                             in_transaction = false;
                                         }
+
+                                        ServiceFeedback::ApplyBackpressure {
+                                            until,
+                                        } => {
+                                            backpressure.apply(until);
+                                        }
                                     }
                                 }
 
-                                if let Some(mut response) = response {
+                                if let Some(response) = response {
+                                    let mut queued =
+                                        (response, received_at.take());
                                     loop {
-                                        match result_q_tx.try_send(response) {
+                                        match result_q_tx.try_send(queued) {
                                             Ok(()) => {
                                                 let pending_writes =
                                                     result_q_tx
@@ -773,8 +980,7 @@ where
                                                     // Wait until there is space in the message queue.
                                                     tokio::task::yield_now()
                                                         .await;
-                                                    response =
-                                                        unused_response;
+                                                    queued = unused_response;
                                                 } else {
                                                     error!("Unable to queue message for sending: queue is full.");
                                                     return;
@@ -783,8 +989,34 @@ where
                                         }
                                     }
                                 }
+
+                                if failed {
+                                    break;
+                                }
+                            }
+                            // A transaction only spans the responses of a
+                            // single stream. Once the stream ends the
+                            // connection is ready for the next request
+                            // whether or not the service ended it properly.
+                            if in_transaction {
+                                warn!("Response stream for request id {request_id} ended without ending its transaction");
                             }
                             trace!("Finished processing service call results for request id {request_id}");
+                        };
+
+                        // Stop processing the request, by dropping the
+                        // service future and stream, if the connection is
+                        // closed before processing completes.
+                        tokio::spawn(async move {
+                            tokio::select! {
+                                biased;
+
+                                _ = cancellation.cancelled() => {
+                                    trace!("Abandoned processing of request id {request_id}: connection closed");
+                                }
+
+                                _ = process => {}
+                            }
                         });
                     }
                 }
@@ -806,6 +1038,7 @@ where
     Svc: Service<Buf::Output> + Clone,
 {
     fn drop(&mut self) {
+        self.cancellation.cancel();
         if self.active {
             self.active = false;
             self.metrics.dec_num_connections();
@@ -884,12 +1117,18 @@ where
         self.cancelled
     }
 
-    /// Receive a single DNS message.
+    /// Receive a single DNS message of at most the given size.
+    ///
+    /// No buffer is allocated for a message that exceeds the given size,
+    /// instead the connection should be closed.
     ///
     /// # Cancel safety
     ///
     /// This function is NOT cancel safe.
-    pub async fn recv(&mut self) -> Result<Buf::Output, ConnectionEvent> {
+    pub async fn recv(
+        &mut self,
+        max_msg_len: usize,
+    ) -> Result<Buf::Output, ConnectionEvent> {
         #[cfg(test)]
         if self.status == Status::WaitingForMessageBody {
             self.cancelled = true;
@@ -900,6 +1139,12 @@ where
             .await?;
 
         let msg_len = u16::from_be_bytes(self.msg_size_buf) as usize;
+        if msg_len > max_msg_len {
+            debug!("Request of {msg_len} bytes exceeds the limit of {max_msg_len} bytes");
+            return Err(ConnectionEvent::DisconnectWithFlush(
+                CloseReason::MessageTooLarge,
+            ));
+        }
         let mut msg_buf = self.buf.create_sized(msg_len);
 
         self.status = Status::WaitingForMessageBody;
@@ -941,7 +1186,9 @@ where
             io::ErrorKind::UnexpectedEof => {
                 // The client disconnected. Per RFC 7766 6.2.4 pending
                 // responses MUST NOT be sent to the client.
-                ControlFlow::Break(ConnectionEvent::DisconnectWithoutFlush)
+                ControlFlow::Break(ConnectionEvent::DisconnectWithoutFlush(
+                    CloseReason::PeerClosed,
+                ))
             }
             io::ErrorKind::TimedOut | io::ErrorKind::Interrupted => {
                 // These errors might be recoverable, try again.
@@ -952,12 +1199,72 @@ where
                 // the time of writing and so we can't guess how to handle it,
                 // so abort.
                 error!("I/O error: {}", err);
-                ControlFlow::Break(ConnectionEvent::DisconnectWithoutFlush)
+                let reason = match err.kind() {
+                    io::ErrorKind::ConnectionReset
+                    | io::ErrorKind::ConnectionAborted
+                    | io::ErrorKind::BrokenPipe => CloseReason::PeerClosed,
+                    _ => CloseReason::Error,
+                };
+                ControlFlow::Break(ConnectionEvent::DisconnectWithoutFlush(
+                    reason,
+                ))
             }
         }
     }
 }
 
+//------------ QueuedResponse ------------------------------------------------
+
+/// A response waiting to be written back to the client.
+///
+/// The response is accompanied by the time at which the request it responds
+/// to was received, if it is the first response to that request.
+type QueuedResponse<Target> =
+    (AdditionalBuilder<StreamTarget<Target>>, Option<Instant>);
+
+//------------ CloseReason ---------------------------------------------------
+
+/// Why a connection was closed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CloseReason {
+    /// The connection was idle for longer than the configured idle timeout.
+    IdleTimeout,
+
+    /// The client closed or reset the connection.
+    PeerClosed,
+
+    /// An error occurred while reading from or writing to the connection, or
+    /// the client sent a message that could not be parsed.
+    Error,
+
+    /// The server was shutdown.
+    Shutdown,
+
+    /// Closing the connection was requested via
+    /// [`ServerCommand::CloseConnection`].
+    Requested,
+
+    /// The client sent a message larger than the configured limit.
+    ///
+    /// See [`Config::set_max_request_size`].
+    MessageTooLarge,
+}
+
+//--- Display
+
+impl Display for CloseReason {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            CloseReason::IdleTimeout => write!(f, "idle timeout"),
+            CloseReason::PeerClosed => write!(f, "closed by peer"),
+            CloseReason::Error => write!(f, "error"),
+            CloseReason::Shutdown => write!(f, "server shutdown"),
+            CloseReason::Requested => write!(f, "close requested"),
+            CloseReason::MessageTooLarge => write!(f, "message too large"),
+        }
+    }
+}
+
 //------------ ConnectionEvent -----------------------------------------------
 
 /// An event that occurred while the connection handler was handling the
@@ -972,14 +1279,14 @@ enum ConnectionEvent {
     /// And: RFC 7766 3 "A DNS server considers an established DNS-over-TCP
     /// session to be idle when it has sent responses to all the queries it
     /// has received on that connection."
-    DisconnectWithoutFlush,
+    DisconnectWithoutFlush(CloseReason),
 
     /// RFC 7766 6.2.3 "If a DNS server finds that a DNS client has closed a
     /// TCP session (or if the session has been otherwise interrupted) before
     /// all pending responses have been sent, then the server MUST NOT attempt
     /// to send those responses.  Of course, the DNS server MAY cache those
     /// responses."
-    DisconnectWithFlush,
+    DisconnectWithFlush(CloseReason),
 }
 
 //--- Display
@@ -987,11 +1294,11 @@ enum ConnectionEvent {
 impl Display for ConnectionEvent {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         match self {
-            ConnectionEvent::DisconnectWithoutFlush => {
-                write!(f, "Disconnect without flush")
+            ConnectionEvent::DisconnectWithoutFlush(reason) => {
+                write!(f, "Disconnect without flush ({reason})")
             }
-            ConnectionEvent::DisconnectWithFlush => {
-                write!(f, "Disconnect with flush")
+            ConnectionEvent::DisconnectWithFlush(reason) => {
+                write!(f, "Disconnect with flush ({reason})")
             }
         }
     }
@@ -10,44 +10,54 @@
 //!
 //! [Datagram]: https://en.wikipedia.org/wiki/Datagram
 use core::fmt::Debug;
-use core::future::poll_fn;
-use core::ops::Deref;
+use core::future::{pending, poll_fn};
+use core::hash::{BuildHasher, Hash, Hasher};
+use core::ops::ControlFlow;
 use core::time::Duration;
 
+use std::collections::hash_map::RandomState;
+use std::collections::HashSet;
 use std::io;
-use std::net::SocketAddr;
-use std::string::String;
-use std::string::ToString;
+use std::net::{IpAddr, SocketAddr};
 use std::sync::{Arc, Mutex};
+use std::vec::Vec;
 
 use arc_swap::ArcSwap;
+use bytes::Bytes;
 use futures_util::stream::StreamExt;
 use octseq::Octets;
-use tokio::io::ReadBuf;
 use tokio::net::UdpSocket;
-use tokio::sync::watch;
+use tokio::sync::{broadcast, mpsc, watch};
+use tokio::task::JoinSet;
 use tokio::time::interval;
+use tokio::time::sleep_until;
 use tokio::time::timeout;
 use tokio::time::Instant;
 use tokio::time::MissedTickBehavior;
 use tracing::warn;
 use tracing::Level;
-use tracing::{enabled, error, trace};
+use tracing::{debug, enabled, error, trace};
 
 use crate::base::wire::Composer;
-use crate::base::Message;
+use crate::base::{Message, Name, Question, ToName};
 use crate::net::server::buf::BufSource;
-use crate::net::server::error::Error;
-use crate::net::server::message::Request;
+use crate::net::server::error::{Error, ServerError};
+use crate::net::server::message::{CancellationToken, Request};
 use crate::net::server::metrics::ServerMetrics;
-use crate::net::server::service::{Service, ServiceFeedback};
-use crate::net::server::sock::AsyncDgramSock;
-use crate::net::server::util::to_pcap_text;
+use crate::net::server::service::{CallResult, Service, ServiceFeedback};
+#[cfg(unix)]
+use crate::net::server::sock::UnixDgramSock;
+use crate::net::server::sock::{AsyncDgramSock, PeerAddr};
+use crate::net::server::util::{
+    call_with_deadline, compress_response, mk_error_response,
+    mk_timeout_response, to_pcap_text, CompressionMode,
+};
 use crate::utils::config::DefMinMax;
 
+use super::backpressure::Backpressure;
 use super::buf::VecBufSource;
 use super::message::{TransportSpecificContext, UdpTransportContext};
-use super::ServerCommand;
+use super::{CommandReceiver, CommandSender, ServerCommand};
 
 /// A UDP transport based DNS server transport.
 ///
@@ -57,6 +67,15 @@ use super::ServerCommand;
 /// used to implement a UDP based DNS server.
 pub type UdpServer<Svc> = DgramServer<UdpSocket, VecBufSource, Svc>;
 
+/// A Unix domain socket based DNS server transport.
+///
+/// This type defines a type of [`DgramServer`] that expects datagrams to be
+/// received via a Unix domain socket wrapped in a [`UnixDgramSock`] and can
+/// thus be used to serve DNS to other processes on the same host. See
+/// [`UnixDgramSock`] for how peers are identified.
+#[cfg(unix)]
+pub type UnixDgramServer<Svc> = DgramServer<UnixDgramSock, VecBufSource, Svc>;
+
 /// Limit the time to wait for a complete message to be written to the client.
 ///
 /// The value has to be between 1ms and 60 seconds. The default value is 5
@@ -89,6 +108,21 @@ pub struct Config {
 
     /// Limit the time to wait for a complete message to be written to the client.
     write_timeout: Duration,
+
+    /// When to compress responses.
+    compression_mode: CompressionMode,
+
+    /// The maximum number of in-flight requests to track in order to drop
+    /// duplicates, or zero to not drop duplicates.
+    max_tracked_requests: usize,
+
+    /// Whether to honor the destination of a [`CallResult`].
+    ///
+    /// [`CallResult`]: super::service::CallResult
+    allow_response_redirection: bool,
+
+    /// The time allowed for processing a request, if limited.
+    request_timeout: Option<Duration>,
 }
 
 impl Config {
@@ -135,6 +169,93 @@ impl Config {
     pub fn set_write_timeout(&mut self, value: Duration) {
         self.write_timeout = value;
     }
+
+    /// Sets when to apply domain name compression to responses.
+    ///
+    /// The default is [`CompressionMode::Never`], i.e. responses are sent
+    /// exactly as produced by the [`Service`].
+    ///
+    /// # Reconfigure
+    ///
+    /// On [`DgramServer::reconfigure`]` any change to this setting will only
+    /// affect requests received after the setting is changed, in progress
+    /// requests will be unaffected.
+    pub fn set_compression_mode(&mut self, value: CompressionMode) {
+        self.compression_mode = value;
+    }
+
+    /// Sets the maximum number of in-flight requests tracked to drop
+    /// duplicates.
+    ///
+    /// Clients sometimes retransmit a query before the response to the
+    /// original query has been sent. If enabled, a request from the same
+    /// client address with the same message ID and question as a request
+    /// still being processed is dropped, as the response to the original
+    /// request will answer it, and counted in
+    /// [`ServerMetrics::num_suppressed_duplicates`].
+    ///
+    /// At most `value` requests are tracked at a time, requests received
+    /// while that many are in flight are processed without being checked.
+    /// Requests are no longer tracked once their processing completes.
+    ///
+    /// The default value is zero which disables dropping duplicates.
+    ///
+    /// # Reconfigure
+    ///
+    /// On [`DgramServer::reconfigure`]` any change to this setting will only
+    /// affect requests received after the setting is changed.
+    pub fn set_duplicate_suppression(&mut self, value: usize) {
+        self.max_tracked_requests = value;
+    }
+
+    /// Sets whether responses may be sent to an address other than the
+    /// client.
+    ///
+    /// If enabled, a response whose [`CallResult`] has a destination set via
+    /// [`CallResult::with_destination()`] is sent to that destination instead
+    /// of to the client the request was received from. This is an advanced
+    /// feature meant for relays and test harnesses. Read the security notes
+    /// of [`CallResult::with_destination()`] before enabling it.
+    ///
+    /// The default is `false`, in which case any destination is ignored and
+    /// responses are always sent to the client.
+    ///
+    /// # Reconfigure
+    ///
+    /// On [`DgramServer::reconfigure`]` any change to this setting will only
+    /// affect responses sent after the setting is changed.
+    ///
+    /// [`CallResult`]: super::service::CallResult
+    /// [`CallResult::with_destination()`]:
+    ///     super::service::CallResult::with_destination
+    pub fn set_allow_response_redirection(&mut self, value: bool) {
+        self.allow_response_redirection = value;
+    }
+
+    /// Sets the time allowed for processing a request.
+    ///
+    /// If set, the [deadline] of each request is set to the time it was
+    /// received plus this timeout. If the [`Service`] has not produced a
+    /// response by then, its processing of the request is aborted and the
+    /// client is sent a SERVFAIL response with an Extended DNS Error instead
+    /// of having to wait for its own timeout. Middleware can override the
+    /// deadline of individual requests.
+    ///
+    /// As the aborted processing includes any middleware the [`Service`] is
+    /// composed of, the SERVFAIL response is not post-processed by it. In
+    /// particular, it is not signed for TSIG signed requests.
+    ///
+    /// The default value is `None`, i.e. processing time is not limited.
+    ///
+    /// # Reconfigure
+    ///
+    /// On [`DgramServer::reconfigure`]` any change to this setting will only
+    /// affect requests received after the setting is changed.
+    ///
+    /// [deadline]: super::message::RequestDeadline
+    pub fn set_request_timeout(&mut self, value: Option<Duration>) {
+        self.request_timeout = value;
+    }
 }
 
 //--- Default
@@ -144,6 +265,10 @@ impl Default for Config {
         Self {
             max_response_size: Some(MAX_RESPONSE_SIZE.default()),
             write_timeout: WRITE_TIMEOUT.default(),
+            compression_mode: CompressionMode::default(),
+            max_tracked_requests: 0,
+            allow_response_redirection: false,
+            request_timeout: None,
         }
     }
 }
@@ -155,6 +280,10 @@ impl Clone for Config {
         Self {
             max_response_size: self.max_response_size,
             write_timeout: self.write_timeout,
+            compression_mode: self.compression_mode,
+            max_tracked_requests: self.max_tracked_requests,
+            allow_response_redirection: self.allow_response_redirection,
+            request_timeout: self.request_timeout,
         }
     }
 }
@@ -164,12 +293,6 @@ impl Clone for Config {
 /// A [`ServerCommand`] capable of propagating a DgramServer [`Config`] value.
 type ServerCommandType = ServerCommand<Config>;
 
-/// A thread safe sender of [`ServerCommand`]s.
-type CommandSender = Arc<Mutex<watch::Sender<ServerCommandType>>>;
-
-/// A thread safe receiver of [`ServerCommand`]s.
-type CommandReceiver = watch::Receiver<ServerCommandType>;
-
 /// A server for connecting clients via a datagram based network transport to
 /// a [`Service`].
 ///
@@ -190,6 +313,8 @@ type CommandReceiver = watch::Receiver<ServerCommandType>;
 /// crate provides an implementation for [`tokio::net::UdpSocket`]. When
 /// wrapped inside an [`Arc`] the same `UdpSocket` can be [`Arc::clone`]d to
 /// multiple instances of [`DgramServer`] potentially increasing throughput.
+/// Use [`WorkerAffinity`] to have the queries of each client processed by
+/// the same one of these instances.
 ///
 /// # Examples
 ///
@@ -230,7 +355,11 @@ type CommandReceiver = watch::Receiver<ServerCommandType>;
 ///     // Create a server that will accept those connections and pass
 ///     // received messages to your service and in turn pass generated
 ///     // responses back to the client.
-///     let srv = Arc::new(DgramServer::new(udpsocket, VecBufSource, svc));
+///     let srv = Arc::new(DgramServer::new(
+///         udpsocket,
+///         VecBufSource::default(),
+///         svc,
+///     ));
 ///
 ///     // Run the server.
 ///     let spawned_srv = srv.clone();
@@ -251,7 +380,7 @@ type CommandReceiver = watch::Receiver<ServerCommandType>;
 pub struct DgramServer<Sock, Buf, Svc>
 where
     Sock: AsyncDgramSock + Send + Sync + 'static,
-    Buf: BufSource + Send + Sync,
+    Buf: BufSource + Send + Sync + 'static,
     <Buf as BufSource>::Output: Octets + Send + Sync + Unpin + 'static,
     Svc: Clone
         + Service<<Buf as BufSource>::Output, ()>
@@ -260,34 +389,57 @@ where
         + 'static,
     <Svc as Service<<Buf as BufSource>::Output, ()>>::Future: Send,
     <Svc as Service<<Buf as BufSource>::Output, ()>>::Stream: Send,
-    <Svc as Service<<Buf as BufSource>::Output, ()>>::Target: Composer + Send,
+    <Svc as Service<<Buf as BufSource>::Output, ()>>::Target:
+        Composer + Default + Send,
 {
     /// The configuration of the server.
     config: Arc<ArcSwap<Config>>,
 
     /// A receiver for receiving [`ServerCommand`]s.
     ///
-    /// Used by both the server and spawned connections to react to sent
-    /// commands.
-    command_rx: CommandReceiver,
+    /// Created together with the command channel and taken by the first
+    /// invocation of [`run`] so that commands sent before the server was
+    /// started are not missed.
+    ///
+    /// [`run`]: Self::run()
+    command_rx: Mutex<Option<CommandReceiver<Config>>>,
 
     /// A sender for sending [`ServerCommand`]s.
     ///
     /// Used to signal the server to stop, reconfigure, etc.
-    command_tx: CommandSender,
+    command_tx: CommandSender<Config>,
 
     /// The network socket over which client requests will be received
     /// and responses sent.
     sock: Arc<Sock>,
 
     /// A [`BufSource`] for creating buffers on demand.
-    buf: Buf,
+    ///
+    /// Shared with the tasks processing requests so that they can hand
+    /// buffers back to it once done with them.
+    buf: Arc<Buf>,
 
     /// A [`Service`] for handling received requests and generating responses.
     service: Svc,
 
     /// [`ServerMetrics`] describing the status of the server.
     metrics: Arc<ServerMetrics>,
+
+    /// Whether the [`Service`] asked for reading requests to be paused.
+    backpressure: Arc<Backpressure>,
+
+    /// Cancelled when the server is shutdown, aborting the processing of
+    /// any requests that is still in progress.
+    cancellation: CancellationToken,
+
+    /// The requests currently being processed, if dropping duplicates.
+    inflight: Arc<InflightRequests<Sock::Addr>>,
+
+    /// The affinity group this server is a member of, if any.
+    affinity: Option<WorkerAffinity<<Buf as BufSource>::Output, Sock::Addr>>,
+
+    /// The outcome of the last [`ServerCommand::Drain`], once completed.
+    drain_outcome: watch::Sender<Option<ShutdownOutcome>>,
 }
 
 /// Creation
@@ -295,12 +447,13 @@ where
 impl<Sock, Buf, Svc> DgramServer<Sock, Buf, Svc>
 where
     Sock: AsyncDgramSock + Send + Sync,
-    Buf: BufSource + Send + Sync,
+    Buf: BufSource + Send + Sync + 'static,
     <Buf as BufSource>::Output: Octets + Send + Sync + Unpin,
     Svc: Clone + Service<<Buf as BufSource>::Output, ()> + Send + Sync,
     <Svc as Service<<Buf as BufSource>::Output, ()>>::Future: Send,
     <Svc as Service<<Buf as BufSource>::Output, ()>>::Stream: Send,
-    <Svc as Service<<Buf as BufSource>::Output, ()>>::Target: Composer + Send,
+    <Svc as Service<<Buf as BufSource>::Output, ()>>::Target:
+        Composer + Default + Send,
 {
     /// Constructs a new [`DgramServer`] with default configuration.
     ///
@@ -330,8 +483,8 @@ where
         service: Svc,
         config: Config,
     ) -> Self {
-        let (command_tx, command_rx) = watch::channel(ServerCommand::Init);
-        let command_tx = Arc::new(Mutex::new(command_tx));
+        let (command_tx, command_rx) = CommandSender::new();
+        let command_rx = Mutex::new(Some(command_rx));
         let metrics = Arc::new(ServerMetrics::connection_less());
         let config = Arc::new(ArcSwap::from_pointee(config));
 
@@ -340,11 +493,28 @@ where
             command_tx,
             command_rx,
             sock: sock.into(),
-            buf,
+            buf: Arc::new(buf),
             service,
             metrics,
+            backpressure: Default::default(),
+            cancellation: CancellationToken::new(),
+            inflight: Default::default(),
+            affinity: None,
+            drain_outcome: watch::channel(None).0,
         }
     }
+
+    /// Makes the server a member of a worker affinity group.
+    ///
+    /// See [`WorkerAffinity`] for when this is useful and its cost.
+    #[must_use]
+    pub fn with_affinity(
+        mut self,
+        affinity: WorkerAffinity<<Buf as BufSource>::Output, Sock::Addr>,
+    ) -> Self {
+        self.affinity = Some(affinity);
+        self
+    }
 }
 
 /// Access
@@ -352,12 +522,13 @@ where
 impl<Sock, Buf, Svc> DgramServer<Sock, Buf, Svc>
 where
     Sock: AsyncDgramSock + Send + Sync,
-    Buf: BufSource + Send + Sync,
+    Buf: BufSource + Send + Sync + 'static,
     <Buf as BufSource>::Output: Octets + Send + Sync + Unpin,
     Svc: Clone + Service<<Buf as BufSource>::Output, ()> + Send + Sync,
     <Svc as Service<<Buf as BufSource>::Output, ()>>::Future: Send,
     <Svc as Service<<Buf as BufSource>::Output, ()>>::Stream: Send,
-    <Svc as Service<<Buf as BufSource>::Output, ()>>::Target: Composer + Send,
+    <Svc as Service<<Buf as BufSource>::Output, ()>>::Target:
+        Composer + Default + Send,
 {
     /// Get a reference to the network source being used to receive messages.
     #[must_use]
@@ -377,7 +548,7 @@ where
 impl<Sock, Buf, Svc> DgramServer<Sock, Buf, Svc>
 where
     Sock: AsyncDgramSock + Send + Sync + 'static,
-    Buf: BufSource + Send + Sync,
+    Buf: BufSource + Send + Sync + 'static,
     <Buf as BufSource>::Output: Octets + Send + Sync + 'static + Unpin,
     Svc: Clone
         + Service<<Buf as BufSource>::Output, ()>
@@ -386,7 +557,8 @@ where
         + 'static,
     <Svc as Service<<Buf as BufSource>::Output, ()>>::Future: Send,
     <Svc as Service<<Buf as BufSource>::Output, ()>>::Stream: Send,
-    <Svc as Service<<Buf as BufSource>::Output, ()>>::Target: Composer + Send,
+    <Svc as Service<<Buf as BufSource>::Output, ()>>::Target:
+        Composer + Default + Send,
 {
     /// Start the server.
     ///
@@ -401,34 +573,96 @@ where
         }
     }
 
+    /// Start the server, returning the error that stopped it, if any.
+    ///
+    /// Like [`run`] but instead of logging the error that stopped the server
+    /// it is returned to the caller, e.g. to decide whether to restart the
+    /// server. Returns `Ok(())` if the server was stopped by a command such
+    /// as [`shutdown`].
+    ///
+    /// [`run`]: Self::run()
+    /// [`shutdown`]: Self::shutdown
+    pub async fn try_run(&self) -> Result<(), ServerError> {
+        self.run_until_error().await
+    }
+
     /// Reconfigure the server while running.
     ///
+    /// The given config replaces the current config as a whole and applies
+    /// to requests received after the server processed the command.
+    /// Requests already being processed keep the settings they started with.
+    ///
+    /// Datagram servers have no connections and so no idle timeout, unlike
+    /// a [`StreamServer`] whose connections can be given a new idle timeout
+    /// by reconfiguring it.
     ///
+    /// The [`Service`] cannot be replaced by reconfiguring. To change
+    /// request processing at runtime, e.g. to enable or disable middleware,
+    /// let the service itself consult shared state that can be updated.
+    /// Some middleware does this already, e.g. the rules of the
+    /// [`AclMiddlewareSvc`] can be replaced while the server is running.
+    ///
+    /// [`AclMiddlewareSvc`]: super::middleware::acl::AclMiddlewareSvc
+    /// [`StreamServer`]: super::stream::StreamServer
     pub fn reconfigure(&self, config: Config) -> Result<(), Error> {
-        self.command_tx
-            .lock()
-            .map_err(|_| Error::CommandCouldNotBeSent)?
-            .send(ServerCommand::Reconfigure(config))
-            .map_err(|_| Error::CommandCouldNotBeSent)
+        self.send_command(ServerCommand::Reconfigure(config))
     }
 
     /// Stop the server.
     ///
-    /// In-flight requests will continue being processed but no new messages
-    /// will be accepted. Pending responses will be written as long as the
-    /// socket that was given to the server when it was created remains
-    /// operational.
+    /// No new messages will be accepted and the [`CancellationToken`] of
+    /// in-flight requests is cancelled, aborting their processing. Responses
+    /// that are already being written will be written as long as the socket
+    /// that was given to the server when it was created remains operational.
+    /// [`Self::run`] returns once these writes have completed or timed out.
     ///
     /// [`Self::is_shutdown`] can be used to dertermine if shutdown is
     /// complete.
     ///
     /// [`Self::await_shutdown`] can be used to wait for shutdown to complete.
     pub fn shutdown(&self) -> Result<(), Error> {
+        self.send_command(ServerCommand::Shutdown)
+    }
+
+    /// Stop the server after letting in-flight requests complete.
+    ///
+    /// No new messages will be accepted, but unlike with [`Self::shutdown`]
+    /// the processing of requests already received, including the writing
+    /// of their responses, continues for at most `drain_timeout`. Any
+    /// processing still in progress after that is aborted as with
+    /// [`Self::terminate`].
+    ///
+    /// Returns once processing has ended, indicating whether all in-flight
+    /// requests completed in time. If the server hasn't been started yet
+    /// this waits until it is [run][Self::run] and has drained. Fails if
+    /// the server has already stopped.
+    pub async fn shutdown_with_timeout(
+        &self,
+        drain_timeout: Duration,
+    ) -> Result<ShutdownOutcome, Error> {
+        self.drain_outcome.send_replace(None);
+        let mut outcome_rx = self.drain_outcome.subscribe();
         self.command_tx
-            .lock()
-            .map_err(|_| Error::CommandCouldNotBeSent)?
-            .send(ServerCommand::Shutdown)
-            .map_err(|_| Error::CommandCouldNotBeSent)
+            .send(ServerCommand::Drain(drain_timeout))
+            .map_err(|_| Error::CommandCouldNotBeSent)?;
+        let outcome = outcome_rx
+            .wait_for(Option::is_some)
+            .await
+            .map_err(|_| Error::CommandCouldNotBeSent)?;
+        Ok(outcome.unwrap_or(ShutdownOutcome::Drained))
+    }
+
+    /// Stop the server immediately.
+    ///
+    /// Like [`Self::shutdown`] no new messages will be accepted and the
+    /// processing of in-flight requests is aborted, but this includes the
+    /// writing of responses. Unlike [`Self::shutdown`], [`Self::run`] only
+    /// returns once all processing has ended, at which point nothing the
+    /// server spawned holds on to its socket anymore. Once the server itself
+    /// is dropped too the socket is closed and its address can be bound
+    /// again.
+    pub fn terminate(&self) -> Result<(), Error> {
+        self.send_command(ServerCommand::Terminate)
     }
 
     /// Check if shutdown has completed.
@@ -465,33 +699,93 @@ where
 impl<Sock, Buf, Svc> DgramServer<Sock, Buf, Svc>
 where
     Sock: AsyncDgramSock + Send + Sync,
-    Buf: BufSource + Send + Sync,
+    Buf: BufSource + Send + Sync + 'static,
     <Buf as BufSource>::Output: Octets + Send + Sync + Unpin,
     Svc: Clone + Service<<Buf as BufSource>::Output, ()> + Send + Sync,
     <Svc as Service<<Buf as BufSource>::Output, ()>>::Future: Send,
     <Svc as Service<<Buf as BufSource>::Output, ()>>::Stream: Send,
-    <Svc as Service<<Buf as BufSource>::Output, ()>>::Target: Composer + Send,
+    <Svc as Service<<Buf as BufSource>::Output, ()>>::Target:
+        Composer + Default + Send,
 {
     /// Receive incoming messages until shutdown or fatal error.
-    async fn run_until_error(&self) -> Result<(), String> {
-        let mut command_rx = self.command_rx.clone();
+    async fn run_until_error(&self) -> Result<(), ServerError> {
+        let mut command_rx = self.command_receiver();
+
+        // Only a specific local address tells services anything useful, for
+        // a socket bound to a wildcard address the actual destination
+        // address of each datagram is not known.
+        let local_addr = self
+            .sock
+            .local_addr()
+            .ok()
+            .filter(|addr| !addr.ip().is_unspecified());
+
+        let mut forwarded_rx = self
+            .affinity
+            .as_ref()
+            .and_then(|affinity| affinity.receiver());
+
+        // The tasks processing received requests.
+        let mut tasks = JoinSet::new();
+
+        let res = loop {
+            let paused_until = self.backpressure.paused_until();
 
-        loop {
             tokio::select! {
                 // Poll futures in match arm order, not randomly.
                 biased;
 
                 // First, prefer obeying `ServerCommand`s over everything
                 // else.
-                res = command_rx.changed() => {
-                    self.process_server_command(res, &mut command_rx)?;
+                res = command_rx.recv() => {
+                    let terminate =
+                        matches!(res, Ok(ServerCommand::Terminate));
+                    let drain_timeout = match res {
+                        Ok(ServerCommand::Drain(drain_timeout)) => {
+                            Some(drain_timeout)
+                        }
+                        _ => None,
+                    };
+                    match self.process_server_command(res) {
+                        Ok(ControlFlow::Continue(())) => {}
+                        Ok(ControlFlow::Break(())) => {
+                            if let Some(drain_timeout) = drain_timeout {
+                                let outcome =
+                                    self.drain(&mut tasks, drain_timeout).await;
+                                self.drain_outcome.send_replace(Some(outcome));
+                            } else if terminate {
+                                // Abort the processing of requests and wait
+                                // for it to have ended, so that nothing we
+                                // spawned holds on to the socket anymore.
+                                tasks.shutdown().await;
+                            } else {
+                                // Processing of requests was cancelled, wait
+                                // for responses already being written.
+                                while tasks.join_next().await.is_some() {}
+                            }
+                            break Ok(());
+                        }
+                        Err(err) => break Err(err),
+                    }
                 }
 
-                _ = self.sock.readable() => {
+                // Forget about tasks that have finished.
+                Some(_) = tasks.join_next(), if !tasks.is_empty() => {}
+
+                // Re-evaluate whether to read when the service asks for
+                // reading to be paused.
+                _ = self.backpressure.extended() => {}
+
+                // Resume reading once the pause has ended.
+                _ = sleep_until(paused_until.unwrap_or_else(Instant::now)), if paused_until.is_some() => {
+                    trace!("Resuming reading after backpressure");
+                }
+
+                _ = self.sock.readable(), if paused_until.is_none() => {
                     let (buf, addr, bytes_read) = match self.recv_from() {
                         Ok(res) => res,
                         Err(err) if err.kind() == io::ErrorKind::WouldBlock => continue,
-                        Err(err) => return Err(format!("Error while receiving message: {err}")),
+                        Err(err) => break Err(ServerError::Io(err)),
                     };
 
                     let received_at = Instant::now();
@@ -502,119 +796,326 @@ where
                         trace!(%addr, pcap_text, "Received message");
                     }
 
-                    let svc = self.service.clone();
-                    let cfg = self.config.clone();
-                    let metrics = self.metrics.clone();
-                    let cloned_sock = self.sock.clone();
-                    let write_timeout = self.config.load().write_timeout;
-
-                    tokio::spawn(async move {
-                        match Message::from_octets(buf) {
-                            Err(err) => {
-                                // TO DO: Count this event?
-                                warn!("Failed while parsing request message: {err}");
-                            }
+                    // Hand the message over to the server its client is
+                    // assigned to, unless that is this server.
+                    let (buf, addr, received_at) = match &self.affinity {
+                        Some(affinity) => match affinity.forward((buf, addr, received_at)) {
+                            Ok(()) => continue,
+                            Err(datagram) => datagram,
+                        },
+                        None => (buf, addr, received_at),
+                    };
 
-                            // https://datatracker.ietf.org/doc/html/rfc1035#section-4.1.1
-                            // 4.1.1. Header section format
-                            //   "QR   A one bit field that specifies whether
-                            //         this message is a query (0), or a
-                            //         response (1)."
-                            Ok(msg) if msg.header().qr() => {
-                                // TO DO: Count this event?
-                                trace!("Ignoring received message because it is a reply, not a query.");
-                            }
+                    self.process_datagram(buf, addr, received_at, local_addr, &mut tasks);
+                }
 
-                            Ok(msg) => {
-                                let ctx = UdpTransportContext::new(cfg.load().max_response_size);
-                                let ctx = TransportSpecificContext::Udp(ctx);
-                                let request = Request::new(addr, received_at, msg, ctx, ());
-                                let mut stream = svc.call(request).await;
-                                while let Some(Ok(call_result)) = stream.next().await {
-                                    let (response, feedback) = call_result.into_inner();
-
-                                    if let Some(feedback) = feedback {
-                                        match feedback {
-                                            ServiceFeedback::Reconfigure {
-                                                idle_timeout: _, // N/A - only applies to connection-oriented transports
-                                            } => {
-                                                // Nothing to do.
-                                            }
-
-                                            ServiceFeedback::BeginTransaction|ServiceFeedback::EndTransaction => {
-                                                // Nothing to do.
-                                            }
-                                        }
-                                    }
+                // Process datagrams that other servers in the affinity group
+                // received from clients assigned to this server.
+                Some((buf, addr, received_at)) = recv_forwarded(&mut forwarded_rx), if paused_until.is_none() => {
+                    trace!(%addr, "Processing forwarded message");
+                    self.process_datagram(buf, addr, received_at, local_addr, &mut tasks);
+                }
+            }
+        };
+
+        // On fatal errors requests still being processed are left to finish
+        // on their own.
+        tasks.detach_all();
+        res
+    }
+
+    /// Wait for the processing of requests to end, aborting it on timeout.
+    async fn drain(
+        &self,
+        tasks: &mut JoinSet<()>,
+        drain_timeout: Duration,
+    ) -> ShutdownOutcome {
+        let drained = timeout(drain_timeout, async {
+            while tasks.join_next().await.is_some() {}
+        })
+        .await
+        .is_ok();
+
+        // Anything still in progress is aborted, as with a terminate.
+        self.cancellation.cancel();
+        if drained {
+            return ShutdownOutcome::Drained;
+        }
+        let num_aborted = tasks.len();
+        debug!("Aborting {num_aborted} requests still in flight after drain timeout");
+        tasks.shutdown().await;
+        ShutdownOutcome::TimedOut { num_aborted }
+    }
+
+    /// Process a received datagram in a newly spawned task.
+    fn process_datagram(
+        &self,
+        buf: Buf::Output,
+        addr: Sock::Addr,
+        received_at: Instant,
+        local_addr: Option<SocketAddr>,
+        tasks: &mut JoinSet<()>,
+    ) {
+        let svc = self.service.clone();
+        let cfg = self.config.clone();
+        let metrics = self.metrics.clone();
+        let backpressure = self.backpressure.clone();
+        let cloned_sock = self.sock.clone();
+        let write_timeout = self.config.load().write_timeout;
+        let max_tracked_requests = self.config.load().max_tracked_requests;
+        let inflight = self.inflight.clone();
+        let cancellation = self.cancellation.clone();
+        let buf_source = self.buf.clone();
+
+        let process = async move {
+            match Message::from_octets(buf) {
+                Err(err) => {
+                    // TO DO: Count this event?
+                    warn!("Failed while parsing request message: {err}");
+                }
+
+                // https://datatracker.ietf.org/doc/html/rfc1035#section-4.1.1
+                // 4.1.1. Header section format
+                //   "QR   A one bit field that specifies whether
+                //         this message is a query (0), or a
+                //         response (1)."
+                Ok(msg) if msg.header().qr() => {
+                    // TO DO: Count this event?
+                    trace!("Ignoring received message because it is a reply, not a query.");
+                    buf_source.recycle(msg.into_octets());
+                }
+
+                Ok(msg) => {
+                    // Held until processing of the request
+                    // completes or is abandoned.
+                    let _inflight_guard = match inflight.track(
+                        addr.clone(),
+                        &msg,
+                        max_tracked_requests,
+                    ) {
+                        Tracked::Duplicate => {
+                            trace!(%addr, "Dropping duplicate of in-flight request with id {}", msg.header().id());
+                            metrics.inc_num_suppressed_duplicates();
+                            return;
+                        }
+                        Tracked::Yes(guard) => Some(guard),
+                        Tracked::No => None,
+                    };
 
-                                    // Process the DNS response message, if any.
-                                    if let Some(response) = response {
-                                        // Convert the DNS response message into bytes.
-                                        let target = response.finish();
-                                        let bytes = target.as_dgram_slice();
-
-                                        // Logging
-                                        if enabled!(Level::TRACE) {
-                                            let pcap_text = to_pcap_text(bytes, bytes.len());
-                                            trace!(%addr, pcap_text, "Sending response");
-                                        }
-
-                                        metrics.inc_num_pending_writes();
-
-                                        // Actually write the DNS response message bytes to the UDP
-                                        // socket.
-                                        if let Err(err) = Self::send_to(
-                                            &cloned_sock,
-                                            bytes,
-                                            &addr,
-                                            write_timeout,
-                                        )
-                                        .await
-                                        {
-                                            warn!(%addr, "Failed to send response: {err}");
-                                        }
-
-                                        metrics.dec_num_pending_writes();
-                                        metrics.inc_num_sent_responses();
+                    let ctx = UdpTransportContext::new(
+                        cfg.load().max_response_size,
+                    );
+                    let ctx = TransportSpecificContext::Udp(ctx);
+                    let request = Request::new(
+                        addr.client_addr(),
+                        received_at,
+                        msg,
+                        ctx,
+                        (),
+                    )
+                    .with_local_addr(local_addr)
+                    .with_cancellation_token(cancellation.clone());
+                    if let Some(timeout) = cfg.load().request_timeout {
+                        request.deadline().set(Some(received_at + timeout));
+                    }
+                    let msg = request.message().clone();
+
+                    // Stop processing the request, by dropping the service
+                    // future and stream, if the server is shutdown before
+                    // processing completes. Responses that are already
+                    // being written are written regardless.
+                    let res = tokio::select! {
+                        biased;
+                        _ = cancellation.cancelled() => {
+                            trace!(%addr, "Abandoned processing of request: server shutdown");
+                            return;
+                        }
+                        res = call_with_deadline(&svc, request) => res,
+                    };
+                    // If processing timed out there is no stream, only a
+                    // SERVFAIL response to send in its place.
+                    let (mut stream, mut timeout_response) = match res {
+                        Some(stream) => (Some(stream), None),
+                        None => {
+                            debug!(%addr, "Request processing timed out, answering with SERVFAIL");
+                            let response = mk_timeout_response(&msg);
+                            (None, Some(CallResult::new(response)))
+                        }
+                    };
+
+                    // Only the first response to a request counts towards
+                    // the request latency.
+                    let mut received_at = Some(received_at);
+                    loop {
+                        let item = match stream.as_mut() {
+                            Some(stream) => tokio::select! {
+                                biased;
+                                _ = cancellation.cancelled() => {
+                                    trace!(%addr, "Abandoned processing of request: server shutdown");
+                                    break;
+                                }
+                                item = stream.next() => item,
+                            },
+                            None => timeout_response.take().map(Ok),
+                        };
+                        // Answer a failed service call with the RCODE of
+                        // the error and stop processing the request.
+                        let (call_result, failed) = match item {
+                            Some(Ok(call_result)) => (call_result, false),
+                            Some(Err(err)) => {
+                                debug!(%addr, "Service failed: {err}, answering with {}", err.rcode());
+                                let response = mk_error_response(
+                                    &msg,
+                                    err.rcode().into(),
+                                );
+                                (CallResult::new(response), true)
+                            }
+                            None => break,
+                        };
+                        let dest = match call_result.destination() {
+                            Some(dest)
+                                if cfg.load().allow_response_redirection =>
+                            {
+                                match Sock::Addr::from_client_addr(dest) {
+                                    Some(dest) => dest,
+                                    None => {
+                                        warn!(%addr, %dest, "Ignoring response destination: not supported by the socket");
+                                        addr.clone()
                                     }
                                 }
                             }
+                            Some(dest) => {
+                                warn!(%addr, %dest, "Ignoring response destination: response redirection is not enabled");
+                                addr.clone()
+                            }
+                            None => addr.clone(),
+                        };
+                        let (response, feedback) = call_result.into_inner();
+
+                        if let Some(feedback) = feedback {
+                            match feedback {
+                                ServiceFeedback::Reconfigure {
+                                    idle_timeout: _, // N/A - only applies to connection-oriented transports
+                                } => {
+                                    // Nothing to do.
+                                }
+
+                                ServiceFeedback::BeginTransaction
+                                | ServiceFeedback::EndTransaction => {
+                                    // Nothing to do.
+                                }
+
+                                ServiceFeedback::ApplyBackpressure {
+                                    until,
+                                } => {
+                                    backpressure.apply(until);
+                                }
+                            }
                         }
-                    });
+
+                        // Process the DNS response message, if any.
+                        if let Some(response) = response {
+                            // Convert the DNS response message into bytes,
+                            // compressing it if so configured.
+                            let target = response.finish();
+                            let compressed = compress_response(
+                                target.as_dgram_slice(),
+                                cfg.load().compression_mode,
+                            );
+                            let bytes = match &compressed {
+                                Some(compressed) => {
+                                    compressed.as_dgram_slice()
+                                }
+                                None => target.as_dgram_slice(),
+                            };
+
+                            // Logging
+                            if enabled!(Level::TRACE) {
+                                let pcap_text =
+                                    to_pcap_text(bytes, bytes.len());
+                                trace!(%addr, %dest, pcap_text, "Sending response");
+                            }
+
+                            let pending_write = PendingWrite::new(&metrics);
+
+                            // Actually write the DNS response message bytes to the UDP
+                            // socket.
+                            if let Err(err) = Self::send_to(
+                                &cloned_sock,
+                                bytes,
+                                &dest,
+                                write_timeout,
+                            )
+                            .await
+                            {
+                                warn!(%dest, "Failed to send response: {err}");
+                            }
+
+                            drop(pending_write);
+                            metrics.inc_num_sent_responses();
+                            if let Some(received_at) = received_at.take() {
+                                metrics.record_latency(received_at.elapsed());
+                            }
+                        }
+
+                        if failed {
+                            break;
+                        }
+                    }
+
+                    // Hand the request buffer back for reuse, unless the
+                    // service kept hold of the request message.
+                    drop(stream);
+                    if let Ok(msg) = Arc::try_unwrap(msg) {
+                        buf_source.recycle(msg.into_octets());
+                    }
                 }
             }
-        }
+        };
+
+        tasks.spawn(process);
+    }
+
+    /// Send a [`ServerCommand`] to the server.
+    fn send_command(&self, command: ServerCommandType) -> Result<(), Error> {
+        // Sending only fails if there are no receivers, i.e. the server has
+        // already stopped running and there is nothing left to command.
+        let _ = self.command_tx.send(command);
+        Ok(())
+    }
+
+    /// Get a receiver for [`ServerCommand`]s.
+    ///
+    /// The first caller receives any commands sent since the server was
+    /// created, later callers only receive commands sent from now on.
+    fn command_receiver(&self) -> CommandReceiver<Config> {
+        self.command_rx
+            .lock()
+            .ok()
+            .and_then(|mut command_rx| command_rx.take())
+            .unwrap_or_else(|| self.command_tx.subscribe())
     }
 
     /// Decide what to do with a received [`ServerCommand`].
+    ///
+    /// Returns [`ControlFlow::Break`] if the server should stop.
     fn process_server_command(
         &self,
-        res: Result<(), watch::error::RecvError>,
-        command_rx: &mut CommandReceiver,
-    ) -> Result<(), String> {
-        // If the parent server no longer exists but was not cleanly shutdown
-        // then the command channel will be closed and attempting to check for
-        // a new command will fail. Advise the caller to break the connection
-        // and cleanup if such a problem occurs.
-        res.map_err(|err| format!("Error while receiving command: {err}"))?;
-
-        // Get the changed command.
-        let lock = command_rx.borrow_and_update();
-        let command = lock.deref();
+        res: Result<ServerCommandType, broadcast::error::RecvError>,
+    ) -> Result<ControlFlow<()>, ServerError> {
+        let command = match res {
+            Ok(command) => command,
+
+            // If the parent server no longer exists but was not cleanly
+            // shutdown then the command channel will be closed and
+            // attempting to check for a new command will fail. Advise the
+            // caller to break the connection and cleanup if such a problem
+            // occurs.
+            Err(_) => return Err(ServerError::CommandChannelClosed),
+        };
 
         // And process it.
         match command {
-            ServerCommand::Init => {
-                // The initial "Init" value in the watch channel is never
-                // actually seen because changed() is required to return true
-                // before we call borrow_and_update() but the initial value in
-                // the channel, Init, is not considered a "change". So the
-                // only way to end up here would be if we somehow wrongly
-                // placed another ServerCommand::Init value into the watch
-                // channel after the initial one.
-                unreachable!()
-            }
-
             ServerCommand::CloseConnection => {
                 // A datagram server does not have connections so handling the
                 // close of a connection which can never happen has no meaning
@@ -625,34 +1126,48 @@ where
             }
 
             ServerCommand::Reconfigure(new_config) => {
-                self.config.store(Arc::new(new_config.clone()));
+                self.config.store(Arc::new(new_config));
             }
 
             ServerCommand::Shutdown => {
-                // Stop receiving new messages.
-                return Err("Shutdown command received".to_string());
+                // Stop receiving new messages and abort the processing of
+                // those already received.
+                self.cancellation.cancel();
+                return Ok(ControlFlow::Break(()));
+            }
+
+            ServerCommand::Terminate => {
+                // As above, the caller also waits for the processing of
+                // received messages to have been aborted.
+                self.cancellation.cancel();
+                return Ok(ControlFlow::Break(()));
+            }
+
+            ServerCommand::Drain(_) => {
+                // Stop receiving new messages, the caller lets the
+                // processing of those already received continue for a
+                // while.
+                return Ok(ControlFlow::Break(()));
             }
         }
 
-        Ok(())
+        Ok(ControlFlow::Continue(()))
     }
 
     /// Receive a single datagram using the user supplied network socket.
     fn recv_from(
         &self,
-    ) -> Result<(Buf::Output, SocketAddr, usize), io::Error> {
-        let mut msg = self.buf.create_buf();
-        let mut buf = ReadBuf::new(msg.as_mut());
-        self.sock
-            .try_recv_buf_from(&mut buf)
-            .map(|(bytes_read, addr)| (msg, addr, bytes_read))
+    ) -> Result<(Buf::Output, Sock::Addr, usize), io::Error> {
+        self.buf
+            .create_filled(|buf| self.sock.try_recv_buf_from(buf))
+            .map(|(msg, (bytes_read, addr))| (msg, addr, bytes_read))
     }
 
     /// Send a single datagram using the user supplied network socket.
     async fn send_to(
         sock: &Sock,
         data: &[u8],
-        dest: &SocketAddr,
+        dest: &Sock::Addr,
         limit: Duration,
     ) -> Result<(), io::Error> {
         let send_res =
@@ -673,12 +1188,296 @@ where
     }
 }
 
+//------------ ShutdownOutcome -----------------------------------------------
+
+/// How the processing of in-flight requests ended during shutdown.
+///
+/// Returned by [`DgramServer::shutdown_with_timeout`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ShutdownOutcome {
+    /// All in-flight requests completed within the drain timeout.
+    Drained,
+
+    /// The drain timeout expired and remaining processing was aborted.
+    TimedOut {
+        /// The number of requests whose processing was aborted.
+        num_aborted: usize,
+    },
+}
+
+//------------ WorkerAffinity ------------------------------------------------
+
+/// A datagram received by one server to be processed by another.
+type ForwardedDatagram<Octs, Addr> = (Octs, Addr, Instant);
+
+/// The number of datagrams that can be queued for each server of an
+/// affinity group.
+const AFFINITY_QUEUE_CAPACITY: usize = 1024;
+
+/// Membership of a [`DgramServer`] in a group of servers with client
+/// affinity.
+///
+/// When several servers receive from the same shared socket, e.g. one per
+/// CPU core, each datagram is read by whichever server happens to be ready.
+/// Queries from the same client are therefore spread across all servers.
+/// This defeats per-client state kept by each server's [`Service`], e.g. the
+/// buckets of a rate limiter, unless that state is shared between servers
+/// which requires synchronisation.
+///
+/// With worker affinity, each client is assigned to one server of the group
+/// by hashing its IP address. A server that receives a datagram from a
+/// client assigned to another server passes it on to that server's queue
+/// rather than processing it itself. All queries from the same client are
+/// thus processed by the same server.
+///
+/// # Tradeoffs
+///
+/// Affinity trades even load distribution for locality. Datagrams received
+/// by the wrong server take an extra hop through a queue, and the load of a
+/// server depends on the clients assigned to it: a few busy clients can
+/// overload one server while others are idle. Without affinity, load is
+/// spread evenly no matter how queries are distributed across clients.
+///
+/// Affinity is thus best suited to many clients with similar query rates
+/// and to services that benefit from keeping per-client state local.
+///
+/// All servers of a group must receive from the same shared socket, as
+/// responses are sent via the socket of the server processing the request.
+/// If the queue of the assigned server is full or that server is not
+/// running, the datagram is processed by the server that received it.
+///
+/// # Examples
+///
+/// ```no_run
+/// use std::sync::Arc;
+///
+/// use tokio::net::UdpSocket;
+///
+/// use domain::net::server::buf::VecBufSource;
+/// use domain::net::server::dgram::{DgramServer, WorkerAffinity};
+/// use domain::net::server::message::Request;
+/// use domain::net::server::service::ServiceResult;
+/// use domain::net::server::util::service_fn;
+///
+/// fn my_service(msg: Request<Vec<u8>>, _meta: ()) -> ServiceResult<Vec<u8>>
+/// {
+///     todo!()
+/// }
+///
+/// # async fn run() {
+/// let sock = Arc::new(UdpSocket::bind("127.0.0.1:8053").await.unwrap());
+/// for affinity in WorkerAffinity::group(4) {
+///     let svc = service_fn(my_service, ());
+///     let srv = DgramServer::new(sock.clone(), VecBufSource::default(), svc)
+///         .with_affinity(affinity);
+///     tokio::spawn(async move { srv.run().await });
+/// }
+/// # }
+/// ```
+///
+/// [`Service`]: super::service::Service
+pub struct WorkerAffinity<Octs, Addr = SocketAddr> {
+    /// The index of the server in the group.
+    index: usize,
+
+    /// The queues of all servers in the group.
+    queues: Arc<[mpsc::Sender<ForwardedDatagram<Octs, Addr>>]>,
+
+    /// The hasher for assigning clients to servers, shared by the group.
+    hasher: Arc<RandomState>,
+
+    /// The receiving end of this server's queue.
+    ///
+    /// Taken by the first invocation of [`DgramServer::run`].
+    rx: Mutex<Option<mpsc::Receiver<ForwardedDatagram<Octs, Addr>>>>,
+}
+
+impl<Octs, Addr: PeerAddr> WorkerAffinity<Octs, Addr> {
+    /// Creates the memberships of a new group of `num_workers` servers.
+    ///
+    /// Pass each of the returned values to a different server via
+    /// [`DgramServer::with_affinity`].
+    ///
+    /// # Panics
+    ///
+    /// Panics if `num_workers` is zero.
+    #[must_use]
+    pub fn group(num_workers: usize) -> Vec<Self> {
+        assert!(num_workers > 0, "affinity group must not be empty");
+        let (queues, receivers): (Vec<_>, Vec<_>) = (0..num_workers)
+            .map(|_| mpsc::channel(AFFINITY_QUEUE_CAPACITY))
+            .unzip();
+        let queues: Arc<[_]> = queues.into();
+        let hasher = Arc::new(RandomState::new());
+        receivers
+            .into_iter()
+            .enumerate()
+            .map(|(index, rx)| WorkerAffinity {
+                index,
+                queues: queues.clone(),
+                hasher: hasher.clone(),
+                rx: Mutex::new(Some(rx)),
+            })
+            .collect()
+    }
+
+    /// Returns the index of the server assigned to the given client.
+    fn worker_for(&self, client: IpAddr) -> usize {
+        let mut hasher = self.hasher.build_hasher();
+        client.hash(&mut hasher);
+        (hasher.finish() % self.queues.len() as u64) as usize
+    }
+
+    /// Passes a datagram on to the server assigned to its client.
+    ///
+    /// Returns the datagram if it should be processed by this server
+    /// instead, either because the client is assigned to it or because the
+    /// datagram cannot be queued for the assigned server.
+    fn forward(
+        &self,
+        datagram: ForwardedDatagram<Octs, Addr>,
+    ) -> Result<(), ForwardedDatagram<Octs, Addr>> {
+        let worker = self.worker_for(datagram.1.client_addr().ip());
+        if worker == self.index {
+            return Err(datagram);
+        }
+        self.queues[worker].try_send(datagram).map_err(|err| {
+            trace!("Cannot forward message to server {worker}: {err}");
+            err.into_inner()
+        })
+    }
+
+    /// Takes the receiving end of this server's queue.
+    fn receiver(
+        &self,
+    ) -> Option<mpsc::Receiver<ForwardedDatagram<Octs, Addr>>> {
+        self.rx.lock().ok().and_then(|mut rx| rx.take())
+    }
+}
+
+//--- Debug
+
+impl<Octs, Addr> Debug for WorkerAffinity<Octs, Addr> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("WorkerAffinity")
+            .field("index", &self.index)
+            .field("num_workers", &self.queues.len())
+            .finish()
+    }
+}
+
+/// Receives a datagram forwarded by another server of an affinity group.
+///
+/// Never completes if this server is not a member of an affinity group.
+async fn recv_forwarded<Octs, Addr>(
+    rx: &mut Option<mpsc::Receiver<ForwardedDatagram<Octs, Addr>>>,
+) -> Option<ForwardedDatagram<Octs, Addr>> {
+    match rx {
+        Some(rx) => rx.recv().await,
+        None => pending().await,
+    }
+}
+
+//------------ InflightRequests ----------------------------------------------
+
+/// The key identifying duplicate requests.
+type InflightKey<Addr> = (Addr, u16, Question<Name<Bytes>>);
+
+/// The requests currently being processed by a [`DgramServer`].
+#[derive(Debug)]
+struct InflightRequests<Addr> {
+    /// The client address, message ID and question of each request.
+    requests: Mutex<HashSet<InflightKey<Addr>>>,
+}
+
+impl<Addr: PeerAddr> InflightRequests<Addr> {
+    /// Starts tracking the given request unless it is a duplicate.
+    ///
+    /// Requests are only tracked if fewer than `limit` requests are tracked
+    /// already and if they have exactly one question.
+    fn track<Octs: Octets>(
+        self: &Arc<Self>,
+        addr: Addr,
+        msg: &Message<Octs>,
+        limit: usize,
+    ) -> Tracked<Addr> {
+        if limit == 0 {
+            return Tracked::No;
+        }
+        let Ok(question) = msg.sole_question() else {
+            return Tracked::No;
+        };
+        let question = Question::new(
+            question.qname().to_bytes(),
+            question.qtype(),
+            question.qclass(),
+        );
+        let key = (addr, msg.header().id(), question);
+
+        let mut requests = self.requests.lock().unwrap();
+        if requests.contains(&key) {
+            Tracked::Duplicate
+        } else if requests.len() >= limit {
+            Tracked::No
+        } else {
+            let _ = requests.insert(key.clone());
+            Tracked::Yes(InflightGuard {
+                inflight: self.clone(),
+                key,
+            })
+        }
+    }
+}
+
+//--- Default
+
+impl<Addr> Default for InflightRequests<Addr> {
+    fn default() -> Self {
+        Self {
+            requests: Default::default(),
+        }
+    }
+}
+
+//------------ Tracked -------------------------------------------------------
+
+/// The result of starting to track a request.
+enum Tracked<Addr: PeerAddr> {
+    /// The request is now tracked until the guard is dropped.
+    Yes(InflightGuard<Addr>),
+
+    /// The request is not tracked.
+    No,
+
+    /// The request duplicates a request that is still in flight.
+    Duplicate,
+}
+
+//------------ InflightGuard -------------------------------------------------
+
+/// Stops tracking a request when dropped.
+struct InflightGuard<Addr: PeerAddr> {
+    /// The tracked requests.
+    inflight: Arc<InflightRequests<Addr>>,
+
+    /// The key of the request to stop tracking.
+    key: InflightKey<Addr>,
+}
+
+impl<Addr: PeerAddr> Drop for InflightGuard<Addr> {
+    fn drop(&mut self) {
+        if let Ok(mut requests) = self.inflight.requests.lock() {
+            let _ = requests.remove(&self.key);
+        }
+    }
+}
+
 //--- Drop
 
 impl<Sock, Buf, Svc> Drop for DgramServer<Sock, Buf, Svc>
 where
     Sock: AsyncDgramSock + Send + Sync + 'static,
-    Buf: BufSource + Send + Sync,
+    Buf: BufSource + Send + Sync + 'static,
     <Buf as BufSource>::Output: Octets + Send + Sync + Unpin + 'static,
     Svc: Clone
         + Service<<Buf as BufSource>::Output, ()>
@@ -687,7 +1486,8 @@ where
         + 'static,
     <Svc as Service<<Buf as BufSource>::Output, ()>>::Future: Send,
     <Svc as Service<<Buf as BufSource>::Output, ()>>::Stream: Send,
-    <Svc as Service<<Buf as BufSource>::Output, ()>>::Target: Composer + Send,
+    <Svc as Service<<Buf as BufSource>::Output, ()>>::Target:
+        Composer + Default + Send,
 {
     fn drop(&mut self) {
         // Shutdown the DgramServer. Don't handle the failure case here as
@@ -696,3 +1496,1367 @@ where
         let _ = self.shutdown();
     }
 }
+
+//------------ PendingWrite --------------------------------------------------
+
+/// Counts a response as pending to be written for as long as it exists.
+///
+/// Ensures the count is decremented also when writing is aborted.
+struct PendingWrite<'a>(&'a ServerMetrics);
+
+impl<'a> PendingWrite<'a> {
+    /// Starts counting a pending write.
+    fn new(metrics: &'a ServerMetrics) -> Self {
+        metrics.inc_num_pending_writes();
+        Self(metrics)
+    }
+}
+
+impl Drop for PendingWrite<'_> {
+    fn drop(&mut self) {
+        self.0.dec_num_pending_writes();
+    }
+}
+
+//============ Tests =========================================================
+
+#[cfg(test)]
+mod tests {
+    use core::future::{ready, Future, Ready};
+    use core::pin::Pin;
+    use core::sync::atomic::{AtomicUsize, Ordering};
+    use core::task::{ready, Context, Poll};
+    use core::time::Duration;
+
+    use std::boxed::Box;
+    use std::collections::VecDeque;
+    use std::io;
+    use std::net::SocketAddr;
+    use std::string::{String, ToString};
+    use std::sync::{Arc, Mutex};
+    use std::vec::Vec;
+
+    use futures_util::stream::{once, Once};
+    use tokio::io::ReadBuf;
+    use tokio::net::UdpSocket;
+    use tokio::sync::{mpsc, Notify};
+    use tokio::time::{sleep, timeout, Instant, Sleep};
+
+    use crate::base::iana::{ExtendedErrorCode, Rcode};
+    use crate::base::opt::ExtendedError;
+    use crate::base::{Message, MessageBuilder, Name, Rtype};
+    use crate::net::server::buf::{
+        PooledBufSource, UninitBufSource, VecBufSource,
+    };
+    use crate::net::server::error::ServerError;
+    use crate::net::server::message::{Request, TransportSpecificContext};
+    use crate::net::server::middleware::rpz::{
+        RpzAction, RpzMiddlewareSvc, RpzPolicySet,
+    };
+    use crate::net::server::service::{
+        CallResult, DeferredResponse, Service, ServiceFeedback, ServiceResult,
+    };
+    use crate::net::server::sock::{AsyncDgramSock, PeerAddr};
+    use crate::net::server::util::{mk_builder_for_target, service_fn};
+    use crate::net::server::COMMAND_CHANNEL_CAPACITY;
+
+    use super::{Config, DgramServer, ShutdownOutcome, WorkerAffinity};
+
+    /// Peer addresses for transports that name their peers.
+    impl PeerAddr for String {
+        fn client_addr(&self) -> SocketAddr {
+            SocketAddr::from(([192, 0, 2, 1], 53))
+        }
+    }
+
+    #[tokio::test]
+    async fn burst_of_commands_is_applied_in_order() {
+        fn my_service(
+            req: Request<Vec<u8>>,
+            _meta: (),
+        ) -> ServiceResult<Vec<u8>> {
+            let builder = mk_builder_for_target();
+            let answer =
+                builder.start_answer(req.message(), Rcode::NOERROR)?;
+            Ok(CallResult::new(answer.additional()))
+        }
+
+        let sock = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let srv = DgramServer::new(
+            sock,
+            VecBufSource::default(),
+            service_fn(my_service, ()),
+        );
+
+        // Send several distinct commands in quick succession, none of which
+        // should be lost, ending with a shutdown.
+        for max_response_size in [1000, 2000, 3000] {
+            let mut config = Config::new();
+            config.set_max_response_size(Some(max_response_size));
+            srv.reconfigure(config).unwrap();
+        }
+        let mut config = Config::new();
+        config.set_write_timeout(Duration::from_secs(10));
+        srv.reconfigure(config).unwrap();
+        srv.shutdown().unwrap();
+
+        // The server only stops once it has processed the shutdown command.
+        tokio::time::timeout(Duration::from_secs(5), srv.run())
+            .await
+            .unwrap();
+
+        let config = srv.config.load();
+        assert_eq!(config.write_timeout, Duration::from_secs(10));
+        assert_eq!(config.max_response_size, Some(1232));
+    }
+
+    #[tokio::test]
+    async fn shutdown_is_not_lost_when_commands_overflow() {
+        fn my_service(
+            req: Request<Vec<u8>>,
+            _meta: (),
+        ) -> ServiceResult<Vec<u8>> {
+            let builder = mk_builder_for_target();
+            let answer =
+                builder.start_answer(req.message(), Rcode::NOERROR)?;
+            Ok(CallResult::new(answer.additional()))
+        }
+
+        let sock = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let srv = DgramServer::new(
+            sock,
+            VecBufSource::default(),
+            service_fn(my_service, ()),
+        );
+
+        // Push the shutdown out of the command channel before the server
+        // gets to read it.
+        srv.shutdown().unwrap();
+        for _ in 0..COMMAND_CHANNEL_CAPACITY + 8 {
+            srv.reconfigure(Config::new()).unwrap();
+        }
+
+        tokio::time::timeout(Duration::from_secs(5), srv.run())
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn reconfigure_applies_to_subsequent_requests() {
+        /// Answers with the response size hint in the message ID.
+        fn my_service(
+            req: Request<Vec<u8>>,
+            _meta: (),
+        ) -> ServiceResult<Vec<u8>> {
+            let hint = match req.transport_ctx() {
+                TransportSpecificContext::Udp(ctx) => {
+                    ctx.max_response_size_hint()
+                }
+                TransportSpecificContext::NonUdp(_) => None,
+            };
+            let builder = mk_builder_for_target();
+            let mut answer =
+                builder.start_answer(req.message(), Rcode::NOERROR)?;
+            answer.header_mut().set_id(hint.unwrap_or_default());
+            Ok(CallResult::new(answer.additional()))
+        }
+
+        let sock = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let srv_addr = sock.local_addr().unwrap();
+        let srv = Arc::new(DgramServer::new(
+            sock,
+            VecBufSource::default(),
+            service_fn(my_service, ()),
+        ));
+        let srv_task = tokio::spawn({
+            let srv = srv.clone();
+            async move { srv.run().await }
+        });
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client.connect(srv_addr).await.unwrap();
+        let mut query = MessageBuilder::new_vec().question();
+        query.push((Name::root_ref(), Rtype::A)).unwrap();
+        let query = query.finish();
+        let exchange = || async {
+            let mut buf = [0; 512];
+            client.send(&query).await.unwrap();
+            let len = timeout(Duration::from_secs(5), client.recv(&mut buf))
+                .await
+                .unwrap()
+                .unwrap();
+            Message::from_octets(&buf[..len]).unwrap().header().id()
+        };
+
+        assert_eq!(exchange().await, 1232);
+
+        let mut config = Config::new();
+        config.set_max_response_size(Some(4000));
+        srv.reconfigure(config).unwrap();
+
+        // The command is processed before further datagrams are read.
+        assert_eq!(exchange().await, 4000);
+
+        srv.shutdown().unwrap();
+        timeout(Duration::from_secs(1), srv_task)
+            .await
+            .unwrap()
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn socket_error_stops_server_with_io_error() {
+        /// Fails every attempt to receive.
+        struct BrokenSock;
+
+        impl AsyncDgramSock for BrokenSock {
+            type Addr = SocketAddr;
+
+            fn poll_send_to(
+                &self,
+                _cx: &mut Context,
+                _data: &[u8],
+                _dest: &SocketAddr,
+            ) -> Poll<io::Result<usize>> {
+                Poll::Pending
+            }
+
+            fn readable(
+                &self,
+            ) -> Pin<Box<dyn Future<Output = io::Result<()>> + '_ + Send>>
+            {
+                Box::pin(ready(Ok(())))
+            }
+
+            fn try_recv_buf_from(
+                &self,
+                _buf: &mut ReadBuf<'_>,
+            ) -> io::Result<(usize, SocketAddr)> {
+                Err(io::Error::new(io::ErrorKind::Other, "broken"))
+            }
+        }
+
+        fn my_service(
+            req: Request<Vec<u8>>,
+            _meta: (),
+        ) -> ServiceResult<Vec<u8>> {
+            let builder = mk_builder_for_target();
+            let answer =
+                builder.start_answer(req.message(), Rcode::NOERROR)?;
+            Ok(CallResult::new(answer.additional()))
+        }
+
+        let srv = DgramServer::new(
+            BrokenSock,
+            VecBufSource::default(),
+            service_fn(my_service, ()),
+        );
+        let res = timeout(Duration::from_secs(5), srv.try_run())
+            .await
+            .unwrap();
+        assert!(
+            matches!(&res, Err(ServerError::Io(err)) if err.to_string() == "broken"),
+            "{res:?}"
+        );
+
+        // Stopping on command is not an error.
+        let sock = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let srv = DgramServer::new(
+            sock,
+            VecBufSource::default(),
+            service_fn(my_service, ()),
+        );
+        srv.shutdown().unwrap();
+        timeout(Duration::from_secs(5), srv.try_run())
+            .await
+            .unwrap()
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn affinity_keeps_client_on_one_server() {
+        fn my_service(
+            req: Request<Vec<u8>>,
+            (index, served_by): (usize, Arc<std::sync::Mutex<Vec<usize>>>),
+        ) -> ServiceResult<Vec<u8>> {
+            served_by.lock().unwrap().push(index);
+            let builder = mk_builder_for_target();
+            let answer =
+                builder.start_answer(req.message(), Rcode::NOERROR)?;
+            Ok(CallResult::new(answer.additional()))
+        }
+
+        let sock = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let srv_addr = sock.local_addr().unwrap();
+        let served_by = Arc::new(std::sync::Mutex::new(Vec::new()));
+
+        let group = WorkerAffinity::group(4);
+        let assigned = group[0].worker_for("127.0.0.1".parse().unwrap());
+        let mut servers = Vec::new();
+        for (index, affinity) in group.into_iter().enumerate() {
+            let svc = service_fn(my_service, (index, served_by.clone()));
+            let srv = Arc::new(
+                DgramServer::new(sock.clone(), VecBufSource::default(), svc)
+                    .with_affinity(affinity),
+            );
+            let task = tokio::spawn({
+                let srv = srv.clone();
+                async move { srv.run().await }
+            });
+            servers.push((srv, task));
+        }
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client.connect(srv_addr).await.unwrap();
+        let mut buf = [0; 512];
+        for id in 0..20 {
+            let mut query = MessageBuilder::new_vec();
+            query.header_mut().set_id(id);
+            let mut query = query.question();
+            query.push((Name::root_ref(), Rtype::A)).unwrap();
+            client.send(&query.finish()).await.unwrap();
+            timeout(Duration::from_secs(5), client.recv(&mut buf))
+                .await
+                .unwrap()
+                .unwrap();
+        }
+
+        assert_eq!(*served_by.lock().unwrap(), [assigned; 20]);
+
+        for (srv, task) in servers {
+            srv.shutdown().unwrap();
+            timeout(Duration::from_secs(5), task)
+                .await
+                .unwrap()
+                .unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn request_buffers_are_recycled() {
+        fn my_service(
+            req: Request<Vec<u8>>,
+            _meta: (),
+        ) -> ServiceResult<Vec<u8>> {
+            let builder = mk_builder_for_target();
+            let answer =
+                builder.start_answer(req.message(), Rcode::NOERROR)?;
+            Ok(CallResult::new(answer.additional()))
+        }
+
+        let buf_source = PooledBufSource::default();
+        let sock = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let srv_addr = sock.local_addr().unwrap();
+        let srv = Arc::new(DgramServer::new(
+            sock,
+            buf_source.clone(),
+            service_fn(my_service, ()),
+        ));
+        let srv_task = tokio::spawn({
+            let srv = srv.clone();
+            async move { srv.run().await }
+        });
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client.connect(srv_addr).await.unwrap();
+        let mut buf = [0; 512];
+        for id in 0..20 {
+            let mut query = MessageBuilder::new_vec();
+            query.header_mut().set_id(id);
+            let mut query = query.question();
+            query.push((Name::root_ref(), Rtype::A)).unwrap();
+            client.send(&query.finish()).await.unwrap();
+            timeout(Duration::from_secs(5), client.recv(&mut buf))
+                .await
+                .unwrap()
+                .unwrap();
+        }
+
+        // A buffer may still be in use by a request when the next one
+        // arrives, but most requests should have been able to reuse one.
+        assert!(buf_source.num_reused() > 0);
+        assert!(buf_source.num_allocated() < 10);
+
+        srv.shutdown().unwrap();
+        timeout(Duration::from_secs(5), srv_task)
+            .await
+            .unwrap()
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn dropped_request_sends_nothing() {
+        fn my_service(
+            req: Request<Vec<u8>>,
+            _meta: (),
+        ) -> ServiceResult<Vec<u8>> {
+            let builder = mk_builder_for_target();
+            let answer =
+                builder.start_answer(req.message(), Rcode::NOERROR)?;
+            Ok(CallResult::new(answer.additional()))
+        }
+
+        let drop_name = Name::vec_from_str("drop.example").unwrap();
+        let policies =
+            RpzPolicySet::new().with_policy(&drop_name, RpzAction::Drop);
+        let svc = RpzMiddlewareSvc::new(service_fn(my_service, ()), policies);
+        let sock = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let srv_addr = sock.local_addr().unwrap();
+        let srv =
+            Arc::new(DgramServer::new(sock, VecBufSource::default(), svc));
+        let srv_task = tokio::spawn({
+            let srv = srv.clone();
+            async move { srv.run().await }
+        });
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client.connect(srv_addr).await.unwrap();
+        for (id, qname) in [(1, drop_name), (2, Name::root_vec())] {
+            let mut query = MessageBuilder::new_vec();
+            query.header_mut().set_id(id);
+            let mut query = query.question();
+            query.push((qname, Rtype::A)).unwrap();
+            client.send(&query.finish()).await.unwrap();
+        }
+
+        // Only the request that wasn't dropped is answered.
+        let mut buf = [0; 512];
+        let len = timeout(Duration::from_secs(5), client.recv(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        let response = Message::from_octets(&buf[..len]).unwrap();
+        assert_eq!(response.header().id(), 2);
+        assert!(timeout(Duration::from_millis(200), client.recv(&mut buf))
+            .await
+            .is_err());
+
+        srv.shutdown().unwrap();
+        timeout(Duration::from_secs(5), srv_task)
+            .await
+            .unwrap()
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn large_datagram_is_received_intact() {
+        fn my_service(
+            req: Request<Vec<u8>>,
+            received: Arc<std::sync::Mutex<Vec<Vec<u8>>>>,
+        ) -> ServiceResult<Vec<u8>> {
+            received
+                .lock()
+                .unwrap()
+                .push(req.message().as_slice().to_vec());
+            let builder = mk_builder_for_target();
+            let answer =
+                builder.start_answer(req.message(), Rcode::NOERROR)?;
+            Ok(CallResult::new(answer.additional()))
+        }
+
+        let received = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let sock = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let srv_addr = sock.local_addr().unwrap();
+        let srv = Arc::new(DgramServer::new(
+            sock,
+            VecBufSource::with_capacity(4096),
+            service_fn(my_service, received.clone()),
+        ));
+        let srv_task = tokio::spawn({
+            let srv = srv.clone();
+            async move { srv.run().await }
+        });
+
+        // A query padded to 2500 bytes.
+        let mut query = MessageBuilder::new_vec().question();
+        query.push((Name::root_ref(), Rtype::A)).unwrap();
+        let mut query = query.additional();
+        query.opt(|opt| opt.padding(2500 - 17 - 11 - 4)).unwrap();
+        let query = query.finish();
+        assert_eq!(query.len(), 2500);
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client.connect(srv_addr).await.unwrap();
+        client.send(&query).await.unwrap();
+        let mut buf = [0; 512];
+        timeout(Duration::from_secs(5), client.recv(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+
+        let received = received.lock().unwrap().clone();
+        assert_eq!(received.len(), 1);
+        assert_eq!(received[0].len(), 4096);
+        assert_eq!(received[0][..2500], query);
+
+        srv.shutdown().unwrap();
+        timeout(Duration::from_secs(5), srv_task)
+            .await
+            .unwrap()
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn uninit_buffers_hold_only_the_received_datagram() {
+        fn my_service(
+            req: Request<Vec<u8>>,
+            lens: Arc<std::sync::Mutex<Vec<usize>>>,
+        ) -> ServiceResult<Vec<u8>> {
+            lens.lock().unwrap().push(req.message().as_slice().len());
+            let builder = mk_builder_for_target();
+            let answer =
+                builder.start_answer(req.message(), Rcode::NOERROR)?;
+            Ok(CallResult::new(answer.additional()))
+        }
+
+        let lens = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let sock = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let srv_addr = sock.local_addr().unwrap();
+        let srv = Arc::new(DgramServer::new(
+            sock,
+            UninitBufSource::default(),
+            service_fn(my_service, lens.clone()),
+        ));
+        let srv_task = tokio::spawn({
+            let srv = srv.clone();
+            async move { srv.run().await }
+        });
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client.connect(srv_addr).await.unwrap();
+        let mut query = MessageBuilder::new_vec().question();
+        query.push((Name::root_ref(), Rtype::A)).unwrap();
+        let query = query.finish();
+        client.send(&query).await.unwrap();
+        let mut buf = [0; 512];
+        timeout(Duration::from_secs(5), client.recv(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(*lens.lock().unwrap(), [query.len()]);
+
+        srv.shutdown().unwrap();
+        timeout(Duration::from_secs(5), srv_task)
+            .await
+            .unwrap()
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn backpressure_pauses_reading_until_shutdown() {
+        fn my_service(
+            req: Request<Vec<u8>>,
+            num_calls: Arc<AtomicUsize>,
+        ) -> ServiceResult<Vec<u8>> {
+            num_calls.fetch_add(1, Ordering::SeqCst);
+            let builder = mk_builder_for_target();
+            let answer =
+                builder.start_answer(req.message(), Rcode::NOERROR)?;
+            let feedback = ServiceFeedback::ApplyBackpressure {
+                until: Instant::now() + Duration::from_secs(3600),
+            };
+            Ok(CallResult::new(answer.additional()).with_feedback(feedback))
+        }
+
+        let num_calls = Arc::new(AtomicUsize::new(0));
+        let sock = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let srv_addr = sock.local_addr().unwrap();
+        let srv = Arc::new(DgramServer::new(
+            sock,
+            VecBufSource::default(),
+            service_fn(my_service, num_calls.clone()),
+        ));
+        let srv_task = tokio::spawn({
+            let srv = srv.clone();
+            async move { srv.run().await }
+        });
+
+        let mut query = MessageBuilder::new_vec().question();
+        query.push((Name::root_ref(), Rtype::A)).unwrap();
+        let query = query.finish();
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client.connect(srv_addr).await.unwrap();
+        client.send(&query).await.unwrap();
+        let mut buf = [0; 512];
+        timeout(Duration::from_secs(5), client.recv(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+
+        // The response asked for backpressure so the next request is not
+        // read.
+        client.send(&query).await.unwrap();
+        sleep(Duration::from_millis(200)).await;
+        assert_eq!(num_calls.load(Ordering::SeqCst), 1);
+
+        // Shutdown is obeyed despite the pause.
+        srv.shutdown().unwrap();
+        timeout(Duration::from_secs(1), srv_task)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(num_calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn duplicate_inflight_requests_are_dropped() {
+        /// Answers after a delay, so that requests stay in flight.
+        async fn my_service(
+            req: Request<Vec<u8>>,
+            num_calls: Arc<AtomicUsize>,
+        ) -> ServiceResult<Vec<u8>> {
+            num_calls.fetch_add(1, Ordering::SeqCst);
+            sleep(Duration::from_millis(200)).await;
+            let builder = mk_builder_for_target();
+            let answer =
+                builder.start_answer(req.message(), Rcode::NOERROR)?;
+            Ok(CallResult::new(answer.additional()))
+        }
+
+        #[derive(Clone)]
+        struct SlowService(Arc<AtomicUsize>);
+
+        impl Service<Vec<u8>> for SlowService {
+            type Target = Vec<u8>;
+            type Stream = Once<
+                Pin<Box<dyn Future<Output = ServiceResult<Vec<u8>>> + Send>>,
+            >;
+            type Future = Ready<Self::Stream>;
+
+            fn call(&self, request: Request<Vec<u8>>) -> Self::Future {
+                ready(once(Box::pin(my_service(request, self.0.clone()))))
+            }
+        }
+
+        let num_calls = Arc::new(AtomicUsize::new(0));
+        let sock = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let srv_addr = sock.local_addr().unwrap();
+        let mut config = Config::new();
+        config.set_duplicate_suppression(16);
+        let srv = Arc::new(DgramServer::with_config(
+            sock,
+            VecBufSource::default(),
+            SlowService(num_calls.clone()),
+            config,
+        ));
+        let srv_task = tokio::spawn({
+            let srv = srv.clone();
+            async move { srv.run().await }
+        });
+
+        let mk_query = |id| {
+            let mut query = MessageBuilder::new_vec();
+            query.header_mut().set_id(id);
+            let mut query = query.question();
+            query.push((Name::root_ref(), Rtype::A)).unwrap();
+            query.finish()
+        };
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client.connect(srv_addr).await.unwrap();
+        let mut buf = [0; 512];
+
+        // A retransmission while the original is in flight is dropped, a
+        // different request is not.
+        client.send(&mk_query(1)).await.unwrap();
+        client.send(&mk_query(1)).await.unwrap();
+        client.send(&mk_query(2)).await.unwrap();
+        for _ in 0..2 {
+            timeout(Duration::from_secs(5), client.recv(&mut buf))
+                .await
+                .unwrap()
+                .unwrap();
+        }
+        assert!(timeout(Duration::from_millis(300), client.recv(&mut buf))
+            .await
+            .is_err());
+        assert_eq!(num_calls.load(Ordering::SeqCst), 2);
+        assert_eq!(srv.metrics().num_suppressed_duplicates(), 1);
+
+        // Once answered, the same request is processed again.
+        client.send(&mk_query(1)).await.unwrap();
+        timeout(Duration::from_secs(5), client.recv(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(num_calls.load(Ordering::SeqCst), 3);
+        assert!(srv.inflight.requests.lock().unwrap().is_empty());
+
+        srv.shutdown().unwrap();
+        timeout(Duration::from_secs(1), srv_task)
+            .await
+            .unwrap()
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn response_is_redirected_only_if_allowed() {
+        fn my_service(
+            req: Request<Vec<u8>>,
+            dest: SocketAddr,
+        ) -> ServiceResult<Vec<u8>> {
+            let builder = mk_builder_for_target();
+            let answer =
+                builder.start_answer(req.message(), Rcode::NOERROR)?;
+            Ok(CallResult::new(answer.additional()).with_destination(dest))
+        }
+
+        let relay = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let relay_addr = relay.local_addr().unwrap();
+
+        for allow in [false, true] {
+            let sock = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let srv_addr = sock.local_addr().unwrap();
+            let mut config = Config::new();
+            config.set_allow_response_redirection(allow);
+            let srv = Arc::new(DgramServer::with_config(
+                sock,
+                VecBufSource::default(),
+                service_fn(my_service, relay_addr),
+                config,
+            ));
+            let srv_task = tokio::spawn({
+                let srv = srv.clone();
+                async move { srv.run().await }
+            });
+
+            let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let mut query = MessageBuilder::new_vec().question();
+            query.push((Name::root_ref(), Rtype::A)).unwrap();
+            client.send_to(&query.finish(), srv_addr).await.unwrap();
+
+            // The response goes to the relay only if redirection is allowed,
+            // otherwise it goes back to the client as usual.
+            let receiver = if allow { &relay } else { &client };
+            let mut buf = [0; 512];
+            let (_len, from) =
+                timeout(Duration::from_secs(5), receiver.recv_from(&mut buf))
+                    .await
+                    .unwrap()
+                    .unwrap();
+            assert_eq!(from, srv_addr);
+
+            srv.shutdown().unwrap();
+            timeout(Duration::from_secs(5), srv_task)
+                .await
+                .unwrap()
+                .unwrap();
+        }
+
+        // Nothing was sent to the relay while redirection was disallowed.
+        let mut buf = [0; 512];
+        assert!(timeout(Duration::from_millis(100), relay.recv(&mut buf))
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn over_deadline_request_gets_servfail_with_ede() {
+        /// Counts when it is dropped.
+        struct DropGuard(Arc<AtomicUsize>);
+
+        impl Drop for DropGuard {
+            fn drop(&mut self) {
+                self.0.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+
+        /// Answers only after a long delay.
+        async fn my_service(
+            req: Request<Vec<u8>>,
+            num_dropped: Arc<AtomicUsize>,
+        ) -> ServiceResult<Vec<u8>> {
+            let _guard = DropGuard(num_dropped);
+            sleep(Duration::from_secs(10)).await;
+            let builder = mk_builder_for_target();
+            let answer =
+                builder.start_answer(req.message(), Rcode::NOERROR)?;
+            Ok(CallResult::new(answer.additional()))
+        }
+
+        #[derive(Clone)]
+        struct SlowService(Arc<AtomicUsize>);
+
+        impl Service<Vec<u8>> for SlowService {
+            type Target = Vec<u8>;
+            type Stream = Once<
+                Pin<Box<dyn Future<Output = ServiceResult<Vec<u8>>> + Send>>,
+            >;
+            type Future = Ready<Self::Stream>;
+
+            fn call(&self, request: Request<Vec<u8>>) -> Self::Future {
+                ready(once(Box::pin(my_service(request, self.0.clone()))))
+            }
+        }
+
+        let num_dropped = Arc::new(AtomicUsize::new(0));
+        let sock = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let srv_addr = sock.local_addr().unwrap();
+        let mut config = Config::new();
+        config.set_request_timeout(Some(Duration::from_millis(100)));
+        let srv = Arc::new(DgramServer::with_config(
+            sock,
+            VecBufSource::default(),
+            SlowService(num_dropped.clone()),
+            config,
+        ));
+        let srv_task = tokio::spawn({
+            let srv = srv.clone();
+            async move { srv.run().await }
+        });
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client.connect(srv_addr).await.unwrap();
+        let mut query = MessageBuilder::new_vec().question();
+        query.push((Name::root_ref(), Rtype::A)).unwrap();
+        let mut query = query.additional();
+        query.opt(|_| Ok(())).unwrap();
+        client.send(&query.finish()).await.unwrap();
+
+        let mut buf = [0; 512];
+        let len = timeout(Duration::from_secs(5), client.recv(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        let response = Message::from_octets(&buf[..len]).unwrap();
+        assert_eq!(response.header().rcode(), Rcode::SERVFAIL);
+        let opt = response.opt().unwrap();
+        let ede = opt.opt().iter::<ExtendedError<_>>().next().unwrap();
+        let ede = ede.unwrap();
+        assert_eq!(ede.code(), ExtendedErrorCode::OTHER);
+        assert_eq!(
+            ede.text_slice(),
+            Some(b"request processing timed out".as_ref())
+        );
+        assert_eq!(srv.metrics().num_sent_responses(), 1);
+
+        // Processing of the request was aborted, not left to run on.
+        assert_eq!(num_dropped.load(Ordering::SeqCst), 1);
+
+        srv.shutdown().unwrap();
+        timeout(Duration::from_secs(1), srv_task)
+            .await
+            .unwrap()
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn never_completed_deferred_response_gets_servfail() {
+        type Sender = tokio::sync::oneshot::Sender<ServiceResult<Vec<u8>>>;
+
+        /// Defers every response, keeping the senders but never using them.
+        #[derive(Clone, Default)]
+        struct DeferringService(Arc<std::sync::Mutex<Vec<Sender>>>);
+
+        impl Service<Vec<u8>> for DeferringService {
+            type Target = Vec<u8>;
+            type Stream = Once<Ready<ServiceResult<Vec<u8>>>>;
+            type Future = DeferredResponse<Vec<u8>>;
+
+            fn call(&self, _request: Request<Vec<u8>>) -> Self::Future {
+                let (tx, response) = DeferredResponse::channel();
+                self.0.lock().unwrap().push(tx);
+                response
+            }
+        }
+
+        let sock = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let srv_addr = sock.local_addr().unwrap();
+        let mut config = Config::new();
+        config.set_request_timeout(Some(Duration::from_millis(100)));
+        let svc = DeferringService::default();
+        let srv = Arc::new(DgramServer::with_config(
+            sock,
+            VecBufSource::default(),
+            svc.clone(),
+            config,
+        ));
+        let srv_task = tokio::spawn({
+            let srv = srv.clone();
+            async move { srv.run().await }
+        });
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client.connect(srv_addr).await.unwrap();
+        let mut query = MessageBuilder::new_vec().question();
+        query.push((Name::root_ref(), Rtype::A)).unwrap();
+        client.send(&query.finish()).await.unwrap();
+
+        let mut buf = [0; 512];
+        let len = timeout(Duration::from_secs(5), client.recv(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        let response = Message::from_octets(&buf[..len]).unwrap();
+        assert_eq!(response.header().rcode(), Rcode::SERVFAIL);
+
+        // The sender was still alive, the deadline answered the request.
+        assert_eq!(svc.0.lock().unwrap().len(), 1);
+
+        srv.shutdown().unwrap();
+        timeout(Duration::from_secs(1), srv_task)
+            .await
+            .unwrap()
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn abandoned_deferred_response_gets_servfail() {
+        /// Defers every response, dropping the senders right away.
+        #[derive(Clone)]
+        struct AbandoningService;
+
+        impl Service<Vec<u8>> for AbandoningService {
+            type Target = Vec<u8>;
+            type Stream = Once<Ready<ServiceResult<Vec<u8>>>>;
+            type Future = DeferredResponse<Vec<u8>>;
+
+            fn call(&self, _request: Request<Vec<u8>>) -> Self::Future {
+                let (_, response) = DeferredResponse::channel();
+                response
+            }
+        }
+
+        let sock = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let srv_addr = sock.local_addr().unwrap();
+        let srv = Arc::new(DgramServer::new(
+            sock,
+            VecBufSource::default(),
+            AbandoningService,
+        ));
+        let srv_task = tokio::spawn({
+            let srv = srv.clone();
+            async move { srv.run().await }
+        });
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client.connect(srv_addr).await.unwrap();
+        let mut query = MessageBuilder::new_vec();
+        query.header_mut().set_id(4711);
+        let mut query = query.question();
+        query.push((Name::root_ref(), Rtype::A)).unwrap();
+        client.send(&query.finish()).await.unwrap();
+
+        let mut buf = [0; 512];
+        let len = timeout(Duration::from_secs(5), client.recv(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        let response = Message::from_octets(&buf[..len]).unwrap();
+        assert_eq!(response.header().id(), 4711);
+        assert_eq!(response.header().rcode(), Rcode::SERVFAIL);
+        assert_eq!(srv.metrics().num_sent_responses(), 1);
+
+        srv.shutdown().unwrap();
+        timeout(Duration::from_secs(1), srv_task)
+            .await
+            .unwrap()
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn terminate_releases_socket_immediately() {
+        /// Counts when it is dropped.
+        struct DropGuard(Arc<AtomicUsize>);
+
+        impl Drop for DropGuard {
+            fn drop(&mut self) {
+                self.0.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+
+        /// Never answers in time.
+        async fn my_service(
+            req: Request<Vec<u8>>,
+            (num_called, num_dropped): (Arc<AtomicUsize>, Arc<AtomicUsize>),
+        ) -> ServiceResult<Vec<u8>> {
+            let _guard = DropGuard(num_dropped);
+            num_called.fetch_add(1, Ordering::SeqCst);
+            sleep(Duration::from_secs(3600)).await;
+            let builder = mk_builder_for_target();
+            let answer =
+                builder.start_answer(req.message(), Rcode::NOERROR)?;
+            Ok(CallResult::new(answer.additional()))
+        }
+
+        #[derive(Clone)]
+        struct SlowService(Arc<AtomicUsize>, Arc<AtomicUsize>);
+
+        impl Service<Vec<u8>> for SlowService {
+            type Target = Vec<u8>;
+            type Stream = Once<
+                Pin<Box<dyn Future<Output = ServiceResult<Vec<u8>>> + Send>>,
+            >;
+            type Future = Ready<Self::Stream>;
+
+            fn call(&self, request: Request<Vec<u8>>) -> Self::Future {
+                let counters = (self.0.clone(), self.1.clone());
+                ready(once(Box::pin(my_service(request, counters))))
+            }
+        }
+
+        let num_called = Arc::new(AtomicUsize::new(0));
+        let num_dropped = Arc::new(AtomicUsize::new(0));
+        let sock = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let srv_addr = sock.local_addr().unwrap();
+        let srv = Arc::new(DgramServer::new(
+            sock,
+            VecBufSource::default(),
+            SlowService(num_called.clone(), num_dropped.clone()),
+        ));
+        let srv_task = tokio::spawn({
+            let srv = srv.clone();
+            async move { srv.run().await }
+        });
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client.connect(srv_addr).await.unwrap();
+        let mut query = MessageBuilder::new_vec().question();
+        query.push((Name::root_ref(), Rtype::A)).unwrap();
+        client.send(&query.finish()).await.unwrap();
+        timeout(Duration::from_secs(5), async {
+            while num_called.load(Ordering::SeqCst) == 0 {
+                sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+
+        srv.terminate().unwrap();
+        timeout(Duration::from_secs(1), srv_task)
+            .await
+            .unwrap()
+            .unwrap();
+
+        // Processing of the request has ended by the time the server stopped.
+        assert_eq!(num_dropped.load(Ordering::SeqCst), 1);
+
+        // With the server gone, so is the socket.
+        drop(srv);
+        UdpSocket::bind(srv_addr).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn shutdown_waits_for_pending_writes() {
+        /// Delays sending until a deadline has passed.
+        struct SlowSendSock {
+            sock: UdpSocket,
+            delay: Mutex<Option<Pin<Box<Sleep>>>>,
+        }
+
+        impl AsyncDgramSock for SlowSendSock {
+            type Addr = SocketAddr;
+
+            fn poll_send_to(
+                &self,
+                cx: &mut Context,
+                data: &[u8],
+                dest: &SocketAddr,
+            ) -> Poll<io::Result<usize>> {
+                let mut delay = self.delay.lock().unwrap();
+                let delay = delay.get_or_insert_with(|| {
+                    Box::pin(sleep(Duration::from_millis(200)))
+                });
+                ready!(delay.as_mut().poll(cx));
+                self.sock.poll_send_to(cx, data, *dest)
+            }
+
+            fn readable(
+                &self,
+            ) -> Pin<Box<dyn Future<Output = io::Result<()>> + '_ + Send>>
+            {
+                Box::pin(self.sock.readable())
+            }
+
+            fn try_recv_buf_from(
+                &self,
+                buf: &mut ReadBuf<'_>,
+            ) -> io::Result<(usize, SocketAddr)> {
+                self.sock.try_recv_buf_from(buf)
+            }
+        }
+
+        fn my_service(
+            req: Request<Vec<u8>>,
+            _meta: (),
+        ) -> ServiceResult<Vec<u8>> {
+            let builder = mk_builder_for_target();
+            let answer =
+                builder.start_answer(req.message(), Rcode::NOERROR)?;
+            Ok(CallResult::new(answer.additional()))
+        }
+
+        let sock = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let srv_addr = sock.local_addr().unwrap();
+        let sock = SlowSendSock {
+            sock,
+            delay: Mutex::new(None),
+        };
+        let srv = Arc::new(DgramServer::new(
+            sock,
+            VecBufSource::default(),
+            service_fn(my_service, ()),
+        ));
+        let metrics = srv.metrics();
+        let srv_task = tokio::spawn({
+            let srv = srv.clone();
+            async move { srv.run().await }
+        });
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client.connect(srv_addr).await.unwrap();
+        let mut query = MessageBuilder::new_vec().question();
+        query.push((Name::root_ref(), Rtype::A)).unwrap();
+        client.send(&query.finish()).await.unwrap();
+        timeout(Duration::from_secs(5), async {
+            while metrics.num_pending_writes() == 0 {
+                sleep(Duration::from_millis(5)).await;
+            }
+        })
+        .await
+        .unwrap();
+
+        // The server only stops once the response has been written.
+        srv.shutdown().unwrap();
+        timeout(Duration::from_secs(5), srv_task)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(metrics.num_pending_writes(), 0);
+        assert_eq!(metrics.num_sent_responses(), 1);
+
+        let mut buf = vec![0; 512];
+        let len = timeout(Duration::from_secs(1), client.recv(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        let response = Message::from_octets(&buf[..len]).unwrap();
+        assert_eq!(response.header().rcode(), Rcode::NOERROR);
+    }
+
+    #[tokio::test]
+    async fn shutdown_with_timeout_drains_or_aborts_requests() {
+        /// Answers after the given delay.
+        async fn my_service(
+            req: Request<Vec<u8>>,
+            (delay, num_called): (Duration, Arc<AtomicUsize>),
+        ) -> ServiceResult<Vec<u8>> {
+            num_called.fetch_add(1, Ordering::SeqCst);
+            sleep(delay).await;
+            let builder = mk_builder_for_target();
+            let answer =
+                builder.start_answer(req.message(), Rcode::NOERROR)?;
+            Ok(CallResult::new(answer.additional()))
+        }
+
+        #[derive(Clone)]
+        struct SlowService(Duration, Arc<AtomicUsize>);
+
+        impl Service<Vec<u8>> for SlowService {
+            type Target = Vec<u8>;
+            type Stream = Once<
+                Pin<Box<dyn Future<Output = ServiceResult<Vec<u8>>> + Send>>,
+            >;
+            type Future = Ready<Self::Stream>;
+
+            fn call(&self, request: Request<Vec<u8>>) -> Self::Future {
+                let meta = (self.0, self.1.clone());
+                ready(once(Box::pin(my_service(request, meta))))
+            }
+        }
+
+        async fn shutdown_during_request(
+            delay: Duration,
+            drain_timeout: Duration,
+        ) -> (ShutdownOutcome, Option<Message<Vec<u8>>>) {
+            let num_called = Arc::new(AtomicUsize::new(0));
+            let sock = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let srv_addr = sock.local_addr().unwrap();
+            let srv = Arc::new(DgramServer::new(
+                sock,
+                VecBufSource::default(),
+                SlowService(delay, num_called.clone()),
+            ));
+            let srv_task = tokio::spawn({
+                let srv = srv.clone();
+                async move { srv.run().await }
+            });
+
+            let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            client.connect(srv_addr).await.unwrap();
+            let mut query = MessageBuilder::new_vec().question();
+            query.push((Name::root_ref(), Rtype::A)).unwrap();
+            client.send(&query.finish()).await.unwrap();
+            timeout(Duration::from_secs(5), async {
+                while num_called.load(Ordering::SeqCst) == 0 {
+                    sleep(Duration::from_millis(10)).await;
+                }
+            })
+            .await
+            .unwrap();
+
+            let outcome = timeout(
+                Duration::from_secs(5),
+                srv.shutdown_with_timeout(drain_timeout),
+            )
+            .await
+            .unwrap()
+            .unwrap();
+            timeout(Duration::from_secs(1), srv_task)
+                .await
+                .unwrap()
+                .unwrap();
+
+            // The server has stopped and cannot be drained again.
+            assert!(srv.shutdown_with_timeout(drain_timeout).await.is_err());
+
+            let mut buf = vec![0; 512];
+            let response =
+                timeout(Duration::from_millis(200), client.recv(&mut buf))
+                    .await
+                    .ok()
+                    .map(|len| {
+                        buf.truncate(len.unwrap());
+                        Message::from_octets(buf).unwrap()
+                    });
+            (outcome, response)
+        }
+
+        // A request completing within the drain timeout is answered.
+        let (outcome, response) = shutdown_during_request(
+            Duration::from_millis(200),
+            Duration::from_secs(5),
+        )
+        .await;
+        assert_eq!(outcome, ShutdownOutcome::Drained);
+        assert_eq!(response.unwrap().header().rcode(), Rcode::NOERROR);
+
+        // A request taking longer is aborted.
+        let (outcome, response) = shutdown_during_request(
+            Duration::from_secs(3600),
+            Duration::from_millis(100),
+        )
+        .await;
+        assert_eq!(outcome, ShutdownOutcome::TimedOut { num_aborted: 1 });
+        assert!(response.is_none());
+    }
+
+    #[tokio::test]
+    async fn string_addressed_transport_works_end_to_end() {
+        /// Exchanges datagrams with peers named by strings.
+        struct NamedPeerSock {
+            incoming: Mutex<VecDeque<(Vec<u8>, String)>>,
+            notify: Notify,
+            outgoing: mpsc::UnboundedSender<(Vec<u8>, String)>,
+        }
+
+        impl NamedPeerSock {
+            fn deliver(&self, data: Vec<u8>, from: &str) {
+                self.incoming
+                    .lock()
+                    .unwrap()
+                    .push_back((data, from.to_string()));
+                self.notify.notify_one();
+            }
+        }
+
+        impl AsyncDgramSock for NamedPeerSock {
+            type Addr = String;
+
+            fn poll_send_to(
+                &self,
+                _cx: &mut Context,
+                data: &[u8],
+                dest: &String,
+            ) -> Poll<io::Result<usize>> {
+                let _ = self.outgoing.send((data.to_vec(), dest.clone()));
+                Poll::Ready(Ok(data.len()))
+            }
+
+            fn readable(
+                &self,
+            ) -> Pin<Box<dyn Future<Output = io::Result<()>> + '_ + Send>>
+            {
+                Box::pin(async move {
+                    while self.incoming.lock().unwrap().is_empty() {
+                        self.notify.notified().await;
+                    }
+                    Ok(())
+                })
+            }
+
+            fn try_recv_buf_from(
+                &self,
+                buf: &mut ReadBuf<'_>,
+            ) -> io::Result<(usize, String)> {
+                let Some((data, from)) =
+                    self.incoming.lock().unwrap().pop_front()
+                else {
+                    return Err(io::ErrorKind::WouldBlock.into());
+                };
+                buf.put_slice(&data);
+                Ok((data.len(), from))
+            }
+        }
+
+        fn my_service(
+            req: Request<Vec<u8>>,
+            _meta: (),
+        ) -> ServiceResult<Vec<u8>> {
+            // Services see the client address the peer address maps to.
+            assert_eq!(
+                req.client_addr(),
+                SocketAddr::from(([192, 0, 2, 1], 53))
+            );
+            let builder = mk_builder_for_target();
+            let answer =
+                builder.start_answer(req.message(), Rcode::NOERROR)?;
+            Ok(CallResult::new(answer.additional()))
+        }
+
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let sock = NamedPeerSock {
+            incoming: Mutex::new(VecDeque::new()),
+            notify: Notify::new(),
+            outgoing: tx,
+        };
+        let srv = Arc::new(DgramServer::new(
+            sock,
+            VecBufSource::default(),
+            service_fn(my_service, ()),
+        ));
+        let srv_task = tokio::spawn({
+            let srv = srv.clone();
+            async move { srv.run().await }
+        });
+
+        for (id, peer) in [(1, "alice"), (2, "bob")] {
+            let mut query = MessageBuilder::new_vec();
+            query.header_mut().set_id(id);
+            let mut query = query.question();
+            query.push((Name::root_ref(), Rtype::A)).unwrap();
+            srv.sock.deliver(query.finish(), peer);
+        }
+
+        let mut responses = Vec::new();
+        for _ in 0..2 {
+            let (data, dest) = timeout(Duration::from_secs(5), rx.recv())
+                .await
+                .unwrap()
+                .unwrap();
+            let response = Message::from_octets(data).unwrap();
+            assert_eq!(response.header().rcode(), Rcode::NOERROR);
+            responses.push((response.header().id(), dest));
+        }
+        responses.sort();
+        assert_eq!(
+            responses,
+            [(1, "alice".to_string()), (2, "bob".to_string())]
+        );
+
+        srv.shutdown().unwrap();
+        srv_task.await.unwrap();
+    }
+}
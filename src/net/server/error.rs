@@ -1,6 +1,7 @@
 //! Server related errors.
 
 use std::fmt::Display;
+use std::io;
 
 /// Errors raised by DNS servers.
 #[derive(Debug)]
@@ -20,3 +21,48 @@ impl Display for Error {
         }
     }
 }
+
+//------------ ServerError ---------------------------------------------------
+
+/// Errors that stop a running DNS server.
+#[derive(Debug)]
+pub enum ServerError {
+    /// The channel over which [`ServerCommand`]s are received was closed.
+    ///
+    /// This happens if the server was dropped while still running, without
+    /// having been shut down.
+    ///
+    /// [`ServerCommand`]: crate::net::server::ServerCommand
+    CommandChannelClosed,
+
+    /// Receiving from the network socket failed.
+    Io(io::Error),
+}
+
+impl Display for ServerError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::CommandChannelClosed => {
+                write!(f, "Server command channel closed")
+            }
+            Self::Io(err) => {
+                write!(f, "Error while receiving message: {err}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for ServerError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::CommandChannelClosed => None,
+            Self::Io(err) => Some(err),
+        }
+    }
+}
+
+impl From<io::Error> for ServerError {
+    fn from(err: io::Error) -> Self {
+        Self::Io(err)
+    }
+}
@@ -0,0 +1,338 @@
+//! A service that routes requests based on the local address.
+//!
+//! A server that listens on several addresses may need to offer different
+//! services on different addresses, e.g. a public resolver on one IP address
+//! and an internal authoritative server on another. The
+//! [`LocalAddrRouter`] selects the service to pass a request to based on the
+//! local address the request was received on, as reported by
+//! [`Request::local_addr()`].
+//!
+//! The same router can be shared, by cloning it, by several servers each
+//! bound to one of the addresses:
+//!
+//! ```no_run
+//! # use domain::base::iana::Rcode;
+//! # use domain::net::server::buf::VecBufSource;
+//! # use domain::net::server::dgram::DgramServer;
+//! # use domain::net::server::local_addr_router::LocalAddrRouter;
+//! # use domain::net::server::message::Request;
+//! # use domain::net::server::service::{CallResult, ServiceResult};
+//! # use domain::net::server::util::{mk_builder_for_target, service_fn};
+//! # use tokio::net::UdpSocket;
+//! fn my_service(req: Request<Vec<u8>>, rcode: Rcode) -> ServiceResult<Vec<u8>> {
+//!     let builder = mk_builder_for_target();
+//!     let answer = builder.start_answer(req.message(), rcode)?;
+//!     Ok(CallResult::new(answer.additional()))
+//! }
+//!
+//! # async fn run() {
+//! let public = "192.0.2.1:53".parse().unwrap();
+//! let internal = "198.51.100.1:53".parse().unwrap();
+//!
+//! let mut router = LocalAddrRouter::new();
+//! router.add(public, service_fn(my_service, Rcode::REFUSED));
+//! router.add(internal, service_fn(my_service, Rcode::NOERROR));
+//!
+//! for addr in [public, internal] {
+//!     let sock = UdpSocket::bind(addr).await.unwrap();
+//!     let srv =
+//!         DgramServer::new(sock, VecBufSource::default(), router.clone());
+//!     tokio::spawn(async move { srv.run().await });
+//! }
+//! # }
+//! ```
+//!
+//! Currently only the [`DgramServer`] reports the local address of
+//! requests, and only if its socket is bound to a specific address rather
+//! than a wildcard address.
+//!
+//! [`DgramServer`]: super::dgram::DgramServer
+
+#![warn(missing_docs)]
+#![warn(clippy::missing_docs_in_private_items)]
+
+use core::future::{ready, Ready};
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+
+use futures_util::stream::{once, Once};
+use octseq::Octets;
+use tracing::trace;
+
+use crate::base::iana::OptRcode;
+use crate::base::wire::Composer;
+
+use super::message::Request;
+use super::middleware::stream::MiddlewareStream;
+use super::service::{CallResult, Service, ServiceResult};
+use super::util::mk_error_response;
+
+//------------ LocalAddrRouter -----------------------------------------------
+
+/// A [`Service`] that routes requests based on the local address.
+///
+/// Services are added for a local socket address. A request is passed to
+/// the service added for exactly the local address it was received on. If
+/// there is none, it is passed to the service added for the same IP address
+/// and port 0, which thus matches any port.
+///
+/// Requests for which no service matches, including those for which the
+/// local address is not known, are passed to the default service if one was
+/// set via [`set_default()`] and are refused otherwise.
+///
+/// All services must be of the same type. To route to services of different
+/// types, wrap them in an enum that implements [`Service`] by delegating to
+/// its variants.
+///
+/// [`set_default()`]: Self::set_default
+#[derive(Clone, Debug)]
+pub struct LocalAddrRouter<Svc> {
+    /// The services by local address.
+    services: HashMap<SocketAddr, Svc>,
+
+    /// The service for requests not matching any local address.
+    default: Option<Svc>,
+}
+
+impl<Svc> LocalAddrRouter<Svc> {
+    /// Creates a new empty router.
+    #[must_use]
+    pub fn new() -> Self {
+        Self {
+            services: HashMap::new(),
+            default: None,
+        }
+    }
+
+    /// Adds a service for requests received on the given local address.
+    ///
+    /// Use port 0 to match requests received on any port of the IP address.
+    /// Replaces any service previously added for the same address.
+    pub fn add(&mut self, local_addr: SocketAddr, service: Svc) {
+        self.services.insert(local_addr, service);
+    }
+
+    /// Sets the service for requests not matching any local address.
+    pub fn set_default(&mut self, service: Svc) {
+        self.default = Some(service);
+    }
+
+    /// Returns the service to route requests received on `local_addr` to.
+    fn select(&self, local_addr: Option<SocketAddr>) -> Option<&Svc> {
+        local_addr
+            .and_then(|addr| {
+                self.services.get(&addr).or_else(|| {
+                    self.services.get(&SocketAddr::new(addr.ip(), 0))
+                })
+            })
+            .or(self.default.as_ref())
+    }
+}
+
+//--- Default
+
+impl<Svc> Default for LocalAddrRouter<Svc> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+//--- Service
+
+impl<RequestOctets, RequestMeta, Svc> Service<RequestOctets, RequestMeta>
+    for LocalAddrRouter<Svc>
+where
+    RequestOctets: Octets + Send + Sync,
+    RequestMeta: Clone + Default,
+    Svc: Service<RequestOctets, RequestMeta>,
+    Svc::Future: Unpin,
+    Svc::Target: Composer + Default,
+{
+    type Target = Svc::Target;
+    type Stream = MiddlewareStream<
+        Svc::Future,
+        Svc::Stream,
+        Once<Ready<ServiceResult<Self::Target>>>,
+        Once<Ready<ServiceResult<Self::Target>>>,
+        ServiceResult<Self::Target>,
+    >;
+    type Future = Ready<Self::Stream>;
+
+    fn call(
+        &self,
+        request: Request<RequestOctets, RequestMeta>,
+    ) -> Self::Future {
+        match self.select(request.local_addr()) {
+            Some(svc) => {
+                ready(MiddlewareStream::IdentityFuture(svc.call(request)))
+            }
+            None => {
+                trace!(
+                    "No service for local address {:?}, refusing request",
+                    request.local_addr()
+                );
+                let response =
+                    mk_error_response(request.message(), OptRcode::REFUSED);
+                ready(MiddlewareStream::Result(once(ready(Ok(
+                    CallResult::new(response),
+                )))))
+            }
+        }
+    }
+}
+
+//============ Tests =========================================================
+
+#[cfg(test)]
+mod tests {
+    use core::time::Duration;
+
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+    use std::vec::Vec;
+
+    use futures_util::StreamExt;
+    use tokio::net::UdpSocket;
+    use tokio::time::timeout;
+
+    use crate::base::iana::Rcode;
+    use crate::base::{Message, MessageBuilder, Name, Rtype};
+    use crate::net::server::buf::VecBufSource;
+    use crate::net::server::dgram::DgramServer;
+    use crate::net::server::message::{Request, UdpTransportContext};
+    use crate::net::server::service::{CallResult, Service, ServiceResult};
+    use crate::net::server::util::{mk_builder_for_target, service_fn};
+
+    use super::LocalAddrRouter;
+
+    #[tokio::test]
+    async fn requests_are_routed_by_local_addr() {
+        let mut router = LocalAddrRouter::new();
+        router.add(
+            addr("192.0.2.1:53"),
+            service_fn(my_service, Rcode::NOERROR),
+        );
+        router.add(
+            addr("192.0.2.2:53"),
+            service_fn(my_service, Rcode::NXDOMAIN),
+        );
+        router.add(
+            addr("192.0.2.2:0"),
+            service_fn(my_service, Rcode::YXDOMAIN),
+        );
+
+        assert_eq!(call(&router, Some("192.0.2.1:53")).await, Rcode::NOERROR);
+        assert_eq!(
+            call(&router, Some("192.0.2.2:53")).await,
+            Rcode::NXDOMAIN
+        );
+        assert_eq!(
+            call(&router, Some("192.0.2.2:853")).await,
+            Rcode::YXDOMAIN
+        );
+
+        // Without a default, unmatched requests are refused.
+        assert_eq!(
+            call(&router, Some("192.0.2.1:853")).await,
+            Rcode::REFUSED
+        );
+        assert_eq!(call(&router, None).await, Rcode::REFUSED);
+
+        router.set_default(service_fn(my_service, Rcode::NOTIMP));
+        assert_eq!(call(&router, Some("192.0.2.3:53")).await, Rcode::NOTIMP);
+        assert_eq!(call(&router, None).await, Rcode::NOTIMP);
+    }
+
+    #[tokio::test]
+    async fn dgram_servers_report_local_addr() {
+        let sock1 = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let sock2 = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr1 = sock1.local_addr().unwrap();
+        let addr2 = sock2.local_addr().unwrap();
+
+        let mut router = LocalAddrRouter::new();
+        router.add(addr1, service_fn(my_service, Rcode::NOERROR));
+        router.add(addr2, service_fn(my_service, Rcode::NXDOMAIN));
+
+        let mut servers = Vec::new();
+        for sock in [sock1, sock2] {
+            let srv = Arc::new(DgramServer::new(
+                sock,
+                VecBufSource::default(),
+                router.clone(),
+            ));
+            let task = tokio::spawn({
+                let srv = srv.clone();
+                async move { srv.run().await }
+            });
+            servers.push((srv, task));
+        }
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        for (srv_addr, rcode) in
+            [(addr1, Rcode::NOERROR), (addr2, Rcode::NXDOMAIN)]
+        {
+            client.send_to(&mk_query(), srv_addr).await.unwrap();
+            let mut buf = [0; 512];
+            let (len, from) =
+                timeout(Duration::from_secs(5), client.recv_from(&mut buf))
+                    .await
+                    .unwrap()
+                    .unwrap();
+            assert_eq!(from, srv_addr);
+            let response = Message::from_octets(&buf[..len]).unwrap();
+            assert_eq!(response.header().rcode(), rcode);
+        }
+
+        for (srv, task) in servers {
+            srv.shutdown().unwrap();
+            timeout(Duration::from_secs(5), task)
+                .await
+                .unwrap()
+                .unwrap();
+        }
+    }
+
+    //------------ Helper functions ------------------------------------------
+
+    fn my_service(
+        req: Request<Vec<u8>>,
+        rcode: Rcode,
+    ) -> ServiceResult<Vec<u8>> {
+        let builder = mk_builder_for_target();
+        let answer = builder.start_answer(req.message(), rcode)?;
+        Ok(CallResult::new(answer.additional()))
+    }
+
+    fn addr(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    fn mk_query() -> Vec<u8> {
+        let mut query = MessageBuilder::new_vec().question();
+        query.push((Name::root_ref(), Rtype::A)).unwrap();
+        query.finish()
+    }
+
+    async fn call<Svc>(svc: &Svc, local_addr: Option<&str>) -> Rcode
+    where
+        Svc: Service<Vec<u8>, Target = Vec<u8>>,
+    {
+        let request = Request::for_test(
+            Message::from_octets(mk_query()).unwrap(),
+            UdpTransportContext::default(),
+            addr("127.0.0.1:12345"),
+        )
+        .with_local_addr(local_addr.map(addr));
+
+        let mut stream = svc.call(request).await;
+        let (response, _feedback) =
+            stream.next().await.unwrap().unwrap().into_inner();
+        let response = response.unwrap().finish();
+        Message::from_octets(response.as_dgram_slice())
+            .unwrap()
+            .header()
+            .rcode()
+    }
+}
@@ -0,0 +1,740 @@
+//! Buffered, asynchronous sinks for query logging.
+//!
+//! Logging every request synchronously, e.g. by emitting a `tracing` event
+//! per request that is written out by a blocking subscriber, quickly becomes
+//! a bottleneck at high query rates. A [`LogSink`] instead buffers log
+//! records in memory and hands them in batches to a [`LogWriter`] running on
+//! a background task, so that logging a record never waits for I/O.
+//!
+//! The buffer has a fixed capacity. What happens when records are logged
+//! faster than they can be written is determined by the [`OverflowPolicy`]:
+//! either the oldest buffered records are dropped, or loggers wait for
+//! space. Either way the number of records that could not be written is
+//! tracked in the [`LogSinkMetrics`] so that operators can tell when logging
+//! can't keep up.
+//!
+//! Records are written whenever a full batch has been buffered and at least
+//! once per [flush interval]. [`LogSink::flush()`] writes all buffered
+//! records on demand and [`LogSink::rotate()`] additionally asks the writer
+//! to start a new output, e.g. to reopen a log file after it was moved away
+//! by a log rotation tool.
+//!
+//! The [`LineLogWriter`] writes records that implement [`Display`] one per
+//! line to any [`AsyncWrite`] target.
+//!
+//! [flush interval]: Config::set_flush_interval
+//! [`Display`]: std::fmt::Display
+//! [`AsyncWrite`]: tokio::io::AsyncWrite
+use core::fmt::{self, Display};
+use core::future::{ready, Future};
+use core::pin::Pin;
+use core::time::Duration;
+
+use std::boxed::Box;
+use std::collections::VecDeque;
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::vec::Vec;
+
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio::sync::{mpsc, oneshot, Notify};
+use tokio::time::{Instant, MissedTickBehavior};
+use tracing::{trace, warn};
+
+//----------- Constants -------------------------------------------------------
+
+/// The default maximum number of buffered records.
+const DEFAULT_CAPACITY: usize = 65_536;
+
+/// The default maximum number of records written at once.
+const DEFAULT_BATCH_SIZE: usize = 1024;
+
+/// The default maximum time records are buffered before being written.
+const DEFAULT_FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+//----------- OverflowPolicy --------------------------------------------------
+
+/// What to do when a record is logged while the buffer is full.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum OverflowPolicy {
+    /// Drop the oldest buffered record to make room for the new one.
+    ///
+    /// Logging never waits.
+    #[default]
+    DropOldest,
+
+    /// Wait until the writer has made room in the buffer.
+    ///
+    /// Only [`LogSink::log()`] waits, [`LogSink::try_log()`] drops the new
+    /// record instead.
+    Block,
+}
+
+//----------- Config ----------------------------------------------------------
+
+/// Configuration for a [`LogSink`].
+#[derive(Clone, Debug)]
+pub struct Config {
+    /// The maximum number of buffered records.
+    capacity: usize,
+
+    /// The maximum number of records written at once.
+    batch_size: usize,
+
+    /// The maximum time records are buffered before being written.
+    flush_interval: Duration,
+
+    /// What to do when the buffer is full.
+    overflow_policy: OverflowPolicy,
+}
+
+impl Config {
+    /// Creates a new, default config.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Sets the maximum number of buffered records.
+    ///
+    /// The value is at least 1. The default value is 65,536.
+    pub fn set_capacity(&mut self, value: usize) {
+        self.capacity = value.max(1);
+    }
+
+    /// Sets the maximum number of records handed to the writer at once.
+    ///
+    /// Buffering this many records also causes them to be written without
+    /// waiting for the flush interval. The value is at least 1. The default
+    /// value is 1024.
+    pub fn set_batch_size(&mut self, value: usize) {
+        self.batch_size = value.max(1);
+    }
+
+    /// Sets the maximum time records are buffered before being written.
+    ///
+    /// The writer is also flushed at this interval. The value is at least
+    /// 1ms. The default value is 1 second.
+    pub fn set_flush_interval(&mut self, value: Duration) {
+        self.flush_interval = value.max(Duration::from_millis(1));
+    }
+
+    /// Sets what to do when a record is logged while the buffer is full.
+    ///
+    /// The default is [`OverflowPolicy::DropOldest`].
+    pub fn set_overflow_policy(&mut self, value: OverflowPolicy) {
+        self.overflow_policy = value;
+    }
+}
+
+//--- Default
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            capacity: DEFAULT_CAPACITY,
+            batch_size: DEFAULT_BATCH_SIZE,
+            flush_interval: DEFAULT_FLUSH_INTERVAL,
+            overflow_policy: OverflowPolicy::default(),
+        }
+    }
+}
+
+//----------- LogWriter -------------------------------------------------------
+
+/// The destination of the records of a [`LogSink`].
+///
+/// The writer is owned by the background task of the sink and only ever
+/// called from there, one call at a time.
+pub trait LogWriter<Record>: Send + 'static {
+    /// Writes a batch of records.
+    ///
+    /// The records are lost if this fails.
+    fn write<'a>(
+        &'a mut self,
+        records: &'a [Record],
+    ) -> Pin<Box<dyn Future<Output = io::Result<()>> + Send + 'a>>;
+
+    /// Makes sure all written records have reached their destination.
+    ///
+    /// The default implementation does nothing.
+    fn flush(
+        &mut self,
+    ) -> Pin<Box<dyn Future<Output = io::Result<()>> + Send + '_>> {
+        Box::pin(ready(Ok(())))
+    }
+
+    /// Starts a new output, e.g. reopens a log file.
+    ///
+    /// Called after all buffered records were written and the writer was
+    /// flushed. The default implementation does nothing.
+    fn rotate(
+        &mut self,
+    ) -> Pin<Box<dyn Future<Output = io::Result<()>> + Send + '_>> {
+        Box::pin(ready(Ok(())))
+    }
+}
+
+//----------- LineLogWriter ---------------------------------------------------
+
+/// A [`LogWriter`] writing records one per line to an [`AsyncWrite`].
+///
+/// Records are formatted using their [`Display`] implementation.
+pub struct LineLogWriter<W> {
+    /// The current output.
+    writer: W,
+
+    /// Creates a new output on rotation, if supported.
+    reopen: Option<Box<dyn FnMut() -> io::Result<W> + Send>>,
+
+    /// The buffer the lines of a batch are formatted into.
+    buf: Vec<u8>,
+}
+
+impl<W> LineLogWriter<W> {
+    /// Creates a writer writing to the given output.
+    ///
+    /// Rotation has no effect on this writer unless a function to create a
+    /// new output is set via [`with_reopen()`][Self::with_reopen].
+    #[must_use]
+    pub fn new(writer: W) -> Self {
+        Self {
+            writer,
+            reopen: None,
+            buf: Vec::new(),
+        }
+    }
+
+    /// Sets the function creating a new output on rotation.
+    ///
+    /// The current output is dropped once the new one was created
+    /// successfully. A [`LogSink`] flushes it before rotating.
+    #[must_use]
+    pub fn with_reopen(
+        mut self,
+        reopen: impl FnMut() -> io::Result<W> + Send + 'static,
+    ) -> Self {
+        self.reopen = Some(Box::new(reopen));
+        self
+    }
+}
+
+impl<W, Record> LogWriter<Record> for LineLogWriter<W>
+where
+    W: AsyncWrite + Unpin + Send + 'static,
+    Record: Display + Sync,
+{
+    fn write<'a>(
+        &'a mut self,
+        records: &'a [Record],
+    ) -> Pin<Box<dyn Future<Output = io::Result<()>> + Send + 'a>> {
+        Box::pin(async move {
+            self.buf.clear();
+            for record in records {
+                writeln!(self.buf, "{record}")?;
+            }
+            self.writer.write_all(&self.buf).await
+        })
+    }
+
+    fn flush(
+        &mut self,
+    ) -> Pin<Box<dyn Future<Output = io::Result<()>> + Send + '_>> {
+        Box::pin(self.writer.flush())
+    }
+
+    fn rotate(
+        &mut self,
+    ) -> Pin<Box<dyn Future<Output = io::Result<()>> + Send + '_>> {
+        Box::pin(async move {
+            if let Some(reopen) = &mut self.reopen {
+                self.writer = reopen()?;
+            }
+            Ok(())
+        })
+    }
+}
+
+impl<W> fmt::Debug for LineLogWriter<W> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LineLogWriter")
+            .field("reopen", &self.reopen.is_some())
+            .finish_non_exhaustive()
+    }
+}
+
+//----------- LogSinkMetrics --------------------------------------------------
+
+/// Counts of records processed by a [`LogSink`].
+#[derive(Debug, Default)]
+pub struct LogSinkMetrics {
+    /// The number of records logged.
+    num_logged: AtomicUsize,
+
+    /// The number of records written.
+    num_written: AtomicUsize,
+
+    /// The number of records dropped because the buffer was full.
+    num_dropped: AtomicUsize,
+
+    /// The number of records lost because writing them failed.
+    num_failed: AtomicUsize,
+}
+
+impl LogSinkMetrics {
+    /// The number of records logged.
+    pub fn num_logged(&self) -> usize {
+        self.num_logged.load(Ordering::Relaxed)
+    }
+
+    /// The number of records successfully handed to the writer.
+    pub fn num_written(&self) -> usize {
+        self.num_written.load(Ordering::Relaxed)
+    }
+
+    /// The number of records dropped because the buffer was full.
+    ///
+    /// A growing count means that records are logged faster than they can
+    /// be written.
+    pub fn num_dropped(&self) -> usize {
+        self.num_dropped.load(Ordering::Relaxed)
+    }
+
+    /// The number of records lost because writing them failed.
+    pub fn num_failed(&self) -> usize {
+        self.num_failed.load(Ordering::Relaxed)
+    }
+}
+
+//----------- LogSink ---------------------------------------------------------
+
+/// A buffered sink for log records.
+///
+/// Records logged to the sink are buffered and written by a background task
+/// owning a [`LogWriter`]. The sink can be cloned cheaply to log from many
+/// places, all clones share the same buffer. The background task ends once
+/// all clones were dropped and the remaining buffered records were written.
+pub struct LogSink<Record> {
+    /// The state shared with the background task.
+    shared: Arc<Shared<Record>>,
+
+    /// Sends commands to the background task.
+    commands:
+        mpsc::UnboundedSender<(Command, oneshot::Sender<io::Result<()>>)>,
+}
+
+impl<Record: Send + 'static> LogSink<Record> {
+    /// Creates a sink and spawns its background task.
+    ///
+    /// Must be called from within a Tokio runtime.
+    pub fn spawn(config: Config, writer: impl LogWriter<Record>) -> Self {
+        let shared = Arc::new(Shared {
+            config,
+            queue: Default::default(),
+            batch_ready: Notify::new(),
+            space: Notify::new(),
+            metrics: Default::default(),
+        });
+        let (tx, rx) = mpsc::unbounded_channel();
+        tokio::spawn(shared.clone().run(writer, rx));
+        Self {
+            shared,
+            commands: tx,
+        }
+    }
+
+    /// Logs a record without ever waiting.
+    ///
+    /// If the buffer is full, either the oldest buffered record or, with
+    /// [`OverflowPolicy::Block`], this record is dropped.
+    pub fn try_log(&self, record: Record) {
+        let mut queue = self.shared.queue.lock().unwrap();
+        if queue.len() >= self.shared.config.capacity {
+            match self.shared.config.overflow_policy {
+                OverflowPolicy::DropOldest => {
+                    queue.pop_front();
+                }
+                OverflowPolicy::Block => {
+                    self.shared.dropped(1);
+                    return;
+                }
+            }
+            self.shared.dropped(1);
+        }
+        self.shared.push(&mut queue, record);
+    }
+
+    /// Logs a record.
+    ///
+    /// With [`OverflowPolicy::Block`] this waits while the buffer is full.
+    /// Otherwise it behaves like [`try_log()`][Self::try_log].
+    pub async fn log(&self, record: Record) {
+        if self.shared.config.overflow_policy != OverflowPolicy::Block {
+            return self.try_log(record);
+        }
+        loop {
+            let notified = self.shared.space.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+            {
+                let mut queue = self.shared.queue.lock().unwrap();
+                if queue.len() < self.shared.config.capacity {
+                    self.shared.push(&mut queue, record);
+                    return;
+                }
+            }
+            if self.commands.is_closed() {
+                // The background task is gone and will never make room.
+                self.shared.dropped(1);
+                return;
+            }
+            notified.await;
+        }
+    }
+
+    /// Writes all buffered records and flushes the writer.
+    ///
+    /// Records logged while this is in progress may or may not be written.
+    pub async fn flush(&self) -> io::Result<()> {
+        self.command(Command::Flush).await
+    }
+
+    /// Writes all buffered records, flushes and then rotates the writer.
+    ///
+    /// See [`LogWriter::rotate()`].
+    pub async fn rotate(&self) -> io::Result<()> {
+        self.command(Command::Rotate).await
+    }
+
+    /// The number of records currently buffered.
+    pub fn num_buffered(&self) -> usize {
+        self.shared.queue.lock().unwrap().len()
+    }
+
+    /// Counts of records processed by this sink.
+    pub fn metrics(&self) -> Arc<LogSinkMetrics> {
+        self.shared.metrics.clone()
+    }
+
+    /// Sends a command to the background task and waits for its result.
+    async fn command(&self, command: Command) -> io::Result<()> {
+        let (tx, rx) = oneshot::channel();
+        if self.commands.send((command, tx)).is_err() {
+            return Err(stopped());
+        }
+        rx.await.unwrap_or_else(|_| Err(stopped()))
+    }
+}
+
+impl<Record> Clone for LogSink<Record> {
+    fn clone(&self) -> Self {
+        Self {
+            shared: self.shared.clone(),
+            commands: self.commands.clone(),
+        }
+    }
+}
+
+impl<Record> fmt::Debug for LogSink<Record> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LogSink")
+            .field("config", &self.shared.config)
+            .field("metrics", &self.shared.metrics)
+            .finish_non_exhaustive()
+    }
+}
+
+/// The error returned when the background task is no longer running.
+fn stopped() -> io::Error {
+    io::Error::new(io::ErrorKind::BrokenPipe, "log sink writer stopped")
+}
+
+//----------- Command ---------------------------------------------------------
+
+/// A request to the background task of a [`LogSink`].
+#[derive(Clone, Copy, Debug)]
+enum Command {
+    /// Write all buffered records and flush the writer.
+    Flush,
+
+    /// Flush, then rotate the writer.
+    Rotate,
+}
+
+//----------- Shared ----------------------------------------------------------
+
+/// The state shared by the clones of a [`LogSink`] and its background task.
+struct Shared<Record> {
+    /// The configuration.
+    config: Config,
+
+    /// The buffered records.
+    queue: Mutex<VecDeque<Record>>,
+
+    /// Wakes the background task when a full batch was buffered.
+    batch_ready: Notify,
+
+    /// Wakes loggers waiting for space in the buffer.
+    space: Notify,
+
+    /// Counts of processed records.
+    metrics: Arc<LogSinkMetrics>,
+}
+
+impl<Record: Send + 'static> Shared<Record> {
+    /// Appends a record to the buffer.
+    fn push(&self, queue: &mut VecDeque<Record>, record: Record) {
+        queue.push_back(record);
+        self.metrics.num_logged.fetch_add(1, Ordering::Relaxed);
+        if queue.len() == self.batch_threshold() {
+            self.batch_ready.notify_one();
+        }
+    }
+
+    /// Returns the number of buffered records that make a full batch.
+    ///
+    /// This is capped at the capacity so that a full buffer is always
+    /// written right away.
+    fn batch_threshold(&self) -> usize {
+        self.config.batch_size.min(self.config.capacity)
+    }
+
+    /// Counts dropped records.
+    fn dropped(&self, count: usize) {
+        self.metrics.num_dropped.fetch_add(count, Ordering::Relaxed);
+    }
+
+    /// Takes the next batch of records from the buffer.
+    fn next_batch(&self) -> Vec<Record> {
+        let mut queue = self.queue.lock().unwrap();
+        let len = queue.len().min(self.config.batch_size);
+        let batch = queue.drain(..len).collect();
+        drop(queue);
+        self.space.notify_waiters();
+        batch
+    }
+
+    /// Writes batches of records until the buffer is empty.
+    ///
+    /// If `full_only` is set, only full batches are written.
+    async fn write_batches(
+        &self,
+        writer: &mut impl LogWriter<Record>,
+        full_only: bool,
+    ) {
+        loop {
+            if full_only
+                && self.queue.lock().unwrap().len() < self.batch_threshold()
+            {
+                return;
+            }
+            let batch = self.next_batch();
+            if batch.is_empty() {
+                return;
+            }
+            trace!("Writing {} log records", batch.len());
+            match writer.write(&batch).await {
+                Ok(()) => {
+                    self.metrics
+                        .num_written
+                        .fetch_add(batch.len(), Ordering::Relaxed);
+                }
+                Err(err) => {
+                    warn!(
+                        "Failed to write {} log records: {err}",
+                        batch.len()
+                    );
+                    self.metrics
+                        .num_failed
+                        .fetch_add(batch.len(), Ordering::Relaxed);
+                }
+            }
+        }
+    }
+
+    /// Writes all buffered records and flushes the writer.
+    async fn flush(
+        &self,
+        writer: &mut impl LogWriter<Record>,
+    ) -> io::Result<()> {
+        self.write_batches(writer, false).await;
+        writer.flush().await
+    }
+
+    /// The background task writing the buffered records.
+    async fn run(
+        self: Arc<Self>,
+        mut writer: impl LogWriter<Record>,
+        mut commands: mpsc::UnboundedReceiver<(
+            Command,
+            oneshot::Sender<io::Result<()>>,
+        )>,
+    ) {
+        let period = self.config.flush_interval;
+        let mut interval =
+            tokio::time::interval_at(Instant::now() + period, period);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        loop {
+            tokio::select! {
+                command = commands.recv() => {
+                    let Some((command, tx)) = command else {
+                        break;
+                    };
+                    let mut res = self.flush(&mut writer).await;
+                    if let (Command::Rotate, Ok(())) = (command, &res) {
+                        res = writer.rotate().await;
+                    }
+                    let _ = tx.send(res);
+                }
+                _ = self.batch_ready.notified() => {
+                    self.write_batches(&mut writer, true).await;
+                }
+                _ = interval.tick() => {
+                    if let Err(err) = self.flush(&mut writer).await {
+                        warn!("Failed to flush log writer: {err}");
+                    }
+                }
+            }
+        }
+
+        // All sinks are gone, write what is left.
+        if let Err(err) = self.flush(&mut writer).await {
+            warn!("Failed to flush log writer: {err}");
+        }
+    }
+}
+
+//============ Tests ==========================================================
+
+#[cfg(test)]
+mod tests {
+    use core::future::{ready, Future};
+    use core::pin::Pin;
+
+    use std::boxed::Box;
+    use std::io;
+    use std::sync::{Arc, Mutex};
+    use std::vec::Vec;
+
+    use super::{Config, LineLogWriter, LogSink, LogWriter, OverflowPolicy};
+
+    //------------ Tests -----------------------------------------------------
+
+    #[tokio::test]
+    async fn records_are_written_on_flush() {
+        let (writer, written) = TestWriter::new();
+        let sink = LogSink::spawn(Config::new(), writer);
+
+        for i in 0..3 {
+            sink.try_log(i);
+        }
+        assert_eq!(sink.num_buffered(), 3);
+
+        sink.flush().await.unwrap();
+        assert_eq!(*written.lock().unwrap(), [vec![0, 1, 2]]);
+        assert_eq!(sink.num_buffered(), 0);
+        assert_eq!(sink.metrics().num_logged(), 3);
+        assert_eq!(sink.metrics().num_written(), 3);
+        assert_eq!(sink.metrics().num_dropped(), 0);
+    }
+
+    #[tokio::test]
+    async fn full_batches_are_written() {
+        let (writer, written) = TestWriter::new();
+        let mut config = Config::new();
+        config.set_batch_size(2);
+        let sink = LogSink::spawn(config, writer);
+
+        for i in 0..5 {
+            sink.log(i).await;
+        }
+        tokio::task::yield_now().await;
+        assert_eq!(*written.lock().unwrap(), [vec![0, 1], vec![2, 3]]);
+
+        sink.flush().await.unwrap();
+        assert_eq!(written.lock().unwrap().last().unwrap(), &[4]);
+    }
+
+    #[tokio::test]
+    async fn oldest_records_are_dropped_when_full() {
+        let (writer, written) = TestWriter::new();
+        let mut config = Config::new();
+        config.set_capacity(3);
+        let sink = LogSink::spawn(config, writer);
+
+        // The background task doesn't get to run in between.
+        for i in 0..5 {
+            sink.try_log(i);
+        }
+        sink.flush().await.unwrap();
+
+        assert_eq!(*written.lock().unwrap(), [vec![2, 3, 4]]);
+        assert_eq!(sink.metrics().num_logged(), 5);
+        assert_eq!(sink.metrics().num_dropped(), 2);
+    }
+
+    #[tokio::test]
+    async fn blocking_loggers_wait_for_space() {
+        let (writer, written) = TestWriter::new();
+        let mut config = Config::new();
+        config.set_capacity(2);
+        config.set_batch_size(2);
+        config.set_overflow_policy(OverflowPolicy::Block);
+        let sink = LogSink::spawn(config, writer);
+
+        for i in 0..3 {
+            sink.log(i).await;
+        }
+        // Without waiting the new record is dropped.
+        sink.try_log(3);
+        sink.try_log(4);
+        sink.flush().await.unwrap();
+
+        let written: Vec<i32> =
+            written.lock().unwrap().iter().flatten().copied().collect();
+        assert_eq!(written, [0, 1, 2, 3]);
+        assert_eq!(sink.metrics().num_dropped(), 1);
+    }
+
+    #[tokio::test]
+    async fn line_writer_writes_and_rotates() {
+        let mut num_reopened = 0;
+        let mut writer =
+            LineLogWriter::new(Vec::new()).with_reopen(move || {
+                num_reopened += 1;
+                Ok(vec![num_reopened])
+            });
+
+        writer.write(&["one", "two"]).await.unwrap();
+        LogWriter::<&str>::flush(&mut writer).await.unwrap();
+        assert_eq!(writer.writer, b"one\ntwo\n");
+
+        LogWriter::<&str>::rotate(&mut writer).await.unwrap();
+        writer.write(&["three"]).await.unwrap();
+        assert_eq!(writer.writer, b"\x01three\n");
+    }
+
+    //------------ TestWriter ------------------------------------------------
+
+    /// A writer recording the batches it was given.
+    struct TestWriter(Arc<Mutex<Vec<Vec<i32>>>>);
+
+    impl TestWriter {
+        fn new() -> (Self, Arc<Mutex<Vec<Vec<i32>>>>) {
+            let written = Arc::new(Mutex::new(Vec::new()));
+            (Self(written.clone()), written)
+        }
+    }
+
+    impl LogWriter<i32> for TestWriter {
+        fn write<'a>(
+            &'a mut self,
+            records: &'a [i32],
+        ) -> Pin<Box<dyn Future<Output = io::Result<()>> + Send + 'a>>
+        {
+            self.0.lock().unwrap().push(records.to_vec());
+            Box::pin(ready(Ok(())))
+        }
+    }
+}
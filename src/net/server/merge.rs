@@ -0,0 +1,378 @@
+//! A service that merges the answers of several services.
+//!
+//! The [`MergeService`] passes each request to every one of a set of inner
+//! services concurrently ("scatter") and combines the answer sections of
+//! their responses into a single response ("gather").
+//!
+//! This is intended for meta-query use cases, e.g. combining records for the
+//! same name from multiple authoritative sources.
+
+#![warn(missing_docs)]
+#![warn(clippy::missing_docs_in_private_items)]
+
+use core::future::{ready, Future, Ready};
+use core::pin::Pin;
+
+use std::boxed::Box;
+use std::vec::Vec;
+
+use futures_util::future::join_all;
+use futures_util::stream::{once, Once, StreamExt};
+use octseq::Octets;
+use tracing::{trace, warn};
+
+use crate::base::iana::{OptRcode, Rcode};
+use crate::base::message_builder::AdditionalBuilder;
+use crate::base::wire::Composer;
+use crate::base::{ParsedName, Record, StreamTarget};
+use crate::rdata::AllRecordData;
+
+use super::message::Request;
+use super::service::{CallResult, Service, ServiceError, ServiceResult};
+use super::util::mk_builder_for_target;
+
+//------------ MergeRcodePolicy ----------------------------------------------
+
+/// How the RCODE of the merged response is determined.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum MergeRcodePolicy {
+    /// Respond with NOERROR if at least one inner service responded with
+    /// NOERROR.
+    ///
+    /// Only the answers of the NOERROR responses are merged. If no inner
+    /// service responded with NOERROR the first response received is
+    /// returned unmodified.
+    #[default]
+    AnyNoError,
+
+    /// Respond with NOERROR only if every inner service responded with
+    /// NOERROR.
+    ///
+    /// Otherwise the first non-NOERROR response is returned unmodified.
+    AllNoError,
+}
+
+//------------ MergeService --------------------------------------------------
+
+/// A [`Service`] that merges the answers of several inner services.
+///
+/// Each request is passed to every inner service concurrently. Each inner
+/// service must produce a single response, inner services that respond with
+/// a stream of more than one response are rejected and cause the request to
+/// fail with [`ServiceError::InternalError`].
+///
+/// The answer sections of the responses selected by the configured
+/// [`MergeRcodePolicy`] are combined into one response, omitting duplicate
+/// records. Records are considered duplicates if their owner, class and
+/// record data are equal. Of a set of duplicates the first received is kept,
+/// including its TTL.
+///
+/// The merged response has the AA flag set only if all of the merged
+/// responses had it set. The authority and additional sections of the inner
+/// responses are not included in the merged response, except for the OPT
+/// record of the first merged response.
+///
+/// Inner services that return an error or feedback without a response are
+/// ignored. If no inner service produces a response the first error, if any,
+/// is returned.
+#[derive(Clone, Debug)]
+pub struct MergeService<Svc> {
+    /// The services to merge the answers of.
+    services: Vec<Svc>,
+
+    /// How to determine the RCODE of the merged response.
+    rcode_policy: MergeRcodePolicy,
+}
+
+impl<Svc> MergeService<Svc> {
+    /// Creates a new merging service for the given inner services.
+    #[must_use]
+    pub fn new(services: Vec<Svc>) -> Self {
+        Self {
+            services,
+            rcode_policy: MergeRcodePolicy::default(),
+        }
+    }
+
+    /// Sets the policy used to determine the RCODE of the merged response.
+    #[must_use]
+    pub fn with_rcode_policy(
+        mut self,
+        rcode_policy: MergeRcodePolicy,
+    ) -> Self {
+        self.rcode_policy = rcode_policy;
+        self
+    }
+}
+
+//--- Service
+
+impl<RequestOctets, RequestMeta, Svc> Service<RequestOctets, RequestMeta>
+    for MergeService<Svc>
+where
+    RequestOctets: Octets + Send + Sync + 'static,
+    RequestMeta: Clone + Default + Send + 'static,
+    Svc: Service<RequestOctets, RequestMeta>,
+    Svc::Future: Send + 'static,
+    Svc::Stream: Send + 'static,
+    Svc::Target: Composer + Default + Send + 'static,
+{
+    type Target = Svc::Target;
+    type Stream = Once<Ready<ServiceResult<Self::Target>>>;
+    type Future = Pin<Box<dyn Future<Output = Self::Stream> + Send>>;
+
+    fn call(
+        &self,
+        request: Request<RequestOctets, RequestMeta>,
+    ) -> Self::Future {
+        let futs: Vec<_> = self
+            .services
+            .iter()
+            .map(|svc| single_result(svc.call(request.clone())))
+            .collect();
+        let rcode_policy = self.rcode_policy;
+
+        Box::pin(async move {
+            let Ok(results) =
+                join_all(futs).await.into_iter().collect::<Result<_, _>>()
+            else {
+                warn!("Inner service produced more than one response, which cannot be merged");
+                return once(ready(Err(ServiceError::InternalError)));
+            };
+
+            once(ready(merge(&request, results, rcode_policy)))
+        })
+    }
+}
+
+//------------ Helper functions ----------------------------------------------
+
+/// Resolve an inner service call to its single result.
+///
+/// Returns `Err(())` if the inner service produced more than one result.
+async fn single_result<Fut, Str, Target>(
+    fut: Fut,
+) -> Result<Option<ServiceResult<Target>>, ()>
+where
+    Fut: Future<Output = Str>,
+    Str: futures_util::stream::Stream<Item = ServiceResult<Target>> + Unpin,
+{
+    let mut stream = fut.await;
+    let item = stream.next().await;
+    if item.is_some() && stream.next().await.is_some() {
+        return Err(());
+    }
+    Ok(item)
+}
+
+/// Merge the given inner service results into a single result.
+fn merge<RequestOctets, RequestMeta, Target>(
+    request: &Request<RequestOctets, RequestMeta>,
+    results: Vec<Option<ServiceResult<Target>>>,
+    rcode_policy: MergeRcodePolicy,
+) -> ServiceResult<Target>
+where
+    RequestOctets: Octets + Send + Sync,
+    Target: Composer + Default,
+{
+    let mut first_err = None;
+    let mut responses = Vec::with_capacity(results.len());
+
+    for res in results.into_iter().flatten() {
+        match res {
+            Ok(call_result) => {
+                if let (Some(response), _) = call_result.into_inner() {
+                    responses.push(response);
+                }
+            }
+            Err(err) => {
+                trace!("Ignoring inner service error: {err}");
+                first_err.get_or_insert(err);
+            }
+        }
+    }
+
+    if responses.is_empty() {
+        return Err(first_err.unwrap_or(ServiceError::InternalError));
+    }
+
+    let is_noerror = |response: &AdditionalBuilder<StreamTarget<_>>| {
+        response.as_message().opt_rcode() == OptRcode::NOERROR
+    };
+
+    let selected: Vec<_> = match rcode_policy {
+        MergeRcodePolicy::AnyNoError => {
+            responses.iter().filter(|r| is_noerror(r)).collect()
+        }
+        MergeRcodePolicy::AllNoError => {
+            if let Some(idx) = responses.iter().position(|r| !is_noerror(r)) {
+                let response = responses.swap_remove(idx);
+                return Ok(CallResult::new(response));
+            }
+            responses.iter().collect()
+        }
+    };
+
+    if selected.is_empty() {
+        let response = responses.swap_remove(0);
+        return Ok(CallResult::new(response));
+    }
+
+    // Gather the answers, skipping duplicates.
+    let mut answers: Vec<Record<ParsedName<_>, AllRecordData<_, _>>> =
+        Vec::new();
+    for response in &selected {
+        let msg = response.as_message();
+        for rr in msg.answer()? {
+            let Some(rr) =
+                rr?.into_record::<AllRecordData<_, ParsedName<_>>>()?
+            else {
+                continue;
+            };
+            if !answers.contains(&rr) {
+                answers.push(rr);
+            }
+        }
+    }
+
+    let aa = selected.iter().all(|r| r.header().aa());
+
+    let mut answer = mk_builder_for_target()
+        .start_answer(request.message(), Rcode::NOERROR)?;
+    answer.header_mut().set_aa(aa);
+    for rr in answers {
+        answer.push(rr)?;
+    }
+
+    let mut additional = answer.additional();
+    if let Some(opt) = selected[0].as_message().opt() {
+        additional.push(opt.as_record())?;
+    }
+
+    Ok(CallResult::new(additional))
+}
+
+//============ Tests =========================================================
+
+#[cfg(test)]
+mod tests {
+    use std::vec::Vec;
+
+    use futures_util::StreamExt;
+    use tokio::time::Instant;
+
+    use crate::base::iana::{Class, Rcode};
+    use crate::base::{Message, MessageBuilder, Name, Rtype};
+    use crate::net::server::message::{Request, UdpTransportContext};
+    use crate::net::server::service::{CallResult, Service, ServiceResult};
+    use crate::net::server::util::{
+        mk_builder_for_target, service_fn, ServiceFn,
+    };
+    use crate::rdata::A;
+
+    use super::{MergeRcodePolicy, MergeService};
+
+    type TestSvc = ServiceFn<
+        Vec<u8>,
+        fn(Request<Vec<u8>>, (Rcode, Vec<[u8; 4]>)) -> ServiceResult<Vec<u8>>,
+        (Rcode, Vec<[u8; 4]>),
+    >;
+
+    #[tokio::test]
+    async fn overlapping_answers_are_deduplicated() {
+        let svc = MergeService::new(vec![
+            mk_svc(Rcode::NOERROR, &[[192, 0, 2, 1], [192, 0, 2, 2]]),
+            mk_svc(Rcode::NOERROR, &[[192, 0, 2, 2], [192, 0, 2, 3]]),
+        ]);
+
+        let response = process(&svc).await;
+        assert_eq!(response.header().rcode(), Rcode::NOERROR);
+        assert_eq!(
+            addrs(&response),
+            [[192, 0, 2, 1], [192, 0, 2, 2], [192, 0, 2, 3]]
+        );
+        assert_eq!(response.opt().unwrap().udp_payload_size(), 1232);
+        assert_eq!(response.header_counts().arcount(), 1);
+    }
+
+    #[tokio::test]
+    async fn any_noerror_policy() {
+        let svc = MergeService::new(vec![
+            mk_svc(Rcode::NXDOMAIN, &[]),
+            mk_svc(Rcode::NOERROR, &[[192, 0, 2, 1]]),
+        ]);
+
+        let response = process(&svc).await;
+        assert_eq!(response.header().rcode(), Rcode::NOERROR);
+        assert_eq!(addrs(&response), [[192, 0, 2, 1]]);
+    }
+
+    #[tokio::test]
+    async fn all_noerror_policy() {
+        let svc = MergeService::new(vec![
+            mk_svc(Rcode::NOERROR, &[[192, 0, 2, 1]]),
+            mk_svc(Rcode::NXDOMAIN, &[]),
+        ])
+        .with_rcode_policy(MergeRcodePolicy::AllNoError);
+
+        let response = process(&svc).await;
+        assert_eq!(response.header().rcode(), Rcode::NXDOMAIN);
+        assert!(addrs(&response).is_empty());
+    }
+
+    //------------ Helper functions ------------------------------------------
+
+    fn mk_svc(rcode: Rcode, addrs: &[[u8; 4]]) -> TestSvc {
+        fn my_service(
+            req: Request<Vec<u8>>,
+            (rcode, addrs): (Rcode, Vec<[u8; 4]>),
+        ) -> ServiceResult<Vec<u8>> {
+            let builder = mk_builder_for_target();
+            let mut answer = builder.start_answer(req.message(), rcode)?;
+            answer.header_mut().set_aa(true);
+            for addr in addrs {
+                answer.push((
+                    Name::root_ref(),
+                    Class::IN,
+                    3600,
+                    A::from_octets(addr[0], addr[1], addr[2], addr[3]),
+                ))?;
+            }
+            let mut additional = answer.additional();
+            additional.opt(|opt| {
+                opt.set_udp_payload_size(1232);
+                Ok(())
+            })?;
+            Ok(CallResult::new(additional))
+        }
+
+        service_fn(my_service, (rcode, addrs.to_vec()))
+    }
+
+    async fn process(svc: &MergeService<TestSvc>) -> Message<Vec<u8>> {
+        let mut query = MessageBuilder::new_vec().question();
+        query.push((Name::root_ref(), Rtype::A)).unwrap();
+        let request = Request::new(
+            "127.0.0.1:12345".parse().unwrap(),
+            Instant::now(),
+            query.into_message(),
+            UdpTransportContext::default().into(),
+            (),
+        );
+
+        let mut stream = svc.call(request).await;
+        let call_result: CallResult<Vec<u8>> =
+            stream.next().await.unwrap().unwrap();
+        let response = call_result.into_inner().0.unwrap().finish();
+        Message::from_octets(response.as_dgram_slice().to_vec()).unwrap()
+    }
+
+    fn addrs(response: &Message<Vec<u8>>) -> Vec<[u8; 4]> {
+        response
+            .answer()
+            .unwrap()
+            .limit_to::<A>()
+            .map(|rr| rr.unwrap().data().addr().octets())
+            .collect()
+    }
+}
@@ -7,13 +7,17 @@ use bytes::Bytes;
 use core::time::Duration;
 
 use std::fmt::Debug;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex};
 use std::vec::Vec;
 
-use tokio::time::Instant;
+use tokio::sync::Notify;
+use tokio::time::{sleep_until, Instant};
 
-use crate::base::opt::AllOptData;
-use crate::base::{Message, Name};
+use crate::base::name::ParsedName;
+use crate::base::opt::{AllOptData, Cookie};
+use crate::base::wire::ParseError;
+use crate::base::{Message, Name, Question, QuestionSection};
 use crate::dep::octseq::Octets;
 use crate::net::client::request;
 use crate::net::client::request::{ComposeRequest, RequestMessage};
@@ -156,6 +160,175 @@ impl From<NonUdpTransportContext> for TransportSpecificContext {
     }
 }
 
+//------------ CancellationToken ---------------------------------------------
+
+/// Signals that the processing of a request is no longer wanted.
+///
+/// Every [`Request`] received by a server carries a token which is cancelled
+/// when the result of processing the request can no longer be delivered:
+///
+/// - For connection-oriented transports (TCP, TLS) when the connection is
+///   closed, whether by the client, by an idle timeout, by a
+///   [`ServerCommand`] or due to an error.
+/// - For all transports when the server is shut down.
+///
+/// When the token is cancelled the server drops the future and response
+/// stream returned by the [`Service`], so any processing that is awaiting
+/// is aborted at its next await point. Work that does not await (e.g. a
+/// long synchronous computation, or a task spawned by the service) is not
+/// aborted and should check [`is_cancelled()`] or await [`cancelled()`]
+/// itself to stop early.
+///
+/// Clones of a token share the same state, cancelling one cancels them all.
+///
+/// [`ServerCommand`]: crate::net::server::ServerCommand
+/// [`Service`]: crate::net::server::service::Service
+/// [`is_cancelled()`]: Self::is_cancelled
+/// [`cancelled()`]: Self::cancelled
+#[derive(Clone, Debug, Default)]
+pub struct CancellationToken {
+    /// The state shared by all clones of this token.
+    inner: Arc<CancellationInner>,
+}
+
+/// The shared state of a [`CancellationToken`].
+#[derive(Debug, Default)]
+struct CancellationInner {
+    /// Has the token been cancelled?
+    cancelled: AtomicBool,
+
+    /// Wakes tasks waiting for the token to be cancelled.
+    notify: Notify,
+}
+
+impl CancellationToken {
+    /// Creates a new token that has not been cancelled.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Cancels the token, waking all tasks waiting for cancellation.
+    ///
+    /// Cancelling an already cancelled token has no effect.
+    pub fn cancel(&self) {
+        if !self.inner.cancelled.swap(true, Ordering::SeqCst) {
+            self.inner.notify.notify_waiters();
+        }
+    }
+
+    /// Has the token been cancelled?
+    pub fn is_cancelled(&self) -> bool {
+        self.inner.cancelled.load(Ordering::SeqCst)
+    }
+
+    /// Waits until the token is cancelled.
+    ///
+    /// Completes immediately if the token has already been cancelled.
+    pub async fn cancelled(&self) {
+        let notified = self.inner.notify.notified();
+        tokio::pin!(notified);
+
+        // Register for notification before checking the flag so that a
+        // cancellation in between cannot be missed.
+        notified.as_mut().enable();
+        if self.is_cancelled() {
+            return;
+        }
+        notified.await
+    }
+}
+
+//------------ RequestDeadline -----------------------------------------------
+
+/// The time by which processing of a request should have produced a response.
+///
+/// Servers configured with a request timeout set the deadline of every
+/// request they receive to the time it was received plus the timeout. If the
+/// [`Service`] hasn't produced its first response by the deadline, the
+/// server stops processing the request by dropping the service future and
+/// response stream, as when a [`CancellationToken`] is cancelled, and
+/// answers with SERVFAIL and an Extended DNS Error instead. The deadline no
+/// longer applies once the first response has been produced, so it doesn't
+/// cut short long running response streams such as zone transfers.
+///
+/// Middleware can override the deadline of an individual request via
+/// [`set()`], e.g. to allow more time for requests known to be expensive or
+/// to set a deadline where the server has none. Changes made while the
+/// request is being processed are taken into account by the server.
+///
+/// Clones of a deadline share the same state, changing one changes them all.
+///
+/// [`Service`]: crate::net::server::service::Service
+/// [`set()`]: Self::set
+#[derive(Clone, Debug, Default)]
+pub struct RequestDeadline {
+    /// The state shared by all clones of this deadline.
+    inner: Arc<DeadlineInner>,
+}
+
+/// The shared state of a [`RequestDeadline`].
+#[derive(Debug, Default)]
+struct DeadlineInner {
+    /// The deadline, if any.
+    deadline: Mutex<Option<Instant>>,
+
+    /// Wakes tasks waiting for the deadline when it is changed.
+    notify: Notify,
+}
+
+impl RequestDeadline {
+    /// Creates a new, unset deadline.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the deadline, if set.
+    pub fn get(&self) -> Option<Instant> {
+        *self.inner.deadline.lock().unwrap()
+    }
+
+    /// Sets or, if `None`, clears the deadline.
+    pub fn set(&self, deadline: Option<Instant>) {
+        *self.inner.deadline.lock().unwrap() = deadline;
+        self.inner.notify.notify_waiters();
+    }
+
+    /// Has the deadline passed?
+    ///
+    /// Always false if no deadline is set.
+    pub fn is_expired(&self) -> bool {
+        self.get()
+            .map_or(false, |deadline| deadline <= Instant::now())
+    }
+
+    /// Waits until the deadline has passed.
+    ///
+    /// Waits forever if no deadline is set and none is set later on.
+    pub async fn expired(&self) {
+        loop {
+            let notified = self.inner.notify.notified();
+            tokio::pin!(notified);
+
+            // Register for notification before reading the deadline so that
+            // a change in between cannot be missed.
+            notified.as_mut().enable();
+            match self.get() {
+                Some(deadline) => {
+                    tokio::select! {
+                        _ = sleep_until(deadline) => {
+                            if self.is_expired() {
+                                return;
+                            }
+                        }
+                        _ = notified => {}
+                    }
+                }
+                None => notified.await,
+            }
+        }
+    }
+}
+
 //------------ Request -------------------------------------------------------
 
 /// A DNS message with additional properties describing its context.
@@ -173,6 +346,9 @@ where
     /// The network address of the connected client.
     client_addr: std::net::SocketAddr,
 
+    /// The local network address the request was received on, if known.
+    local_addr: Option<std::net::SocketAddr>,
+
     /// The instant when the request was received.
     received_at: Instant,
 
@@ -191,6 +367,12 @@ where
     /// still possible to generate responses that ignore this value.
     num_reserved_bytes: u16,
 
+    /// Cancelled when the response to this request is no longer wanted.
+    cancellation: CancellationToken,
+
+    /// The time by which a response should have been produced.
+    deadline: RequestDeadline,
+
     /// user defined metadata to associate with the request.
     ///
     /// For example this could be used to pass data from one [middleware]
@@ -215,14 +397,60 @@ where
     ) -> Self {
         Self {
             client_addr,
+            local_addr: None,
             received_at,
             message: Arc::new(message),
             transport_specific,
             num_reserved_bytes: 0,
+            cancellation: CancellationToken::new(),
+            deadline: RequestDeadline::new(),
             metadata,
         }
     }
 
+    /// Creates a new request for use in tests.
+    ///
+    /// This allows a [`Service`] to be exercised in isolation, without
+    /// standing up a server or any network transport. The request is marked
+    /// as received now and has default metadata.
+    ///
+    /// Pass a [`UdpTransportContext`] with a maximum response size hint to
+    /// exercise behaviour that depends on the UDP response size limit, e.g.
+    /// truncation.
+    ///
+    /// ```
+    /// use domain::base::{MessageBuilder, Name, Rtype};
+    /// use domain::net::server::message::{Request, UdpTransportContext};
+    ///
+    /// let mut query = MessageBuilder::new_vec().question();
+    /// query.push((Name::root_ref(), Rtype::A)).unwrap();
+    ///
+    /// let request: Request<Vec<u8>> = Request::for_test(
+    ///     query.into_message(),
+    ///     UdpTransportContext::new(Some(512)),
+    ///     "127.0.0.1:53".parse().unwrap(),
+    /// );
+    /// assert!(request.transport_ctx().is_udp());
+    /// ```
+    ///
+    /// [`Service`]: crate::net::server::service::Service
+    pub fn for_test(
+        message: Message<Octs>,
+        transport_specific: impl Into<TransportSpecificContext>,
+        client_addr: std::net::SocketAddr,
+    ) -> Self
+    where
+        Metadata: Default,
+    {
+        Self::new(
+            client_addr,
+            Instant::now(),
+            message,
+            transport_specific.into(),
+            Metadata::default(),
+        )
+    }
+
     /// When was this message received?
     pub fn received_at(&self) -> Instant {
         self.received_at
@@ -238,6 +466,29 @@ where
         self.client_addr
     }
 
+    /// On which local IP address and port number was this message received?
+    ///
+    /// `None` if the server did not know the specific local address, e.g.
+    /// because the socket is bound to a wildcard address.
+    pub fn local_addr(&self) -> Option<std::net::SocketAddr> {
+        self.local_addr
+    }
+
+    /// Record the local address the request was received on.
+    ///
+    /// Servers call this when they know the specific local address. Like
+    /// the cancellation token, middleware that creates a new request from an
+    /// existing one should pass on the local address of the original
+    /// request.
+    #[must_use]
+    pub fn with_local_addr(
+        mut self,
+        local_addr: Option<std::net::SocketAddr>,
+    ) -> Self {
+        self.local_addr = local_addr;
+        self
+    }
+
     /// Read access to the inner message
     pub fn message(&self) -> &Arc<Message<Octs>> {
         &self.message
@@ -259,14 +510,58 @@ where
         self.num_reserved_bytes
     }
 
+    /// Use the given token to signal cancellation of this request.
+    ///
+    /// Servers call this to tie the request to the connection or server it
+    /// was received by. Middleware that creates a new request from an
+    /// existing one should pass on the token of the original request.
+    #[must_use]
+    pub fn with_cancellation_token(
+        mut self,
+        token: CancellationToken,
+    ) -> Self {
+        self.cancellation = token;
+        self
+    }
+
+    /// The token signalling that the response to this request is no longer
+    /// wanted.
+    ///
+    /// See [`CancellationToken`] for when servers cancel it.
+    pub fn cancellation_token(&self) -> &CancellationToken {
+        &self.cancellation
+    }
+
+    /// Use the given deadline for processing this request.
+    ///
+    /// Like the cancellation token, middleware that creates a new request
+    /// from an existing one should pass on the deadline of the original
+    /// request.
+    #[must_use]
+    pub fn with_deadline(mut self, deadline: RequestDeadline) -> Self {
+        self.deadline = deadline;
+        self
+    }
+
+    /// The time by which processing of this request should have produced a
+    /// response.
+    ///
+    /// See [`RequestDeadline`] for how servers use it.
+    pub fn deadline(&self) -> &RequestDeadline {
+        &self.deadline
+    }
+
     /// Set user defined metadata to associate with this request.
     pub fn with_new_metadata<T>(self, new_metadata: T) -> Request<Octs, T> {
         Request::<Octs, T> {
             client_addr: self.client_addr,
+            local_addr: self.local_addr,
             received_at: self.received_at,
             message: self.message,
             transport_specific: self.transport_specific,
             num_reserved_bytes: self.num_reserved_bytes,
+            cancellation: self.cancellation,
+            deadline: self.deadline,
             metadata: new_metadata,
         }
     }
@@ -277,6 +572,52 @@ where
     }
 }
 
+impl<Octs, Metadata> Request<Octs, Metadata>
+where
+    Octs: Octets + Send + Sync,
+{
+    /// Returns the questions of the request message.
+    ///
+    /// Standard DNS queries have exactly one question but a request may
+    /// legitimately have none, e.g. when querying for a server cookie (see
+    /// [`is_server_cookie_query()`]), and experimental protocols may send
+    /// more than one. Unlike [`Message::sole_question()`] the result makes
+    /// each of these cases explicit so that services can handle them
+    /// deliberately instead of failing on them.
+    ///
+    /// Returns an error if the sole question of the request cannot be
+    /// parsed.
+    ///
+    /// [`is_server_cookie_query()`]: Self::is_server_cookie_query
+    pub fn questions(
+        &self,
+    ) -> Result<RequestQuestions<'_, Octs>, ParseError> {
+        match self.message.header_counts().qdcount() {
+            0 => Ok(RequestQuestions::None),
+            1 => self.message.sole_question().map(RequestQuestions::Sole),
+            _ => Ok(RequestQuestions::Multiple(self.message.question())),
+        }
+    }
+
+    /// Returns whether the request only asks for a server cookie.
+    ///
+    /// This is the case if the request has no question but a COOKIE option,
+    /// as described in [RFC 7873 section 5.4]. Such requests are answered
+    /// by the [`CookiesMiddlewareSvc`] if that is in use, otherwise services
+    /// that want to support them need to do so themselves.
+    ///
+    /// [RFC 7873 section 5.4]:
+    ///     https://datatracker.ietf.org/doc/html/rfc7873#section-5.4
+    /// [`CookiesMiddlewareSvc`]:
+    ///     crate::net::server::middleware::cookies::CookiesMiddlewareSvc
+    pub fn is_server_cookie_query(&self) -> bool {
+        self.message.header_counts().qdcount() == 0
+            && self.message.opt().map_or(false, |opt| {
+                opt.opt().iter::<Cookie>().next().is_some()
+            })
+    }
+}
+
 //--- Clone
 
 impl<Octs, Metadata> Clone for Request<Octs, Metadata>
@@ -287,15 +628,41 @@ where
     fn clone(&self) -> Self {
         Self {
             client_addr: self.client_addr,
+            local_addr: self.local_addr,
             received_at: self.received_at,
             message: Arc::clone(&self.message),
             transport_specific: self.transport_specific.clone(),
             num_reserved_bytes: self.num_reserved_bytes,
+            cancellation: self.cancellation.clone(),
+            deadline: self.deadline.clone(),
             metadata: self.metadata.clone(),
         }
     }
 }
 
+//------------ RequestQuestions ----------------------------------------------
+
+/// The questions of a request, distinguished by their number.
+///
+/// This is returned by [`Request::questions()`].
+#[derive(Debug)]
+pub enum RequestQuestions<'a, Octs: Octets + ?Sized + 'a> {
+    /// The request has no question.
+    ///
+    /// This is not an error in itself, e.g. a request querying for a server
+    /// cookie has no question, see [`Request::is_server_cookie_query()`].
+    None,
+
+    /// The request has exactly one question, as standard DNS queries do.
+    Sole(Question<ParsedName<Octs::Range<'a>>>),
+
+    /// The request has more than one question.
+    ///
+    /// This is not supported by standard DNS but used by some experimental
+    /// protocols. The section yields each question in turn.
+    Multiple(QuestionSection<'a, Octs>),
+}
+
 //--- TryFrom<Request<Octs>> for RequestMessage<Octs>>
 
 impl<Octs: Octets + Send + Sync + Debug + Clone> TryFrom<Request<Octs>>
@@ -349,3 +716,106 @@ fn dnssec_ok<Octs: Octets>(msg: &Message<Octs>) -> bool {
         false
     }
 }
+
+//============ Tests =========================================================
+
+#[cfg(test)]
+mod tests {
+    use core::time::Duration;
+
+    use std::vec::Vec;
+
+    use tokio::time::{timeout, Instant};
+
+    use crate::base::opt::cookie::ClientCookie;
+    use crate::base::opt::Cookie;
+    use crate::base::{Message, MessageBuilder, Name, Rtype};
+
+    use super::{
+        Request, RequestDeadline, RequestQuestions, UdpTransportContext,
+    };
+
+    #[tokio::test(start_paused = true)]
+    async fn deadline_can_be_changed_while_waiting() {
+        let deadline = RequestDeadline::new();
+        assert!(!deadline.is_expired());
+
+        let start = Instant::now();
+        deadline.set(Some(start + Duration::from_secs(1)));
+        let waiter = tokio::spawn({
+            let deadline = deadline.clone();
+            async move { deadline.expired().await }
+        });
+
+        // Extending the deadline via a clone, as middleware would, delays
+        // expiry.
+        tokio::task::yield_now().await;
+        deadline.set(Some(start + Duration::from_secs(5)));
+        timeout(Duration::from_secs(10), waiter)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(deadline.is_expired());
+        assert_eq!(Instant::now() - start, Duration::from_secs(5));
+
+        // Clearing the deadline means it never expires.
+        deadline.set(None);
+        assert!(!deadline.is_expired());
+        assert!(timeout(Duration::from_secs(60), deadline.expired())
+            .await
+            .is_err());
+    }
+
+    #[test]
+    fn questions() {
+        let req = mk_request(0, false);
+        assert!(matches!(req.questions(), Ok(RequestQuestions::None)));
+        assert!(!req.is_server_cookie_query());
+
+        let req = mk_request(1, false);
+        let Ok(RequestQuestions::Sole(question)) = req.questions() else {
+            panic!("expected a sole question");
+        };
+        assert_eq!(question.qtype(), Rtype::A);
+
+        let req = mk_request(2, false);
+        let Ok(RequestQuestions::Multiple(questions)) = req.questions()
+        else {
+            panic!("expected multiple questions");
+        };
+        assert_eq!(questions.count(), 2);
+    }
+
+    #[test]
+    fn server_cookie_query() {
+        let req = mk_request(0, true);
+        assert!(matches!(req.questions(), Ok(RequestQuestions::None)));
+        assert!(req.is_server_cookie_query());
+
+        // A cookie with a question is an ordinary query.
+        let req = mk_request(1, true);
+        assert!(!req.is_server_cookie_query());
+    }
+
+    //------------ Helper functions ------------------------------------------
+
+    fn mk_request(
+        num_questions: usize,
+        with_cookie: bool,
+    ) -> Request<Vec<u8>> {
+        let mut msg = MessageBuilder::new_vec().question();
+        for _ in 0..num_questions {
+            msg.push((Name::root_ref(), Rtype::A)).unwrap();
+        }
+        let mut msg = msg.additional();
+        if with_cookie {
+            let cookie = Cookie::new(ClientCookie::from_octets([1; 8]), None);
+            msg.opt(|opt| opt.cookie(cookie)).unwrap();
+        }
+        Request::for_test(
+            Message::from_octets(msg.finish()).unwrap(),
+            UdpTransportContext::default(),
+            "127.0.0.1:12345".parse().unwrap(),
+        )
+    }
+}
@@ -2,7 +2,12 @@
 
 //------------ ServerMetrics -------------------------------------------------
 
-use std::sync::atomic::{AtomicUsize, Ordering};
+use core::fmt::Write;
+use core::time::Duration;
+
+use std::boxed::Box;
+use std::string::String;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 
 /// Metrics common to all provided DNS server implementations.
 ///
@@ -26,6 +31,15 @@ pub struct ServerMetrics {
 
     /// The total number of responses sent since this metric collection was created.
     num_sent_responses: AtomicUsize,
+
+    /// The total number of duplicate requests dropped since this metric collection was created.
+    num_suppressed_duplicates: AtomicUsize,
+
+    /// The total number of requests exceeding a rate limit since this metric collection was created.
+    num_throttled_requests: AtomicUsize,
+
+    /// The distribution of the time taken to respond to requests.
+    latency: LatencyHistogram,
 }
 
 impl ServerMetrics {
@@ -169,4 +183,519 @@ impl ServerMetrics {
     pub fn dec_num_sent_responses(&self) {
         self.num_sent_responses.fetch_sub(1, Ordering::Relaxed);
     }
+
+    /// The number of duplicate DNS requests dropped while the original
+    /// request was still being processed.
+    ///
+    /// This will be zero unless duplicate suppression is enabled, see
+    /// [`dgram::Config::set_duplicate_suppression`].
+    ///
+    /// [`dgram::Config::set_duplicate_suppression`]:
+    ///     crate::net::server::dgram::Config::set_duplicate_suppression
+    pub fn num_suppressed_duplicates(&self) -> usize {
+        self.num_suppressed_duplicates.load(Ordering::Relaxed)
+    }
+
+    /// Set the number of suppressed duplicates metric.
+    pub fn set_num_suppressed_duplicates(&self, new_value: usize) {
+        self.num_suppressed_duplicates
+            .store(new_value, Ordering::Relaxed);
+    }
+
+    /// Increment the number of suppressed duplicates metric.
+    pub fn inc_num_suppressed_duplicates(&self) {
+        self.num_suppressed_duplicates
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Decrement the number of suppressed duplicates metric.
+    pub fn dec_num_suppressed_duplicates(&self) {
+        self.num_suppressed_duplicates
+            .fetch_sub(1, Ordering::Relaxed);
+    }
+
+    /// The number of DNS requests that exceeded a rate limit.
+    ///
+    /// This will be zero unless a [`RateLimitMiddlewareSvc`] is configured
+    /// to record into these metrics, see
+    /// [`RateLimitMiddlewareSvc::with_metrics`].
+    ///
+    /// [`RateLimitMiddlewareSvc`]:
+    ///     crate::net::server::middleware::rate_limit::RateLimitMiddlewareSvc
+    /// [`RateLimitMiddlewareSvc::with_metrics`]:
+    ///     crate::net::server::middleware::rate_limit::RateLimitMiddlewareSvc::with_metrics
+    pub fn num_throttled_requests(&self) -> usize {
+        self.num_throttled_requests.load(Ordering::Relaxed)
+    }
+
+    /// Set the number of throttled requests metric.
+    pub fn set_num_throttled_requests(&self, new_value: usize) {
+        self.num_throttled_requests
+            .store(new_value, Ordering::Relaxed);
+    }
+
+    /// Increment the number of throttled requests metric.
+    pub fn inc_num_throttled_requests(&self) {
+        self.num_throttled_requests.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Decrement the number of throttled requests metric.
+    pub fn dec_num_throttled_requests(&self) {
+        self.num_throttled_requests.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl ServerMetrics {
+    /// Renders the metrics of one or more servers in the Prometheus text
+    /// exposition format.
+    ///
+    /// Each metric is named `<name_prefix>_<metric>`, e.g.
+    /// `dns_inflight_requests`, and is preceded once by its HELP and TYPE
+    /// lines. It is followed by one sample per server, carrying the labels
+    /// given for that server, which should thus differ between servers, e.g.
+    /// by the transport or the address the server listens on. The number of
+    /// connections is only included for connection-oriented servers.
+    ///
+    /// The latencies recorded via [`record_latency`][Self::record_latency]
+    /// are rendered as a histogram named `<name_prefix>_latency_seconds`
+    /// with a bucket per power of two microseconds.
+    ///
+    /// Label values are escaped as required by the format. The prefix and
+    /// label names are used as is and must be valid Prometheus names.
+    pub fn encode_prometheus(
+        name_prefix: &str,
+        servers: &[(&[(&str, &str)], &ServerMetrics)],
+    ) -> String {
+        let full_name = |name: &str| {
+            if name_prefix.is_empty() {
+                String::from(name)
+            } else {
+                format!("{name_prefix}_{name}")
+            }
+        };
+
+        let mut out = String::new();
+        let mut metric =
+            |name: &str,
+             kind: &str,
+             help: &str,
+             value: fn(&ServerMetrics) -> Option<usize>| {
+                let name = full_name(name);
+                let mut samples = servers
+                    .iter()
+                    .filter_map(|(labels, metrics)| {
+                        Some((labels, value(metrics)?))
+                    })
+                    .peekable();
+                if samples.peek().is_none() {
+                    return;
+                }
+                // Writing to a String cannot fail.
+                let _ = writeln!(out, "# HELP {name} {help}");
+                let _ = writeln!(out, "# TYPE {name} {kind}");
+                for (labels, value) in samples {
+                    let labels = encode_labels(labels, None);
+                    let _ = writeln!(out, "{name}{labels} {value}");
+                }
+            };
+
+        metric(
+            "connections",
+            "gauge",
+            "The number of connections currently being handled.",
+            |metrics| {
+                metrics
+                    .num_connections
+                    .as_ref()
+                    .map(|_| metrics.num_connections())
+            },
+        );
+        metric(
+            "inflight_requests",
+            "gauge",
+            "The number of requests received but not yet responded to.",
+            |metrics| Some(metrics.num_inflight_requests()),
+        );
+        metric(
+            "pending_writes",
+            "gauge",
+            "The number of responses waiting to be sent to the client.",
+            |metrics| Some(metrics.num_pending_writes()),
+        );
+        metric(
+            "received_requests_total",
+            "counter",
+            "The total number of requests received.",
+            |metrics| Some(metrics.num_received_requests()),
+        );
+        metric(
+            "sent_responses_total",
+            "counter",
+            "The total number of responses sent.",
+            |metrics| Some(metrics.num_sent_responses()),
+        );
+        metric(
+            "suppressed_duplicates_total",
+            "counter",
+            "The total number of duplicate requests dropped.",
+            |metrics| Some(metrics.num_suppressed_duplicates()),
+        );
+        metric(
+            "throttled_requests_total",
+            "counter",
+            "The total number of requests exceeding a rate limit.",
+            |metrics| Some(metrics.num_throttled_requests()),
+        );
+
+        if servers.is_empty() {
+            return out;
+        }
+        let name = full_name("latency_seconds");
+        let _ = writeln!(
+            out,
+            "# HELP {name} The time taken to respond to requests."
+        );
+        let _ = writeln!(out, "# TYPE {name} histogram");
+        for (labels, metrics) in servers {
+            let mut total = 0;
+            for (index, count) in metrics.latency.counts().iter().enumerate()
+            {
+                total += count;
+                if index % 4 != 3
+                    || index == LatencyHistogram::NUM_BUCKETS - 1
+                {
+                    continue;
+                }
+                // Recorded latencies are truncated to whole microseconds,
+                // so all latencies in the bucket are below the next one.
+                let le = (LatencyHistogram::upper_bound(index) + 1) as f64
+                    / 1_000_000.0;
+                let le = format!("{le}");
+                let bucket_labels = encode_labels(labels, Some(("le", &le)));
+                let _ = writeln!(out, "{name}_bucket{bucket_labels} {total}");
+            }
+            let inf_labels = encode_labels(labels, Some(("le", "+Inf")));
+            let sum = metrics.latency.sum().as_secs_f64();
+            let labels = encode_labels(labels, None);
+            let _ = writeln!(out, "{name}_bucket{inf_labels} {total}");
+            let _ = writeln!(out, "{name}_sum{labels} {sum}");
+            let _ = writeln!(out, "{name}_count{labels} {total}");
+        }
+
+        out
+    }
+}
+
+impl ServerMetrics {
+    /// Records the time taken to respond to a request.
+    ///
+    /// The servers record the time from receiving a request to sending the
+    /// first response to it.
+    pub fn record_latency(&self, latency: Duration) {
+        self.latency.record(latency);
+    }
+
+    /// The number of request latencies recorded.
+    pub fn num_latency_samples(&self) -> u64 {
+        self.latency.count()
+    }
+
+    /// The median time taken to respond to a request.
+    ///
+    /// See [`latency_percentile`][Self::latency_percentile] for details.
+    pub fn latency_p50(&self) -> Option<Duration> {
+        self.latency_percentile(50.0)
+    }
+
+    /// The 99th percentile of the time taken to respond to a request.
+    ///
+    /// See [`latency_percentile`][Self::latency_percentile] for details.
+    pub fn latency_p99(&self) -> Option<Duration> {
+        self.latency_percentile(99.0)
+    }
+
+    /// The given percentile of the time taken to respond to a request.
+    ///
+    /// Latencies are recorded in buckets with a resolution of a microsecond
+    /// and a relative error of at most 25%. The upper bound of the bucket
+    /// containing the requested percentile is returned, so the result may
+    /// overestimate but never underestimate the actual latency. Latencies
+    /// above about an hour are all counted in the last bucket.
+    ///
+    /// Returns `None` if no latencies have been recorded yet.
+    pub fn latency_percentile(&self, percentile: f64) -> Option<Duration> {
+        self.latency.percentile(percentile)
+    }
+}
+
+//------------ LatencyHistogram ----------------------------------------------
+
+/// A histogram of latencies with log-linear buckets.
+///
+/// Latencies are recorded in microseconds. Values below four each have
+/// their own bucket, every power of two above that is split into four
+/// equally sized buckets.
+#[derive(Debug)]
+struct LatencyHistogram {
+    /// The number of recorded values per bucket.
+    buckets: Box<[AtomicU64]>,
+
+    /// The sum of the recorded values.
+    sum: AtomicU64,
+}
+
+impl LatencyHistogram {
+    /// The number of buckets, covering values up to 2^32 microseconds.
+    const NUM_BUCKETS: usize = 4 * 31;
+
+    fn record(&self, latency: Duration) {
+        let micros = u64::try_from(latency.as_micros()).unwrap_or(u64::MAX);
+        self.buckets[Self::bucket_index(micros)]
+            .fetch_add(1, Ordering::Relaxed);
+        self.sum.fetch_add(micros, Ordering::Relaxed);
+    }
+
+    fn count(&self) -> u64 {
+        self.buckets
+            .iter()
+            .map(|bucket| bucket.load(Ordering::Relaxed))
+            .sum()
+    }
+
+    fn sum(&self) -> Duration {
+        Duration::from_micros(self.sum.load(Ordering::Relaxed))
+    }
+
+    /// Returns the number of recorded values per bucket.
+    fn counts(&self) -> Box<[u64]> {
+        self.buckets
+            .iter()
+            .map(|bucket| bucket.load(Ordering::Relaxed))
+            .collect()
+    }
+
+    fn percentile(&self, percentile: f64) -> Option<Duration> {
+        let counts = self.counts();
+        let total: u64 = counts.iter().sum();
+        if total == 0 {
+            return None;
+        }
+
+        // The rank of the value at the requested percentile, starting at 1.
+        let fraction = percentile.clamp(0.0, 100.0) / 100.0;
+        let rank = ((fraction * total as f64).ceil() as u64).max(1);
+
+        let mut seen = 0;
+        for (index, count) in counts.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return Some(Duration::from_micros(Self::upper_bound(index)));
+            }
+        }
+        None
+    }
+
+    /// Returns the index of the bucket for the given value.
+    fn bucket_index(value: u64) -> usize {
+        if value < 4 {
+            return value as usize;
+        }
+        let exp = 63 - value.leading_zeros() as usize;
+        let sub = (value >> (exp - 2)) as usize & 3;
+        (4 * (exp - 1) + sub).min(Self::NUM_BUCKETS - 1)
+    }
+
+    /// Returns the largest value counted in the bucket with the given index.
+    fn upper_bound(index: usize) -> u64 {
+        if index < 4 {
+            return index as u64;
+        }
+        if index == Self::NUM_BUCKETS - 1 {
+            return u64::MAX;
+        }
+        let exp = index / 4 + 1;
+        let lower = (4 + (index % 4) as u64) << (exp - 2);
+        lower + (1 << (exp - 2)) - 1
+    }
+}
+
+impl Default for LatencyHistogram {
+    fn default() -> Self {
+        Self {
+            buckets: (0..Self::NUM_BUCKETS)
+                .map(|_| AtomicU64::new(0))
+                .collect(),
+            sum: AtomicU64::new(0),
+        }
+    }
+}
+
+//------------ Helper functions ----------------------------------------------
+
+/// Renders a set of Prometheus labels, including the braces.
+///
+/// Returns an empty string if there are no labels.
+fn encode_labels(
+    labels: &[(&str, &str)],
+    extra: Option<(&str, &str)>,
+) -> String {
+    let mut label_set = String::new();
+    for (name, value) in labels.iter().chain(extra.as_ref()) {
+        label_set.push(if label_set.is_empty() { '{' } else { ',' });
+        label_set.push_str(name);
+        label_set.push_str("=\"");
+        for ch in value.chars() {
+            match ch {
+                '\\' => label_set.push_str("\\\\"),
+                '"' => label_set.push_str("\\\""),
+                '\n' => label_set.push_str("\\n"),
+                ch => label_set.push(ch),
+            }
+        }
+        label_set.push('"');
+    }
+    if !label_set.is_empty() {
+        label_set.push('}');
+    }
+    label_set
+}
+
+//============ Tests =========================================================
+
+#[cfg(test)]
+mod tests {
+    use core::time::Duration;
+
+    use std::collections::{HashMap, HashSet};
+    use std::string::String;
+
+    use super::{LatencyHistogram, ServerMetrics};
+
+    #[test]
+    fn encode_prometheus() {
+        let tcp = ServerMetrics::connection_oriented();
+        tcp.set_num_connections(3);
+        tcp.set_num_inflight_requests(2);
+        tcp.set_num_received_requests(10);
+        tcp.set_num_sent_responses(8);
+        tcp.record_latency(Duration::from_micros(3));
+        tcp.record_latency(Duration::from_micros(100));
+        let udp = ServerMetrics::connection_less();
+        udp.set_num_received_requests(5);
+
+        let out = ServerMetrics::encode_prometheus(
+            "dns",
+            &[
+                (&[("transport", "tcp"), ("note", "a \"b\"\\")], &tcp),
+                (&[("transport", "udp")], &udp),
+            ],
+        );
+
+        // Parse the samples and check each metric is described once,
+        // before its samples.
+        let mut described = HashSet::new();
+        let mut samples = HashMap::new();
+        for line in out.lines() {
+            if let Some(help) = line.strip_prefix("# HELP ") {
+                let (name, _) = help.split_once(' ').unwrap();
+                assert!(described.insert(String::from(name)), "{name}");
+                continue;
+            }
+            if let Some(kind) = line.strip_prefix("# TYPE ") {
+                let (name, _) = kind.split_once(' ').unwrap();
+                assert!(described.contains(name));
+                continue;
+            }
+            let (name, rest) = line.split_once('{').unwrap();
+            let base = ["_bucket", "_sum", "_count"]
+                .iter()
+                .find_map(|suffix| name.strip_suffix(suffix))
+                .unwrap_or(name);
+            assert!(described.contains(base), "{name}");
+            let (labels, value) = rest.rsplit_once("} ").unwrap();
+            samples.insert(
+                (String::from(name), String::from(labels)),
+                value.parse::<f64>().unwrap(),
+            );
+        }
+
+        let tcp_labels = r#"transport="tcp",note="a \"b\"\\""#;
+        let sample = |name: &str, labels: &str| {
+            samples[&(String::from(name), String::from(labels))]
+        };
+        assert_eq!(sample("dns_connections", tcp_labels), 3.0);
+        assert_eq!(sample("dns_inflight_requests", tcp_labels), 2.0);
+        assert_eq!(sample("dns_pending_writes", tcp_labels), 0.0);
+        assert_eq!(sample("dns_received_requests_total", tcp_labels), 10.0);
+        assert_eq!(sample("dns_sent_responses_total", tcp_labels), 8.0);
+        assert_eq!(sample("dns_throttled_requests_total", tcp_labels), 0.0);
+        let udp_labels = r#"transport="udp""#;
+        assert_eq!(sample("dns_received_requests_total", udp_labels), 5.0);
+
+        // Connection-less servers have no connections metric.
+        assert!(!samples.contains_key(&(
+            String::from("dns_connections"),
+            String::from(udp_labels)
+        )));
+
+        // Latency buckets are cumulative.
+        let bucket = |le: &str| {
+            sample(
+                "dns_latency_seconds_bucket",
+                &format!(r#"{tcp_labels},le="{le}""#),
+            )
+        };
+        assert_eq!(bucket("0.000004"), 1.0);
+        assert_eq!(bucket("0.000064"), 1.0);
+        assert_eq!(bucket("0.000128"), 2.0);
+        assert_eq!(bucket("+Inf"), 2.0);
+        assert_eq!(sample("dns_latency_seconds_count", tcp_labels), 2.0);
+        assert_eq!(sample("dns_latency_seconds_sum", tcp_labels), 0.000103);
+        assert_eq!(sample("dns_latency_seconds_count", udp_labels), 0.0);
+
+        // Metrics without labels have no braces.
+        let metrics = ServerMetrics::connection_less();
+        let out = ServerMetrics::encode_prometheus("", &[(&[], &metrics)]);
+        assert!(out.contains("\ninflight_requests 0\n"));
+    }
+
+    #[test]
+    fn latency_percentiles() {
+        let metrics = ServerMetrics::connection_less();
+        assert_eq!(metrics.latency_p50(), None);
+
+        for millis in 1..=100 {
+            metrics.record_latency(Duration::from_millis(millis));
+        }
+        assert_eq!(metrics.num_latency_samples(), 100);
+
+        // Percentiles are never underestimated and at most 25% too large.
+        let check = |actual: Option<Duration>, expected: Duration| {
+            let actual = actual.unwrap();
+            assert!(actual >= expected, "{actual:?} < {expected:?}");
+            assert!(actual <= expected * 5 / 4, "{actual:?} > {expected:?}");
+        };
+        check(metrics.latency_p50(), Duration::from_millis(50));
+        check(metrics.latency_p99(), Duration::from_millis(99));
+        check(
+            metrics.latency_percentile(100.0),
+            Duration::from_millis(100),
+        );
+        check(metrics.latency_percentile(0.0), Duration::from_millis(1));
+    }
+
+    #[test]
+    fn latency_buckets_are_contiguous() {
+        let mut next = 0;
+        for index in 0..LatencyHistogram::NUM_BUCKETS - 1 {
+            assert_eq!(LatencyHistogram::bucket_index(next), index);
+            let upper = LatencyHistogram::upper_bound(index);
+            assert_eq!(LatencyHistogram::bucket_index(upper), index);
+            next = upper + 1;
+        }
+        assert_eq!(
+            LatencyHistogram::bucket_index(u64::MAX),
+            LatencyHistogram::NUM_BUCKETS - 1
+        );
+    }
 }
@@ -0,0 +1,310 @@
+//! Access control by client address.
+//!
+//! The [`AclMiddlewareSvc`] refuses requests from clients whose address is
+//! not permitted by an [`Acl`] and passes all other requests on unmodified.
+//!
+//! An [`Acl`] consists of allow and deny rules, each an [`IpPrefix`]. The
+//! rule with the longest prefix containing the client address decides
+//! whether the client is allowed. If an allow and a deny rule of the same
+//! prefix length match, the deny rule wins. Clients not matched by any rule
+//! are allowed only if there are no allow rules at all, so an [`Acl`] with
+//! allow rules only acts as an allowlist and one with deny rules only acts
+//! as a denylist.
+//!
+//! The rules of a running [`AclMiddlewareSvc`] can be replaced with
+//! [`AclMiddlewareSvc::set_acl`], e.g. to reload them from configuration
+//! without restarting the server.
+use core::future::{ready, Ready};
+use core::marker::PhantomData;
+use core::ops::ControlFlow;
+
+use std::sync::Arc;
+use std::vec::Vec;
+
+use arc_swap::ArcSwap;
+use futures_util::stream::{once, Once};
+use octseq::Octets;
+use tracing::debug;
+
+use crate::base::iana::OptRcode;
+use crate::base::message_builder::AdditionalBuilder;
+use crate::base::net::IpAddr;
+use crate::base::wire::Composer;
+use crate::base::StreamTarget;
+use crate::net::server::message::Request;
+use crate::net::server::middleware::stream::MiddlewareStream;
+use crate::net::server::service::{CallResult, Service, ServiceResult};
+use crate::net::server::util::{mk_error_response, IpPrefix};
+
+//----------- Acl -------------------------------------------------------------
+
+/// A set of rules deciding which clients are allowed.
+///
+/// See the [module documentation][self] for how the rules are applied.
+#[derive(Clone, Debug, Default)]
+pub struct Acl {
+    /// The prefixes of allowed clients.
+    allow: Vec<IpPrefix>,
+
+    /// The prefixes of denied clients.
+    deny: Vec<IpPrefix>,
+}
+
+impl Acl {
+    /// Creates an empty ACL allowing all clients.
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Creates an ACL from lists of allow and deny rules.
+    #[must_use]
+    pub fn from_rules(allow: Vec<IpPrefix>, deny: Vec<IpPrefix>) -> Self {
+        Self { allow, deny }
+    }
+
+    /// Adds a rule allowing clients with addresses in the given prefix.
+    #[must_use]
+    pub fn with_allow(mut self, prefix: IpPrefix) -> Self {
+        self.allow.push(prefix);
+        self
+    }
+
+    /// Adds a rule denying clients with addresses in the given prefix.
+    #[must_use]
+    pub fn with_deny(mut self, prefix: IpPrefix) -> Self {
+        self.deny.push(prefix);
+        self
+    }
+
+    /// Returns whether a client with the given address is allowed.
+    pub fn is_allowed(&self, addr: IpAddr) -> bool {
+        let longest_match = |rules: &[IpPrefix]| {
+            rules
+                .iter()
+                .filter(|prefix| prefix.contains(addr))
+                .map(IpPrefix::prefix_len)
+                .max()
+        };
+
+        match (longest_match(&self.allow), longest_match(&self.deny)) {
+            (Some(allow), Some(deny)) => allow > deny,
+            (Some(_), None) => true,
+            (None, Some(_)) => false,
+            (None, None) => self.allow.is_empty(),
+        }
+    }
+}
+
+//----------- AclMiddlewareSvc ------------------------------------------------
+
+/// A middleware service for refusing requests from disallowed clients.
+///
+/// Requests from clients whose address is not allowed by the configured
+/// [`Acl`] are answered with REFUSED without invoking the inner service. All
+/// other requests are passed to the inner service unmodified.
+#[derive(Clone, Debug)]
+pub struct AclMiddlewareSvc<RequestOctets, NextSvc, RequestMeta> {
+    /// The upstream [`Service`] to pass requests to and receive responses
+    /// from.
+    next_svc: NextSvc,
+
+    /// The rules deciding which clients are allowed.
+    ///
+    /// Shared between clones of this service so that replacing the rules
+    /// affects all of them.
+    acl: Arc<ArcSwap<Acl>>,
+
+    _phantom: PhantomData<(RequestOctets, RequestMeta)>,
+}
+
+impl<RequestOctets, NextSvc, RequestMeta>
+    AclMiddlewareSvc<RequestOctets, NextSvc, RequestMeta>
+{
+    /// Creates an instance of this middleware service.
+    #[must_use]
+    pub fn new(next_svc: NextSvc, acl: Acl) -> Self {
+        Self {
+            next_svc,
+            acl: Arc::new(ArcSwap::from_pointee(acl)),
+            _phantom: PhantomData,
+        }
+    }
+
+    /// Replace the rules deciding which clients are allowed.
+    ///
+    /// Requests already being processed are not affected.
+    pub fn set_acl(&self, acl: Acl) {
+        debug!("ACL replaced");
+        self.acl.store(Arc::new(acl));
+    }
+}
+
+impl<RequestOctets, NextSvc, RequestMeta>
+    AclMiddlewareSvc<RequestOctets, NextSvc, RequestMeta>
+where
+    RequestOctets: Octets + Send + Sync + Unpin,
+    RequestMeta: Clone + Default,
+    NextSvc: Service<RequestOctets, RequestMeta>,
+    NextSvc::Target: Composer + Default,
+{
+    /// Refuse the request if the client isn't allowed.
+    fn preprocess(
+        &self,
+        request: &Request<RequestOctets, RequestMeta>,
+    ) -> ControlFlow<AdditionalBuilder<StreamTarget<NextSvc::Target>>> {
+        let client_addr = request.client_addr();
+        if self.acl.load().is_allowed(client_addr.ip()) {
+            return ControlFlow::Continue(());
+        }
+
+        debug!("Refused request from disallowed client {client_addr}");
+        ControlFlow::Break(mk_error_response(
+            request.message(),
+            OptRcode::REFUSED,
+        ))
+    }
+}
+
+//--- Service
+
+impl<RequestOctets, NextSvc, RequestMeta> Service<RequestOctets, RequestMeta>
+    for AclMiddlewareSvc<RequestOctets, NextSvc, RequestMeta>
+where
+    RequestOctets: Octets + Send + Sync + 'static + Unpin,
+    RequestMeta: Clone + Default,
+    NextSvc: Service<RequestOctets, RequestMeta>,
+    NextSvc::Target: Composer + Default,
+    NextSvc::Future: Unpin,
+{
+    type Target = NextSvc::Target;
+    type Stream = MiddlewareStream<
+        NextSvc::Future,
+        NextSvc::Stream,
+        NextSvc::Stream,
+        Once<Ready<ServiceResult<Self::Target>>>,
+        ServiceResult<Self::Target>,
+    >;
+    type Future = Ready<Self::Stream>;
+
+    fn call(
+        &self,
+        request: Request<RequestOctets, RequestMeta>,
+    ) -> Self::Future {
+        match self.preprocess(&request) {
+            ControlFlow::Continue(()) => {
+                let svc_call_fut = self.next_svc.call(request);
+                ready(MiddlewareStream::IdentityFuture(svc_call_fut))
+            }
+            ControlFlow::Break(response) => ready(MiddlewareStream::Result(
+                once(ready(Ok(CallResult::new(response)))),
+            )),
+        }
+    }
+}
+
+//============ Tests =========================================================
+
+#[cfg(test)]
+mod tests {
+    use crate::base::iana::Rcode;
+    use crate::base::net::IpAddr;
+    use crate::net::server::middleware::test_helpers::{
+        process_from, service,
+    };
+    use crate::net::server::util::IpPrefix;
+
+    use super::{Acl, AclMiddlewareSvc};
+
+    //------------ Tests -----------------------------------------------------
+
+    #[test]
+    fn longest_matching_rule_wins() {
+        let acl = Acl::new()
+            .with_allow(prefix("192.0.2.0", 24))
+            .with_deny(prefix("192.0.2.128", 25))
+            .with_allow(prefix("192.0.2.200", 32));
+
+        assert!(acl.is_allowed(addr("192.0.2.1")));
+        assert!(!acl.is_allowed(addr("192.0.2.129")));
+        assert!(acl.is_allowed(addr("192.0.2.200")));
+
+        // Not matched at all while there are allow rules.
+        assert!(!acl.is_allowed(addr("198.51.100.1")));
+        assert!(!acl.is_allowed(addr("2001:db8::1")));
+
+        // Deny wins over allow for the same prefix length.
+        let acl = Acl::from_rules(
+            vec![prefix("2001:db8::", 32)],
+            vec![prefix("2001:db8::", 32)],
+        );
+        assert!(!acl.is_allowed(addr("2001:db8::1")));
+    }
+
+    #[test]
+    fn deny_only_acl_allows_unmatched_clients() {
+        assert!(Acl::new().is_allowed(addr("192.0.2.1")));
+
+        let acl = Acl::new().with_deny(prefix("192.0.2.0", 24));
+        assert!(!acl.is_allowed(addr("192.0.2.1")));
+        assert!(acl.is_allowed(addr("198.51.100.1")));
+        assert!(acl.is_allowed(addr("2001:db8::1")));
+    }
+
+    #[tokio::test]
+    async fn allowed_client_is_passed_through() {
+        let svc = AclMiddlewareSvc::new(service(), acl());
+        let response = process_from(&svc, "192.0.2.1:12345").await;
+        assert_eq!(response.header().rcode(), Rcode::NOERROR);
+    }
+
+    #[tokio::test]
+    async fn denied_client_is_refused() {
+        let svc = AclMiddlewareSvc::new(service(), acl());
+        let response = process_from(&svc, "198.51.100.1:12345").await;
+        assert_eq!(response.header().rcode(), Rcode::REFUSED);
+        assert_eq!(response.header_counts().qdcount(), 1);
+    }
+
+    #[tokio::test]
+    async fn v4_mapped_clients_match_v4_rules() {
+        let acl = acl().with_deny(prefix("192.0.2.128", 25));
+        assert!(acl.is_allowed(addr("::ffff:192.0.2.1")));
+        assert!(!acl.is_allowed(addr("::ffff:192.0.2.129")));
+        assert!(!acl.is_allowed(addr("::ffff:198.51.100.1")));
+
+        let svc = AclMiddlewareSvc::new(service(), acl);
+        let response = process_from(&svc, "[::ffff:192.0.2.1]:12345").await;
+        assert_eq!(response.header().rcode(), Rcode::NOERROR);
+        let response = process_from(&svc, "[::ffff:192.0.2.129]:12345").await;
+        assert_eq!(response.header().rcode(), Rcode::REFUSED);
+    }
+
+    #[tokio::test]
+    async fn replaced_acl_applies_to_subsequent_requests() {
+        let svc = AclMiddlewareSvc::new(service(), Acl::new());
+        let clone = svc.clone();
+        let response = process_from(&clone, "198.51.100.1:12345").await;
+        assert_eq!(response.header().rcode(), Rcode::NOERROR);
+
+        svc.set_acl(acl());
+        let response = process_from(&clone, "198.51.100.1:12345").await;
+        assert_eq!(response.header().rcode(), Rcode::REFUSED);
+        let response = process_from(&clone, "192.0.2.1:12345").await;
+        assert_eq!(response.header().rcode(), Rcode::NOERROR);
+    }
+
+    //------------ Helper functions ------------------------------------------
+
+    fn addr(addr: &str) -> IpAddr {
+        addr.parse().unwrap()
+    }
+
+    fn prefix(addr_str: &str, len: u8) -> IpPrefix {
+        IpPrefix::new(addr(addr_str), len)
+    }
+
+    fn acl() -> Acl {
+        Acl::new().with_allow(prefix("192.0.2.0", 24))
+    }
+}
@@ -0,0 +1,485 @@
+//! Blocking of requests for listed names.
+//!
+//! A common use of DNS filtering is to blackhole a list of unwanted domains,
+//! e.g. those serving ads or malware. The [`BlocklistMiddlewareSvc`] answers
+//! requests for names in a [`Blocklist`] with a configured
+//! [`BlockResponse`], either NXDOMAIN or sinkhole addresses, and passes all
+//! other requests on unmodified.
+//!
+//! This is a simpler alternative to the [`RpzMiddlewareSvc`] intended for
+//! large lists that only need a single kind of response. The list is stored
+//! as a label trie so that lookups take time proportional to the number of
+//! labels of the query name, no matter the size of the list.
+//!
+//! The list can be replaced at runtime, e.g. after fetching an updated block
+//! list, via [`BlocklistMiddlewareSvc::set_blocklist()`]. The number of
+//! blocked requests is tracked in [`BlocklistMetrics`].
+//!
+//! [`RpzMiddlewareSvc`]: super::rpz::RpzMiddlewareSvc
+use core::future::{ready, Ready};
+use core::marker::PhantomData;
+use core::ops::ControlFlow;
+
+use std::boxed::Box;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::vec::Vec;
+
+use arc_swap::ArcSwap;
+use futures_util::stream::{once, Once};
+use octseq::Octets;
+use tracing::{debug, warn};
+
+use crate::base::iana::{OptRcode, Rcode};
+use crate::base::message_builder::{AdditionalBuilder, PushError};
+use crate::base::name::Label;
+use crate::base::net::IpAddr;
+use crate::base::wire::Composer;
+use crate::base::{Message, Rtype, StreamTarget, ToName, Ttl};
+use crate::net::server::message::Request;
+use crate::net::server::middleware::stream::MiddlewareStream;
+use crate::net::server::service::{CallResult, Service, ServiceResult};
+use crate::net::server::util::{mk_builder_for_target, mk_error_response};
+use crate::rdata::{Aaaa, A};
+
+//----------- Constants -------------------------------------------------------
+
+/// The TTL of records synthesized by [`BlockResponse::Sinkhole`].
+const SINKHOLE_TTL: Ttl = Ttl::from_secs(300);
+
+//----------- BlockResponse ---------------------------------------------------
+
+/// The response to a request for a blocked name.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub enum BlockResponse {
+    /// Answer with NXDOMAIN, pretending the name does not exist.
+    #[default]
+    NxDomain,
+
+    /// Answer with the given addresses, e.g. those of a sinkhole server.
+    ///
+    /// A queries are answered with the IPv4 addresses and AAAA queries with
+    /// the IPv6 addresses. Queries for other types, or for which there are no
+    /// addresses of the queried family, are answered with NOERROR and an
+    /// empty answer section.
+    Sinkhole(Vec<IpAddr>),
+}
+
+//----------- Blocklist -------------------------------------------------------
+
+/// A set of blocked names.
+///
+/// Names are added either as an exact name, blocking only that name, or as a
+/// suffix, blocking the name itself and all names below it. Names are
+/// compared case insensitively.
+#[derive(Clone, Debug, Default)]
+pub struct Blocklist {
+    /// The root of the label trie.
+    root: BlocklistNode,
+
+    /// The number of names added.
+    len: usize,
+}
+
+impl Blocklist {
+    /// Creates an empty blocklist.
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a name to block exactly.
+    #[must_use]
+    pub fn with_name(mut self, name: &impl ToName) -> Self {
+        self.add_name(name);
+        self
+    }
+
+    /// Adds a name to block together with all names below it.
+    #[must_use]
+    pub fn with_suffix(mut self, name: &impl ToName) -> Self {
+        self.add_suffix(name);
+        self
+    }
+
+    /// Adds a name to block exactly.
+    pub fn add_name(&mut self, name: &impl ToName) {
+        let node = self.root.get_or_insert(name);
+        if !node.exact {
+            node.exact = true;
+            self.len += 1;
+        }
+    }
+
+    /// Adds a name to block together with all names below it.
+    pub fn add_suffix(&mut self, name: &impl ToName) {
+        let node = self.root.get_or_insert(name);
+        if !node.suffix {
+            node.suffix = true;
+            self.len += 1;
+        }
+    }
+
+    /// Returns the number of names in the list.
+    ///
+    /// A name added both exactly and as a suffix is counted twice.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Returns whether the list contains no names.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Returns whether the given name is blocked.
+    pub fn is_blocked(&self, qname: &impl ToName) -> bool {
+        let mut node = &self.root;
+        let mut key = [0u8; Label::MAX_LEN];
+        for label in qname.iter_labels().rev().filter(|l| !l.is_root()) {
+            if node.suffix {
+                return true;
+            }
+            let key = lowercase_label(label, &mut key);
+            match node.children.get(key) {
+                Some(child) => node = child,
+                None => return false,
+            }
+        }
+        node.exact || node.suffix
+    }
+}
+
+//----------- BlocklistNode ---------------------------------------------------
+
+/// A node of the label trie of a [`Blocklist`].
+#[derive(Clone, Debug, Default)]
+struct BlocklistNode {
+    /// The nodes of the names one label below, by lowercase label.
+    children: HashMap<Box<[u8]>, BlocklistNode>,
+
+    /// Is the name of this node blocked exactly?
+    exact: bool,
+
+    /// Are the name of this node and all names below it blocked?
+    suffix: bool,
+}
+
+impl BlocklistNode {
+    /// Returns the node for the given name, creating it if needed.
+    fn get_or_insert(&mut self, name: &impl ToName) -> &mut Self {
+        let mut node = self;
+        let mut key = [0u8; Label::MAX_LEN];
+        for label in name.iter_labels().rev().filter(|l| !l.is_root()) {
+            let key = lowercase_label(label, &mut key);
+            node = node.children.entry(key.into()).or_default();
+        }
+        node
+    }
+}
+
+/// Copies the lowercase form of a label into a buffer.
+fn lowercase_label<'a>(
+    label: &Label,
+    buf: &'a mut [u8; Label::MAX_LEN],
+) -> &'a [u8] {
+    let key = &mut buf[..label.len()];
+    key.copy_from_slice(label.as_slice());
+    key.make_ascii_lowercase();
+    key
+}
+
+//----------- BlocklistMetrics ------------------------------------------------
+
+/// Counts of requests processed by a [`BlocklistMiddlewareSvc`].
+#[derive(Debug, Default)]
+pub struct BlocklistMetrics {
+    /// The number of requests for blocked names.
+    num_blocked: AtomicUsize,
+}
+
+impl BlocklistMetrics {
+    /// The number of requests for blocked names.
+    pub fn num_blocked(&self) -> usize {
+        self.num_blocked.load(Ordering::Relaxed)
+    }
+}
+
+//----------- BlocklistMiddlewareSvc ------------------------------------------
+
+/// A middleware service for blocking requests for listed names.
+///
+/// Requests whose query name is blocked by the configured [`Blocklist`] are
+/// answered with the configured [`BlockResponse`], NXDOMAIN by default,
+/// without invoking the inner service. All other requests are passed to the
+/// inner service unmodified.
+///
+/// Responses synthesized by this service don't include an OPT record, place
+/// an [`EdnsMiddlewareSvc`] in front of this service to add one where
+/// needed.
+///
+/// [`EdnsMiddlewareSvc`]: super::edns::EdnsMiddlewareSvc
+#[derive(Clone, Debug)]
+pub struct BlocklistMiddlewareSvc<RequestOctets, NextSvc, RequestMeta> {
+    /// The upstream [`Service`] to pass requests to and receive responses
+    /// from.
+    next_svc: NextSvc,
+
+    /// The names to block.
+    ///
+    /// Shared between clones of this service so that replacing the list
+    /// affects all of them.
+    blocklist: Arc<ArcSwap<Blocklist>>,
+
+    /// The response to requests for blocked names.
+    response: BlockResponse,
+
+    /// Counts of blocked requests.
+    ///
+    /// Shared between clones of this service.
+    metrics: Arc<BlocklistMetrics>,
+
+    _phantom: PhantomData<(RequestOctets, RequestMeta)>,
+}
+
+impl<RequestOctets, NextSvc, RequestMeta>
+    BlocklistMiddlewareSvc<RequestOctets, NextSvc, RequestMeta>
+{
+    /// Creates an instance of this middleware service.
+    #[must_use]
+    pub fn new(next_svc: NextSvc, blocklist: Blocklist) -> Self {
+        Self {
+            next_svc,
+            blocklist: Arc::new(ArcSwap::from_pointee(blocklist)),
+            response: BlockResponse::default(),
+            metrics: Default::default(),
+            _phantom: PhantomData,
+        }
+    }
+
+    /// Sets the response to requests for blocked names.
+    ///
+    /// Defaults to [`BlockResponse::NxDomain`].
+    #[must_use]
+    pub fn with_response(mut self, response: BlockResponse) -> Self {
+        self.response = response;
+        self
+    }
+
+    /// Replace the list of names blocked by this service.
+    ///
+    /// Requests already being processed are not affected.
+    pub fn set_blocklist(&self, blocklist: Blocklist) {
+        debug!("Blocklist replaced with {} names", blocklist.len());
+        self.blocklist.store(Arc::new(blocklist));
+    }
+
+    /// Counts of requests processed by this service.
+    pub fn metrics(&self) -> Arc<BlocklistMetrics> {
+        self.metrics.clone()
+    }
+}
+
+impl<RequestOctets, NextSvc, RequestMeta>
+    BlocklistMiddlewareSvc<RequestOctets, NextSvc, RequestMeta>
+where
+    RequestOctets: Octets + Send + Sync + Unpin,
+    RequestMeta: Clone + Default,
+    NextSvc: Service<RequestOctets, RequestMeta>,
+    NextSvc::Target: Composer + Default,
+{
+    /// Answer the request if its query name is blocked.
+    fn preprocess(
+        &self,
+        request: &Request<RequestOctets, RequestMeta>,
+    ) -> ControlFlow<AdditionalBuilder<StreamTarget<NextSvc::Target>>> {
+        let msg = request.message();
+        let Ok(question) = msg.sole_question() else {
+            return ControlFlow::Continue(());
+        };
+
+        if !self.blocklist.load().is_blocked(&question.qname()) {
+            return ControlFlow::Continue(());
+        }
+
+        debug!(
+            "Blocked request for {} from {}",
+            question.qname(),
+            request.client_addr()
+        );
+        self.metrics.num_blocked.fetch_add(1, Ordering::Relaxed);
+
+        let response = self
+            .mk_response(msg, &question.qname(), question.qtype())
+            .unwrap_or_else(|err| {
+                warn!("Failed to build blocklist response: {err}");
+                mk_error_response(msg, OptRcode::SERVFAIL)
+            });
+
+        ControlFlow::Break(response)
+    }
+
+    /// Build the response to a request for a blocked name.
+    fn mk_response(
+        &self,
+        msg: &Message<RequestOctets>,
+        qname: &impl ToName,
+        qtype: Rtype,
+    ) -> Result<AdditionalBuilder<StreamTarget<NextSvc::Target>>, PushError>
+    {
+        let builder = mk_builder_for_target();
+        let addrs = match &self.response {
+            BlockResponse::NxDomain => {
+                let answer = builder.start_answer(msg, Rcode::NXDOMAIN)?;
+                return Ok(answer.additional());
+            }
+            BlockResponse::Sinkhole(addrs) => addrs,
+        };
+
+        let mut answer = builder.start_answer(msg, Rcode::NOERROR)?;
+        for addr in addrs {
+            match (addr, qtype) {
+                (IpAddr::V4(addr), Rtype::A) => {
+                    answer.push((qname, SINKHOLE_TTL, A::new(*addr)))?
+                }
+                (IpAddr::V6(addr), Rtype::AAAA) => {
+                    answer.push((qname, SINKHOLE_TTL, Aaaa::new(*addr)))?
+                }
+                _ => {}
+            }
+        }
+        Ok(answer.additional())
+    }
+}
+
+//--- Service
+
+impl<RequestOctets, NextSvc, RequestMeta> Service<RequestOctets, RequestMeta>
+    for BlocklistMiddlewareSvc<RequestOctets, NextSvc, RequestMeta>
+where
+    RequestOctets: Octets + Send + Sync + 'static + Unpin,
+    RequestMeta: Clone + Default,
+    NextSvc: Service<RequestOctets, RequestMeta>,
+    NextSvc::Target: Composer + Default,
+    NextSvc::Future: Unpin,
+{
+    type Target = NextSvc::Target;
+    type Stream = MiddlewareStream<
+        NextSvc::Future,
+        NextSvc::Stream,
+        NextSvc::Stream,
+        Once<Ready<ServiceResult<Self::Target>>>,
+        ServiceResult<Self::Target>,
+    >;
+    type Future = Ready<Self::Stream>;
+
+    fn call(
+        &self,
+        request: Request<RequestOctets, RequestMeta>,
+    ) -> Self::Future {
+        match self.preprocess(&request) {
+            ControlFlow::Continue(()) => {
+                let svc_call_fut = self.next_svc.call(request);
+                ready(MiddlewareStream::IdentityFuture(svc_call_fut))
+            }
+            ControlFlow::Break(response) => ready(MiddlewareStream::Result(
+                once(ready(Ok(CallResult::new(response)))),
+            )),
+        }
+    }
+}
+
+//============ Tests =========================================================
+
+#[cfg(test)]
+mod tests {
+    use crate::base::iana::Rcode;
+    use crate::base::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+    use crate::base::Rtype;
+    use crate::net::server::middleware::test_helpers::{
+        answer, name, process, service,
+    };
+
+    use super::{BlockResponse, Blocklist, BlocklistMiddlewareSvc};
+
+    //------------ Tests -----------------------------------------------------
+
+    #[test]
+    fn names_and_suffixes_are_matched() {
+        let blocklist = blocklist();
+        assert_eq!(blocklist.len(), 2);
+
+        // Exact names only match themselves.
+        assert!(blocklist.is_blocked(&name("Exact.Example")));
+        assert!(!blocklist.is_blocked(&name("www.exact.example")));
+        assert!(!blocklist.is_blocked(&name("example")));
+
+        // Suffixes match themselves and all names below.
+        assert!(blocklist.is_blocked(&name("ads.example")));
+        assert!(blocklist.is_blocked(&name("a.b.ADS.example")));
+        assert!(!blocklist.is_blocked(&name("bads.example")));
+        assert!(!blocklist.is_blocked(&name(".")));
+    }
+
+    #[test]
+    fn root_suffix_blocks_everything() {
+        let blocklist = Blocklist::new().with_suffix(&name("."));
+        assert!(blocklist.is_blocked(&name(".")));
+        assert!(blocklist.is_blocked(&name("www.example")));
+    }
+
+    #[tokio::test]
+    async fn blocked_names_get_nxdomain() {
+        let svc = BlocklistMiddlewareSvc::new(service(), blocklist());
+
+        let response = process(&svc, "www.ads.example", Rtype::A).await;
+        assert_eq!(response.header().rcode(), Rcode::NXDOMAIN);
+        assert!(answer(&response).is_empty());
+
+        let response = process(&svc, "www.example", Rtype::A).await;
+        assert_eq!(answer(&response), ["A 192.0.2.1"]);
+
+        assert_eq!(svc.metrics().num_blocked(), 1);
+    }
+
+    #[tokio::test]
+    async fn blocked_names_get_sinkhole() {
+        let svc = BlocklistMiddlewareSvc::new(service(), blocklist())
+            .with_response(BlockResponse::Sinkhole(vec![
+                IpAddr::V4(Ipv4Addr::new(198, 51, 100, 1)),
+                IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1)),
+            ]));
+
+        let response = process(&svc, "exact.example", Rtype::A).await;
+        assert_eq!(answer(&response), ["A 198.51.100.1"]);
+        let response = process(&svc, "exact.example", Rtype::AAAA).await;
+        assert_eq!(answer(&response), ["AAAA 2001:db8::1"]);
+        let response = process(&svc, "exact.example", Rtype::MX).await;
+        assert_eq!(response.header().rcode(), Rcode::NOERROR);
+        assert!(answer(&response).is_empty());
+
+        assert_eq!(svc.metrics().num_blocked(), 3);
+    }
+
+    #[tokio::test]
+    async fn blocklist_can_be_replaced() {
+        let svc = BlocklistMiddlewareSvc::new(service(), Blocklist::new());
+        let response = process(&svc, "ads.example", Rtype::A).await;
+        assert_eq!(answer(&response), ["A 192.0.2.1"]);
+
+        // Replacing the list of a clone affects the original too.
+        svc.clone().set_blocklist(blocklist());
+        let response = process(&svc, "ads.example", Rtype::A).await;
+        assert_eq!(response.header().rcode(), Rcode::NXDOMAIN);
+        assert_eq!(svc.metrics().num_blocked(), 1);
+    }
+
+    //------------ Helper functions ------------------------------------------
+
+    fn blocklist() -> Blocklist {
+        Blocklist::new()
+            .with_name(&name("exact.example"))
+            .with_suffix(&name("ads.example"))
+    }
+}
@@ -0,0 +1,423 @@
+//! DNS 0x20 query name case preservation.
+//!
+//! [DNS 0x20] is an anti-spoofing technique whereby the sender of a query
+//! randomizes the case of the letters in the query name and then rejects any
+//! response which does not echo back the query name with exactly the same
+//! case.
+//!
+//! As DNS name comparison is case-insensitive a [`Service`] may legitimately
+//! generate a response whose question section (and the owner names of
+//! records in the answer section) use a different case to that of the
+//! request, e.g. because the response was built from zone data rather than
+//! from the request itself. Such responses would be rejected by a client
+//! using DNS 0x20.
+//!
+//! The [`Case0x20MiddlewareSvc`] restores the exact case of the request query
+//! name in such responses.
+//!
+//! [DNS 0x20]:
+//!     https://datatracker.ietf.org/doc/html/draft-vixie-dnsext-dns0x20-00
+//! [`Service`]: crate::net::server::service::Service
+use core::future::{ready, Ready};
+use core::marker::PhantomData;
+
+use std::fmt::Display;
+
+use octseq::Octets;
+use tracing::{trace, warn};
+
+use crate::base::message_builder::{AdditionalBuilder, PushError};
+use crate::base::name::Label;
+use crate::base::wire::{Composer, ParseError};
+use crate::base::{ParsedName, Record, StreamTarget, ToName};
+use crate::net::server::message::Request;
+use crate::net::server::service::{Service, ServiceResult};
+use crate::net::server::util::mk_builder_for_target;
+use crate::rdata::AllRecordData;
+
+use super::stream::PostprocessingStream;
+
+//------------ Case0x20MiddlewareSvc -----------------------------------------
+
+/// A middleware service for echoing the exact case of the request query
+/// name in responses.
+///
+/// The question section of each response is rewritten such that any
+/// question whose name is equal (ignoring case) to that of the request uses
+/// the exact octets of the request query name.
+///
+/// Optionally the owner names of answer section records that are equal
+/// (ignoring case) to the request query name can be rewritten in the same
+/// way, see [`rewrite_answers()`].
+///
+/// Responses whose question section already matches the request exactly are
+/// passed through unmodified.
+///
+/// [`rewrite_answers()`]: Self::rewrite_answers
+#[derive(Clone, Debug)]
+pub struct Case0x20MiddlewareSvc<RequestOctets, NextSvc, RequestMeta> {
+    /// The upstream [`Service`] to pass requests to and receive responses
+    /// from.
+    next_svc: NextSvc,
+
+    /// Should matching answer section owner names also be rewritten?
+    ///
+    /// Defaults to false.
+    rewrite_answers: bool,
+
+    _phantom: PhantomData<(RequestOctets, RequestMeta)>,
+}
+
+impl<RequestOctets, NextSvc, RequestMeta>
+    Case0x20MiddlewareSvc<RequestOctets, NextSvc, RequestMeta>
+{
+    /// Creates an instance of this middleware service.
+    #[must_use]
+    pub fn new(next_svc: NextSvc) -> Self {
+        Self {
+            next_svc,
+            rewrite_answers: false,
+            _phantom: PhantomData,
+        }
+    }
+
+    /// Also rewrite the owner names of matching answer section records.
+    #[must_use]
+    pub fn rewrite_answers(mut self, enabled: bool) -> Self {
+        self.rewrite_answers = enabled;
+        self
+    }
+}
+
+impl<RequestOctets, NextSvc, RequestMeta>
+    Case0x20MiddlewareSvc<RequestOctets, NextSvc, RequestMeta>
+where
+    RequestOctets: Octets + Send + Sync + Unpin,
+    NextSvc: Service<RequestOctets, RequestMeta>,
+    NextSvc::Target: Composer + Default,
+    RequestMeta: Clone + Default,
+{
+    fn postprocess(
+        request: &Request<RequestOctets, RequestMeta>,
+        response: &mut AdditionalBuilder<StreamTarget<NextSvc::Target>>,
+        rewrite_answers: bool,
+    ) {
+        let Some(question) = request.message().first_question() else {
+            return;
+        };
+        let qname = question.qname();
+
+        if !Self::needs_rewrite(qname, response, rewrite_answers) {
+            return;
+        }
+
+        if let Err(err) = Self::rewrite(qname, response, rewrite_answers) {
+            warn!("Unable to restore query name case in response: {err}");
+        }
+    }
+
+    /// Does the response contain names that differ only in case from the
+    /// query name?
+    fn needs_rewrite(
+        qname: &ParsedName<RequestOctets::Range<'_>>,
+        response: &AdditionalBuilder<StreamTarget<NextSvc::Target>>,
+        rewrite_answers: bool,
+    ) -> bool {
+        let source = response.as_message();
+
+        let question_mismatch = source
+            .question()
+            .flatten()
+            .any(|q| q.qname().name_eq(qname) && !eq_exact(q.qname(), qname));
+
+        if question_mismatch || !rewrite_answers {
+            return question_mismatch;
+        }
+
+        let Ok(answer) = source.answer() else {
+            return false;
+        };
+
+        answer.flatten().any(|rr| {
+            rr.owner().name_eq(qname) && !eq_exact(&rr.owner(), qname)
+        })
+    }
+
+    /// Rebuild the response using the exact query name octets.
+    fn rewrite(
+        qname: &ParsedName<RequestOctets::Range<'_>>,
+        response: &mut AdditionalBuilder<StreamTarget<NextSvc::Target>>,
+        rewrite_answers: bool,
+    ) -> Result<(), RewriteError> {
+        let source = response.as_message();
+        let mut target = mk_builder_for_target();
+
+        *target.header_mut() = source.header();
+
+        let mut target = target.question();
+        for q in source.question() {
+            let q = q?;
+            if q.qname().name_eq(qname) {
+                target.push((qname, q.qtype(), q.qclass()))?;
+            } else {
+                target.push(q)?;
+            }
+        }
+
+        let mut target = target.answer();
+        for rr in source.answer()? {
+            let rr = rr?;
+            let rewrite = rewrite_answers && rr.owner().name_eq(qname);
+            if let Some(rr) =
+                rr.into_record::<AllRecordData<_, ParsedName<_>>>()?
+            {
+                if rewrite {
+                    let (class, ttl) = (rr.class(), rr.ttl());
+                    let (_, data) = rr.into_owner_and_data();
+                    target.push(Record::new(qname, class, ttl, data))?;
+                } else {
+                    target.push(rr)?;
+                }
+            }
+        }
+
+        let mut target = target.authority();
+        for rr in source.authority()? {
+            if let Some(rr) =
+                rr?.into_record::<AllRecordData<_, ParsedName<_>>>()?
+            {
+                target.push(rr)?;
+            }
+        }
+
+        let mut target = target.additional();
+        for rr in source.additional()? {
+            if let Some(rr) =
+                rr?.into_record::<AllRecordData<_, ParsedName<_>>>()?
+            {
+                target.push(rr)?;
+            }
+        }
+
+        trace!("Restored query name case '{qname}' in response");
+
+        *response = target;
+
+        Ok(())
+    }
+
+    fn map_stream_item(
+        request: Request<RequestOctets, RequestMeta>,
+        mut stream_item: ServiceResult<NextSvc::Target>,
+        rewrite_answers: &mut bool,
+    ) -> ServiceResult<NextSvc::Target> {
+        if let Ok(cr) = &mut stream_item {
+            if let Some(response) = cr.response_mut() {
+                Self::postprocess(&request, response, *rewrite_answers);
+            }
+        }
+        stream_item
+    }
+}
+
+//--- Service
+
+impl<RequestOctets, NextSvc, RequestMeta> Service<RequestOctets, RequestMeta>
+    for Case0x20MiddlewareSvc<RequestOctets, NextSvc, RequestMeta>
+where
+    RequestOctets: Octets + Send + Sync + 'static + Unpin,
+    NextSvc: Service<RequestOctets, RequestMeta>,
+    NextSvc::Future: Unpin,
+    NextSvc::Target: Composer + Default,
+    RequestMeta: Clone + Default + Unpin,
+{
+    type Target = NextSvc::Target;
+    type Stream = PostprocessingStream<
+        RequestOctets,
+        NextSvc::Future,
+        NextSvc::Stream,
+        RequestMeta,
+        bool,
+    >;
+    type Future = Ready<Self::Stream>;
+
+    fn call(
+        &self,
+        request: Request<RequestOctets, RequestMeta>,
+    ) -> Self::Future {
+        let svc_call_fut = self.next_svc.call(request.clone());
+        ready(PostprocessingStream::new(
+            svc_call_fut,
+            request,
+            self.rewrite_answers,
+            Self::map_stream_item,
+        ))
+    }
+}
+
+//------------ Helper functions ----------------------------------------------
+
+/// Compares two names label by label, taking case into account.
+fn eq_exact<N: ToName + ?Sized, M: ToName + ?Sized>(a: &N, b: &M) -> bool {
+    let mut a = a.iter_labels();
+    let mut b = b.iter_labels();
+    loop {
+        match (a.next(), b.next()) {
+            (Some(a), Some(b)) => {
+                if Label::as_slice(a) != Label::as_slice(b) {
+                    return false;
+                }
+            }
+            (None, None) => return true,
+            _ => return false,
+        }
+    }
+}
+
+//------------ RewriteError --------------------------------------------------
+
+/// An error occured while rewriting the response.
+enum RewriteError {
+    /// There was a problem parsing the response.
+    InvalidResponse(ParseError),
+
+    /// There was a problem pushing to the rewritten response.
+    PushFailure(PushError),
+}
+
+impl Display for RewriteError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            RewriteError::InvalidResponse(err) => {
+                write!(f, "Unable to parse response: {err}")
+            }
+            RewriteError::PushFailure(err) => {
+                write!(f, "Unable to push into response: {err}")
+            }
+        }
+    }
+}
+
+impl From<ParseError> for RewriteError {
+    fn from(err: ParseError) -> Self {
+        Self::InvalidResponse(err)
+    }
+}
+
+impl From<PushError> for RewriteError {
+    fn from(err: PushError) -> Self {
+        Self::PushFailure(err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::str::FromStr;
+
+    use std::vec::Vec;
+
+    use bytes::Bytes;
+    use futures_util::StreamExt;
+    use tokio::time::Instant;
+
+    use crate::base::iana::{Class, Rcode};
+    use crate::base::{Message, MessageBuilder, Name, Rtype, ToName};
+    use crate::net::server::message::{Request, UdpTransportContext};
+    use crate::net::server::service::{CallResult, Service, ServiceResult};
+    use crate::net::server::util::{mk_builder_for_target, service_fn};
+    use crate::rdata::A;
+
+    use super::Case0x20MiddlewareSvc;
+
+    //------------ Tests -----------------------------------------------------
+
+    #[tokio::test]
+    async fn question_case_is_echoed_exactly() {
+        let response = process("wWw.ExAmPlE.cOm", false).await;
+
+        let question = response.sole_question().unwrap();
+        assert_eq!(
+            question.qname().to_vec().as_slice(),
+            wire_name("wWw.ExAmPlE.cOm").as_slice()
+        );
+
+        // The answer was left as generated by the service.
+        let answer = response.answer().unwrap().next().unwrap().unwrap();
+        assert_eq!(
+            answer.owner().to_vec().as_slice(),
+            wire_name("www.example.com").as_slice()
+        );
+    }
+
+    #[tokio::test]
+    async fn answer_owner_case_is_echoed_exactly() {
+        let response = process("wWw.ExAmPlE.cOm", true).await;
+
+        let question = response.sole_question().unwrap();
+        assert_eq!(
+            question.qname().to_vec().as_slice(),
+            wire_name("wWw.ExAmPlE.cOm").as_slice()
+        );
+
+        let answer = response.answer().unwrap().next().unwrap().unwrap();
+        assert_eq!(
+            answer.owner().to_vec().as_slice(),
+            wire_name("wWw.ExAmPlE.cOm").as_slice()
+        );
+        assert_eq!(answer.rtype(), Rtype::A);
+    }
+
+    //------------ Helper functions ------------------------------------------
+
+    fn wire_name(name: &str) -> Vec<u8> {
+        Name::<Vec<u8>>::from_str(name).unwrap().into_octets()
+    }
+
+    async fn process(qname: &str, rewrite_answers: bool) -> Message<Vec<u8>> {
+        // Build a mixed case DNS query.
+        let query = MessageBuilder::new_vec();
+        let mut query = query.question();
+        query
+            .push((Name::<Bytes>::from_str(qname).unwrap(), Rtype::A))
+            .unwrap();
+        let message = query.into_message();
+
+        let ctx = UdpTransportContext::default();
+        let request = Request::new(
+            "127.0.0.1:12345".parse().unwrap(),
+            Instant::now(),
+            message,
+            ctx.into(),
+            (),
+        );
+
+        // A service that answers using a lower case name.
+        fn my_service(
+            req: Request<Vec<u8>>,
+            _meta: (),
+        ) -> ServiceResult<Vec<u8>> {
+            let name = Name::<Vec<u8>>::from_str("www.example.com").unwrap();
+            let builder = mk_builder_for_target();
+            let mut question = builder.question();
+            let q = req.message().sole_question().unwrap();
+            question.push((&name, q.qtype(), q.qclass())).unwrap();
+            let mut answer = question.answer();
+            answer.header_mut().set_rcode(Rcode::NOERROR);
+            answer
+                .push((&name, Class::IN, 3600, A::from_octets(192, 0, 2, 1)))
+                .unwrap();
+            Ok(CallResult::new(answer.additional()))
+        }
+
+        let my_svc = service_fn(my_service, ());
+        let middleware_svc = Case0x20MiddlewareSvc::new(my_svc)
+            .rewrite_answers(rewrite_answers);
+        let mut stream = middleware_svc.call(request).await;
+        let call_result: CallResult<Vec<u8>> =
+            stream.next().await.unwrap().unwrap();
+        let (response, _feedback) = call_result.into_inner();
+        let response = response.unwrap().finish();
+
+        Message::from_octets(response.as_dgram_slice().to_vec()).unwrap()
+    }
+}
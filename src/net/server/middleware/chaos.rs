@@ -0,0 +1,417 @@
+//! Fault injection for testing the resilience of clients.
+//!
+//! **Never use this in production.** The [`ChaosMiddlewareSvc`] deliberately
+//! makes a server misbehave: it delays responses, drops them or replaces
+//! them with SERVFAIL at configurable probabilities. This allows testing how
+//! clients cope with a slow or unreliable server, e.g. whether their retry
+//! and timeout logic works as intended.
+//!
+//! This module is only available with the `chaos` feature, which should
+//! only ever be enabled for test builds.
+use core::future::Future;
+use core::marker::PhantomData;
+use core::option;
+use core::pin::Pin;
+use core::time::Duration;
+
+use std::boxed::Box;
+use std::sync::{Arc, Mutex};
+
+use arc_swap::ArcSwap;
+use futures_util::stream::{iter, Iter, Stream};
+use octseq::Octets;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use tracing::{trace, warn};
+
+use crate::base::iana::OptRcode;
+use crate::base::wire::Composer;
+use crate::net::server::message::Request;
+use crate::net::server::middleware::stream::MiddlewareStream;
+use crate::net::server::service::{CallResult, Service, ServiceResult};
+use crate::net::server::util::mk_error_response;
+
+//----------- ChaosConfig -----------------------------------------------------
+
+/// The faults injected by a [`ChaosMiddlewareSvc`].
+///
+/// The default injects no faults.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ChaosConfig {
+    /// The fixed delay added to every response.
+    latency: Duration,
+
+    /// The upper bound of the random delay added to every response.
+    jitter: Duration,
+
+    /// The probability of dropping a request.
+    drop_probability: f64,
+
+    /// The probability of answering a request with SERVFAIL.
+    servfail_probability: f64,
+}
+
+impl ChaosConfig {
+    /// Creates a configuration that injects no faults.
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Delays every response by the given duration.
+    #[must_use]
+    pub fn with_latency(mut self, latency: Duration) -> Self {
+        self.latency = latency;
+        self
+    }
+
+    /// Delays every response by an additional random duration up to the
+    /// given one.
+    #[must_use]
+    pub fn with_jitter(mut self, jitter: Duration) -> Self {
+        self.jitter = jitter;
+        self
+    }
+
+    /// Drops requests, i.e. sends no response, with the given probability.
+    ///
+    /// The probability is clamped to the range 0.0 to 1.0.
+    #[must_use]
+    pub fn with_drop_probability(mut self, probability: f64) -> Self {
+        self.drop_probability = probability.clamp(0.0, 1.0);
+        self
+    }
+
+    /// Answers requests with SERVFAIL with the given probability.
+    ///
+    /// The probability is clamped to the range 0.0 to 1.0. Requests that
+    /// are dropped are not answered with SERVFAIL, so the effective
+    /// probability is at most one minus the drop probability.
+    #[must_use]
+    pub fn with_servfail_probability(mut self, probability: f64) -> Self {
+        self.servfail_probability = probability.clamp(0.0, 1.0);
+        self
+    }
+
+    /// The fixed delay added to every response.
+    pub fn latency(&self) -> Duration {
+        self.latency
+    }
+
+    /// The upper bound of the random delay added to every response.
+    pub fn jitter(&self) -> Duration {
+        self.jitter
+    }
+
+    /// The probability of dropping a request.
+    pub fn drop_probability(&self) -> f64 {
+        self.drop_probability
+    }
+
+    /// The probability of answering a request with SERVFAIL.
+    pub fn servfail_probability(&self) -> f64 {
+        self.servfail_probability
+    }
+}
+
+//----------- ChaosMiddlewareSvc ----------------------------------------------
+
+/// A middleware service injecting faults for testing.
+///
+/// **Never use this in production.** A warning is logged whenever an
+/// instance is created.
+///
+/// Every request is delayed by the configured latency plus a random jitter
+/// and then, at the configured probabilities, either dropped, in which case
+/// the server sends no response, answered with SERVFAIL without invoking the
+/// next service, or passed to the next service.
+///
+/// The configuration is shared between clones of this service and can be
+/// changed at runtime via [`set_config()`]. For reproducible test runs the
+/// random number generator can be seeded via [`with_seed()`].
+///
+/// [`set_config()`]: Self::set_config
+/// [`with_seed()`]: Self::with_seed
+#[derive(Clone, Debug)]
+pub struct ChaosMiddlewareSvc<RequestOctets, NextSvc, RequestMeta> {
+    /// The upstream [`Service`] to pass requests to and receive responses
+    /// from.
+    next_svc: NextSvc,
+
+    /// The faults to inject.
+    config: Arc<ArcSwap<ChaosConfig>>,
+
+    /// The source of randomness for jitter and fault injection.
+    rng: Arc<Mutex<StdRng>>,
+
+    _phantom: PhantomData<(RequestOctets, RequestMeta)>,
+}
+
+impl<RequestOctets, NextSvc, RequestMeta>
+    ChaosMiddlewareSvc<RequestOctets, NextSvc, RequestMeta>
+{
+    /// Creates an instance of this middleware service.
+    #[must_use]
+    pub fn new(next_svc: NextSvc, config: ChaosConfig) -> Self {
+        warn!("Chaos fault injection is enabled: do not use in production!");
+        Self {
+            next_svc,
+            config: Arc::new(ArcSwap::from_pointee(config)),
+            rng: Arc::new(Mutex::new(StdRng::from_entropy())),
+            _phantom: PhantomData,
+        }
+    }
+
+    /// Seeds the random number generator.
+    ///
+    /// Given the same seed and sequence of requests the same faults are
+    /// injected.
+    #[must_use]
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.rng = Arc::new(Mutex::new(StdRng::seed_from_u64(seed)));
+        self
+    }
+
+    /// Returns the faults currently injected.
+    pub fn config(&self) -> ChaosConfig {
+        **self.config.load()
+    }
+
+    /// Replaces the faults to inject.
+    ///
+    /// Requests already being processed are not affected.
+    pub fn set_config(&self, config: ChaosConfig) {
+        self.config.store(Arc::new(config));
+    }
+
+    /// Decides what to do with a request.
+    fn roll(&self) -> (Duration, ChaosFault) {
+        let config = self.config();
+        let mut rng = self.rng.lock().unwrap();
+
+        let mut delay = config.latency;
+        if !config.jitter.is_zero() {
+            delay += config.jitter.mul_f64(rng.gen::<f64>());
+        }
+
+        let roll = rng.gen::<f64>();
+        let fault = if roll < config.drop_probability {
+            ChaosFault::Drop
+        } else if roll < config.drop_probability + config.servfail_probability
+        {
+            ChaosFault::ServFail
+        } else {
+            ChaosFault::None
+        };
+
+        (delay, fault)
+    }
+}
+
+//--- Service
+
+impl<RequestOctets, NextSvc, RequestMeta> Service<RequestOctets, RequestMeta>
+    for ChaosMiddlewareSvc<RequestOctets, NextSvc, RequestMeta>
+where
+    RequestOctets: Octets + Send + Sync + 'static + Unpin,
+    NextSvc: Service<RequestOctets, RequestMeta>,
+    NextSvc::Future: Send + Unpin + 'static,
+    NextSvc::Stream: Send + 'static,
+    NextSvc::Target: Composer + Default + Send + 'static,
+    RequestMeta: Clone + Default + Send + 'static,
+{
+    type Target = NextSvc::Target;
+    type Stream = MiddlewareStream<
+        NextSvc::Future,
+        NextSvc::Stream,
+        NextSvc::Stream,
+        Iter<option::IntoIter<ServiceResult<Self::Target>>>,
+        <NextSvc::Stream as Stream>::Item,
+    >;
+    type Future = Pin<Box<dyn Future<Output = Self::Stream> + Send>>;
+
+    fn call(
+        &self,
+        request: Request<RequestOctets, RequestMeta>,
+    ) -> Self::Future {
+        let (delay, fault) = self.roll();
+
+        let stream = match fault {
+            ChaosFault::None => {
+                MiddlewareStream::IdentityFuture(self.next_svc.call(request))
+            }
+            ChaosFault::Drop => {
+                trace!("Chaos: dropping request");
+                MiddlewareStream::Result(iter(None))
+            }
+            ChaosFault::ServFail => {
+                trace!("Chaos: answering request with SERVFAIL");
+                let response =
+                    mk_error_response(request.message(), OptRcode::SERVFAIL);
+                MiddlewareStream::Result(iter(Some(Ok(CallResult::new(
+                    response,
+                )))))
+            }
+        };
+
+        Box::pin(async move {
+            if !delay.is_zero() {
+                trace!("Chaos: delaying response by {delay:?}");
+                tokio::time::sleep(delay).await;
+            }
+            stream
+        })
+    }
+}
+
+//----------- ChaosFault ------------------------------------------------------
+
+/// The fault injected for a request.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum ChaosFault {
+    /// Pass the request to the next service.
+    None,
+
+    /// Send no response.
+    Drop,
+
+    /// Answer with SERVFAIL.
+    ServFail,
+}
+
+#[cfg(test)]
+mod tests {
+    use core::str::FromStr;
+    use core::time::Duration;
+
+    use std::vec::Vec;
+
+    use bytes::Bytes;
+    use futures_util::StreamExt;
+    use tokio::time::Instant;
+
+    use crate::base::iana::Rcode;
+    use crate::base::{Message, MessageBuilder, Name, Rtype};
+    use crate::net::server::message::{Request, UdpTransportContext};
+    use crate::net::server::service::{CallResult, Service, ServiceResult};
+    use crate::net::server::util::{
+        mk_builder_for_target, service_fn, ServiceFn,
+    };
+
+    use super::{ChaosConfig, ChaosMiddlewareSvc};
+
+    //------------ Tests -----------------------------------------------------
+
+    #[tokio::test(start_paused = true)]
+    async fn no_faults_by_default() {
+        let svc = mk_svc(ChaosConfig::new());
+        let started = Instant::now();
+        let response = process(&svc).await.unwrap();
+        assert_eq!(response.header().rcode(), Rcode::NOERROR);
+        assert_eq!(started.elapsed(), Duration::ZERO);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn responses_are_delayed() {
+        let config = ChaosConfig::new()
+            .with_latency(Duration::from_millis(100))
+            .with_jitter(Duration::from_millis(50));
+        let svc = mk_svc(config).with_seed(1);
+
+        for _ in 0..10 {
+            let started = Instant::now();
+            assert!(process(&svc).await.is_some());
+            let elapsed = started.elapsed();
+            assert!(elapsed >= Duration::from_millis(100));
+            assert!(elapsed <= Duration::from_millis(150));
+        }
+    }
+
+    #[tokio::test]
+    async fn faults_follow_config() {
+        let svc = mk_svc(ChaosConfig::new().with_drop_probability(1.0));
+        assert!(process(&svc).await.is_none());
+
+        svc.set_config(ChaosConfig::new().with_servfail_probability(2.0));
+        assert_eq!(svc.config().servfail_probability(), 1.0);
+        let response = process(&svc).await.unwrap();
+        assert_eq!(response.header().rcode(), Rcode::SERVFAIL);
+
+        svc.set_config(ChaosConfig::new());
+        let response = process(&svc).await.unwrap();
+        assert_eq!(response.header().rcode(), Rcode::NOERROR);
+    }
+
+    #[tokio::test]
+    async fn seeded_faults_are_reproducible() {
+        let config = ChaosConfig::new()
+            .with_drop_probability(0.3)
+            .with_servfail_probability(0.3);
+
+        let mut runs = Vec::new();
+        for _ in 0..2 {
+            let svc = mk_svc(config).with_seed(42);
+            let mut outcomes = Vec::new();
+            for _ in 0..32 {
+                outcomes.push(
+                    process(&svc).await.map(|msg| msg.header().rcode()),
+                );
+            }
+            runs.push(outcomes);
+        }
+
+        assert_eq!(runs[0], runs[1]);
+        assert!(runs[0].contains(&None));
+        assert!(runs[0].contains(&Some(Rcode::SERVFAIL)));
+        assert!(runs[0].contains(&Some(Rcode::NOERROR)));
+    }
+
+    //------------ Helper functions ------------------------------------------
+
+    type TestSvc = ChaosMiddlewareSvc<
+        Vec<u8>,
+        ServiceFn<
+            Vec<u8>,
+            fn(Request<Vec<u8>>, ()) -> ServiceResult<Vec<u8>>,
+            (),
+        >,
+        (),
+    >;
+
+    fn mk_svc(config: ChaosConfig) -> TestSvc {
+        fn my_service(
+            req: Request<Vec<u8>>,
+            _meta: (),
+        ) -> ServiceResult<Vec<u8>> {
+            let builder = mk_builder_for_target();
+            let answer =
+                builder.start_answer(req.message(), Rcode::NOERROR).unwrap();
+            Ok(CallResult::new(answer.additional()))
+        }
+
+        ChaosMiddlewareSvc::new(
+            service_fn(my_service as fn(_, _) -> _, ()),
+            config,
+        )
+    }
+
+    async fn process(svc: &TestSvc) -> Option<Message<Vec<u8>>> {
+        let mut query = MessageBuilder::new_vec().question();
+        query
+            .push((Name::<Bytes>::from_str("example.com").unwrap(), Rtype::A))
+            .unwrap();
+        let request = Request::for_test(
+            query.into_message(),
+            UdpTransportContext::default(),
+            "127.0.0.1:12345".parse().unwrap(),
+        );
+
+        let mut stream = svc.call(request).await;
+        let call_result: CallResult<Vec<u8>> = stream.next().await?.unwrap();
+        assert!(stream.next().await.is_none());
+        let response = call_result.into_inner().0.unwrap().finish();
+        Some(
+            Message::from_octets(response.as_dgram_slice().to_vec()).unwrap(),
+        )
+    }
+}
@@ -2,13 +2,18 @@
 use core::future::{ready, Ready};
 use core::marker::PhantomData;
 use core::ops::ControlFlow;
+use core::time::Duration;
 
+use std::io;
+use std::sync::Arc;
 use std::vec::Vec;
 
+use arc_swap::ArcSwap;
 use futures_util::stream::{once, Once, Stream};
 use octseq::Octets;
 use rand::RngCore;
-use tracing::{debug, error, trace, warn};
+use tokio::time::Instant;
+use tracing::{debug, error, info, trace, warn};
 
 use crate::base::iana::{OptRcode, Rcode};
 use crate::base::message_builder::AdditionalBuilder;
@@ -32,6 +37,116 @@ const FIVE_MINUTES_AS_SECS: u32 = 5 * 60;
 /// https://www.rfc-editor.org/rfc/rfc9018.html#section-4.3.
 const ONE_HOUR_AS_SECS: u32 = 60 * 60;
 
+/// The default period for which cookies minted with a previous server secret
+/// continue to be accepted after the secret is rotated.
+///
+/// Matches the one hour cookie lifetime referred to by
+/// https://www.rfc-editor.org/rfc/rfc9018.html#section-4.3 so that no cookie
+/// that would otherwise still be valid is rejected due to rotation.
+const DEFAULT_SECRET_GRACE_PERIOD: Duration =
+    Duration::from_secs(ONE_HOUR_AS_SECS as u64);
+
+//----------- ServerSecrets ---------------------------------------------------
+
+/// The current and, during secret rotation, previous server secrets.
+#[derive(Clone, Debug)]
+struct ServerSecrets {
+    /// The secret used to mint new server cookies.
+    current: [u8; 16],
+
+    /// The secret that was current prior to the last rotation, and when it
+    /// was retired.
+    previous: Option<([u8; 16], Instant)>,
+}
+
+//----------- CookieState -----------------------------------------------------
+
+/// The state of a [`CookiesMiddlewareSvc`] worth keeping across restarts.
+///
+/// Clients hold on to a server cookie for up to an hour and expect the
+/// server to accept it for that long. A server that picks a new secret each
+/// time it starts rejects all of them with BADCOOKIE after a restart, which
+/// for a busy server means a burst of extra round trips. Saving this state
+/// on shutdown, or whenever the secret is rotated, and restoring it on start
+/// avoids that.
+///
+/// Only the server secrets are part of the state, the middleware keeps no
+/// per-client state. Note that the state contains secret key material and
+/// should be stored with appropriate care.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct CookieState {
+    /// The secret used to mint new server cookies.
+    current: [u8; 16],
+
+    /// The previous secret, if cookies minted with it are still accepted.
+    previous: Option<[u8; 16]>,
+}
+
+impl CookieState {
+    /// The version of the format written by [`Self::save`].
+    const FORMAT_VERSION: u8 = 1;
+
+    /// Creates a new state from the current and previous server secrets.
+    #[must_use]
+    pub fn new(current: [u8; 16], previous: Option<[u8; 16]>) -> Self {
+        Self { current, previous }
+    }
+
+    /// The secret used to mint new server cookies.
+    pub fn current_secret(&self) -> &[u8; 16] {
+        &self.current
+    }
+
+    /// The previous secret, if cookies minted with it are still accepted.
+    pub fn previous_secret(&self) -> Option<&[u8; 16]> {
+        self.previous.as_ref()
+    }
+
+    /// Writes the state to the given target.
+    ///
+    /// The state is written as a version octet, the number of secrets that
+    /// follow and the secrets themselves, current secret first.
+    pub fn save(&self, mut target: impl io::Write) -> io::Result<()> {
+        let count = if self.previous.is_some() { 2 } else { 1 };
+        target.write_all(&[Self::FORMAT_VERSION, count])?;
+        target.write_all(&self.current)?;
+        if let Some(previous) = &self.previous {
+            target.write_all(previous)?;
+        }
+        target.flush()
+    }
+
+    /// Reads state previously written by [`Self::save`].
+    pub fn load(mut source: impl io::Read) -> io::Result<Self> {
+        let mut header = [0u8; 2];
+        source.read_exact(&mut header)?;
+        let [version, count] = header;
+        if version != Self::FORMAT_VERSION {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "unsupported DNS cookie state version",
+            ));
+        }
+        if !(1..=2).contains(&count) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "invalid number of DNS cookie secrets",
+            ));
+        }
+
+        let mut current = [0u8; 16];
+        source.read_exact(&mut current)?;
+        let previous = if count == 2 {
+            let mut previous = [0u8; 16];
+            source.read_exact(&mut previous)?;
+            Some(previous)
+        } else {
+            None
+        };
+        Ok(Self { current, previous })
+    }
+}
+
 //----------- CookiesMiddlewareSvc --------------------------------------------
 
 /// A middleware service for enforcing the use of DNS Cookies.
@@ -51,8 +166,15 @@ pub struct CookiesMiddlewareSvc<RequestOctets, NextSvc, RequestMeta> {
     /// from.
     next_svc: NextSvc,
 
-    /// A user supplied secret used in making the cookie value.
-    server_secret: [u8; 16],
+    /// User supplied secrets used in making and checking the cookie value.
+    ///
+    /// Shared between clones of this service so that rotating the secret
+    /// affects all of them.
+    server_secrets: Arc<ArcSwap<ServerSecrets>>,
+
+    /// How long server cookies minted with the previous secret continue to
+    /// be accepted after the secret is rotated.
+    secret_grace_period: Duration,
 
     /// Clients connecting from these IP addresses will be required to provide
     /// a cookie otherwise they will receive REFUSED with TC=1 prompting them
@@ -74,9 +196,15 @@ impl<RequestOctets, NextSvc, RequestMeta>
     /// Creates an instance of this middleware service.
     #[must_use]
     pub fn new(next_svc: NextSvc, server_secret: [u8; 16]) -> Self {
+        let server_secrets = ServerSecrets {
+            current: server_secret,
+            previous: None,
+        };
+
         Self {
             next_svc,
-            server_secret,
+            server_secrets: Arc::new(ArcSwap::from_pointee(server_secrets)),
+            secret_grace_period: DEFAULT_SECRET_GRACE_PERIOD,
             ip_deny_list: vec![],
             enabled: true,
             _phantom: PhantomData,
@@ -99,10 +227,70 @@ impl<RequestOctets, NextSvc, RequestMeta>
         self
     }
 
+    /// Define how long cookies minted with a rotated out secret remain
+    /// valid.
+    ///
+    /// Defaults to one hour.
+    #[must_use]
+    pub fn with_secret_grace_period(
+        mut self,
+        grace_period: Duration,
+    ) -> Self {
+        self.secret_grace_period = grace_period;
+        self
+    }
+
     pub fn enable(mut self, enabled: bool) -> Self {
         self.enabled = enabled;
         self
     }
+
+    /// Replace the server secret used to mint new server cookies.
+    ///
+    /// Server cookies minted with the secret being replaced will continue to
+    /// be accepted until the configured grace period has elapsed, so that
+    /// rotation does not cause a flood of BADCOOKIE responses to clients
+    /// holding a recently issued cookie.
+    ///
+    /// Rotating again before the grace period has elapsed discards the
+    /// oldest secret.
+    pub fn rotate_secret(&self, new_secret: [u8; 16]) {
+        let retired_at = Instant::now();
+        self.server_secrets.rcu(|old| ServerSecrets {
+            current: new_secret,
+            previous: Some((old.current, retired_at)),
+        });
+        info!("DNS cookie server secret rotated");
+    }
+
+    /// Returns the state to persist in order to restore it after a restart.
+    ///
+    /// The previous secret is only included while cookies minted with it
+    /// are still accepted.
+    pub fn state(&self) -> CookieState {
+        let secrets = self.server_secrets.load();
+        let previous = secrets.previous.and_then(|(previous, retired_at)| {
+            (retired_at.elapsed() <= self.secret_grace_period)
+                .then_some(previous)
+        });
+        CookieState::new(secrets.current, previous)
+    }
+
+    /// Restores state obtained via [`Self::state`], e.g. before a restart.
+    ///
+    /// Cookies minted with the previous secret of the restored state, if
+    /// any, are accepted for a full grace period from now on as the time
+    /// at which it was rotated out is not part of the state.
+    pub fn restore_state(&self, state: CookieState) {
+        let new = ServerSecrets {
+            current: state.current,
+            previous: state
+                .previous
+                .map(|previous| (previous, Instant::now())),
+        };
+        self.server_secrets.store(Arc::new(new));
+        info!("DNS cookie server secrets restored");
+    }
 }
 
 impl<RequestOctets, NextSvc, RequestMeta>
@@ -171,6 +359,44 @@ where
         }
     }
 
+    /// Check the server cookie against the current and, if still within the
+    /// grace period, the previous server secret.
+    #[must_use]
+    fn server_cookie_is_valid(
+        &self,
+        request: &Request<RequestOctets, RequestMeta>,
+        cookie: &opt::Cookie,
+    ) -> bool {
+        let client_ip = request.client_addr().ip();
+        let secrets = self.server_secrets.load();
+
+        if cookie.check_server_hash(
+            client_ip,
+            &secrets.current,
+            Self::timestamp_ok,
+        ) {
+            return true;
+        }
+
+        match secrets.previous {
+            Some((previous, retired_at))
+                if retired_at.elapsed() <= self.secret_grace_period =>
+            {
+                let valid = cookie.check_server_hash(
+                    client_ip,
+                    &previous,
+                    Self::timestamp_ok,
+                );
+                if valid {
+                    trace!("Server cookie accepted using previous secret");
+                }
+                valid
+            }
+
+            _ => false,
+        }
+    }
+
     /// Create a DNS response message for the given request, including cookie.
     fn response_with_cookie(
         &self,
@@ -195,7 +421,7 @@ where
             let response_cookie = client_cookie.create_response(
                 Serial::now(),
                 request.client_addr().ip(),
-                &self.server_secret,
+                &self.server_secrets.load().current,
             );
 
             // Note: if rcode is non-extended this will also correctly handle
@@ -318,9 +544,10 @@ where
                 // cookie back with the response, so we don't do that here
                 // unlike in the other cases where we respond early.
                 debug!("Received malformed DNS cookie: {err}");
-                let mut builder = mk_builder_for_target();
-                builder.header_mut().set_rcode(Rcode::FORMERR);
-                return ControlFlow::Break(builder.additional());
+                return ControlFlow::Break(mk_error_response(
+                    request.message(),
+                    OptRcode::FORMERR,
+                ));
             }
 
             Some(Ok(cookie)) => {
@@ -331,11 +558,8 @@ where
                 // do this?
 
                 let server_cookie_exists = cookie.server().is_some();
-                let server_cookie_is_valid = cookie.check_server_hash(
-                    request.client_addr().ip(),
-                    &self.server_secret,
-                    Self::timestamp_ok,
-                );
+                let server_cookie_is_valid =
+                    self.server_cookie_is_valid(request, &cookie);
 
                 if !server_cookie_is_valid {
                     trace!("Request has an invalid DNS server cookie");
@@ -397,7 +621,7 @@ where
                     // TODO: Does the TCP check also apply to RFC 7873 section
                     // 5.4 "Querying for a Server Cookie" too?
 
-                    if request.message().header_counts().qdcount() == 0 {
+                    if request.is_server_cookie_query() {
                         let additional = if !server_cookie_exists {
                             // "If such a query provided just a Client Cookie
                             // and no Server Cookie, the response SHALL have
@@ -423,7 +647,7 @@ where
                         debug!("Rejecting non-TCP request with invalid server cookie due to matching deny list entry");
                         return ControlFlow::Break(additional);
                     }
-                } else if request.message().header_counts().qdcount() == 0 {
+                } else if request.is_server_cookie_query() {
                     // https://datatracker.ietf.org/doc/html/rfc7873#section-5.4
                     // Querying for a Server Cookie:
                     //   "This mechanism can also be used to
@@ -497,17 +721,157 @@ where
 #[cfg(test)]
 mod tests {
     use bytes::Bytes;
+    use core::time::Duration;
     use std::vec::Vec;
     use tokio::time::Instant;
     use tokio_stream::StreamExt;
 
+    use octseq::builder::OctetsBuilder;
+
+    use crate::base::iana::{OptRcode, OptionCode, Rcode};
     use crate::base::opt::cookie::ClientCookie;
     use crate::base::opt::Cookie;
-    use crate::base::{Message, MessageBuilder, Name, Rtype};
+    use crate::base::{Message, MessageBuilder, Name, Rtype, Serial};
     use crate::net::server::message::{Request, UdpTransportContext};
-    use crate::net::server::middleware::cookies::CookiesMiddlewareSvc;
+    use crate::net::server::middleware::cookies::{
+        CookieState, CookiesMiddlewareSvc,
+    };
     use crate::net::server::service::{CallResult, Service, ServiceResult};
-    use crate::net::server::util::service_fn;
+    use crate::net::server::util::{
+        mk_builder_for_target, service_fn, ServiceFn,
+    };
+
+    type TestSvc = ServiceFn<
+        Vec<u8>,
+        fn(Request<Vec<u8>>, ()) -> ServiceResult<Vec<u8>>,
+        (),
+    >;
+
+    const OLD_SECRET: [u8; 16] =
+        [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15];
+    const NEW_SECRET: [u8; 16] =
+        [15, 14, 13, 12, 11, 10, 9, 8, 7, 6, 5, 4, 3, 2, 1, 0];
+
+    //------------ Tests -----------------------------------------------------
+
+    #[tokio::test(start_paused = true)]
+    async fn old_cookie_valid_during_grace_period() {
+        let middleware_svc = mk_rotating_svc(Duration::from_secs(60));
+        let request = mk_request_with_server_cookie(&OLD_SECRET);
+
+        middleware_svc.rotate_secret(NEW_SECRET);
+        tokio::time::advance(Duration::from_secs(59)).await;
+
+        let response = process(&middleware_svc, request).await;
+        assert_eq!(response.opt_rcode(), OptRcode::NOERROR);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn old_cookie_invalid_after_grace_period() {
+        let middleware_svc = mk_rotating_svc(Duration::from_secs(60));
+        let request = mk_request_with_server_cookie(&OLD_SECRET);
+
+        middleware_svc.rotate_secret(NEW_SECRET);
+        tokio::time::advance(Duration::from_secs(61)).await;
+
+        let response = process(&middleware_svc, request).await;
+        assert_eq!(response.opt_rcode(), OptRcode::BADCOOKIE);
+
+        // The BADCOOKIE response carries a cookie minted with the new secret.
+        let cookie = response.opt().unwrap().opt().cookie().unwrap();
+        let client_ip = "127.0.0.1".parse().unwrap();
+        assert!(cookie.check_server_hash(client_ip, &NEW_SECRET, |_| true));
+        assert!(!cookie.check_server_hash(client_ip, &OLD_SECRET, |_| true));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn new_cookie_valid_after_rotation() {
+        let middleware_svc = mk_rotating_svc(Duration::from_secs(60));
+        middleware_svc.rotate_secret(NEW_SECRET);
+        tokio::time::advance(Duration::from_secs(61)).await;
+
+        let request = mk_request_with_server_cookie(&NEW_SECRET);
+        let response = process(&middleware_svc, request).await;
+        assert_eq!(response.opt_rcode(), OptRcode::NOERROR);
+    }
+
+    #[test]
+    fn state_round_trips() {
+        for state in [
+            CookieState::new(NEW_SECRET, None),
+            CookieState::new(NEW_SECRET, Some(OLD_SECRET)),
+        ] {
+            let mut saved = Vec::new();
+            state.save(&mut saved).unwrap();
+            assert_eq!(
+                saved.len(),
+                2 + 16 * (1 + state.previous.iter().len())
+            );
+            assert_eq!(CookieState::load(saved.as_slice()).unwrap(), state);
+        }
+
+        // Unknown versions and truncated state are rejected.
+        assert!(CookieState::load([2u8, 1].as_slice()).is_err());
+        assert!(CookieState::load([1u8, 3].as_slice()).is_err());
+        assert!(CookieState::load([1u8, 1, 0, 1, 2].as_slice()).is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn restored_state_accepts_cookies_from_before_restart() {
+        let middleware_svc = mk_rotating_svc(Duration::from_secs(60));
+        middleware_svc.rotate_secret(NEW_SECRET);
+        let state = middleware_svc.state();
+        assert_eq!(state, CookieState::new(NEW_SECRET, Some(OLD_SECRET)));
+
+        // Once the grace period is over the previous secret is not saved.
+        tokio::time::advance(Duration::from_secs(61)).await;
+        assert_eq!(middleware_svc.state().previous_secret(), None);
+
+        // A "restarted" service starts out with a different secret.
+        let restarted = mk_rotating_svc(Duration::from_secs(60));
+        restarted.rotate_secret([7; 16]);
+        let request = mk_request_with_server_cookie(&NEW_SECRET);
+        let response = process(&restarted, request).await;
+        assert_eq!(response.opt_rcode(), OptRcode::BADCOOKIE);
+
+        restarted.restore_state(state);
+        for secret in [NEW_SECRET, OLD_SECRET] {
+            let request = mk_request_with_server_cookie(&secret);
+            let response = process(&restarted, request).await;
+            assert_eq!(response.opt_rcode(), OptRcode::NOERROR);
+        }
+    }
+
+    #[tokio::test]
+    async fn short_client_cookie_is_formerr() {
+        let middleware_svc = mk_rotating_svc(Duration::from_secs(60));
+        let request = mk_request_with_raw_cookie(&[0; 4]);
+        let id = request.message().header().id();
+
+        let response = process(&middleware_svc, request).await;
+        assert_eq!(response.header().rcode(), Rcode::FORMERR);
+        assert_eq!(response.header().id(), id);
+        assert_eq!(response.header_counts().qdcount(), 1);
+    }
+
+    #[tokio::test]
+    async fn long_server_cookie_is_formerr() {
+        let middleware_svc = mk_rotating_svc(Duration::from_secs(60));
+
+        // The longest valid option: 8 bytes client, 32 bytes server cookie.
+        let request = mk_request_with_raw_cookie(&[0; 40]);
+        let response = process(&middleware_svc, request).await;
+        assert_eq!(response.opt_rcode(), OptRcode::BADCOOKIE);
+
+        let request = mk_request_with_raw_cookie(&[0; 41]);
+        let response = process(&middleware_svc, request).await;
+        assert_eq!(response.header().rcode(), Rcode::FORMERR);
+
+        // A server cookie shorter than 8 bytes is malformed, too.
+        let request = mk_request_with_raw_cookie(&[0; 12]);
+        let response = process(&middleware_svc, request).await;
+        assert_eq!(response.header().rcode(), Rcode::FORMERR);
+    }
 
     #[tokio::test]
     async fn dont_add_cookie_twice() {
@@ -582,4 +946,80 @@ mod tests {
             "There should only be one COOKIE option"
         );
     }
+
+    //------------ Helper functions ------------------------------------------
+
+    fn mk_rotating_svc(
+        grace_period: Duration,
+    ) -> CookiesMiddlewareSvc<Vec<u8>, TestSvc, ()> {
+        fn my_service(
+            req: Request<Vec<u8>>,
+            _meta: (),
+        ) -> ServiceResult<Vec<u8>> {
+            let builder = mk_builder_for_target();
+            let answer =
+                builder.start_answer(req.message(), Rcode::NOERROR)?;
+            Ok(CallResult::new(answer.additional()))
+        }
+
+        let my_svc: TestSvc = service_fn(my_service, ());
+        CookiesMiddlewareSvc::new(my_svc, OLD_SECRET)
+            .with_denied_ips(["127.0.0.1".parse().unwrap()])
+            .with_secret_grace_period(grace_period)
+    }
+
+    fn mk_request_with_server_cookie(secret: &[u8; 16]) -> Request<Vec<u8>> {
+        let client_addr: std::net::SocketAddr =
+            "127.0.0.1:12345".parse().unwrap();
+        let cookie = Cookie::new(ClientCookie::new_random(), None)
+            .create_response(Serial::now(), client_addr.ip(), secret);
+
+        let query = MessageBuilder::new_vec();
+        let mut query = query.question();
+        query.push((Name::<Bytes>::root(), Rtype::A)).unwrap();
+        let mut additional = query.additional();
+        additional.opt(|builder| builder.cookie(cookie)).unwrap();
+        let message = additional.into_message();
+
+        let ctx = UdpTransportContext::default();
+        Request::new(client_addr, Instant::now(), message, ctx.into(), ())
+    }
+
+    async fn process<Svc>(
+        middleware_svc: &Svc,
+        request: Request<Vec<u8>>,
+    ) -> Message<Vec<u8>>
+    where
+        Svc: Service<Vec<u8>, (), Target = Vec<u8>>,
+    {
+        let mut stream = middleware_svc.call(request).await;
+        let call_result: CallResult<Vec<u8>> =
+            stream.next().await.unwrap().unwrap();
+        let (response, _feedback) = call_result.into_inner();
+        let response = response.unwrap().finish();
+        Message::from_octets(response.as_dgram_slice().to_vec()).unwrap()
+    }
+
+    fn mk_request_with_raw_cookie(data: &[u8]) -> Request<Vec<u8>> {
+        let mut query = MessageBuilder::new_vec();
+        query.header_mut().set_random_id();
+        let mut query = query.question();
+        query.push((Name::<Bytes>::root(), Rtype::A)).unwrap();
+        let mut additional = query.additional();
+        additional
+            .opt(|builder| {
+                builder.push_raw_option(
+                    OptionCode::COOKIE,
+                    data.len().try_into().unwrap(),
+                    |target| target.append_slice(data),
+                )
+            })
+            .unwrap();
+
+        Request::for_test(
+            additional.into_message(),
+            UdpTransportContext::default(),
+            "127.0.0.1:12345".parse().unwrap(),
+        )
+    }
 }
@@ -0,0 +1,528 @@
+//! Answering diagnostic queries about the server itself.
+//!
+//! Operators often want to check on a running server without a separate
+//! admin interface. The [`DiagnosticsMiddlewareSvc`] answers TXT queries for
+//! a configurable magic name with information about the server, such as its
+//! version, uptime and the [`ServerMetrics`] of the servers it is used by,
+//! and passes all other requests on unmodified.
+//!
+//! Each piece of information is returned as a TXT record holding a single
+//! `key=value` string, e.g.:
+//!
+//! ```text
+//! diagnostics.server. 0 CH TXT "uptime=3600"
+//! diagnostics.server. 0 CH TXT "udp.received_requests=1234"
+//! ```
+//!
+//! By default only the uptime and the request and response counters are
+//! exposed and only in the CH class. The version is not exposed by default
+//! as it makes it easier to find servers affected by known vulnerabilities.
+//! Which fields are exposed can be changed via [`DiagnosticsConfig`].
+use core::future::{ready, Ready};
+use core::marker::PhantomData;
+use core::ops::ControlFlow;
+use core::str::FromStr;
+
+use std::format;
+use std::string::String;
+use std::sync::{Arc, Mutex};
+use std::vec::Vec;
+
+use bytes::Bytes;
+use futures_util::stream::{once, Once};
+use octseq::Octets;
+use tokio::time::Instant;
+use tracing::{debug, warn};
+
+use crate::base::iana::{Class, OptRcode, Rcode};
+use crate::base::message_builder::{AdditionalBuilder, PushError};
+use crate::base::wire::Composer;
+use crate::base::{Message, Name, Rtype, StreamTarget, ToName, Ttl};
+use crate::net::server::message::Request;
+use crate::net::server::metrics::ServerMetrics;
+use crate::net::server::middleware::stream::MiddlewareStream;
+use crate::net::server::service::{CallResult, Service, ServiceResult};
+use crate::net::server::util::{mk_builder_for_target, mk_error_response};
+use crate::rdata::Txt;
+
+//----------- Constants -------------------------------------------------------
+
+/// The default name answered by a [`DiagnosticsMiddlewareSvc`].
+const DEFAULT_QNAME: &str = "diagnostics.server.";
+
+/// The metrics of a server with the label they are reported under.
+type LabeledMetrics = (String, Arc<ServerMetrics>);
+
+//----------- DiagnosticsField ------------------------------------------------
+
+/// A piece of information exposed by a [`DiagnosticsMiddlewareSvc`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum DiagnosticsField {
+    /// The configured version string, as `version=<version>`.
+    Version,
+
+    /// The number of seconds since the service was created, as
+    /// `uptime=<seconds>`.
+    Uptime,
+
+    /// The current number of connections of each server.
+    Connections,
+
+    /// The current number of requests being processed by each server.
+    InflightRequests,
+
+    /// The current number of responses waiting to be written by each server.
+    PendingWrites,
+
+    /// The total number of requests received by each server.
+    ReceivedRequests,
+
+    /// The total number of responses sent by each server.
+    SentResponses,
+}
+
+impl DiagnosticsField {
+    /// The fields exposed by default.
+    ///
+    /// These don't reveal anything about the server software or its
+    /// configuration.
+    pub const DEFAULT: &'static [Self] =
+        &[Self::Uptime, Self::ReceivedRequests, Self::SentResponses];
+
+    /// Returns the key of the field for the given server metrics.
+    fn metrics_key(self) -> Option<&'static str> {
+        match self {
+            Self::Version | Self::Uptime => None,
+            Self::Connections => Some("connections"),
+            Self::InflightRequests => Some("inflight_requests"),
+            Self::PendingWrites => Some("pending_writes"),
+            Self::ReceivedRequests => Some("received_requests"),
+            Self::SentResponses => Some("sent_responses"),
+        }
+    }
+
+    /// Returns the value of the field from the given server metrics.
+    fn metrics_value(self, metrics: &ServerMetrics) -> usize {
+        match self {
+            Self::Version | Self::Uptime => 0,
+            Self::Connections => metrics.num_connections(),
+            Self::InflightRequests => metrics.num_inflight_requests(),
+            Self::PendingWrites => metrics.num_pending_writes(),
+            Self::ReceivedRequests => metrics.num_received_requests(),
+            Self::SentResponses => metrics.num_sent_responses(),
+        }
+    }
+}
+
+//----------- DiagnosticsConfig -----------------------------------------------
+
+/// Configuration for a [`DiagnosticsMiddlewareSvc`].
+#[derive(Clone, Debug)]
+pub struct DiagnosticsConfig {
+    /// The name to answer diagnostic queries for.
+    qname: Name<Bytes>,
+
+    /// The class to answer diagnostic queries in.
+    class: Class,
+
+    /// The fields to expose, in the order of the TXT records.
+    fields: Vec<DiagnosticsField>,
+
+    /// The version string exposed via [`DiagnosticsField::Version`].
+    version: String,
+}
+
+impl DiagnosticsConfig {
+    /// Creates the default configuration.
+    ///
+    /// Answers queries for `diagnostics.server.` in class CH, exposing the
+    /// [default fields][DiagnosticsField::DEFAULT]. The version string is
+    /// the name and version of this crate.
+    #[must_use]
+    pub fn new() -> Self {
+        Self {
+            qname: Name::from_str(DEFAULT_QNAME).unwrap(),
+            class: Class::CH,
+            fields: DiagnosticsField::DEFAULT.to_vec(),
+            version: format!(
+                "{} {}",
+                env!("CARGO_PKG_NAME"),
+                env!("CARGO_PKG_VERSION")
+            ),
+        }
+    }
+
+    /// Sets the name to answer diagnostic queries for.
+    #[must_use]
+    pub fn with_qname(mut self, qname: Name<Bytes>) -> Self {
+        self.qname = qname;
+        self
+    }
+
+    /// Sets the class to answer diagnostic queries in.
+    ///
+    /// Queries for the name in other classes are passed on to the next
+    /// service. Using class IN makes the information available to ordinary
+    /// queries, so consider whether that is desired.
+    #[must_use]
+    pub fn with_class(mut self, class: Class) -> Self {
+        self.class = class;
+        self
+    }
+
+    /// Sets the fields to expose, in the order of the TXT records.
+    #[must_use]
+    pub fn with_fields(mut self, fields: &[DiagnosticsField]) -> Self {
+        self.fields = fields.to_vec();
+        self
+    }
+
+    /// Sets the version string exposed via [`DiagnosticsField::Version`].
+    ///
+    /// The version is only exposed if that field is enabled.
+    #[must_use]
+    pub fn with_version(mut self, version: impl Into<String>) -> Self {
+        self.version = version.into();
+        self
+    }
+
+    /// The name to answer diagnostic queries for.
+    pub fn qname(&self) -> &Name<Bytes> {
+        &self.qname
+    }
+
+    /// The class to answer diagnostic queries in.
+    pub fn class(&self) -> Class {
+        self.class
+    }
+
+    /// The fields exposed.
+    pub fn fields(&self) -> &[DiagnosticsField] {
+        &self.fields
+    }
+}
+
+//--- Default
+
+impl Default for DiagnosticsConfig {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+//----------- DiagnosticsMiddlewareSvc ----------------------------------------
+
+/// A middleware service answering diagnostic queries about the server.
+///
+/// TXT and ANY queries for the configured name in the configured class are
+/// answered with one TXT record per exposed piece of information, see the
+/// [module documentation][self], without invoking the next service. Queries
+/// for the name with other types get an empty NOERROR answer. All other
+/// requests are passed to the next service unmodified.
+///
+/// As a server is created from the service it uses, the metrics of servers
+/// are registered after creating them via [`add_metrics()`]. The metrics are
+/// shared between clones of this service, so registering them with any clone
+/// is enough.
+///
+/// Responses synthesized by this service don't include an OPT record, place
+/// an [`EdnsMiddlewareSvc`] in front of this service to add one where
+/// needed.
+///
+/// [`add_metrics()`]: Self::add_metrics
+/// [`EdnsMiddlewareSvc`]: super::edns::EdnsMiddlewareSvc
+#[derive(Clone, Debug)]
+pub struct DiagnosticsMiddlewareSvc<RequestOctets, NextSvc, RequestMeta> {
+    /// The upstream [`Service`] to pass requests to and receive responses
+    /// from.
+    next_svc: NextSvc,
+
+    /// What to answer and which information to expose.
+    config: Arc<DiagnosticsConfig>,
+
+    /// The time the service was created, for reporting the uptime.
+    started: Instant,
+
+    /// The metrics of the servers to report, with their labels.
+    ///
+    /// Shared between clones of this service.
+    metrics: Arc<Mutex<Vec<LabeledMetrics>>>,
+
+    _phantom: PhantomData<(RequestOctets, RequestMeta)>,
+}
+
+impl<RequestOctets, NextSvc, RequestMeta>
+    DiagnosticsMiddlewareSvc<RequestOctets, NextSvc, RequestMeta>
+{
+    /// Creates an instance of this middleware service.
+    #[must_use]
+    pub fn new(next_svc: NextSvc, config: DiagnosticsConfig) -> Self {
+        Self {
+            next_svc,
+            config: Arc::new(config),
+            started: Instant::now(),
+            metrics: Default::default(),
+            _phantom: PhantomData,
+        }
+    }
+
+    /// Registers the metrics of a server to report.
+    ///
+    /// The metrics fields are reported with the label as a prefix, e.g.
+    /// `udp.received_requests=1234` for a label of `udp`. Registering
+    /// metrics under a label already in use replaces them.
+    pub fn add_metrics(
+        &self,
+        label: impl Into<String>,
+        metrics: Arc<ServerMetrics>,
+    ) {
+        let label = label.into();
+        let mut all = self.metrics.lock().unwrap();
+        match all.iter_mut().find(|(existing, _)| *existing == label) {
+            Some(entry) => entry.1 = metrics,
+            None => all.push((label, metrics)),
+        }
+    }
+
+    /// Returns the `key=value` strings for the exposed fields.
+    fn collect(&self) -> Vec<String> {
+        let metrics = self.metrics.lock().unwrap();
+        let mut res = Vec::new();
+        for field in &self.config.fields {
+            match field {
+                DiagnosticsField::Version => {
+                    res.push(format!("version={}", self.config.version))
+                }
+                DiagnosticsField::Uptime => res.push(format!(
+                    "uptime={}",
+                    self.started.elapsed().as_secs()
+                )),
+                _ => {
+                    let key = field.metrics_key().unwrap_or_default();
+                    for (label, metrics) in metrics.iter() {
+                        res.push(format!(
+                            "{label}.{key}={}",
+                            field.metrics_value(metrics)
+                        ));
+                    }
+                }
+            }
+        }
+        res
+    }
+}
+
+impl<RequestOctets, NextSvc, RequestMeta>
+    DiagnosticsMiddlewareSvc<RequestOctets, NextSvc, RequestMeta>
+where
+    RequestOctets: Octets + Send + Sync + Unpin,
+    RequestMeta: Clone + Default,
+    NextSvc: Service<RequestOctets, RequestMeta>,
+    NextSvc::Target: Composer + Default,
+{
+    /// Answer the request if it is a diagnostic query.
+    fn preprocess(
+        &self,
+        request: &Request<RequestOctets, RequestMeta>,
+    ) -> ControlFlow<AdditionalBuilder<StreamTarget<NextSvc::Target>>> {
+        let msg = request.message();
+        let Ok(question) = msg.sole_question() else {
+            return ControlFlow::Continue(());
+        };
+
+        if question.qclass() != self.config.class
+            || question.qname().name_cmp(&self.config.qname).is_ne()
+        {
+            return ControlFlow::Continue(());
+        }
+
+        debug!("Answering diagnostic query from {}", request.client_addr());
+
+        let response = mk_txt_response(
+            msg,
+            &question.qname(),
+            self.config.class,
+            question.qtype(),
+            self.collect(),
+        )
+        .unwrap_or_else(|err| {
+            warn!("Failed to build diagnostics response: {err}");
+            mk_error_response(msg, OptRcode::SERVFAIL)
+        });
+
+        ControlFlow::Break(response)
+    }
+}
+
+//--- Service
+
+impl<RequestOctets, NextSvc, RequestMeta> Service<RequestOctets, RequestMeta>
+    for DiagnosticsMiddlewareSvc<RequestOctets, NextSvc, RequestMeta>
+where
+    RequestOctets: Octets + Send + Sync + 'static + Unpin,
+    RequestMeta: Clone + Default,
+    NextSvc: Service<RequestOctets, RequestMeta>,
+    NextSvc::Target: Composer + Default,
+    NextSvc::Future: Unpin,
+{
+    type Target = NextSvc::Target;
+    type Stream = MiddlewareStream<
+        NextSvc::Future,
+        NextSvc::Stream,
+        NextSvc::Stream,
+        Once<Ready<ServiceResult<Self::Target>>>,
+        ServiceResult<Self::Target>,
+    >;
+    type Future = Ready<Self::Stream>;
+
+    fn call(
+        &self,
+        request: Request<RequestOctets, RequestMeta>,
+    ) -> Self::Future {
+        match self.preprocess(&request) {
+            ControlFlow::Continue(()) => {
+                let svc_call_fut = self.next_svc.call(request);
+                ready(MiddlewareStream::IdentityFuture(svc_call_fut))
+            }
+            ControlFlow::Break(response) => ready(MiddlewareStream::Result(
+                once(ready(Ok(CallResult::new(response)))),
+            )),
+        }
+    }
+}
+
+//------------ Helper functions ----------------------------------------------
+
+/// Build an authoritative answer holding the given strings as TXT records.
+///
+/// The answer holds one TXT record per string for TXT and ANY queries and is
+/// empty for queries of other types.
+pub(super) fn mk_txt_response<RequestOctets, Target>(
+    msg: &Message<RequestOctets>,
+    qname: &impl ToName,
+    qclass: Class,
+    qtype: Rtype,
+    texts: impl IntoIterator<Item = impl AsRef<str>>,
+) -> Result<AdditionalBuilder<StreamTarget<Target>>, PushError>
+where
+    RequestOctets: Octets,
+    Target: Composer + Default,
+{
+    let builder = mk_builder_for_target();
+    let mut answer = builder.start_answer(msg, Rcode::NOERROR)?;
+    answer.header_mut().set_aa(true);
+
+    if matches!(qtype, Rtype::TXT | Rtype::ANY) {
+        for text in texts {
+            // Strings longer than a single character string are split into
+            // several by the TXT builder.
+            let Ok(txt) =
+                Txt::<Vec<u8>>::build_from_slice(text.as_ref().as_bytes())
+            else {
+                warn!("Skipping TXT value that is too long");
+                continue;
+            };
+            answer.push((qname, qclass, Ttl::ZERO, txt))?;
+        }
+    }
+
+    Ok(answer.additional())
+}
+
+//============ Tests =========================================================
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::base::iana::{Class, Rcode};
+    use crate::base::Rtype;
+    use crate::net::server::metrics::ServerMetrics;
+    use crate::net::server::middleware::test_helpers::{
+        name, process_in_class, service, txts,
+    };
+
+    use super::{
+        DiagnosticsConfig, DiagnosticsField, DiagnosticsMiddlewareSvc,
+    };
+
+    //------------ Tests -----------------------------------------------------
+
+    #[tokio::test(start_paused = true)]
+    async fn default_exposes_counters_but_not_version() {
+        let svc = DiagnosticsMiddlewareSvc::new(
+            service(),
+            DiagnosticsConfig::new(),
+        );
+        let metrics = Arc::new(ServerMetrics::connection_less());
+        metrics.inc_num_received_requests();
+        metrics.inc_num_received_requests();
+        metrics.inc_num_sent_responses();
+        svc.clone().add_metrics("udp", metrics);
+
+        let response = process_in_class(
+            &svc,
+            "diagnostics.server",
+            Rtype::TXT,
+            Class::CH,
+        )
+        .await;
+        assert_eq!(response.header().rcode(), Rcode::NOERROR);
+        assert!(response.header().aa());
+        assert_eq!(
+            txts(&response),
+            [
+                "uptime=0",
+                "udp.received_requests=2",
+                "udp.sent_responses=1"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn other_queries_are_passed_on() {
+        let svc = DiagnosticsMiddlewareSvc::new(
+            service(),
+            DiagnosticsConfig::new(),
+        );
+
+        // Wrong class.
+        let response = process_in_class(
+            &svc,
+            "diagnostics.server",
+            Rtype::TXT,
+            Class::IN,
+        )
+        .await;
+        assert_eq!(response.answer().unwrap().count(), 1);
+        assert!(txts(&response).is_empty());
+
+        // Wrong name.
+        let response =
+            process_in_class(&svc, "other.server", Rtype::TXT, Class::CH)
+                .await;
+        assert!(txts(&response).is_empty());
+
+        // Right name, other type: empty answer.
+        let response =
+            process_in_class(&svc, "Diagnostics.Server", Rtype::A, Class::CH)
+                .await;
+        assert_eq!(response.header().rcode(), Rcode::NOERROR);
+        assert_eq!(response.answer().unwrap().count(), 0);
+    }
+
+    #[tokio::test]
+    async fn name_class_and_fields_are_configurable() {
+        let config = DiagnosticsConfig::new()
+            .with_qname(name("status.example"))
+            .with_class(Class::IN)
+            .with_fields(&[DiagnosticsField::Version])
+            .with_version("test 1.0");
+        let svc = DiagnosticsMiddlewareSvc::new(service(), config);
+
+        let response =
+            process_in_class(&svc, "status.example", Rtype::ANY, Class::IN)
+                .await;
+        assert_eq!(txts(&response), ["version=test 1.0"]);
+    }
+}
@@ -0,0 +1,370 @@
+//! Auditing of DNSSEC signatures in responses.
+//!
+//! A client that sets the DNSSEC OK (DO) bit in its query expects responses
+//! from signed zones to include the RRSIG records covering the returned
+//! RRsets. A response for a signed zone that lacks them, e.g. because of a
+//! bug in the signing pipeline, will fail validation at the client but
+//! would otherwise go unnoticed by the operator of the server.
+//!
+//! The [`DnssecResponseAuditMiddlewareSvc`] checks responses for such missing
+//! signatures, logging a warning and optionally attaching an [RFC 8914]
+//! "RRSIGs Missing" extended error to the response when one is found.
+//!
+//! As this requires a scan of the answer section of every response to a DO
+//! query it is intended as an opt-in self-check and is not part of the
+//! default middleware chain.
+//!
+//! [RFC 8914]: https://www.rfc-editor.org/rfc/rfc8914.html
+use core::future::{ready, Ready};
+use core::marker::PhantomData;
+
+use std::sync::Arc;
+use std::vec::Vec;
+
+use bytes::Bytes;
+use octseq::Octets;
+use tracing::warn;
+
+use crate::base::iana::ExtendedErrorCode;
+use crate::base::message_builder::AdditionalBuilder;
+use crate::base::opt::ExtendedError;
+use crate::base::wire::Composer;
+use crate::base::{Name, ParsedName, Rtype, StreamTarget, ToName};
+use crate::net::server::message::Request;
+use crate::net::server::service::{Service, ServiceResult};
+use crate::net::server::util::add_edns_options;
+use crate::rdata::Rrsig;
+
+use super::stream::PostprocessingStream;
+
+//------------ DnssecResponseAuditMiddlewareSvc ------------------------------
+
+/// A middleware service for detecting unsigned answers from signed zones.
+///
+/// For each response to a request with the DNSSEC OK bit set, every RRset
+/// in the answer section whose owner is at or below the apex of one of the
+/// zones configured via [`with_signed_zone()`] must be covered by an RRSIG
+/// record in the answer section with the same owner and a matching type
+/// covered field.
+///
+/// If that is not the case a warning is logged and, if enabled via
+/// [`add_ede()`], an "RRSIGs Missing" extended error is added to the
+/// response. The response is otherwise passed through unmodified.
+///
+/// Note that the presence of a covering RRSIG is all that is checked: the
+/// signatures themselves are not validated.
+///
+/// [`with_signed_zone()`]: Self::with_signed_zone
+/// [`add_ede()`]: Self::add_ede
+#[derive(Clone, Debug)]
+pub struct DnssecResponseAuditMiddlewareSvc<
+    RequestOctets,
+    NextSvc,
+    RequestMeta,
+> {
+    /// The upstream [`Service`] to pass requests to and receive responses
+    /// from.
+    next_svc: NextSvc,
+
+    /// The apexes of the zones whose answers should be signed.
+    signed_zones: Arc<Vec<Name<Bytes>>>,
+
+    /// Should an extended error be added to responses missing RRSIGs?
+    ///
+    /// Defaults to false.
+    add_ede: bool,
+
+    _phantom: PhantomData<(RequestOctets, RequestMeta)>,
+}
+
+impl<RequestOctets, NextSvc, RequestMeta>
+    DnssecResponseAuditMiddlewareSvc<RequestOctets, NextSvc, RequestMeta>
+{
+    /// Creates an instance of this middleware service.
+    ///
+    /// No zones are considered signed until added using
+    /// [`with_signed_zone()`].
+    ///
+    /// [`with_signed_zone()`]: Self::with_signed_zone
+    #[must_use]
+    pub fn new(next_svc: NextSvc) -> Self {
+        Self {
+            next_svc,
+            signed_zones: Default::default(),
+            add_ede: false,
+            _phantom: PhantomData,
+        }
+    }
+
+    /// Audits answers from the zone with the given apex.
+    #[must_use]
+    pub fn with_signed_zone(mut self, apex: &impl ToName) -> Self {
+        Arc::make_mut(&mut self.signed_zones).push(apex.to_name());
+        self
+    }
+
+    /// Adds an "RRSIGs Missing" extended error to responses that fail the
+    /// audit.
+    #[must_use]
+    pub fn add_ede(mut self, enabled: bool) -> Self {
+        self.add_ede = enabled;
+        self
+    }
+}
+
+impl<RequestOctets, NextSvc, RequestMeta>
+    DnssecResponseAuditMiddlewareSvc<RequestOctets, NextSvc, RequestMeta>
+where
+    RequestOctets: Octets + Send + Sync + Unpin,
+    NextSvc: Service<RequestOctets, RequestMeta>,
+    NextSvc::Target: Composer + Default,
+    RequestMeta: Clone + Default,
+{
+    fn postprocess(
+        request: &Request<RequestOctets, RequestMeta>,
+        response: &mut AdditionalBuilder<StreamTarget<NextSvc::Target>>,
+        signed_zones: &[Name<Bytes>],
+        add_ede: bool,
+    ) {
+        let dnssec_ok = match request.message().opt() {
+            Some(opt) => opt.dnssec_ok(),
+            None => false,
+        };
+        if !dnssec_ok || signed_zones.is_empty() {
+            return;
+        }
+
+        let Some((owner, rtype)) =
+            Self::find_unsigned(response, signed_zones)
+        else {
+            return;
+        };
+
+        warn!(
+            "DNSSEC audit: response to {} lacks an RRSIG covering the {rtype} RRset at {owner}",
+            request.client_addr()
+        );
+
+        if add_ede {
+            if let Err(err) = add_edns_options(response, |builder| {
+                builder.push(&ExtendedError::<&[u8]>::from(
+                    ExtendedErrorCode::RRSIGS_MISSING,
+                ))
+            }) {
+                warn!("Unable to add RRSIGs Missing EDE to response: {err}");
+            }
+        }
+    }
+
+    /// Returns the owner and type of the first RRset in the answer section
+    /// of the response that should be signed but isn't.
+    fn find_unsigned(
+        response: &AdditionalBuilder<StreamTarget<NextSvc::Target>>,
+        signed_zones: &[Name<Bytes>],
+    ) -> Option<(Name<Vec<u8>>, Rtype)> {
+        let source = response.as_message();
+        let answer = source.answer().ok()?;
+
+        let mut rrsets = Vec::new();
+        let mut covered = Vec::new();
+        for rr in answer.flatten() {
+            let owner = rr.owner();
+            if !signed_zones.iter().any(|apex| owner.ends_with(apex)) {
+                continue;
+            }
+            if rr.rtype() == Rtype::RRSIG {
+                if let Ok(Some(rrsig)) =
+                    rr.to_record::<Rrsig<_, ParsedName<_>>>()
+                {
+                    covered.push((owner, rrsig.data().type_covered()));
+                }
+            } else {
+                rrsets.push((owner, rr.rtype()));
+            }
+        }
+
+        rrsets
+            .into_iter()
+            .find(|(owner, rtype)| {
+                !covered.iter().any(|(covered_owner, covered_rtype)| {
+                    covered_rtype == rtype && covered_owner.name_eq(owner)
+                })
+            })
+            .map(|(owner, rtype)| (owner.to_name(), rtype))
+    }
+
+    fn map_stream_item(
+        request: Request<RequestOctets, RequestMeta>,
+        mut stream_item: ServiceResult<NextSvc::Target>,
+        pp_meta: &mut (Arc<Vec<Name<Bytes>>>, bool),
+    ) -> ServiceResult<NextSvc::Target> {
+        if let Ok(cr) = &mut stream_item {
+            if let Some(response) = cr.response_mut() {
+                let (signed_zones, add_ede) = pp_meta;
+                Self::postprocess(&request, response, signed_zones, *add_ede);
+            }
+        }
+        stream_item
+    }
+}
+
+//--- Service
+
+impl<RequestOctets, NextSvc, RequestMeta> Service<RequestOctets, RequestMeta>
+    for DnssecResponseAuditMiddlewareSvc<RequestOctets, NextSvc, RequestMeta>
+where
+    RequestOctets: Octets + Send + Sync + 'static + Unpin,
+    NextSvc: Service<RequestOctets, RequestMeta>,
+    NextSvc::Future: Unpin,
+    NextSvc::Target: Composer + Default,
+    RequestMeta: Clone + Default + Unpin,
+{
+    type Target = NextSvc::Target;
+    type Stream = PostprocessingStream<
+        RequestOctets,
+        NextSvc::Future,
+        NextSvc::Stream,
+        RequestMeta,
+        (Arc<Vec<Name<Bytes>>>, bool),
+    >;
+    type Future = Ready<Self::Stream>;
+
+    fn call(
+        &self,
+        request: Request<RequestOctets, RequestMeta>,
+    ) -> Self::Future {
+        let svc_call_fut = self.next_svc.call(request.clone());
+        ready(PostprocessingStream::new(
+            svc_call_fut,
+            request,
+            (self.signed_zones.clone(), self.add_ede),
+            Self::map_stream_item,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::str::FromStr;
+
+    use std::vec::Vec;
+
+    use bytes::Bytes;
+    use futures_util::StreamExt;
+
+    use crate::base::iana::{Class, ExtendedErrorCode, Rcode, SecAlg};
+    use crate::base::opt::ExtendedError;
+    use crate::base::{Message, MessageBuilder, Name, Rtype, Ttl};
+    use crate::net::server::message::{Request, UdpTransportContext};
+    use crate::net::server::service::{CallResult, Service, ServiceResult};
+    use crate::net::server::util::{mk_builder_for_target, service_fn};
+    use crate::rdata::dnssec::Timestamp;
+    use crate::rdata::{Rrsig, A};
+
+    use super::DnssecResponseAuditMiddlewareSvc;
+
+    //------------ Tests -----------------------------------------------------
+
+    #[tokio::test]
+    async fn signed_answer_passes_audit() {
+        let response = process(true, true).await;
+        assert_eq!(response.header().rcode(), Rcode::NOERROR);
+        assert!(ede_codes(&response).is_empty());
+    }
+
+    #[tokio::test]
+    async fn missing_rrsig_adds_ede() {
+        let response = process(true, false).await;
+        assert_eq!(ede_codes(&response), [ExtendedErrorCode::RRSIGS_MISSING]);
+    }
+
+    #[tokio::test]
+    async fn missing_rrsig_is_ignored_without_do() {
+        let response = process(false, false).await;
+        assert!(ede_codes(&response).is_empty());
+    }
+
+    //------------ Helper functions ------------------------------------------
+
+    fn ede_codes(response: &Message<Vec<u8>>) -> Vec<ExtendedErrorCode> {
+        let Some(opt) = response.opt() else {
+            return Vec::new();
+        };
+        opt.opt()
+            .iter::<ExtendedError<_>>()
+            .map(|ede| ede.unwrap().code())
+            .collect()
+    }
+
+    async fn process(dnssec_ok: bool, signed: bool) -> Message<Vec<u8>> {
+        let query = MessageBuilder::new_vec();
+        let mut query = query.question();
+        query
+            .push((
+                Name::<Bytes>::from_str("www.example.com").unwrap(),
+                Rtype::A,
+            ))
+            .unwrap();
+        let mut query = query.additional();
+        query
+            .opt(|opt| {
+                opt.set_dnssec_ok(dnssec_ok);
+                Ok(())
+            })
+            .unwrap();
+        let message = query.into_message();
+
+        let request = Request::for_test(
+            message,
+            UdpTransportContext::default(),
+            "127.0.0.1:12345".parse().unwrap(),
+        );
+
+        fn signed_service(
+            req: Request<Vec<u8>>,
+            signed: bool,
+        ) -> ServiceResult<Vec<u8>> {
+            let name = Name::<Vec<u8>>::from_str("www.example.com").unwrap();
+            let apex = Name::<Vec<u8>>::from_str("example.com").unwrap();
+            let builder = mk_builder_for_target();
+            let mut question = builder.question();
+            question
+                .push(req.message().sole_question().unwrap())
+                .unwrap();
+            let mut answer = question.answer();
+            answer
+                .push((&name, Class::IN, 3600, A::from_octets(192, 0, 2, 1)))
+                .unwrap();
+            if signed {
+                let rrsig = Rrsig::new(
+                    Rtype::A,
+                    SecAlg::ECDSAP256SHA256,
+                    3,
+                    Ttl::from_secs(3600),
+                    Timestamp::from(1_000_000),
+                    Timestamp::from(0),
+                    12345,
+                    apex,
+                    Bytes::from_static(b"signature"),
+                )
+                .unwrap();
+                answer.push((&name, Class::IN, 3600, rrsig)).unwrap();
+            }
+            Ok(CallResult::new(answer.additional()))
+        }
+
+        let my_svc = service_fn(signed_service, signed);
+        let middleware_svc = DnssecResponseAuditMiddlewareSvc::new(my_svc)
+            .with_signed_zone(
+                &Name::<Bytes>::from_str("example.com").unwrap(),
+            )
+            .add_ede(true);
+        let mut stream = middleware_svc.call(request).await;
+        let call_result: CallResult<Vec<u8>> =
+            stream.next().await.unwrap().unwrap();
+        let (response, _feedback) = call_result.into_inner();
+        let response = response.unwrap().finish();
+
+        Message::from_octets(response.as_dgram_slice().to_vec()).unwrap()
+    }
+}
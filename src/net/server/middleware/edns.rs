@@ -32,6 +32,31 @@ use crate::base::name::ToLabelIter;
 /// [IANA registry]: https://www.iana.org/assignments/dns-parameters/dns-parameters.xhtml#dns-parameters-14
 const EDNS_VERSION_ZERO: u8 = 0;
 
+//------------ UnsupportedVersionPolicy --------------------------------------
+
+/// How to handle requests whose OPT record specifies an EDNS version that is
+/// not supported by this implementation.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum UnsupportedVersionPolicy {
+    /// Respond with extended RCODE BADVERS and an OPT record advertising the
+    /// highest supported EDNS version, i.e. version 0.
+    ///
+    /// This is the behaviour required by [RFC 6891 section 6.1.3].
+    ///
+    /// [RFC 6891 section 6.1.3]:
+    ///     https://datatracker.ietf.org/doc/html/rfc6891#section-6.1.3
+    #[default]
+    BadVers,
+
+    /// Process the request as if it were an EDNS version 0 request.
+    ///
+    /// This is NOT RFC 6891 compliant but may be useful when interoperating
+    /// with broken clients.
+    Ignore,
+}
+
+//------------ EdnsMiddlewareSvc ---------------------------------------------
+
 /// A middleware service for adding EDNS(0) related functionality.
 ///
 /// Standards covered by ths implementation:
@@ -57,6 +82,11 @@ pub struct EdnsMiddlewareSvc<RequestOctets, NextSvc, RequestMeta> {
     /// responses through unmodified.
     enabled: bool,
 
+    /// How to handle requests with an unsupported EDNS version.
+    ///
+    /// Defaults to [`UnsupportedVersionPolicy::BadVers`].
+    unsupported_version_policy: UnsupportedVersionPolicy,
+
     _phantom: PhantomData<(RequestOctets, RequestMeta)>,
 }
 
@@ -69,6 +99,7 @@ impl<RequestOctets, NextSvc, RequestMeta>
         Self {
             next_svc,
             enabled: true,
+            unsupported_version_policy: UnsupportedVersionPolicy::default(),
             _phantom: PhantomData,
         }
     }
@@ -77,6 +108,16 @@ impl<RequestOctets, NextSvc, RequestMeta>
         self.enabled = enabled;
         self
     }
+
+    /// Sets how requests with an unsupported EDNS version are handled.
+    #[must_use]
+    pub fn with_unsupported_version_policy(
+        mut self,
+        policy: UnsupportedVersionPolicy,
+    ) -> Self {
+        self.unsupported_version_policy = policy;
+        self
+    }
 }
 
 impl<RequestOctets, NextSvc, RequestMeta>
@@ -127,11 +168,18 @@ where
                 //   "If a responder does not implement the VERSION level of
                 //    the request, then it MUST respond with RCODE=BADVERS."
                 if opt_rec.version() > EDNS_VERSION_ZERO {
-                    debug!("RFC 6891 6.1.3 violation: request EDNS version {} > 0", opt_rec.version());
-                    return ControlFlow::Break(mk_error_response(
-                        request.message(),
-                        OptRcode::BADVERS,
-                    ));
+                    match self.unsupported_version_policy {
+                        UnsupportedVersionPolicy::BadVers => {
+                            debug!("RFC 6891 6.1.3 violation: request EDNS version {} > 0", opt_rec.version());
+                            return ControlFlow::Break(
+                                Self::mk_badvers_response(request),
+                            );
+                        }
+
+                        UnsupportedVersionPolicy::Ignore => {
+                            trace!("Ignoring unsupported request EDNS version {} per policy", opt_rec.version());
+                        }
+                    }
                 }
 
                 match request.transport_ctx() {
@@ -340,6 +388,26 @@ where
         // field to some value?
     }
 
+    /// Creates a BADVERS response advertising EDNS version 0.
+    fn mk_badvers_response(
+        request: &Request<RequestOctets, RequestMeta>,
+    ) -> AdditionalBuilder<StreamTarget<NextSvc::Target>> {
+        // https://datatracker.ietf.org/doc/html/rfc6891#section-6.1.3
+        // 6.1.3. OPT Record TTL Field Use
+        //   "All responses MUST be limited in format to the VERSION level
+        //    of the request, but the VERSION of each response SHOULD be the
+        //    highest implementation level of the responder."
+        let mut response =
+            mk_error_response(request.message(), OptRcode::BADVERS);
+        if let Err(err) = add_edns_options(&mut response, |builder| {
+            builder.set_version(EDNS_VERSION_ZERO);
+            Ok(())
+        }) {
+            warn!("Cannot set EDNS version in BADVERS response: {err}");
+        }
+        response
+    }
+
     fn reserve_space_for_opt(
         request: &mut Request<RequestOctets, RequestMeta>,
         is_tcp: bool,
@@ -397,6 +465,11 @@ where
         _pp_meta: &mut (),
     ) -> ServiceResult<NextSvc::Target> {
         if let Ok(cr) = &mut stream_item {
+            // Extended DNS errors are carried in the OPT record which must
+            // not be included if the request lacked one, see RFC 8914.
+            if request.message().opt().is_none() {
+                cr.clear_edes();
+            }
             if let Some(response) = cr.response_mut() {
                 Self::postprocess(&request, response);
             }
@@ -475,12 +548,13 @@ mod tests {
         Request, TransportSpecificContext, UdpTransportContext,
     };
 
-    use crate::base::iana::Rcode;
+    use crate::base::iana::{ExtendedErrorCode, OptRcode, Rcode};
+    use crate::base::opt::ExtendedError;
     use crate::net::server::middleware::mandatory::MINIMUM_RESPONSE_BYTE_LEN;
     use crate::net::server::service::{CallResult, Service, ServiceResult};
     use crate::net::server::util::{mk_builder_for_target, service_fn};
 
-    use super::EdnsMiddlewareSvc;
+    use super::{EdnsMiddlewareSvc, UnsupportedVersionPolicy};
 
     //------------ Constants -------------------------------------------------
 
@@ -528,8 +602,134 @@ mod tests {
         assert_eq!(process(HUGE, HUGE).await, HUGE);
     }
 
+    #[tokio::test]
+    async fn unsupported_version_gets_badvers() {
+        let response = process_version(1, None).await;
+
+        assert_eq!(response.header().rcode(), Rcode::NOERROR);
+        assert_eq!(response.opt_rcode(), OptRcode::BADVERS);
+        let opt = response.opt().unwrap();
+        assert_eq!(opt.version(), 0);
+    }
+
+    #[tokio::test]
+    async fn unsupported_version_can_be_ignored() {
+        let response =
+            process_version(1, Some(UnsupportedVersionPolicy::Ignore)).await;
+
+        assert_eq!(response.header().rcode(), Rcode::NXDOMAIN);
+        assert_eq!(response.opt_rcode(), OptRcode::NXDOMAIN);
+    }
+
+    #[tokio::test]
+    async fn ede_is_only_added_for_edns_requests() {
+        fn my_service(
+            req: Request<Vec<u8>>,
+            _meta: (),
+        ) -> ServiceResult<Vec<u8>> {
+            let builder = mk_builder_for_target();
+            let answer =
+                builder.start_answer(req.message(), Rcode::REFUSED)?;
+            Ok(CallResult::new(answer.additional())
+                .with_ede(ExtendedErrorCode::PROHIBITED, "not for you"))
+        }
+
+        let svc = EdnsMiddlewareSvc::new(service_fn(my_service, ()));
+
+        let response = call(&svc, mk_request(true)).await;
+        let opt = response.opt().unwrap();
+        let ede = opt.opt().iter::<ExtendedError<_>>().next().unwrap();
+        let ede = ede.unwrap();
+        assert_eq!(ede.code(), ExtendedErrorCode::PROHIBITED);
+        assert_eq!(ede.text_slice(), Some(b"not for you".as_ref()));
+
+        let response = call(&svc, mk_request(false)).await;
+        assert!(response.opt().is_none());
+        assert_eq!(response.header_counts().arcount(), 0);
+    }
+
     //------------ Helper functions ------------------------------------------
 
+    fn mk_request(with_opt: bool) -> Request<Vec<u8>> {
+        let mut query = MessageBuilder::new_vec().question();
+        query.push((Name::<Bytes>::root(), Rtype::A)).unwrap();
+        let mut additional = query.additional();
+        if with_opt {
+            additional.opt(|_| Ok(())).unwrap();
+        }
+        Request::for_test(
+            additional.into_message(),
+            UdpTransportContext::default(),
+            "127.0.0.1:12345".parse().unwrap(),
+        )
+    }
+
+    async fn call<Svc>(
+        svc: &Svc,
+        request: Request<Vec<u8>>,
+    ) -> Message<Vec<u8>>
+    where
+        Svc: Service<Vec<u8>, (), Target = Vec<u8>>,
+    {
+        let mut stream = svc.call(request).await;
+        let call_result: CallResult<Vec<u8>> =
+            stream.next().await.unwrap().unwrap();
+        let (response, _feedback) = call_result.into_inner();
+        let response = response.unwrap().finish();
+        Message::from_octets(response.as_dgram_slice().to_vec()).unwrap()
+    }
+
+    async fn process_version(
+        version: u8,
+        policy: Option<UnsupportedVersionPolicy>,
+    ) -> Message<Vec<u8>> {
+        // Build a dummy DNS query with the given EDNS version.
+        let query = MessageBuilder::new_vec();
+        let mut query = query.question();
+        query.push((Name::<Bytes>::root(), Rtype::A)).unwrap();
+        let mut additional = query.additional();
+        additional
+            .opt(|builder| {
+                builder.set_version(version);
+                Ok(())
+            })
+            .unwrap();
+        let message = additional.into_message();
+
+        let ctx = UdpTransportContext::new(None);
+        let request = Request::new(
+            "127.0.0.1:12345".parse().unwrap(),
+            Instant::now(),
+            message,
+            ctx.into(),
+            (),
+        );
+
+        fn my_service(
+            req: Request<Vec<u8>>,
+            _meta: (),
+        ) -> ServiceResult<Vec<u8>> {
+            let builder = mk_builder_for_target();
+            let answer =
+                builder.start_answer(req.message(), Rcode::NXDOMAIN)?;
+            Ok(CallResult::new(answer.additional()))
+        }
+
+        let my_svc = service_fn(my_service, ());
+        let mut middleware_svc = EdnsMiddlewareSvc::new(my_svc);
+        if let Some(policy) = policy {
+            middleware_svc =
+                middleware_svc.with_unsupported_version_policy(policy);
+        }
+        let mut stream = middleware_svc.call(request).await;
+        let call_result: CallResult<Vec<u8>> =
+            stream.next().await.unwrap().unwrap();
+        let (response, _feedback) = call_result.into_inner();
+        let response = response.unwrap().finish();
+
+        Message::from_octets(response.as_dgram_slice().to_vec()).unwrap()
+    }
+
     async fn process(
         client_value: Option<u16>,
         server_value: Option<u16>,
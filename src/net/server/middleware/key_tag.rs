@@ -0,0 +1,237 @@
+//! Collection of RFC 8145 trust anchor telemetry.
+//!
+//! [RFC 8145] allows validating resolvers to signal which DNSSEC trust
+//! anchors they have configured by including an edns-key-tag option listing
+//! the key tags of those trust anchors in their queries. Authoritative
+//! operators, in particular of the root zone, can use this to judge how far
+//! a key rollover has been picked up by resolvers.
+//!
+//! The [`KeyTagMiddlewareSvc`] tallies the key tags received in such options
+//! and makes the tallies available as a [`KeyTagSnapshot`].
+//!
+//! [RFC 8145]: https://www.rfc-editor.org/rfc/rfc8145.html
+use core::marker::PhantomData;
+
+use std::collections::{BTreeMap, HashSet};
+use std::sync::{Arc, Mutex};
+
+use octseq::Octets;
+
+use crate::net::server::message::Request;
+use crate::net::server::service::Service;
+
+//------------ KeyTagMiddlewareSvc -------------------------------------------
+
+/// A middleware service for tallying the key tags reported by resolvers.
+///
+/// For each request with an edns-key-tag option every key tag listed in the
+/// option is counted once. The request is then passed to the next service
+/// unmodified.
+///
+/// The tallies are shared between all clones of the service and can be
+/// obtained at any time via [`snapshot()`].
+///
+/// As this adds work, and a lock, to the processing of every request with an
+/// edns-key-tag option it is intended to be opted in to and is not part of
+/// the default middleware chain.
+///
+/// [`snapshot()`]: Self::snapshot
+#[derive(Clone, Debug)]
+pub struct KeyTagMiddlewareSvc<RequestOctets, NextSvc, RequestMeta> {
+    /// The upstream [`Service`] to pass requests to and receive responses
+    /// from.
+    next_svc: NextSvc,
+
+    /// The tallies so far.
+    tallies: Arc<Mutex<KeyTagSnapshot>>,
+
+    _phantom: PhantomData<(RequestOctets, RequestMeta)>,
+}
+
+impl<RequestOctets, NextSvc, RequestMeta>
+    KeyTagMiddlewareSvc<RequestOctets, NextSvc, RequestMeta>
+{
+    /// Creates an instance of this middleware service.
+    #[must_use]
+    pub fn new(next_svc: NextSvc) -> Self {
+        Self {
+            next_svc,
+            tallies: Default::default(),
+            _phantom: PhantomData,
+        }
+    }
+
+    /// Returns a copy of the tallies so far.
+    pub fn snapshot(&self) -> KeyTagSnapshot {
+        self.tallies.lock().unwrap().clone()
+    }
+
+    /// Returns the tallies so far and resets them.
+    pub fn take_snapshot(&self) -> KeyTagSnapshot {
+        core::mem::take(&mut *self.tallies.lock().unwrap())
+    }
+}
+
+impl<RequestOctets, NextSvc, RequestMeta>
+    KeyTagMiddlewareSvc<RequestOctets, NextSvc, RequestMeta>
+where
+    RequestOctets: Octets + Send + Sync + Unpin,
+{
+    fn preprocess(&self, request: &Request<RequestOctets, RequestMeta>) {
+        let Some(opt) = request.message().opt() else {
+            return;
+        };
+        let Some(key_tag) = opt.opt().key_tag() else {
+            return;
+        };
+
+        // Count a tag listed more than once in the same option only once.
+        let tags: HashSet<u16> = key_tag.iter().collect();
+
+        let mut tallies = self.tallies.lock().unwrap();
+        tallies.num_reports += 1;
+        for tag in tags {
+            *tallies.tags.entry(tag).or_default() += 1;
+        }
+    }
+}
+
+//--- Service
+
+impl<RequestOctets, NextSvc, RequestMeta> Service<RequestOctets, RequestMeta>
+    for KeyTagMiddlewareSvc<RequestOctets, NextSvc, RequestMeta>
+where
+    RequestOctets: Octets + Send + Sync + 'static + Unpin,
+    NextSvc: Service<RequestOctets, RequestMeta>,
+    RequestMeta: Clone + Default,
+{
+    type Target = NextSvc::Target;
+    type Stream = NextSvc::Stream;
+    type Future = NextSvc::Future;
+
+    fn call(
+        &self,
+        request: Request<RequestOctets, RequestMeta>,
+    ) -> Self::Future {
+        self.preprocess(&request);
+        self.next_svc.call(request)
+    }
+}
+
+//------------ KeyTagSnapshot ------------------------------------------------
+
+/// The key tags reported by resolvers.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct KeyTagSnapshot {
+    /// The number of requests that included an edns-key-tag option.
+    num_reports: u64,
+
+    /// The number of requests that listed each key tag.
+    tags: BTreeMap<u16, u64>,
+}
+
+impl KeyTagSnapshot {
+    /// The number of requests that included an edns-key-tag option.
+    pub fn num_reports(&self) -> u64 {
+        self.num_reports
+    }
+
+    /// The number of requests that listed each key tag, by key tag.
+    pub fn tags(&self) -> &BTreeMap<u16, u64> {
+        &self.tags
+    }
+
+    /// The number of requests that listed the given key tag.
+    pub fn count(&self, key_tag: u16) -> u64 {
+        self.tags.get(&key_tag).copied().unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::str::FromStr;
+
+    use std::vec::Vec;
+
+    use bytes::Bytes;
+    use futures_util::StreamExt;
+
+    use crate::base::iana::Rcode;
+    use crate::base::opt::KeyTag;
+    use crate::base::{MessageBuilder, Name, Rtype};
+    use crate::net::server::message::{Request, UdpTransportContext};
+    use crate::net::server::service::{CallResult, Service, ServiceResult};
+    use crate::net::server::util::{mk_builder_for_target, service_fn};
+
+    use super::KeyTagMiddlewareSvc;
+
+    //------------ Tests -----------------------------------------------------
+
+    #[tokio::test]
+    async fn key_tags_are_tallied() {
+        fn my_service(
+            req: Request<Vec<u8>>,
+            _meta: (),
+        ) -> ServiceResult<Vec<u8>> {
+            let builder = mk_builder_for_target();
+            let answer =
+                builder.start_answer(req.message(), Rcode::NOERROR).unwrap();
+            Ok(CallResult::new(answer.additional()))
+        }
+
+        let svc = KeyTagMiddlewareSvc::new(service_fn(my_service, ()));
+
+        for tags in [&[20326, 38696][..], &[20326, 20326], &[], &[38696]] {
+            let mut stream = svc.call(mk_request(Some(tags))).await;
+            assert!(stream.next().await.unwrap().is_ok());
+        }
+        let mut stream = svc.call(mk_request(None)).await;
+        assert!(stream.next().await.unwrap().is_ok());
+
+        let snapshot = svc.snapshot();
+        assert_eq!(snapshot.num_reports(), 4);
+        assert_eq!(snapshot.count(20326), 2);
+        assert_eq!(snapshot.count(38696), 2);
+        assert_eq!(snapshot.count(19036), 0);
+        assert_eq!(snapshot.tags().len(), 2);
+
+        assert_eq!(svc.take_snapshot(), snapshot);
+        assert_eq!(svc.snapshot().num_reports(), 0);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn snapshot_serializes() {
+        let snapshot = super::KeyTagSnapshot {
+            num_reports: 3,
+            tags: [(20326, 3), (38696, 1)].into(),
+        };
+        assert_eq!(
+            serde_json::to_string(&snapshot).unwrap(),
+            r#"{"num_reports":3,"tags":{"20326":3,"38696":1}}"#
+        );
+    }
+
+    //------------ Helper functions ------------------------------------------
+
+    fn mk_request(tags: Option<&[u16]>) -> Request<Vec<u8>> {
+        let mut query = MessageBuilder::new_vec().question();
+        query
+            .push((Name::<Bytes>::from_str(".").unwrap(), Rtype::DNSKEY))
+            .unwrap();
+        let mut query = query.additional();
+        if let Some(tags) = tags {
+            let octets: Vec<u8> =
+                tags.iter().flat_map(|tag| tag.to_be_bytes()).collect();
+            let key_tag = KeyTag::from_octets(octets).unwrap();
+            query.opt(|opt| opt.key_tag(&key_tag)).unwrap();
+        }
+
+        Request::for_test(
+            query.into_message(),
+            UdpTransportContext::default(),
+            "127.0.0.1:12345".parse().unwrap(),
+        )
+    }
+}
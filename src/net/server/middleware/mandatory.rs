@@ -3,19 +3,26 @@ use core::future::{ready, Ready};
 use core::marker::PhantomData;
 use core::ops::ControlFlow;
 
-use std::fmt::Display;
+use std::boxed::Box;
+use std::fmt::{Debug, Display};
+use std::sync::Arc;
 
 use futures_util::stream::{once, Once, Stream};
 use octseq::Octets;
 use tracing::{debug, error, trace, warn};
 
-use crate::base::iana::{Opcode, OptRcode};
-use crate::base::message_builder::{AdditionalBuilder, PushError};
+use crate::base::iana::{Opcode, OptRcode, Rtype};
+use crate::base::message::{debug_validate_message, QuestionSection};
+use crate::base::message_builder::{
+    rebuild_opt, AdditionalBuilder, OptOverrides, PushError,
+};
+use crate::base::name::ParsedName;
 use crate::base::wire::{Composer, ParseError};
 use crate::base::{Message, StreamTarget};
 use crate::net::server::message::{Request, TransportSpecificContext};
 use crate::net::server::service::{CallResult, Service, ServiceResult};
 use crate::net::server::util::{mk_builder_for_target, mk_error_response};
+use crate::rdata::AllRecordData;
 
 use super::stream::{MiddlewareStream, PostprocessingStream};
 
@@ -40,6 +47,19 @@ pub const MINIMUM_RESPONSE_BYTE_LEN: u16 = 512;
 /// [1035]: https://datatracker.ietf.org/doc/html/rfc1035
 /// [2181]: https://datatracker.ietf.org/doc/html/rfc2181
 /// [9619]: https://datatracker.ietf.org/doc/html/rfc9619
+///
+/// # Response size
+///
+/// UDP responses are truncated to fit the size allowed for the response
+/// minus any space reserved in the request by middleware that wraps this
+/// one. For example, the `TsigMiddlewareSvc` reserves space for the TSIG
+/// record it adds when signing the response. This only works if this
+/// service is wrapped by the TSIG middleware, i.e. the response is
+/// truncated first and signed afterwards.
+///
+/// Which records a truncated response retains is decided by a
+/// [`TruncationStrategy`], by default [`DefaultTruncation`]. A different
+/// strategy can be used via [`Self::with_truncation_strategy`].
 #[derive(Clone, Debug)]
 pub struct MandatoryMiddlewareSvc<RequestOctets, NextSvc, RequestMeta> {
     /// The upstream [`Service`] to pass requests to and receive responses
@@ -50,6 +70,9 @@ pub struct MandatoryMiddlewareSvc<RequestOctets, NextSvc, RequestMeta> {
     /// responses.
     strict: bool,
 
+    /// Decides which records to keep when truncating a response.
+    truncation: Arc<dyn TruncationStrategy>,
+
     _phantom: PhantomData<(RequestOctets, RequestMeta)>,
 }
 
@@ -64,6 +87,7 @@ impl<RequestOctets, NextSvc, RequestMeta>
         Self {
             strict: true,
             next_svc,
+            truncation: Arc::new(DefaultTruncation),
             _phantom: PhantomData,
         }
     }
@@ -76,9 +100,20 @@ impl<RequestOctets, NextSvc, RequestMeta>
         Self {
             strict: false,
             next_svc,
+            truncation: Arc::new(DefaultTruncation),
             _phantom: PhantomData,
         }
     }
+
+    /// Sets the strategy for truncating responses that are too large.
+    #[must_use]
+    pub fn with_truncation_strategy(
+        mut self,
+        strategy: Box<dyn TruncationStrategy>,
+    ) -> Self {
+        self.truncation = strategy.into();
+        self
+    }
 }
 
 impl<RequestOctets, NextSvc, RequestMeta>
@@ -96,12 +131,18 @@ where
     /// allowed to be, or if missing will instead honour the clients indicated
     /// UDP response payload size (if an EDNS OPT is present in the request).
     ///
-    /// Truncation discards the authority and additional sections, except for
-    /// any OPT record present which will be preserved, then truncates to the
-    /// specified byte length.
+    /// Space reserved in the request via [`Request::reserve_bytes()`] is
+    /// kept free. This is space for records that middleware wrapping this
+    /// one will add after truncation, such as the TSIG record added by
+    /// `TsigMiddlewareSvc`.
+    ///
+    /// Which records are retained is decided by the given strategy. Any OPT
+    /// record present is always preserved. Should the strategy retain too
+    /// many records, they are all discarded as with [`DefaultTruncation`].
     fn truncate(
         request: &Request<RequestOctets, RequestMeta>,
         response: &mut AdditionalBuilder<StreamTarget<NextSvc::Target>>,
+        strategy: &dyn TruncationStrategy,
     ) -> Result<(), TruncateError> {
         if let TransportSpecificContext::Udp(ctx) = request.transport_ctx() {
             // https://datatracker.ietf.org/doc/html/rfc1035#section-4.2.1
@@ -111,7 +152,9 @@ where
             let max_response_size = ctx
                 .max_response_size_hint()
                 .unwrap_or(MINIMUM_RESPONSE_BYTE_LEN);
-            let max_response_size = max_response_size as usize;
+            let max_response_size = max_response_size
+                .saturating_sub(request.num_reserved_bytes())
+                as usize;
             let response_len = response.as_slice().len();
 
             if response_len > max_response_size {
@@ -151,43 +194,29 @@ where
                 // Remember the original length.
                 let old_len = response.as_slice().len();
 
-                // Copy the header, question and opt record from the
-                // additional section, but leave the answer and authority
-                // sections empty.
                 let source = response.as_message();
-                let mut target = mk_builder_for_target();
-
-                *target.header_mut() = source.header();
-
-                let mut target = target.question();
-                for rr in source.question() {
-                    target.push(rr?)?;
-                }
-
-                let mut target = target.additional();
-                if let Some(opt) = source.opt() {
-                    if let Err(err) = target.push(opt.as_record()) {
-                        warn!("Error while truncating response: unable to push OPT record: {err}");
-                        // As the client had an OPT record and RFC 6891 says
-                        // when truncating that there MUST be an OPT record,
-                        // attempt to push just the empty OPT record (as the
-                        // OPT record header still has value, e.g. the
-                        // requestors payload size field and extended rcode).
-                        if let Err(err) = target.opt(|builder| {
-                            builder.set_version(opt.version());
-                            builder.set_rcode(opt.rcode(response.header()));
-                            builder
-                                .set_udp_payload_size(opt.udp_payload_size());
-                            Ok(())
-                        }) {
-                            error!("Error while truncating response: unable to add minimal OPT record: {err}");
-                        }
-                    }
+                let retain = strategy.retain(source, max_response_size);
+                let mut target =
+                    Self::rebuild(source, source.question(), &retain, true)?;
+                if target.as_slice().len() > max_response_size
+                    && retain != Retain::default()
+                {
+                    warn!("Truncation strategy retained too many records, retaining none");
+                    target = Self::rebuild(
+                        source,
+                        source.question(),
+                        &Retain::default(),
+                        true,
+                    )?;
                 }
 
                 let new_len = target.as_slice().len();
                 trace!("Truncating response from {old_len} bytes to {new_len} bytes");
 
+                if let Err(err) = debug_validate_message(&target.as_message())
+                {
+                    warn!("Rebuilt message is invalid: {err}");
+                }
                 *response = target;
             }
         }
@@ -195,6 +224,182 @@ where
         Ok(())
     }
 
+    /// Removes the OPT record from the response if the request had none.
+    ///
+    /// The rest of the response is left untouched.
+    fn strip_opt(
+        request: &Request<RequestOctets, RequestMeta>,
+        response: &mut AdditionalBuilder<StreamTarget<NextSvc::Target>>,
+    ) -> Result<(), TruncateError> {
+        // https://datatracker.ietf.org/doc/html/rfc6891#section-7
+        //   "Lack of presence of an OPT record in a request MUST be taken as
+        //    an indication that the requestor does not implement any part of
+        //    this specification and that the responder MUST NOT include an
+        //    OPT record in its response."
+        if request.message().opt().is_some()
+            || response.as_message().opt().is_none()
+        {
+            return Ok(());
+        }
+
+        trace!("Removing OPT record from response to non-EDNS request");
+        let source = response.as_message();
+        let target =
+            Self::rebuild(source, source.question(), &Retain::ALL, false)?;
+        if let Err(err) = debug_validate_message(&target.as_message()) {
+            warn!("Rebuilt message is invalid: {err}");
+        }
+        *response = target;
+
+        Ok(())
+    }
+
+    /// Adds a minimal OPT record to the response if the request had one.
+    ///
+    /// The OPT record has version 0 and the DO bit unset. Its UDP payload
+    /// size is the transport supplied hint, if present, or else the one
+    /// advertised by the requestor, but never less than 512 bytes.
+    fn add_missing_opt(
+        request: &Request<RequestOctets, RequestMeta>,
+        response: &mut AdditionalBuilder<StreamTarget<NextSvc::Target>>,
+    ) -> Result<(), PushError> {
+        // https://datatracker.ietf.org/doc/html/rfc6891#section-6.1.1
+        //   "If an OPT record is present in a received request, compliant
+        //    responders MUST include an OPT record in their respective
+        //    responses."
+        let Some(opt) = request.message().opt() else {
+            return Ok(());
+        };
+        if response.as_message().opt().is_some() {
+            return Ok(());
+        }
+
+        let hint = match request.transport_ctx() {
+            TransportSpecificContext::Udp(ctx) => {
+                ctx.max_response_size_hint()
+            }
+            _ => None,
+        };
+        let udp_payload_size = hint
+            .unwrap_or(opt.udp_payload_size())
+            .max(MINIMUM_RESPONSE_BYTE_LEN);
+
+        trace!("Adding missing OPT record to response to EDNS request");
+        response.opt(|builder| {
+            builder.set_udp_payload_size(udp_payload_size);
+            Ok(())
+        })
+    }
+
+    /// Copies the question of the request into the response if it has none.
+    ///
+    /// Responses built without the help of [`MessageBuilder::start_answer()`]
+    /// may lack a question section, but some clients reject responses with
+    /// a QDCOUNT of zero. Unbound and NSD echo the question in all responses
+    /// including errors, so we do the same.
+    ///
+    /// [`MessageBuilder::start_answer()`]:
+    ///     crate::base::MessageBuilder::start_answer
+    fn echo_question(
+        request: &Request<RequestOctets, RequestMeta>,
+        response: &mut AdditionalBuilder<StreamTarget<NextSvc::Target>>,
+    ) -> Result<(), TruncateError> {
+        if response.counts().qdcount() != 0
+            || request.message().header_counts().qdcount() == 0
+        {
+            return Ok(());
+        }
+
+        trace!("Copying question from request into response");
+        let target = Self::rebuild(
+            response.as_message(),
+            request.message().question(),
+            &Retain::ALL,
+            true,
+        )?;
+        if let Err(err) = debug_validate_message(&target.as_message()) {
+            warn!("Rebuilt message is invalid: {err}");
+        }
+        *response = target;
+
+        Ok(())
+    }
+
+    /// Builds a copy of a response retaining only some of its records.
+    ///
+    /// The header is copied, as are the number of records given by `retain`
+    /// from the start of each section and, if `with_opt` is true, the OPT
+    /// record, if any. The question section is taken from `question` which
+    /// usually is that of `source`.
+    fn rebuild<QuestionOctets: Octets + ?Sized>(
+        source: Message<&[u8]>,
+        question: QuestionSection<'_, QuestionOctets>,
+        retain: &Retain,
+        with_opt: bool,
+    ) -> Result<AdditionalBuilder<StreamTarget<NextSvc::Target>>, TruncateError>
+    {
+        let mut target = mk_builder_for_target();
+
+        *target.header_mut() = source.header();
+
+        let mut target = target.question();
+        for rr in question {
+            target.push(rr?)?;
+        }
+
+        let mut target = target.answer();
+        for rr in source.answer()?.take(retain.answer) {
+            if let Some(rr) =
+                rr?.into_record::<AllRecordData<_, ParsedName<_>>>()?
+            {
+                target.push(rr)?;
+            }
+        }
+
+        let mut target = target.authority();
+        for rr in source.authority()?.take(retain.authority) {
+            if let Some(rr) =
+                rr?.into_record::<AllRecordData<_, ParsedName<_>>>()?
+            {
+                target.push(rr)?;
+            }
+        }
+
+        let mut target = target.additional();
+        let records = source.additional()?.filter(|rr| {
+            rr.as_ref().map_or(true, |rr| rr.rtype() != Rtype::OPT)
+        });
+        for rr in records.take(retain.additional) {
+            if let Some(rr) =
+                rr?.into_record::<AllRecordData<_, ParsedName<_>>>()?
+            {
+                target.push(rr)?;
+            }
+        }
+
+        if let Some(opt) = source.opt().filter(|_| with_opt) {
+            if let Err(err) = target.push(opt.as_record()) {
+                warn!("Error while truncating response: unable to push OPT record: {err}");
+                // As the client had an OPT record and RFC 6891 says when
+                // truncating that there MUST be an OPT record, attempt to
+                // push just the empty OPT record (as the OPT record header
+                // still has value, e.g. the requestors payload size field
+                // and extended rcode).
+                if let Err(err) = target.opt(|builder| {
+                    rebuild_opt(
+                        &opt,
+                        builder,
+                        &OptOverrides::new().without_options(),
+                    )
+                }) {
+                    error!("Error while truncating response: unable to add minimal OPT record: {err}");
+                }
+            }
+        }
+
+        Ok(target)
+    }
+
     fn preprocess(
         &self,
         msg: &Message<RequestOctets>,
@@ -238,8 +443,30 @@ where
         request: &Request<RequestOctets, RequestMeta>,
         response: &mut AdditionalBuilder<StreamTarget<NextSvc::Target>>,
         strict: bool,
+        truncation: &dyn TruncationStrategy,
     ) {
-        if let Err(err) = Self::truncate(request, response) {
+        if let Err(err) = Self::strip_opt(request, response) {
+            error!("Error while removing OPT record from response: {err}");
+            *response =
+                mk_error_response(request.message(), OptRcode::SERVFAIL);
+            return;
+        }
+
+        if let Err(err) = Self::add_missing_opt(request, response) {
+            error!("Error while adding OPT record to response: {err}");
+            *response =
+                mk_error_response(request.message(), OptRcode::SERVFAIL);
+            return;
+        }
+
+        if let Err(err) = Self::echo_question(request, response) {
+            error!("Error while copying question into response: {err}");
+            *response =
+                mk_error_response(request.message(), OptRcode::SERVFAIL);
+            return;
+        }
+
+        if let Err(err) = Self::truncate(request, response, truncation) {
             error!("Error while truncating response: {err}");
             *response =
                 mk_error_response(request.message(), OptRcode::SERVFAIL);
@@ -287,11 +514,16 @@ where
     fn map_stream_item(
         request: Request<RequestOctets, RequestMeta>,
         mut stream_item: ServiceResult<NextSvc::Target>,
-        strict: &mut bool,
+        (strict, truncation): &mut (bool, Arc<dyn TruncationStrategy>),
     ) -> ServiceResult<NextSvc::Target> {
         if let Ok(cr) = &mut stream_item {
             if let Some(response) = cr.response_mut() {
-                Self::postprocess(&request, response, *strict);
+                Self::postprocess(
+                    &request,
+                    response,
+                    *strict,
+                    truncation.as_ref(),
+                );
             }
         }
         stream_item
@@ -318,7 +550,7 @@ where
             NextSvc::Future,
             NextSvc::Stream,
             RequestMeta,
-            bool,
+            (bool, Arc<dyn TruncationStrategy>),
         >,
         Once<Ready<<NextSvc::Stream as Stream>::Item>>,
         <NextSvc::Stream as Stream>::Item,
@@ -335,13 +567,18 @@ where
                 let map = PostprocessingStream::new(
                     svc_call_fut,
                     request,
-                    self.strict,
+                    (self.strict, self.truncation.clone()),
                     Self::map_stream_item,
                 );
                 ready(MiddlewareStream::Map(map))
             }
             ControlFlow::Break(mut response) => {
-                Self::postprocess(&request, &mut response, self.strict);
+                Self::postprocess(
+                    &request,
+                    &mut response,
+                    self.strict,
+                    self.truncation.as_ref(),
+                );
                 ready(MiddlewareStream::Result(once(ready(Ok(
                     CallResult::new(response),
                 )))))
@@ -350,6 +587,70 @@ where
     }
 }
 
+//------------ TruncationStrategy --------------------------------------------
+
+/// A strategy for truncating responses that are too large.
+///
+/// When a response exceeds the size allowed for it, the
+/// [`MandatoryMiddlewareSvc`] sets the TC bit and rebuilds the response from
+/// its header, question and OPT record, if any, plus the records the
+/// strategy asks to retain.
+///
+/// RFC 2181 section 9 permits leaving records in a truncated response, but
+/// a client receiving it should query again over a transport that permits
+/// larger responses. Leaving records in place is thus mostly useful for
+/// clients that can make use of partial data.
+pub trait TruncationStrategy: Debug + Send + Sync {
+    /// Decides which records of an oversized response to retain.
+    ///
+    /// The truncated response must not exceed `max_len` bytes. If it does,
+    /// all records are discarded instead.
+    fn retain(&self, response: Message<&[u8]>, max_len: usize) -> Retain;
+}
+
+//------------ Retain --------------------------------------------------------
+
+/// The records of each section to retain in a truncated response.
+///
+/// Each field gives the number of records to retain from the start of the
+/// respective section. The OPT record is always retained and isn't counted
+/// for the additional section.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Retain {
+    /// The number of answer records to retain.
+    pub answer: usize,
+
+    /// The number of authority records to retain.
+    pub authority: usize,
+
+    /// The number of additional records to retain.
+    pub additional: usize,
+}
+
+impl Retain {
+    /// Retains all records.
+    const ALL: Self = Self {
+        answer: usize::MAX,
+        authority: usize::MAX,
+        additional: usize::MAX,
+    };
+}
+
+//------------ DefaultTruncation ---------------------------------------------
+
+/// The default truncation strategy, retaining no records.
+///
+/// Truncated responses consist only of the header, question and OPT record
+/// which is the minimal response required by RFC 6891 section 7.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DefaultTruncation;
+
+impl TruncationStrategy for DefaultTruncation {
+    fn retain(&self, _response: Message<&[u8]>, _max_len: usize) -> Retain {
+        Retain::default()
+    }
+}
+
 //------------ TruncateError -------------------------------------------------
 
 /// An error occured during oversize response truncation.
@@ -389,6 +690,7 @@ impl From<PushError> for TruncateError {
 
 #[cfg(test)]
 mod tests {
+    use std::boxed::Box;
     use std::vec::Vec;
 
     use bytes::Bytes;
@@ -396,12 +698,16 @@ mod tests {
     use tokio::time::Instant;
 
     use crate::base::iana::Rcode;
-    use crate::base::{MessageBuilder, Name, Rtype};
+    use crate::base::{Message, MessageBuilder, Name, Rtype};
     use crate::net::server::message::{Request, UdpTransportContext};
     use crate::net::server::service::{CallResult, Service, ServiceResult};
     use crate::net::server::util::{mk_builder_for_target, service_fn};
+    use crate::rdata::A;
 
-    use super::{MandatoryMiddlewareSvc, MINIMUM_RESPONSE_BYTE_LEN};
+    use super::{
+        MandatoryMiddlewareSvc, Retain, TruncationStrategy,
+        MINIMUM_RESPONSE_BYTE_LEN,
+    };
 
     //------------ Constants -------------------------------------------------
 
@@ -426,8 +732,207 @@ mod tests {
         assert!(process(Some(HUGE)).await <= Some(HUGE as usize));
     }
 
+    #[tokio::test]
+    async fn truncation_strategy_decides_retained_records() {
+        #[derive(Debug)]
+        struct KeepOneAnswer;
+
+        impl TruncationStrategy for KeepOneAnswer {
+            fn retain(&self, _response: Message<&[u8]>, _: usize) -> Retain {
+                Retain {
+                    answer: 1,
+                    ..Default::default()
+                }
+            }
+        }
+
+        fn many_answers(
+            req: Request<Vec<u8>>,
+            _meta: (),
+        ) -> ServiceResult<Vec<u8>> {
+            let builder = mk_builder_for_target();
+            let mut answer =
+                builder.start_answer(req.message(), Rcode::NOERROR)?;
+            for i in 0..100 {
+                answer.push((
+                    Name::root_ref(),
+                    60,
+                    A::from_octets(192, 0, 2, i),
+                ))?;
+            }
+            Ok(CallResult::new(answer.additional()))
+        }
+
+        let mut query = MessageBuilder::new_vec().question();
+        query.push((Name::<Bytes>::root(), Rtype::A)).unwrap();
+        let request = Request::new(
+            "127.0.0.1:12345".parse().unwrap(),
+            Instant::now(),
+            query.into_message(),
+            UdpTransportContext::new(None).into(),
+            (),
+        );
+
+        let svc = service_fn(many_answers, ());
+        let svc = MandatoryMiddlewareSvc::new(svc)
+            .with_truncation_strategy(Box::new(KeepOneAnswer));
+        let mut stream = svc.call(request).await;
+        let call_result: CallResult<Vec<u8>> =
+            stream.next().await.unwrap().unwrap();
+        let (response, _feedback) = call_result.into_inner();
+        let response = response.unwrap();
+        let response = response.as_message();
+
+        assert!(response.header().tc());
+        assert_eq!(response.header_counts().ancount(), 1);
+        assert!(response.as_slice().len() <= MIN_ALLOWED as usize);
+    }
+
+    #[tokio::test]
+    async fn opt_is_stripped_for_non_edns_request() {
+        fn answer_with_opt(
+            req: Request<Vec<u8>>,
+            _meta: (),
+        ) -> ServiceResult<Vec<u8>> {
+            let builder = mk_builder_for_target();
+            let mut answer =
+                builder.start_answer(req.message(), Rcode::NOERROR)?;
+            answer.push((
+                Name::root_ref(),
+                60,
+                A::from_octets(192, 0, 2, 1),
+            ))?;
+            let mut additional = answer.additional();
+            additional.push((
+                Name::root_ref(),
+                60,
+                A::from_octets(192, 0, 2, 2),
+            ))?;
+            additional.opt(|builder| {
+                builder.set_udp_payload_size(1232);
+                Ok(())
+            })?;
+            Ok(CallResult::new(additional))
+        }
+
+        let mut query = MessageBuilder::new_vec().question();
+        query.push((Name::<Bytes>::root(), Rtype::A)).unwrap();
+        let request = Request::new(
+            "127.0.0.1:12345".parse().unwrap(),
+            Instant::now(),
+            query.into_message(),
+            UdpTransportContext::new(None).into(),
+            (),
+        );
+
+        let svc =
+            MandatoryMiddlewareSvc::new(service_fn(answer_with_opt, ()));
+        let mut stream = svc.call(request).await;
+        let call_result: CallResult<Vec<u8>> =
+            stream.next().await.unwrap().unwrap();
+        let (response, _feedback) = call_result.into_inner();
+        let response = response.unwrap();
+        let response = response.as_message();
+
+        assert!(response.opt().is_none());
+        assert!(!response.header().tc());
+        assert_eq!(response.header_counts().ancount(), 1);
+        assert_eq!(response.header_counts().arcount(), 1);
+    }
+
+    #[tokio::test]
+    async fn question_is_echoed_in_responses() {
+        // A short-circuit FORMERR response from the middleware itself.
+        let mut query = MessageBuilder::new_vec().question();
+        query.push((Name::<Bytes>::root(), Rtype::A)).unwrap();
+        query.push((Name::<Bytes>::root(), Rtype::AAAA)).unwrap();
+        let response = call_with_question(
+            query.into_message(),
+            |req: Request<Vec<u8>>, _meta: ()| -> ServiceResult<Vec<u8>> {
+                let builder = mk_builder_for_target();
+                Ok(CallResult::new(
+                    builder
+                        .start_answer(req.message(), Rcode::NOERROR)?
+                        .additional(),
+                ))
+            },
+        )
+        .await;
+        assert_eq!(response.header().rcode(), Rcode::FORMERR);
+        assert_eq!(response.header_counts().qdcount(), 2);
+
+        // A FORMERR response built by a service without a question.
+        let mut query = MessageBuilder::new_vec().question();
+        query.push((Name::<Bytes>::root(), Rtype::A)).unwrap();
+        let response = call_with_question(
+            query.into_message(),
+            |_req: Request<Vec<u8>>, _meta: ()| -> ServiceResult<Vec<u8>> {
+                let mut builder = mk_builder_for_target();
+                builder.header_mut().set_rcode(Rcode::FORMERR);
+                Ok(CallResult::new(builder.additional()))
+            },
+        )
+        .await;
+        assert_eq!(response.header().rcode(), Rcode::FORMERR);
+        assert_eq!(response.header_counts().qdcount(), 1);
+        let question = response.sole_question().unwrap();
+        assert_eq!(question.qname(), &Name::<Bytes>::root());
+        assert_eq!(question.qtype(), Rtype::A);
+    }
+
+    #[tokio::test]
+    async fn missing_opt_is_added_for_edns_request() {
+        let mut query = MessageBuilder::new_vec().question();
+        query.push((Name::<Bytes>::root(), Rtype::A)).unwrap();
+        let mut query = query.additional();
+        query
+            .opt(|builder| {
+                builder.set_udp_payload_size(4096);
+                builder.set_dnssec_ok(true);
+                Ok(())
+            })
+            .unwrap();
+        let response = call_with_question(
+            query.into_message(),
+            |req: Request<Vec<u8>>, _meta: ()| -> ServiceResult<Vec<u8>> {
+                let builder = mk_builder_for_target();
+                Ok(CallResult::new(
+                    builder
+                        .start_answer(req.message(), Rcode::NOERROR)?
+                        .additional(),
+                ))
+            },
+        )
+        .await;
+
+        let opt = response.opt().unwrap();
+        assert_eq!(opt.udp_payload_size(), 4096);
+        assert_eq!(opt.version(), 0);
+        assert!(!opt.dnssec_ok());
+        assert_eq!(response.header_counts().arcount(), 1);
+    }
+
     //------------ Helper functions ------------------------------------------
 
+    async fn call_with_question(
+        query: Message<Vec<u8>>,
+        service: fn(Request<Vec<u8>>, ()) -> ServiceResult<Vec<u8>>,
+    ) -> Message<Vec<u8>> {
+        let request = Request::new(
+            "127.0.0.1:12345".parse().unwrap(),
+            Instant::now(),
+            query,
+            UdpTransportContext::new(None).into(),
+            (),
+        );
+        let svc = MandatoryMiddlewareSvc::new(service_fn(service, ()));
+        let mut stream = svc.call(request).await;
+        let call_result: CallResult<Vec<u8>> =
+            stream.next().await.unwrap().unwrap();
+        let (response, _feedback) = call_result.into_inner();
+        Message::from_octets(response.unwrap().as_slice().to_vec()).unwrap()
+    }
+
     // Returns Some(n) if truncation occurred where n is the size after
     // truncation.
     async fn process(max_response_size_hint: Option<u16>) -> Option<usize> {
@@ -0,0 +1,454 @@
+//! Minimal responses.
+//!
+//! The authority and additional sections of a positive answer are optional:
+//! they may contain the NS records of the zone and the addresses of the
+//! name servers or of the targets of MX, SRV and similar records, which can
+//! save the client further queries, but the answer is complete without them.
+//!
+//! Leaving them out, as done by the `minimal-responses` option of other
+//! name servers, makes responses smaller, reducing the risk of truncation
+//! and thus of retries over TCP, as well as the potential for amplification.
+//!
+//! The [`MinimalResponsesMiddlewareSvc`] either always strips these sections
+//! from positive answers or, in its [`minimal_when_large()`] mode, only when
+//! a response comes close to the size limit of the transport.
+//!
+//! [`minimal_when_large()`]: MinimalResponsesMiddlewareSvc::minimal_when_large
+use core::future::{ready, Ready};
+use core::marker::PhantomData;
+
+use octseq::Octets;
+use tracing::{trace, warn};
+
+use crate::base::iana::Rcode;
+use crate::base::message_builder::{AdditionalBuilder, PushError};
+use crate::base::wire::{Composer, ParseError};
+use crate::base::{ParsedName, StreamTarget};
+use crate::net::server::message::{Request, TransportSpecificContext};
+use crate::net::server::service::{Service, ServiceResult};
+use crate::net::server::util::mk_builder_for_target;
+use crate::rdata::AllRecordData;
+
+use super::mandatory::MINIMUM_RESPONSE_BYTE_LEN;
+use super::stream::PostprocessingStream;
+
+//----------- Constants -------------------------------------------------------
+
+/// The size limit of responses sent over connection-oriented transports.
+const MAX_STREAM_RESPONSE_BYTE_LEN: u16 = u16::MAX;
+
+//----------- MinimalResponsesMiddlewareSvc -----------------------------------
+
+/// A middleware service for removing optional records from responses.
+///
+/// The authority and additional sections of positive answers, i.e. NOERROR
+/// responses with a non-empty answer section, are removed except for the
+/// OPT record. Negative answers and referrals are left untouched as their
+/// authority records and glue are required.
+///
+/// By default this is done for every positive answer. Use
+/// [`minimal_when_large()`] to only do so for responses that would
+/// otherwise come close to the size limit of the transport.
+///
+/// As signatures such as TSIG must cover the final response this service
+/// must be placed closer to the application service than the middleware
+/// adding them.
+///
+/// [`minimal_when_large()`]: Self::minimal_when_large
+#[derive(Clone, Debug)]
+pub struct MinimalResponsesMiddlewareSvc<RequestOctets, NextSvc, RequestMeta>
+{
+    /// The upstream [`Service`] to pass requests to and receive responses
+    /// from.
+    next_svc: NextSvc,
+
+    /// The percentage of the size limit above which responses are made
+    /// minimal.
+    ///
+    /// If `None` all positive answers are made minimal.
+    when_large: Option<u8>,
+
+    _phantom: PhantomData<(RequestOctets, RequestMeta)>,
+}
+
+impl<RequestOctets, NextSvc, RequestMeta>
+    MinimalResponsesMiddlewareSvc<RequestOctets, NextSvc, RequestMeta>
+{
+    /// Creates an instance of this middleware service.
+    ///
+    /// The service will make all positive answers minimal.
+    #[must_use]
+    pub fn new(next_svc: NextSvc) -> Self {
+        Self {
+            next_svc,
+            when_large: None,
+            _phantom: PhantomData,
+        }
+    }
+
+    /// Only makes responses minimal if they are large.
+    ///
+    /// A response is considered large if it exceeds the given percentage of
+    /// the effective size limit of the transport: for UDP the maximum
+    /// response size hinted by the server or, lacking one, 512 bytes, less
+    /// any bytes reserved by middleware, and for other transports 65,535
+    /// bytes. Percentages above 100 are capped.
+    ///
+    /// Large responses are only made minimal if this actually reduces their
+    /// size, so small helpful additional records are kept.
+    #[must_use]
+    pub fn minimal_when_large(mut self, percent: u8) -> Self {
+        self.when_large = Some(percent.min(100));
+        self
+    }
+}
+
+impl<RequestOctets, NextSvc, RequestMeta>
+    MinimalResponsesMiddlewareSvc<RequestOctets, NextSvc, RequestMeta>
+where
+    RequestOctets: Octets + Send + Sync + Unpin,
+    NextSvc: Service<RequestOctets, RequestMeta>,
+    NextSvc::Target: Composer + Default,
+    RequestMeta: Clone + Default,
+{
+    fn postprocess(
+        request: &Request<RequestOctets, RequestMeta>,
+        response: &mut AdditionalBuilder<StreamTarget<NextSvc::Target>>,
+        when_large: Option<u8>,
+    ) {
+        let msg = response.as_message();
+        let header = msg.header();
+        let counts = msg.header_counts();
+        if header.rcode() != Rcode::NOERROR
+            || counts.ancount() == 0
+            || (counts.nscount() == 0 && counts.arcount() <= 1)
+            || msg.is_xfr()
+        {
+            return;
+        }
+
+        let len = msg.as_slice().len();
+        if let Some(percent) = when_large {
+            let limit = usize::from(Self::size_limit(request));
+            if len * 100 <= limit * usize::from(percent) {
+                return;
+            }
+        }
+
+        // Estimate the size of the minimal response before rebuilding it,
+        // i.e. everything up to the end of the answer section plus the OPT
+        // record.
+        let Ok(authority) = msg.authority() else {
+            return;
+        };
+        let opt_len = msg.opt().map(|opt| 11 + opt.opt().len()).unwrap_or(0);
+        let estimated_len = authority.pos() + opt_len;
+        if estimated_len >= len {
+            return;
+        }
+
+        match Self::mk_minimal_response(response) {
+            Ok(minimal) if minimal.as_slice().len() < len => {
+                trace!(
+                    "Made response minimal, reducing it from {len} to {} bytes",
+                    minimal.as_slice().len()
+                );
+                *response = minimal;
+            }
+            Ok(_) => {}
+            Err(err) => {
+                warn!("Unable to create minimal response: {err}");
+            }
+        }
+    }
+
+    /// Returns the effective size limit for the response to the request.
+    fn size_limit(request: &Request<RequestOctets, RequestMeta>) -> u16 {
+        match request.transport_ctx() {
+            TransportSpecificContext::Udp(ctx) => ctx
+                .max_response_size_hint()
+                .unwrap_or(MINIMUM_RESPONSE_BYTE_LEN)
+                .saturating_sub(request.num_reserved_bytes()),
+            TransportSpecificContext::NonUdp(_) => {
+                MAX_STREAM_RESPONSE_BYTE_LEN
+            }
+        }
+    }
+
+    /// Creates a copy of the response with only the header, question and
+    /// answer sections and the OPT record.
+    fn mk_minimal_response(
+        response: &AdditionalBuilder<StreamTarget<NextSvc::Target>>,
+    ) -> Result<AdditionalBuilder<StreamTarget<NextSvc::Target>>, MinimalError>
+    {
+        let source = response.as_message();
+        let mut target = mk_builder_for_target();
+        *target.header_mut() = source.header();
+
+        let mut target = target.question();
+        for question in source.question() {
+            target.push(question?)?;
+        }
+
+        let mut target = target.answer();
+        for record in source
+            .answer()?
+            .into_records::<AllRecordData<_, ParsedName<_>>>()
+        {
+            target.push(record?)?;
+        }
+
+        let mut target = target.additional();
+        if let Some(opt) = source.opt() {
+            target.push(opt.as_record())?;
+        }
+
+        Ok(target)
+    }
+
+    fn map_stream_item(
+        request: Request<RequestOctets, RequestMeta>,
+        mut stream_item: ServiceResult<NextSvc::Target>,
+        when_large: &mut Option<u8>,
+    ) -> ServiceResult<NextSvc::Target> {
+        if let Ok(cr) = &mut stream_item {
+            if let Some(response) = cr.response_mut() {
+                Self::postprocess(&request, response, *when_large);
+            }
+        }
+        stream_item
+    }
+}
+
+//--- Service
+
+impl<RequestOctets, NextSvc, RequestMeta> Service<RequestOctets, RequestMeta>
+    for MinimalResponsesMiddlewareSvc<RequestOctets, NextSvc, RequestMeta>
+where
+    RequestOctets: Octets + Send + Sync + 'static + Unpin,
+    NextSvc: Service<RequestOctets, RequestMeta>,
+    NextSvc::Future: Unpin,
+    NextSvc::Target: Composer + Default,
+    RequestMeta: Clone + Default + Unpin,
+{
+    type Target = NextSvc::Target;
+    type Stream = PostprocessingStream<
+        RequestOctets,
+        NextSvc::Future,
+        NextSvc::Stream,
+        RequestMeta,
+        Option<u8>,
+    >;
+    type Future = Ready<Self::Stream>;
+
+    fn call(
+        &self,
+        request: Request<RequestOctets, RequestMeta>,
+    ) -> Self::Future {
+        let svc_call_fut = self.next_svc.call(request.clone());
+        ready(PostprocessingStream::new(
+            svc_call_fut,
+            request,
+            self.when_large,
+            Self::map_stream_item,
+        ))
+    }
+}
+
+//----------- MinimalError ----------------------------------------------------
+
+/// A minimal response could not be created.
+#[derive(Clone, Copy, Debug)]
+enum MinimalError {
+    ParseError(ParseError),
+    PushError(PushError),
+}
+
+impl core::fmt::Display for MinimalError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            MinimalError::ParseError(err) => write!(f, "{err:?}"),
+            MinimalError::PushError(err) => write!(f, "{err}"),
+        }
+    }
+}
+
+impl From<ParseError> for MinimalError {
+    fn from(err: ParseError) -> Self {
+        Self::ParseError(err)
+    }
+}
+
+impl From<PushError> for MinimalError {
+    fn from(err: PushError) -> Self {
+        Self::PushError(err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::str::FromStr;
+
+    use std::vec::Vec;
+
+    use bytes::Bytes;
+    use futures_util::StreamExt;
+
+    use crate::base::iana::Rcode;
+    use crate::base::{Message, MessageBuilder, Name, Rtype};
+    use crate::net::server::message::{
+        NonUdpTransportContext, Request, TransportSpecificContext,
+        UdpTransportContext,
+    };
+    use crate::net::server::service::{CallResult, Service, ServiceResult};
+    use crate::net::server::util::{
+        mk_builder_for_target, service_fn, ServiceFn,
+    };
+    use crate::rdata::{Ns, A};
+
+    use super::MinimalResponsesMiddlewareSvc;
+
+    //------------ Tests -----------------------------------------------------
+
+    #[tokio::test]
+    async fn always_minimal() {
+        let svc = MinimalResponsesMiddlewareSvc::new(mk_service());
+
+        let response = process(&svc, "www.example.com", udp(None)).await;
+        assert_eq!(counts(&response), (1, 0, 1));
+        assert!(response.opt().is_some());
+        assert_eq!(response.header().id(), 1234);
+        assert!(response.header().aa());
+    }
+
+    #[tokio::test]
+    async fn small_response_is_kept_intact() {
+        let svc = MinimalResponsesMiddlewareSvc::new(mk_service())
+            .minimal_when_large(80);
+
+        let response = process(&svc, "www.example.com", udp(None)).await;
+        assert_eq!(counts(&response), (1, 2, 3));
+    }
+
+    #[tokio::test]
+    async fn large_response_is_stripped() {
+        let svc = MinimalResponsesMiddlewareSvc::new(mk_service())
+            .minimal_when_large(80);
+
+        // The full response is 175 bytes, more than 80% of 200.
+        let response = process(&svc, "www.example.com", udp(Some(200))).await;
+        assert_eq!(counts(&response), (1, 0, 1));
+
+        // Over TCP the same response is small.
+        let response = process(
+            &svc,
+            "www.example.com",
+            NonUdpTransportContext::new(None).into(),
+        )
+        .await;
+        assert_eq!(counts(&response), (1, 2, 3));
+    }
+
+    #[tokio::test]
+    async fn negative_answer_is_kept_intact() {
+        let svc = MinimalResponsesMiddlewareSvc::new(mk_service());
+
+        let response = process(&svc, "nx.example.com", udp(None)).await;
+        assert_eq!(response.header().rcode(), Rcode::NXDOMAIN);
+        assert_eq!(counts(&response), (0, 2, 3));
+    }
+
+    //------------ Helper functions ------------------------------------------
+
+    type TestSvc = ServiceFn<
+        Vec<u8>,
+        fn(Request<Vec<u8>>, ()) -> ServiceResult<Vec<u8>>,
+        (),
+    >;
+
+    /// A service answering with an A record, the NS records of the zone
+    /// and their addresses.
+    fn mk_service() -> TestSvc {
+        fn my_service(
+            req: Request<Vec<u8>>,
+            _meta: (),
+        ) -> ServiceResult<Vec<u8>> {
+            let name = |s| Name::<Bytes>::from_str(s).unwrap();
+            let apex = name("example.com");
+            let nx = req
+                .message()
+                .sole_question()
+                .unwrap()
+                .qname()
+                .first()
+                .as_slice()
+                == b"nx";
+            let rcode = if nx { Rcode::NXDOMAIN } else { Rcode::NOERROR };
+
+            let builder = mk_builder_for_target();
+            let mut answer =
+                builder.start_answer(req.message(), rcode).unwrap();
+            answer.header_mut().set_aa(true);
+            if !nx {
+                answer
+                    .push((
+                        name("www.example.com"),
+                        3600,
+                        A::from_octets(192, 0, 2, 1),
+                    ))
+                    .unwrap();
+            }
+            let mut authority = answer.authority();
+            for ns in ["ns1.example.com", "ns2.example.com"] {
+                authority.push((&apex, 3600, Ns::new(name(ns)))).unwrap();
+            }
+            let mut additional = authority.additional();
+            for (ns, addr) in
+                [("ns1.example.com", 53), ("ns2.example.com", 54)]
+            {
+                additional
+                    .push((name(ns), 3600, A::from_octets(192, 0, 2, addr)))
+                    .unwrap();
+            }
+            additional.opt(|_| Ok(())).unwrap();
+            Ok(CallResult::new(additional))
+        }
+
+        service_fn(my_service as fn(_, _) -> _, ())
+    }
+
+    fn udp(max_response_size: Option<u16>) -> TransportSpecificContext {
+        UdpTransportContext::new(max_response_size).into()
+    }
+
+    fn counts(response: &Message<Vec<u8>>) -> (u16, u16, u16) {
+        let counts = response.header_counts();
+        (counts.ancount(), counts.nscount(), counts.arcount())
+    }
+
+    async fn process(
+        svc: &MinimalResponsesMiddlewareSvc<Vec<u8>, TestSvc, ()>,
+        qname: &str,
+        ctx: TransportSpecificContext,
+    ) -> Message<Vec<u8>> {
+        let mut query = MessageBuilder::new_vec();
+        query.header_mut().set_id(1234);
+        let mut query = query.question();
+        query
+            .push((Name::<Bytes>::from_str(qname).unwrap(), Rtype::A))
+            .unwrap();
+        let mut query = query.additional();
+        query.opt(|_| Ok(())).unwrap();
+        let request = Request::for_test(
+            query.into_message(),
+            ctx,
+            "127.0.0.1:12345".parse().unwrap(),
+        );
+
+        let mut stream = svc.call(request).await;
+        let call_result: CallResult<Vec<u8>> =
+            stream.next().await.unwrap().unwrap();
+        let response = call_result.into_inner().0.unwrap().finish();
+        Message::from_octets(response.as_dgram_slice().to_vec()).unwrap()
+    }
+}
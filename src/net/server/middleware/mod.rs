@@ -20,6 +20,24 @@
 //! middleware is able to handle and respond to the request entirely on its
 //! own.
 //!
+//! Middleware can also drop a request silently, so that nothing at all is
+//! sent to the client, by responding with an empty response stream or with
+//! a [`CallResult`][crate::net::server::service::CallResult] that has no
+//! response. Pre-processing that decides between passing a request on,
+//! answering it or dropping it can be expressed as a
+//! [`ControlFlow`][core::ops::ControlFlow] whose `Break` value is an
+//! optional response, with `Break(None)` mapping to an empty stream, as done
+//! by the [`RpzMiddlewareSvc`][rpz::RpzMiddlewareSvc].
+//!
+//! # Processing order
+//!
+//! Because each layer invokes the layer above it itself, pre-processing and
+//! post-processing are always symmetric. Requests are pre-processed from the
+//! outermost layer inwards and responses are post-processed in the reverse
+//! order. A layer that answers a request without passing it on prevents all
+//! layers above it, and the application service, from seeing the request,
+//! while its response is still post-processed by every layer beneath it.
+//!
 //! # Middleware layering strategies
 //!
 //! The simplest strategy for using middleware is to use a single layered
@@ -36,6 +54,102 @@
 //! server instances listening on separate ports or interfaces, each with
 //! their own differing middleware "chains".
 //!
+//! # Per-transport middleware
+//!
+//! Each server is given its own [`Service`] and so its own middleware
+//! stack. As each server handles a single transport, giving the
+//! [`DgramServer`] and the [`StreamServer`] differently layered stacks atop
+//! clones of the same application service lets policies that only matter
+//! for one transport, e.g. response rate limiting for UDP, be applied
+//! without the middleware having to check the transport of each request:
+//!
+//! ```no_run
+//! use std::sync::Arc;
+//!
+//! use tokio::net::{TcpListener, UdpSocket};
+//!
+//! use domain::net::server::buf::VecBufSource;
+//! use domain::net::server::dgram::DgramServer;
+//! use domain::net::server::message::Request;
+//! use domain::net::server::middleware::edns::EdnsMiddlewareSvc;
+//! use domain::net::server::middleware::mandatory::MandatoryMiddlewareSvc;
+//! use domain::net::server::middleware::rrl::RrlMiddlewareSvc;
+//! use domain::net::server::service::ServiceResult;
+//! use domain::net::server::stream::StreamServer;
+//! use domain::net::server::util::service_fn;
+//!
+//! fn my_service(msg: Request<Vec<u8>>, _meta: ()) -> ServiceResult<Vec<u8>>
+//! {
+//!     todo!()
+//! }
+//!
+//! #[tokio::main]
+//! async fn main() {
+//!     // The application service shared by both transports.
+//!     let svc = service_fn(my_service, ());
+//!
+//!     // UDP: rate limit responses to mitigate amplification attacks.
+//!     let udp_svc = MandatoryMiddlewareSvc::new(EdnsMiddlewareSvc::new(
+//!         RrlMiddlewareSvc::new(svc.clone()),
+//!     ));
+//!
+//!     // TCP: no rate limiting needed.
+//!     let tcp_svc = MandatoryMiddlewareSvc::new(EdnsMiddlewareSvc::new(svc));
+//!
+//!     let udpsocket = UdpSocket::bind("127.0.0.1:8053").await.unwrap();
+//!     let udp_srv = Arc::new(DgramServer::new(
+//!         udpsocket,
+//!         VecBufSource::default(),
+//!         udp_svc,
+//!     ));
+//!
+//!     let listener = TcpListener::bind("127.0.0.1:8053").await.unwrap();
+//!     let tcp_srv = Arc::new(StreamServer::new(
+//!         listener,
+//!         VecBufSource::default(),
+//!         tcp_svc,
+//!     ));
+//!
+//!     let spawned_srv = udp_srv.clone();
+//!     tokio::spawn(async move { spawned_srv.run().await });
+//!     let spawned_srv = tcp_srv.clone();
+//!     tokio::spawn(async move { spawned_srv.run().await });
+//! }
+//! ```
+//!
+//! Within a stack the ordering is the same for every transport: requests
+//! are pre-processed by the outermost layer first and responses are
+//! post-processed by the outermost layer last. The stacks of different
+//! servers are independent of each other, so middleware state, e.g. the
+//! rate limiting state of a [`RrlMiddlewareSvc`][rrl::RrlMiddlewareSvc], is
+//! only shared if the same middleware instance is cloned into both stacks.
+//! Likewise any state of the application service itself is only shared if
+//! the service keeps it behind an [`Arc`][std::sync::Arc], as it is cloned
+//! rather than moved into each stack.
+//!
+//! Whatever else differs, every stack should have a
+//! [`MandatoryMiddlewareSvc`][mandatory::MandatoryMiddlewareSvc] as its
+//! outermost layer, so that the checks and fixups required of all responses
+//! by the DNS RFCs, e.g. truncation of too large UDP responses, are applied
+//! after all other post-processing, on every transport.
+//!
+//! # State across restarts
+//!
+//! Some middleware keeps state that is lost when the server restarts. Most
+//! of it is deliberately ephemeral: the buckets of the
+//! [`RrlMiddlewareSvc`][rrl::RrlMiddlewareSvc] and the counters of the
+//! [`NxdomainLimitMiddlewareSvc`][nxdomain_limit::NxdomainLimitMiddlewareSvc]
+//! describe recent traffic only, are rebuilt within seconds and would be
+//! stale by the time a restarted server loaded them.
+//!
+//! The server secrets of the
+//! [`CookiesMiddlewareSvc`][cookies::CookiesMiddlewareSvc] however are used
+//! to check server cookies that clients keep for up to an hour. Persist them
+//! via [`CookiesMiddlewareSvc::state`][cookies::CookiesMiddlewareSvc::state]
+//! and restore them via
+//! [`CookiesMiddlewareSvc::restore_state`][cookies::CookiesMiddlewareSvc::restore_state]
+//! so that a restart doesn't answer those clients with BADCOOKIE.
+//!
 //! # Middleware-to-middleware communication
 //!
 //! If needed middleware services can pass service specific data to upstream
@@ -45,13 +159,36 @@
 //!
 //! Currently the following middleware are available:
 //!
+//! [`DgramServer`]: crate::net::server::dgram::DgramServer
 //! [`Service`]: crate::net::server::service::Service
+//! [`StreamServer`]: crate::net::server::stream::StreamServer
+pub mod acl;
+pub mod blocklist;
+pub mod case0x20;
+#[cfg(feature = "chaos")]
+pub mod chaos;
 #[cfg(feature = "siphasher")]
 pub mod cookies;
+pub mod diagnostics;
+pub mod dnssec_audit;
 pub mod edns;
+pub mod key_tag;
 pub mod mandatory;
+pub mod minimal;
 pub mod notify;
+pub mod nsid;
+pub mod nxdomain_limit;
+pub mod padding;
+pub mod query_log;
+pub mod rate_limit;
+pub mod report_channel;
+pub mod rpz;
+pub mod rrl;
+pub mod server_id;
+pub mod special_use;
 pub mod stream;
+#[cfg(test)]
+mod test_helpers;
 #[cfg(feature = "tsig")]
 pub mod tsig;
 #[cfg(feature = "unstable-xfr")]
@@ -0,0 +1,239 @@
+//! Name Server Identifier (NSID) support.
+//!
+//! [RFC 5001] allows a client to ask which particular server answered its
+//! query by including an empty NSID option in the query. This helps when
+//! debugging a setup where multiple servers share a common address, e.g.
+//! behind a load balancer or anycast.
+//!
+//! The [`NsidMiddlewareSvc`] answers such requests by adding an NSID option
+//! with a configured identifier to the response.
+//!
+//! [RFC 5001]: https://www.rfc-editor.org/rfc/rfc5001.html
+use core::future::{ready, Ready};
+use core::marker::PhantomData;
+
+use bytes::Bytes;
+use octseq::Octets;
+use tracing::{trace, warn};
+
+use crate::base::message_builder::AdditionalBuilder;
+use crate::base::opt::Nsid;
+use crate::base::wire::Composer;
+use crate::base::StreamTarget;
+use crate::net::server::message::Request;
+use crate::net::server::service::{Service, ServiceResult};
+use crate::net::server::util::add_edns_options;
+
+use super::stream::PostprocessingStream;
+
+//------------ NsidMiddlewareSvc ---------------------------------------------
+
+/// A middleware service for identifying the server in responses.
+///
+/// If a request has an OPT record containing an NSID option, an NSID option
+/// with the identifier given at construction is added to the OPT record of
+/// the response, creating the OPT record if needed. Responses that already
+/// contain an NSID option, e.g. because the inner service added one, and
+/// responses to requests without an NSID option are left untouched.
+#[derive(Clone, Debug)]
+pub struct NsidMiddlewareSvc<RequestOctets, NextSvc, RequestMeta> {
+    /// The upstream [`Service`] to pass requests to and receive responses
+    /// from.
+    next_svc: NextSvc,
+
+    /// The identifier of this server.
+    nsid: Nsid<Bytes>,
+
+    _phantom: PhantomData<(RequestOctets, RequestMeta)>,
+}
+
+impl<RequestOctets, NextSvc, RequestMeta>
+    NsidMiddlewareSvc<RequestOctets, NextSvc, RequestMeta>
+{
+    /// Creates an instance of this middleware service.
+    ///
+    /// The given identifier is included in responses to requests asking
+    /// for it. RFC 5001 leaves its content up to the operator.
+    #[must_use]
+    pub fn new(next_svc: NextSvc, nsid: Nsid<Bytes>) -> Self {
+        Self {
+            next_svc,
+            nsid,
+            _phantom: PhantomData,
+        }
+    }
+}
+
+impl<RequestOctets, NextSvc, RequestMeta>
+    NsidMiddlewareSvc<RequestOctets, NextSvc, RequestMeta>
+where
+    RequestOctets: Octets + Send + Sync + Unpin,
+    NextSvc: Service<RequestOctets, RequestMeta>,
+    NextSvc::Target: Composer + Default,
+    RequestMeta: Clone + Default,
+{
+    fn postprocess(
+        request: &Request<RequestOctets, RequestMeta>,
+        response: &mut AdditionalBuilder<StreamTarget<NextSvc::Target>>,
+        nsid: &Nsid<Bytes>,
+    ) {
+        // https://www.rfc-editor.org/rfc/rfc5001.html#section-2.1
+        //   "A name server that understands the NSID option and chooses to
+        //    honor a particular NSID request responds by including
+        //    identifying information in a NSID option in an OPT
+        //    pseudo-RR in the response message."
+        let requested = request
+            .message()
+            .opt()
+            .map_or(false, |opt| opt.opt().nsid().is_some());
+        if !requested {
+            return;
+        }
+
+        let present = response
+            .as_message()
+            .opt()
+            .map_or(false, |opt| opt.opt().nsid().is_some());
+        if present {
+            return;
+        }
+
+        trace!("Adding NSID option to response");
+        if let Err(err) =
+            add_edns_options(response, |builder| builder.push(nsid))
+        {
+            warn!("Failed to add NSID option to response: {err}");
+        }
+    }
+
+    fn map_stream_item(
+        request: Request<RequestOctets, RequestMeta>,
+        mut stream_item: ServiceResult<NextSvc::Target>,
+        nsid: &mut Nsid<Bytes>,
+    ) -> ServiceResult<NextSvc::Target> {
+        if let Ok(cr) = &mut stream_item {
+            if let Some(response) = cr.response_mut() {
+                Self::postprocess(&request, response, nsid);
+            }
+        }
+        stream_item
+    }
+}
+
+//--- Service
+
+impl<RequestOctets, NextSvc, RequestMeta> Service<RequestOctets, RequestMeta>
+    for NsidMiddlewareSvc<RequestOctets, NextSvc, RequestMeta>
+where
+    RequestOctets: Octets + Send + Sync + 'static + Unpin,
+    NextSvc: Service<RequestOctets, RequestMeta>,
+    NextSvc::Future: Unpin,
+    NextSvc::Target: Composer + Default,
+    RequestMeta: Clone + Default + Unpin,
+{
+    type Target = NextSvc::Target;
+    type Stream = PostprocessingStream<
+        RequestOctets,
+        NextSvc::Future,
+        NextSvc::Stream,
+        RequestMeta,
+        Nsid<Bytes>,
+    >;
+    type Future = Ready<Self::Stream>;
+
+    fn call(
+        &self,
+        request: Request<RequestOctets, RequestMeta>,
+    ) -> Self::Future {
+        let svc_call_fut = self.next_svc.call(request.clone());
+        ready(PostprocessingStream::new(
+            svc_call_fut,
+            request,
+            self.nsid.clone(),
+            Self::map_stream_item,
+        ))
+    }
+}
+
+//============ Tests =========================================================
+
+#[cfg(test)]
+mod tests {
+    use std::vec::Vec;
+
+    use bytes::Bytes;
+    use futures_util::StreamExt;
+
+    use crate::base::iana::Rcode;
+    use crate::base::opt::Nsid;
+    use crate::base::{Message, MessageBuilder, Name, Rtype};
+    use crate::net::server::message::{Request, UdpTransportContext};
+    use crate::net::server::service::{CallResult, Service, ServiceResult};
+    use crate::net::server::util::{mk_builder_for_target, service_fn};
+
+    use super::NsidMiddlewareSvc;
+
+    //------------ Tests -----------------------------------------------------
+
+    #[tokio::test]
+    async fn nsid_is_added_when_requested() {
+        let response = process(true).await;
+        let opt = response.opt().unwrap();
+        assert_eq!(opt.opt().nsid().unwrap().as_slice(), b"ns1.example");
+        assert_eq!(response.header_counts().arcount(), 1);
+    }
+
+    #[tokio::test]
+    async fn nsid_is_not_added_when_not_requested() {
+        let response = process(false).await;
+        let opt = response.opt().unwrap();
+        assert!(opt.opt().nsid().is_none());
+    }
+
+    //------------ Helper functions ------------------------------------------
+
+    async fn process(request_nsid: bool) -> Message<Vec<u8>> {
+        let mut query = MessageBuilder::new_vec().question();
+        query.push((Name::<Bytes>::root(), Rtype::A)).unwrap();
+        let mut query = query.additional();
+        query
+            .opt(|builder| {
+                if request_nsid {
+                    builder.client_nsid()?;
+                }
+                Ok(())
+            })
+            .unwrap();
+        let request = Request::for_test(
+            query.into_message(),
+            UdpTransportContext::default(),
+            "127.0.0.1:12345".parse().unwrap(),
+        );
+
+        // A service that answers with an OPT record of its own.
+        fn my_service(
+            req: Request<Vec<u8>>,
+            _meta: (),
+        ) -> ServiceResult<Vec<u8>> {
+            let builder = mk_builder_for_target();
+            let answer =
+                builder.start_answer(req.message(), Rcode::NOERROR)?;
+            let mut additional = answer.additional();
+            additional.opt(|builder| {
+                builder.set_udp_payload_size(1232);
+                Ok(())
+            })?;
+            Ok(CallResult::new(additional))
+        }
+
+        let nsid = Nsid::from_octets(Bytes::from_static(b"ns1.example"));
+        let svc =
+            NsidMiddlewareSvc::new(service_fn(my_service, ()), nsid.unwrap());
+        let mut stream = svc.call(request).await;
+        let call_result: CallResult<Vec<u8>> =
+            stream.next().await.unwrap().unwrap();
+        let (response, _feedback) = call_result.into_inner();
+        let response = response.unwrap().finish();
+        Message::from_octets(response.as_dgram_slice().to_vec()).unwrap()
+    }
+}
@@ -0,0 +1,708 @@
+//! Limiting the rate of NXDOMAIN responses per client network.
+//!
+//! So called "water torture" or random subdomain attacks send queries for
+//! large numbers of random, non-existent names below a zone, with the aim of
+//! exhausting the resources of the authoritative servers of that zone and of
+//! the resolvers in between. Unlike the answers of legitimate clients, the
+//! vast majority of the responses to such queries are NXDOMAIN.
+//!
+//! The [`NxdomainLimitMiddlewareSvc`] counts the NXDOMAIN responses sent to
+//! each client network and stops answering UDP queries from networks that
+//! exceed a configurable rate. Limited queries are not passed to the next
+//! service at all, they are either dropped or answered with an empty
+//! truncated response which legitimate clients can follow by retrying over
+//! TCP. The [`NxdomainLimitMetrics`] tell clients that merely receive the
+//! occasional NXDOMAIN apart from those that are being limited.
+use core::future::{ready, Ready};
+use core::marker::PhantomData;
+use core::ops::ControlFlow;
+use core::time::Duration;
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::vec::Vec;
+
+use futures_util::stream::{iter, Iter, Stream};
+use octseq::Octets;
+use tokio::time::Instant;
+use tracing::{debug, info, trace, warn};
+
+use crate::base::iana::Rcode;
+use crate::base::message_builder::AdditionalBuilder;
+use crate::base::wire::Composer;
+use crate::base::StreamTarget;
+use crate::net::server::message::{Request, TransportSpecificContext};
+use crate::net::server::service::{CallResult, Service, ServiceResult};
+use crate::net::server::util::{
+    client_prefix, mk_builder_for_target, IpPrefix,
+};
+
+use super::stream::{MiddlewareStream, PostprocessingStream};
+
+//----------- Constants -------------------------------------------------------
+
+/// The default number of NXDOMAIN responses per window before a client
+/// network is limited.
+const DEFAULT_THRESHOLD: u32 = 1000;
+
+/// The default window over which NXDOMAIN responses are counted.
+const DEFAULT_WINDOW: Duration = Duration::from_secs(10);
+
+/// The default prefix length used to group IPv4 clients into networks.
+const DEFAULT_IPV4_PREFIX_LEN: u8 = 24;
+
+/// The default prefix length used to group IPv6 clients into networks.
+const DEFAULT_IPV6_PREFIX_LEN: u8 = 56;
+
+/// The default maximum number of client networks tracked.
+const DEFAULT_MAX_ENTRIES: usize = 100_000;
+
+//----------- NxdomainLimitAction ---------------------------------------------
+
+/// What to do with UDP queries from a limited client network.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum NxdomainLimitAction {
+    /// Answer with an empty response with the TC flag set.
+    ///
+    /// Legitimate clients will retry over TCP, which isn't limited.
+    #[default]
+    Truncate,
+
+    /// Don't respond at all.
+    Drop,
+}
+
+//----------- NxdomainLimitConfig ---------------------------------------------
+
+/// Configuration for NXDOMAIN rate limiting.
+#[derive(Clone, Copy, Debug)]
+struct NxdomainLimitConfig {
+    /// The number of NXDOMAIN responses per window before a client network
+    /// is limited.
+    threshold: u32,
+
+    /// The window over which NXDOMAIN responses are counted.
+    window: Duration,
+
+    /// What to do with queries from limited client networks.
+    action: NxdomainLimitAction,
+
+    /// The prefix length used to group IPv4 clients into networks.
+    ipv4_prefix_len: u8,
+
+    /// The prefix length used to group IPv6 clients into networks.
+    ipv6_prefix_len: u8,
+
+    /// The maximum number of client networks tracked.
+    max_entries: usize,
+}
+
+impl Default for NxdomainLimitConfig {
+    fn default() -> Self {
+        Self {
+            threshold: DEFAULT_THRESHOLD,
+            window: DEFAULT_WINDOW,
+            action: NxdomainLimitAction::default(),
+            ipv4_prefix_len: DEFAULT_IPV4_PREFIX_LEN,
+            ipv6_prefix_len: DEFAULT_IPV6_PREFIX_LEN,
+            max_entries: DEFAULT_MAX_ENTRIES,
+        }
+    }
+}
+
+//----------- NxdomainLimitMetrics --------------------------------------------
+
+/// Counts of NXDOMAIN responses and of the queries limited because of them.
+#[derive(Debug, Default)]
+pub struct NxdomainLimitMetrics {
+    /// The number of NXDOMAIN responses seen.
+    num_nxdomain: AtomicUsize,
+
+    /// The number of queries dropped.
+    num_dropped: AtomicUsize,
+
+    /// The number of queries answered with a truncated response.
+    num_truncated: AtomicUsize,
+
+    /// The number of client networks currently tracked.
+    num_tracked_clients: AtomicUsize,
+
+    /// The number of client networks currently limited.
+    num_limited_clients: AtomicUsize,
+}
+
+impl NxdomainLimitMetrics {
+    /// The number of NXDOMAIN responses seen.
+    pub fn num_nxdomain(&self) -> usize {
+        self.num_nxdomain.load(Ordering::Relaxed)
+    }
+
+    /// The number of queries dropped.
+    pub fn num_dropped(&self) -> usize {
+        self.num_dropped.load(Ordering::Relaxed)
+    }
+
+    /// The number of queries answered with a truncated response.
+    pub fn num_truncated(&self) -> usize {
+        self.num_truncated.load(Ordering::Relaxed)
+    }
+
+    /// The total number of queries from limited client networks.
+    pub fn num_limited(&self) -> usize {
+        self.num_dropped() + self.num_truncated()
+    }
+
+    /// The number of client networks that recently received NXDOMAIN
+    /// responses.
+    ///
+    /// This includes the networks counted by
+    /// [`num_limited_clients`][Self::num_limited_clients].
+    pub fn num_tracked_clients(&self) -> usize {
+        self.num_tracked_clients.load(Ordering::Relaxed)
+    }
+
+    /// The number of client networks currently limited.
+    pub fn num_limited_clients(&self) -> usize {
+        self.num_limited_clients.load(Ordering::Relaxed)
+    }
+}
+
+//----------- NxdomainLimitMiddlewareSvc --------------------------------------
+
+/// A middleware service for limiting the rate of NXDOMAIN responses.
+///
+/// Clients are grouped into networks by truncating their address to a
+/// configurable prefix length. Every NXDOMAIN response sent to a network
+/// increments its counter, which decays continuously by the [threshold]
+/// every [window]. Once the counter reaches the threshold the network is
+/// limited, which is logged, and UDP queries from it are answered according
+/// to the configured [action] without consulting the next service. The
+/// network is no longer limited once its counter has decayed below the
+/// threshold again.
+///
+/// Queries received via TCP are never limited, but their NXDOMAIN responses
+/// are counted.
+///
+/// [threshold]: Self::with_threshold
+/// [window]: Self::with_window
+/// [action]: Self::with_action
+#[derive(Clone, Debug)]
+pub struct NxdomainLimitMiddlewareSvc<RequestOctets, NextSvc, RequestMeta> {
+    /// The upstream [`Service`] to pass requests to and receive responses
+    /// from.
+    next_svc: NextSvc,
+
+    /// The limiting state.
+    ///
+    /// Shared between clones of this service.
+    state: Arc<NxdomainLimitState>,
+
+    _phantom: PhantomData<(RequestOctets, RequestMeta)>,
+}
+
+impl<RequestOctets, NextSvc, RequestMeta>
+    NxdomainLimitMiddlewareSvc<RequestOctets, NextSvc, RequestMeta>
+{
+    /// Creates an instance of this middleware service.
+    ///
+    /// By default client networks are limited after 1000 NXDOMAIN responses
+    /// in 10 seconds, queries from limited networks are answered with a
+    /// truncated response and clients are grouped into IPv4 /24 and IPv6
+    /// /56 networks.
+    #[must_use]
+    pub fn new(next_svc: NextSvc) -> Self {
+        Self {
+            next_svc,
+            state: Default::default(),
+            _phantom: PhantomData,
+        }
+    }
+
+    /// Sets the number of NXDOMAIN responses per window before a client
+    /// network is limited.
+    ///
+    /// A threshold of zero disables limiting.
+    #[must_use]
+    pub fn with_threshold(mut self, threshold: u32) -> Self {
+        self.update_config(|config| config.threshold = threshold);
+        self
+    }
+
+    /// Sets the window over which NXDOMAIN responses are counted.
+    ///
+    /// A zero window disables limiting.
+    #[must_use]
+    pub fn with_window(mut self, window: Duration) -> Self {
+        self.update_config(|config| config.window = window);
+        self
+    }
+
+    /// Sets what to do with UDP queries from limited client networks.
+    #[must_use]
+    pub fn with_action(mut self, action: NxdomainLimitAction) -> Self {
+        self.update_config(|config| config.action = action);
+        self
+    }
+
+    /// Sets the prefix lengths used to group clients into networks.
+    ///
+    /// Prefix lengths larger than the address length are capped.
+    #[must_use]
+    pub fn with_prefix_lens(mut self, ipv4: u8, ipv6: u8) -> Self {
+        self.update_config(|config| {
+            config.ipv4_prefix_len = ipv4.min(32);
+            config.ipv6_prefix_len = ipv6.min(128);
+        });
+        self
+    }
+
+    /// Sets the maximum number of client networks tracked.
+    ///
+    /// NXDOMAIN responses to new networks are not counted while the
+    /// maximum number of networks is tracked and none of them are idle.
+    #[must_use]
+    pub fn with_max_entries(mut self, max_entries: usize) -> Self {
+        self.update_config(|config| config.max_entries = max_entries);
+        self
+    }
+
+    /// Counts of NXDOMAIN responses and limited queries.
+    pub fn metrics(&self) -> Arc<NxdomainLimitMetrics> {
+        self.state.metrics.clone()
+    }
+
+    /// Returns the client networks currently limited.
+    pub fn limited_clients(&self) -> Vec<IpPrefix> {
+        let clients = self.state.clients.lock().unwrap();
+        clients
+            .iter()
+            .filter(|(_, counter)| counter.limited)
+            .map(|(prefix, _)| *prefix)
+            .collect()
+    }
+
+    /// Updates the configuration, resetting all counters.
+    fn update_config(&mut self, op: impl FnOnce(&mut NxdomainLimitConfig)) {
+        let mut config = self.state.config;
+        op(&mut config);
+        let metrics = self.state.metrics.clone();
+        metrics.num_tracked_clients.store(0, Ordering::Relaxed);
+        metrics.num_limited_clients.store(0, Ordering::Relaxed);
+        self.state = Arc::new(NxdomainLimitState {
+            config,
+            clients: Default::default(),
+            metrics,
+        });
+    }
+}
+
+impl<RequestOctets, NextSvc, RequestMeta>
+    NxdomainLimitMiddlewareSvc<RequestOctets, NextSvc, RequestMeta>
+where
+    RequestOctets: Octets + Send + Sync + Unpin,
+    NextSvc: Service<RequestOctets, RequestMeta>,
+    NextSvc::Target: Composer + Default,
+    RequestMeta: Clone + Default,
+{
+    fn preprocess(
+        &self,
+        request: &Request<RequestOctets, RequestMeta>,
+    ) -> ControlFlow<Option<AdditionalBuilder<StreamTarget<NextSvc::Target>>>>
+    {
+        if !matches!(
+            request.transport_ctx(),
+            TransportSpecificContext::Udp(_)
+        ) {
+            return ControlFlow::Continue(());
+        }
+
+        let prefix = self.state.prefix(request);
+        if !self.state.is_limited(prefix) {
+            return ControlFlow::Continue(());
+        }
+
+        match self.state.config.action {
+            NxdomainLimitAction::Truncate => {
+                trace!("Truncating query from limited network {prefix}");
+                self.state
+                    .metrics
+                    .num_truncated
+                    .fetch_add(1, Ordering::Relaxed);
+                match mk_builder_for_target()
+                    .start_answer(request.message(), Rcode::NOERROR)
+                {
+                    Ok(mut answer) => {
+                        answer.header_mut().set_tc(true);
+                        ControlFlow::Break(Some(answer.additional()))
+                    }
+                    Err(err) => {
+                        warn!("Unable to create truncated response: {err}");
+                        ControlFlow::Break(None)
+                    }
+                }
+            }
+            NxdomainLimitAction::Drop => {
+                trace!("Dropping query from limited network {prefix}");
+                self.state
+                    .metrics
+                    .num_dropped
+                    .fetch_add(1, Ordering::Relaxed);
+                ControlFlow::Break(None)
+            }
+        }
+    }
+
+    fn map_stream_item(
+        request: Request<RequestOctets, RequestMeta>,
+        stream_item: ServiceResult<NextSvc::Target>,
+        state: &mut Arc<NxdomainLimitState>,
+    ) -> ServiceResult<NextSvc::Target> {
+        if let Ok(cr) = &stream_item {
+            if let Some(response) = cr.response() {
+                if response.header().rcode() == Rcode::NXDOMAIN {
+                    state.record_nxdomain(state.prefix(&request));
+                }
+            }
+        }
+        stream_item
+    }
+}
+
+//--- Service
+
+impl<RequestOctets, NextSvc, RequestMeta> Service<RequestOctets, RequestMeta>
+    for NxdomainLimitMiddlewareSvc<RequestOctets, NextSvc, RequestMeta>
+where
+    RequestOctets: Octets + Send + Sync + 'static + Unpin,
+    NextSvc: Service<RequestOctets, RequestMeta>,
+    NextSvc::Future: Unpin,
+    NextSvc::Target: Composer + Default,
+    RequestMeta: Clone + Default + Unpin,
+{
+    type Target = NextSvc::Target;
+    type Stream = MiddlewareStream<
+        NextSvc::Future,
+        NextSvc::Stream,
+        PostprocessingStream<
+            RequestOctets,
+            NextSvc::Future,
+            NextSvc::Stream,
+            RequestMeta,
+            Arc<NxdomainLimitState>,
+        >,
+        Iter<std::option::IntoIter<<NextSvc::Stream as Stream>::Item>>,
+        <NextSvc::Stream as Stream>::Item,
+    >;
+    type Future = Ready<Self::Stream>;
+
+    fn call(
+        &self,
+        request: Request<RequestOctets, RequestMeta>,
+    ) -> Self::Future {
+        match self.preprocess(&request) {
+            ControlFlow::Continue(()) => {
+                let svc_call_fut = self.next_svc.call(request.clone());
+                let map = PostprocessingStream::new(
+                    svc_call_fut,
+                    request,
+                    self.state.clone(),
+                    Self::map_stream_item,
+                );
+                ready(MiddlewareStream::Map(map))
+            }
+            ControlFlow::Break(response) => {
+                let item =
+                    response.map(|response| Ok(CallResult::new(response)));
+                ready(MiddlewareStream::Result(iter(item)))
+            }
+        }
+    }
+}
+
+//----------- NxdomainLimitState ----------------------------------------------
+
+/// The state of NXDOMAIN rate limiting.
+///
+/// Shared by all clones of an [`NxdomainLimitMiddlewareSvc`] and the
+/// response streams they produce.
+#[derive(Debug, Default)]
+pub struct NxdomainLimitState {
+    /// The configuration.
+    config: NxdomainLimitConfig,
+
+    /// The counters of the tracked client networks.
+    clients: Mutex<HashMap<IpPrefix, Counter>>,
+
+    /// Counts of NXDOMAIN responses and limited queries.
+    metrics: Arc<NxdomainLimitMetrics>,
+}
+
+impl NxdomainLimitState {
+    /// Returns the network of the client of the given request.
+    fn prefix<RequestOctets: Octets + Send + Sync, RequestMeta>(
+        &self,
+        request: &Request<RequestOctets, RequestMeta>,
+    ) -> IpPrefix {
+        client_prefix(
+            &request.client_addr(),
+            self.config.ipv4_prefix_len,
+            self.config.ipv6_prefix_len,
+        )
+    }
+
+    /// Returns the rate at which counters decay per second.
+    ///
+    /// Returns `None` if limiting is disabled.
+    fn decay_rate(&self) -> Option<f64> {
+        let window = self.config.window.as_secs_f64();
+        if self.config.threshold == 0 || window == 0.0 {
+            return None;
+        }
+        Some(f64::from(self.config.threshold) / window)
+    }
+
+    /// Returns whether queries from the given network are limited.
+    fn is_limited(&self, prefix: IpPrefix) -> bool {
+        let Some(rate) = self.decay_rate() else {
+            return false;
+        };
+        let now = Instant::now();
+
+        let mut clients = self.clients.lock().unwrap();
+        let Some(counter) = clients.get_mut(&prefix) else {
+            return false;
+        };
+        counter.decay(now, rate);
+        if counter.limited && counter.count < f64::from(self.config.threshold)
+        {
+            info!("Client network {prefix} is no longer limited");
+            counter.limited = false;
+            self.metrics
+                .num_limited_clients
+                .fetch_sub(1, Ordering::Relaxed);
+        }
+        counter.limited
+    }
+
+    /// Counts an NXDOMAIN response sent to the given network.
+    fn record_nxdomain(&self, prefix: IpPrefix) {
+        self.metrics.num_nxdomain.fetch_add(1, Ordering::Relaxed);
+        let Some(rate) = self.decay_rate() else {
+            return;
+        };
+        let now = Instant::now();
+
+        let mut clients = self.clients.lock().unwrap();
+        if clients.len() >= self.config.max_entries
+            && !clients.contains_key(&prefix)
+        {
+            // Forget networks whose counters have decayed completely.
+            clients.retain(|_, counter| {
+                counter.decay(now, rate);
+                if counter.count > 0.0 {
+                    return true;
+                }
+                if counter.limited {
+                    self.metrics
+                        .num_limited_clients
+                        .fetch_sub(1, Ordering::Relaxed);
+                }
+                false
+            });
+            self.metrics
+                .num_tracked_clients
+                .store(clients.len(), Ordering::Relaxed);
+            if clients.len() >= self.config.max_entries {
+                debug!("Too many client networks, not counting NXDOMAIN");
+                return;
+            }
+        }
+
+        let counter = clients.entry(prefix).or_insert_with(|| {
+            self.metrics
+                .num_tracked_clients
+                .fetch_add(1, Ordering::Relaxed);
+            Counter {
+                count: 0.0,
+                updated: now,
+                limited: false,
+            }
+        });
+        counter.decay(now, rate);
+        counter.count += 1.0;
+        if !counter.limited
+            && counter.count >= f64::from(self.config.threshold)
+        {
+            warn!(
+                "Client network {prefix} exceeded {} NXDOMAIN responses in \
+                 {:?}, limiting",
+                self.config.threshold, self.config.window
+            );
+            counter.limited = true;
+            self.metrics
+                .num_limited_clients
+                .fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+//----------- Counter ---------------------------------------------------------
+
+/// The decaying NXDOMAIN counter of a client network.
+#[derive(Clone, Debug)]
+struct Counter {
+    /// The number of recent NXDOMAIN responses, after decay.
+    count: f64,
+
+    /// When the counter was last updated.
+    updated: Instant,
+
+    /// Whether queries from the network are currently limited.
+    limited: bool,
+}
+
+impl Counter {
+    /// Decays the counter up to `now` at `rate` per second.
+    fn decay(&mut self, now: Instant, rate: f64) {
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.count = (self.count - elapsed * rate).max(0.0);
+        self.updated = now;
+    }
+}
+
+//============ Tests ==========================================================
+
+#[cfg(test)]
+mod tests {
+    use core::time::Duration;
+
+    use std::string::ToString;
+    use std::vec::Vec;
+
+    use crate::base::iana::Rcode;
+    use crate::net::server::message::NonUdpTransportContext;
+
+    use super::super::test_helpers::{
+        nxdomain_service, try_process_from_ip, try_process_with_ctx,
+        NxdomainService,
+    };
+    use super::{NxdomainLimitAction, NxdomainLimitMiddlewareSvc};
+
+    //------------ Tests -----------------------------------------------------
+
+    #[tokio::test(start_paused = true)]
+    async fn networks_exceeding_threshold_are_truncated() {
+        let svc = mk_svc().with_threshold(3);
+
+        for _ in 0..3 {
+            let msg =
+                try_process_from_ip(&svc, "nx.example.com", "192.0.2.1")
+                    .await
+                    .unwrap();
+            assert_eq!(msg.header().rcode(), Rcode::NXDOMAIN);
+        }
+        assert_eq!(svc.metrics().num_limited_clients(), 1);
+
+        // Even existing names are no longer answered for the whole network.
+        let msg = try_process_from_ip(&svc, "www.example.com", "192.0.2.200")
+            .await
+            .unwrap();
+        assert!(msg.header().tc());
+        assert_eq!(msg.header().rcode(), Rcode::NOERROR);
+        assert_eq!(msg.header_counts().ancount(), 0);
+
+        // Other networks are unaffected.
+        let msg =
+            try_process_from_ip(&svc, "www.example.com", "198.51.100.1")
+                .await
+                .unwrap();
+        assert!(!msg.header().tc());
+        assert_eq!(msg.header_counts().ancount(), 1);
+
+        let metrics = svc.metrics();
+        assert_eq!(metrics.num_nxdomain(), 3);
+        assert_eq!(metrics.num_truncated(), 1);
+        assert_eq!(metrics.num_dropped(), 0);
+        assert_eq!(metrics.num_tracked_clients(), 1);
+        assert_eq!(
+            svc.limited_clients()
+                .iter()
+                .map(|prefix| prefix.to_string())
+                .collect::<Vec<_>>(),
+            ["192.0.2.0/24"]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn networks_below_threshold_are_not_limited() {
+        let svc = mk_svc().with_threshold(3);
+
+        for _ in 0..10 {
+            let msg =
+                try_process_from_ip(&svc, "nx.example.com", "192.0.2.1")
+                    .await
+                    .unwrap();
+            assert_eq!(msg.header().rcode(), Rcode::NXDOMAIN);
+            tokio::time::advance(Duration::from_secs(5)).await;
+        }
+        assert_eq!(svc.metrics().num_nxdomain(), 10);
+        assert_eq!(svc.metrics().num_tracked_clients(), 1);
+        assert_eq!(svc.metrics().num_limited_clients(), 0);
+        assert_eq!(svc.metrics().num_limited(), 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn limits_are_lifted_after_decay() {
+        let svc = mk_svc()
+            .with_threshold(2)
+            .with_action(NxdomainLimitAction::Drop);
+
+        for _ in 0..2 {
+            assert!(try_process_from_ip(
+                &svc,
+                "nx.example.com",
+                "2001:db8::1"
+            )
+            .await
+            .is_some());
+        }
+        assert!(try_process_from_ip(&svc, "nx.example.com", "2001:db8::2")
+            .await
+            .is_none());
+        assert_eq!(svc.metrics().num_dropped(), 1);
+
+        tokio::time::advance(Duration::from_secs(5)).await;
+        assert!(try_process_from_ip(&svc, "www.example.com", "2001:db8::1")
+            .await
+            .is_some());
+        assert_eq!(svc.metrics().num_limited_clients(), 0);
+        assert!(svc.limited_clients().is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn tcp_queries_are_not_limited() {
+        let svc = mk_svc().with_threshold(1);
+
+        for _ in 0..3 {
+            let msg = try_process_with_ctx(
+                &svc,
+                "nx.example.com",
+                "192.0.2.1",
+                NonUdpTransportContext::new(None).into(),
+            )
+            .await
+            .unwrap();
+            assert_eq!(msg.header().rcode(), Rcode::NXDOMAIN);
+        }
+        assert_eq!(svc.metrics().num_limited_clients(), 1);
+        assert_eq!(svc.metrics().num_limited(), 0);
+    }
+
+    //------------ Helper functions ------------------------------------------
+
+    fn mk_svc() -> NxdomainLimitMiddlewareSvc<Vec<u8>, NxdomainService, ()> {
+        NxdomainLimitMiddlewareSvc::new(nxdomain_service())
+            .with_window(Duration::from_secs(10))
+    }
+}
@@ -0,0 +1,441 @@
+//! EDNS(0) padding of responses.
+//!
+//! Encryption of DNS messages, e.g. via DNS over TLS or DNS over HTTPS,
+//! hides their content but not their length, which can still reveal much
+//! about the names being queried. [RFC 7830] defines the EDNS(0) Padding
+//! option which allows to increase the length of a message with padding
+//! octets, and [RFC 8467] recommends padding responses to a multiple of 468
+//! octets.
+//!
+//! The [`PaddingMiddlewareSvc`] pads responses in this way.
+//!
+//! [RFC 7830]: https://www.rfc-editor.org/rfc/rfc7830.html
+//! [RFC 8467]: https://www.rfc-editor.org/rfc/rfc8467.html
+use core::future::{ready, Ready};
+use core::marker::PhantomData;
+
+use octseq::Octets;
+use tracing::{trace, warn};
+
+use crate::base::message_builder::AdditionalBuilder;
+use crate::base::opt::Padding;
+use crate::base::wire::Composer;
+use crate::base::StreamTarget;
+use crate::net::server::message::{Request, TransportSpecificContext};
+use crate::net::server::service::{Service, ServiceResult};
+use crate::net::server::util::add_edns_options;
+
+use super::mandatory::MINIMUM_RESPONSE_BYTE_LEN;
+use super::stream::PostprocessingStream;
+
+//----------- Constants -------------------------------------------------------
+
+/// The default block size responses are padded to.
+///
+/// This is the block size recommended for responses by [RFC 8467 section
+/// 4.1](https://www.rfc-editor.org/rfc/rfc8467.html#section-4.1).
+pub const DEFAULT_BLOCK_SIZE: u16 = 468;
+
+/// The length of an OPT record without any options.
+const EMPTY_OPT_LEN: usize = 11;
+
+/// The length of the code and length fields of an EDNS option.
+const OPTION_HEADER_LEN: usize = 4;
+
+//------------ PaddingMiddlewareSvc ------------------------------------------
+
+/// A middleware service for padding responses to a multiple of a block size.
+///
+/// A Padding option is added to the OPT record of responses to requests that
+/// include a Padding option themselves, such that the length of the
+/// response becomes a multiple of the [block size]. Optionally responses to
+/// all requests with an OPT record can be [padded].
+///
+/// Responses to requests without an OPT record are never padded as they
+/// must not contain an OPT record. Responses that already contain a Padding
+/// option are left untouched. Responses to UDP requests are not padded
+/// beyond the maximum response size for the request, and responses already
+/// exceeding it aren't padded at all.
+///
+/// As padding must cover the whole message, this service should be placed
+/// beneath any middleware adding to the response after it, e.g. the
+/// [`TsigMiddlewareSvc`], whose records would otherwise not be accounted
+/// for.
+///
+/// [block size]: Self::with_block_size
+/// [padded]: Self::always_pad
+/// [`TsigMiddlewareSvc`]: super::tsig::TsigMiddlewareSvc
+#[derive(Clone, Debug)]
+pub struct PaddingMiddlewareSvc<RequestOctets, NextSvc, RequestMeta> {
+    /// The upstream [`Service`] to pass requests to and receive responses
+    /// from.
+    next_svc: NextSvc,
+
+    /// The padding configuration.
+    config: PaddingConfig,
+
+    _phantom: PhantomData<(RequestOctets, RequestMeta)>,
+}
+
+impl<RequestOctets, NextSvc, RequestMeta>
+    PaddingMiddlewareSvc<RequestOctets, NextSvc, RequestMeta>
+{
+    /// Creates an instance of this middleware service.
+    ///
+    /// By default responses are padded to a multiple of
+    /// [`DEFAULT_BLOCK_SIZE`] octets if the request asked for padding.
+    #[must_use]
+    pub fn new(next_svc: NextSvc) -> Self {
+        Self {
+            next_svc,
+            config: PaddingConfig {
+                block_size: DEFAULT_BLOCK_SIZE,
+                always: false,
+            },
+            _phantom: PhantomData,
+        }
+    }
+
+    /// Sets the block size responses are padded to a multiple of.
+    ///
+    /// A block size of zero or one disables padding.
+    #[must_use]
+    pub fn with_block_size(mut self, block_size: u16) -> Self {
+        self.config.block_size = block_size;
+        self
+    }
+
+    /// Pad responses to all requests with an OPT record.
+    ///
+    /// This includes requests without a Padding option.
+    #[must_use]
+    pub fn always_pad(mut self, enabled: bool) -> Self {
+        self.config.always = enabled;
+        self
+    }
+}
+
+impl<RequestOctets, NextSvc, RequestMeta>
+    PaddingMiddlewareSvc<RequestOctets, NextSvc, RequestMeta>
+where
+    RequestOctets: Octets + Send + Sync + Unpin,
+    NextSvc: Service<RequestOctets, RequestMeta>,
+    NextSvc::Target: Composer + Default,
+    RequestMeta: Clone + Default,
+{
+    fn postprocess(
+        request: &Request<RequestOctets, RequestMeta>,
+        response: &mut AdditionalBuilder<StreamTarget<NextSvc::Target>>,
+        config: PaddingConfig,
+    ) {
+        let Some(request_opt) = request.message().opt() else {
+            return;
+        };
+
+        // https://www.rfc-editor.org/rfc/rfc7830.html#section-4
+        //   "Responders MUST pad DNS responses when the respective DNS query
+        //    included the 'Padding' option, unless doing so would violate
+        //    the maximum UDP payload size."
+        let requested = request_opt.opt().first::<Padding<_>>().is_some();
+        if !(requested || config.always) || config.block_size <= 1 {
+            return;
+        }
+
+        let (has_opt, has_padding) =
+            response.as_message().opt().map_or((false, false), |opt| {
+                (true, opt.opt().first::<Padding<_>>().is_some())
+            });
+        if has_padding {
+            return;
+        }
+
+        let max_len = match request.transport_ctx() {
+            TransportSpecificContext::Udp(ctx) => ctx
+                .max_response_size_hint()
+                .unwrap_or(MINIMUM_RESPONSE_BYTE_LEN),
+            TransportSpecificContext::NonUdp(_) => u16::MAX,
+        };
+        let max_len = usize::from(max_len)
+            .saturating_sub(request.num_reserved_bytes().into());
+
+        let mut unpadded_len = response.as_slice().len() + OPTION_HEADER_LEN;
+        if !has_opt {
+            unpadded_len += EMPTY_OPT_LEN;
+        }
+        let Some(padding_len) =
+            padding_len(unpadded_len, config.block_size.into(), max_len)
+        else {
+            trace!("Not padding response as it is too large");
+            return;
+        };
+
+        trace!("Padding response with {padding_len} octets");
+        if let Err(err) =
+            add_edns_options(response, |builder| builder.padding(padding_len))
+        {
+            warn!("Failed to add Padding option to response: {err}");
+        }
+    }
+
+    fn map_stream_item(
+        request: Request<RequestOctets, RequestMeta>,
+        mut stream_item: ServiceResult<NextSvc::Target>,
+        config: &mut PaddingConfig,
+    ) -> ServiceResult<NextSvc::Target> {
+        if let Ok(cr) = &mut stream_item {
+            if let Some(response) = cr.response_mut() {
+                Self::postprocess(&request, response, *config);
+            }
+        }
+        stream_item
+    }
+}
+
+//--- Service
+
+impl<RequestOctets, NextSvc, RequestMeta> Service<RequestOctets, RequestMeta>
+    for PaddingMiddlewareSvc<RequestOctets, NextSvc, RequestMeta>
+where
+    RequestOctets: Octets + Send + Sync + 'static + Unpin,
+    NextSvc: Service<RequestOctets, RequestMeta>,
+    NextSvc::Future: Unpin,
+    NextSvc::Target: Composer + Default,
+    RequestMeta: Clone + Default + Unpin,
+{
+    type Target = NextSvc::Target;
+    type Stream = PostprocessingStream<
+        RequestOctets,
+        NextSvc::Future,
+        NextSvc::Stream,
+        RequestMeta,
+        PaddingConfig,
+    >;
+    type Future = Ready<Self::Stream>;
+
+    fn call(
+        &self,
+        request: Request<RequestOctets, RequestMeta>,
+    ) -> Self::Future {
+        let svc_call_fut = self.next_svc.call(request.clone());
+        ready(PostprocessingStream::new(
+            svc_call_fut,
+            request,
+            self.config,
+            Self::map_stream_item,
+        ))
+    }
+}
+
+//------------ PaddingConfig -------------------------------------------------
+
+/// The configuration of a [`PaddingMiddlewareSvc`].
+#[derive(Clone, Copy, Debug)]
+pub struct PaddingConfig {
+    /// The block size responses are padded to a multiple of.
+    block_size: u16,
+
+    /// Pad responses even if the request didn't ask for it?
+    always: bool,
+}
+
+//------------ Helper functions ----------------------------------------------
+
+/// Returns the number of padding octets needed.
+///
+/// Pads a message of `len` octets, which already includes the header of the
+/// Padding option, to the next multiple of `block_size`, but not beyond
+/// `max_len`. Returns `None` if even an empty Padding option doesn't fit.
+fn padding_len(len: usize, block_size: usize, max_len: usize) -> Option<u16> {
+    if len > max_len {
+        return None;
+    }
+    let padded_len = len.div_euclid(block_size) * block_size;
+    let padded_len = if padded_len < len {
+        padded_len + block_size
+    } else {
+        padded_len
+    };
+    let padded_len = padded_len.min(max_len);
+    u16::try_from(padded_len - len).ok()
+}
+
+//============ Tests =========================================================
+
+#[cfg(test)]
+mod tests {
+    use std::vec::Vec;
+
+    use bytes::Bytes;
+    use futures_util::StreamExt;
+
+    use crate::base::iana::{ExtendedErrorCode, Rcode};
+    use crate::base::opt::{ExtendedError, Padding};
+    use crate::base::{Message, MessageBuilder, Name, Rtype};
+    use crate::net::server::message::{Request, UdpTransportContext};
+    use crate::net::server::service::{CallResult, Service, ServiceResult};
+    use crate::net::server::util::{mk_builder_for_target, service_fn};
+    use crate::rdata::A;
+
+    use super::{padding_len, PaddingMiddlewareSvc, DEFAULT_BLOCK_SIZE};
+
+    //------------ Tests -----------------------------------------------------
+
+    #[test]
+    fn padding_len_is_capped() {
+        assert_eq!(padding_len(100, 468, 65535), Some(368));
+        assert_eq!(padding_len(468, 468, 65535), Some(0));
+        assert_eq!(padding_len(100, 468, 300), Some(200));
+        assert_eq!(padding_len(301, 468, 300), None);
+    }
+
+    #[tokio::test]
+    async fn padded_response_is_multiple_of_block_size() {
+        for (svc_opt, num_answers) in [(false, 1), (true, 1), (true, 30)] {
+            let response = process(
+                DEFAULT_BLOCK_SIZE,
+                false,
+                true,
+                svc_opt,
+                num_answers,
+                false,
+            )
+            .await;
+            let len = response.as_slice().len();
+            assert_eq!(len % usize::from(DEFAULT_BLOCK_SIZE), 0, "{len}");
+            assert!(has_padding(&response));
+            assert_eq!(response.header_counts().ancount(), num_answers);
+        }
+
+        let response = process(128, false, true, true, 1, false).await;
+        assert_eq!(response.as_slice().len(), 128);
+    }
+
+    #[tokio::test]
+    async fn only_requested_padding_by_default() {
+        let response =
+            process(DEFAULT_BLOCK_SIZE, false, false, true, 1, false).await;
+        assert!(!has_padding(&response));
+
+        let response =
+            process(DEFAULT_BLOCK_SIZE, true, false, true, 1, false).await;
+        assert!(has_padding(&response));
+        assert_eq!(
+            response.as_slice().len() % usize::from(DEFAULT_BLOCK_SIZE),
+            0
+        );
+    }
+
+    #[tokio::test]
+    async fn extended_error_is_added_before_padding() {
+        for svc_opt in [false, true] {
+            let response =
+                process(DEFAULT_BLOCK_SIZE, false, true, svc_opt, 1, true)
+                    .await;
+            let len = response.as_slice().len();
+            assert_eq!(len % usize::from(DEFAULT_BLOCK_SIZE), 0, "{len}");
+            assert!(has_padding(&response));
+            let opt = response.opt().unwrap();
+            let ede = opt.opt().first::<ExtendedError<_>>().unwrap();
+            assert_eq!(ede.code(), ExtendedErrorCode::STALE_ANSWER);
+        }
+    }
+
+    //------------ Helper functions ------------------------------------------
+
+    fn has_padding(response: &Message<Vec<u8>>) -> bool {
+        response
+            .opt()
+            .map_or(false, |opt| opt.opt().first::<Padding<_>>().is_some())
+    }
+
+    async fn process(
+        block_size: u16,
+        always: bool,
+        request_padding: bool,
+        svc_opt: bool,
+        num_answers: u16,
+        ede: bool,
+    ) -> Message<Vec<u8>> {
+        let mut query = MessageBuilder::new_vec().question();
+        query.push((Name::<Bytes>::root(), Rtype::A)).unwrap();
+        let mut query = query.additional();
+        query
+            .opt(|builder| {
+                if request_padding {
+                    builder.padding(0)?;
+                }
+                Ok(())
+            })
+            .unwrap();
+        let request = Request::for_test(
+            query.into_message(),
+            UdpTransportContext::new(Some(4096)),
+            "127.0.0.1:12345".parse().unwrap(),
+        );
+
+        fn with_opt(
+            req: Request<Vec<u8>>,
+            (num_answers, ede): (u16, bool),
+        ) -> ServiceResult<Vec<u8>> {
+            let builder = mk_builder_for_target();
+            let mut answer =
+                builder.start_answer(req.message(), Rcode::NOERROR)?;
+            for i in 0..num_answers {
+                answer.push((
+                    Name::root_ref(),
+                    60,
+                    A::from_octets(192, 0, 2, i as u8),
+                ))?;
+            }
+            let mut additional = answer.additional();
+            additional.opt(|builder| {
+                builder.set_udp_payload_size(1232);
+                Ok(())
+            })?;
+            Ok(with_ede(CallResult::new(additional), ede))
+        }
+
+        fn without_opt(
+            req: Request<Vec<u8>>,
+            (num_answers, ede): (u16, bool),
+        ) -> ServiceResult<Vec<u8>> {
+            let builder = mk_builder_for_target();
+            let mut answer =
+                builder.start_answer(req.message(), Rcode::NOERROR)?;
+            for i in 0..num_answers {
+                answer.push((
+                    Name::root_ref(),
+                    60,
+                    A::from_octets(192, 0, 2, i as u8),
+                ))?;
+            }
+            Ok(with_ede(CallResult::new(answer.additional()), ede))
+        }
+
+        fn with_ede(
+            call_result: CallResult<Vec<u8>>,
+            ede: bool,
+        ) -> CallResult<Vec<u8>> {
+            if ede {
+                call_result.with_ede(ExtendedErrorCode::STALE_ANSWER, "")
+            } else {
+                call_result
+            }
+        }
+
+        let svc = if svc_opt {
+            service_fn(with_opt as fn(_, _) -> _, (num_answers, ede))
+        } else {
+            service_fn(without_opt as fn(_, _) -> _, (num_answers, ede))
+        };
+        let svc = PaddingMiddlewareSvc::new(svc)
+            .with_block_size(block_size)
+            .always_pad(always);
+        let mut stream = svc.call(request).await;
+        let call_result: CallResult<Vec<u8>> =
+            stream.next().await.unwrap().unwrap();
+        let (response, _feedback) = call_result.into_inner();
+        let response = response.unwrap().finish();
+        Message::from_octets(response.as_dgram_slice().to_vec()).unwrap()
+    }
+}
@@ -0,0 +1,427 @@
+//! Logging of requests and their responses.
+//!
+//! The [`QueryLogMiddlewareSvc`] emits one structured [`tracing`] event per
+//! request, describing the question asked, the response given and how long
+//! it took to produce it. The events use the target `domain::query_log` so
+//! that they can be enabled, filtered or routed independently of the rest of
+//! the logging output.
+//!
+//! Each event has the following fields:
+//!
+//! - `client`: the IP address and port of the client.
+//! - `qname`, `qtype` and `qclass`: the first question of the request.
+//! - `rcode`: the (extended) response code of the response.
+//! - `ancount`: the number of records in the answer section.
+//! - `elapsed_us`: the time in microseconds between the request being
+//!   received and its response being produced.
+//!
+//! Fields that are not known, e.g. the question of a request without one,
+//! are logged as `-`. If the service doesn't produce any response to a
+//! request, e.g. because it decided to drop the request, the event says so
+//! and all response fields are unknown.
+use core::fmt;
+use core::future::{ready, Ready};
+use core::marker::PhantomData;
+use core::pin::Pin;
+use core::task::{Context, Poll};
+
+use futures_util::future::FlattenStream;
+use futures_util::stream::Stream;
+use futures_util::{ready, FutureExt, StreamExt};
+use octseq::Octets;
+use tracing::{event, Level};
+
+use crate::base::wire::Composer;
+use crate::net::server::message::Request;
+use crate::net::server::service::{Service, ServiceResult};
+
+/// The target of the events emitted by [`QueryLogMiddlewareSvc`].
+pub const LOG_TARGET: &str = "domain::query_log";
+
+//------------ QueryLogMiddlewareSvc -----------------------------------------
+
+/// A middleware service for logging requests and their responses.
+///
+/// An event is emitted at the configured level, [`Level::INFO`] by default,
+/// for the first response to each request. Further responses to the same
+/// request, e.g. the subsequent messages of a zone transfer, are not logged.
+/// If the upstream service produces no response at all, an event stating
+/// that the query was dropped is emitted instead. Responses are passed on
+/// unmodified.
+///
+/// The elapsed time is measured from the moment the request was received by
+/// the server, so place this middleware outermost in the stack to capture
+/// the time spent in all other middleware.
+#[derive(Clone, Debug)]
+pub struct QueryLogMiddlewareSvc<RequestOctets, NextSvc, RequestMeta> {
+    /// The upstream [`Service`] to pass requests to and receive responses
+    /// from.
+    next_svc: NextSvc,
+
+    /// The level at which to emit events.
+    level: Level,
+
+    _phantom: PhantomData<(RequestOctets, RequestMeta)>,
+}
+
+impl<RequestOctets, NextSvc, RequestMeta>
+    QueryLogMiddlewareSvc<RequestOctets, NextSvc, RequestMeta>
+{
+    /// Creates an instance of this middleware service.
+    #[must_use]
+    pub fn new(next_svc: NextSvc) -> Self {
+        Self {
+            next_svc,
+            level: Level::INFO,
+            _phantom: PhantomData,
+        }
+    }
+
+    /// Sets the level at which events are emitted.
+    #[must_use]
+    pub fn with_level(mut self, level: Level) -> Self {
+        self.level = level;
+        self
+    }
+}
+
+impl<RequestOctets, NextSvc, RequestMeta>
+    QueryLogMiddlewareSvc<RequestOctets, NextSvc, RequestMeta>
+where
+    RequestOctets: Octets + Send + Sync + Unpin,
+    NextSvc: Service<RequestOctets, RequestMeta>,
+    NextSvc::Target: Composer + Default,
+    RequestMeta: Clone + Default,
+{
+    /// Emits the event for a request.
+    ///
+    /// The stream item is the first response to the request or `None` if
+    /// the service didn't produce any.
+    fn log(
+        request: &Request<RequestOctets, RequestMeta>,
+        stream_item: Option<&ServiceResult<NextSvc::Target>>,
+        level: Level,
+    ) {
+        let elapsed_us = request.received_at().elapsed().as_micros() as u64;
+        let client = request.client_addr();
+        let question = request.message().first_question();
+        let qname = Field(question.as_ref().map(|q| q.qname()));
+        let qtype = Field(question.as_ref().map(|q| q.qtype()));
+        let qclass = Field(question.as_ref().map(|q| q.qclass()));
+
+        let outcome = match stream_item {
+            Some(_) => "answered",
+            None => "dropped",
+        };
+        let response = stream_item
+            .and_then(|item| item.as_ref().ok())
+            .and_then(|cr| cr.response())
+            .map(|response| response.as_message());
+        let rcode = Field(response.as_ref().map(|msg| msg.opt_rcode()));
+        let ancount =
+            Field(response.as_ref().map(|msg| msg.header_counts().ancount()));
+
+        macro_rules! log_at {
+            ($level:expr) => {
+                event!(
+                    target: LOG_TARGET,
+                    $level,
+                    %client,
+                    %qname,
+                    %qtype,
+                    %qclass,
+                    %rcode,
+                    %ancount,
+                    elapsed_us,
+                    "Query {}",
+                    outcome
+                )
+            };
+        }
+
+        // The level passed to event! must be a constant.
+        if level == Level::ERROR {
+            log_at!(Level::ERROR);
+        } else if level == Level::WARN {
+            log_at!(Level::WARN);
+        } else if level == Level::INFO {
+            log_at!(Level::INFO);
+        } else if level == Level::DEBUG {
+            log_at!(Level::DEBUG);
+        } else {
+            log_at!(Level::TRACE);
+        }
+    }
+}
+
+//--- Service
+
+impl<RequestOctets, NextSvc, RequestMeta> Service<RequestOctets, RequestMeta>
+    for QueryLogMiddlewareSvc<RequestOctets, NextSvc, RequestMeta>
+where
+    RequestOctets: Octets + Send + Sync + 'static + Unpin,
+    NextSvc: Service<RequestOctets, RequestMeta>,
+    NextSvc::Future: Unpin,
+    NextSvc::Target: Composer + Default,
+    RequestMeta: Clone + Default + Unpin,
+{
+    type Target = NextSvc::Target;
+    type Stream = QueryLogStream<RequestOctets, NextSvc, RequestMeta>;
+    type Future = Ready<Self::Stream>;
+
+    fn call(
+        &self,
+        request: Request<RequestOctets, RequestMeta>,
+    ) -> Self::Future {
+        let svc_call_fut = self.next_svc.call(request.clone());
+        ready(QueryLogStream {
+            stream: svc_call_fut.flatten_stream(),
+            request,
+            level: self.level,
+            logged: false,
+        })
+    }
+}
+
+//------------ QueryLogStream ------------------------------------------------
+
+/// The response stream of a [`QueryLogMiddlewareSvc`].
+///
+/// Passes on the responses of the upstream service, logging the first one
+/// or, if the upstream stream ends without any, that there was none.
+pub struct QueryLogStream<RequestOctets, NextSvc, RequestMeta>
+where
+    RequestOctets: Octets + Send + Sync + Unpin,
+    NextSvc: Service<RequestOctets, RequestMeta>,
+    RequestMeta: Clone + Default,
+{
+    /// The response stream of the upstream service.
+    stream: FlattenStream<NextSvc::Future>,
+
+    /// The request the responses are for.
+    request: Request<RequestOctets, RequestMeta>,
+
+    /// The level at which to emit the event.
+    level: Level,
+
+    /// Whether the event for the request has been emitted.
+    logged: bool,
+}
+
+//--- impl Stream
+
+impl<RequestOctets, NextSvc, RequestMeta> Stream
+    for QueryLogStream<RequestOctets, NextSvc, RequestMeta>
+where
+    RequestOctets: Octets + Send + Sync + Unpin,
+    NextSvc: Service<RequestOctets, RequestMeta>,
+    NextSvc::Future: Unpin,
+    NextSvc::Target: Composer + Default,
+    RequestMeta: Clone + Default + Unpin,
+{
+    type Item = ServiceResult<NextSvc::Target>;
+
+    fn poll_next(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Self::Item>> {
+        let stream_item = ready!(self.stream.poll_next_unpin(cx));
+        if !self.logged {
+            self.logged = true;
+            QueryLogMiddlewareSvc::<RequestOctets, NextSvc, RequestMeta>::log(
+                &self.request,
+                stream_item.as_ref(),
+                self.level,
+            );
+        }
+        Poll::Ready(stream_item)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.stream.size_hint()
+    }
+}
+
+//------------ Field ---------------------------------------------------------
+
+/// A log field value that may be unknown.
+struct Field<T>(Option<T>);
+
+impl<T: fmt::Display> fmt::Display for Field<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.0 {
+            Some(value) => value.fmt(f),
+            None => f.write_str("-"),
+        }
+    }
+}
+
+//============ Tests =========================================================
+
+#[cfg(test)]
+mod tests {
+    use core::future::{ready, Ready};
+
+    use std::collections::HashMap;
+    use std::string::{String, ToString};
+    use std::sync::{Arc, Mutex};
+    use std::vec::Vec;
+
+    use futures_util::stream::{self, Empty};
+    use futures_util::StreamExt;
+    use tracing::field::{Field, Visit};
+    use tracing::span::{Attributes, Id, Record};
+    use tracing::{Event, Level, Metadata, Subscriber};
+
+    use crate::base::iana::{Class, Rcode};
+    use crate::base::{MessageBuilder, Name, Rtype};
+    use crate::net::server::message::{Request, UdpTransportContext};
+    use crate::net::server::service::{CallResult, Service, ServiceResult};
+    use crate::net::server::util::{mk_builder_for_target, service_fn};
+    use crate::rdata::A;
+
+    use super::{QueryLogMiddlewareSvc, LOG_TARGET};
+
+    //------------ Tests -----------------------------------------------------
+
+    #[tokio::test]
+    async fn query_is_logged_once_with_fields() {
+        let subscriber = CapturingSubscriber::default();
+        let events = subscriber.events.clone();
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        fn my_service(
+            req: Request<Vec<u8>>,
+            _meta: (),
+        ) -> ServiceResult<Vec<u8>> {
+            let builder = mk_builder_for_target();
+            let mut answer =
+                builder.start_answer(req.message(), Rcode::NXDOMAIN)?;
+            let qname = req.message().sole_question().unwrap().into_qname();
+            answer.push((qname, 60, A::from_octets(192, 0, 2, 1)))?;
+            Ok(CallResult::new(answer.additional()))
+        }
+
+        let svc = QueryLogMiddlewareSvc::new(service_fn(my_service, ()))
+            .with_level(Level::DEBUG);
+        let mut stream = svc.call(mk_request()).await;
+        let _ = stream.next().await.unwrap().unwrap();
+        assert!(stream.next().await.is_none());
+
+        let events = events.lock().unwrap();
+        assert_eq!(events.len(), 1);
+        let (level, fields) = &events[0];
+        assert_eq!(*level, Level::DEBUG);
+        assert_eq!(fields["message"], "Query answered");
+        assert_eq!(fields["client"], "192.0.2.1:12345");
+        assert_eq!(fields["qname"], "example.com");
+        assert_eq!(fields["qtype"], Rtype::A.to_string());
+        assert_eq!(fields["qclass"], Class::IN.to_string());
+        assert_eq!(fields["rcode"], "NXDOMAIN");
+        assert_eq!(fields["ancount"], "1");
+        assert!(fields["elapsed_us"].parse::<u64>().is_ok());
+    }
+
+    #[tokio::test]
+    async fn dropped_query_is_logged() {
+        let subscriber = CapturingSubscriber::default();
+        let events = subscriber.events.clone();
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        struct DroppingService;
+
+        impl Service<Vec<u8>> for DroppingService {
+            type Target = Vec<u8>;
+            type Stream = Empty<ServiceResult<Vec<u8>>>;
+            type Future = Ready<Self::Stream>;
+
+            fn call(&self, _request: Request<Vec<u8>>) -> Self::Future {
+                ready(stream::empty())
+            }
+        }
+
+        let svc = QueryLogMiddlewareSvc::new(DroppingService);
+        let mut stream = svc.call(mk_request()).await;
+        assert!(stream.next().await.is_none());
+
+        let events = events.lock().unwrap();
+        assert_eq!(events.len(), 1);
+        let (level, fields) = &events[0];
+        assert_eq!(*level, Level::INFO);
+        assert_eq!(fields["message"], "Query dropped");
+        assert_eq!(fields["client"], "192.0.2.1:12345");
+        assert_eq!(fields["qname"], "example.com");
+        assert_eq!(fields["rcode"], "-");
+        assert_eq!(fields["ancount"], "-");
+    }
+
+    //------------ Helper functions ------------------------------------------
+
+    /// Returns a UDP request for the A record of example.com.
+    fn mk_request() -> Request<Vec<u8>> {
+        let mut query = MessageBuilder::new_vec().question();
+        query
+            .push((Name::vec_from_str("example.com").unwrap(), Rtype::A))
+            .unwrap();
+        Request::for_test(
+            query.into_message(),
+            UdpTransportContext::default(),
+            "192.0.2.1:12345".parse().unwrap(),
+        )
+    }
+
+    //------------ Helper types ----------------------------------------------
+
+    type CapturedEvent = (Level, HashMap<String, String>);
+
+    /// A subscriber recording the fields of query log events.
+    #[derive(Default)]
+    struct CapturingSubscriber {
+        events: Arc<Mutex<Vec<CapturedEvent>>>,
+    }
+
+    impl Subscriber for CapturingSubscriber {
+        fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+            metadata.target() == LOG_TARGET
+        }
+
+        fn new_span(&self, _span: &Attributes<'_>) -> Id {
+            Id::from_u64(1)
+        }
+
+        fn record(&self, _span: &Id, _values: &Record<'_>) {}
+
+        fn record_follows_from(&self, _span: &Id, _follows: &Id) {}
+
+        fn event(&self, event: &Event<'_>) {
+            let mut visitor = FieldVisitor::default();
+            event.record(&mut visitor);
+            self.events
+                .lock()
+                .unwrap()
+                .push((*event.metadata().level(), visitor.0));
+        }
+
+        fn enter(&self, _span: &Id) {}
+
+        fn exit(&self, _span: &Id) {}
+    }
+
+    #[derive(Default)]
+    struct FieldVisitor(HashMap<String, String>);
+
+    impl Visit for FieldVisitor {
+        fn record_u64(&mut self, field: &Field, value: u64) {
+            self.0.insert(field.name().to_string(), value.to_string());
+        }
+
+        fn record_debug(
+            &mut self,
+            field: &Field,
+            value: &dyn core::fmt::Debug,
+        ) {
+            self.0
+                .insert(field.name().to_string(), format!("{value:?}"));
+        }
+    }
+}
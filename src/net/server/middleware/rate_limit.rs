@@ -0,0 +1,692 @@
+//! Request rate limiting by client network.
+//!
+//! The [`RateLimitMiddlewareSvc`] caps the number of requests per second
+//! that are processed for each client network, protecting the service from
+//! clients that flood it with requests. Requests exceeding the limit are not
+//! passed to the inner service but answered with a small response that is
+//! cheap to produce, see [`RateLimitAction`].
+//!
+//! Unlike the [`RrlMiddlewareSvc`] which limits identical responses in order
+//! to mitigate reflection attacks, this limits all requests of a client
+//! network alike, no matter what they ask for. Throttled requests are
+//! counted in [`ServerMetrics`], see [`RateLimitMiddlewareSvc::with_metrics`].
+//!
+//! The rate of a running [`RateLimitMiddlewareSvc`] can be changed with
+//! [`RateLimitMiddlewareSvc::set_rate`] without restarting the server.
+//!
+//! [`RrlMiddlewareSvc`]: super::rrl::RrlMiddlewareSvc
+//! [`ServerMetrics`]: crate::net::server::metrics::ServerMetrics
+use core::future::{ready, Ready};
+use core::hash::{BuildHasher, Hash, Hasher};
+use core::marker::PhantomData;
+use core::ops::ControlFlow;
+use core::time::Duration;
+
+use std::boxed::Box;
+use std::collections::hash_map::RandomState;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+
+use arc_swap::ArcSwap;
+use futures_util::stream::{once, Once};
+use octseq::Octets;
+use tokio::time::Instant;
+use tracing::{debug, trace};
+
+use crate::base::iana::OptRcode;
+use crate::base::message_builder::AdditionalBuilder;
+use crate::base::wire::Composer;
+use crate::base::StreamTarget;
+use crate::net::server::message::{Request, TransportSpecificContext};
+use crate::net::server::metrics::ServerMetrics;
+use crate::net::server::middleware::stream::MiddlewareStream;
+use crate::net::server::service::{CallResult, Service, ServiceResult};
+use crate::net::server::util::{client_prefix, mk_error_response, IpPrefix};
+
+//----------- Constants -------------------------------------------------------
+
+/// The default number of requests per second processed per client network.
+const DEFAULT_RATE: u32 = 20;
+
+/// The default prefix length used to group IPv4 clients into networks.
+const DEFAULT_IPV4_PREFIX_LEN: u8 = 24;
+
+/// The default prefix length used to group IPv6 clients into networks.
+const DEFAULT_IPV6_PREFIX_LEN: u8 = 64;
+
+/// The default maximum number of client networks tracked.
+const DEFAULT_MAX_ENTRIES: usize = 100_000;
+
+/// The number of independently locked shards of the bucket map.
+const NUM_SHARDS: usize = 16;
+
+/// The period over which a bucket is refilled completely.
+const REFILL_PERIOD: Duration = Duration::from_secs(1);
+
+//----------- RateLimitAction -------------------------------------------------
+
+/// The response to a request exceeding the rate limit.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum RateLimitAction {
+    /// Answer with an empty response with the TC flag set.
+    ///
+    /// This prompts legitimate clients to retry over TCP which forged
+    /// requests can't do. Requests received over a stream transport are
+    /// answered with REFUSED instead as truncation is meaningless there.
+    #[default]
+    Truncate,
+
+    /// Answer with REFUSED.
+    Refuse,
+}
+
+//----------- RateLimitConfig -------------------------------------------------
+
+/// Configuration for request rate limiting.
+#[derive(Clone, Copy, Debug)]
+struct RateLimitConfig {
+    /// The number of requests per second processed per client network.
+    rate: u32,
+
+    /// The response to requests exceeding the limit.
+    action: RateLimitAction,
+
+    /// The prefix length used to group IPv4 clients into networks.
+    ipv4_prefix_len: u8,
+
+    /// The prefix length used to group IPv6 clients into networks.
+    ipv6_prefix_len: u8,
+
+    /// The maximum number of client networks tracked.
+    max_entries: usize,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            rate: DEFAULT_RATE,
+            action: RateLimitAction::default(),
+            ipv4_prefix_len: DEFAULT_IPV4_PREFIX_LEN,
+            ipv6_prefix_len: DEFAULT_IPV6_PREFIX_LEN,
+            max_entries: DEFAULT_MAX_ENTRIES,
+        }
+    }
+}
+
+//----------- RateLimitMiddlewareSvc ------------------------------------------
+
+/// A middleware service for limiting the rate of requests per client network.
+///
+/// Clients are grouped into networks by truncating their address to a
+/// configurable prefix length. Each network has a bucket of tokens that
+/// refills at the [rate] up to one second's worth of tokens. Processing a
+/// request takes a token from the bucket of its network. If the bucket is
+/// empty, the request is answered according to the configured
+/// [`RateLimitAction`] without invoking the inner service.
+///
+/// The buckets are kept in a map split into independently locked shards so
+/// that requests from different networks rarely contend for the same lock.
+///
+/// [rate]: Self::with_rate
+#[derive(Clone, Debug)]
+pub struct RateLimitMiddlewareSvc<RequestOctets, NextSvc, RequestMeta> {
+    /// The upstream [`Service`] to pass requests to and receive responses
+    /// from.
+    next_svc: NextSvc,
+
+    /// The rate limiting state.
+    ///
+    /// Shared between clones of this service so that changing the rate
+    /// affects all of them.
+    state: Arc<ArcSwap<RateLimitState>>,
+
+    /// The metrics to count throttled requests in.
+    metrics: Arc<ServerMetrics>,
+
+    _phantom: PhantomData<(RequestOctets, RequestMeta)>,
+}
+
+impl<RequestOctets, NextSvc, RequestMeta>
+    RateLimitMiddlewareSvc<RequestOctets, NextSvc, RequestMeta>
+{
+    /// Creates an instance of this middleware service.
+    ///
+    /// By default 20 requests per second are processed per client network,
+    /// requests exceeding the limit get truncated responses and clients are
+    /// grouped into IPv4 /24 and IPv6 /64 networks.
+    #[must_use]
+    pub fn new(next_svc: NextSvc) -> Self {
+        Self {
+            next_svc,
+            state: Default::default(),
+            metrics: Arc::new(ServerMetrics::connection_less()),
+            _phantom: PhantomData,
+        }
+    }
+
+    /// Sets the number of requests per second processed per client network.
+    ///
+    /// A rate of zero disables rate limiting.
+    #[must_use]
+    pub fn with_rate(mut self, requests_per_second: u32) -> Self {
+        self.update_config(|config| config.rate = requests_per_second);
+        self
+    }
+
+    /// Sets the response to requests exceeding the rate limit.
+    ///
+    /// Defaults to [`RateLimitAction::Truncate`].
+    #[must_use]
+    pub fn with_action(mut self, action: RateLimitAction) -> Self {
+        self.update_config(|config| config.action = action);
+        self
+    }
+
+    /// Sets the prefix lengths used to group clients into networks.
+    ///
+    /// Prefix lengths larger than the address length are capped.
+    #[must_use]
+    pub fn with_prefix_lens(mut self, ipv4: u8, ipv6: u8) -> Self {
+        self.update_config(|config| {
+            config.ipv4_prefix_len = ipv4.min(32);
+            config.ipv6_prefix_len = ipv6.min(128);
+        });
+        self
+    }
+
+    /// Sets the maximum number of client networks tracked.
+    ///
+    /// Once the maximum is reached, the network seen longest ago is
+    /// forgotten to make room for a new one.
+    #[must_use]
+    pub fn with_max_entries(mut self, max_entries: usize) -> Self {
+        self.update_config(|config| config.max_entries = max_entries);
+        self
+    }
+
+    /// Sets the metrics to count throttled requests in.
+    ///
+    /// Pass the metrics of the server this service is used with to have
+    /// them include [`ServerMetrics::num_throttled_requests`]. By default
+    /// the service uses metrics of its own, see [`Self::metrics`].
+    #[must_use]
+    pub fn with_metrics(mut self, metrics: Arc<ServerMetrics>) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    /// Replace the number of requests per second processed per client
+    /// network.
+    ///
+    /// All buckets are reset. Requests already being processed are not
+    /// affected. A rate of zero disables rate limiting.
+    pub fn set_rate(&self, requests_per_second: u32) {
+        debug!("Rate limit replaced with {requests_per_second} per second");
+        self.state.store(Arc::new(
+            self.updated_state(|config| config.rate = requests_per_second),
+        ));
+    }
+
+    /// The metrics throttled requests are counted in.
+    pub fn metrics(&self) -> Arc<ServerMetrics> {
+        self.metrics.clone()
+    }
+
+    /// Updates the configuration, resetting all buckets.
+    ///
+    /// Unlike [`Self::set_rate`] this does not affect clones of this
+    /// service.
+    fn update_config(&mut self, op: impl FnOnce(&mut RateLimitConfig)) {
+        self.state = Arc::new(ArcSwap::from_pointee(self.updated_state(op)));
+    }
+
+    /// Returns new state with an updated configuration and empty buckets.
+    fn updated_state(
+        &self,
+        op: impl FnOnce(&mut RateLimitConfig),
+    ) -> RateLimitState {
+        let state = self.state.load();
+        let mut config = state.config;
+        op(&mut config);
+        RateLimitState {
+            config,
+            ..Default::default()
+        }
+    }
+}
+
+impl<RequestOctets, NextSvc, RequestMeta>
+    RateLimitMiddlewareSvc<RequestOctets, NextSvc, RequestMeta>
+where
+    RequestOctets: Octets + Send + Sync + Unpin,
+    RequestMeta: Clone + Default,
+    NextSvc: Service<RequestOctets, RequestMeta>,
+    NextSvc::Target: Composer + Default,
+{
+    /// Answer the request if its client network exceeded the rate limit.
+    fn preprocess(
+        &self,
+        request: &Request<RequestOctets, RequestMeta>,
+    ) -> ControlFlow<AdditionalBuilder<StreamTarget<NextSvc::Target>>> {
+        let state = self.state.load();
+        let config = &state.config;
+        let prefix = client_prefix(
+            &request.client_addr(),
+            config.ipv4_prefix_len,
+            config.ipv6_prefix_len,
+        );
+
+        if state.check(prefix) {
+            return ControlFlow::Continue(());
+        }
+
+        trace!("Throttling request from {}", request.client_addr());
+        self.metrics.inc_num_throttled_requests();
+
+        let msg = request.message();
+        let truncate = config.action == RateLimitAction::Truncate
+            && matches!(
+                request.transport_ctx(),
+                TransportSpecificContext::Udp(_)
+            );
+        let response = if truncate {
+            let mut response = mk_error_response(msg, OptRcode::NOERROR);
+            response.header_mut().set_tc(true);
+            response
+        } else {
+            mk_error_response(msg, OptRcode::REFUSED)
+        };
+
+        ControlFlow::Break(response)
+    }
+}
+
+//--- Service
+
+impl<RequestOctets, NextSvc, RequestMeta> Service<RequestOctets, RequestMeta>
+    for RateLimitMiddlewareSvc<RequestOctets, NextSvc, RequestMeta>
+where
+    RequestOctets: Octets + Send + Sync + 'static + Unpin,
+    RequestMeta: Clone + Default,
+    NextSvc: Service<RequestOctets, RequestMeta>,
+    NextSvc::Target: Composer + Default,
+    NextSvc::Future: Unpin,
+{
+    type Target = NextSvc::Target;
+    type Stream = MiddlewareStream<
+        NextSvc::Future,
+        NextSvc::Stream,
+        NextSvc::Stream,
+        Once<Ready<ServiceResult<Self::Target>>>,
+        ServiceResult<Self::Target>,
+    >;
+    type Future = Ready<Self::Stream>;
+
+    fn call(
+        &self,
+        request: Request<RequestOctets, RequestMeta>,
+    ) -> Self::Future {
+        match self.preprocess(&request) {
+            ControlFlow::Continue(()) => {
+                let svc_call_fut = self.next_svc.call(request);
+                ready(MiddlewareStream::IdentityFuture(svc_call_fut))
+            }
+            ControlFlow::Break(response) => ready(MiddlewareStream::Result(
+                once(ready(Ok(CallResult::new(response)))),
+            )),
+        }
+    }
+}
+
+//----------- RateLimitState --------------------------------------------------
+
+/// The state of request rate limiting.
+///
+/// Shared by all clones of a [`RateLimitMiddlewareSvc`].
+#[derive(Debug)]
+struct RateLimitState {
+    /// The configuration.
+    config: RateLimitConfig,
+
+    /// The buckets of the tracked client networks, split into shards.
+    shards: Box<[Mutex<Shard>]>,
+
+    /// The hasher used to pick the shard of a client network.
+    hasher: RandomState,
+}
+
+impl Default for RateLimitState {
+    fn default() -> Self {
+        Self {
+            config: Default::default(),
+            shards: (0..NUM_SHARDS).map(|_| Default::default()).collect(),
+            hasher: Default::default(),
+        }
+    }
+}
+
+impl RateLimitState {
+    /// Takes a token for a request from the given client network.
+    ///
+    /// Returns whether the request is within the rate limit.
+    fn check(&self, prefix: IpPrefix) -> bool {
+        let rate = self.config.rate;
+        if rate == 0 {
+            return true;
+        }
+        let rate = f64::from(rate);
+        let now = Instant::now();
+
+        let mut hasher = self.hasher.build_hasher();
+        prefix.hash(&mut hasher);
+        let shard = (hasher.finish() as usize) % self.shards.len();
+        let max_entries =
+            (self.config.max_entries / self.shards.len()).max(1);
+
+        let mut shard = self.shards[shard].lock().unwrap();
+        let bucket = shard.bucket(prefix, max_entries, || Bucket {
+            tokens: rate,
+            updated: now,
+            last_use: 0,
+        });
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64()
+            / REFILL_PERIOD.as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * rate).min(rate);
+        bucket.updated = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+//----------- Shard -----------------------------------------------------------
+
+/// An independently locked part of the buckets of the tracked networks.
+///
+/// The order in which the buckets were used is kept in a queue so that the
+/// least recently used bucket can be found when the shard is full. Instead
+/// of moving a bucket to the back of the queue on each use, which would
+/// require searching the queue, a new entry is pushed and the bucket
+/// remembers which entry is current. Outdated entries are skipped when
+/// popped and removed all at once when they start to dominate the queue, so
+/// that each use takes amortized constant time.
+#[derive(Debug, Default)]
+struct Shard {
+    /// The buckets of the client networks.
+    buckets: HashMap<IpPrefix, Bucket>,
+
+    /// The uses of buckets, least recent first.
+    uses: VecDeque<(IpPrefix, u64)>,
+
+    /// The number of the next use.
+    next_use: u64,
+}
+
+impl Shard {
+    /// Returns the bucket of a client network, marking it as just used.
+    ///
+    /// If there is no bucket for the network yet, a new one is created,
+    /// replacing the least recently used bucket if there are `max_entries`
+    /// buckets already.
+    fn bucket(
+        &mut self,
+        prefix: IpPrefix,
+        max_entries: usize,
+        new: impl FnOnce() -> Bucket,
+    ) -> &mut Bucket {
+        if !self.buckets.contains_key(&prefix) {
+            while self.buckets.len() >= max_entries {
+                self.evict_least_recently_used();
+            }
+        }
+        if self.uses.len() > 2 * self.buckets.len() {
+            let buckets = &self.buckets;
+            self.uses.retain(|(prefix, last_use)| {
+                buckets
+                    .get(prefix)
+                    .map_or(false, |bucket| bucket.last_use == *last_use)
+            });
+        }
+
+        let last_use = self.next_use;
+        self.next_use += 1;
+        self.uses.push_back((prefix, last_use));
+        let bucket = self.buckets.entry(prefix).or_insert_with(new);
+        bucket.last_use = last_use;
+        bucket
+    }
+
+    /// Removes the least recently used bucket.
+    fn evict_least_recently_used(&mut self) {
+        while let Some((prefix, last_use)) = self.uses.pop_front() {
+            let current = self
+                .buckets
+                .get(&prefix)
+                .map_or(false, |bucket| bucket.last_use == last_use);
+            if current {
+                self.buckets.remove(&prefix);
+                return;
+            }
+        }
+        // Every bucket has an entry in the queue, so this is unreachable,
+        // but make sure not to loop forever should that ever change.
+        self.buckets.clear();
+    }
+}
+
+//----------- Bucket ----------------------------------------------------------
+
+/// The token bucket of a client network.
+#[derive(Clone, Debug)]
+struct Bucket {
+    /// The number of tokens currently in the bucket.
+    tokens: f64,
+
+    /// When the bucket was last updated.
+    updated: Instant,
+
+    /// The number of the last use of the bucket in its [`Shard`].
+    last_use: u64,
+}
+
+//============ Tests =========================================================
+
+#[cfg(test)]
+mod tests {
+    use core::time::Duration;
+
+    use std::net::IpAddr;
+    use std::sync::Arc;
+    use std::vec::Vec;
+
+    use crate::base::iana::Rcode;
+    use crate::net::server::message::{
+        NonUdpTransportContext, TransportSpecificContext, UdpTransportContext,
+    };
+    use crate::net::server::metrics::ServerMetrics;
+    use crate::net::server::util::client_prefix;
+
+    use super::super::test_helpers::{
+        nxdomain_service, try_process_with_ctx, NxdomainService,
+    };
+    use super::{
+        Bucket, Instant, RateLimitAction, RateLimitMiddlewareSvc, Shard,
+    };
+
+    //------------ Tests -----------------------------------------------------
+
+    #[tokio::test(start_paused = true)]
+    async fn burst_above_rate_is_throttled() {
+        let svc = mk_svc().with_rate(3);
+
+        let mut truncated = Vec::new();
+        for _ in 0..5 {
+            let response =
+                try_process_with_ctx(&svc, ".", "192.0.2.1", udp())
+                    .await
+                    .unwrap();
+            truncated.push(response.header().tc());
+            if response.header().tc() {
+                assert_eq!(response.header_counts().ancount(), 0);
+                assert_eq!(response.header_counts().qdcount(), 1);
+            }
+        }
+        assert_eq!(truncated, [false, false, false, true, true]);
+        assert_eq!(svc.metrics().num_throttled_requests(), 2);
+
+        // Other networks have their own buckets.
+        let response = try_process_with_ctx(&svc, ".", "192.0.2.200", udp())
+            .await
+            .unwrap();
+        assert!(response.header().tc());
+        let response = try_process_with_ctx(&svc, ".", "198.51.100.1", udp())
+            .await
+            .unwrap();
+        assert!(!response.header().tc());
+
+        // Buckets refill over time.
+        tokio::time::advance(Duration::from_secs(1)).await;
+        let response = try_process_with_ctx(&svc, ".", "192.0.2.1", udp())
+            .await
+            .unwrap();
+        assert!(!response.header().tc());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn throttled_requests_can_be_refused() {
+        let svc = mk_svc().with_rate(1).with_action(RateLimitAction::Refuse);
+
+        let response = try_process_with_ctx(&svc, ".", "2001:db8::1", udp())
+            .await
+            .unwrap();
+        assert_eq!(response.header().rcode(), Rcode::NOERROR);
+        let response = try_process_with_ctx(&svc, ".", "2001:db8::2", udp())
+            .await
+            .unwrap();
+        assert_eq!(response.header().rcode(), Rcode::REFUSED);
+        assert!(!response.header().tc());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn throttled_stream_requests_are_refused() {
+        let svc = mk_svc().with_rate(1);
+        let tcp = || NonUdpTransportContext::new(None).into();
+
+        let response = try_process_with_ctx(&svc, ".", "192.0.2.1", tcp())
+            .await
+            .unwrap();
+        assert_eq!(response.header().rcode(), Rcode::NOERROR);
+        let response = try_process_with_ctx(&svc, ".", "192.0.2.1", tcp())
+            .await
+            .unwrap();
+        assert_eq!(response.header().rcode(), Rcode::REFUSED);
+        assert!(!response.header().tc());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn replaced_rate_applies_to_clones() {
+        let svc = mk_svc().with_rate(0);
+        let clone = svc.clone();
+        for _ in 0..5 {
+            let response =
+                try_process_with_ctx(&clone, ".", "192.0.2.1", udp())
+                    .await
+                    .unwrap();
+            assert!(!response.header().tc());
+        }
+
+        svc.set_rate(1);
+        let response = try_process_with_ctx(&clone, ".", "192.0.2.1", udp())
+            .await
+            .unwrap();
+        assert!(!response.header().tc());
+        let response = try_process_with_ctx(&clone, ".", "192.0.2.1", udp())
+            .await
+            .unwrap();
+        assert!(response.header().tc());
+        assert_eq!(svc.metrics().num_throttled_requests(), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn least_recently_used_network_is_forgotten() {
+        // With a single entry per shard, a network is forgotten once another
+        // network in its shard is seen.
+        let metrics = Arc::new(ServerMetrics::connection_less());
+        let svc = mk_svc()
+            .with_rate(1)
+            .with_max_entries(1)
+            .with_metrics(metrics.clone());
+
+        // Exhaust the bucket of a network, then see many others. Requests
+        // from new networks are still limited while the table is full.
+        let response = try_process_with_ctx(&svc, ".", "192.0.2.1", udp())
+            .await
+            .unwrap();
+        assert!(!response.header().tc());
+        let response = try_process_with_ctx(&svc, ".", "192.0.2.1", udp())
+            .await
+            .unwrap();
+        assert!(response.header().tc());
+        for i in 0..=255 {
+            let client = format!("198.51.{i}.1");
+            let response = try_process_with_ctx(&svc, ".", &client, udp())
+                .await
+                .unwrap();
+            assert!(!response.header().tc());
+            let response = try_process_with_ctx(&svc, ".", &client, udp())
+                .await
+                .unwrap();
+            assert!(response.header().tc());
+        }
+        assert_eq!(metrics.num_throttled_requests(), 257);
+
+        // The first network was forgotten and gets a full bucket again.
+        let response = try_process_with_ctx(&svc, ".", "192.0.2.1", udp())
+            .await
+            .unwrap();
+        assert!(!response.header().tc());
+    }
+
+    #[test]
+    fn shard_evicts_least_recently_used_bucket() {
+        let prefix = |ip: &str| {
+            client_prefix(&(ip.parse::<IpAddr>().unwrap(), 53).into(), 24, 64)
+        };
+        let new = || Bucket {
+            tokens: 1.0,
+            updated: Instant::now(),
+            last_use: 0,
+        };
+        let mut shard = Shard::default();
+        let (a, b, c) =
+            (prefix("192.0.2.1"), prefix("198.51.100.1"), prefix("::1"));
+
+        // Many uses of the same buckets don't grow the queue unbounded.
+        for _ in 0..100 {
+            shard.bucket(a, 2, new);
+            shard.bucket(b, 2, new);
+        }
+        assert!(shard.uses.len() <= 5);
+
+        // Using a makes b the least recently used bucket.
+        shard.bucket(a, 2, new).tokens = 0.0;
+        shard.bucket(c, 2, new);
+        assert_eq!(shard.buckets.len(), 2);
+        assert!(!shard.buckets.contains_key(&b));
+        assert_eq!(shard.bucket(a, 2, new).tokens, 0.0);
+    }
+
+    //------------ Helper functions ------------------------------------------
+
+    fn mk_svc() -> RateLimitMiddlewareSvc<Vec<u8>, NxdomainService, ()> {
+        RateLimitMiddlewareSvc::new(nxdomain_service())
+    }
+
+    fn udp() -> TransportSpecificContext {
+        UdpTransportContext::default().into()
+    }
+}
@@ -0,0 +1,312 @@
+//! Advertising of an RFC 9567 DNS error reporting agent.
+//!
+//! [RFC 9567] allows a validating resolver to report failures it encounters
+//! while resolving names in a zone to a monitoring agent designated by the
+//! operator of the zone. The authoritative server advertises the domain of
+//! that agent by adding a Report-Channel EDNS option to its responses.
+//!
+//! The [`ReportChannelMiddlewareSvc`] adds this option to responses for the
+//! zones that have opted in to error reporting.
+//!
+//! [RFC 9567]: https://www.rfc-editor.org/rfc/rfc9567.html
+use core::future::{ready, Ready};
+use core::marker::PhantomData;
+
+use std::sync::Arc;
+use std::vec::Vec;
+
+use bytes::Bytes;
+use octseq::Octets;
+use tracing::warn;
+
+use crate::base::message_builder::AdditionalBuilder;
+use crate::base::opt::ReportChannel;
+use crate::base::wire::Composer;
+use crate::base::{Name, StreamTarget, ToName};
+use crate::net::server::message::Request;
+use crate::net::server::service::{Service, ServiceResult};
+use crate::net::server::util::add_edns_options;
+
+use super::stream::PostprocessingStream;
+
+/// The apexes of the zones that opted in and their Report-Channel options.
+type Zones = Vec<(Name<Bytes>, ReportChannel<Name<Bytes>>)>;
+
+//------------ ReportChannelMiddlewareSvc ------------------------------------
+
+/// A middleware service for advertising DNS error reporting agents.
+///
+/// Responses to requests for a name at or below the apex of one of the zones
+/// configured via [`with_zone()`] get a Report-Channel EDNS option holding
+/// the agent domain configured for that zone. If the name is within more
+/// than one configured zone the one with the longest apex is used.
+///
+/// The option is only added if the request included an OPT record, i.e. if
+/// the client supports EDNS, and if the response doesn't already contain a
+/// Report-Channel option. Responses for names outside of the configured
+/// zones are passed through unmodified.
+///
+/// [`with_zone()`]: Self::with_zone
+#[derive(Clone, Debug)]
+pub struct ReportChannelMiddlewareSvc<RequestOctets, NextSvc, RequestMeta> {
+    /// The upstream [`Service`] to pass requests to and receive responses
+    /// from.
+    next_svc: NextSvc,
+
+    /// The apexes of the zones that opted in and their agent domains.
+    zones: Arc<Zones>,
+
+    _phantom: PhantomData<(RequestOctets, RequestMeta)>,
+}
+
+impl<RequestOctets, NextSvc, RequestMeta>
+    ReportChannelMiddlewareSvc<RequestOctets, NextSvc, RequestMeta>
+{
+    /// Creates an instance of this middleware service.
+    ///
+    /// No zones opt in to error reporting until added using
+    /// [`with_zone()`].
+    ///
+    /// [`with_zone()`]: Self::with_zone
+    #[must_use]
+    pub fn new(next_svc: NextSvc) -> Self {
+        Self {
+            next_svc,
+            zones: Default::default(),
+            _phantom: PhantomData,
+        }
+    }
+
+    /// Advertises the given Report-Channel option in responses for the
+    /// zone with the given apex.
+    #[must_use]
+    pub fn with_zone(
+        mut self,
+        apex: &impl ToName,
+        report_channel: ReportChannel<Name<Bytes>>,
+    ) -> Self {
+        Arc::make_mut(&mut self.zones).push((apex.to_name(), report_channel));
+        self
+    }
+}
+
+impl<RequestOctets, NextSvc, RequestMeta>
+    ReportChannelMiddlewareSvc<RequestOctets, NextSvc, RequestMeta>
+where
+    RequestOctets: Octets + Send + Sync + Unpin,
+    NextSvc: Service<RequestOctets, RequestMeta>,
+    NextSvc::Target: Composer + Default,
+    RequestMeta: Clone + Default,
+{
+    fn postprocess(
+        request: &Request<RequestOctets, RequestMeta>,
+        response: &mut AdditionalBuilder<StreamTarget<NextSvc::Target>>,
+        zones: &[(Name<Bytes>, ReportChannel<Name<Bytes>>)],
+    ) {
+        if zones.is_empty() || request.message().opt().is_none() {
+            return;
+        }
+
+        let Some(report_channel) = Self::report_channel(request, zones)
+        else {
+            return;
+        };
+
+        if let Some(opt) = response.as_message().opt() {
+            if opt.opt().report_channel().is_some() {
+                return;
+            }
+        }
+
+        if let Err(err) =
+            add_edns_options(response, |builder| builder.push(report_channel))
+        {
+            warn!("Unable to add Report-Channel option to response: {err}");
+        }
+    }
+
+    /// Returns the Report-Channel option of the closest enclosing zone of
+    /// the queried name, if any.
+    fn report_channel<'a>(
+        request: &Request<RequestOctets, RequestMeta>,
+        zones: &'a [(Name<Bytes>, ReportChannel<Name<Bytes>>)],
+    ) -> Option<&'a ReportChannel<Name<Bytes>>> {
+        let question = request.message().sole_question().ok()?;
+        let qname = question.qname();
+        zones
+            .iter()
+            .filter(|(apex, _)| qname.ends_with(apex))
+            .max_by_key(|(apex, _)| apex.label_count())
+            .map(|(_, report_channel)| report_channel)
+    }
+
+    fn map_stream_item(
+        request: Request<RequestOctets, RequestMeta>,
+        mut stream_item: ServiceResult<NextSvc::Target>,
+        zones: &mut Arc<Zones>,
+    ) -> ServiceResult<NextSvc::Target> {
+        if let Ok(cr) = &mut stream_item {
+            if let Some(response) = cr.response_mut() {
+                Self::postprocess(&request, response, zones);
+            }
+        }
+        stream_item
+    }
+}
+
+//--- Service
+
+impl<RequestOctets, NextSvc, RequestMeta> Service<RequestOctets, RequestMeta>
+    for ReportChannelMiddlewareSvc<RequestOctets, NextSvc, RequestMeta>
+where
+    RequestOctets: Octets + Send + Sync + 'static + Unpin,
+    NextSvc: Service<RequestOctets, RequestMeta>,
+    NextSvc::Future: Unpin,
+    NextSvc::Target: Composer + Default,
+    RequestMeta: Clone + Default + Unpin,
+{
+    type Target = NextSvc::Target;
+    type Stream = PostprocessingStream<
+        RequestOctets,
+        NextSvc::Future,
+        NextSvc::Stream,
+        RequestMeta,
+        Arc<Zones>,
+    >;
+    type Future = Ready<Self::Stream>;
+
+    fn call(
+        &self,
+        request: Request<RequestOctets, RequestMeta>,
+    ) -> Self::Future {
+        let svc_call_fut = self.next_svc.call(request.clone());
+        ready(PostprocessingStream::new(
+            svc_call_fut,
+            request,
+            self.zones.clone(),
+            Self::map_stream_item,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::str::FromStr;
+
+    use std::string::{String, ToString};
+    use std::vec::Vec;
+
+    use bytes::Bytes;
+    use futures_util::StreamExt;
+
+    use crate::base::iana::Rcode;
+    use crate::base::opt::ReportChannel;
+    use crate::base::{Message, MessageBuilder, Name, Rtype};
+    use crate::net::server::message::{Request, UdpTransportContext};
+    use crate::net::server::service::{CallResult, Service, ServiceResult};
+    use crate::net::server::util::{mk_builder_for_target, service_fn};
+
+    use super::ReportChannelMiddlewareSvc;
+
+    //------------ Tests -----------------------------------------------------
+
+    #[tokio::test]
+    async fn option_is_added_for_opted_in_zone() {
+        let response = process("www.example.com", true).await;
+        assert_eq!(
+            agent_domain(&response).as_deref(),
+            Some("agent.example.net")
+        );
+
+        // The option is encoded as code 18, the length and the
+        // uncompressed agent domain.
+        let mut expected = Vec::new();
+        expected.extend_from_slice(&[0, 18, 0, 19]);
+        expected.extend_from_slice(b"\x05agent\x07example\x03net\x00");
+        assert!(response
+            .as_slice()
+            .windows(expected.len())
+            .any(|window| window == expected));
+    }
+
+    #[tokio::test]
+    async fn closest_zone_wins() {
+        let response = process("www.sub.example.com", true).await;
+        assert_eq!(
+            agent_domain(&response).as_deref(),
+            Some("agent.sub.example.net")
+        );
+    }
+
+    #[tokio::test]
+    async fn option_is_not_added_for_other_zones() {
+        let response = process("www.example.org", true).await;
+        assert_eq!(agent_domain(&response), None);
+    }
+
+    #[tokio::test]
+    async fn option_is_not_added_without_edns() {
+        let response = process("www.example.com", false).await;
+        assert!(response.opt().is_none());
+    }
+
+    //------------ Helper functions ------------------------------------------
+
+    fn agent_domain(response: &Message<Vec<u8>>) -> Option<String> {
+        let opt = response.opt()?;
+        let mut options = opt.opt().iter::<ReportChannel<_>>();
+        let agent_domain =
+            options.next()?.unwrap().agent_domain().to_string();
+        assert!(options.next().is_none());
+        Some(agent_domain)
+    }
+
+    async fn process(qname: &str, edns: bool) -> Message<Vec<u8>> {
+        let query = MessageBuilder::new_vec();
+        let mut query = query.question();
+        query
+            .push((Name::<Bytes>::from_str(qname).unwrap(), Rtype::A))
+            .unwrap();
+        let mut query = query.additional();
+        if edns {
+            query.opt(|_| Ok(())).unwrap();
+        }
+        let message = query.into_message();
+
+        let request = Request::for_test(
+            message,
+            UdpTransportContext::default(),
+            "127.0.0.1:12345".parse().unwrap(),
+        );
+
+        fn my_service(
+            req: Request<Vec<u8>>,
+            _meta: (),
+        ) -> ServiceResult<Vec<u8>> {
+            let builder = mk_builder_for_target();
+            let answer =
+                builder.start_answer(req.message(), Rcode::NOERROR).unwrap();
+            Ok(CallResult::new(answer.additional()))
+        }
+
+        let name = |s| Name::<Bytes>::from_str(s).unwrap();
+        let report_channel = |s| ReportChannel::new(name(s)).unwrap();
+        let middleware_svc =
+            ReportChannelMiddlewareSvc::new(service_fn(my_service, ()))
+                .with_zone(
+                    &name("example.com"),
+                    report_channel("agent.example.net"),
+                )
+                .with_zone(
+                    &name("sub.example.com"),
+                    report_channel("agent.sub.example.net"),
+                );
+        let mut stream = middleware_svc.call(request).await;
+        let call_result: CallResult<Vec<u8>> =
+            stream.next().await.unwrap().unwrap();
+        let (response, _feedback) = call_result.into_inner();
+        let response = response.unwrap().finish();
+
+        Message::from_octets(response.as_dgram_slice().to_vec()).unwrap()
+    }
+}
@@ -0,0 +1,588 @@
+//! Response Policy Zone (RPZ) style filtering of requests.
+//!
+//! Response policies let an operator override the answers to queries for
+//! selected names, e.g. to block access to known malicious domains or to
+//! redirect clients to a walled garden explaining why access was denied.
+//!
+//! The [`RpzMiddlewareSvc`] applies an [`RpzPolicySet`] mapping query name
+//! triggers to [`RpzAction`]s. The actions mirror the common policy actions
+//! of [draft-vixie-dnsop-dns-rpz], but policies are supplied directly rather
+//! than loaded from a policy zone.
+//!
+//! The policy set can be replaced at runtime, e.g. after fetching an updated
+//! block list, via [`RpzMiddlewareSvc::set_policies()`]. The number of
+//! policy hits per action is tracked in [`RpzMetrics`].
+//!
+//! [draft-vixie-dnsop-dns-rpz]: https://datatracker.ietf.org/doc/draft-vixie-dnsop-dns-rpz/
+use core::future::{ready, Ready};
+use core::marker::PhantomData;
+use core::ops::ControlFlow;
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::vec::Vec;
+
+use arc_swap::ArcSwap;
+use bytes::Bytes;
+use futures_util::stream::{iter, Iter, Stream};
+use octseq::Octets;
+use tracing::{debug, warn};
+
+use crate::base::iana::{OptRcode, Rcode};
+use crate::base::message_builder::{AdditionalBuilder, PushError};
+use crate::base::net::IpAddr;
+use crate::base::wire::Composer;
+use crate::base::{Message, Name, Rtype, StreamTarget, ToName, Ttl};
+use crate::net::server::message::Request;
+use crate::net::server::middleware::stream::MiddlewareStream;
+use crate::net::server::service::{CallResult, Service};
+use crate::net::server::util::{mk_builder_for_target, mk_error_response};
+use crate::rdata::{Aaaa, Cname, A};
+
+//----------- Constants -------------------------------------------------------
+
+/// The default TTL of records synthesized by [`RpzAction::Redirect`] and
+/// [`RpzAction::Cname`].
+const DEFAULT_LOCAL_DATA_TTL: Ttl = Ttl::from_secs(300);
+
+//----------- RpzAction -------------------------------------------------------
+
+/// The action to take for a request whose query name triggers a policy.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum RpzAction {
+    /// Answer with NXDOMAIN, pretending the name does not exist.
+    NxDomain,
+
+    /// Answer with NOERROR and an empty answer section, pretending the name
+    /// has no records of the queried type.
+    NoData,
+
+    /// Don't answer the request at all.
+    Drop,
+
+    /// Pass the request to the inner service unmodified.
+    ///
+    /// Use this to exempt names from broader policies, e.g. a single host
+    /// from a policy for all names under its domain.
+    Passthru,
+
+    /// Answer with the given addresses, e.g. those of a walled garden.
+    ///
+    /// A queries are answered with the IPv4 addresses and AAAA queries with
+    /// the IPv6 addresses. Queries for other types, or for which there are no
+    /// addresses of the queried family, are answered as for
+    /// [`RpzAction::NoData`].
+    Redirect(Vec<IpAddr>),
+
+    /// Answer with a CNAME record pointing to the given name.
+    ///
+    /// The client is expected to follow the CNAME to the target, e.g. a
+    /// walled garden host that is resolvable by normal means.
+    Cname(Name<Bytes>),
+}
+
+//----------- RpzPolicySet ----------------------------------------------------
+
+/// A set of policies mapping query name triggers to actions.
+///
+/// A trigger is either an exact name, e.g. `bad.example.com`, or a wildcard
+/// name, e.g. `*.example.com`, which matches all names below but not at
+/// `example.com`. Names are compared case insensitively.
+///
+/// An exact trigger takes precedence over wildcard triggers and the wildcard
+/// trigger closest to the query name takes precedence over those further
+/// away.
+#[derive(Clone, Debug)]
+pub struct RpzPolicySet {
+    /// Actions for exact name triggers.
+    exact: HashMap<Name<Bytes>, RpzAction>,
+
+    /// Actions for wildcard triggers, keyed by the name below the `*` label.
+    wildcard: HashMap<Name<Bytes>, RpzAction>,
+
+    /// The TTL of records synthesized by policy actions.
+    local_data_ttl: Ttl,
+}
+
+impl RpzPolicySet {
+    /// Creates an empty policy set.
+    #[must_use]
+    pub fn new() -> Self {
+        Self {
+            exact: HashMap::new(),
+            wildcard: HashMap::new(),
+            local_data_ttl: DEFAULT_LOCAL_DATA_TTL,
+        }
+    }
+
+    /// Adds a policy taking the given action for the given trigger.
+    ///
+    /// Replaces any existing policy for the same trigger.
+    #[must_use]
+    pub fn with_policy(
+        mut self,
+        trigger: &impl ToName,
+        action: RpzAction,
+    ) -> Self {
+        self.add_policy(trigger, action);
+        self
+    }
+
+    /// Adds a policy taking the given action for the given trigger.
+    ///
+    /// Replaces any existing policy for the same trigger.
+    pub fn add_policy(&mut self, trigger: &impl ToName, action: RpzAction) {
+        let trigger: Name<Bytes> = trigger.to_name();
+        if trigger.first().is_wildcard() {
+            if let Some(parent) = trigger.parent() {
+                self.wildcard.insert(parent, action);
+            }
+        } else {
+            self.exact.insert(trigger, action);
+        }
+    }
+
+    /// Sets the TTL of records synthesized by policy actions.
+    ///
+    /// Defaults to 300 seconds.
+    #[must_use]
+    pub fn with_local_data_ttl(mut self, ttl: Ttl) -> Self {
+        self.local_data_ttl = ttl;
+        self
+    }
+
+    /// Returns the number of policies in the set.
+    pub fn len(&self) -> usize {
+        self.exact.len() + self.wildcard.len()
+    }
+
+    /// Returns whether the set contains no policies.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns the action of the policy triggered by the given name, if any.
+    pub fn lookup(&self, qname: &impl ToName) -> Option<&RpzAction> {
+        let qname: Name<Bytes> = qname.to_name();
+        if let Some(action) = self.exact.get(&qname) {
+            return Some(action);
+        }
+        qname
+            .iter_suffixes()
+            .skip(1)
+            .find_map(|suffix| self.wildcard.get(&suffix))
+    }
+}
+
+//--- Default
+
+impl Default for RpzPolicySet {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+//----------- RpzMetrics ------------------------------------------------------
+
+/// Counts of requests that triggered a policy, per action.
+#[derive(Debug, Default)]
+pub struct RpzMetrics {
+    /// The number of requests answered with NXDOMAIN.
+    num_nxdomain: AtomicUsize,
+
+    /// The number of requests answered with NODATA.
+    num_nodata: AtomicUsize,
+
+    /// The number of requests dropped.
+    num_dropped: AtomicUsize,
+
+    /// The number of requests passed through.
+    num_passthru: AtomicUsize,
+
+    /// The number of requests answered with redirect addresses.
+    num_redirected: AtomicUsize,
+
+    /// The number of requests answered with a CNAME.
+    num_cname: AtomicUsize,
+}
+
+impl RpzMetrics {
+    /// The number of requests answered with NXDOMAIN.
+    pub fn num_nxdomain(&self) -> usize {
+        self.num_nxdomain.load(Ordering::Relaxed)
+    }
+
+    /// The number of requests answered with NODATA.
+    pub fn num_nodata(&self) -> usize {
+        self.num_nodata.load(Ordering::Relaxed)
+    }
+
+    /// The number of requests dropped.
+    pub fn num_dropped(&self) -> usize {
+        self.num_dropped.load(Ordering::Relaxed)
+    }
+
+    /// The number of requests passed through due to a passthru policy.
+    pub fn num_passthru(&self) -> usize {
+        self.num_passthru.load(Ordering::Relaxed)
+    }
+
+    /// The number of requests answered with redirect addresses.
+    pub fn num_redirected(&self) -> usize {
+        self.num_redirected.load(Ordering::Relaxed)
+    }
+
+    /// The number of requests answered with a CNAME.
+    pub fn num_cname(&self) -> usize {
+        self.num_cname.load(Ordering::Relaxed)
+    }
+
+    /// The total number of requests that triggered a policy.
+    pub fn num_hits(&self) -> usize {
+        self.num_nxdomain()
+            + self.num_nodata()
+            + self.num_dropped()
+            + self.num_passthru()
+            + self.num_redirected()
+            + self.num_cname()
+    }
+
+    /// Count a hit for a policy with the given action.
+    fn inc(&self, action: &RpzAction) {
+        let counter = match action {
+            RpzAction::NxDomain => &self.num_nxdomain,
+            RpzAction::NoData => &self.num_nodata,
+            RpzAction::Drop => &self.num_dropped,
+            RpzAction::Passthru => &self.num_passthru,
+            RpzAction::Redirect(_) => &self.num_redirected,
+            RpzAction::Cname(_) => &self.num_cname,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+//----------- RpzMiddlewareSvc ------------------------------------------------
+
+/// A middleware service for applying response policies.
+///
+/// Requests whose query name triggers a policy in the configured
+/// [`RpzPolicySet`] are handled according to the [`RpzAction`] of the
+/// policy. As all triggers are query name triggers the action is taken
+/// before the request reaches the inner service, which is only invoked for
+/// requests that trigger no policy or a [`RpzAction::Passthru`] policy.
+///
+/// Responses synthesized by this service don't include an OPT record, place
+/// an [`EdnsMiddlewareSvc`] in front of this service to add one where
+/// needed.
+///
+/// [`EdnsMiddlewareSvc`]: super::edns::EdnsMiddlewareSvc
+#[derive(Clone, Debug)]
+pub struct RpzMiddlewareSvc<RequestOctets, NextSvc, RequestMeta> {
+    /// The upstream [`Service`] to pass requests to and receive responses
+    /// from.
+    next_svc: NextSvc,
+
+    /// The policies to apply.
+    ///
+    /// Shared between clones of this service so that replacing the policies
+    /// affects all of them.
+    policies: Arc<ArcSwap<RpzPolicySet>>,
+
+    /// Counts of policy hits.
+    ///
+    /// Shared between clones of this service.
+    metrics: Arc<RpzMetrics>,
+
+    _phantom: PhantomData<(RequestOctets, RequestMeta)>,
+}
+
+impl<RequestOctets, NextSvc, RequestMeta>
+    RpzMiddlewareSvc<RequestOctets, NextSvc, RequestMeta>
+{
+    /// Creates an instance of this middleware service.
+    #[must_use]
+    pub fn new(next_svc: NextSvc, policies: RpzPolicySet) -> Self {
+        Self {
+            next_svc,
+            policies: Arc::new(ArcSwap::from_pointee(policies)),
+            metrics: Default::default(),
+            _phantom: PhantomData,
+        }
+    }
+
+    /// Replace the policies applied by this service.
+    ///
+    /// Requests already being processed are not affected.
+    pub fn set_policies(&self, policies: RpzPolicySet) {
+        self.policies.store(Arc::new(policies));
+        debug!("RPZ policy set replaced");
+    }
+
+    /// Counts of requests that triggered a policy.
+    pub fn metrics(&self) -> Arc<RpzMetrics> {
+        self.metrics.clone()
+    }
+}
+
+impl<RequestOctets, NextSvc, RequestMeta>
+    RpzMiddlewareSvc<RequestOctets, NextSvc, RequestMeta>
+where
+    RequestOctets: Octets + Send + Sync + Unpin,
+    RequestMeta: Clone + Default,
+    NextSvc: Service<RequestOctets, RequestMeta>,
+    NextSvc::Target: Composer + Default,
+{
+    /// Apply the policy triggered by the request, if any.
+    ///
+    /// Returns [`ControlFlow::Continue`] if the request should be passed to
+    /// the inner service, otherwise [`ControlFlow::Break`] with the response
+    /// to send, if any.
+    fn preprocess(
+        &self,
+        request: &Request<RequestOctets, RequestMeta>,
+    ) -> ControlFlow<Option<AdditionalBuilder<StreamTarget<NextSvc::Target>>>>
+    {
+        let msg = request.message();
+        let Ok(question) = msg.sole_question() else {
+            return ControlFlow::Continue(());
+        };
+
+        let policies = self.policies.load();
+        let Some(action) = policies.lookup(&question.qname()) else {
+            return ControlFlow::Continue(());
+        };
+
+        debug!(
+            "RPZ policy hit for {} from {}: {action:?}",
+            question.qname(),
+            request.client_addr()
+        );
+        self.metrics.inc(action);
+
+        let response = match action {
+            RpzAction::Passthru => return ControlFlow::Continue(()),
+            RpzAction::Drop => return ControlFlow::Break(None),
+            action => Self::mk_response(
+                msg,
+                &question.qname(),
+                question.qtype(),
+                action,
+                policies.local_data_ttl,
+            ),
+        };
+
+        let response = response.unwrap_or_else(|err| {
+            warn!("Failed to build RPZ policy response: {err}");
+            mk_error_response(msg, OptRcode::SERVFAIL)
+        });
+
+        ControlFlow::Break(Some(response))
+    }
+
+    /// Build the response for a policy with the given action.
+    fn mk_response(
+        msg: &Message<RequestOctets>,
+        qname: &impl ToName,
+        qtype: Rtype,
+        action: &RpzAction,
+        ttl: Ttl,
+    ) -> Result<AdditionalBuilder<StreamTarget<NextSvc::Target>>, PushError>
+    {
+        let rcode = match action {
+            RpzAction::NxDomain => Rcode::NXDOMAIN,
+            _ => Rcode::NOERROR,
+        };
+        let mut answer = mk_builder_for_target().start_answer(msg, rcode)?;
+
+        match action {
+            RpzAction::Redirect(addrs) => {
+                for addr in addrs {
+                    match (addr, qtype) {
+                        (IpAddr::V4(addr), Rtype::A) => {
+                            answer.push((qname, ttl, A::new(*addr)))?
+                        }
+                        (IpAddr::V6(addr), Rtype::AAAA) => {
+                            answer.push((qname, ttl, Aaaa::new(*addr)))?
+                        }
+                        _ => {}
+                    }
+                }
+            }
+            RpzAction::Cname(target) => {
+                answer.push((qname, ttl, Cname::new(target)))?;
+            }
+            _ => {}
+        }
+
+        Ok(answer.additional())
+    }
+}
+
+//--- Service
+
+impl<RequestOctets, NextSvc, RequestMeta> Service<RequestOctets, RequestMeta>
+    for RpzMiddlewareSvc<RequestOctets, NextSvc, RequestMeta>
+where
+    RequestOctets: Octets + Send + Sync + 'static + Unpin,
+    RequestMeta: Clone + Default,
+    NextSvc: Service<RequestOctets, RequestMeta>,
+    NextSvc::Target: Composer + Default,
+    NextSvc::Future: Unpin,
+{
+    type Target = NextSvc::Target;
+    type Stream = MiddlewareStream<
+        NextSvc::Future,
+        NextSvc::Stream,
+        NextSvc::Stream,
+        Iter<std::option::IntoIter<<NextSvc::Stream as Stream>::Item>>,
+        <NextSvc::Stream as Stream>::Item,
+    >;
+    type Future = Ready<Self::Stream>;
+
+    fn call(
+        &self,
+        request: Request<RequestOctets, RequestMeta>,
+    ) -> Self::Future {
+        match self.preprocess(&request) {
+            ControlFlow::Continue(()) => {
+                let svc_call_fut = self.next_svc.call(request);
+                ready(MiddlewareStream::IdentityFuture(svc_call_fut))
+            }
+            ControlFlow::Break(response) => {
+                let item =
+                    response.map(|response| Ok(CallResult::new(response)));
+                ready(MiddlewareStream::Result(iter(item)))
+            }
+        }
+    }
+}
+
+//============ Tests =========================================================
+
+#[cfg(test)]
+mod tests {
+    use crate::base::iana::{Class, Rcode};
+    use crate::base::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+    use crate::base::Rtype;
+    use crate::net::server::middleware::test_helpers::{
+        answer, mk_request, name, process, service, try_process,
+    };
+
+    use super::{RpzAction, RpzMiddlewareSvc, RpzPolicySet};
+
+    //------------ Tests -----------------------------------------------------
+
+    #[test]
+    fn exact_trigger_beats_wildcard() {
+        let policies = policies();
+        assert_eq!(
+            policies.lookup(&name("good.blocked.example")),
+            Some(&RpzAction::Passthru)
+        );
+        assert_eq!(
+            policies.lookup(&name("WWW.Blocked.Example")),
+            Some(&RpzAction::NxDomain)
+        );
+        assert_eq!(
+            policies.lookup(&name("a.b.blocked.example")),
+            Some(&RpzAction::NxDomain)
+        );
+        // A wildcard doesn't match the name it is below.
+        assert_eq!(policies.lookup(&name("blocked.example")), None);
+        assert_eq!(policies.lookup(&name("example")), None);
+    }
+
+    #[tokio::test]
+    async fn nxdomain_policy() {
+        let svc = RpzMiddlewareSvc::new(service(), policies());
+        let response = process(&svc, "www.blocked.example", Rtype::A).await;
+        assert_eq!(response.header().rcode(), Rcode::NXDOMAIN);
+        assert_eq!(response.header_counts().ancount(), 0);
+        assert_eq!(svc.metrics().num_nxdomain(), 1);
+        assert_eq!(svc.metrics().num_hits(), 1);
+    }
+
+    #[tokio::test]
+    async fn nodata_policy() {
+        let svc = RpzMiddlewareSvc::new(service(), policies());
+        let response = process(&svc, "nodata.example", Rtype::A).await;
+        assert_eq!(response.header().rcode(), Rcode::NOERROR);
+        assert_eq!(response.header_counts().ancount(), 0);
+        assert_eq!(svc.metrics().num_nodata(), 1);
+    }
+
+    #[tokio::test]
+    async fn drop_policy() {
+        let svc = RpzMiddlewareSvc::new(service(), policies());
+        let request =
+            mk_request("drop.example", Rtype::A, Class::IN, "127.0.0.1:53");
+        assert!(try_process(&svc, request).await.is_none());
+        assert_eq!(svc.metrics().num_dropped(), 1);
+    }
+
+    #[tokio::test]
+    async fn passthru_policy() {
+        let svc = RpzMiddlewareSvc::new(service(), policies());
+        let response = process(&svc, "good.blocked.example", Rtype::A).await;
+        assert_eq!(answer(&response), ["A 192.0.2.1"]);
+        assert_eq!(svc.metrics().num_passthru(), 1);
+    }
+
+    #[tokio::test]
+    async fn redirect_policy() {
+        let svc = RpzMiddlewareSvc::new(service(), policies());
+        let response = process(&svc, "redirect.example", Rtype::A).await;
+        assert_eq!(answer(&response), ["A 198.51.100.1"]);
+        let response = process(&svc, "redirect.example", Rtype::AAAA).await;
+        assert_eq!(answer(&response), ["AAAA 2001:db8::1"]);
+        let response = process(&svc, "redirect.example", Rtype::MX).await;
+        assert!(answer(&response).is_empty());
+        assert_eq!(svc.metrics().num_redirected(), 3);
+    }
+
+    #[tokio::test]
+    async fn cname_policy() {
+        let svc = RpzMiddlewareSvc::new(service(), policies());
+        let response = process(&svc, "cname.example", Rtype::A).await;
+        assert_eq!(answer(&response), ["CNAME garden.example.net."]);
+        assert_eq!(svc.metrics().num_cname(), 1);
+    }
+
+    #[tokio::test]
+    async fn policies_can_be_replaced() {
+        let svc = RpzMiddlewareSvc::new(service(), RpzPolicySet::new());
+        let response = process(&svc, "www.blocked.example", Rtype::A).await;
+        assert_eq!(answer(&response), ["A 192.0.2.1"]);
+        assert_eq!(svc.metrics().num_hits(), 0);
+
+        // Replacing the policies of a clone affects the original too.
+        svc.clone().set_policies(policies());
+        let response = process(&svc, "www.blocked.example", Rtype::A).await;
+        assert_eq!(response.header().rcode(), Rcode::NXDOMAIN);
+        assert_eq!(svc.metrics().num_hits(), 1);
+    }
+
+    //------------ Helper functions ------------------------------------------
+
+    fn policies() -> RpzPolicySet {
+        RpzPolicySet::new()
+            .with_policy(&name("*.blocked.example"), RpzAction::NxDomain)
+            .with_policy(&name("good.blocked.example"), RpzAction::Passthru)
+            .with_policy(&name("nodata.example"), RpzAction::NoData)
+            .with_policy(&name("drop.example"), RpzAction::Drop)
+            .with_policy(
+                &name("redirect.example"),
+                RpzAction::Redirect(vec![
+                    IpAddr::V4(Ipv4Addr::new(198, 51, 100, 1)),
+                    IpAddr::V6(Ipv6Addr::new(
+                        0x2001, 0xdb8, 0, 0, 0, 0, 0, 1,
+                    )),
+                ]),
+            )
+            .with_policy(
+                &name("cname.example"),
+                RpzAction::Cname(name("garden.example.net")),
+            )
+    }
+}
@@ -0,0 +1,693 @@
+//! Response Rate Limiting (RRL).
+//!
+//! UDP based DNS can be abused for reflection and amplification attacks: an
+//! attacker sends requests with the forged source address of the victim and
+//! the server sends its, often much larger, responses to the victim.
+//!
+//! Response rate limiting mitigates this by limiting the rate at which
+//! identical responses are sent to the same client network. Unlike request
+//! rate limiting this doesn't penalize legitimate clients that send many
+//! different requests, and as responses that exceed the limit are either
+//! dropped or replaced by small truncated responses ("slipped"), legitimate
+//! clients whose address is being forged can still get an answer by
+//! retrying over TCP.
+//!
+//! The [`RrlMiddlewareSvc`] implements the algorithm [used by BIND] and
+//! other name servers. Counts of limited responses are tracked in
+//! [`RrlMetrics`].
+//!
+//! [used by BIND]: https://kb.isc.org/docs/aa-00994
+use core::future::{ready, Ready};
+use core::marker::PhantomData;
+use core::time::Duration;
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use bytes::Bytes;
+use octseq::Octets;
+use tokio::time::Instant;
+use tracing::{debug, trace, warn};
+
+use crate::base::iana::Rcode;
+use crate::base::message_builder::{AdditionalBuilder, PushError};
+use crate::base::wire::{Composer, ParseError};
+use crate::base::{Message, Name, Rtype, StreamTarget, ToName};
+use crate::net::server::message::{Request, TransportSpecificContext};
+use crate::net::server::service::{Service, ServiceResult};
+use crate::net::server::util::{
+    client_prefix, mk_builder_for_target, IpPrefix,
+};
+
+use super::stream::PostprocessingStream;
+
+//----------- Constants -------------------------------------------------------
+
+/// The default number of identical responses per second sent to a client
+/// network.
+const DEFAULT_LEAK_RATE: u32 = 5;
+
+/// The default number of limited responses per slipped response.
+const DEFAULT_SLIP_RATE: u32 = 2;
+
+/// The default prefix length used to group IPv4 clients into networks.
+const DEFAULT_IPV4_PREFIX_LEN: u8 = 24;
+
+/// The default prefix length used to group IPv6 clients into networks.
+const DEFAULT_IPV6_PREFIX_LEN: u8 = 56;
+
+/// The default maximum number of response signatures tracked.
+const DEFAULT_MAX_ENTRIES: usize = 100_000;
+
+/// The period over which a bucket is refilled completely.
+const REFILL_PERIOD: Duration = Duration::from_secs(1);
+
+//----------- RrlConfig -------------------------------------------------------
+
+/// Configuration for response rate limiting.
+#[derive(Clone, Copy, Debug)]
+struct RrlConfig {
+    /// The number of identical responses per second sent to a client
+    /// network.
+    leak_rate: u32,
+
+    /// Every how manieth limited response is slipped instead of dropped.
+    slip_rate: u32,
+
+    /// The prefix length used to group IPv4 clients into networks.
+    ipv4_prefix_len: u8,
+
+    /// The prefix length used to group IPv6 clients into networks.
+    ipv6_prefix_len: u8,
+
+    /// The maximum number of response signatures tracked.
+    max_entries: usize,
+}
+
+impl Default for RrlConfig {
+    fn default() -> Self {
+        Self {
+            leak_rate: DEFAULT_LEAK_RATE,
+            slip_rate: DEFAULT_SLIP_RATE,
+            ipv4_prefix_len: DEFAULT_IPV4_PREFIX_LEN,
+            ipv6_prefix_len: DEFAULT_IPV6_PREFIX_LEN,
+            max_entries: DEFAULT_MAX_ENTRIES,
+        }
+    }
+}
+
+//----------- RrlMetrics ------------------------------------------------------
+
+/// Counts of responses affected by response rate limiting.
+#[derive(Debug, Default)]
+pub struct RrlMetrics {
+    /// The number of responses dropped.
+    num_dropped: AtomicUsize,
+
+    /// The number of responses replaced by a truncated response.
+    num_slipped: AtomicUsize,
+}
+
+impl RrlMetrics {
+    /// The number of responses dropped.
+    pub fn num_dropped(&self) -> usize {
+        self.num_dropped.load(Ordering::Relaxed)
+    }
+
+    /// The number of responses replaced by a truncated response.
+    pub fn num_slipped(&self) -> usize {
+        self.num_slipped.load(Ordering::Relaxed)
+    }
+
+    /// The total number of responses that exceeded the rate limit.
+    pub fn num_limited(&self) -> usize {
+        self.num_dropped() + self.num_slipped()
+    }
+}
+
+//----------- RrlMiddlewareSvc ------------------------------------------------
+
+/// A middleware service for limiting the rate of identical responses.
+///
+/// Responses to UDP requests are grouped by the network of the client, i.e.
+/// its address truncated to a configurable prefix length, and by their
+/// signature:
+///
+/// - Positive answers are identified by the query name and type.
+/// - Negative answers, i.e. NXDOMAIN and NODATA responses, and referrals
+///   are identified by the owner of the SOA or NS record in the authority
+///   section, i.e. by the zone or delegation. This prevents attackers from
+///   evading the limit by querying random names.
+/// - Other error responses are identified only by their RCODE.
+///
+/// Each group has a bucket of tokens that refills at the [leak rate] up to
+/// one second's worth of tokens. Sending a response takes a token from the
+/// bucket of its group. If the bucket is empty the response is limited:
+/// every [slip rate]th limited response is replaced by a truncated
+/// response, prompting legitimate clients to retry over TCP, and all other
+/// limited responses are dropped.
+///
+/// Responses to requests received via TCP are never limited as TCP cannot
+/// be used for reflection attacks.
+///
+/// [leak rate]: Self::with_leak_rate
+/// [slip rate]: Self::with_slip_rate
+#[derive(Clone, Debug)]
+pub struct RrlMiddlewareSvc<RequestOctets, NextSvc, RequestMeta> {
+    /// The upstream [`Service`] to pass requests to and receive responses
+    /// from.
+    next_svc: NextSvc,
+
+    /// The rate limiting state.
+    ///
+    /// Shared between clones of this service.
+    state: Arc<RrlState>,
+
+    _phantom: PhantomData<(RequestOctets, RequestMeta)>,
+}
+
+impl<RequestOctets, NextSvc, RequestMeta>
+    RrlMiddlewareSvc<RequestOctets, NextSvc, RequestMeta>
+{
+    /// Creates an instance of this middleware service.
+    ///
+    /// By default 5 identical responses per second are sent to a client
+    /// network, every second limited response is slipped and clients are
+    /// grouped into IPv4 /24 and IPv6 /56 networks.
+    #[must_use]
+    pub fn new(next_svc: NextSvc) -> Self {
+        Self {
+            next_svc,
+            state: Default::default(),
+            _phantom: PhantomData,
+        }
+    }
+
+    /// Sets the number of identical responses per second sent to a client
+    /// network.
+    ///
+    /// This is the rate at which the bucket of a group of identical
+    /// responses refills. A rate of zero disables rate limiting.
+    #[must_use]
+    pub fn with_leak_rate(mut self, responses_per_second: u32) -> Self {
+        self.update_config(|config| config.leak_rate = responses_per_second);
+        self
+    }
+
+    /// Sets every how manieth limited response is slipped.
+    ///
+    /// A slipped response is replaced by an empty response with the TC flag
+    /// set. A rate of zero drops all limited responses, a rate of one
+    /// slips all of them.
+    #[must_use]
+    pub fn with_slip_rate(mut self, slip_rate: u32) -> Self {
+        self.update_config(|config| config.slip_rate = slip_rate);
+        self
+    }
+
+    /// Sets the prefix lengths used to group clients into networks.
+    ///
+    /// Prefix lengths larger than the address length are capped.
+    #[must_use]
+    pub fn with_prefix_lens(mut self, ipv4: u8, ipv6: u8) -> Self {
+        self.update_config(|config| {
+            config.ipv4_prefix_len = ipv4.min(32);
+            config.ipv6_prefix_len = ipv6.min(128);
+        });
+        self
+    }
+
+    /// Sets the maximum number of response groups tracked.
+    ///
+    /// Responses of new groups are not limited while the maximum number of
+    /// groups is tracked and none of them are idle.
+    #[must_use]
+    pub fn with_max_entries(mut self, max_entries: usize) -> Self {
+        self.update_config(|config| config.max_entries = max_entries);
+        self
+    }
+
+    /// Counts of responses affected by rate limiting.
+    pub fn metrics(&self) -> Arc<RrlMetrics> {
+        self.state.metrics.clone()
+    }
+
+    /// Updates the configuration, resetting all buckets.
+    fn update_config(&mut self, op: impl FnOnce(&mut RrlConfig)) {
+        let mut config = self.state.config;
+        op(&mut config);
+        self.state = Arc::new(RrlState {
+            config,
+            buckets: Default::default(),
+            metrics: self.state.metrics.clone(),
+        });
+    }
+}
+
+impl<RequestOctets, NextSvc, RequestMeta>
+    RrlMiddlewareSvc<RequestOctets, NextSvc, RequestMeta>
+where
+    RequestOctets: Octets + Send + Sync + Unpin,
+    NextSvc: Service<RequestOctets, RequestMeta>,
+    NextSvc::Target: Composer + Default,
+    RequestMeta: Clone + Default,
+{
+    fn map_stream_item(
+        request: Request<RequestOctets, RequestMeta>,
+        mut stream_item: ServiceResult<NextSvc::Target>,
+        state: &mut Arc<RrlState>,
+    ) -> ServiceResult<NextSvc::Target> {
+        if !matches!(
+            request.transport_ctx(),
+            TransportSpecificContext::Udp(_)
+        ) {
+            return stream_item;
+        }
+
+        if let Ok(cr) = &mut stream_item {
+            if let Some(response) = cr.response_mut() {
+                let prefix = client_prefix(
+                    &request.client_addr(),
+                    state.config.ipv4_prefix_len,
+                    state.config.ipv6_prefix_len,
+                );
+                let Some(key) =
+                    RrlKey::from_response(prefix, &response.as_message())
+                else {
+                    return stream_item;
+                };
+
+                match state.check(key) {
+                    RrlVerdict::Send => {}
+                    RrlVerdict::Slip => {
+                        trace!("Slipping rate limited response");
+                        state
+                            .metrics
+                            .num_slipped
+                            .fetch_add(1, Ordering::Relaxed);
+                        match Self::mk_slip_response(response) {
+                            Ok(slipped) => *response = slipped,
+                            Err(err) => {
+                                warn!(
+                                    "Unable to create truncated response: {err}"
+                                );
+                                cr.take_response();
+                            }
+                        }
+                    }
+                    RrlVerdict::Drop => {
+                        trace!("Dropping rate limited response");
+                        state
+                            .metrics
+                            .num_dropped
+                            .fetch_add(1, Ordering::Relaxed);
+                        cr.take_response();
+                    }
+                }
+            }
+        }
+
+        stream_item
+    }
+
+    /// Creates a truncated copy of the given response.
+    ///
+    /// The copy has the header, question and OPT record of the response but
+    /// no other records.
+    fn mk_slip_response(
+        response: &AdditionalBuilder<StreamTarget<NextSvc::Target>>,
+    ) -> Result<AdditionalBuilder<StreamTarget<NextSvc::Target>>, SlipError>
+    {
+        let source = response.as_message();
+        let mut target = mk_builder_for_target();
+        *target.header_mut() = source.header();
+        target.header_mut().set_tc(true);
+
+        let mut target = target.question();
+        for question in source.question() {
+            target.push(question?)?;
+        }
+
+        let mut target = target.additional();
+        if let Some(opt) = source.opt() {
+            target.push(opt.as_record())?;
+        }
+
+        Ok(target)
+    }
+}
+
+//--- Service
+
+impl<RequestOctets, NextSvc, RequestMeta> Service<RequestOctets, RequestMeta>
+    for RrlMiddlewareSvc<RequestOctets, NextSvc, RequestMeta>
+where
+    RequestOctets: Octets + Send + Sync + 'static + Unpin,
+    NextSvc: Service<RequestOctets, RequestMeta>,
+    NextSvc::Future: Unpin,
+    NextSvc::Target: Composer + Default,
+    RequestMeta: Clone + Default + Unpin,
+{
+    type Target = NextSvc::Target;
+    type Stream = PostprocessingStream<
+        RequestOctets,
+        NextSvc::Future,
+        NextSvc::Stream,
+        RequestMeta,
+        Arc<RrlState>,
+    >;
+    type Future = Ready<Self::Stream>;
+
+    fn call(
+        &self,
+        request: Request<RequestOctets, RequestMeta>,
+    ) -> Self::Future {
+        let svc_call_fut = self.next_svc.call(request.clone());
+        ready(PostprocessingStream::new(
+            svc_call_fut,
+            request,
+            self.state.clone(),
+            Self::map_stream_item,
+        ))
+    }
+}
+
+//----------- RrlState --------------------------------------------------------
+
+/// The state of response rate limiting.
+///
+/// Shared by all clones of an [`RrlMiddlewareSvc`] and the response streams
+/// they produce.
+#[derive(Debug, Default)]
+pub struct RrlState {
+    /// The configuration.
+    config: RrlConfig,
+
+    /// The buckets of the tracked response groups.
+    buckets: Mutex<HashMap<RrlKey, Bucket>>,
+
+    /// Counts of limited responses.
+    metrics: Arc<RrlMetrics>,
+}
+
+impl RrlState {
+    /// Takes a token for a response of the given group.
+    fn check(&self, key: RrlKey) -> RrlVerdict {
+        let rate = self.config.leak_rate;
+        if rate == 0 {
+            return RrlVerdict::Send;
+        }
+        let rate = f64::from(rate);
+        let now = Instant::now();
+
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= self.config.max_entries
+            && !buckets.contains_key(&key)
+        {
+            // Forget buckets that would have refilled completely by now.
+            buckets.retain(|_, bucket| {
+                now.duration_since(bucket.updated) < REFILL_PERIOD
+            });
+            if buckets.len() >= self.config.max_entries {
+                debug!("Too many response groups, not rate limiting");
+                return RrlVerdict::Send;
+            }
+        }
+
+        let bucket = buckets.entry(key).or_insert(Bucket {
+            tokens: rate,
+            updated: now,
+            num_limited: 0,
+        });
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * rate).min(rate);
+        bucket.updated = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            return RrlVerdict::Send;
+        }
+
+        bucket.num_limited = bucket.num_limited.wrapping_add(1);
+        let slip_rate = self.config.slip_rate;
+        if slip_rate != 0 && bucket.num_limited % slip_rate == 0 {
+            RrlVerdict::Slip
+        } else {
+            RrlVerdict::Drop
+        }
+    }
+}
+
+//----------- RrlKey ----------------------------------------------------------
+
+/// The group of identical responses a response belongs to.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+struct RrlKey {
+    /// The network of the client.
+    prefix: IpPrefix,
+
+    /// The RCODE of the response.
+    rcode: Rcode,
+
+    /// The name identifying the response, if any.
+    name: Option<Name<Bytes>>,
+
+    /// The query type for positive answers.
+    qtype: Option<Rtype>,
+}
+
+impl RrlKey {
+    /// Determines the group of the given response.
+    ///
+    /// Returns `None` if the response can't be parsed.
+    fn from_response<Octs: Octets + ?Sized>(
+        prefix: IpPrefix,
+        msg: &Message<Octs>,
+    ) -> Option<Self> {
+        let rcode = msg.header().rcode();
+        let question = msg.first_question()?;
+        let (name, qtype) = match rcode {
+            Rcode::NOERROR if msg.header_counts().ancount() > 0 => {
+                (Some(question.qname().to_name()), Some(question.qtype()))
+            }
+            Rcode::NOERROR | Rcode::NXDOMAIN => {
+                let name = msg.authority().ok()?.find_map(|record| {
+                    let record = record.ok()?;
+                    matches!(record.rtype(), Rtype::SOA | Rtype::NS)
+                        .then(|| record.owner().to_name())
+                });
+                // Without an authority record, fall back to the query name.
+                (
+                    Some(name.unwrap_or_else(|| question.qname().to_name())),
+                    None,
+                )
+            }
+            _ => (None, None),
+        };
+        Some(Self {
+            prefix,
+            rcode,
+            name,
+            qtype,
+        })
+    }
+}
+
+//----------- Bucket ----------------------------------------------------------
+
+/// The token bucket of a group of identical responses.
+#[derive(Clone, Debug)]
+struct Bucket {
+    /// The number of tokens currently in the bucket.
+    tokens: f64,
+
+    /// When the bucket was last updated.
+    updated: Instant,
+
+    /// The number of responses limited since the bucket was created.
+    num_limited: u32,
+}
+
+//----------- RrlVerdict ------------------------------------------------------
+
+/// What to do with a response.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum RrlVerdict {
+    /// Send the response.
+    Send,
+
+    /// Send a truncated response instead.
+    Slip,
+
+    /// Send nothing.
+    Drop,
+}
+
+//----------- SlipError -------------------------------------------------------
+
+/// A truncated response could not be created.
+#[derive(Clone, Copy, Debug)]
+enum SlipError {
+    ParseError(ParseError),
+    PushError(PushError),
+}
+
+impl core::fmt::Display for SlipError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            SlipError::ParseError(err) => write!(f, "{err:?}"),
+            SlipError::PushError(err) => write!(f, "{err}"),
+        }
+    }
+}
+
+impl From<ParseError> for SlipError {
+    fn from(err: ParseError) -> Self {
+        Self::ParseError(err)
+    }
+}
+
+impl From<PushError> for SlipError {
+    fn from(err: PushError) -> Self {
+        Self::PushError(err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::time::Duration;
+
+    use std::vec::Vec;
+
+    use crate::net::server::message::NonUdpTransportContext;
+
+    use super::super::test_helpers::{
+        nxdomain_service, try_process_from_ip, try_process_with_ctx,
+        NxdomainService,
+    };
+    use super::RrlMiddlewareSvc;
+
+    //------------ Tests -----------------------------------------------------
+
+    #[tokio::test(start_paused = true)]
+    async fn limited_responses_are_dropped_or_slipped() {
+        let svc = mk_svc().with_leak_rate(2).with_slip_rate(2);
+
+        let mut outcomes = Vec::new();
+        for _ in 0..6 {
+            let response =
+                try_process_from_ip(&svc, "www.example.com", "192.0.2.1")
+                    .await;
+            outcomes.push(response.map(|msg| {
+                let slipped = msg.header().tc();
+                if slipped {
+                    assert_eq!(msg.header_counts().qdcount(), 1);
+                    assert_eq!(msg.header_counts().ancount(), 0);
+                } else {
+                    assert_eq!(msg.header_counts().ancount(), 1);
+                }
+                slipped
+            }));
+        }
+
+        // Two responses fit the bucket, then every second limited response
+        // is slipped and the others are dropped.
+        assert_eq!(
+            outcomes,
+            [Some(false), Some(false), None, Some(true), None, Some(true)]
+        );
+        assert_eq!(svc.metrics().num_dropped(), 2);
+        assert_eq!(svc.metrics().num_slipped(), 2);
+        assert_eq!(svc.metrics().num_limited(), 4);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn clients_are_grouped_by_network() {
+        let svc = mk_svc().with_leak_rate(1).with_slip_rate(0);
+
+        assert!(try_process_from_ip(&svc, "www.example.com", "192.0.2.1")
+            .await
+            .is_some());
+        assert!(try_process_from_ip(&svc, "www.example.com", "192.0.2.200")
+            .await
+            .is_none());
+        assert!(try_process_from_ip(&svc, "www.example.com", "198.51.100.1")
+            .await
+            .is_some());
+        assert!(try_process_from_ip(
+            &svc,
+            "www.example.com",
+            "2001:db8:0:ff::1"
+        )
+        .await
+        .is_some());
+        assert!(try_process_from_ip(
+            &svc,
+            "www.example.com",
+            "2001:db8:0:1::1"
+        )
+        .await
+        .is_none());
+
+        // Different query names are different responses.
+        assert!(try_process_from_ip(&svc, "ftp.example.com", "192.0.2.1")
+            .await
+            .is_some());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn negative_answers_are_grouped_by_zone() {
+        let svc = mk_svc().with_leak_rate(1).with_slip_rate(0);
+
+        assert!(try_process_from_ip(&svc, "nx1.example.com", "192.0.2.1")
+            .await
+            .is_some());
+        assert!(try_process_from_ip(&svc, "nx2.example.com", "192.0.2.1")
+            .await
+            .is_none());
+        assert!(try_process_from_ip(&svc, "nx1.example.net", "192.0.2.1")
+            .await
+            .is_some());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn buckets_refill() {
+        let svc = mk_svc().with_leak_rate(1).with_slip_rate(0);
+
+        assert!(try_process_from_ip(&svc, "www.example.com", "192.0.2.1")
+            .await
+            .is_some());
+        assert!(try_process_from_ip(&svc, "www.example.com", "192.0.2.1")
+            .await
+            .is_none());
+
+        tokio::time::advance(Duration::from_secs(1)).await;
+        assert!(try_process_from_ip(&svc, "www.example.com", "192.0.2.1")
+            .await
+            .is_some());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn tcp_responses_are_not_limited() {
+        let svc = mk_svc().with_leak_rate(1).with_slip_rate(0);
+
+        for _ in 0..3 {
+            let response = try_process_with_ctx(
+                &svc,
+                "www.example.com",
+                "192.0.2.1",
+                NonUdpTransportContext::new(None).into(),
+            )
+            .await;
+            assert!(response.is_some());
+        }
+        assert_eq!(svc.metrics().num_limited(), 0);
+    }
+
+    //------------ Helper functions ------------------------------------------
+
+    fn mk_svc() -> RrlMiddlewareSvc<Vec<u8>, NxdomainService, ()> {
+        RrlMiddlewareSvc::new(nxdomain_service())
+    }
+}
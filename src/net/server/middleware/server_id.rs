@@ -0,0 +1,383 @@
+//! Answering CH class server identification queries.
+//!
+//! Name servers such as BIND, NSD and Knot answer TXT queries in the CH
+//! (Chaos) class for a few well known names with information identifying
+//! themselves, and monitoring tools commonly rely on this:
+//!
+//! - `version.bind.` and `version.server.` for the software version,
+//! - `hostname.bind.` for the host name of the server,
+//! - `id.server.` for the identity of the server as per [RFC 4892].
+//!
+//! The [`ServerIdMiddlewareSvc`] answers these queries with the values of a
+//! [`ServerIdConfig`] and passes all other requests on unmodified.
+//!
+//! No values are disclosed by default. In particular disclosing the version
+//! makes it easier to find servers affected by known vulnerabilities, so
+//! consider whether that is desired before setting it.
+//!
+//! [RFC 4892]: https://www.rfc-editor.org/rfc/rfc4892.html
+use core::future::{ready, Ready};
+use core::marker::PhantomData;
+use core::ops::ControlFlow;
+use core::str::FromStr;
+
+use std::string::String;
+use std::sync::Arc;
+use std::vec::Vec;
+
+use futures_util::stream::{once, Once};
+use octseq::Octets;
+use tracing::{debug, warn};
+
+use crate::base::iana::{Class, OptRcode};
+use crate::base::message_builder::AdditionalBuilder;
+use crate::base::wire::Composer;
+use crate::base::{Name, StreamTarget, ToName};
+use crate::net::server::message::Request;
+use crate::net::server::middleware::diagnostics::mk_txt_response;
+use crate::net::server::middleware::stream::MiddlewareStream;
+use crate::net::server::service::{CallResult, Service, ServiceResult};
+use crate::net::server::util::mk_error_response;
+
+//----------- ServerIdConfig --------------------------------------------------
+
+/// The values answered by a [`ServerIdMiddlewareSvc`].
+///
+/// By default no values are set and queries for all names are refused.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct ServerIdConfig {
+    /// The answer to `version.bind.` and `version.server.` queries.
+    version: Option<String>,
+
+    /// The answer to `hostname.bind.` queries.
+    hostname: Option<String>,
+
+    /// The answer to `id.server.` queries.
+    id: Option<String>,
+}
+
+impl ServerIdConfig {
+    /// Creates a configuration disclosing nothing.
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the version answered for `version.bind.` and `version.server.`.
+    #[must_use]
+    pub fn with_version(mut self, version: impl Into<String>) -> Self {
+        self.version = Some(version.into());
+        self
+    }
+
+    /// Stops disclosing the version.
+    ///
+    /// Queries for `version.bind.` and `version.server.` are refused.
+    #[must_use]
+    pub fn without_version(mut self) -> Self {
+        self.version = None;
+        self
+    }
+
+    /// Sets the host name answered for `hostname.bind.`.
+    ///
+    /// Unless an identity is set via [`with_id()`][Self::with_id], this
+    /// is also answered for `id.server.`.
+    #[must_use]
+    pub fn with_hostname(mut self, hostname: impl Into<String>) -> Self {
+        self.hostname = Some(hostname.into());
+        self
+    }
+
+    /// Sets the identity answered for `id.server.`.
+    #[must_use]
+    pub fn with_id(mut self, id: impl Into<String>) -> Self {
+        self.id = Some(id.into());
+        self
+    }
+
+    /// The version answered, if any.
+    pub fn version(&self) -> Option<&str> {
+        self.version.as_deref()
+    }
+
+    /// The host name answered, if any.
+    pub fn hostname(&self) -> Option<&str> {
+        self.hostname.as_deref()
+    }
+
+    /// The identity answered, if any.
+    pub fn id(&self) -> Option<&str> {
+        self.id.as_deref().or(self.hostname())
+    }
+}
+
+//----------- ServerIdMiddlewareSvc -------------------------------------------
+
+/// A middleware service answering CH class server identification queries.
+///
+/// Queries in class CH for one of the names listed in the [module
+/// documentation][self] are answered without invoking the next service:
+/// TXT and ANY queries with a TXT record holding the configured value and
+/// queries for other types with an empty NOERROR answer. If no value is
+/// configured for the name, the query is refused. All other requests are
+/// passed to the next service unmodified.
+///
+/// Responses synthesized by this service don't include an OPT record, place
+/// an [`EdnsMiddlewareSvc`] in front of this service to add one where
+/// needed.
+///
+/// [`EdnsMiddlewareSvc`]: super::edns::EdnsMiddlewareSvc
+#[derive(Clone, Debug)]
+pub struct ServerIdMiddlewareSvc<RequestOctets, NextSvc, RequestMeta> {
+    /// The upstream [`Service`] to pass requests to and receive responses
+    /// from.
+    next_svc: NextSvc,
+
+    /// The values to answer with.
+    config: Arc<ServerIdConfig>,
+
+    /// The names answered, with the value they are answered with.
+    names: Arc<Vec<(Name<Vec<u8>>, ServerIdName)>>,
+
+    _phantom: PhantomData<(RequestOctets, RequestMeta)>,
+}
+
+impl<RequestOctets, NextSvc, RequestMeta>
+    ServerIdMiddlewareSvc<RequestOctets, NextSvc, RequestMeta>
+{
+    /// Creates an instance of this middleware service.
+    #[must_use]
+    pub fn new(next_svc: NextSvc, config: ServerIdConfig) -> Self {
+        let names = ServerIdName::ALL
+            .iter()
+            .map(|(name, which)| (Name::from_str(name).unwrap(), *which))
+            .collect();
+        Self {
+            next_svc,
+            config: Arc::new(config),
+            names: Arc::new(names),
+            _phantom: PhantomData,
+        }
+    }
+}
+
+impl<RequestOctets, NextSvc, RequestMeta>
+    ServerIdMiddlewareSvc<RequestOctets, NextSvc, RequestMeta>
+where
+    RequestOctets: Octets + Send + Sync + Unpin,
+    RequestMeta: Clone + Default,
+    NextSvc: Service<RequestOctets, RequestMeta>,
+    NextSvc::Target: Composer + Default,
+{
+    /// Answer the request if it is a server identification query.
+    fn preprocess(
+        &self,
+        request: &Request<RequestOctets, RequestMeta>,
+    ) -> ControlFlow<AdditionalBuilder<StreamTarget<NextSvc::Target>>> {
+        let msg = request.message();
+        let Ok(question) = msg.sole_question() else {
+            return ControlFlow::Continue(());
+        };
+        if question.qclass() != Class::CH {
+            return ControlFlow::Continue(());
+        }
+        let Some(which) = self.names.iter().find_map(|(name, which)| {
+            question.qname().name_eq(name).then_some(*which)
+        }) else {
+            return ControlFlow::Continue(());
+        };
+
+        let Some(value) = which.value(&self.config) else {
+            debug!(
+                "Refusing undisclosed {} query from {}",
+                question.qname(),
+                request.client_addr()
+            );
+            return ControlFlow::Break(mk_error_response(
+                msg,
+                OptRcode::REFUSED,
+            ));
+        };
+
+        let response = mk_txt_response(
+            msg,
+            &question.qname(),
+            Class::CH,
+            question.qtype(),
+            [value],
+        )
+        .unwrap_or_else(|err| {
+            warn!("Failed to build server identification response: {err}");
+            mk_error_response(msg, OptRcode::SERVFAIL)
+        });
+
+        ControlFlow::Break(response)
+    }
+}
+
+//--- Service
+
+impl<RequestOctets, NextSvc, RequestMeta> Service<RequestOctets, RequestMeta>
+    for ServerIdMiddlewareSvc<RequestOctets, NextSvc, RequestMeta>
+where
+    RequestOctets: Octets + Send + Sync + 'static + Unpin,
+    RequestMeta: Clone + Default,
+    NextSvc: Service<RequestOctets, RequestMeta>,
+    NextSvc::Target: Composer + Default,
+    NextSvc::Future: Unpin,
+{
+    type Target = NextSvc::Target;
+    type Stream = MiddlewareStream<
+        NextSvc::Future,
+        NextSvc::Stream,
+        NextSvc::Stream,
+        Once<Ready<ServiceResult<Self::Target>>>,
+        ServiceResult<Self::Target>,
+    >;
+    type Future = Ready<Self::Stream>;
+
+    fn call(
+        &self,
+        request: Request<RequestOctets, RequestMeta>,
+    ) -> Self::Future {
+        match self.preprocess(&request) {
+            ControlFlow::Continue(()) => {
+                let svc_call_fut = self.next_svc.call(request);
+                ready(MiddlewareStream::IdentityFuture(svc_call_fut))
+            }
+            ControlFlow::Break(response) => ready(MiddlewareStream::Result(
+                once(ready(Ok(CallResult::new(response)))),
+            )),
+        }
+    }
+}
+
+//----------- ServerIdName ----------------------------------------------------
+
+/// The value a server identification name is answered with.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum ServerIdName {
+    /// The version.
+    Version,
+
+    /// The host name.
+    Hostname,
+
+    /// The identity.
+    Id,
+}
+
+impl ServerIdName {
+    /// The names answered and their values.
+    const ALL: &'static [(&'static str, Self)] = &[
+        ("version.bind.", Self::Version),
+        ("version.server.", Self::Version),
+        ("hostname.bind.", Self::Hostname),
+        ("id.server.", Self::Id),
+    ];
+
+    /// Returns the configured value for this name, if any.
+    fn value(self, config: &ServerIdConfig) -> Option<&str> {
+        match self {
+            Self::Version => config.version(),
+            Self::Hostname => config.hostname(),
+            Self::Id => config.id(),
+        }
+    }
+}
+
+//============ Tests =========================================================
+
+#[cfg(test)]
+mod tests {
+    use crate::base::iana::{Class, Rcode};
+    use crate::base::Rtype;
+    use crate::net::server::middleware::test_helpers::{
+        process_in_class, service, txts,
+    };
+
+    use super::{ServerIdConfig, ServerIdMiddlewareSvc};
+
+    //------------ Tests -----------------------------------------------------
+
+    #[tokio::test]
+    async fn configured_values_are_answered() {
+        let config = ServerIdConfig::new()
+            .with_version("example 1.0")
+            .with_hostname("ns1.example");
+        let svc = ServerIdMiddlewareSvc::new(service(), config);
+
+        let response =
+            process_in_class(&svc, "version.bind", Rtype::TXT, Class::CH)
+                .await;
+        assert_eq!(response.header().rcode(), Rcode::NOERROR);
+        assert!(response.header().aa());
+        assert_eq!(txts(&response), ["example 1.0"]);
+
+        let response =
+            process_in_class(&svc, "VERSION.SERVER", Rtype::ANY, Class::CH)
+                .await;
+        assert_eq!(txts(&response), ["example 1.0"]);
+
+        let response =
+            process_in_class(&svc, "hostname.bind", Rtype::TXT, Class::CH)
+                .await;
+        assert_eq!(txts(&response), ["ns1.example"]);
+
+        // Without an explicit identity, the host name is used.
+        let response =
+            process_in_class(&svc, "id.server", Rtype::TXT, Class::CH).await;
+        assert_eq!(txts(&response), ["ns1.example"]);
+
+        // Other types get an empty answer.
+        let response =
+            process_in_class(&svc, "version.bind", Rtype::A, Class::CH).await;
+        assert_eq!(response.header().rcode(), Rcode::NOERROR);
+        assert_eq!(response.answer().unwrap().count(), 0);
+    }
+
+    #[tokio::test]
+    async fn undisclosed_values_are_refused() {
+        let config = ServerIdConfig::new()
+            .with_version("example 1.0")
+            .without_version()
+            .with_id("pop1");
+        let svc = ServerIdMiddlewareSvc::new(service(), config);
+
+        let response =
+            process_in_class(&svc, "version.bind", Rtype::TXT, Class::CH)
+                .await;
+        assert_eq!(response.header().rcode(), Rcode::REFUSED);
+
+        let response =
+            process_in_class(&svc, "hostname.bind", Rtype::TXT, Class::CH)
+                .await;
+        assert_eq!(response.header().rcode(), Rcode::REFUSED);
+
+        let response =
+            process_in_class(&svc, "id.server", Rtype::TXT, Class::CH).await;
+        assert_eq!(txts(&response), ["pop1"]);
+    }
+
+    #[tokio::test]
+    async fn other_queries_are_passed_on() {
+        let config = ServerIdConfig::new().with_version("example 1.0");
+        let svc = ServerIdMiddlewareSvc::new(service(), config);
+
+        // IN class.
+        let response =
+            process_in_class(&svc, "version.bind", Rtype::TXT, Class::IN)
+                .await;
+        assert_eq!(response.answer().unwrap().count(), 1);
+        assert!(txts(&response).is_empty());
+
+        // Other names.
+        let response =
+            process_in_class(&svc, "other.bind", Rtype::TXT, Class::CH).await;
+        assert_eq!(response.answer().unwrap().count(), 1);
+        assert!(txts(&response).is_empty());
+    }
+
+    //------------ Helper functions ------------------------------------------
+}
@@ -0,0 +1,496 @@
+//! Local answers for special-use domain names.
+//!
+//! [RFC 6761] reserves a number of domain names for special purposes and
+//! specifies how name resolution software should treat them. Most notably,
+//! caching resolvers should answer queries for them immediately rather than
+//! querying the global DNS, which would only leak information about the
+//! local network and burden the root servers with queries that cannot be
+//! answered.
+//!
+//! The [`SpecialUseMiddlewareSvc`] answers queries for such names locally
+//! and passes all other queries on unmodified. It comes preloaded with the
+//! names reserved by RFC 6761, see [`SpecialUseNames::rfc6761()`], and can
+//! be given additional names via [`SpecialUseNames`].
+//!
+//! [RFC 6761]: https://www.rfc-editor.org/rfc/rfc6761
+use core::future::{ready, Ready};
+use core::marker::PhantomData;
+use core::ops::ControlFlow;
+use core::str::FromStr;
+
+use std::sync::Arc;
+use std::vec::Vec;
+
+use bytes::Bytes;
+use futures_util::stream::{once, Once};
+use octseq::Octets;
+use tracing::{trace, warn};
+
+use crate::base::iana::{OptRcode, Rcode};
+use crate::base::message_builder::{AdditionalBuilder, PushError};
+use crate::base::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use crate::base::wire::Composer;
+use crate::base::{Message, Name, Rtype, StreamTarget, ToName, Ttl};
+use crate::net::server::message::Request;
+use crate::net::server::middleware::stream::MiddlewareStream;
+use crate::net::server::service::{CallResult, Service, ServiceResult};
+use crate::net::server::util::{mk_builder_for_target, mk_error_response};
+use crate::rdata::{Aaaa, Ptr, A};
+
+//----------- Constants -------------------------------------------------------
+
+/// The TTL of records synthesized for special-use names.
+const SPECIAL_USE_TTL: Ttl = Ttl::from_secs(300);
+
+//----------- SpecialUseResponse ----------------------------------------------
+
+/// The response to queries for a special-use name.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum SpecialUseResponse {
+    /// Answer with NXDOMAIN.
+    NxDomain,
+
+    /// Answer address queries with the given addresses.
+    ///
+    /// A queries are answered with the IPv4 addresses and AAAA queries with
+    /// the IPv6 addresses. Queries for other types, or for which there are
+    /// no addresses of the queried family, are answered with NOERROR and an
+    /// empty answer section.
+    Addresses(Vec<IpAddr>),
+
+    /// Answer PTR queries with the given name.
+    ///
+    /// Queries for other types are answered with NOERROR and an empty
+    /// answer section.
+    Ptr(Name<Bytes>),
+}
+
+//----------- SpecialUseNames -------------------------------------------------
+
+/// A set of special-use names and how to answer queries for them.
+///
+/// Each name covers itself and all names below it. If several names cover a
+/// query name, the longest of them applies. Names are compared case
+/// insensitively.
+#[derive(Clone, Debug, Default)]
+pub struct SpecialUseNames {
+    /// The names and their responses.
+    entries: Vec<(Name<Bytes>, SpecialUseResponse)>,
+}
+
+impl SpecialUseNames {
+    /// Creates an empty set of special-use names.
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Creates the set of names whose treatment by caching resolvers is
+    /// specified by RFC 6761.
+    ///
+    /// These are:
+    ///
+    /// - `localhost.`, answered with the loopback addresses `127.0.0.1` and
+    ///   `::1`,
+    /// - `invalid.` and `test.`, answered with NXDOMAIN,
+    /// - the reverse mapping zones of the private IPv4 address ranges of
+    ///   [RFC 1918], answered with NXDOMAIN.
+    ///
+    /// In addition, the reverse mapping zones of the loopback addresses, as
+    /// listed in [RFC 6303], are answered with a PTR to `localhost.`.
+    ///
+    /// [RFC 1918]: https://www.rfc-editor.org/rfc/rfc1918
+    /// [RFC 6303]: https://www.rfc-editor.org/rfc/rfc6303
+    #[must_use]
+    pub fn rfc6761() -> Self {
+        let localhost = Name::from_str("localhost.").unwrap();
+        let mut names = Self::new()
+            .with_name(
+                &localhost,
+                SpecialUseResponse::Addresses(vec![
+                    IpAddr::V4(Ipv4Addr::LOCALHOST),
+                    IpAddr::V6(Ipv6Addr::LOCALHOST),
+                ]),
+            )
+            .with_name(
+                &Name::<Bytes>::from_str("127.in-addr.arpa.").unwrap(),
+                SpecialUseResponse::Ptr(localhost.clone()),
+            )
+            .with_name(
+                &Name::<Bytes>::from_str(
+                    "1.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.\
+                     0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.ip6.arpa.",
+                )
+                .unwrap(),
+                SpecialUseResponse::Ptr(localhost),
+            );
+
+        let mut nxdomain = |name: &str| {
+            names.add_name(
+                &Name::<Bytes>::from_str(name).unwrap(),
+                SpecialUseResponse::NxDomain,
+            )
+        };
+        nxdomain("invalid.");
+        nxdomain("test.");
+        nxdomain("10.in-addr.arpa.");
+        nxdomain("168.192.in-addr.arpa.");
+        for octet in 16..32 {
+            nxdomain(&format!("{octet}.172.in-addr.arpa."));
+        }
+        names
+    }
+
+    /// Adds a name and the response to queries for it.
+    #[must_use]
+    pub fn with_name(
+        mut self,
+        name: &impl ToName,
+        response: SpecialUseResponse,
+    ) -> Self {
+        self.add_name(name, response);
+        self
+    }
+
+    /// Adds a name and the response to queries for it.
+    ///
+    /// Replaces the response of the name if it was added before.
+    pub fn add_name(
+        &mut self,
+        name: &impl ToName,
+        response: SpecialUseResponse,
+    ) {
+        let name: Name<Bytes> = name.to_name();
+        match self.entries.iter_mut().find(|(n, _)| *n == name) {
+            Some(entry) => entry.1 = response,
+            None => self.entries.push((name, response)),
+        }
+    }
+
+    /// Returns the number of names in the set.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Returns whether the set contains no names.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Returns the response to queries for the given name, if it is covered
+    /// by the set.
+    pub fn find(&self, qname: &impl ToName) -> Option<&SpecialUseResponse> {
+        self.find_entry(qname).map(|(_, response)| response)
+    }
+
+    /// Returns the longest name covering the given name and its response.
+    fn find_entry(
+        &self,
+        qname: &impl ToName,
+    ) -> Option<&(Name<Bytes>, SpecialUseResponse)> {
+        self.entries
+            .iter()
+            .filter(|(name, _)| qname.ends_with(name))
+            .max_by_key(|(name, _)| name.label_count())
+    }
+}
+
+//----------- SpecialUseMiddlewareSvc -----------------------------------------
+
+/// A middleware service answering queries for special-use names locally.
+///
+/// Requests whose query name is covered by the built-in names of
+/// [`SpecialUseNames::rfc6761()`] or by the configured additional names are
+/// answered with the [`SpecialUseResponse`] of the longest covering name,
+/// without invoking the inner service. If a name is both built-in and
+/// additional, the additional name's response applies. All other requests
+/// are passed to the inner service unmodified.
+///
+/// Responses synthesized by this service don't include an OPT record, place
+/// an [`EdnsMiddlewareSvc`] in front of this service to add one where
+/// needed.
+///
+/// [`EdnsMiddlewareSvc`]: super::edns::EdnsMiddlewareSvc
+#[derive(Clone, Debug)]
+pub struct SpecialUseMiddlewareSvc<RequestOctets, NextSvc, RequestMeta> {
+    /// The upstream [`Service`] to pass requests to and receive responses
+    /// from.
+    next_svc: NextSvc,
+
+    /// The built-in names, if enabled.
+    builtins: Option<Arc<SpecialUseNames>>,
+
+    /// The additional names.
+    names: Arc<SpecialUseNames>,
+
+    _phantom: PhantomData<(RequestOctets, RequestMeta)>,
+}
+
+impl<RequestOctets, NextSvc, RequestMeta>
+    SpecialUseMiddlewareSvc<RequestOctets, NextSvc, RequestMeta>
+{
+    /// Creates an instance of this middleware service.
+    ///
+    /// The built-in names are enabled and there are no additional names.
+    #[must_use]
+    pub fn new(next_svc: NextSvc) -> Self {
+        Self {
+            next_svc,
+            builtins: Some(Arc::new(SpecialUseNames::rfc6761())),
+            names: Default::default(),
+            _phantom: PhantomData,
+        }
+    }
+
+    /// Enables or disables the built-in names of RFC 6761.
+    #[must_use]
+    pub fn with_builtins(mut self, enabled: bool) -> Self {
+        self.builtins = match (enabled, self.builtins) {
+            (true, None) => Some(Arc::new(SpecialUseNames::rfc6761())),
+            (true, builtins) => builtins,
+            (false, _) => None,
+        };
+        self
+    }
+
+    /// Sets the additional special-use names.
+    #[must_use]
+    pub fn with_names(mut self, names: SpecialUseNames) -> Self {
+        self.names = Arc::new(names);
+        self
+    }
+
+    /// Returns the response to queries for the given name, if any.
+    fn find(&self, qname: &impl ToName) -> Option<&SpecialUseResponse> {
+        let custom = self.names.find_entry(qname);
+        let builtin = self
+            .builtins
+            .as_ref()
+            .and_then(|builtins| builtins.find_entry(qname));
+        match (custom, builtin) {
+            (Some(custom), Some(builtin))
+                if builtin.0.label_count() > custom.0.label_count() =>
+            {
+                Some(&builtin.1)
+            }
+            (Some(custom), _) => Some(&custom.1),
+            (None, builtin) => builtin.map(|(_, response)| response),
+        }
+    }
+}
+
+impl<RequestOctets, NextSvc, RequestMeta>
+    SpecialUseMiddlewareSvc<RequestOctets, NextSvc, RequestMeta>
+where
+    RequestOctets: Octets + Send + Sync + Unpin,
+    RequestMeta: Clone + Default,
+    NextSvc: Service<RequestOctets, RequestMeta>,
+    NextSvc::Target: Composer + Default,
+{
+    /// Answer the request if its query name is a special-use name.
+    fn preprocess(
+        &self,
+        request: &Request<RequestOctets, RequestMeta>,
+    ) -> ControlFlow<AdditionalBuilder<StreamTarget<NextSvc::Target>>> {
+        let msg = request.message();
+        let Ok(question) = msg.sole_question() else {
+            return ControlFlow::Continue(());
+        };
+
+        let Some(response) = self.find(&question.qname()) else {
+            return ControlFlow::Continue(());
+        };
+
+        trace!("Answering special-use name {}", question.qname());
+
+        let response = Self::mk_response(
+            msg,
+            &question.qname(),
+            question.qtype(),
+            response,
+        )
+        .unwrap_or_else(|err| {
+            warn!("Failed to build special-use response: {err}");
+            mk_error_response(msg, OptRcode::SERVFAIL)
+        });
+
+        ControlFlow::Break(response)
+    }
+
+    /// Build the response to a request for a special-use name.
+    fn mk_response(
+        msg: &Message<RequestOctets>,
+        qname: &impl ToName,
+        qtype: Rtype,
+        response: &SpecialUseResponse,
+    ) -> Result<AdditionalBuilder<StreamTarget<NextSvc::Target>>, PushError>
+    {
+        let builder = mk_builder_for_target();
+        let mut answer = match response {
+            SpecialUseResponse::NxDomain => {
+                let answer = builder.start_answer(msg, Rcode::NXDOMAIN)?;
+                return Ok(answer.additional());
+            }
+            _ => builder.start_answer(msg, Rcode::NOERROR)?,
+        };
+        match response {
+            SpecialUseResponse::NxDomain => {}
+            SpecialUseResponse::Addresses(addrs) => {
+                for addr in addrs {
+                    match (addr, qtype) {
+                        (IpAddr::V4(addr), Rtype::A) => answer.push((
+                            qname,
+                            SPECIAL_USE_TTL,
+                            A::new(*addr),
+                        ))?,
+                        (IpAddr::V6(addr), Rtype::AAAA) => answer.push((
+                            qname,
+                            SPECIAL_USE_TTL,
+                            Aaaa::new(*addr),
+                        ))?,
+                        _ => {}
+                    }
+                }
+            }
+            SpecialUseResponse::Ptr(target) => {
+                if qtype == Rtype::PTR {
+                    answer.push((
+                        qname,
+                        SPECIAL_USE_TTL,
+                        Ptr::new(target),
+                    ))?;
+                }
+            }
+        }
+        Ok(answer.additional())
+    }
+}
+
+//--- Service
+
+impl<RequestOctets, NextSvc, RequestMeta> Service<RequestOctets, RequestMeta>
+    for SpecialUseMiddlewareSvc<RequestOctets, NextSvc, RequestMeta>
+where
+    RequestOctets: Octets + Send + Sync + 'static + Unpin,
+    RequestMeta: Clone + Default,
+    NextSvc: Service<RequestOctets, RequestMeta>,
+    NextSvc::Target: Composer + Default,
+    NextSvc::Future: Unpin,
+{
+    type Target = NextSvc::Target;
+    type Stream = MiddlewareStream<
+        NextSvc::Future,
+        NextSvc::Stream,
+        NextSvc::Stream,
+        Once<Ready<ServiceResult<Self::Target>>>,
+        ServiceResult<Self::Target>,
+    >;
+    type Future = Ready<Self::Stream>;
+
+    fn call(
+        &self,
+        request: Request<RequestOctets, RequestMeta>,
+    ) -> Self::Future {
+        match self.preprocess(&request) {
+            ControlFlow::Continue(()) => {
+                let svc_call_fut = self.next_svc.call(request);
+                ready(MiddlewareStream::IdentityFuture(svc_call_fut))
+            }
+            ControlFlow::Break(response) => ready(MiddlewareStream::Result(
+                once(ready(Ok(CallResult::new(response)))),
+            )),
+        }
+    }
+}
+
+//============ Tests =========================================================
+
+#[cfg(test)]
+mod tests {
+    use crate::base::iana::Rcode;
+    use crate::base::net::{IpAddr, Ipv4Addr};
+    use crate::base::Rtype;
+    use crate::net::server::middleware::test_helpers::{
+        answer, name, process, service,
+    };
+
+    use super::{
+        SpecialUseMiddlewareSvc, SpecialUseNames, SpecialUseResponse,
+    };
+
+    //------------ Tests -----------------------------------------------------
+
+    #[test]
+    fn longest_name_applies() {
+        let names = SpecialUseNames::rfc6761();
+        assert_eq!(
+            names.find(&name("1.0.0.127.IN-ADDR.arpa")),
+            Some(&SpecialUseResponse::Ptr(name("localhost")))
+        );
+        assert_eq!(
+            names.find(&name("1.0.20.172.in-addr.arpa")),
+            Some(&SpecialUseResponse::NxDomain)
+        );
+        assert_eq!(names.find(&name("1.0.32.172.in-addr.arpa")), None);
+        assert_eq!(names.find(&name("example")), None);
+    }
+
+    #[tokio::test]
+    async fn builtin_names_are_answered_locally() {
+        let svc = SpecialUseMiddlewareSvc::new(service());
+
+        let response = process(&svc, "localhost", Rtype::A).await;
+        assert_eq!(answer(&response), ["A 127.0.0.1"]);
+        let response = process(&svc, "www.LocalHost", Rtype::AAAA).await;
+        assert_eq!(answer(&response), ["AAAA ::1"]);
+        let response = process(&svc, "localhost", Rtype::MX).await;
+        assert_eq!(response.header().rcode(), Rcode::NOERROR);
+        assert!(answer(&response).is_empty());
+
+        let response =
+            process(&svc, "1.0.0.127.in-addr.arpa", Rtype::PTR).await;
+        assert_eq!(answer(&response), ["PTR localhost."]);
+
+        let response = process(&svc, "foo.invalid", Rtype::A).await;
+        assert_eq!(response.header().rcode(), Rcode::NXDOMAIN);
+        assert!(answer(&response).is_empty());
+
+        let response = process(&svc, "www.example", Rtype::A).await;
+        assert_eq!(answer(&response), ["A 192.0.2.1"]);
+    }
+
+    #[tokio::test]
+    async fn builtin_names_can_be_disabled() {
+        let svc =
+            SpecialUseMiddlewareSvc::new(service()).with_builtins(false);
+
+        let response = process(&svc, "localhost", Rtype::A).await;
+        assert_eq!(answer(&response), ["A 192.0.2.1"]);
+        let response = process(&svc, "foo.invalid", Rtype::A).await;
+        assert_eq!(answer(&response), ["A 192.0.2.1"]);
+    }
+
+    #[tokio::test]
+    async fn custom_names_are_answered_locally() {
+        let svc = SpecialUseMiddlewareSvc::new(service()).with_names(
+            SpecialUseNames::new()
+                .with_name(&name("home.arpa"), SpecialUseResponse::NxDomain)
+                .with_name(
+                    &name("localhost"),
+                    SpecialUseResponse::Addresses(vec![IpAddr::V4(
+                        Ipv4Addr::new(127, 0, 0, 2),
+                    )]),
+                ),
+        );
+
+        let response = process(&svc, "printer.home.arpa", Rtype::A).await;
+        assert_eq!(response.header().rcode(), Rcode::NXDOMAIN);
+
+        // Custom names take precedence over built-in ones.
+        let response = process(&svc, "localhost", Rtype::A).await;
+        assert_eq!(answer(&response), ["A 127.0.0.2"]);
+
+        let response = process(&svc, "foo.invalid", Rtype::A).await;
+        assert_eq!(response.header().rcode(), Rcode::NXDOMAIN);
+    }
+}
@@ -171,6 +171,23 @@ type PostprocessingStreamCallback<
 /// defining the transformation logic via a user supplied callback function
 /// which will be invoked on each received response stream item.
 ///
+/// This applies equally to services that respond with a single message and
+/// to those that respond with many, e.g. a zone transfer, so middleware can
+/// post-process every message of a multi-message response.
+///
+/// # Ordering
+///
+/// The callback is invoked exactly once for each item of the upper service
+/// response stream, in the order in which the upper service produced them.
+/// Items are mapped lazily, one at a time as the stream is polled, and each
+/// item is passed downstream before the next one is requested from the
+/// upper service. The post-processing metadata is shared by all invocations
+/// of the callback for a request, so state such as whether the first
+/// response has already been seen can be carried from one item to the next.
+///
+/// The callback maps each item to exactly one item: it cannot drop items or
+/// produce additional ones.
+///
 /// [`futures::stream::Stream`]: futures::stream::Stream
 pub struct PostprocessingStream<
     RequestOctets,
@@ -291,3 +308,248 @@ where
         }
     }
 }
+
+//============ Tests =========================================================
+
+#[cfg(test)]
+mod tests {
+    use core::future::{ready, Ready};
+
+    use std::sync::{Arc, Mutex};
+    use std::vec::Vec;
+
+    use futures_util::stream::{self, Iter, Once};
+    use futures_util::StreamExt;
+
+    use crate::base::iana::Rcode;
+    use crate::base::{MessageBuilder, Name, Rtype, Serial, Ttl};
+    use crate::net::server::message::{Request, UdpTransportContext};
+    use crate::net::server::service::{CallResult, Service, ServiceResult};
+    use crate::net::server::util::{mk_builder_for_target, service_fn};
+    use crate::rdata::{Soa, A};
+
+    use super::{MiddlewareStream, PostprocessingStream};
+
+    //------------ Tests -----------------------------------------------------
+
+    #[tokio::test]
+    async fn every_streamed_response_is_postprocessed_in_order() {
+        let mut query = MessageBuilder::new_vec().question();
+        query
+            .push((Name::vec_from_str("example.com").unwrap(), Rtype::AXFR))
+            .unwrap();
+        let request = Request::for_test(
+            query.into_message(),
+            UdpTransportContext::default(),
+            "192.0.2.1:12345".parse().unwrap(),
+        );
+
+        let svc = AxfrService;
+        let mut stream = PostprocessingStream::new(
+            svc.call(request.clone()),
+            request,
+            0x1000u16,
+            set_next_id,
+        );
+
+        let mut responses = Vec::new();
+        while let Some(item) = stream.next().await {
+            let call_result = item.unwrap();
+            let response = call_result.response().unwrap().as_message();
+            let rtype =
+                response.answer().unwrap().next().unwrap().unwrap().rtype();
+            responses.push((response.header().id(), rtype));
+        }
+
+        assert_eq!(
+            responses,
+            [
+                (0x1000, Rtype::SOA),
+                (0x1001, Rtype::A),
+                (0x1002, Rtype::SOA)
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn answering_layer_is_postprocessed_by_lower_layers_only() {
+        fn my_service(
+            req: Request<Vec<u8>>,
+            _meta: (),
+        ) -> ServiceResult<Vec<u8>> {
+            let builder = mk_builder_for_target();
+            let answer =
+                builder.start_answer(req.message(), Rcode::NOERROR)?;
+            Ok(CallResult::new(answer.additional()))
+        }
+
+        let log = ProcessingLog::default();
+        let layer2 =
+            RecordingSvc::new(service_fn(my_service, ()), 2, false, &log);
+        let layer1 = RecordingSvc::new(layer2, 1, true, &log);
+        let layer0 = RecordingSvc::new(layer1, 0, false, &log);
+
+        let mut query = MessageBuilder::new_vec().question();
+        query.push((Name::root_vec(), Rtype::A)).unwrap();
+        let request = Request::for_test(
+            query.into_message(),
+            UdpTransportContext::default(),
+            "192.0.2.1:12345".parse().unwrap(),
+        );
+        let mut stream = layer0.call(request).await;
+        let call_result = stream.next().await.unwrap().unwrap();
+        assert!(stream.next().await.is_none());
+
+        let response = call_result.response().unwrap().as_message();
+        assert_eq!(response.header().rcode(), Rcode::REFUSED);
+        assert_eq!(
+            *log.lock().unwrap(),
+            [("pre", 0), ("pre", 1), ("post", 0)]
+        );
+    }
+
+    //------------ Helper types ----------------------------------------------
+
+    type ProcessingLog = Arc<Mutex<Vec<(&'static str, usize)>>>;
+
+    /// A middleware recording when it pre- and post-processes.
+    ///
+    /// If configured to answer, the middleware answers every request with
+    /// REFUSED instead of passing it on.
+    struct RecordingSvc<NextSvc> {
+        next_svc: NextSvc,
+        idx: usize,
+        answer: bool,
+        log: ProcessingLog,
+    }
+
+    impl<NextSvc> RecordingSvc<NextSvc> {
+        fn new(
+            next_svc: NextSvc,
+            idx: usize,
+            answer: bool,
+            log: &ProcessingLog,
+        ) -> Self {
+            Self {
+                next_svc,
+                idx,
+                answer,
+                log: log.clone(),
+            }
+        }
+    }
+
+    impl<NextSvc> Service for RecordingSvc<NextSvc>
+    where
+        NextSvc: Service<Target = Vec<u8>>,
+        NextSvc::Future: Unpin,
+    {
+        type Target = Vec<u8>;
+        type Stream = MiddlewareStream<
+            NextSvc::Future,
+            NextSvc::Stream,
+            PostprocessingStream<
+                Vec<u8>,
+                NextSvc::Future,
+                NextSvc::Stream,
+                (),
+                (usize, ProcessingLog),
+            >,
+            Once<Ready<ServiceResult<Vec<u8>>>>,
+            ServiceResult<Vec<u8>>,
+        >;
+        type Future = Ready<Self::Stream>;
+
+        fn call(&self, request: Request<Vec<u8>>) -> Self::Future {
+            self.log.lock().unwrap().push(("pre", self.idx));
+            if self.answer {
+                let builder = mk_builder_for_target();
+                let answer = builder
+                    .start_answer(request.message(), Rcode::REFUSED)
+                    .unwrap();
+                let item = Ok(CallResult::new(answer.additional()));
+                return ready(MiddlewareStream::Result(stream::once(ready(
+                    item,
+                ))));
+            }
+            let svc_call_fut = self.next_svc.call(request.clone());
+            ready(MiddlewareStream::Map(PostprocessingStream::new(
+                svc_call_fut,
+                request,
+                (self.idx, self.log.clone()),
+                record_postprocessing,
+            )))
+        }
+    }
+
+    /// A service responding to every request like a three message AXFR.
+    struct AxfrService;
+
+    impl Service for AxfrService {
+        type Target = Vec<u8>;
+        type Stream = Iter<std::vec::IntoIter<ServiceResult<Vec<u8>>>>;
+        type Future = Ready<Self::Stream>;
+
+        fn call(&self, request: Request<Vec<u8>>) -> Self::Future {
+            let apex = Name::vec_from_str("example.com").unwrap();
+            let soa = Soa::new(
+                Name::vec_from_str("ns.example.com").unwrap(),
+                Name::vec_from_str("admin.example.com").unwrap(),
+                Serial(1),
+                Ttl::from_secs(3600),
+                Ttl::from_secs(600),
+                Ttl::from_secs(86400),
+                Ttl::from_secs(300),
+            );
+
+            let mut results = Vec::new();
+            for i in 0..3 {
+                let builder = mk_builder_for_target();
+                let mut answer = builder
+                    .start_answer(request.message(), Rcode::NOERROR)
+                    .unwrap();
+                if i == 1 {
+                    answer
+                        .push((
+                            apex.clone(),
+                            60,
+                            A::from_octets(192, 0, 2, 1),
+                        ))
+                        .unwrap();
+                } else {
+                    answer.push((apex.clone(), 60, soa.clone())).unwrap();
+                }
+                results.push(Ok(CallResult::new(answer.additional())));
+            }
+
+            ready(stream::iter(results))
+        }
+    }
+
+    //------------ Helper functions ------------------------------------------
+
+    /// Records that a response was post-processed.
+    fn record_postprocessing(
+        _request: Request<Vec<u8>>,
+        item: ServiceResult<Vec<u8>>,
+        (idx, log): &mut (usize, ProcessingLog),
+    ) -> ServiceResult<Vec<u8>> {
+        log.lock().unwrap().push(("post", *idx));
+        item
+    }
+
+    /// Sets the message ID of each response to the next ID in sequence.
+    fn set_next_id(
+        _request: Request<Vec<u8>>,
+        mut item: ServiceResult<Vec<u8>>,
+        next_id: &mut u16,
+    ) -> ServiceResult<Vec<u8>> {
+        if let Ok(call_result) = &mut item {
+            if let Some(response) = call_result.response_mut() {
+                response.header_mut().set_id(*next_id);
+                *next_id += 1;
+            }
+        }
+        item
+    }
+}
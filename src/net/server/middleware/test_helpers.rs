@@ -0,0 +1,251 @@
+//! Helpers shared by the tests of the middleware services.
+//!
+//! The helpers pass single UDP queries through a middleware service wrapped
+//! around the simple upstream [`service()`] and render the responses in a
+//! form that is easy to compare against.
+use core::str::FromStr;
+
+use std::string::String;
+use std::vec::Vec;
+
+use bytes::Bytes;
+use futures_util::StreamExt;
+
+use crate::base::iana::{Class, Rcode};
+use crate::base::{
+    Message, MessageBuilder, Name, ParsedName, Question, Rtype, Serial,
+    ToName, Ttl,
+};
+use crate::net::server::message::{
+    Request, TransportSpecificContext, UdpTransportContext,
+};
+use crate::net::server::service::{CallResult, Service, ServiceResult};
+use crate::net::server::util::{
+    mk_builder_for_target, service_fn, ServiceFn,
+};
+use crate::rdata::{AllRecordData, Soa, Txt, A};
+
+/// The client address of requests unless given otherwise.
+const CLIENT_ADDR: &str = "127.0.0.1:12345";
+
+/// Returns the name for the given string.
+pub fn name(name: &str) -> Name<Bytes> {
+    Name::from_str(name).unwrap()
+}
+
+/// Returns a service answering every query with an A record for the
+/// queried name pointing to 192.0.2.1.
+pub fn service(
+) -> impl Service<Vec<u8>, (), Target = Vec<u8>, Future = impl Unpin> + Clone
+{
+    fn my_service(
+        req: Request<Vec<u8>>,
+        _meta: (),
+    ) -> ServiceResult<Vec<u8>> {
+        let builder = mk_builder_for_target();
+        let mut answer =
+            builder.start_answer(req.message(), Rcode::NOERROR).unwrap();
+        let question = req.message().sole_question().unwrap();
+        answer
+            .push((question.qname(), 3600, A::from_octets(192, 0, 2, 1)))
+            .unwrap();
+        Ok(CallResult::new(answer.additional()))
+    }
+    service_fn(my_service, ())
+}
+
+/// The type of the service returned by [`nxdomain_service()`].
+pub type NxdomainService = ServiceFn<
+    Vec<u8>,
+    fn(Request<Vec<u8>>, ()) -> ServiceResult<Vec<u8>>,
+    (),
+>;
+
+/// Returns a service answering queries for names with a first label
+/// starting with `nx` with NXDOMAIN and all other queries with an A record
+/// for the queried name pointing to 192.0.2.1.
+///
+/// NXDOMAIN responses carry the SOA record of the parent of the queried
+/// name in the authority section.
+pub fn nxdomain_service() -> NxdomainService {
+    fn my_service(
+        req: Request<Vec<u8>>,
+        _meta: (),
+    ) -> ServiceResult<Vec<u8>> {
+        let question = req.message().sole_question().unwrap();
+        let qname: Name<Bytes> = question.qname().to_name();
+        let builder = mk_builder_for_target();
+        if qname.first().as_slice().starts_with(b"nx") {
+            let answer = builder
+                .start_answer(req.message(), Rcode::NXDOMAIN)
+                .unwrap();
+            let apex = qname.parent().unwrap();
+            let mut authority = answer.authority();
+            authority
+                .push((
+                    &apex,
+                    3600,
+                    Soa::new(
+                        &apex,
+                        &apex,
+                        Serial(1),
+                        Ttl::HOUR,
+                        Ttl::HOUR,
+                        Ttl::HOUR,
+                        Ttl::HOUR,
+                    ),
+                ))
+                .unwrap();
+            return Ok(CallResult::new(authority.additional()));
+        }
+        let mut answer =
+            builder.start_answer(req.message(), Rcode::NOERROR).unwrap();
+        answer
+            .push((&qname, 3600, A::from_octets(192, 0, 2, 1)))
+            .unwrap();
+        Ok(CallResult::new(answer.additional()))
+    }
+    service_fn(my_service as fn(_, _) -> _, ())
+}
+
+/// Returns the answer section as `"<rtype> <data>"` strings.
+pub fn answer(response: &Message<Vec<u8>>) -> Vec<String> {
+    response
+        .answer()
+        .unwrap()
+        .limit_to::<AllRecordData<_, ParsedName<_>>>()
+        .map(|rr| {
+            let rr = rr.unwrap();
+            format!("{} {}", rr.rtype(), rr.data())
+        })
+        .collect()
+}
+
+/// Returns the text of the TXT records in the answer section.
+pub fn txts(response: &Message<Vec<u8>>) -> Vec<String> {
+    response
+        .answer()
+        .unwrap()
+        .limit_to::<Txt<_>>()
+        .map(|rr| {
+            let rr = rr.unwrap();
+            String::from_utf8(rr.data().text::<Vec<u8>>()).unwrap()
+        })
+        .collect()
+}
+
+/// Returns a UDP request for the given question from the given client.
+pub fn mk_request(
+    qname: &str,
+    qtype: Rtype,
+    qclass: Class,
+    client_addr: &str,
+) -> Request<Vec<u8>> {
+    let mut query = MessageBuilder::new_vec().question();
+    query
+        .push(Question::new(name(qname), qtype, qclass))
+        .unwrap();
+    Request::for_test(
+        query.into_message(),
+        UdpTransportContext::default(),
+        client_addr.parse().unwrap(),
+    )
+}
+
+/// Passes a request to the service and returns the first response.
+///
+/// Returns `None` if the service didn't produce a response.
+pub async fn try_process<Svc>(
+    svc: &Svc,
+    request: Request<Vec<u8>>,
+) -> Option<Message<Vec<u8>>>
+where
+    Svc: Service<Vec<u8>, (), Target = Vec<u8>>,
+{
+    let mut stream = svc.call(request).await;
+    let call_result: CallResult<Vec<u8>> = stream.next().await?.unwrap();
+    let (response, _feedback) = call_result.into_inner();
+    let response = response?.finish();
+    Some(Message::from_octets(response.as_dgram_slice().to_vec()).unwrap())
+}
+
+/// Passes a query of class IN to the service and returns the response.
+pub async fn process<Svc>(
+    svc: &Svc,
+    qname: &str,
+    qtype: Rtype,
+) -> Message<Vec<u8>>
+where
+    Svc: Service<Vec<u8>, (), Target = Vec<u8>>,
+{
+    process_in_class(svc, qname, qtype, Class::IN).await
+}
+
+/// Passes a query of the given class to the service and returns the
+/// response.
+pub async fn process_in_class<Svc>(
+    svc: &Svc,
+    qname: &str,
+    qtype: Rtype,
+    qclass: Class,
+) -> Message<Vec<u8>>
+where
+    Svc: Service<Vec<u8>, (), Target = Vec<u8>>,
+{
+    let request = mk_request(qname, qtype, qclass, CLIENT_ADDR);
+    try_process(svc, request).await.unwrap()
+}
+
+/// Passes a query for the A record of the root from the given client to
+/// the service and returns the response.
+pub async fn process_from<Svc>(
+    svc: &Svc,
+    client_addr: &str,
+) -> Message<Vec<u8>>
+where
+    Svc: Service<Vec<u8>, (), Target = Vec<u8>>,
+{
+    let request = mk_request(".", Rtype::A, Class::IN, client_addr);
+    try_process(svc, request).await.unwrap()
+}
+
+/// Passes a UDP query for the A record of the given name from the given
+/// client IP address to the service and returns the first response.
+///
+/// Returns `None` if the service didn't produce a response.
+pub async fn try_process_from_ip<Svc>(
+    svc: &Svc,
+    qname: &str,
+    client_ip: &str,
+) -> Option<Message<Vec<u8>>>
+where
+    Svc: Service<Vec<u8>, (), Target = Vec<u8>>,
+{
+    let ctx = UdpTransportContext::default().into();
+    try_process_with_ctx(svc, qname, client_ip, ctx).await
+}
+
+/// Passes a query for the A record of the given name from the given client
+/// IP address over the given transport to the service and returns the
+/// first response.
+///
+/// Returns `None` if the service didn't produce a response.
+pub async fn try_process_with_ctx<Svc>(
+    svc: &Svc,
+    qname: &str,
+    client_ip: &str,
+    ctx: TransportSpecificContext,
+) -> Option<Message<Vec<u8>>>
+where
+    Svc: Service<Vec<u8>, (), Target = Vec<u8>>,
+{
+    let mut query = MessageBuilder::new_vec().question();
+    query.push((name(qname), Rtype::A)).unwrap();
+    let client_ip = client_ip.parse::<std::net::IpAddr>().unwrap();
+    let request = Request::for_test(
+        query.into_message(),
+        ctx,
+        (client_ip, 12345).into(),
+    );
+    try_process(svc, request).await
+}
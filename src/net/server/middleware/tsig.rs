@@ -1,10 +1,15 @@
-//! RFC 8495 TSIG message authentication middleware.
+//! RFC 8945 TSIG message authentication middleware.
 //!
 //! This module provides a TSIG request validation and response signing
 //! middleware service. The underlying TSIG RR processing is implemented using
 //! the [`rdata::tsig`][crate::rdata::tsig] module.
 //!
-//! Signed requests that fail signature verification will be rejected.
+//! Signed requests that fail signature verification will be rejected. The
+//! rejection is a NOTAUTH response whose TSIG record carries the error, e.g.
+//! BADKEY for an unknown key, BADSIG for a wrong signature or BADTIME for a
+//! request signed too long ago, in which case the response is signed. A
+//! request whose TSIG record can't be interpreted or isn't the last record
+//! of the message is rejected with FORMERR.
 //!
 //! Unsigned requests and correctly signed requests will pass through this
 //! middleware unchanged.
@@ -23,6 +28,20 @@
 //! the request metadata to determine the key that the request was signed
 //! with.
 //!
+//! # Response size
+//!
+//! Space for the TSIG record of the response is reserved in the request
+//! passed to the upstream service via [`Request::reserve_bytes()`].
+//! Upstream services, and middleware such as the
+//! [`MandatoryMiddlewareSvc`] that truncates UDP responses, leave this space
+//! free so that the signed response still fits. For this to work the TSIG
+//! middleware has to wrap the middleware that truncates responses.
+//!
+//! Should the TSIG record not fit nonetheless, a truncated response
+//! containing only the question and the TSIG record is returned instead.
+//!
+//! [`MandatoryMiddlewareSvc`]: super::mandatory::MandatoryMiddlewareSvc
+//!
 //! # Limitations
 //!
 //! * RFC 8945 5.2.3 Time Check and Error Handling states: _"The server SHOULD
@@ -149,7 +168,10 @@ where
                     new_msg,
                     req.transport_ctx().clone(),
                     Some(tsig.wrapped_key().clone()),
-                );
+                )
+                .with_cancellation_token(req.cancellation_token().clone())
+                .with_deadline(req.deadline().clone())
+                .with_local_addr(req.local_addr());
 
                 let num_bytes_to_reserve = tsig.key().compose_len();
                 new_req.reserve_bytes(num_bytes_to_reserve);
@@ -504,3 +526,296 @@ enum TruncationContext<'a, KSeq, KTxn> {
 
     NoSignerOnlyTheKey(KTxn),
 }
+
+#[cfg(test)]
+mod tests {
+    use core::str::FromStr;
+    use core::time::Duration;
+
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::vec::Vec;
+
+    use bytes::Bytes;
+    use futures_util::StreamExt;
+    use mock_instant::thread_local::MockClock;
+    use tokio::time::Instant;
+
+    use crate::base::iana::{Class, ExtendedErrorCode, Rcode, TsigRcode};
+    use crate::base::message_builder::AdditionalBuilder;
+    use crate::base::opt::ExtendedError;
+    use crate::base::{Message, MessageBuilder, Name, ParsedName, Rtype};
+    use crate::net::server::message::{Request, UdpTransportContext};
+    use crate::net::server::middleware::mandatory::{
+        MandatoryMiddlewareSvc, MINIMUM_RESPONSE_BYTE_LEN,
+    };
+    use crate::net::server::service::{CallResult, Service, ServiceResult};
+    use crate::net::server::util::{mk_builder_for_target, service_fn};
+    use crate::rdata::tsig::{Time48, Tsig};
+    use crate::rdata::A;
+    use crate::tsig::{Algorithm, ClientTransaction, Key, KeyName};
+
+    use super::TsigMiddlewareSvc;
+
+    //------------ Tests -----------------------------------------------------
+
+    #[tokio::test]
+    async fn signed_udp_response_fits_after_truncation() {
+        // A response with this many A records fits into a minimal UDP
+        // response, but not once the TSIG record is added as well.
+        const NUM_RRS: u8 = 28;
+
+        fn my_service(
+            req: Request<Vec<u8>, Option<Arc<Key>>>,
+            _meta: (),
+        ) -> ServiceResult<Vec<u8>> {
+            let builder = mk_builder_for_target();
+            let mut answer =
+                builder.start_answer(req.message(), Rcode::NOERROR).unwrap();
+            for i in 0..NUM_RRS {
+                answer
+                    .push((
+                        Name::root_ref(),
+                        Class::IN,
+                        3600,
+                        A::from_octets(192, 0, 2, i),
+                    ))
+                    .unwrap();
+            }
+            Ok(CallResult::new(answer.additional()))
+        }
+
+        let key = Arc::new(
+            Key::new(
+                Algorithm::Sha256,
+                b"0123456789abcdef",
+                KeyName::from_str("test.key.").unwrap(),
+                None,
+                None,
+            )
+            .unwrap(),
+        );
+        let svc = TsigMiddlewareSvc::new(
+            MandatoryMiddlewareSvc::new(service_fn(my_service, ())),
+            key.clone(),
+        );
+
+        // Sign a query that arrived via UDP without EDNS, thus allowing only
+        // a minimal size response.
+        let mut query = MessageBuilder::new_vec().question();
+        query.push((Name::<Bytes>::root(), Rtype::A)).unwrap();
+        let mut query = query.additional();
+        let txn = ClientTransaction::request(key, &mut query, Time48::now())
+            .unwrap();
+        let request = Request::new(
+            "127.0.0.1:12345".parse().unwrap(),
+            Instant::now(),
+            query.into_message(),
+            UdpTransportContext::default().into(),
+            (),
+        );
+
+        let mut stream = svc.call(request).await;
+        let call_result = stream.next().await.unwrap().unwrap();
+        let (response, _feedback) = call_result.into_inner();
+        let response = response.unwrap().finish();
+        let response = response.as_dgram_slice().to_vec();
+
+        // The response was truncated before it was signed so it fits
+        // including the TSIG record and the signature is valid.
+        assert!(response.len() <= usize::from(MINIMUM_RESPONSE_BYTE_LEN));
+        let mut response = Message::from_octets(response).unwrap();
+        assert!(response.header().tc());
+        txn.answer(&mut response, Time48::now()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn signed_request_is_passed_on_and_response_signed() {
+        let num_calls = Arc::new(AtomicUsize::new(0));
+        let svc = TsigMiddlewareSvc::new(
+            service_fn(counting_service, num_calls.clone()),
+            key("test.key."),
+        );
+
+        let (query, txn) = signed_query(key("test.key."), Time48::now());
+        let mut response = process(&svc, query.finish()).await;
+
+        assert_eq!(num_calls.load(Ordering::Relaxed), 1);
+        assert_eq!(response.header().rcode(), Rcode::NOERROR);
+        txn.answer(&mut response, Time48::now()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn extended_error_is_added_before_signing() {
+        fn ede_service(
+            req: Request<Vec<u8>, Option<Arc<Key>>>,
+            _meta: (),
+        ) -> ServiceResult<Vec<u8>> {
+            let builder = mk_builder_for_target();
+            let answer =
+                builder.start_answer(req.message(), Rcode::REFUSED)?;
+            Ok(CallResult::new(answer.additional())
+                .with_ede(ExtendedErrorCode::PROHIBITED, "not for you"))
+        }
+
+        let svc = TsigMiddlewareSvc::new(
+            service_fn(ede_service, ()),
+            key("test.key."),
+        );
+
+        let (query, txn) = signed_query(key("test.key."), Time48::now());
+        let mut response = process(&svc, query.finish()).await;
+
+        // The extended error was added before the response was signed, so
+        // the TSIG record is still last and the signature is valid.
+        let ede = response.opt().unwrap();
+        let ede = ede.opt().first::<ExtendedError<_>>().unwrap();
+        assert_eq!(ede.code(), ExtendedErrorCode::PROHIBITED);
+        txn.answer(&mut response, Time48::now()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn tampered_request_is_refused_with_badsig() {
+        let num_calls = Arc::new(AtomicUsize::new(0));
+        let svc = TsigMiddlewareSvc::new(
+            service_fn(counting_service, num_calls.clone()),
+            key("test.key."),
+        );
+
+        // Change the QTYPE from A to AAAA after signing.
+        let (query, _txn) = signed_query(key("test.key."), Time48::now());
+        let mut query = query.finish();
+        assert_eq!(query[13..15], Rtype::A.to_int().to_be_bytes());
+        query[13..15].copy_from_slice(&Rtype::AAAA.to_int().to_be_bytes());
+        let response = process(&svc, query).await;
+
+        assert_eq!(num_calls.load(Ordering::Relaxed), 0);
+        assert_eq!(response.header().rcode(), Rcode::NOTAUTH);
+        let (error, mac_len) = last_tsig(&response);
+        assert_eq!(error, TsigRcode::BADSIG);
+        assert_eq!(mac_len, 0);
+    }
+
+    #[tokio::test]
+    async fn request_signed_with_unknown_key_is_refused_with_badkey() {
+        let num_calls = Arc::new(AtomicUsize::new(0));
+        let svc = TsigMiddlewareSvc::new(
+            service_fn(counting_service, num_calls.clone()),
+            key("test.key."),
+        );
+
+        let (query, _txn) = signed_query(key("other.key."), Time48::now());
+        let response = process(&svc, query.finish()).await;
+
+        assert_eq!(num_calls.load(Ordering::Relaxed), 0);
+        assert_eq!(response.header().rcode(), Rcode::NOTAUTH);
+        let (error, mac_len) = last_tsig(&response);
+        assert_eq!(error, TsigRcode::BADKEY);
+        assert_eq!(mac_len, 0);
+    }
+
+    #[tokio::test]
+    async fn stale_request_is_refused_with_signed_badtime() {
+        let num_calls = Arc::new(AtomicUsize::new(0));
+        let svc = TsigMiddlewareSvc::new(
+            service_fn(counting_service, num_calls.clone()),
+            key("test.key."),
+        );
+
+        // Signed an hour ago, well outside the default fudge of 300s.
+        MockClock::set_system_time(Duration::from_secs(3600));
+        let (query, _txn) =
+            signed_query(key("test.key."), Time48::from_u64(0));
+        let response = process(&svc, query.finish()).await;
+
+        assert_eq!(num_calls.load(Ordering::Relaxed), 0);
+        assert_eq!(response.header().rcode(), Rcode::NOTAUTH);
+        let (error, mac_len) = last_tsig(&response);
+        assert_eq!(error, TsigRcode::BADTIME);
+        assert!(mac_len > 0);
+    }
+
+    #[tokio::test]
+    async fn misplaced_tsig_record_is_refused_with_formerr() {
+        let num_calls = Arc::new(AtomicUsize::new(0));
+        let svc = TsigMiddlewareSvc::new(
+            service_fn(counting_service, num_calls.clone()),
+            key("test.key."),
+        );
+
+        // Add another record after the TSIG record.
+        let (mut query, _txn) = signed_query(key("test.key."), Time48::now());
+        query
+            .push((Name::root_ref(), 3600, A::from_octets(192, 0, 2, 1)))
+            .unwrap();
+        let response = process(&svc, query.finish()).await;
+
+        assert_eq!(num_calls.load(Ordering::Relaxed), 0);
+        assert_eq!(response.header().rcode(), Rcode::FORMERR);
+    }
+
+    //------------ Helper functions ------------------------------------------
+
+    fn key(name: &str) -> Arc<Key> {
+        Arc::new(
+            Key::new(
+                Algorithm::Sha256,
+                b"0123456789abcdef",
+                KeyName::from_str(name).unwrap(),
+                None,
+                None,
+            )
+            .unwrap(),
+        )
+    }
+
+    fn signed_query(
+        key: Arc<Key>,
+        now: Time48,
+    ) -> (AdditionalBuilder<Vec<u8>>, ClientTransaction<Arc<Key>>) {
+        let mut query = MessageBuilder::new_vec().question();
+        query.push((Name::<Bytes>::root(), Rtype::A)).unwrap();
+        let mut query = query.additional();
+        let txn = ClientTransaction::request(key, &mut query, now).unwrap();
+        (query, txn)
+    }
+
+    fn counting_service(
+        req: Request<Vec<u8>, Option<Arc<Key>>>,
+        num_calls: Arc<AtomicUsize>,
+    ) -> ServiceResult<Vec<u8>> {
+        num_calls.fetch_add(1, Ordering::Relaxed);
+        let builder = mk_builder_for_target();
+        let answer = builder.start_answer(req.message(), Rcode::NOERROR)?;
+        Ok(CallResult::new(answer.additional()))
+    }
+
+    async fn process<Svc>(svc: &Svc, query: Vec<u8>) -> Message<Vec<u8>>
+    where
+        Svc: Service<Vec<u8>, (), Target = Vec<u8>>,
+    {
+        let request = Request::new(
+            "127.0.0.1:12345".parse().unwrap(),
+            Instant::now(),
+            Message::from_octets(query).unwrap(),
+            UdpTransportContext::default().into(),
+            (),
+        );
+
+        let mut stream = svc.call(request).await;
+        let call_result = stream.next().await.unwrap().unwrap();
+        let (response, _feedback) = call_result.into_inner();
+        let response = response.unwrap().finish();
+        Message::from_octets(response.as_dgram_slice().to_vec()).unwrap()
+    }
+
+    /// Returns the error and MAC length of the final TSIG record.
+    fn last_tsig(msg: &Message<Vec<u8>>) -> (TsigRcode, usize) {
+        let record = msg.additional().unwrap().last().unwrap().unwrap();
+        let record = record
+            .into_record::<Tsig<_, ParsedName<_>>>()
+            .unwrap()
+            .unwrap();
+        (record.data().error(), record.data().mac().len())
+    }
+}
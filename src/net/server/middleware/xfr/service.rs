@@ -229,7 +229,9 @@ where
                 }
             })?;
 
-        // Read the zone SOA RR
+        // Read the zone SOA RR. The same read snapshot is used to serve the
+        // rest of an AXFR so that the transfer matches the SOA serial even if
+        // the zone is changed meanwhile.
         let read = xfr_data.zone().read();
         let Ok(zone_soa_answer) = read_soa(&read, q.qname().to_name()).await
         else {
@@ -343,6 +345,14 @@ where
     }
 
     /// Generate and send an AXFR response for a given request and zone.
+    ///
+    /// The response stream starts and ends with the SOA RR in
+    /// `zone_soa_answer`, as required by RFC 5936 section 2.2. All other RRs
+    /// are taken from `read`, which must be the same snapshot of the zone
+    /// that the SOA RR was read from. As the snapshot is not affected by
+    /// later changes to the zone, the transfer is consistent with the
+    /// bracketing SOA serial even if the zone is updated while the transfer
+    /// is in progress.
     #[allow(clippy::too_many_arguments)]
     async fn respond_to_axfr_query<T>(
         zone_walk_semaphore: Arc<Semaphore>,
@@ -19,7 +19,8 @@ use tokio::time::Instant;
 
 use crate::base::iana::{Class, OptRcode, Rcode};
 use crate::base::{
-    Message, MessageBuilder, Name, ParsedName, Rtype, Serial, ToName, Ttl,
+    Message, MessageBuilder, Name, ParsedName, Record, Rtype, Serial, ToName,
+    Ttl,
 };
 use crate::net::server::message::{
     NonUdpTransportContext, Request, TransportSpecificContext,
@@ -36,7 +37,8 @@ use crate::rdata::{
 };
 use crate::tsig::{Algorithm, Key, KeyName};
 use crate::zonefile::inplace::Zonefile;
-use crate::zonetree::types::{EmptyZoneDiff, Rrset};
+use crate::zonetree::types::{EmptyZoneDiff, Rrset, ZoneUpdate};
+use crate::zonetree::update::ZoneUpdater;
 use crate::zonetree::{
     AnswerContent, InMemoryZoneDiff, InMemoryZoneDiffBuilder, SharedRrset,
     Zone,
@@ -151,6 +153,97 @@ async fn axfr_multi_response() {
     ));
 }
 
+#[tokio::test]
+async fn axfr_uses_consistent_soa_and_snapshot() {
+    let zone = load_zone(include_bytes!(
+        "../../../../../test-data/zonefiles/big.example.com.txt"
+    ));
+    let zone_soa = get_zone_soa(&zone).await;
+
+    let req = mk_axfr_request(zone.apex_name(), ());
+
+    let res = do_preprocess(zone.clone(), &req).await.unwrap();
+
+    let ControlFlow::Break(mut stream) = res else {
+        panic!("AXFR failed");
+    };
+
+    let msg = stream.next().await.unwrap().unwrap();
+    assert!(matches!(
+        msg.feedback(),
+        Some(ServiceFeedback::BeginTransaction)
+    ));
+
+    // Change the zone while the transfer is in progress: add a record and
+    // bump the SOA serial.
+    let new_soa = Soa::new(
+        ParsedName::from(zone_soa.mname().clone()),
+        ParsedName::from(zone_soa.rname().clone()),
+        zone_soa.serial().add(1),
+        zone_soa.refresh(),
+        zone_soa.retry(),
+        zone_soa.expire(),
+        zone_soa.minimum(),
+    );
+    let mut updater = ZoneUpdater::new(zone.clone()).await.unwrap();
+    updater
+        .apply(ZoneUpdate::AddRecord(Record::new(
+            ParsedName::from(n("new.example.com")),
+            Class::IN,
+            Ttl::from_secs(3600),
+            A::new(p("192.0.2.99")).into(),
+        )))
+        .await
+        .unwrap();
+    updater
+        .apply(ZoneUpdate::Finished(Record::new(
+            ParsedName::from(n("example.com")),
+            Class::IN,
+            Ttl::from_secs(3600),
+            new_soa.into(),
+        )))
+        .await
+        .unwrap();
+    assert_eq!(get_zone_soa(&zone).await.serial(), zone_soa.serial().add(1));
+
+    // Collect the records of all remaining response messages.
+    let mut records = vec![];
+    while let Some(item) = stream.next().await {
+        let (response, _feedback) = item.unwrap().into_inner();
+        let Some(response) = response else {
+            continue;
+        };
+        let response = Message::from_octets(Bytes::copy_from_slice(
+            response.as_message().as_slice(),
+        ))
+        .unwrap();
+        assert!(response.is_answer(req.message()));
+        for rec in response.answer().unwrap() {
+            let rec = rec
+                .unwrap()
+                .into_record::<AllRecordData<_, ParsedName<_>>>()
+                .unwrap()
+                .unwrap();
+            records.push((rec.owner().to_name::<Bytes>(), rec.into_data()));
+        }
+    }
+
+    // The transfer is bracketed by the same SOA, that of the zone snapshot
+    // at the time the transfer started.
+    let (first, last) = (records.first().unwrap(), records.last().unwrap());
+    let expected_soa: AllRecordData<Bytes, Name<Bytes>> = zone_soa.into();
+    assert_eq!(first.0, n("example.com"));
+    assert_eq!(last.0, n("example.com"));
+    assert!(first.1 == expected_soa);
+    assert!(last.1 == expected_soa);
+
+    // All other records also come from that snapshot.
+    assert_eq!(records.len(), 10000 + 11 + 2);
+    assert!(records
+        .iter()
+        .all(|(owner, _)| owner != &n("new.example.com")));
+}
+
 #[tokio::test]
 async fn axfr_delegation_records() {
     // https://datatracker.ietf.org/doc/html/rfc5936#section-3.2
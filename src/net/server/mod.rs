@@ -80,7 +80,8 @@
 //! for [`tokio::net::UdpSocket`].
 //!
 //! The type alias [`UdpServer`] is provided for convenience for
-//! implementations based on [`tokio::net::UdpSocket`].
+//! implementations based on [`tokio::net::UdpSocket`]. On Unix systems
+//! [`UnixDgramServer`] serves DNS over a Unix domain datagram socket.
 //!
 //! ## Stream (e.g. TCP) servers
 //!
@@ -216,6 +217,7 @@
 //! [`StreamServer`]: stream::StreamServer
 //! [`TcpServer`]: stream::TcpServer
 //! [`UdpServer`]: dgram::UdpServer
+//! [`UnixDgramServer`]: dgram::UnixDgramServer
 //! [`tokio::io::AsyncRead`]:
 //!     https://docs.rs/tokio/latest/tokio/io/trait.AsyncRead.html
 //! [`tokio::io::AsyncWrite`]:
@@ -266,11 +268,18 @@
 //! This router is deliberately kept very simple. It is assumed that
 //! applications that need more complex routers implement them themselves
 //! in the application.
+//!
+//! Servers listening on several addresses can offer a different [`Service`]
+//! per address using the
+//! [LocalAddrRouter][local_addr_router::LocalAddrRouter], which routes
+//! requests based on the local address they were received on.
 
 #![cfg(feature = "unstable-server-transport")]
 #![cfg_attr(docsrs, doc(cfg(feature = "unstable-server-transport")))]
 
+mod backpressure;
 mod connection;
+pub use connection::CloseReason;
 pub use connection::Config as ConnectionConfig;
 
 pub mod adapter;
@@ -278,15 +287,26 @@ pub mod batcher;
 pub mod buf;
 pub mod dgram;
 pub mod error;
+pub mod local_addr_router;
+pub mod log_sink;
+pub mod merge;
 pub mod message;
 pub mod metrics;
 pub mod middleware;
+pub mod proxy_protocol;
 pub mod qname_router;
+pub mod rewrite;
 pub mod service;
 pub mod single_service;
 pub mod sock;
 pub mod stream;
+pub mod transform;
 pub mod util;
+#[cfg(all(
+    feature = "unstable-zonetree",
+    feature = "unstable-client-transport"
+))]
+pub mod zone_service;
 
 #[cfg(test)]
 pub mod tests;
@@ -294,12 +314,12 @@ pub mod tests;
 //------------ ServerCommand ------------------------------------------------
 
 /// Command a server to do something.
+///
+/// Commands are delivered to the server, and for connection-oriented
+/// transports also to each of its connections, in the order in which they
+/// were sent.
 #[derive(Copy, Clone, Debug)]
 pub enum ServerCommand<T: Sized> {
-    #[doc(hidden)]
-    /// This command is for internal use only.
-    Init,
-
     /// Command the server to alter its configuration.
     Reconfigure(T),
 
@@ -311,4 +331,159 @@ pub enum ServerCommand<T: Sized> {
 
     /// Command the server to terminate.
     Shutdown,
+
+    /// Command the server to terminate immediately.
+    ///
+    /// Like [`Shutdown`], but servers that support it also abort any
+    /// processing of requests still in progress, including the writing of
+    /// responses, and wait for that to finish before they stop. Once a
+    /// server stopped this way nothing it spawned holds on to its socket
+    /// anymore. Servers that don't support it treat it as [`Shutdown`].
+    ///
+    /// [`Shutdown`]: Self::Shutdown
+    Terminate,
+
+    /// Command the server to terminate once in-flight requests are done.
+    ///
+    /// Like [`Shutdown`], but servers that support it let the processing of
+    /// requests already received continue for at most the given time before
+    /// aborting whatever processing remains. Servers that don't support it
+    /// treat it as [`Shutdown`].
+    ///
+    /// [`Shutdown`]: Self::Shutdown
+    Drain(core::time::Duration),
+}
+
+/// The number of [`ServerCommand`]s that can be queued for a receiver.
+///
+/// Commands are rarely sent so this only needs to be large enough to absorb
+/// a burst of commands sent before a receiver gets a chance to process them.
+const COMMAND_CHANNEL_CAPACITY: usize = 32;
+
+//------------ CommandSender -------------------------------------------------
+
+/// A sender of [`ServerCommand`]s to a server and its connections.
+///
+/// Commands are broadcast to every receiver. A receiver that falls behind
+/// by more than [`COMMAND_CHANNEL_CAPACITY`] commands loses the oldest of
+/// them. As commands either change the configuration or stop the server,
+/// the sender also keeps the latest of each from which a receiver that fell
+/// behind recovers.
+#[derive(Debug)]
+struct CommandSender<T> {
+    /// The channel broadcasting the commands.
+    commands: tokio::sync::broadcast::Sender<ServerCommand<T>>,
+
+    /// The latest configuration and stop command sent.
+    latest: tokio::sync::watch::Sender<LatestCommands<T>>,
+}
+
+impl<T: Clone> CommandSender<T> {
+    /// Creates a new sender and the first receiver.
+    fn new() -> (Self, CommandReceiver<T>) {
+        let (commands, commands_rx) =
+            tokio::sync::broadcast::channel(COMMAND_CHANNEL_CAPACITY);
+        let (latest, latest_rx) =
+            tokio::sync::watch::channel(LatestCommands::default());
+        let rx = CommandReceiver {
+            commands: commands_rx,
+            latest: latest_rx,
+        };
+        (Self { commands, latest }, rx)
+    }
+
+    /// Sends a command to all receivers.
+    ///
+    /// Fails only if there are no receivers.
+    fn send(
+        &self,
+        command: ServerCommand<T>,
+    ) -> Result<(), tokio::sync::broadcast::error::SendError<ServerCommand<T>>>
+    {
+        self.latest.send_modify(|latest| match &command {
+            ServerCommand::Reconfigure(config) => {
+                latest.config = Some(config.clone())
+            }
+            ServerCommand::Shutdown
+            | ServerCommand::Terminate
+            | ServerCommand::Drain(_) => latest.stop = Some(command.clone()),
+            ServerCommand::CloseConnection => {}
+        });
+        self.commands.send(command).map(|_| ())
+    }
+
+    /// Creates a receiver for commands sent from now on.
+    fn subscribe(&self) -> CommandReceiver<T> {
+        CommandReceiver {
+            commands: self.commands.subscribe(),
+            latest: self.latest.subscribe(),
+        }
+    }
+}
+
+//------------ CommandReceiver -----------------------------------------------
+
+/// A receiver of [`ServerCommand`]s.
+///
+/// See [`CommandSender`] for details.
+#[derive(Debug)]
+struct CommandReceiver<T> {
+    /// The channel the commands are broadcast on.
+    commands: tokio::sync::broadcast::Receiver<ServerCommand<T>>,
+
+    /// The latest configuration and stop command sent.
+    latest: tokio::sync::watch::Receiver<LatestCommands<T>>,
+}
+
+impl<T: Clone> CommandReceiver<T> {
+    /// Receives the next command.
+    ///
+    /// If commands were missed, the latest stop command is returned if there
+    /// is one, otherwise the latest configuration.
+    ///
+    /// Returns an error if the sender is gone.
+    async fn recv(
+        &mut self,
+    ) -> Result<ServerCommand<T>, tokio::sync::broadcast::error::RecvError>
+    {
+        loop {
+            match self.commands.recv().await {
+                Err(tokio::sync::broadcast::error::RecvError::Lagged(n)) => {
+                    tracing::warn!(
+                        "{n} server commands were missed, \
+                         applying the latest instead"
+                    );
+                    let latest = self.latest.borrow();
+                    if let Some(stop) = latest.stop.clone() {
+                        return Ok(stop);
+                    }
+                    if let Some(config) = latest.config.clone() {
+                        return Ok(ServerCommand::Reconfigure(config));
+                    }
+                }
+                res => return res,
+            }
+        }
+    }
+}
+
+//------------ LatestCommands ------------------------------------------------
+
+/// The latest configuration and stop command sent to a server.
+#[derive(Debug)]
+struct LatestCommands<T> {
+    /// The configuration of the latest reconfigure command.
+    config: Option<T>,
+
+    /// The latest command stopping the server.
+    stop: Option<ServerCommand<T>>,
+}
+
+impl<T> Default for LatestCommands<T> {
+    fn default() -> Self {
+        Self {
+            config: None,
+            stop: None,
+        }
+    }
 }
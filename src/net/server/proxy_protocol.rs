@@ -0,0 +1,404 @@
+//! Support for the PROXY protocol.
+//!
+//! A load balancer or proxy forwarding TCP connections to a server hides
+//! the address of the actual client: the server sees the proxy as its
+//! peer. Using version 2 of the [PROXY protocol] the proxy sends a header
+//! with the original addresses at the start of each connection.
+//!
+//! The [`ProxyProtocolListener`] wraps another [`AsyncAccept`] listener,
+//! reads and removes this header from each accepted stream and makes the
+//! [`StreamServer`] use the original client address instead of the address
+//! of the proxy, so that it is also the address passed to services with
+//! each request.
+//!
+//! Headers of the LOCAL command, sent e.g. by proxies for their own health
+//! checks, carry no client address. Their streams are served with the
+//! address of the proxy. Streams not starting with a valid header are
+//! closed, as only trusted proxies should be able to connect to a listener
+//! expecting the PROXY protocol.
+//!
+//! [PROXY protocol]: https://www.haproxy.org/download/2.9/doc/proxy-protocol.txt
+//! [`StreamServer`]: crate::net::server::stream::StreamServer
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll};
+use core::time::Duration;
+
+use std::boxed::Box;
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::vec;
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, ReadBuf};
+use tokio::time::timeout;
+
+use super::sock::AsyncAccept;
+
+/// The signature starting every PROXY protocol version 2 header.
+const SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+
+/// The length of the fixed part of a header.
+const HEADER_LEN: usize = 16;
+
+//------------ ProxyProtocolListener -----------------------------------------
+
+/// A listener for streams starting with a PROXY protocol version 2 header.
+///
+/// See the [module documentation][self] for details.
+#[derive(Debug)]
+pub struct ProxyProtocolListener<Listener> {
+    /// The listener accepting the streams.
+    listener: Listener,
+
+    /// The time allowed for receiving the header of a stream.
+    header_timeout: Duration,
+}
+
+impl<Listener> ProxyProtocolListener<Listener> {
+    /// The default time allowed for receiving the header of a stream.
+    pub const DEFAULT_HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+
+    /// Creates a listener reading headers from streams accepted by
+    /// `listener`.
+    pub fn new(listener: Listener) -> Self {
+        Self {
+            listener,
+            header_timeout: Self::DEFAULT_HEADER_TIMEOUT,
+        }
+    }
+
+    /// Sets the time allowed for receiving the header of a stream.
+    ///
+    /// Streams whose header isn't received in time are closed.
+    #[must_use]
+    pub fn with_header_timeout(mut self, header_timeout: Duration) -> Self {
+        self.header_timeout = header_timeout;
+        self
+    }
+
+    /// Returns the wrapped listener.
+    pub fn listener(&self) -> &Listener {
+        &self.listener
+    }
+}
+
+//--- AsyncAccept
+
+impl<Listener> AsyncAccept for ProxyProtocolListener<Listener>
+where
+    Listener: AsyncAccept<Error = io::Error>,
+    Listener::Future: Send + 'static,
+    Listener::StreamType: AsyncRead + Unpin + Send + 'static,
+{
+    type Error = io::Error;
+    type StreamType = ProxiedStream<Listener::StreamType>;
+    type Future = Pin<
+        Box<dyn Future<Output = Result<Self::StreamType, io::Error>> + Send>,
+    >;
+
+    fn poll_accept(
+        &self,
+        cx: &mut Context,
+    ) -> Poll<io::Result<(Self::Future, SocketAddr)>> {
+        let header_timeout = self.header_timeout;
+        self.listener.poll_accept(cx).map(|res| {
+            res.map(|(fut, addr)| {
+                let fut: Self::Future = Box::pin(async move {
+                    let mut stream = fut.await?;
+                    let client_addr =
+                        timeout(header_timeout, read_header(&mut stream))
+                            .await
+                            .map_err(|_| {
+                                io::Error::new(
+                                    io::ErrorKind::TimedOut,
+                                    "PROXY protocol header timed out",
+                                )
+                            })??;
+                    Ok(ProxiedStream {
+                        stream,
+                        client_addr,
+                    })
+                });
+                (fut, addr)
+            })
+        })
+    }
+
+    fn client_addr(
+        stream: &Self::StreamType,
+        accepted_from: SocketAddr,
+    ) -> SocketAddr {
+        stream.client_addr.unwrap_or_else(|| {
+            Listener::client_addr(&stream.stream, accepted_from)
+        })
+    }
+}
+
+//------------ ProxiedStream -------------------------------------------------
+
+/// A stream whose PROXY protocol header has been read.
+///
+/// Reading from and writing to the stream continues after the header.
+#[derive(Debug)]
+pub struct ProxiedStream<Stream> {
+    /// The underlying stream.
+    stream: Stream,
+
+    /// The client address given by the header, if any.
+    client_addr: Option<SocketAddr>,
+}
+
+impl<Stream> ProxiedStream<Stream> {
+    /// Returns the client address given by the PROXY protocol header.
+    ///
+    /// Returns `None` for the LOCAL command or if the header carried no
+    /// IP address.
+    pub fn client_addr(&self) -> Option<SocketAddr> {
+        self.client_addr
+    }
+
+    /// Returns a reference to the underlying stream.
+    pub fn get_ref(&self) -> &Stream {
+        &self.stream
+    }
+
+    /// Returns the underlying stream.
+    pub fn into_inner(self) -> Stream {
+        self.stream
+    }
+}
+
+//--- AsyncRead and AsyncWrite
+
+impl<Stream: AsyncRead + Unpin> AsyncRead for ProxiedStream<Stream> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_read(cx, buf)
+    }
+}
+
+impl<Stream: AsyncWrite + Unpin> AsyncWrite for ProxiedStream<Stream> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
+
+//------------ Helper functions ----------------------------------------------
+
+/// Reads a header from the stream, returning the client address if any.
+///
+/// Exactly the bytes of the header are read, so that reading can continue
+/// with the data following it.
+async fn read_header<Stream: AsyncRead + Unpin>(
+    stream: &mut Stream,
+) -> Result<Option<SocketAddr>, io::Error> {
+    let mut header = [0; HEADER_LEN];
+    stream.read_exact(&mut header).await?;
+    let (is_proxy, family, len) = parse_header(&header)?;
+    let mut addrs = vec![0; len];
+    stream.read_exact(&mut addrs).await?;
+    Ok(if is_proxy {
+        parse_addrs(family, &addrs)?
+    } else {
+        None
+    })
+}
+
+/// Parses the fixed part of a header.
+///
+/// Returns whether this is the PROXY rather than the LOCAL command, the
+/// address family and protocol byte, and the length of the rest of the
+/// header.
+fn parse_header(
+    header: &[u8; HEADER_LEN],
+) -> Result<(bool, u8, usize), io::Error> {
+    if header[..SIGNATURE.len()] != SIGNATURE {
+        return Err(invalid("no PROXY protocol v2 signature"));
+    }
+    let is_proxy = match header[12] {
+        0x20 => false,
+        0x21 => true,
+        _ => return Err(invalid("unsupported PROXY protocol version")),
+    };
+    let len = u16::from_be_bytes([header[14], header[15]]);
+    Ok((is_proxy, header[13], len.into()))
+}
+
+/// Parses the client address from the addresses of a PROXY command.
+///
+/// Returns `None` for address families other than IPv4 and IPv6, which
+/// must be ignored. Any TLVs following the addresses are ignored, too.
+fn parse_addrs(
+    family: u8,
+    addrs: &[u8],
+) -> Result<Option<SocketAddr>, io::Error> {
+    // The high nibble is the address family, the low nibble the protocol.
+    match family >> 4 {
+        0x1 => {
+            let addrs = addrs
+                .get(..12)
+                .ok_or_else(|| invalid("short PROXY protocol addresses"))?;
+            let ip = Ipv4Addr::new(addrs[0], addrs[1], addrs[2], addrs[3]);
+            let port = u16::from_be_bytes([addrs[8], addrs[9]]);
+            Ok(Some(SocketAddr::new(ip.into(), port)))
+        }
+        0x2 => {
+            let addrs = addrs
+                .get(..36)
+                .ok_or_else(|| invalid("short PROXY protocol addresses"))?;
+            let mut ip = [0; 16];
+            ip.copy_from_slice(&addrs[..16]);
+            let port = u16::from_be_bytes([addrs[32], addrs[33]]);
+            Ok(Some(SocketAddr::new(Ipv6Addr::from(ip).into(), port)))
+        }
+        _ => Ok(None),
+    }
+}
+
+/// Creates an error for an invalid header.
+fn invalid(msg: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+//============ Tests =========================================================
+
+#[cfg(test)]
+mod tests {
+    use core::future::poll_fn;
+
+    use std::net::SocketAddr;
+    use std::vec::Vec;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+
+    use crate::net::server::sock::AsyncAccept;
+
+    use super::{read_header, ProxyProtocolListener};
+
+    /// A header as sent by HAProxy for a TCP over IPv4 connection from
+    /// 192.0.2.1:56324 to 198.51.100.1:53.
+    const TCP4_HEADER: &[u8] = b"\
+        \r\n\r\n\0\r\nQUIT\n\x21\x11\x00\x0c\
+        \xc0\x00\x02\x01\xc6\x33\x64\x01\xdc\x04\x00\x35";
+
+    /// A header as sent by HAProxy for a TCP over IPv6 connection from
+    /// [2001:db8::1]:56324 to [2001:db8::53]:53, with a NOOP TLV.
+    const TCP6_HEADER: &[u8] = b"\
+        \r\n\r\n\0\r\nQUIT\n\x21\x21\x00\x27\
+        \x20\x01\x0d\xb8\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x01\
+        \x20\x01\x0d\xb8\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x53\
+        \xdc\x04\x00\x35\
+        \x04\x00\x00";
+
+    /// A header of the LOCAL command as sent for health checks.
+    const LOCAL_HEADER: &[u8] = b"\
+        \r\n\r\n\0\r\nQUIT\n\x20\x00\x00\x00";
+
+    //------------ Tests -----------------------------------------------------
+
+    #[tokio::test]
+    async fn tcp4_header() {
+        assert_eq!(
+            parse(TCP4_HEADER).await.unwrap(),
+            Some("192.0.2.1:56324".parse().unwrap())
+        );
+    }
+
+    #[tokio::test]
+    async fn tcp6_header() {
+        assert_eq!(
+            parse(TCP6_HEADER).await.unwrap(),
+            Some("[2001:db8::1]:56324".parse().unwrap())
+        );
+    }
+
+    #[tokio::test]
+    async fn local_header() {
+        assert_eq!(parse(LOCAL_HEADER).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn invalid_headers() {
+        // PROXY protocol version 1.
+        assert!(parse(b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 53\r\n")
+            .await
+            .is_err());
+
+        // Unknown version.
+        let mut header = TCP4_HEADER.to_vec();
+        header[12] = 0x31;
+        assert!(parse(&header).await.is_err());
+
+        // Addresses shorter than required by the address family.
+        let mut header = TCP4_HEADER[..TCP4_HEADER.len() - 2].to_vec();
+        header[15] = 10;
+        assert!(parse(&header).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn listener_reports_client_addr() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let listener = ProxyProtocolListener::new(listener);
+
+        for (header, expected) in [
+            (TCP4_HEADER, Some("192.0.2.1:56324")),
+            (TCP6_HEADER, Some("[2001:db8::1]:56324")),
+            (LOCAL_HEADER, None),
+        ] {
+            let mut client = TcpStream::connect(addr).await.unwrap();
+            client.write_all(header).await.unwrap();
+            client.write_all(b"data").await.unwrap();
+
+            let (fut, peer) =
+                poll_fn(|cx| listener.poll_accept(cx)).await.unwrap();
+            assert_eq!(peer, client.local_addr().unwrap());
+            let mut stream = fut.await.unwrap();
+            let client_addr =
+                ProxyProtocolListener::<TcpListener>::client_addr(
+                    &stream, peer,
+                );
+            let expected = expected
+                .map(|addr| addr.parse::<SocketAddr>().unwrap())
+                .unwrap_or(peer);
+            assert_eq!(client_addr, expected);
+
+            // The data following the header is read unaltered.
+            let mut data = [0; 4];
+            stream.read_exact(&mut data).await.unwrap();
+            assert_eq!(&data, b"data");
+        }
+    }
+
+    //------------ Helper functions ------------------------------------------
+
+    async fn parse(
+        header: &[u8],
+    ) -> Result<Option<SocketAddr>, std::io::Error> {
+        let mut header: Vec<u8> = header.into();
+        header.extend_from_slice(b"trailing data");
+        read_header(&mut header.as_slice()).await
+    }
+}
@@ -0,0 +1,470 @@
+//! A service that rewrites query names.
+//!
+//! The [`RewriteService`] substitutes the suffix of the query name of a
+//! request according to a configured table of rewrites before passing the
+//! request to an inner service, and reverses the substitution in the
+//! response produced by the inner service.
+//!
+//! This is intended for migration scenarios, e.g. to transparently answer
+//! queries for names under `old.example.` from data held for names under
+//! `new.example.`.
+
+#![warn(missing_docs)]
+#![warn(clippy::missing_docs_in_private_items)]
+
+use core::future::{ready, Ready};
+
+use std::fmt::Display;
+use std::vec::Vec;
+
+use bytes::Bytes;
+use futures_util::stream::{once, Once};
+use octseq::{EmptyBuilder, FromBuilder, Octets};
+use tracing::{debug, trace, warn};
+
+use crate::base::iana::OptRcode;
+use crate::base::message_builder::{AdditionalBuilder, PushError};
+use crate::base::name::LongChainError;
+use crate::base::wire::{Composer, ParseError};
+use crate::base::{
+    Message, MessageBuilder, Name, ParsedName, Record, StreamTarget, ToName,
+};
+use crate::rdata::AllRecordData;
+
+use super::message::Request;
+use super::middleware::stream::{MiddlewareStream, PostprocessingStream};
+use super::service::{CallResult, Service, ServiceResult};
+use super::util::{mk_builder_for_target, mk_error_response};
+
+//------------ RewriteService ------------------------------------------------
+
+/// A [`Service`] that rewrites query names via a suffix substitution table.
+///
+/// Each rewrite consists of a suffix to replace and the suffix to replace it
+/// with. The rewrites are tried in the order they were added and the first
+/// one whose suffix the query name ends with is applied. Requests whose query
+/// name matches no rewrite are passed to the inner service unmodified.
+///
+/// When a rewrite is applied, the question section of the request is
+/// rebuilt with the substituted name before the request is passed to the
+/// inner service. The question section and the owner names of the records in
+/// the response are then rebuilt with the substitution reversed. Names in
+/// record data are not rewritten.
+///
+/// If a substitution in either direction would produce a name longer than
+/// 255 octets, the request is answered with SERVFAIL.
+#[derive(Clone, Debug)]
+pub struct RewriteService<Svc> {
+    /// The service to pass rewritten requests to.
+    inner: Svc,
+
+    /// The suffix rewrites as pairs of suffix to replace and replacement.
+    rewrites: Vec<(Name<Bytes>, Name<Bytes>)>,
+}
+
+impl<Svc> RewriteService<Svc> {
+    /// Creates a new rewriting service for the given inner service.
+    ///
+    /// The service has no rewrites. Add them with [`with_rewrite()`].
+    ///
+    /// [`with_rewrite()`]: Self::with_rewrite
+    #[must_use]
+    pub fn new(inner: Svc) -> Self {
+        Self {
+            inner,
+            rewrites: Vec::new(),
+        }
+    }
+
+    /// Adds a rewrite of the suffix `from` to the suffix `to`.
+    #[must_use]
+    pub fn with_rewrite(
+        mut self,
+        from: &impl ToName,
+        to: &impl ToName,
+    ) -> Self {
+        self.rewrites.push((from.to_name(), to.to_name()));
+        self
+    }
+}
+
+impl<Svc> RewriteService<Svc> {
+    /// Build a copy of the request with the query names rewritten.
+    fn rewrite_request<RequestOctets, RequestMeta>(
+        request: &Request<RequestOctets, RequestMeta>,
+        from: &Name<Bytes>,
+        to: &Name<Bytes>,
+    ) -> Result<Request<RequestOctets, RequestMeta>, RewriteError>
+    where
+        RequestOctets: Octets + FromBuilder + Send + Sync,
+        <RequestOctets as FromBuilder>::Builder: Composer + EmptyBuilder,
+        RequestMeta: Clone,
+    {
+        let source = request.message();
+        let target = MessageBuilder::from_target(
+            <RequestOctets as FromBuilder>::Builder::empty(),
+        )
+        .map_err(PushError::from)?;
+        let target = rewrite_message(source, target, from, to)?;
+
+        let mut rewritten = Request::new(
+            request.client_addr(),
+            request.received_at(),
+            target.into_message(),
+            request.transport_ctx().clone(),
+            request.metadata().clone(),
+        )
+        .with_cancellation_token(request.cancellation_token().clone())
+        .with_deadline(request.deadline().clone())
+        .with_local_addr(request.local_addr());
+        rewritten.reserve_bytes(request.num_reserved_bytes());
+        Ok(rewritten)
+    }
+
+    /// Reverse the rewrite in a response.
+    fn postprocess<RequestOctets, RequestMeta, Target>(
+        request: &Request<RequestOctets, RequestMeta>,
+        response: &mut AdditionalBuilder<StreamTarget<Target>>,
+        (from, to): &(Name<Bytes>, Name<Bytes>),
+    ) where
+        RequestOctets: Octets + Send + Sync,
+        Target: Composer + Default,
+    {
+        let source = response.as_message();
+        match rewrite_message(&source, mk_builder_for_target(), from, to) {
+            Ok(target) => *response = target,
+            Err(err) => {
+                warn!("Unable to reverse query name rewrite: {err}");
+                *response =
+                    mk_error_response(request.message(), OptRcode::SERVFAIL);
+            }
+        }
+    }
+
+    /// Reverse the rewrite in a response stream item.
+    fn map_stream_item<RequestOctets, RequestMeta, Target>(
+        request: Request<RequestOctets, RequestMeta>,
+        mut stream_item: ServiceResult<Target>,
+        rewrite: &mut (Name<Bytes>, Name<Bytes>),
+    ) -> ServiceResult<Target>
+    where
+        RequestOctets: Octets + Send + Sync,
+        Target: Composer + Default,
+    {
+        if let Ok(cr) = &mut stream_item {
+            if let Some(response) = cr.response_mut() {
+                Self::postprocess(&request, response, rewrite);
+            }
+        }
+        stream_item
+    }
+}
+
+//--- Service
+
+impl<RequestOctets, RequestMeta, Svc> Service<RequestOctets, RequestMeta>
+    for RewriteService<Svc>
+where
+    RequestOctets: Octets + FromBuilder + Send + Sync + 'static + Unpin,
+    <RequestOctets as FromBuilder>::Builder: Composer + EmptyBuilder,
+    RequestMeta: Clone + Default + Unpin,
+    Svc: Service<RequestOctets, RequestMeta>,
+    Svc::Future: Unpin,
+    Svc::Target: Composer + Default,
+{
+    type Target = Svc::Target;
+    type Stream = MiddlewareStream<
+        Svc::Future,
+        Svc::Stream,
+        PostprocessingStream<
+            RequestOctets,
+            Svc::Future,
+            Svc::Stream,
+            RequestMeta,
+            (Name<Bytes>, Name<Bytes>),
+        >,
+        Once<Ready<ServiceResult<Self::Target>>>,
+        ServiceResult<Self::Target>,
+    >;
+    type Future = Ready<Self::Stream>;
+
+    fn call(
+        &self,
+        request: Request<RequestOctets, RequestMeta>,
+    ) -> Self::Future {
+        let rewrite = request.message().first_question().and_then(|q| {
+            let qname = q.qname();
+            self.rewrites.iter().find(|(from, _)| qname.ends_with(from))
+        });
+
+        let Some((from, to)) = rewrite else {
+            return ready(MiddlewareStream::IdentityFuture(
+                self.inner.call(request),
+            ));
+        };
+
+        match Self::rewrite_request(&request, from, to) {
+            Ok(rewritten) => {
+                trace!("Rewriting suffix '{from}' to '{to}'");
+                let svc_call_fut = self.inner.call(rewritten);
+                ready(MiddlewareStream::Map(PostprocessingStream::new(
+                    svc_call_fut,
+                    request,
+                    (to.clone(), from.clone()),
+                    Self::map_stream_item,
+                )))
+            }
+            Err(err) => {
+                debug!("Unable to rewrite suffix '{from}' to '{to}': {err}");
+                let response =
+                    mk_error_response(request.message(), OptRcode::SERVFAIL);
+                ready(MiddlewareStream::Result(once(ready(Ok(
+                    CallResult::new(response),
+                )))))
+            }
+        }
+    }
+}
+
+//------------ Helper functions ----------------------------------------------
+
+/// Copy a message into the given builder rewriting the suffix of names.
+///
+/// The names in the question section and the owner names of all records
+/// that end with `from` have that suffix replaced by `to`.
+fn rewrite_message<Octs, Target>(
+    source: &Message<Octs>,
+    mut target: MessageBuilder<Target>,
+    from: &Name<Bytes>,
+    to: &Name<Bytes>,
+) -> Result<AdditionalBuilder<Target>, RewriteError>
+where
+    Octs: Octets + ?Sized,
+    Target: Composer,
+{
+    *target.header_mut() = source.header();
+
+    let mut target = target.question();
+    for q in source.question() {
+        let q = q?;
+        match replace_suffix(&q.qname(), from, to)? {
+            Some(qname) => target.push((qname, q.qtype(), q.qclass()))?,
+            None => target.push(q)?,
+        }
+    }
+
+    let mut target = target.answer();
+    for rr in source.answer()? {
+        if let Some(rr) =
+            rr?.into_record::<AllRecordData<_, ParsedName<_>>>()?
+        {
+            match replace_suffix(rr.owner(), from, to)? {
+                Some(owner) => target.push(with_owner(rr, owner))?,
+                None => target.push(rr)?,
+            }
+        }
+    }
+
+    let mut target = target.authority();
+    for rr in source.authority()? {
+        if let Some(rr) =
+            rr?.into_record::<AllRecordData<_, ParsedName<_>>>()?
+        {
+            match replace_suffix(rr.owner(), from, to)? {
+                Some(owner) => target.push(with_owner(rr, owner))?,
+                None => target.push(rr)?,
+            }
+        }
+    }
+
+    let mut target = target.additional();
+    for rr in source.additional()? {
+        if let Some(rr) =
+            rr?.into_record::<AllRecordData<_, ParsedName<_>>>()?
+        {
+            match replace_suffix(rr.owner(), from, to)? {
+                Some(owner) => target.push(with_owner(rr, owner))?,
+                None => target.push(rr)?,
+            }
+        }
+    }
+
+    Ok(target)
+}
+
+/// Replace the suffix `from` of the given name with `to`.
+///
+/// Returns `None` if the name does not end with `from`.
+fn replace_suffix(
+    name: &impl ToName,
+    from: &Name<Bytes>,
+    to: &Name<Bytes>,
+) -> Result<Option<Name<Bytes>>, LongChainError> {
+    let Ok(prefix) = name.to_bytes().strip_suffix(from) else {
+        return Ok(None);
+    };
+    Ok(Some(prefix.chain(to)?.to_name()))
+}
+
+/// Replace the owner name of a record.
+fn with_owner<Name, NewName, Data>(
+    rr: Record<Name, Data>,
+    owner: NewName,
+) -> Record<NewName, Data> {
+    let (class, ttl) = (rr.class(), rr.ttl());
+    let (_, data) = rr.into_owner_and_data();
+    Record::new(owner, class, ttl, data)
+}
+
+//------------ RewriteError --------------------------------------------------
+
+/// An error occured while rewriting a message.
+enum RewriteError {
+    /// There was a problem parsing the message.
+    InvalidMessage(ParseError),
+
+    /// There was a problem pushing to the rewritten message.
+    PushFailure(PushError),
+
+    /// A rewritten name would be longer than 255 octets.
+    NameTooLong,
+}
+
+impl Display for RewriteError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            RewriteError::InvalidMessage(err) => {
+                write!(f, "Unable to parse message: {err}")
+            }
+            RewriteError::PushFailure(err) => {
+                write!(f, "Unable to push into message: {err}")
+            }
+            RewriteError::NameTooLong => {
+                write!(f, "Rewritten name is too long")
+            }
+        }
+    }
+}
+
+impl From<ParseError> for RewriteError {
+    fn from(err: ParseError) -> Self {
+        Self::InvalidMessage(err)
+    }
+}
+
+impl From<PushError> for RewriteError {
+    fn from(err: PushError) -> Self {
+        Self::PushFailure(err)
+    }
+}
+
+impl From<LongChainError> for RewriteError {
+    fn from(_: LongChainError) -> Self {
+        Self::NameTooLong
+    }
+}
+
+//============ Tests =========================================================
+
+#[cfg(test)]
+mod tests {
+    use core::str::FromStr;
+
+    use std::string::{String, ToString};
+    use std::vec::Vec;
+
+    use futures_util::StreamExt;
+
+    use crate::base::iana::{Class, Rcode};
+    use crate::base::{Message, MessageBuilder, Name, Rtype};
+    use crate::net::server::message::{Request, UdpTransportContext};
+    use crate::net::server::service::{CallResult, Service, ServiceResult};
+    use crate::net::server::util::{mk_builder_for_target, service_fn};
+    use crate::rdata::A;
+
+    use super::RewriteService;
+
+    #[tokio::test]
+    async fn round_trip_rewrite() {
+        let response = process("www.old.example", "new.example").await;
+        assert_eq!(response.header().rcode(), Rcode::NOERROR);
+
+        let question = response.sole_question().unwrap();
+        assert_eq!(question.qname().to_string(), "www.old.example");
+
+        let owners: Vec<String> = response
+            .answer()
+            .unwrap()
+            .limit_to::<A>()
+            .map(|rr| rr.unwrap().owner().to_string())
+            .collect();
+        assert_eq!(owners, ["www.old.example"]);
+    }
+
+    #[tokio::test]
+    async fn unmatched_name_is_not_rewritten() {
+        let response = process("www.other.example", "new.example").await;
+        assert_eq!(response.header().rcode(), Rcode::NOERROR);
+
+        let question = response.sole_question().unwrap();
+        assert_eq!(question.qname().to_string(), "www.other.example");
+    }
+
+    #[tokio::test]
+    async fn overlong_rewrite_is_servfail() {
+        // A 249 octet suffix that leaves no room for the 12 octet prefix.
+        let label = "a".repeat(63);
+        let to = format!("{label}.{label}.{label}.{}", "a".repeat(55));
+
+        let response = process("host-name-1.old.example", &to).await;
+        assert_eq!(response.header().rcode(), Rcode::SERVFAIL);
+    }
+
+    //------------ Helper functions ------------------------------------------
+
+    async fn process(qname: &str, to: &str) -> Message<Vec<u8>> {
+        // A service that answers the query name it was asked about, but only
+        // for names under the rewritten suffix.
+        fn my_service(
+            req: Request<Vec<u8>>,
+            _meta: (),
+        ) -> ServiceResult<Vec<u8>> {
+            let question = req.message().sole_question().unwrap();
+            let qname = question.qname();
+            let builder = mk_builder_for_target();
+            let mut answer =
+                builder.start_answer(req.message(), Rcode::NOERROR)?;
+            if qname.to_string().ends_with("new.example") {
+                answer.push((
+                    qname,
+                    Class::IN,
+                    3600,
+                    A::from_octets(192, 0, 2, 1),
+                ))?;
+            }
+            Ok(CallResult::new(answer.additional()))
+        }
+
+        let svc = RewriteService::new(service_fn(my_service, ()))
+            .with_rewrite(
+                &Name::<Vec<u8>>::from_str("old.example").unwrap(),
+                &Name::<Vec<u8>>::from_str(to).unwrap(),
+            );
+
+        let mut query = MessageBuilder::new_vec().question();
+        query
+            .push((Name::<Vec<u8>>::from_str(qname).unwrap(), Rtype::A))
+            .unwrap();
+        let request = Request::for_test(
+            query.into_message(),
+            UdpTransportContext::default(),
+            "127.0.0.1:12345".parse().unwrap(),
+        );
+
+        let mut stream = svc.call(request).await;
+        let call_result: CallResult<Vec<u8>> =
+            stream.next().await.unwrap().unwrap();
+        let response = call_result.into_inner().0.unwrap().finish();
+        Message::from_octets(response.as_dgram_slice().to_vec()).unwrap()
+    }
+}
@@ -4,17 +4,29 @@
 //! given DNS request. resulting in a future that yields a stream of one or
 //! more future DNS responses, and/or [`ServiceFeedback`].
 use core::fmt::Display;
+use core::future::{ready, Future, Ready};
 use core::ops::Deref;
+use core::pin::Pin;
+use core::task::{Context, Poll};
 
+use std::net::SocketAddr;
 use std::time::Duration;
 use std::vec::Vec;
 
-use crate::base::iana::Rcode;
+use futures_util::stream::{once, Once};
+use tokio::sync::oneshot;
+use tokio::time::Instant;
+
+use tracing::warn;
+
+use crate::base::iana::{ExtendedErrorCode, Rcode};
 use crate::base::message_builder::{AdditionalBuilder, PushError};
-use crate::base::wire::ParseError;
+use crate::base::opt::ExtendedError;
+use crate::base::wire::{Composer, ParseError};
 use crate::base::StreamTarget;
 
 use super::message::Request;
+use super::util::add_edns_options;
 
 //------------ Service -------------------------------------------------------
 
@@ -291,6 +303,22 @@ pub enum ServiceFeedback {
 
     /// Signal to the server that the transaction that we began has ended.
     EndTransaction,
+
+    /// Ask the server to pause reading new requests until the given instant.
+    ///
+    /// This allows an overloaded `Service` to shed load cooperatively rather
+    /// than by failing requests. While paused, requests that were already
+    /// read continue to be processed and their responses continue to be
+    /// sent, and server commands such as shutdown continue to be obeyed.
+    ///
+    /// The pause is limited to at most five seconds from when the feedback
+    /// is received. If the server is already paused until a later instant
+    /// the feedback has no effect. For connection-oriented servers only the
+    /// current connection is paused.
+    ApplyBackpressure {
+        /// The instant until which the server should pause.
+        until: Instant,
+    },
 }
 
 //------------ CallResult ----------------------------------------------------
@@ -305,6 +333,12 @@ pub enum ServiceFeedback {
 /// [`ServiceFeedback`] directing the server or connection handler handling
 /// the request to adjust its own configuration, or even to terminate the
 /// connection.
+///
+/// For advanced use, such as relaying or test harnesses, a datagram server
+/// can be directed to send the response to a different address than the
+/// client, see [`with_destination()`].
+///
+/// [`with_destination()`]: Self::with_destination
 #[derive(Clone, Debug)]
 pub struct CallResult<Target> {
     /// Optional response to send back to the client.
@@ -312,6 +346,12 @@ pub struct CallResult<Target> {
 
     /// Optional feedback from the `Service` to the server.
     feedback: Option<ServiceFeedback>,
+
+    /// Optional address to send the response to instead of the client.
+    destination: Option<SocketAddr>,
+
+    /// Extended DNS errors to add to the response when it is finalized.
+    edes: Vec<ExtendedError<Vec<u8>>>,
 }
 
 impl<Target> CallResult<Target> {
@@ -321,6 +361,8 @@ impl<Target> CallResult<Target> {
         Self {
             response: Some(response),
             feedback: None,
+            destination: None,
+            edes: Vec::new(),
         }
     }
 
@@ -330,6 +372,8 @@ impl<Target> CallResult<Target> {
         Self {
             response: None,
             feedback: Some(command),
+            destination: None,
+            edes: Vec::new(),
         }
     }
 
@@ -340,6 +384,39 @@ impl<Target> CallResult<Target> {
         self
     }
 
+    /// Send the response to the given address instead of the client.
+    ///
+    /// This is meant for advanced uses such as DNS relays and test harnesses
+    /// that redirect responses. Only the [`DgramServer`] honors the
+    /// destination, and only if enabled via
+    /// [`Config::set_allow_response_redirection()`], otherwise the response
+    /// is sent to the client as usual. Connection-oriented servers always
+    /// respond on the connection the request was received on.
+    ///
+    /// # Security
+    ///
+    /// Sending responses to an address other than the one a request came
+    /// from turns the server into a potential reflector: anyone able to
+    /// influence the destination can direct traffic at a third party. Only
+    /// derive the destination from trusted configuration, never from the
+    /// content of the request itself.
+    ///
+    /// [`DgramServer`]: super::dgram::DgramServer
+    /// [`Config::set_allow_response_redirection()`]:
+    ///     super::dgram::Config::set_allow_response_redirection
+    #[must_use]
+    pub fn with_destination(mut self, destination: SocketAddr) -> Self {
+        self.destination = Some(destination);
+        self
+    }
+
+    /// Get the address to send the response to instead of the client, if
+    /// any.
+    #[must_use]
+    pub fn destination(&self) -> Option<SocketAddr> {
+        self.destination
+    }
+
     /// Get the contained feedback, if any.
     #[must_use]
     pub fn feedback(&self) -> Option<ServiceFeedback> {
@@ -354,25 +431,111 @@ impl<Target> CallResult<Target> {
         self.response.as_ref()
     }
 
+    /// Remove the contained DNS response message, if any.
+    ///
+    /// The feedback, if any, is retained. A [`CallResult`] without a
+    /// response causes the server to send nothing to the client.
+    pub fn take_response(
+        &mut self,
+    ) -> Option<AdditionalBuilder<StreamTarget<Target>>> {
+        self.response.take()
+    }
+
+    /// Discard the Extended DNS Errors added via [`Self::with_ede`].
+    ///
+    /// This is meant for middleware that knows that the response must not
+    /// carry an OPT record, e.g. because the request lacked one.
+    pub fn clear_edes(&mut self) {
+        self.edes.clear();
+    }
+}
+
+impl<Target: Composer> CallResult<Target> {
+    /// Adds an [RFC 8914] Extended DNS Error option to the response, if any.
+    ///
+    /// The option is not added right away but when the response is first
+    /// accessed via [`Self::response_mut`], i.e. by the innermost middleware
+    /// post-processing the response, or, if there is no such middleware,
+    /// when the response is finalized via [`Self::into_inner`]. This way
+    /// the option is in place before middleware truncates, pads or signs the
+    /// response. It is added to the OPT record of the response, which is
+    /// created if the response lacks one. Any options already present are
+    /// kept. An empty `text` adds the option without extra text, as does a
+    /// text too long to fit into the option.
+    ///
+    /// The service doesn't need to check whether the request has an OPT
+    /// record itself as [`EdnsMiddlewareSvc`] and [`MandatoryMiddlewareSvc`]
+    /// remove the OPT record from responses to requests without one.
+    ///
+    /// If the option cannot be added, e.g. because the response would
+    /// become too large, a warning is logged.
+    ///
+    /// [RFC 8914]: https://www.rfc-editor.org/rfc/rfc8914.html
+    /// [`EdnsMiddlewareSvc`]: super::middleware::edns::EdnsMiddlewareSvc
+    /// [`MandatoryMiddlewareSvc`]:
+    ///     super::middleware::mandatory::MandatoryMiddlewareSvc
+    #[must_use]
+    pub fn with_ede(mut self, code: ExtendedErrorCode, text: &str) -> Self {
+        let ede = match text {
+            "" => ExtendedError::new(code, None),
+            text => ExtendedError::new_with_str(code, text).or_else(|_| {
+                warn!("Extended error text is too long, leaving it out");
+                ExtendedError::new(code, None)
+            }),
+        };
+        match ede {
+            Ok(ede) => self.edes.push(ede),
+            Err(err) => warn!("Unable to create extended error: {err}"),
+        }
+        self
+    }
+
     /// Get a mutable reference to the contained DNS response message, if any.
+    ///
+    /// Any Extended DNS Errors added via [`Self::with_ede`] but not yet
+    /// added to the response are added before the reference is returned.
     #[must_use]
     pub fn response_mut(
         &mut self,
     ) -> Option<&mut AdditionalBuilder<StreamTarget<Target>>> {
+        self.add_edes();
         self.response.as_mut()
     }
 
     /// Convert the [`CallResult`] into the contained DNS response message and command.
+    ///
+    /// Any Extended DNS Errors added via [`Self::with_ede`] but not yet
+    /// added to the response are added at this point.
     #[must_use]
     pub fn into_inner(
-        self,
+        mut self,
     ) -> (
         Option<AdditionalBuilder<StreamTarget<Target>>>,
         Option<ServiceFeedback>,
     ) {
-        let CallResult { response, feedback } = self;
+        self.add_edes();
+        let CallResult {
+            response, feedback, ..
+        } = self;
         (response, feedback)
     }
+
+    /// Adds the pending Extended DNS Errors to the response, if any.
+    fn add_edes(&mut self) {
+        if self.edes.is_empty() {
+            return;
+        }
+        let edes = core::mem::take(&mut self.edes);
+        let Some(response) = &mut self.response else {
+            return;
+        };
+        let res = add_edns_options(response, |builder| {
+            edes.iter().try_for_each(|ede| builder.push(ede))
+        });
+        if let Err(err) = res {
+            warn!("Unable to add extended error to response: {err}");
+        }
+    }
 }
 
 //--- From<AdditionalBuilder>
@@ -392,3 +555,203 @@ impl<Target> From<ServiceFeedback> for CallResult<Target> {
         Self::feedback_only(feedback)
     }
 }
+
+//------------ DeferredResponse ----------------------------------------------
+
+/// A single response that completes once an external event has happened.
+///
+/// Some services can only respond after something outside of the service
+/// has happened, e.g. a dynamic update has been confirmed by a backend. Such
+/// a service can hand the [`oneshot::Sender`] half of a channel to whatever
+/// will produce the response and return a `DeferredResponse` created from
+/// the receiving half as its [`Service::Future`]. It resolves to a stream
+/// yielding whatever result is sent over the channel.
+///
+/// If the sender is dropped without sending anything the request is
+/// answered with SERVFAIL. If the sender is kept but never used, the request
+/// is only answered once the request timeout configured on the server
+/// expires, at which point it is answered with SERVFAIL, see for example
+/// [`dgram::Config::set_request_timeout`]. Without a request timeout such a
+/// request is never answered.
+///
+/// [`dgram::Config::set_request_timeout`]:
+///     crate::net::server::dgram::Config::set_request_timeout
+#[derive(Debug)]
+pub struct DeferredResponse<Target> {
+    /// The receiving half of the channel the response will arrive on.
+    rx: oneshot::Receiver<ServiceResult<Target>>,
+}
+
+impl<Target> DeferredResponse<Target> {
+    /// Creates a response that resolves when `rx` receives a result.
+    #[must_use]
+    pub fn from_oneshot(
+        rx: oneshot::Receiver<ServiceResult<Target>>,
+    ) -> Self {
+        Self { rx }
+    }
+
+    /// Creates a new channel and the response waiting on it.
+    #[must_use]
+    pub fn channel() -> (oneshot::Sender<ServiceResult<Target>>, Self) {
+        let (tx, rx) = oneshot::channel();
+        (tx, Self::from_oneshot(rx))
+    }
+}
+
+//--- Future
+
+impl<Target> Future for DeferredResponse<Target> {
+    type Output = Once<Ready<ServiceResult<Target>>>;
+
+    fn poll(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Self::Output> {
+        Pin::new(&mut self.rx).poll(cx).map(|res| {
+            let res = res.unwrap_or_else(|_| {
+                warn!("Deferred response abandoned without a result");
+                Err(ServiceError::InternalError)
+            });
+            once(ready(res))
+        })
+    }
+}
+
+//============ Tests =========================================================
+
+#[cfg(test)]
+mod tests {
+    use std::vec::Vec;
+
+    use futures_util::StreamExt;
+
+    use crate::base::iana::{ExtendedErrorCode, Rcode};
+    use crate::base::message_builder::AdditionalBuilder;
+    use crate::base::opt::{ExtendedError, Nsid};
+    use crate::base::{Message, MessageBuilder, Name, Rtype, StreamTarget};
+    use crate::net::server::util::mk_builder_for_target;
+
+    use super::{CallResult, DeferredResponse, ServiceError};
+
+    #[test]
+    fn ede_is_added_to_response() {
+        let result = CallResult::new(mk_response(false))
+            .with_ede(ExtendedErrorCode::PROHIBITED, "not for you");
+
+        let response = finish(result);
+        let opt = response.opt().unwrap();
+        let ede = opt.opt().iter::<ExtendedError<_>>().next().unwrap();
+        let ede = ede.unwrap();
+        assert_eq!(ede.code(), ExtendedErrorCode::PROHIBITED);
+        assert_eq!(ede.text_slice(), Some(b"not for you".as_ref()));
+    }
+
+    #[test]
+    fn ede_is_added_on_first_mutable_access() {
+        let mut result = CallResult::new(mk_response(false))
+            .with_ede(ExtendedErrorCode::PROHIBITED, "");
+        assert!(result.response().unwrap().as_message().opt().is_none());
+
+        // Clearing before the response is accessed drops the error.
+        let mut cleared = result.clone();
+        cleared.clear_edes();
+        assert!(finish(cleared).opt().is_none());
+
+        let response = result.response_mut().unwrap().as_message();
+        let opt = response.opt().unwrap();
+        let ede = opt.opt().iter::<ExtendedError<_>>().next().unwrap();
+        assert_eq!(ede.unwrap().code(), ExtendedErrorCode::PROHIBITED);
+
+        // The error is only added once.
+        let response = finish(result);
+        let opt = response.opt().unwrap();
+        assert_eq!(opt.opt().iter::<ExtendedError<_>>().count(), 1);
+    }
+
+    #[test]
+    fn ede_coexists_with_other_options() {
+        let result = CallResult::new(mk_response(true))
+            .with_ede(ExtendedErrorCode::STALE_ANSWER, "")
+            .with_ede(ExtendedErrorCode::OTHER, "second");
+
+        let response = finish(result);
+        let opt = response.opt().unwrap();
+        assert_eq!(opt.udp_payload_size(), 1232);
+        assert_eq!(
+            opt.opt()
+                .iter::<Nsid<_>>()
+                .next()
+                .unwrap()
+                .unwrap()
+                .as_slice(),
+            b"ns1"
+        );
+        let codes: Vec<_> = opt
+            .opt()
+            .iter::<ExtendedError<_>>()
+            .map(|ede| ede.unwrap().code())
+            .collect();
+        assert_eq!(
+            codes,
+            [ExtendedErrorCode::STALE_ANSWER, ExtendedErrorCode::OTHER]
+        );
+
+        // Only the one OPT record exists.
+        assert_eq!(response.header_counts().arcount(), 1);
+    }
+
+    #[tokio::test]
+    async fn deferred_response_resolves_when_sent() {
+        let (tx, response) = DeferredResponse::channel();
+        let task = tokio::spawn(async move {
+            let mut stream = response.await;
+            let item = stream.next().await.unwrap();
+            assert!(stream.next().await.is_none());
+            item
+        });
+        tx.send(Ok(CallResult::new(mk_response(false)))).unwrap();
+
+        let result = task.await.unwrap().unwrap();
+        assert_eq!(finish(result).header().rcode(), Rcode::REFUSED);
+    }
+
+    #[tokio::test]
+    async fn abandoned_deferred_response_is_an_error() {
+        let (tx, response) = DeferredResponse::<Vec<u8>>::channel();
+        drop(tx);
+
+        let item = response.await.next().await.unwrap();
+        assert!(matches!(item, Err(ServiceError::InternalError)));
+    }
+
+    //------------ Helper functions ------------------------------------------
+
+    fn mk_response(
+        with_opt: bool,
+    ) -> AdditionalBuilder<StreamTarget<Vec<u8>>> {
+        let mut query = MessageBuilder::new_vec().question();
+        query.push((Name::root_ref(), Rtype::A)).unwrap();
+        let query = query.into_message();
+
+        let mut response = mk_builder_for_target()
+            .start_answer(&query, Rcode::REFUSED)
+            .unwrap()
+            .additional();
+        if with_opt {
+            response
+                .opt(|opt| {
+                    opt.set_udp_payload_size(1232);
+                    opt.nsid(b"ns1").unwrap();
+                    Ok(())
+                })
+                .unwrap();
+        }
+        response
+    }
+
+    fn finish(result: CallResult<Vec<u8>>) -> Message<Vec<u8>> {
+        let response = result.into_inner().0.unwrap().finish();
+        Message::from_octets(response.as_dgram_slice().to_vec()).unwrap()
+    }
+}
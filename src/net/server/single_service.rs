@@ -11,6 +11,7 @@
 
 use super::message::Request;
 use super::service::ServiceError;
+use crate::base::message::debug_validate_message;
 use crate::base::message_builder::AdditionalBuilder;
 use crate::base::opt::{AllOptData, ComposeOptData, LongOptData, OptRecord};
 use crate::base::{Message, MessageBuilder, ParsedName, Rtype, StreamTarget};
@@ -20,6 +21,7 @@ use std::boxed::Box;
 use std::future::Future;
 use std::pin::Pin;
 use std::vec::Vec;
+use tracing::warn;
 
 /// Trait for a service that results in a single response.
 pub trait SingleService<RequestOcts: Send + Sync, CR> {
@@ -179,6 +181,9 @@ impl ComposeReply for ReplyMessage {
             target.push(opt.as_record()).expect("push should not fail");
         }
 
+        if let Err(err) = debug_validate_message(&target.as_message()) {
+            warn!("Rebuilt message is invalid: {err}");
+        }
         Ok(target)
     }
 }
@@ -1,14 +1,37 @@
 //! Network socket abstractions.
+#[cfg(feature = "tls")]
+use core::time::Duration;
+
+#[cfg(unix)]
+use std::collections::hash_map::RandomState;
 use std::io;
+#[cfg(unix)]
+use std::net::Ipv4Addr;
 use std::net::SocketAddr;
+#[cfg(unix)]
+use std::path::Path;
 use std::task::{Context, Poll};
 
 use std::boxed::Box;
+use std::fmt::{Debug, Display};
 use std::future::Future;
+use std::hash::Hash;
+#[cfg(unix)]
+use std::hash::{BuildHasher, Hasher};
 use std::pin::Pin;
 use std::sync::Arc;
+use std::sync::Mutex;
+use std::vec::Vec;
+
+use socket2::{Domain, Protocol, Socket, Type};
 use tokio::io::ReadBuf;
+#[cfg(unix)]
+use tokio::net::UnixDatagram;
 use tokio::net::{TcpListener, TcpStream, UdpSocket};
+#[cfg(feature = "tls")]
+use tokio::time::timeout;
+#[cfg(unix)]
+use tracing::trace;
 
 //------------ AsyncDgramSock ------------------------------------------------
 
@@ -31,14 +54,25 @@ use tokio::net::{TcpListener, TcpStream, UdpSocket};
 /// [`Arc::clone`] the socket and use it with multiple server instances at
 /// once for greater throughput without any such locking occurring.
 ///
+/// # Peer addresses
+///
+/// Peers are identified by the associated [`Addr`] type. This is
+/// [`SocketAddr`] for IP based sockets but can be any type implementing
+/// [`PeerAddr`], allowing transports whose peers are identified differently
+/// to be used with a [`DgramServer`].
+///
+/// [`Addr`]: Self::Addr
 /// [`DgramServer`]: crate::net::server::stream::DgramServer.
 pub trait AsyncDgramSock {
+    /// The type of the addresses of peers of the socket.
+    type Addr: PeerAddr;
+
     /// Attempts to send data on the socket to a given address.
     fn poll_send_to(
         &self,
         cx: &mut Context,
         data: &[u8],
-        dest: &SocketAddr,
+        dest: &Self::Addr,
     ) -> Poll<io::Result<usize>>;
 
     /// Waits for the socket to become readable.
@@ -61,10 +95,26 @@ pub trait AsyncDgramSock {
     fn try_recv_buf_from(
         &self,
         buf: &mut ReadBuf<'_>,
-    ) -> io::Result<(usize, SocketAddr)>;
+    ) -> io::Result<(usize, Self::Addr)>;
+
+    /// Returns the local address that this socket is bound to.
+    ///
+    /// The [`DgramServer`] passes this on to services via
+    /// [`Request::local_addr()`]. The default implementation returns an
+    /// error of kind [`io::ErrorKind::Unsupported`], in which case the local
+    /// address of requests is unknown.
+    ///
+    /// [`DgramServer`]: crate::net::server::dgram::DgramServer
+    /// [`Request::local_addr()`]:
+    ///     crate::net::server::message::Request::local_addr
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
 }
 
 impl AsyncDgramSock for UdpSocket {
+    type Addr = SocketAddr;
+
     fn poll_send_to(
         &self,
         cx: &mut Context,
@@ -86,9 +136,15 @@ impl AsyncDgramSock for UdpSocket {
     ) -> io::Result<(usize, SocketAddr)> {
         UdpSocket::try_recv_buf_from(self, buf)
     }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        UdpSocket::local_addr(self)
+    }
 }
 
 impl AsyncDgramSock for Arc<UdpSocket> {
+    type Addr = SocketAddr;
+
     fn poll_send_to(
         &self,
         cx: &mut Context,
@@ -110,6 +166,286 @@ impl AsyncDgramSock for Arc<UdpSocket> {
     ) -> io::Result<(usize, SocketAddr)> {
         UdpSocket::try_recv_buf_from(self, buf)
     }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        UdpSocket::local_addr(self)
+    }
+}
+
+//------------ PeerAddr ------------------------------------------------------
+
+/// The address of a peer of an [`AsyncDgramSock`].
+///
+/// Services are told the address of the client of a request as a
+/// [`SocketAddr`], see [`Request::client_addr()`]. Peer addresses of other
+/// kinds therefore need to be represented as one.
+///
+/// [`Request::client_addr()`]:
+///     crate::net::server::message::Request::client_addr
+pub trait PeerAddr:
+    Clone + Debug + Display + Eq + Hash + Send + Sync + 'static
+{
+    /// Returns the address to present to services as the client address.
+    ///
+    /// This address is also used to assign clients to servers when using
+    /// [`WorkerAffinity`].
+    ///
+    /// [`WorkerAffinity`]: crate::net::server::dgram::WorkerAffinity
+    fn client_addr(&self) -> SocketAddr;
+
+    /// Converts an address given by a service into a peer address.
+    ///
+    /// This is used to send a response to a destination requested by a
+    /// service via [`CallResult::with_destination()`]. Returns `None` if
+    /// responses cannot be sent to the given address, which is what the
+    /// default implementation always does.
+    ///
+    /// [`CallResult::with_destination()`]:
+    ///     crate::net::server::service::CallResult::with_destination
+    fn from_client_addr(addr: SocketAddr) -> Option<Self> {
+        let _ = addr;
+        None
+    }
+}
+
+impl PeerAddr for SocketAddr {
+    fn client_addr(&self) -> SocketAddr {
+        *self
+    }
+
+    fn from_client_addr(addr: SocketAddr) -> Option<Self> {
+        Some(addr)
+    }
+}
+
+//------------ UnixDgramSock -------------------------------------------------
+
+/// A Unix domain datagram socket usable with a [`DgramServer`].
+///
+/// Peers of a Unix domain socket are identified by the filesystem path of
+/// their socket, see [`UnixPeerAddr`]. Datagrams received from unnamed
+/// sockets are discarded as there is no way to send a response to them.
+///
+/// [`DgramServer`]: crate::net::server::dgram::DgramServer
+#[cfg(unix)]
+#[derive(Debug)]
+pub struct UnixDgramSock {
+    /// The underlying socket.
+    sock: UnixDatagram,
+
+    /// The hasher used to derive client addresses from peer paths.
+    hasher: RandomState,
+}
+
+#[cfg(unix)]
+impl UnixDgramSock {
+    /// Wraps an existing Unix domain datagram socket.
+    pub fn new(sock: UnixDatagram) -> Self {
+        Self {
+            sock,
+            hasher: RandomState::new(),
+        }
+    }
+
+    /// Creates a socket bound to the given filesystem path.
+    ///
+    /// # Panics
+    ///
+    /// Panics if not called from within a Tokio runtime.
+    pub fn bind(path: impl AsRef<Path>) -> io::Result<Self> {
+        UnixDatagram::bind(path).map(Self::new)
+    }
+
+    /// Returns a reference to the underlying socket.
+    pub fn get_ref(&self) -> &UnixDatagram {
+        &self.sock
+    }
+
+    /// Returns the client address to present to services for a peer path.
+    fn client_addr(&self, path: &Path) -> SocketAddr {
+        let mut hasher = self.hasher.build_hasher();
+        path.hash(&mut hasher);
+        let [a, b, c, d, e, ..] = hasher.finish().to_be_bytes();
+        SocketAddr::new(
+            Ipv4Addr::new(127, a, b, c).into(),
+            u16::from_be_bytes([d, e]),
+        )
+    }
+}
+
+#[cfg(unix)]
+impl AsyncDgramSock for UnixDgramSock {
+    type Addr = UnixPeerAddr;
+
+    fn poll_send_to(
+        &self,
+        cx: &mut Context,
+        data: &[u8],
+        dest: &UnixPeerAddr,
+    ) -> Poll<io::Result<usize>> {
+        self.sock.poll_send_to(cx, data, dest.path())
+    }
+
+    fn readable(
+        &self,
+    ) -> Pin<Box<dyn Future<Output = io::Result<()>> + '_ + Send>> {
+        Box::pin(self.sock.readable())
+    }
+
+    fn try_recv_buf_from(
+        &self,
+        buf: &mut ReadBuf<'_>,
+    ) -> io::Result<(usize, UnixPeerAddr)> {
+        loop {
+            let (len, addr) =
+                self.sock.try_recv_from(buf.initialize_unfilled())?;
+            if let Some(path) = addr.as_pathname() {
+                buf.advance(len);
+                let addr = UnixPeerAddr {
+                    path: path.into(),
+                    client_addr: self.client_addr(path),
+                };
+                return Ok((len, addr));
+            }
+            trace!("Discarding datagram from unnamed Unix domain socket");
+        }
+    }
+}
+
+//------------ UnixPeerAddr --------------------------------------------------
+
+/// The address of a peer of a [`UnixDgramSock`].
+///
+/// A peer is identified by the filesystem path of its socket. As services
+/// are told the client of a request as a [`SocketAddr`], each peer is also
+/// given a synthetic client address derived from its path. The address is
+/// taken from all of `127.0.0.0/8` rather than just `127.0.0.1` so that
+/// middleware grouping clients by address, such as access control and rate
+/// limiting, can tell peers apart. Rules for `127.0.0.0/8` match all peers.
+/// Responses are always sent to the path, so a service cannot direct a
+/// response elsewhere via [`CallResult::with_destination()`].
+///
+/// [`CallResult::with_destination()`]:
+///     crate::net::server::service::CallResult::with_destination
+#[cfg(unix)]
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct UnixPeerAddr {
+    /// The path of the peer socket.
+    path: Arc<Path>,
+
+    /// The address presented to services as the client address.
+    client_addr: SocketAddr,
+}
+
+#[cfg(unix)]
+impl UnixPeerAddr {
+    /// Returns the path of the peer socket.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+#[cfg(unix)]
+impl Display for UnixPeerAddr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.path.display())
+    }
+}
+
+#[cfg(unix)]
+impl PeerAddr for UnixPeerAddr {
+    fn client_addr(&self) -> SocketAddr {
+        self.client_addr
+    }
+}
+
+//------------ bind_reuse_port -----------------------------------------------
+
+/// Binds several UDP sockets to the same address.
+///
+/// Sharing a single [`Arc`]ed socket between multiple [`DgramServer`]s, as
+/// described for [`AsyncDgramSock`], funnels all received datagrams through
+/// the one receive queue of that socket. This function instead binds `n`
+/// separate sockets to `addr` with the `SO_REUSEPORT` socket option set so
+/// that each server can be given its own socket and thus its own receive
+/// queue.
+///
+/// If the port of `addr` is zero, the first socket is bound to a port of
+/// the operating system's choosing and the remaining sockets are bound to
+/// that same port.
+///
+/// On Linux the kernel distributes incoming datagrams across all the
+/// sockets. The BSDs and macOS support the option too but may deliver all
+/// datagrams from a given client, or even all datagrams, to just one of the
+/// sockets.
+///
+/// # Fallback
+///
+/// On platforms without support for `SO_REUSEPORT`, such as Windows, only a
+/// single socket is bound and returned regardless of `n`. Share it between
+/// servers by wrapping it in an [`Arc`] instead.
+///
+/// # Panics
+///
+/// Must be called from within a Tokio runtime, otherwise the conversion to
+/// [`tokio::net::UdpSocket`] panics.
+///
+/// [`DgramServer`]: crate::net::server::dgram::DgramServer
+pub fn bind_reuse_port(
+    addr: SocketAddr,
+    n: usize,
+) -> io::Result<Vec<UdpSocket>> {
+    let mut socks = Vec::with_capacity(n);
+    if n == 0 {
+        return Ok(socks);
+    }
+
+    let (first, reuse_port) = bind_one_reuse_port(addr)?;
+    let addr = first.local_addr()?;
+    socks.push(first);
+
+    if reuse_port {
+        for _ in 1..n {
+            socks.push(bind_one_reuse_port(addr)?.0);
+        }
+    }
+
+    Ok(socks)
+}
+
+/// Binds a single non-blocking UDP socket with `SO_REUSEPORT` set, if
+/// supported.
+///
+/// Returns the socket and whether `SO_REUSEPORT` was set.
+fn bind_one_reuse_port(addr: SocketAddr) -> io::Result<(UdpSocket, bool)> {
+    let sock = Socket::new(
+        Domain::for_address(addr),
+        Type::DGRAM,
+        Some(Protocol::UDP),
+    )?;
+    let reuse_port = set_reuse_port(&sock)?;
+    sock.set_nonblocking(true)?;
+    sock.bind(&addr.into())?;
+    Ok((UdpSocket::from_std(sock.into())?, reuse_port))
+}
+
+/// Sets `SO_REUSEPORT` on the socket if the platform supports it.
+///
+/// Returns whether the option was set.
+#[allow(unreachable_code, unused_variables)]
+fn set_reuse_port(sock: &Socket) -> io::Result<bool> {
+    #[cfg(any(
+        target_os = "linux",
+        target_os = "android",
+        target_os = "freebsd",
+        target_os = "netbsd",
+        target_os = "openbsd",
+        target_os = "dragonfly",
+        target_os = "macos",
+        target_os = "ios",
+    ))]
+    return sock.set_reuse_port(true).map(|()| true);
+    Ok(false)
 }
 
 //------------ AsyncAccept ---------------------------------------------------
@@ -139,6 +475,20 @@ pub trait AsyncAccept {
         &self,
         cx: &mut Context,
     ) -> Poll<io::Result<(Self::Future, SocketAddr)>>;
+
+    /// Returns the address of the client of an accepted stream.
+    ///
+    /// Invoked with the stream resolved from the future returned by
+    /// [`poll_accept`][Self::poll_accept] and the address returned along
+    /// with it. The default implementation returns that address. Listeners
+    /// that learn the actual client address from the stream itself, e.g.
+    /// when behind a load balancer, can return that address instead.
+    fn client_addr(
+        _stream: &Self::StreamType,
+        accepted_from: SocketAddr,
+    ) -> SocketAddr {
+        accepted_from
+    }
 }
 
 impl AsyncAccept for TcpListener {
@@ -150,14 +500,291 @@ impl AsyncAccept for TcpListener {
         &self,
         cx: &mut Context,
     ) -> Poll<io::Result<(Self::Future, SocketAddr)>> {
+        // Socket options of accepted streams can be set by wrapping the
+        // listener in a ConfiguredTcpListener.
         TcpListener::poll_accept(self, cx).map(|res| {
-            // TODO: Should we support some sort of callback here to set
-            // arbitrary socket options? E.g. TCP keep alive ala
-            // https://stackoverflow.com/a/75697898 ? Or is it okay that this
-            // is the plain implementation and users who want to set things
-            // like TCP keep alive would need to provide their own impl? (just
-            // as the serve example currently does).
             res.map(|(stream, addr)| (std::future::ready(Ok(stream)), addr))
         })
     }
 }
+
+//------------ ConfiguredTcpListener -----------------------------------------
+
+/// A TCP listener that configures each accepted stream.
+///
+/// The given function is invoked once for every accepted stream before the
+/// stream is used, e.g. to set socket options such as TCP keep-alive,
+/// `TCP_NODELAY` or `SO_LINGER`. Unlike the [pre-connect hook] of a
+/// [`StreamServer`] the function can hold state and can fail. If it fails
+/// the stream is closed without serving the connection.
+///
+/// ```ignore
+/// let listener = ConfiguredTcpListener::new(listener, |stream| {
+///     let keep_alive = socket2::TcpKeepalive::new()
+///         .with_time(Duration::from_secs(20));
+///     socket2::SockRef::from(stream).set_tcp_keepalive(&keep_alive)?;
+///     stream.set_nodelay(true)
+/// });
+/// let srv = StreamServer::new(listener, VecBufSource::default(), svc);
+/// ```
+///
+/// [pre-connect hook]: crate::net::server::stream::StreamServer::with_pre_connect_hook
+/// [`StreamServer`]: crate::net::server::stream::StreamServer
+pub struct ConfiguredTcpListener<F> {
+    /// The listener accepting the streams.
+    listener: TcpListener,
+
+    /// The function configuring accepted streams.
+    configure: Mutex<F>,
+}
+
+impl<F> ConfiguredTcpListener<F>
+where
+    F: FnMut(&TcpStream) -> io::Result<()>,
+{
+    /// Creates a listener configuring streams accepted by `listener`.
+    pub fn new(listener: TcpListener, configure: F) -> Self {
+        Self {
+            listener,
+            configure: Mutex::new(configure),
+        }
+    }
+
+    /// Returns the wrapped listener.
+    pub fn listener(&self) -> &TcpListener {
+        &self.listener
+    }
+}
+
+impl<F> AsyncAccept for ConfiguredTcpListener<F>
+where
+    F: FnMut(&TcpStream) -> io::Result<()>,
+{
+    type Error = io::Error;
+    type StreamType = TcpStream;
+    type Future = std::future::Ready<Result<Self::StreamType, io::Error>>;
+
+    fn poll_accept(
+        &self,
+        cx: &mut Context,
+    ) -> Poll<io::Result<(Self::Future, SocketAddr)>> {
+        TcpListener::poll_accept(&self.listener, cx).map(|res| {
+            res.map(|(stream, addr)| {
+                // A panic in the function poisons the lock but leaves
+                // nothing inconsistent that we rely on.
+                let mut configure = self
+                    .configure
+                    .lock()
+                    .unwrap_or_else(|err| err.into_inner());
+                let res = configure(&stream).map(|()| stream);
+                (std::future::ready(res), addr)
+            })
+        })
+    }
+}
+
+impl<F> Debug for ConfiguredTcpListener<F> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("ConfiguredTcpListener")
+            .field("listener", &self.listener)
+            .finish_non_exhaustive()
+    }
+}
+
+//------------ RustlsTcpListener ---------------------------------------------
+
+/// A TCP listener accepting TLS connections.
+///
+/// Each accepted TCP stream is secured using the given
+/// [`tokio_rustls::TlsAcceptor`], with the TLS handshake performed by the
+/// future returned by [`AsyncAccept::poll_accept`]. This allows a
+/// [`StreamServer`] to serve DNS over TLS ([RFC 7858]), normally on port
+/// 853. Connections for which the handshake fails or doesn't complete in
+/// time are closed.
+///
+/// ```ignore
+/// let config = rustls::ServerConfig::builder()
+///     .with_no_client_auth()
+///     .with_single_cert(certs, key)?;
+/// let acceptor = TlsAcceptor::from(Arc::new(config));
+/// let listener = TcpListener::bind("[::]:853").await?;
+/// let listener = RustlsTcpListener::new(listener, acceptor);
+/// let srv = StreamServer::new(listener, VecBufSource::default(), svc);
+/// ```
+///
+/// [`StreamServer`]: crate::net::server::stream::StreamServer
+/// [RFC 7858]: https://www.rfc-editor.org/rfc/rfc7858
+#[cfg(feature = "tls")]
+pub struct RustlsTcpListener {
+    /// The listener accepting the TCP streams.
+    listener: TcpListener,
+
+    /// The acceptor performing the TLS handshake on accepted streams.
+    acceptor: tokio_rustls::TlsAcceptor,
+
+    /// The time allowed for completing the TLS handshake of a stream.
+    handshake_timeout: Duration,
+}
+
+#[cfg(feature = "tls")]
+impl RustlsTcpListener {
+    /// The default time allowed for completing the TLS handshake.
+    pub const DEFAULT_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+    /// Creates a listener securing streams accepted by `listener`.
+    pub fn new(
+        listener: TcpListener,
+        acceptor: tokio_rustls::TlsAcceptor,
+    ) -> Self {
+        Self {
+            listener,
+            acceptor,
+            handshake_timeout: Self::DEFAULT_HANDSHAKE_TIMEOUT,
+        }
+    }
+
+    /// Sets the time allowed for completing the TLS handshake of a stream.
+    ///
+    /// Streams whose handshake isn't completed in time are closed.
+    #[must_use]
+    pub fn with_handshake_timeout(
+        mut self,
+        handshake_timeout: Duration,
+    ) -> Self {
+        self.handshake_timeout = handshake_timeout;
+        self
+    }
+
+    /// Returns the wrapped listener.
+    pub fn listener(&self) -> &TcpListener {
+        &self.listener
+    }
+}
+
+#[cfg(feature = "tls")]
+impl AsyncAccept for RustlsTcpListener {
+    type Error = io::Error;
+    type StreamType = tokio_rustls::server::TlsStream<TcpStream>;
+    type Future =
+        Pin<Box<dyn Future<Output = io::Result<Self::StreamType>> + Send>>;
+
+    fn poll_accept(
+        &self,
+        cx: &mut Context,
+    ) -> Poll<io::Result<(Self::Future, SocketAddr)>> {
+        let handshake_timeout = self.handshake_timeout;
+        TcpListener::poll_accept(&self.listener, cx).map(|res| {
+            res.map(|(stream, addr)| {
+                let accept = self.acceptor.accept(stream);
+                let fut: Self::Future = Box::pin(async move {
+                    timeout(handshake_timeout, accept).await.map_err(
+                        |_| {
+                            io::Error::new(
+                                io::ErrorKind::TimedOut,
+                                "TLS handshake timed out",
+                            )
+                        },
+                    )?
+                });
+                (fut, addr)
+            })
+        })
+    }
+}
+
+#[cfg(feature = "tls")]
+impl Debug for RustlsTcpListener {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("RustlsTcpListener")
+            .field("listener", &self.listener)
+            .field("handshake_timeout", &self.handshake_timeout)
+            .finish_non_exhaustive()
+    }
+}
+
+//============ Tests =========================================================
+
+#[cfg(test)]
+mod tests {
+    use core::future::poll_fn;
+
+    use std::net::SocketAddr;
+    #[cfg(unix)]
+    use std::path::Path;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use tokio::net::{TcpListener, TcpStream};
+
+    #[cfg(unix)]
+    use super::UnixDgramSock;
+    use super::{bind_reuse_port, AsyncAccept, ConfiguredTcpListener};
+
+    #[tokio::test]
+    async fn reuse_port_sockets_share_an_address() {
+        let addr: SocketAddr = "127.0.0.1:0".parse().unwrap();
+        let socks = bind_reuse_port(addr, 4).unwrap();
+        if cfg!(target_os = "linux") {
+            assert_eq!(socks.len(), 4);
+        }
+        let addr = socks[0].local_addr().unwrap();
+        assert_ne!(addr.port(), 0);
+        for sock in &socks {
+            assert_eq!(sock.local_addr().unwrap(), addr);
+        }
+
+        assert!(bind_reuse_port(addr, 0).unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn accepted_streams_are_configured_once_each() {
+        let num_configured = Arc::new(AtomicUsize::new(0));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let listener = ConfiguredTcpListener::new(listener, {
+            let num_configured = num_configured.clone();
+            move |stream: &TcpStream| {
+                num_configured.fetch_add(1, Ordering::SeqCst);
+                stream.set_nodelay(true)
+            }
+        });
+
+        for expected in 1..=2 {
+            let _client = TcpStream::connect(addr).await.unwrap();
+            let (fut, _peer) =
+                poll_fn(|cx| listener.poll_accept(cx)).await.unwrap();
+            let stream = fut.await.unwrap();
+            assert!(stream.nodelay().unwrap());
+            assert_eq!(num_configured.load(Ordering::SeqCst), expected);
+        }
+
+        // A failing function fails the accepted stream.
+        let listener = ConfiguredTcpListener::new(
+            TcpListener::bind("127.0.0.1:0").await.unwrap(),
+            |_: &TcpStream| Err(std::io::ErrorKind::Other.into()),
+        );
+        let addr = listener.listener().local_addr().unwrap();
+        let _client = TcpStream::connect(addr).await.unwrap();
+        let (fut, _peer) =
+            poll_fn(|cx| listener.poll_accept(cx)).await.unwrap();
+        assert!(fut.await.is_err());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn unix_peers_get_distinct_loopback_addrs() {
+        let path = std::env::temp_dir()
+            .join(format!("domain-{}-sock-test.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let sock = UnixDgramSock::bind(&path).unwrap();
+
+        let a = sock.client_addr(Path::new("/tmp/a"));
+        let b = sock.client_addr(Path::new("/tmp/b"));
+        assert!(a.ip().is_loopback());
+        assert!(b.ip().is_loopback());
+        assert_ne!(a.ip(), b.ip());
+        assert_eq!(sock.client_addr(Path::new("/tmp/a")), a);
+
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -15,7 +15,6 @@
 //! [stream]: https://en.wikipedia.org/wiki/Reliable_byte_streamuse
 use arc_swap::ArcSwap;
 use core::future::poll_fn;
-use core::ops::Deref;
 use core::sync::atomic::{AtomicUsize, Ordering};
 use core::time::Duration;
 use octseq::Octets;
@@ -25,7 +24,7 @@ use std::net::SocketAddr;
 use std::string::{String, ToString};
 use std::sync::{Arc, Mutex};
 use tokio::net::TcpListener;
-use tokio::sync::watch;
+use tokio::sync::broadcast;
 use tokio::time::{interval, timeout, MissedTickBehavior};
 use tracing::{error, trace, trace_span, warn};
 
@@ -37,8 +36,8 @@ use crate::net::server::sock::AsyncAccept;
 use crate::utils::config::DefMinMax;
 
 use super::buf::VecBufSource;
-use super::connection::{self, Connection};
-use super::ServerCommand;
+use super::connection::{self, CloseReason, Connection};
+use super::{CommandReceiver, CommandSender, ServerCommand};
 use crate::base::wire::Composer;
 use tokio::io::{AsyncRead, AsyncWrite};
 
@@ -122,6 +121,9 @@ impl Config {
     /// false, no new connections will be accepted unitl the number of
     /// concurrent connections falls below the limit.
     ///
+    /// Connections whose stream is still being set up by the listener, e.g.
+    /// while performing a TLS handshake, count towards the limit.
+    ///
     /// # Reconfigure
     ///
     /// On [`StreamServer::reconfigure`] if there are more connections
@@ -182,12 +184,6 @@ impl Clone for Config {
 /// A [`ServerCommand`] capable of propagating a StreamServer [`Config`] value.
 type ServerCommandType = ServerCommand<Config>;
 
-/// A thread safe sender of [`ServerCommand`]s.
-type CommandSender = Arc<Mutex<watch::Sender<ServerCommandType>>>;
-
-/// A thread safe receiver of [`ServerCommand`]s.
-type CommandReceiver = watch::Receiver<ServerCommandType>;
-
 /// A server for connecting clients via stream based network transport to a
 /// [`Service`].
 ///
@@ -245,7 +241,11 @@ type CommandReceiver = watch::Receiver<ServerCommandType>;
 ///     // Create a server that will accept those connections and pass
 ///     // received messages to your service and in turn pass generated
 ///     // responses back to the client.
-///     let srv = Arc::new(StreamServer::new(listener, VecBufSource, svc));
+///     let srv = Arc::new(StreamServer::new(
+///         listener,
+///         VecBufSource::default(),
+///         svc,
+///     ));
 ///
 ///     // Run the server.
 ///     let spawned_srv = srv.clone();
@@ -278,14 +278,18 @@ where
 
     /// A receiver for receiving [`ServerCommand`]s.
     ///
-    /// Used by both the server and spawned connections to react to sent
-    /// commands.
-    command_rx: CommandReceiver,
+    /// Created together with the command channel and taken by the first
+    /// invocation of [`run`] so that commands sent before the server was
+    /// started are not missed.
+    ///
+    /// [`run`]: Self::run()
+    command_rx: Mutex<Option<CommandReceiver<Config>>>,
 
     /// A sender for sending [`ServerCommand`]s.
     ///
-    /// Used to signal the server to stop, reconfigure, etc.
-    command_tx: CommandSender,
+    /// Used to signal the server to stop, reconfigure, etc. Spawned
+    /// connections subscribe to it to react to sent commands.
+    command_tx: CommandSender<Config>,
 
     /// A listener for listening for and accepting incoming stream
     /// connections.
@@ -300,10 +304,20 @@ where
     /// An optional pre-connect hook.
     pre_connect_hook: Option<fn(&mut Listener::StreamType)>,
 
+    /// An optional hook invoked when a connection has been accepted.
+    on_accept_hook: Option<fn(SocketAddr, &Listener::StreamType)>,
+
+    /// An optional hook invoked when a connection has been closed.
+    on_close_hook: Option<fn(SocketAddr, CloseReason)>,
+
     /// An ascending "ID" number assigned incrementally to newly accepted
     /// connections.
     connection_idx: AtomicUsize,
 
+    /// The number of accepted connections whose stream is still being set
+    /// up by the listener, e.g. by performing a TLS handshake.
+    num_pending_connections: Arc<AtomicUsize>,
+
     /// [`ServerMetrics`] describing the status of the server.
     metrics: Arc<ServerMetrics>,
 }
@@ -338,8 +352,8 @@ where
         service: Svc,
         config: Config,
     ) -> Self {
-        let (command_tx, command_rx) = watch::channel(ServerCommand::Init);
-        let command_tx = Arc::new(Mutex::new(command_tx));
+        let (command_tx, command_rx) = CommandSender::new();
+        let command_rx = Mutex::new(Some(command_rx));
         let listener = Arc::new(listener);
         let metrics = Arc::new(ServerMetrics::connection_oriented());
         let config = Arc::new(ArcSwap::from_pointee(config));
@@ -352,8 +366,11 @@ where
             buf,
             service,
             pre_connect_hook: None,
+            on_accept_hook: None,
+            on_close_hook: None,
             metrics,
             connection_idx: AtomicUsize::new(0),
+            num_pending_connections: Default::default(),
         }
     }
 
@@ -386,6 +403,46 @@ where
         self.pre_connect_hook = Some(pre_connect_hook);
         self
     }
+
+    /// Specify a hook to be invoked when a connection has been accepted.
+    ///
+    /// The hook is passed the address of the client and the accepted stream,
+    /// e.g. for connection level logging or metrics. It is invoked after any
+    /// [pre-connect hook] and before the first request is read from the
+    /// stream.
+    ///
+    /// The hook is invoked by the task handling the connection, not by the
+    /// task accepting connections, so a slow hook delays only its own
+    /// connection. It should nevertheless return quickly.
+    ///
+    /// [pre-connect hook]: Self::with_pre_connect_hook
+    #[must_use]
+    pub fn with_on_accept_hook(
+        mut self,
+        on_accept_hook: fn(SocketAddr, &Listener::StreamType),
+    ) -> Self {
+        self.on_accept_hook = Some(on_accept_hook);
+        self
+    }
+
+    /// Specify a hook to be invoked when a connection has been closed.
+    ///
+    /// The hook is passed the address of the client and the
+    /// [`CloseReason`] the connection ended. It is only invoked for
+    /// connections for which the [accept hook] would be invoked.
+    ///
+    /// Like the [accept hook] it is invoked by the task handling the
+    /// connection.
+    ///
+    /// [accept hook]: Self::with_on_accept_hook
+    #[must_use]
+    pub fn with_on_close_hook(
+        mut self,
+        on_close_hook: fn(SocketAddr, CloseReason),
+    ) -> Self {
+        self.on_close_hook = Some(on_close_hook);
+        self
+    }
 }
 
 /// # Access
@@ -450,11 +507,7 @@ where
     /// This command will be received both by the server and by any existing
     /// connections.
     pub fn reconfigure(&self, config: Config) -> Result<(), Error> {
-        self.command_tx
-            .lock()
-            .map_err(|_| Error::CommandCouldNotBeSent)?
-            .send(ServerCommand::Reconfigure(config))
-            .map_err(|_| Error::CommandCouldNotBeSent)
+        self.send_command(ServerCommand::Reconfigure(config))
     }
 
     /// Stop the server.
@@ -470,11 +523,7 @@ where
     ///
     /// [`Self::await_shutdown`] can be used to wait for shutdown to complete.
     pub fn shutdown(&self) -> Result<(), Error> {
-        self.command_tx
-            .lock()
-            .map_err(|_| Error::CommandCouldNotBeSent)?
-            .send(ServerCommand::Shutdown)
-            .map_err(|_| Error::CommandCouldNotBeSent)
+        self.send_command(ServerCommand::Shutdown)
     }
 
     /// Check if shutdown has completed.
@@ -529,7 +578,7 @@ where
         Svc::Stream: Send,
         Svc::Future: Send,
     {
-        let mut command_rx = self.command_rx.clone();
+        let mut command_rx = self.command_receiver();
 
         loop {
             tokio::select! {
@@ -538,8 +587,8 @@ where
 
                 // First, prefer obeying [`ServerCommands`] over everything
                 // else.
-                res = command_rx.changed() => {
-                    self.process_server_command(res, &mut command_rx)?;
+                res = command_rx.recv() => {
+                    self.process_server_command(res)?;
                 }
 
                 // Next, handle a connection that has been accepted, if any.
@@ -567,7 +616,8 @@ where
     /// See [`Config::max_concurrent_connections`].
     fn at_connection_limit(&self) -> bool {
         let config = ArcSwap::load(&self.config);
-        let num_conn = self.metrics.num_connections();
+        let num_conn = self.metrics.num_connections()
+            + self.num_pending_connections.load(Ordering::Relaxed);
         num_conn >= config.max_concurrent_connections()
     }
 
@@ -585,50 +635,64 @@ where
         }
     }
 
+    /// Send a [`ServerCommand`] to the server and its connections.
+    fn send_command(&self, command: ServerCommandType) -> Result<(), Error> {
+        // Sending only fails if there are no receivers, i.e. the server and
+        // all of its connections have already stopped running and there is
+        // nothing left to command.
+        let _ = self.command_tx.send(command);
+        Ok(())
+    }
+
+    /// Get a receiver for [`ServerCommand`]s.
+    ///
+    /// The first caller receives any commands sent since the server was
+    /// created, later callers only receive commands sent from now on.
+    fn command_receiver(&self) -> CommandReceiver<Config> {
+        self.command_rx
+            .lock()
+            .ok()
+            .and_then(|mut command_rx| command_rx.take())
+            .unwrap_or_else(|| self.command_tx.subscribe())
+    }
+
     /// Decide what to do with a received [`ServerCommand`].
     fn process_server_command(
         &self,
-        res: Result<(), watch::error::RecvError>,
-        command_rx: &mut watch::Receiver<ServerCommand<Config>>,
+        res: Result<ServerCommandType, broadcast::error::RecvError>,
     ) -> Result<(), String> {
-        // If the parent server no longer exists but was not cleanly shutdown
-        // then the command channel will be closed and attempting to check for
-        // a new command will fail. Advise the caller to break the connection
-        // and cleanup if such a problem occurs.
-        res.map_err(|err| format!("Error while receiving command: {err}"))?;
-
-        // Get the changed command.
-        let lock = command_rx.borrow_and_update();
-        let command = lock.deref();
+        let command = match res {
+            Ok(command) => command,
+
+            // If the parent server no longer exists but was not cleanly
+            // shutdown then the command channel will be closed and
+            // attempting to check for a new command will fail. Advise the
+            // caller to break the connection and cleanup if such a problem
+            // occurs.
+            Err(err) => {
+                return Err(format!("Error while receiving command: {err}"))
+            }
+        };
 
         // And process it.
         match command {
             ServerCommand::Reconfigure(new_config) => {
-                self.config.store(Arc::new(new_config.clone()));
+                self.config.store(Arc::new(new_config));
             }
 
-            ServerCommand::Shutdown => {
+            ServerCommand::Shutdown
+            | ServerCommand::Terminate
+            | ServerCommand::Drain(_) => {
                 // Stop accepting new connections, terminate the server. Child
                 // connections also receeive the command and handle it
                 // themselves.
                 return Err("Shutdown command received".to_string());
             }
 
-            ServerCommand::Init => {
-                // The initial "Init" value in the watch channel is never
-                // actually seen because changed() is required to return true
-                // before we call borrow_and_update() but the initial value in
-                // the channel, Init, is not considered a "change". So the
-                // only way to end up here would be if we somehow wrongly
-                // placed another ServerCommand::Init value into the watch
-                // channel after the initial one.
-                unreachable!()
-            }
-
             ServerCommand::CloseConnection => {
-                // Individual connections can be closed. The server itself
-                // should never receive a CloseConnection command.
-                unreachable!()
+                // Individual connections can be closed, this is handled by
+                // the connections themselves. There is nothing for the
+                // server itself to do.
             }
         }
 
@@ -656,13 +720,17 @@ where
         // connection handler that it actually needs.
         let config = ArcSwap::load(&self.config);
         let conn_config = config.connection_config;
-        let conn_command_rx = self.command_rx.clone();
+        let conn_command_rx = self.command_tx.subscribe();
         let conn_service = self.service.clone();
         let conn_buf = self.buf.clone();
         let conn_metrics = self.metrics.clone();
         let pre_connect_hook = self.pre_connect_hook;
+        let on_accept_hook = self.on_accept_hook;
+        let on_close_hook = self.on_close_hook;
         let new_connection_idx =
             self.connection_idx.fetch_add(1, Ordering::SeqCst);
+        let num_pending_connections = self.num_pending_connections.clone();
+        num_pending_connections.fetch_add(1, Ordering::Relaxed);
 
         trace!("Spawning new connection handler.");
         tokio::spawn(async move {
@@ -670,7 +738,10 @@ where
             let _guard = span.enter();
 
             trace!("Accepting connection.");
-            if let Ok(mut stream) = stream.await {
+            let stream = stream.await;
+            num_pending_connections.fetch_sub(1, Ordering::Relaxed);
+            if let Ok(mut stream) = stream {
+                let addr = Listener::client_addr(&stream, addr);
                 trace!("Connection accepted.");
                 // Let the caller inspect and/or modify the accepted stream
                 // before passing it to Connection.
@@ -679,6 +750,11 @@ where
                     hook(&mut stream);
                 }
 
+                if let Some(hook) = on_accept_hook {
+                    trace!("Running on-accept hook.");
+                    hook(addr, &stream);
+                }
+
                 let conn = Connection::with_config(
                     conn_service,
                     conn_buf,
@@ -689,8 +765,13 @@ where
                 );
 
                 trace!("Starting connection handler.");
-                conn.run(conn_command_rx).await;
-                trace!("Connection handler terminated.");
+                let reason = conn.run(conn_command_rx).await;
+                trace!("Connection handler terminated: {reason}");
+
+                if let Some(hook) = on_close_hook {
+                    trace!("Running on-close hook.");
+                    hook(addr, reason);
+                }
             }
         });
     }
@@ -245,7 +245,7 @@ where
     // Create a dgram server for handling UDP requests.
     let dgram_server = DgramServer::<_, _, Svc>::with_config(
         dgram_server_conn.clone(),
-        VecBufSource,
+        VecBufSource::default(),
         service.clone(),
         dgram_config,
     );
@@ -257,7 +257,7 @@ where
     // with "MATCH TCP".
     let stream_server = StreamServer::with_config(
         stream_server_conn.clone(),
-        VecBufSource,
+        VecBufSource::default(),
         service,
         stream_config,
     );
@@ -11,25 +11,33 @@ use std::net::SocketAddr;
 use std::sync::{Arc, Mutex};
 use std::vec::Vec;
 
-use tokio::io::{AsyncRead, AsyncWrite};
-use tokio::time::sleep;
+use futures_util::stream::Once;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
 use tokio::time::Instant;
+use tokio::time::{sleep, timeout};
 use tracing::trace;
 use tracing_subscriber::EnvFilter;
 
-use crate::base::MessageBuilder;
+use crate::base::iana::Rcode;
 use crate::base::Name;
 use crate::base::Rtype;
 use crate::base::StaticCompressor;
 use crate::base::StreamTarget;
-use crate::net::server::buf::BufSource;
-use crate::net::server::message::Request;
+use crate::base::{Message, MessageBuilder};
+use crate::net::server::buf::{BufSource, VecBufSource};
+use crate::net::server::message::{CancellationToken, Request};
 use crate::net::server::middleware::mandatory::MandatoryMiddlewareSvc;
 use crate::net::server::service::{
-    CallResult, Service, ServiceError, ServiceFeedback,
+    CallResult, DeferredResponse, Service, ServiceError, ServiceFeedback,
+    ServiceResult,
 };
 use crate::net::server::sock::AsyncAccept;
-use crate::net::server::stream::StreamServer;
+use crate::net::server::stream::{self, StreamServer};
+use crate::net::server::{
+    CloseReason, CommandSender, ConnectionConfig, ServerCommand,
+    COMMAND_CHANNEL_CAPACITY,
+};
 
 /// Mock I/O which supplies a sequence of mock messages to the server at a
 /// defined rate.
@@ -44,7 +52,7 @@ struct MockStream {
     /// The rate at which messages should be made available to the server.
     new_message_every: Duration,
 
-    /// The number of responses pending.
+    /// The number of requests read that have not been responded to yet.
     pending_responses: usize,
 
     /// Disconnect while one or more responses are pending?
@@ -57,12 +65,11 @@ impl MockStream {
         new_message_every: Duration,
         disconnect_with_pending_responses: bool,
     ) -> Self {
-        let pending_responses = messages_to_read.len();
         Self {
             last_ready: Mutex::new(Option::None),
             messages_to_read: Mutex::new(messages_to_read),
             new_message_every,
-            pending_responses,
+            pending_responses: 0,
             disconnect_with_pending_responses,
         }
     }
@@ -75,13 +82,14 @@ impl AsyncRead for MockStream {
         cx: &mut Context<'_>,
         buf: &mut tokio::io::ReadBuf<'_>,
     ) -> Poll<io::Result<()>> {
-        let mut last_ready = self.last_ready.lock().unwrap();
+        let this = self.get_mut();
+        let mut last_ready = this.last_ready.lock().unwrap();
 
         if last_ready
-            .map(|instant| instant.elapsed() > self.new_message_every)
+            .map(|instant| instant.elapsed() > this.new_message_every)
             .unwrap_or(true)
         {
-            let mut messages_to_read = self.messages_to_read.lock().unwrap();
+            let mut messages_to_read = this.messages_to_read.lock().unwrap();
             match buf.remaining() {
                 2 => {
                     // Initial read: return the number of bytes that will follow
@@ -93,12 +101,12 @@ impl AsyncRead for MockStream {
                         return Poll::Ready(Ok(()));
                     } else {
                         // Disconnect once we've sent all of the requests AND received all of the responses.
-                        if self.disconnect_with_pending_responses {
+                        if this.disconnect_with_pending_responses {
                             return Poll::Ready(Err(io::Error::new(
                                 io::ErrorKind::ConnectionAborted,
                                 "mock connection premature disconnect",
                             )));
-                        } else if self.pending_responses == 0 {
+                        } else if this.pending_responses == 0 {
                             return Poll::Ready(Err(io::Error::new(
                                 io::ErrorKind::ConnectionAborted,
                                 "mock connection normal disconnect",
@@ -110,6 +118,7 @@ impl AsyncRead for MockStream {
                     // subsequent read, return the message bytes
                     if let Some(msg) = messages_to_read.pop_front() {
                         buf.put_slice(&msg);
+                        this.pending_responses += 1;
                         return Poll::Ready(Ok(()));
                     }
                 }
@@ -132,7 +141,9 @@ impl AsyncWrite for MockStream {
         _cx: &mut Context<'_>,
         buf: &[u8],
     ) -> Poll<Result<usize, io::Error>> {
-        // Assume a single write is an entire response.
+        // Assume a single write is an entire response. A request can have
+        // more than one response, e.g. a zone transfer, so don't count
+        // below zero.
         if self.pending_responses > 0 {
             self.pending_responses -= 1;
         }
@@ -350,6 +361,11 @@ impl Service<Vec<u8>> for MyService {
 
 /// Create a mock DNS client request.
 fn mk_query() -> StreamTarget<Vec<u8>> {
+    mk_query_for(Rtype::A)
+}
+
+/// Create a mock DNS client request for the given record type.
+fn mk_query_for(qtype: Rtype) -> StreamTarget<Vec<u8>> {
     let mut msg = MessageBuilder::from_target(StaticCompressor::new(
         StreamTarget::new_vec(),
     ))
@@ -358,7 +374,7 @@ fn mk_query() -> StreamTarget<Vec<u8>> {
     msg.header_mut().set_random_id();
 
     let mut msg = msg.question();
-    msg.push((Name::<Vec<u8>>::from_str("example.com.").unwrap(), Rtype::A))
+    msg.push((Name::<Vec<u8>>::from_str("example.com.").unwrap(), qtype))
         .unwrap();
 
     let mut msg = msg.additional();
@@ -572,3 +588,358 @@ async fn tcp_client_disconnect_test() {
     // Terminate the task that periodically prints the server status
     server_status_printer_handle.abort();
 }
+
+#[tokio::test(flavor = "current_thread", start_paused = true)]
+async fn tcp_connection_hooks_test() {
+    static EVENTS: Mutex<Vec<(SocketAddr, Option<CloseReason>)>> =
+        Mutex::new(Vec::new());
+
+    fn on_accept(addr: SocketAddr, _stream: &MockStream) {
+        EVENTS.lock().unwrap().push((addr, None));
+    }
+
+    fn on_close(addr: SocketAddr, reason: CloseReason) {
+        EVENTS.lock().unwrap().push((addr, Some(reason)));
+    }
+
+    let client = MockClientConfig {
+        new_message_every: Duration::from_millis(100),
+        messages: VecDeque::from([
+            mk_query().as_dgram_slice().to_vec(),
+            mk_query().as_dgram_slice().to_vec(),
+        ]),
+        client_port: 1,
+        disconnect_with_pending_responses: false,
+    };
+    let listener =
+        MockListener::new(VecDeque::from([client]), Duration::ZERO);
+    let ready_flag = listener.get_ready_flag();
+
+    let srv = Arc::new(
+        StreamServer::new(
+            listener,
+            MockBufSource,
+            Arc::new(MyService::new()),
+        )
+        .with_on_accept_hook(on_accept)
+        .with_on_close_hook(on_close),
+    );
+
+    let spawned_srv = srv.clone();
+    let srv_handle = tokio::spawn(async move { spawned_srv.run().await });
+
+    ready_flag.store(true, Ordering::Relaxed);
+
+    // Give the client time to connect, communicate and disconnect.
+    sleep(Duration::from_secs(5)).await;
+
+    let addr: SocketAddr = "192.168.0.1:1".parse().unwrap();
+    assert_eq!(
+        *EVENTS.lock().unwrap(),
+        [(addr, None), (addr, Some(CloseReason::PeerClosed))]
+    );
+
+    srv.shutdown().unwrap();
+    let _ = srv_handle.await;
+}
+
+#[tokio::test(flavor = "current_thread", start_paused = true)]
+async fn tcp_max_request_size_test() {
+    static CLOSE_REASONS: Mutex<Vec<CloseReason>> = Mutex::new(Vec::new());
+
+    fn on_close(_addr: SocketAddr, reason: CloseReason) {
+        CLOSE_REASONS.lock().unwrap().push(reason);
+    }
+
+    let query = mk_query().as_dgram_slice().to_vec();
+    let client = MockClientConfig {
+        new_message_every: Duration::from_millis(100),
+        messages: VecDeque::from([query.clone()]),
+        client_port: 1,
+        disconnect_with_pending_responses: false,
+    };
+    let listener =
+        MockListener::new(VecDeque::from([client]), Duration::ZERO);
+    let ready_flag = listener.get_ready_flag();
+
+    let mut conn_config = ConnectionConfig::new();
+    conn_config.set_max_request_size(u16::try_from(query.len() - 1).unwrap());
+    let mut config = stream::Config::new();
+    config.set_connection_config(conn_config);
+
+    let srv = Arc::new(
+        StreamServer::with_config(
+            listener,
+            MockBufSource,
+            Arc::new(MyService::new()),
+            config,
+        )
+        .with_on_close_hook(on_close),
+    );
+
+    let spawned_srv = srv.clone();
+    let srv_handle = tokio::spawn(async move { spawned_srv.run().await });
+
+    ready_flag.store(true, Ordering::Relaxed);
+
+    // Give the client time to connect and send its oversized request.
+    sleep(Duration::from_secs(5)).await;
+
+    assert_eq!(
+        *CLOSE_REASONS.lock().unwrap(),
+        [CloseReason::MessageTooLarge]
+    );
+    assert_eq!(srv.metrics().num_received_requests(), 0);
+
+    srv.shutdown().unwrap();
+    let _ = srv_handle.await;
+}
+
+#[tokio::test(flavor = "current_thread", start_paused = true)]
+async fn tcp_cancel_on_disconnect_test() {
+    static TOKENS: Mutex<Vec<CancellationToken>> = Mutex::new(Vec::new());
+    static STREAM_DROPPED: AtomicBool = AtomicBool::new(false);
+
+    /// A response stream that never produces a response.
+    struct NeverStream;
+
+    impl futures_util::stream::Stream for NeverStream {
+        type Item = Result<CallResult<Vec<u8>>, ServiceError>;
+
+        fn poll_next(
+            self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+        ) -> Poll<Option<Self::Item>> {
+            Poll::Pending
+        }
+    }
+
+    impl Drop for NeverStream {
+        fn drop(&mut self) {
+            STREAM_DROPPED.store(true, Ordering::SeqCst);
+        }
+    }
+
+    /// A service that never finishes processing a request.
+    struct SlowService;
+
+    impl Service<Vec<u8>> for SlowService {
+        type Target = Vec<u8>;
+        type Stream = NeverStream;
+        type Future = Ready<Self::Stream>;
+
+        fn call(&self, request: Request<Vec<u8>>) -> Self::Future {
+            TOKENS
+                .lock()
+                .unwrap()
+                .push(request.cancellation_token().clone());
+            ready(NeverStream)
+        }
+    }
+
+    // Disconnect a while after the first request so that it is being
+    // processed when the connection is closed.
+    let client = MockClientConfig {
+        new_message_every: Duration::from_millis(1000),
+        messages: VecDeque::from([
+            mk_query().as_dgram_slice().to_vec(),
+            mk_query().as_dgram_slice().to_vec(),
+        ]),
+        client_port: 1,
+        disconnect_with_pending_responses: true,
+    };
+    let listener =
+        MockListener::new(VecDeque::from([client]), Duration::ZERO);
+    let ready_flag = listener.get_ready_flag();
+
+    let srv = Arc::new(StreamServer::new(
+        listener,
+        MockBufSource,
+        Arc::new(SlowService),
+    ));
+
+    let spawned_srv = srv.clone();
+    let srv_handle = tokio::spawn(async move { spawned_srv.run().await });
+
+    ready_flag.store(true, Ordering::Relaxed);
+
+    // Give the client time to send its request and disconnect.
+    sleep(Duration::from_secs(5)).await;
+
+    let tokens = TOKENS.lock().unwrap().clone();
+    assert!(!tokens.is_empty());
+    assert!(tokens.iter().all(CancellationToken::is_cancelled));
+    tokens[0].cancelled().await;
+    assert!(STREAM_DROPPED.load(Ordering::SeqCst));
+
+    srv.shutdown().unwrap();
+    let _ = srv_handle.await;
+}
+
+#[tokio::test(flavor = "current_thread", start_paused = true)]
+async fn tcp_reuse_after_axfr_test() {
+    static SERVED: Mutex<Vec<Rtype>> = Mutex::new(Vec::new());
+    static EVENTS: Mutex<Vec<(SocketAddr, Option<CloseReason>)>> =
+        Mutex::new(Vec::new());
+
+    fn on_accept(addr: SocketAddr, _stream: &MockStream) {
+        EVENTS.lock().unwrap().push((addr, None));
+    }
+
+    fn on_close(addr: SocketAddr, reason: CloseReason) {
+        EVENTS.lock().unwrap().push((addr, Some(reason)));
+    }
+
+    type ResponseStream = futures_util::stream::Iter<
+        std::vec::IntoIter<Result<CallResult<Vec<u8>>, ServiceError>>,
+    >;
+
+    /// A service that answers AXFR queries with a transaction of three
+    /// responses and all other queries with a single response.
+    struct XfrService;
+
+    impl Service<Vec<u8>> for XfrService {
+        type Target = Vec<u8>;
+        type Stream = ResponseStream;
+        type Future = Ready<Self::Stream>;
+
+        fn call(&self, request: Request<Vec<u8>>) -> Self::Future {
+            let qtype = request.message().sole_question().unwrap().qtype();
+            SERVED.lock().unwrap().push(qtype);
+
+            let num_responses = if qtype == Rtype::AXFR { 3 } else { 1 };
+            let results = (0..num_responses)
+                .map(|i| {
+                    let response = MessageBuilder::new_stream_vec()
+                        .start_answer(request.message(), Rcode::NOERROR)
+                        .unwrap()
+                        .additional();
+                    let mut call_result = CallResult::new(response);
+                    if qtype == Rtype::AXFR && i == 0 {
+                        call_result = call_result
+                            .with_feedback(ServiceFeedback::BeginTransaction);
+                    } else if qtype == Rtype::AXFR && i == num_responses - 1 {
+                        call_result = call_result
+                            .with_feedback(ServiceFeedback::EndTransaction);
+                    }
+                    Ok(call_result)
+                })
+                .collect::<Vec<_>>();
+            ready(futures_util::stream::iter(results))
+        }
+    }
+
+    // Send the A query once the AXFR has completed.
+    let client = MockClientConfig {
+        new_message_every: Duration::from_millis(1000),
+        messages: VecDeque::from([
+            mk_query_for(Rtype::AXFR).as_dgram_slice().to_vec(),
+            mk_query_for(Rtype::A).as_dgram_slice().to_vec(),
+        ]),
+        client_port: 1,
+        disconnect_with_pending_responses: false,
+    };
+    let listener =
+        MockListener::new(VecDeque::from([client]), Duration::ZERO);
+    let ready_flag = listener.get_ready_flag();
+
+    let srv = Arc::new(
+        StreamServer::new(listener, MockBufSource, Arc::new(XfrService))
+            .with_on_accept_hook(on_accept)
+            .with_on_close_hook(on_close),
+    );
+
+    let spawned_srv = srv.clone();
+    let srv_handle = tokio::spawn(async move { spawned_srv.run().await });
+
+    ready_flag.store(true, Ordering::Relaxed);
+
+    // Give the client time to connect, communicate and disconnect.
+    sleep(Duration::from_secs(5)).await;
+
+    // Both queries were answered on the one connection which was only
+    // closed when the client went away.
+    assert_eq!(*SERVED.lock().unwrap(), [Rtype::AXFR, Rtype::A]);
+    assert_eq!(srv.metrics().num_received_requests(), 2);
+    assert_eq!(srv.metrics().num_sent_responses(), 4);
+    assert_eq!(srv.metrics().num_pending_writes(), 0);
+    let addr: SocketAddr = "192.168.0.1:1".parse().unwrap();
+    assert_eq!(
+        *EVENTS.lock().unwrap(),
+        [(addr, None), (addr, Some(CloseReason::PeerClosed))]
+    );
+
+    srv.shutdown().unwrap();
+    let _ = srv_handle.await;
+}
+
+#[tokio::test]
+async fn tcp_abandoned_deferred_response_test() {
+    /// Defers every response, dropping the senders right away.
+    #[derive(Clone)]
+    struct AbandoningService;
+
+    impl Service<Vec<u8>> for AbandoningService {
+        type Target = Vec<u8>;
+        type Stream = Once<Ready<ServiceResult<Vec<u8>>>>;
+        type Future = DeferredResponse<Vec<u8>>;
+
+        fn call(&self, _request: Request<Vec<u8>>) -> Self::Future {
+            let (_, response) = DeferredResponse::channel();
+            response
+        }
+    }
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let srv_addr = listener.local_addr().unwrap();
+    let srv = Arc::new(StreamServer::new(
+        listener,
+        VecBufSource::default(),
+        AbandoningService,
+    ));
+    let spawned_srv = srv.clone();
+    let srv_handle = tokio::spawn(async move { spawned_srv.run().await });
+
+    let mut stream = TcpStream::connect(srv_addr).await.unwrap();
+    stream
+        .write_all(mk_query().as_stream_slice())
+        .await
+        .unwrap();
+
+    // The request is answered with SERVFAIL rather than not at all.
+    let len = timeout(Duration::from_secs(5), stream.read_u16())
+        .await
+        .unwrap()
+        .unwrap();
+    let mut buf = vec![0; len.into()];
+    stream.read_exact(&mut buf).await.unwrap();
+    let response = Message::from_octets(buf).unwrap();
+    assert_eq!(response.header().rcode(), Rcode::SERVFAIL);
+
+    srv.shutdown().unwrap();
+    let _ = srv_handle.await;
+}
+
+#[tokio::test]
+async fn lagging_command_receiver_gets_latest_commands() {
+    let (tx, mut rx) = CommandSender::<u32>::new();
+
+    // Overflow the channel with reconfigures only. The latest
+    // configuration is delivered in place of those that were missed.
+    for config in 0..COMMAND_CHANNEL_CAPACITY as u32 * 2 {
+        tx.send(ServerCommand::Reconfigure(config)).unwrap();
+    }
+    let last = COMMAND_CHANNEL_CAPACITY as u32 * 2 - 1;
+    assert!(matches!(
+        rx.recv().await,
+        Ok(ServerCommand::Reconfigure(config)) if config == last
+    ));
+
+    // A shutdown that was pushed out of the channel is not lost.
+    let mut rx = tx.subscribe();
+    tx.send(ServerCommand::Shutdown).unwrap();
+    for config in 0..COMMAND_CHANNEL_CAPACITY as u32 {
+        tx.send(ServerCommand::Reconfigure(config)).unwrap();
+    }
+    assert!(matches!(rx.recv().await, Ok(ServerCommand::Shutdown)));
+}
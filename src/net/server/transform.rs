@@ -0,0 +1,330 @@
+//! A service that transforms requests and responses with closures.
+//!
+//! The [`TransformService`] passes each request through a closure before
+//! handing it to an inner service and passes each response produced by the
+//! inner service through a second closure. This allows one-off tweaks to the
+//! requests and responses of a particular service without writing a
+//! middleware service.
+//!
+//! # When to prefer middleware
+//!
+//! A [`TransformService`] is meant for small transformations that are local
+//! to a single service. Write a middleware service instead if the
+//! transformation:
+//!
+//! - should be reusable and configurable across services,
+//! - needs to change the type of the request metadata,
+//! - needs state that is shared between requests beyond what the closures
+//!   capture, or
+//! - needs to know how the response stream ends, e.g. to act on the last
+//!   response of a zone transfer.
+
+#![warn(missing_docs)]
+#![warn(clippy::missing_docs_in_private_items)]
+
+use core::future::{ready, Ready};
+use core::ops::ControlFlow;
+
+use futures_util::stream::{once, Once};
+use octseq::Octets;
+
+use crate::base::message_builder::AdditionalBuilder;
+use crate::base::wire::Composer;
+use crate::base::StreamTarget;
+
+use super::message::Request;
+use super::middleware::stream::{MiddlewareStream, PostprocessingStream};
+use super::service::{CallResult, Service, ServiceResult};
+
+//------------ TransformService ----------------------------------------------
+
+/// A [`Service`] that transforms requests and responses with closures.
+///
+/// The `pre_fn` closure is called with each request before it is passed to
+/// the inner service. It returns either [`ControlFlow::Continue`] with the,
+/// possibly modified, request to pass on, or [`ControlFlow::Break`] with a
+/// response to answer the request with directly without calling the inner
+/// service.
+///
+/// The `post_fn` closure is called with the original request and each
+/// response produced by the inner service and may modify the response in
+/// place. For services that produce a stream of responses, such as zone
+/// transfers, it is called for each response in the stream. It is not
+/// called for responses produced by `pre_fn` nor for errors.
+///
+/// See the [module documentation] for when to prefer writing a middleware
+/// service instead.
+///
+/// [module documentation]: self
+#[derive(Clone, Debug)]
+pub struct TransformService<Svc, PreFn, PostFn> {
+    /// The service to pass requests to.
+    inner: Svc,
+
+    /// The closure to call with each request.
+    pre_fn: PreFn,
+
+    /// The closure to call with each response.
+    post_fn: PostFn,
+}
+
+impl<Svc, PreFn, PostFn> TransformService<Svc, PreFn, PostFn> {
+    /// Creates a new transforming service for the given inner service.
+    #[must_use]
+    pub fn new(inner: Svc, pre_fn: PreFn, post_fn: PostFn) -> Self {
+        Self {
+            inner,
+            pre_fn,
+            post_fn,
+        }
+    }
+}
+
+impl<Svc, PreFn, PostFn> TransformService<Svc, PreFn, PostFn> {
+    /// Apply the post transformation to a response stream item.
+    fn map_stream_item<RequestOctets, RequestMeta, Target>(
+        request: Request<RequestOctets, RequestMeta>,
+        mut stream_item: ServiceResult<Target>,
+        post_fn: &mut PostFn,
+    ) -> ServiceResult<Target>
+    where
+        RequestOctets: Octets + Send + Sync,
+        Target: Composer,
+        PostFn: Fn(
+            &Request<RequestOctets, RequestMeta>,
+            &mut AdditionalBuilder<StreamTarget<Target>>,
+        ),
+    {
+        if let Ok(cr) = &mut stream_item {
+            if let Some(response) = cr.response_mut() {
+                post_fn(&request, response);
+            }
+        }
+        stream_item
+    }
+}
+
+//--- Service
+
+impl<RequestOctets, RequestMeta, Svc, PreFn, PostFn>
+    Service<RequestOctets, RequestMeta>
+    for TransformService<Svc, PreFn, PostFn>
+where
+    RequestOctets: Octets + Send + Sync + 'static + Unpin,
+    RequestMeta: Clone + Default + Unpin,
+    Svc: Service<RequestOctets, RequestMeta>,
+    Svc::Future: Unpin,
+    Svc::Target: Composer + Default,
+    PreFn: Fn(
+        Request<RequestOctets, RequestMeta>,
+    ) -> ControlFlow<
+        AdditionalBuilder<StreamTarget<Svc::Target>>,
+        Request<RequestOctets, RequestMeta>,
+    >,
+    PostFn: Fn(
+            &Request<RequestOctets, RequestMeta>,
+            &mut AdditionalBuilder<StreamTarget<Svc::Target>>,
+        ) + Clone
+        + Unpin,
+{
+    type Target = Svc::Target;
+    type Stream = MiddlewareStream<
+        Svc::Future,
+        Svc::Stream,
+        PostprocessingStream<
+            RequestOctets,
+            Svc::Future,
+            Svc::Stream,
+            RequestMeta,
+            PostFn,
+        >,
+        Once<Ready<ServiceResult<Self::Target>>>,
+        ServiceResult<Self::Target>,
+    >;
+    type Future = Ready<Self::Stream>;
+
+    fn call(
+        &self,
+        request: Request<RequestOctets, RequestMeta>,
+    ) -> Self::Future {
+        match (self.pre_fn)(request) {
+            ControlFlow::Continue(request) => {
+                let svc_call_fut = self.inner.call(request.clone());
+                ready(MiddlewareStream::Map(PostprocessingStream::new(
+                    svc_call_fut,
+                    request,
+                    self.post_fn.clone(),
+                    Self::map_stream_item,
+                )))
+            }
+            ControlFlow::Break(response) => ready(MiddlewareStream::Result(
+                once(ready(Ok(CallResult::new(response)))),
+            )),
+        }
+    }
+}
+
+//============ Tests =========================================================
+
+#[cfg(test)]
+mod tests {
+    use core::future::{ready, Ready};
+    use core::ops::ControlFlow;
+    use core::str::FromStr;
+
+    use std::vec::Vec;
+
+    use bytes::Bytes;
+    use futures_util::stream::{self, StreamExt};
+
+    use crate::base::iana::{Class, Rcode};
+    use crate::base::message_builder::AdditionalBuilder;
+    use crate::base::{Message, MessageBuilder, Name, Rtype, StreamTarget};
+    use crate::net::server::message::{Request, UdpTransportContext};
+    use crate::net::server::service::{CallResult, Service, ServiceResult};
+    use crate::net::server::util::{mk_builder_for_target, service_fn};
+    use crate::rdata::A;
+
+    use super::TransformService;
+
+    #[tokio::test]
+    async fn request_and_response_are_transformed() {
+        // A service that answers with the RD flag of the request as AA.
+        fn my_service(
+            req: Request<Vec<u8>>,
+            _meta: (),
+        ) -> ServiceResult<Vec<u8>> {
+            let builder = mk_builder_for_target();
+            let mut answer =
+                builder.start_answer(req.message(), Rcode::NOERROR).unwrap();
+            answer.header_mut().set_aa(req.message().header().rd());
+            Ok(CallResult::new(answer.additional()))
+        }
+
+        let svc = TransformService::new(
+            service_fn(my_service, ()),
+            |req: Request<Vec<u8>>| {
+                // Refuse queries for anything but A, clear RD on others.
+                let qtype = req.message().sole_question().unwrap().qtype();
+                if qtype != Rtype::A {
+                    let builder = mk_builder_for_target();
+                    let answer = builder
+                        .start_answer(req.message(), Rcode::REFUSED)
+                        .unwrap();
+                    return ControlFlow::Break(answer.additional());
+                }
+                let mut msg =
+                    Message::from_octets(req.message().as_slice().to_vec())
+                        .unwrap();
+                msg.header_mut().set_rd(false);
+                ControlFlow::Continue(Request::new(
+                    req.client_addr(),
+                    req.received_at(),
+                    msg,
+                    req.transport_ctx().clone(),
+                    (),
+                ))
+            },
+            |_req: &Request<Vec<u8>>,
+             response: &mut AdditionalBuilder<StreamTarget<Vec<u8>>>| {
+                response.header_mut().set_ra(true);
+            },
+        );
+
+        let response = call(&svc, Rtype::A).await;
+        assert_eq!(response.len(), 1);
+        let header = response[0].header();
+        assert_eq!(header.rcode(), Rcode::NOERROR);
+        assert!(!header.aa());
+        assert!(header.ra());
+
+        // The short-circuited response is not post-processed.
+        let response = call(&svc, Rtype::AAAA).await;
+        assert_eq!(response.len(), 1);
+        let header = response[0].header();
+        assert_eq!(header.rcode(), Rcode::REFUSED);
+        assert!(!header.ra());
+    }
+
+    #[tokio::test]
+    async fn each_stream_item_is_transformed() {
+        let svc = TransformService::new(
+            StreamService,
+            ControlFlow::Continue,
+            |_req: &Request<Vec<u8>>,
+             response: &mut AdditionalBuilder<StreamTarget<Vec<u8>>>| {
+                response
+                    .push((
+                        Name::root_ref(),
+                        Class::IN,
+                        0,
+                        A::from_octets(192, 0, 2, 1),
+                    ))
+                    .unwrap();
+            },
+        );
+
+        let responses = call(&svc, Rtype::AXFR).await;
+        assert_eq!(responses.len(), 2);
+        for response in responses {
+            assert_eq!(response.header_counts().arcount(), 1);
+        }
+    }
+
+    //------------ StreamService ---------------------------------------------
+
+    /// A service that answers every request with two responses.
+    #[derive(Clone)]
+    struct StreamService;
+
+    impl Service<Vec<u8>> for StreamService {
+        type Target = Vec<u8>;
+        type Stream =
+            stream::Iter<std::vec::IntoIter<ServiceResult<Vec<u8>>>>;
+        type Future = Ready<Self::Stream>;
+
+        fn call(&self, request: Request<Vec<u8>>) -> Self::Future {
+            let items = (0..2)
+                .map(|_| {
+                    let builder = mk_builder_for_target();
+                    let answer = builder
+                        .start_answer(request.message(), Rcode::NOERROR)
+                        .unwrap();
+                    Ok(CallResult::new(answer.additional()))
+                })
+                .collect::<Vec<_>>();
+            ready(stream::iter(items))
+        }
+    }
+
+    //------------ Helper functions ------------------------------------------
+
+    async fn call<Svc>(svc: &Svc, qtype: Rtype) -> Vec<Message<Vec<u8>>>
+    where
+        Svc: Service<Vec<u8>, Target = Vec<u8>>,
+    {
+        let mut query = MessageBuilder::new_vec();
+        query.header_mut().set_rd(true);
+        let mut query = query.question();
+        query
+            .push((Name::<Bytes>::from_str("example.com").unwrap(), qtype))
+            .unwrap();
+
+        let request = Request::for_test(
+            query.into_message(),
+            UdpTransportContext::default(),
+            "127.0.0.1:12345".parse().unwrap(),
+        );
+
+        let stream = svc.call(request).await;
+        stream
+            .map(|item| {
+                let (response, _feedback) = item.unwrap().into_inner();
+                let response = response.unwrap().finish();
+                Message::from_octets(response.as_dgram_slice().to_vec())
+                    .unwrap()
+            })
+            .collect()
+            .await
+    }
+}
@@ -1,18 +1,25 @@
 //! Small utilities for building and working with servers.
+use core::fmt::Display;
 use core::future::{ready, Ready};
 
 use core::marker::PhantomData;
+use core::option;
+use std::net::SocketAddr;
 use std::string::{String, ToString};
+use std::vec::Vec;
 
-use futures_util::stream::Once;
+use futures_util::stream::{iter, Chain, Iter, Once, StreamExt};
 use octseq::{Octets, OctetsBuilder};
 use tracing::warn;
 
-use crate::base::iana::OptRcode;
+use crate::base::iana::{ExtendedErrorCode, OptRcode};
 use crate::base::message_builder::{
-    AdditionalBuilder, OptBuilder, PushError,
+    rebuild_opt, AdditionalBuilder, OptBuilder, OptOverrides, PushError,
+    TreeCompressor,
 };
-use crate::base::wire::Composer;
+use crate::base::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use crate::base::opt::{ExtendedError, Padding};
+use crate::base::wire::{Composer, ParseError};
 use crate::base::Message;
 use crate::base::{MessageBuilder, ParsedName, Rtype, StreamTarget};
 use crate::rdata::AllRecordData;
@@ -213,6 +220,76 @@ where
     additional
 }
 
+//------------ mk_timeout_response -------------------------------------------
+
+/// Creates a SERVFAIL response to a request whose deadline has passed.
+///
+/// If the request has an OPT record, the response includes an Extended DNS
+/// Error with info-code 0 (Other Error) and extra text saying that processing
+/// the request timed out, so that clients can tell it apart from other
+/// failures.
+pub(crate) fn mk_timeout_response<RequestOctets, Target>(
+    msg: &Message<RequestOctets>,
+) -> AdditionalBuilder<StreamTarget<Target>>
+where
+    RequestOctets: Octets,
+    Target: Composer + Default,
+{
+    let mut response = mk_error_response(msg, OptRcode::SERVFAIL);
+    if msg.opt().is_some() {
+        if let Ok(ede) = ExtendedError::<Vec<u8>>::new_with_str(
+            ExtendedErrorCode::OTHER,
+            "request processing timed out",
+        ) {
+            if let Err(err) =
+                add_edns_options(&mut response, |builder| builder.push(&ede))
+            {
+                warn!("Failed to add EDE to timeout response: {err}");
+            }
+        }
+    }
+    response
+}
+
+//------------ call_with_deadline() ------------------------------------------
+
+/// The responses of a service call that met the deadline of its request.
+pub(crate) type DeadlineStream<Stream, Target> =
+    Chain<Iter<option::IntoIter<ServiceResult<Target>>>, Stream>;
+
+/// Calls a service, giving up if the deadline of the request passes first.
+///
+/// Waits for the service to produce its first response or for the
+/// [deadline] of the request to pass, whichever comes first. If the deadline
+/// passes first, the service future or response stream is dropped, aborting
+/// any processing awaiting at the time, and `None` is returned. Otherwise
+/// the complete response stream is returned.
+///
+/// [deadline]: super::message::RequestDeadline
+pub(crate) async fn call_with_deadline<Svc, RequestOctets, RequestMeta>(
+    svc: &Svc,
+    request: Request<RequestOctets, RequestMeta>,
+) -> Option<DeadlineStream<Svc::Stream, Svc::Target>>
+where
+    RequestOctets: AsRef<[u8]> + Send + Sync,
+    RequestMeta: Clone + Default,
+    Svc: Service<RequestOctets, RequestMeta>,
+{
+    let deadline = request.deadline().clone();
+    let call = async {
+        let mut stream = svc.call(request).await;
+        let first = stream.next().await;
+        iter(first).chain(stream)
+    };
+
+    tokio::select! {
+        biased;
+
+        stream = call => Some(stream),
+        _ = deadline.expired() => None,
+    }
+}
+
 //----------- add_edns_option ------------------------------------------------
 
 /// Adds one or more EDNS OPT options to a response.
@@ -282,7 +359,7 @@ where
             // the options within the existing OPT record plus the new options
             // that we want to add.
             let res = response.opt(|builder| {
-                builder.clone_from(&current_opt)?;
+                rebuild_opt(&current_opt, builder, &OptOverrides::new())?;
                 op(builder)
             });
 
@@ -339,8 +416,298 @@ where
     Ok(())
 }
 
+//------------ client_prefix() -----------------------------------------------
+
+/// Returns the network of a client.
+///
+/// The IP address of the client is truncated to `v4_bits` for IPv4 and to
+/// `v6_bits` for IPv6 clients, e.g. to 24 and 56 bits respectively to treat
+/// all clients of a typical customer network alike. Prefix lengths longer
+/// than the address are capped.
+///
+/// IPv4 clients connecting to a dual-stack socket, i.e. with an IPv4-mapped
+/// IPv6 address, are treated as the IPv4 clients they are.
+///
+/// This is intended as the common key for processors that limit or filter
+/// clients by network rather than by individual address.
+pub fn client_prefix(
+    addr: &SocketAddr,
+    v4_bits: u8,
+    v6_bits: u8,
+) -> IpPrefix {
+    match canonical_addr(addr.ip()) {
+        IpAddr::V4(addr) => IpPrefix::new(addr.into(), v4_bits),
+        IpAddr::V6(addr) => IpPrefix::new(addr.into(), v6_bits),
+    }
+}
+
+//------------ IpPrefix ------------------------------------------------------
+
+/// An IP address prefix, i.e. a network.
+///
+/// The address bits beyond the prefix length are always zero, so two
+/// prefixes of addresses in the same network compare and hash equal.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct IpPrefix {
+    /// The address with all bits beyond the prefix length cleared.
+    addr: IpAddr,
+
+    /// The prefix length in bits.
+    len: u8,
+}
+
+impl IpPrefix {
+    /// Creates the prefix of the given length containing the address.
+    ///
+    /// Prefix lengths longer than the address are capped.
+    pub fn new(addr: IpAddr, len: u8) -> Self {
+        match addr {
+            IpAddr::V4(addr) => {
+                let len = len.min(32);
+                let mask =
+                    u32::MAX.checked_shl(32 - u32::from(len)).unwrap_or(0);
+                Self {
+                    addr: Ipv4Addr::from(u32::from(addr) & mask).into(),
+                    len,
+                }
+            }
+            IpAddr::V6(addr) => {
+                let len = len.min(128);
+                let mask =
+                    u128::MAX.checked_shl(128 - u32::from(len)).unwrap_or(0);
+                Self {
+                    addr: Ipv6Addr::from(u128::from(addr) & mask).into(),
+                    len,
+                }
+            }
+        }
+    }
+
+    /// Returns the first address of the prefix.
+    pub fn addr(&self) -> IpAddr {
+        self.addr
+    }
+
+    /// Returns the prefix length in bits.
+    pub fn prefix_len(&self) -> u8 {
+        self.len
+    }
+
+    /// Returns whether the prefix contains the given address.
+    ///
+    /// IPv4-mapped IPv6 addresses are contained in the prefixes of the IPv4
+    /// addresses they map.
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        Self::new(canonical_addr(addr), self.len) == *self
+    }
+}
+
+/// Converts an IPv4-mapped IPv6 address into the IPv4 address it maps.
+///
+/// All other addresses are returned unchanged.
+fn canonical_addr(addr: IpAddr) -> IpAddr {
+    match addr {
+        IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+            Some(v4) => v4.into(),
+            None => addr,
+        },
+        IpAddr::V4(_) => addr,
+    }
+}
+
+//--- Display
+
+impl Display for IpPrefix {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{}/{}", self.addr, self.len)
+    }
+}
+
+//------------ CompressionMode -----------------------------------------------
+
+/// When to apply domain name compression to responses.
+///
+/// Name compression reduces the size of a response but costs CPU time to
+/// perform. Servers compress responses just before sending them, after the
+/// [`Service`] and any middleware have finished with them, according to the
+/// configured mode.
+///
+/// Responses that are TSIG signed or carry an EDNS(0) Padding option are
+/// never compressed, as changing their length would invalidate the
+/// signature or defeat the padding.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CompressionMode {
+    /// Compress every response.
+    Always,
+
+    /// Never compress responses.
+    ///
+    /// Responses are sent exactly as produced by the [`Service`].
+    #[default]
+    Never,
+
+    /// Compress only responses that are larger than the given number of
+    /// bytes.
+    WhenBeneficial(u16),
+}
+
+/// Compresses a response according to the given [`CompressionMode`].
+///
+/// Returns `None` if the mode says the response should not be compressed,
+/// or if the response could not be compressed, in which case the response
+/// should be sent as is.
+pub(super) fn compress_response(
+    response: &[u8],
+    mode: CompressionMode,
+) -> Option<StreamTarget<Vec<u8>>> {
+    match mode {
+        CompressionMode::Never => return None,
+        CompressionMode::WhenBeneficial(threshold)
+            if response.len() <= usize::from(threshold) =>
+        {
+            return None
+        }
+        _ => {}
+    }
+
+    let source = match Message::from_slice(response) {
+        Ok(source) => source,
+        Err(err) => {
+            warn!("Unable to compress response: {err}");
+            return None;
+        }
+    };
+
+    // Compressing changes the length of the message, which would invalidate
+    // a TSIG signature and defeat any padding that hides the length.
+    if is_signed_or_padded(source) {
+        return None;
+    }
+
+    match compress(source) {
+        Ok(compressed)
+            if compressed.as_dgram_slice().len() < response.len() =>
+        {
+            Some(compressed)
+        }
+        Ok(_) => None,
+        Err(err) => {
+            warn!("Unable to compress response: {err}");
+            None
+        }
+    }
+}
+
+/// Compresses a response like the servers do.
+///
+/// This is [`compress_response`] made available to the benchmarks. It is
+/// not part of the public API.
+#[doc(hidden)]
+pub fn bench_compress_response(
+    response: &[u8],
+    mode: CompressionMode,
+) -> Option<StreamTarget<Vec<u8>>> {
+    compress_response(response, mode)
+}
+
+/// Returns whether the given message is TSIG signed or EDNS(0) padded.
+fn is_signed_or_padded(msg: &Message<[u8]>) -> bool {
+    if let Some(opt) = msg.opt() {
+        if opt.opt().first::<Padding<_>>().is_some() {
+            return true;
+        }
+    }
+    msg.additional().map_or(false, |mut section| {
+        section.any(|rr| rr.map_or(false, |rr| rr.rtype() == Rtype::TSIG))
+    })
+}
+
+/// Rebuilds the given message using name compression.
+fn compress(
+    source: &Message<[u8]>,
+) -> Result<StreamTarget<Vec<u8>>, CompressError> {
+    let target = TreeCompressor::new(StreamTarget::new_vec());
+    let mut target =
+        MessageBuilder::from_target(target).map_err(PushError::from)?;
+
+    *target.header_mut() = source.header();
+
+    let mut target = target.question();
+    for q in source.question() {
+        target.push(q?)?;
+    }
+
+    let mut target = target.answer();
+    for rr in source.answer()? {
+        if let Some(rr) =
+            rr?.into_record::<AllRecordData<_, ParsedName<_>>>()?
+        {
+            target.push(rr)?;
+        }
+    }
+
+    let mut target = target.authority();
+    for rr in source.authority()? {
+        if let Some(rr) =
+            rr?.into_record::<AllRecordData<_, ParsedName<_>>>()?
+        {
+            target.push(rr)?;
+        }
+    }
+
+    let mut target = target.additional();
+    for rr in source.additional()? {
+        if let Some(rr) =
+            rr?.into_record::<AllRecordData<_, ParsedName<_>>>()?
+        {
+            target.push(rr)?;
+        }
+    }
+
+    Ok(target.finish().into_target())
+}
+
+//------------ CompressError -------------------------------------------------
+
+/// An error occurred while compressing a response.
+#[derive(Debug)]
+enum CompressError {
+    /// There was a problem parsing the response.
+    InvalidResponse(ParseError),
+
+    /// There was a problem pushing to the compressed response.
+    PushFailure(PushError),
+}
+
+impl Display for CompressError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            CompressError::InvalidResponse(err) => {
+                write!(f, "Unable to parse response: {err}")
+            }
+            CompressError::PushFailure(err) => {
+                write!(f, "Unable to push into response: {err}")
+            }
+        }
+    }
+}
+
+impl From<ParseError> for CompressError {
+    fn from(err: ParseError) -> Self {
+        Self::InvalidResponse(err)
+    }
+}
+
+impl From<PushError> for CompressError {
+    fn from(err: PushError) -> Self {
+        Self::PushFailure(err)
+    }
+}
+
 #[cfg(test)]
 mod tests {
+    use std::string::ToString;
+
     use bytes::Bytes;
     use tokio::time::Instant;
 
@@ -348,12 +715,16 @@ mod tests {
     use crate::net::server::message::{Request, UdpTransportContext};
 
     use crate::base::iana::{OptRcode, Rcode};
-    use crate::base::message_builder::AdditionalBuilder;
+    use crate::base::message_builder::{AdditionalBuilder, AnswerBuilder};
+    use crate::base::net::IpAddr;
     use crate::base::opt::UnknownOptData;
     use crate::base::wire::Composer;
     use crate::net::server::util::{
-        add_edns_options, mk_builder_for_target, remove_edns_opt_record,
+        add_edns_options, client_prefix, compress_response,
+        mk_builder_for_target, remove_edns_opt_record, CompressionMode,
     };
+    use crate::rdata::A;
+    use core::str::FromStr;
     use std::vec::Vec;
 
     #[test]
@@ -484,8 +855,178 @@ mod tests {
         assert_opt(reply.clone(), Rcode::NOERROR, None);
     }
 
+    #[test]
+    fn test_compress_response() {
+        // Given a response with the same owner name repeated several times.
+        let reply = mk_repetitive_reply().finish();
+        let uncompressed = reply.as_dgram_slice();
+
+        // Never compressing leaves the response alone.
+        assert!(
+            compress_response(uncompressed, CompressionMode::Never).is_none()
+        );
+
+        // Compressing produces a smaller but otherwise equal response.
+        let compressed =
+            compress_response(uncompressed, CompressionMode::Always).unwrap();
+        assert!(compressed.as_dgram_slice().len() < uncompressed.len());
+        let compressed =
+            Message::from_octets(compressed.as_dgram_slice()).unwrap();
+        let uncompressed = Message::from_octets(uncompressed).unwrap();
+        assert_eq!(compressed.header(), uncompressed.header());
+        assert!(compressed
+            .answer()
+            .unwrap()
+            .eq(uncompressed.answer().unwrap()));
+
+        // Responses at or below the threshold are not compressed.
+        let len = u16::try_from(uncompressed.as_slice().len()).unwrap();
+        assert!(compress_response(
+            uncompressed.as_slice(),
+            CompressionMode::WhenBeneficial(len)
+        )
+        .is_none());
+        assert!(compress_response(
+            uncompressed.as_slice(),
+            CompressionMode::WhenBeneficial(len - 1)
+        )
+        .is_some());
+    }
+
+    #[test]
+    fn padded_response_is_not_compressed() {
+        let mut reply = mk_repetitive_reply().additional();
+        reply.opt(|builder| builder.padding(16)).unwrap();
+        let reply = reply.finish();
+
+        assert!(compress_response(
+            reply.as_dgram_slice(),
+            CompressionMode::Always
+        )
+        .is_none());
+    }
+
+    #[cfg(feature = "tsig")]
+    #[test]
+    fn signed_response_is_not_compressed() {
+        use crate::rdata::tsig::Time48;
+        use crate::tsig::{Algorithm, ClientTransaction, Key, KeyName};
+
+        let key = Key::new(
+            Algorithm::Sha256,
+            b"0123456789abcdef",
+            KeyName::from_str("key.example").unwrap(),
+            None,
+            None,
+        )
+        .unwrap();
+        let mut reply = mk_repetitive_reply().additional();
+        ClientTransaction::request(&key, &mut reply, Time48::from_u64(0))
+            .unwrap();
+        let reply = reply.finish();
+
+        assert!(compress_response(
+            reply.as_dgram_slice(),
+            CompressionMode::Always
+        )
+        .is_none());
+    }
+
+    #[test]
+    fn client_prefix_v4() {
+        let addr = "192.0.2.201:53".parse().unwrap();
+
+        let prefix = client_prefix(&addr, 24, 56);
+        assert_eq!(prefix.addr(), "192.0.2.0".parse::<IpAddr>().unwrap());
+        assert_eq!(prefix.prefix_len(), 24);
+        assert_eq!(prefix.to_string(), "192.0.2.0/24");
+        assert!(prefix.contains("192.0.2.1".parse().unwrap()));
+        assert!(!prefix.contains("192.0.3.1".parse().unwrap()));
+        assert!(!prefix.contains("2001:db8::1".parse().unwrap()));
+
+        let other = "192.0.2.7:12345".parse().unwrap();
+        assert_eq!(client_prefix(&other, 24, 56), prefix);
+
+        assert_eq!(client_prefix(&addr, 20, 56).to_string(), "192.0.0.0/20");
+        assert_eq!(client_prefix(&addr, 0, 56).to_string(), "0.0.0.0/0");
+        assert_eq!(
+            client_prefix(&addr, 40, 56).to_string(),
+            "192.0.2.201/32"
+        );
+    }
+
+    #[test]
+    fn client_prefix_v4_mapped() {
+        let addr = "[::ffff:192.0.2.201]:53".parse().unwrap();
+
+        let prefix = client_prefix(&addr, 24, 56);
+        assert_eq!(prefix.to_string(), "192.0.2.0/24");
+        assert_ne!(
+            prefix,
+            client_prefix(
+                &"[::ffff:198.51.100.1]:53".parse().unwrap(),
+                24,
+                56
+            )
+        );
+        assert_eq!(
+            prefix,
+            client_prefix(&"192.0.2.1:53".parse().unwrap(), 24, 56)
+        );
+        assert!(prefix.contains("::ffff:192.0.2.1".parse().unwrap()));
+        assert!(!prefix.contains("::ffff:198.51.100.1".parse().unwrap()));
+    }
+
+    #[test]
+    fn client_prefix_v6() {
+        let addr = "[2001:db8:aaaa:bbcc:dddd::1]:53".parse().unwrap();
+
+        let prefix = client_prefix(&addr, 24, 56);
+        assert_eq!(
+            prefix.addr(),
+            "2001:db8:aaaa:bb00::".parse::<IpAddr>().unwrap()
+        );
+        assert_eq!(prefix.prefix_len(), 56);
+        assert_eq!(prefix.to_string(), "2001:db8:aaaa:bb00::/56");
+        assert!(prefix.contains("2001:db8:aaaa:bbff::2".parse().unwrap()));
+        assert!(!prefix.contains("2001:db8:aaaa:bc00::2".parse().unwrap()));
+        assert!(!prefix.contains("192.0.2.1".parse().unwrap()));
+
+        assert_eq!(
+            client_prefix(&addr, 24, 48).to_string(),
+            "2001:db8:aaaa::/48"
+        );
+        assert_eq!(
+            client_prefix(&addr, 24, 64).to_string(),
+            "2001:db8:aaaa:bbcc::/64"
+        );
+        assert_eq!(client_prefix(&addr, 24, 0).to_string(), "::/0");
+        assert_eq!(
+            client_prefix(&addr, 24, 200).to_string(),
+            "2001:db8:aaaa:bbcc:dddd::1/128"
+        );
+    }
+
     //------------ Helper functions ------------------------------------------
 
+    /// Creates a response with the same owner name repeated several times.
+    fn mk_repetitive_reply() -> AnswerBuilder<StreamTarget<Vec<u8>>> {
+        let qname = Name::<Vec<u8>>::from_str("www.example.com").unwrap();
+        let mut query = MessageBuilder::new_vec().question();
+        query.push((&qname, Rtype::A)).unwrap();
+        let query = query.into_message();
+
+        let mut reply = mk_builder_for_target::<Vec<u8>>()
+            .start_answer(&query, Rcode::NOERROR)
+            .unwrap();
+        for i in 1..=4 {
+            reply
+                .push((&qname, 3600, A::from_octets(192, 0, 2, i)))
+                .unwrap();
+        }
+        reply
+    }
+
     fn assert_opt<Target: Composer>(
         reply: AdditionalBuilder<StreamTarget<Target>>,
         expected_rcode: Rcode,
@@ -0,0 +1,1822 @@
+//! A service for answering queries from a [`ZoneTree`].
+//!
+//! The [`ZoneTreeService`] answers queries using the [`Zone`] in a
+//! [`ZoneTree`] that most closely encloses the query name.
+//!
+//! Each zone has a [`ZoneRole`] which determines how queries for names in
+//! that zone are handled. By default zones are [`ZoneRole::Authoritative`]
+//! and are answered from the data in the zone with the AA flag set. Zones can
+//! instead be marked as [`ZoneRole::Forward`] in which case queries for names
+//! in the zone are forwarded to an upstream client transport. This allows a
+//! single service to act as both an authoritative server for some zones and
+//! a forwarder for others.
+//!
+//! When answering authoritatively, CNAMEs whose target lies in the same zone
+//! are followed and the answer includes each CNAME along the way as well as
+//! the records of the final target. Following stops at the zone boundary, on
+//! a loop, or after a bounded number of CNAMEs.
+//!
+//! Queries for names below a DNAME are answered with the DNAME and a CNAME
+//! synthesized from it as described in [RFC 6672]. The synthesized CNAME is
+//! followed like any other. If the synthesized name would be too long, the
+//! query is answered with YXDOMAIN.
+//!
+//! Referrals to delegated child zones include glue only for the nameservers
+//! whose names lie within the child zone. For responses sent over UDP, glue
+//! that would make the response too large is left out and the TC flag is
+//! set.
+//!
+//! Forward zones must still be present in the [`ZoneTree`] so that queries
+//! can be matched to them, but they do not need to contain any data other
+//! than the apex. To forward queries for all names outside of the zones in
+//! the tree instead, use a [`SplitService`].
+//!
+//! Queries for names outside of all zones in the tree are answered with
+//! REFUSED by default, as the server is not authoritative for them and so
+//! cannot assert their non-existence. See [`OutOfZoneResponse`] for
+//! alternatives.
+//!
+//! Answers with MX, SRV or NS records get the A and AAAA records of their
+//! targets added to the additional section, as far as the targets lie
+//! within the zone and the records fit, see
+//! [`ZoneTreeService::with_additional_section`].
+//!
+//! Optionally, the ipv4hint and ipv6hint parameters of SVCB and HTTPS
+//! records can be filled in from the address records of their target, see
+//! [`ZoneTreeService::with_svcb_hint_synthesis`].
+//!
+//! Queries asking for the version of the zone via an empty ZONEVERSION EDNS
+//! option as described in [RFC 9660] get the SOA serial of the zone the
+//! answer was taken from in their response, see
+//! [`ZoneTreeService::with_zone_version`].
+//!
+//! [`Zone`]: crate::zonetree::Zone
+//! [RFC 6672]: https://www.rfc-editor.org/rfc/rfc6672.html
+//! [RFC 9660]: https://www.rfc-editor.org/rfc/rfc9660.html
+
+#![warn(missing_docs)]
+#![warn(clippy::missing_docs_in_private_items)]
+
+use core::future::{ready, Future, Ready};
+use core::pin::Pin;
+
+use std::boxed::Box;
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::string::ToString;
+use std::sync::Arc;
+use std::vec::Vec;
+
+use bytes::Bytes;
+use futures_util::stream::{once, Once};
+use octseq::{Octets, Parser};
+use tracing::{debug, trace};
+
+use crate::base::iana::{Class, ExtendedErrorCode, OptRcode, Rcode};
+use crate::base::message_builder::AdditionalBuilder;
+use crate::base::opt::{ExtendedError, ZoneVersion};
+use crate::base::rdata::{ComposeRecordData, UnknownRecordData};
+use crate::base::{
+    Message, MessageBuilder, Record, Rtype, StreamTarget, ToName,
+};
+use crate::net::client::request::{RequestMessage, SendRequest};
+use crate::rdata::svcb::SvcParamsBuilder;
+use crate::rdata::{Cname, Svcb, ZoneRecordData};
+use crate::zonetree::{
+    Answer, AnswerAdditional, AnswerContent, ReadableZone, Rrset, StoredName,
+    StoredRecord, ZoneTree,
+};
+
+use super::message::{Request, TransportSpecificContext};
+use super::middleware::mandatory::MINIMUM_RESPONSE_BYTE_LEN;
+use super::service::{CallResult, Service, ServiceError, ServiceResult};
+use super::single_service::{ComposeReply, ReplyMessage};
+use super::util::{
+    add_edns_options, mk_builder_for_target, mk_error_response,
+};
+
+//------------ Constants -----------------------------------------------------
+
+/// The maximum number of in-zone CNAMEs followed when answering a query.
+///
+/// This bounds the work done for a single query should the zone contain a
+/// long or looping CNAME chain.
+const MAX_CNAME_CHAIN_LEN: usize = 8;
+
+//------------ ZoneRole ------------------------------------------------------
+
+/// How queries for names in a zone are answered.
+#[derive(Clone, Debug, Default)]
+pub enum ZoneRole<Upstream> {
+    /// Answer from the data in the zone with the AA flag set.
+    #[default]
+    Authoritative,
+
+    /// Forward queries to the given upstream client transport.
+    ///
+    /// Responses from the upstream are returned with the AA flag cleared.
+    Forward(Upstream),
+}
+
+//------------ OutOfZoneResponse ---------------------------------------------
+
+/// How queries for names outside of all zones in the tree are answered.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum OutOfZoneResponse {
+    /// Answer with REFUSED and the AA flag cleared.
+    ///
+    /// This is the behaviour resolvers expect of an authoritative server for
+    /// queries it is not authoritative for.
+    #[default]
+    Refused,
+
+    /// Answer with NXDOMAIN and the AA flag set.
+    ///
+    /// As no zone is available to take an SOA record from the response has
+    /// an empty authority section, so resolvers cannot cache it negatively.
+    NxDomain,
+}
+
+//------------ ZoneTreeService -----------------------------------------------
+
+/// A [`Service`] that answers queries from a [`ZoneTree`].
+///
+/// See the [module documentation][self] for more information.
+#[derive(Debug)]
+pub struct ZoneTreeService<Upstream> {
+    /// The zones to answer from.
+    zones: Arc<ZoneTree>,
+
+    /// The role of each zone that is not authoritative.
+    ///
+    /// Zones without an entry are [`ZoneRole::Authoritative`].
+    roles: Arc<HashMap<(StoredName, Class), ZoneRole<Upstream>>>,
+
+    /// How to answer queries for names outside of all zones.
+    out_of_zone: OutOfZoneResponse,
+
+    /// Whether to add address records for the targets of MX, SRV and NS
+    /// records to the additional section.
+    additional_section: bool,
+
+    /// Whether to add address hints to SVCB and HTTPS records.
+    svcb_hints: bool,
+
+    /// Whether to answer requests for the ZONEVERSION option.
+    zone_version: bool,
+}
+
+impl<Upstream> ZoneTreeService<Upstream> {
+    /// Creates a new service answering authoritatively for every zone in the
+    /// given tree.
+    #[must_use]
+    pub fn new(zones: Arc<ZoneTree>) -> Self {
+        Self {
+            zones,
+            roles: Default::default(),
+            out_of_zone: Default::default(),
+            additional_section: true,
+            svcb_hints: false,
+            zone_version: true,
+        }
+    }
+
+    /// Sets how queries for names outside of all zones are answered.
+    ///
+    /// Defaults to [`OutOfZoneResponse::Refused`].
+    #[must_use]
+    pub fn with_out_of_zone_response(
+        mut self,
+        out_of_zone: OutOfZoneResponse,
+    ) -> Self {
+        self.out_of_zone = out_of_zone;
+        self
+    }
+
+    /// Sets whether to do additional section processing.
+    ///
+    /// If enabled, the A and AAAA records of the targets of MX, SRV and NS
+    /// records in the answer section are added to the additional section as
+    /// described in [RFC 1035] section 3.3, provided the targets lie in the
+    /// same zone. Records that don't fit in the response are left out
+    /// without setting the TC flag.
+    ///
+    /// Disable this if minimal responses are preferred. Glue in referrals
+    /// is always included regardless of this setting.
+    ///
+    /// Enabled by default.
+    ///
+    /// [RFC 1035]: https://www.rfc-editor.org/rfc/rfc1035.html
+    #[must_use]
+    pub fn with_additional_section(mut self, enabled: bool) -> Self {
+        self.additional_section = enabled;
+        self
+    }
+
+    /// Sets whether to synthesize address hints for SVCB and HTTPS records.
+    ///
+    /// If enabled, SVCB and HTTPS records in ServiceMode that are returned
+    /// in answers to SVCB and HTTPS queries and lack an ipv4hint or
+    /// ipv6hint parameter get one listing the addresses of the A or AAAA
+    /// records of their target as per [RFC 9460] section 7.3, provided the
+    /// target lies in the same zone and has such records. Records whose
+    /// target is outside the zone are returned unchanged.
+    ///
+    /// Hints present in the zone are never replaced. Note that synthesized
+    /// hints are not covered by any RRSIG of the records, so this should
+    /// not be used with signed zones.
+    ///
+    /// Disabled by default.
+    ///
+    /// [RFC 9460]: https://www.rfc-editor.org/rfc/rfc9460.html
+    #[must_use]
+    pub fn with_svcb_hint_synthesis(mut self, enabled: bool) -> Self {
+        self.svcb_hints = enabled;
+        self
+    }
+
+    /// Sets whether to include the version of the zone when asked for it.
+    ///
+    /// If enabled, responses to requests with an empty ZONEVERSION option
+    /// that are answered from a zone in the tree get a ZONEVERSION option
+    /// with the serial of the zone's SOA record as per [RFC 9660]. Responses
+    /// to requests for names outside of all zones or in forward zones never
+    /// get the option.
+    ///
+    /// Enabled by default.
+    ///
+    /// [RFC 9660]: https://www.rfc-editor.org/rfc/rfc9660.html
+    #[must_use]
+    pub fn with_zone_version(mut self, enabled: bool) -> Self {
+        self.zone_version = enabled;
+        self
+    }
+
+    /// Sets the role of the zone with the given apex name and class.
+    ///
+    /// The zone should exist in the [`ZoneTree`] given to [`new()`],
+    /// otherwise the role will have no effect.
+    ///
+    /// [`new()`]: Self::new
+    #[must_use]
+    pub fn with_zone_role(
+        mut self,
+        apex_name: &impl ToName,
+        class: Class,
+        role: ZoneRole<Upstream>,
+    ) -> Self
+    where
+        Upstream: Clone,
+    {
+        let roles = Arc::make_mut(&mut self.roles);
+        let key = (apex_name.to_name(), class);
+        match role {
+            ZoneRole::Authoritative => {
+                let _ = roles.remove(&key);
+            }
+            role => {
+                let _ = roles.insert(key, role);
+            }
+        }
+        self
+    }
+}
+
+impl<Upstream> Clone for ZoneTreeService<Upstream> {
+    fn clone(&self) -> Self {
+        Self {
+            zones: self.zones.clone(),
+            roles: self.roles.clone(),
+            out_of_zone: self.out_of_zone,
+            additional_section: self.additional_section,
+            svcb_hints: self.svcb_hints,
+            zone_version: self.zone_version,
+        }
+    }
+}
+
+impl<Upstream> ZoneTreeService<Upstream> {
+    /// Answer the request from the data in the given zone.
+    async fn answer_authoritatively<RequestOctets, RequestMeta>(
+        request: Request<RequestOctets, RequestMeta>,
+        zones: Arc<ZoneTree>,
+        out_of_zone: OutOfZoneResponse,
+        additional_section: bool,
+        svcb_hints: bool,
+        zone_version: bool,
+    ) -> ServiceResult<Vec<u8>>
+    where
+        RequestOctets: Octets + Send + Sync,
+    {
+        let (qname, qclass, qtype) = {
+            let question = request.message().sole_question()?;
+            (
+                question.qname().to_bytes(),
+                question.qclass(),
+                question.qtype(),
+            )
+        };
+        let Some(zone) = zones.find_zone(&qname, qclass) else {
+            trace!("No zone for '{qname}', answering with {out_of_zone:?}");
+            let answer = match out_of_zone {
+                OutOfZoneResponse::Refused => Answer::refused(),
+                OutOfZoneResponse::NxDomain => {
+                    let mut answer = Answer::new(Rcode::NXDOMAIN);
+                    answer.set_authoritative(true);
+                    answer
+                }
+            };
+            let builder = mk_response_builder(&request);
+            return Ok(CallResult::new(
+                answer.to_message(request.message(), builder),
+            ));
+        };
+        let apex_name = zone.apex_name().clone();
+        let zone = zone.read();
+
+        let mut owner = qname;
+        let mut answer = query_zone(&*zone, owner.clone(), qtype).await?;
+
+        // Follow CNAMEs that stay within the zone, unless the CNAME itself
+        // was asked for. Names below a DNAME have a CNAME synthesized for
+        // them which is followed likewise.
+        let follow_cnames = qtype != Rtype::CNAME && qtype != Rtype::ANY;
+        let mut chain: Vec<StoredRecord> = Vec::new();
+        let mut hops = 0;
+        loop {
+            let (target, cname) = match answer.content() {
+                AnswerContent::Cname(cname) if follow_cnames => {
+                    let ZoneRecordData::Cname(target) = cname.data() else {
+                        break;
+                    };
+                    let record = Record::new(
+                        owner.clone(),
+                        qclass,
+                        cname.ttl(),
+                        cname.data().clone(),
+                    );
+                    (target.cname().clone(), Some(record))
+                }
+                _ if answer.rcode() == Rcode::NXDOMAIN => {
+                    let Some(dname) =
+                        find_dname(&*zone, &apex_name, &owner, qclass)
+                            .await?
+                    else {
+                        break;
+                    };
+                    let Some(cname) = synthesize_cname(&owner, &dname) else {
+                        // RFC 6672 section 2.2: a substitution resulting in
+                        // an overlong name is answered with YXDOMAIN.
+                        debug!(
+                            "DNAME substitution for '{owner}' is too long"
+                        );
+                        chain.push(dname);
+                        answer = Answer::new(Rcode::YXDOMAIN);
+                        break;
+                    };
+                    let ZoneRecordData::Cname(target) = cname.data() else {
+                        break;
+                    };
+                    let target = target.cname().clone();
+
+                    // The DNAME and synthesized CNAME are part of the
+                    // answer even if the target isn't followed.
+                    chain.push(dname);
+                    chain.push(cname);
+                    answer = Answer::new(Rcode::NOERROR);
+                    (target, None)
+                }
+                _ => break,
+            };
+
+            if !target.ends_with(&apex_name) {
+                trace!("CNAME target '{target}' is outside the zone");
+                break;
+            }
+            if target == owner || chain.iter().any(|rr| *rr.owner() == target)
+            {
+                debug!("CNAME loop detected at '{target}'");
+                break;
+            }
+            if hops >= MAX_CNAME_CHAIN_LEN {
+                debug!("CNAME chain for '{}' is too long", chain[0].owner());
+                break;
+            }
+
+            chain.extend(cname);
+            hops += 1;
+            owner = target;
+            answer = query_zone(&*zone, owner.clone(), qtype).await?;
+        }
+
+        if svcb_hints && matches!(qtype, Rtype::SVCB | Rtype::HTTPS) {
+            synthesize_svcb_hints(&*zone, &apex_name, &owner, &mut answer)
+                .await?;
+        }
+
+        if additional_section {
+            add_additional_records(&*zone, &apex_name, qclass, &mut answer)
+                .await?;
+        }
+
+        answer.set_cname_chain(chain);
+        answer.set_authoritative(true);
+
+        let builder = mk_response_builder(&request);
+        let mut response = answer.to_message(request.message(), builder);
+        if zone_version && wants_zone_version(request.message()) {
+            add_zone_version(&*zone, &apex_name, &mut response).await?;
+        }
+        Ok(CallResult::new(response))
+    }
+
+    /// Forward the request to the given upstream.
+    async fn forward<RequestOctets, RequestMeta>(
+        request: Request<RequestOctets, RequestMeta>,
+        upstream: &Upstream,
+    ) -> ServiceResult<Vec<u8>>
+    where
+        RequestOctets: Octets + Clone + Debug + Send + Sync,
+        Upstream: SendRequest<RequestMessage<RequestOctets>>,
+    {
+        let msg = request.message().clone();
+        let reqmsg: RequestMessage<RequestOctets> = request
+            .with_new_metadata(())
+            .try_into()
+            .map_err(|_| ServiceError::InternalError)?;
+
+        let mut response =
+            match upstream.send_request(reqmsg).get_response().await {
+                Ok(msg) => ReplyMessage::from_message(&msg)?
+                    .additional_builder_stream_target()?,
+
+                Err(err) => {
+                    debug!("Upstream request failed: {err}");
+                    let mut response =
+                        mk_error_response(&msg, OptRcode::SERVFAIL);
+                    if let Ok(ede) = ExtendedError::<Vec<u8>>::new_with_str(
+                        ExtendedErrorCode::OTHER,
+                        &err.to_string(),
+                    ) {
+                        let _ = add_edns_options(&mut response, |builder| {
+                            builder.push(&ede)
+                        });
+                    }
+                    response
+                }
+            };
+
+        response.header_mut().set_id(msg.header().id());
+        response.header_mut().set_aa(false);
+
+        Ok(CallResult::new(response))
+    }
+}
+
+//------------ Helper functions ----------------------------------------------
+
+/// Creates a builder for an authoritative response to the given request.
+///
+/// For requests received over UDP the push limit of the builder is set to
+/// the maximum response size less any bytes reserved by middleware, so that
+/// [`Answer::to_message`] can leave out additional records, e.g. referral
+/// glue, that would not fit.
+fn mk_response_builder<RequestOctets, RequestMeta>(
+    request: &Request<RequestOctets, RequestMeta>,
+) -> MessageBuilder<StreamTarget<Vec<u8>>>
+where
+    RequestOctets: Octets + Send + Sync,
+{
+    let mut builder = mk_builder_for_target();
+    if let TransportSpecificContext::Udp(ctx) = request.transport_ctx() {
+        let max_response_size = ctx
+            .max_response_size_hint()
+            .unwrap_or(MINIMUM_RESPONSE_BYTE_LEN);
+        let limit =
+            max_response_size.saturating_sub(request.num_reserved_bytes());
+        builder.set_push_limit(limit.into());
+    }
+    builder
+}
+
+/// Query the given zone, whether it is async or not.
+async fn query_zone(
+    zone: &dyn ReadableZone,
+    qname: StoredName,
+    qtype: Rtype,
+) -> Result<Answer, ServiceError> {
+    let res = if zone.is_async() {
+        zone.query_async(qname, qtype).await
+    } else {
+        zone.query(qname, qtype)
+    };
+    res.map_err(|_| ServiceError::InternalError)
+}
+
+/// Returns whether the request asks for the version of the zone.
+///
+/// This is the case if it contains an empty ZONEVERSION option.
+fn wants_zone_version<Octs: Octets>(msg: &Message<Octs>) -> bool {
+    msg.opt().map_or(false, |opt| {
+        opt.opt()
+            .zone_version()
+            .map_or(false, |version| version.is_empty())
+    })
+}
+
+/// Adds a ZONEVERSION option with the SOA serial of the zone to a response.
+///
+/// Nothing is added if the zone has no SOA record or the option doesn't
+/// fit.
+async fn add_zone_version(
+    zone: &dyn ReadableZone,
+    apex_name: &StoredName,
+    response: &mut AdditionalBuilder<StreamTarget<Vec<u8>>>,
+) -> Result<(), ServiceError> {
+    let answer = query_zone(zone, apex_name.clone(), Rtype::SOA).await?;
+    let AnswerContent::Data(rrset) = answer.content() else {
+        return Ok(());
+    };
+    let Some(ZoneRecordData::Soa(soa)) = rrset.data().first() else {
+        return Ok(());
+    };
+
+    // The label count doesn't include the root label. Names have at most
+    // 127 labels, so this always fits.
+    let label_count = u8::try_from(apex_name.label_count() - 1)
+        .map_err(|_| ServiceError::InternalError)?;
+    let version = ZoneVersion::soa_serial(label_count, soa.serial());
+    if let Err(err) =
+        add_edns_options(response, |builder| builder.push(&version))
+    {
+        debug!("Unable to add ZONEVERSION option: {err}");
+    }
+    Ok(())
+}
+
+/// Finds the DNAME record whose subtree contains the given name.
+///
+/// Each ancestor of the name within the zone, starting at the apex, is
+/// checked for a DNAME. Returns the DNAME record found, if any.
+async fn find_dname(
+    zone: &dyn ReadableZone,
+    apex_name: &StoredName,
+    name: &StoredName,
+    qclass: Class,
+) -> Result<Option<StoredRecord>, ServiceError> {
+    let mut ancestors: Vec<StoredName> = name
+        .iter_suffixes()
+        .skip(1)
+        .take_while(|suffix| suffix.ends_with(apex_name))
+        .collect();
+    while let Some(ancestor) = ancestors.pop() {
+        let answer = query_zone(zone, ancestor.clone(), Rtype::DNAME).await?;
+        match answer.content() {
+            AnswerContent::Data(rrset) if rrset.rtype() == Rtype::DNAME => {
+                if let Some(rr) = rrset.first() {
+                    return Ok(Some(Record::new(
+                        ancestor,
+                        qclass,
+                        rr.ttl(),
+                        rr.data().clone(),
+                    )));
+                }
+            }
+            // Nothing exists below a name that doesn't exist.
+            _ if answer.rcode() == Rcode::NXDOMAIN => break,
+            _ => {}
+        }
+    }
+    Ok(None)
+}
+
+/// Synthesizes the CNAME for a name below the owner of a DNAME.
+///
+/// The CNAME has the TTL of the DNAME as required by [RFC 6672 section
+/// 3.1]. Returns `None` if the target of the CNAME would be longer than the
+/// maximum length of a domain name.
+///
+/// [RFC 6672 section 3.1]:
+///     https://www.rfc-editor.org/rfc/rfc6672.html#section-3.1
+fn synthesize_cname(
+    name: &StoredName,
+    dname: &StoredRecord,
+) -> Option<StoredRecord> {
+    let ZoneRecordData::Dname(dname_target) = dname.data() else {
+        return None;
+    };
+    let prefix = name.clone().strip_suffix(dname.owner()).ok()?;
+    let target = prefix.chain(dname_target.dname().clone()).ok()?;
+    Some(Record::new(
+        name.clone(),
+        dname.class(),
+        dname.ttl(),
+        ZoneRecordData::Cname(Cname::new(target.to_bytes())),
+    ))
+}
+
+/// Adds the address records of the targets of the answer to the answer.
+///
+/// Only the targets of MX, SRV and NS records that lie within the zone are
+/// looked up. The address records are added as discardable additional
+/// records so that they are left out if the response would be too large.
+async fn add_additional_records(
+    zone: &dyn ReadableZone,
+    apex_name: &StoredName,
+    qclass: Class,
+    answer: &mut Answer,
+) -> Result<(), ServiceError> {
+    let AnswerContent::Data(rrset) = answer.content() else {
+        return Ok(());
+    };
+
+    let mut targets: Vec<StoredName> = Vec::new();
+    for data in rrset.data() {
+        let target = match data {
+            ZoneRecordData::Mx(mx) => mx.exchange(),
+            ZoneRecordData::Srv(srv) => srv.target(),
+            ZoneRecordData::Ns(ns) => ns.nsdname(),
+            _ => continue,
+        };
+        // A target of "." means that there is no such service.
+        if target.is_root() || !target.ends_with(apex_name) {
+            continue;
+        }
+        if !targets.contains(target) {
+            targets.push(target.clone());
+        }
+    }
+
+    let mut records = Vec::new();
+    for target in targets {
+        for rtype in [Rtype::A, Rtype::AAAA] {
+            let answer = query_zone(zone, target.clone(), rtype).await?;
+            if let AnswerContent::Data(rrset) = answer.content() {
+                records.extend(rrset.data().iter().map(|data| {
+                    Record::new(
+                        target.clone(),
+                        qclass,
+                        rrset.ttl(),
+                        data.clone(),
+                    )
+                }));
+            }
+        }
+    }
+
+    if !records.is_empty() {
+        let mut additional = AnswerAdditional::default();
+        additional.push_discardable(records);
+        answer.set_additional(additional);
+    }
+    Ok(())
+}
+
+/// Adds address hints to the SVCB or HTTPS records of the answer.
+///
+/// As SVCB and HTTPS records are stored in zones in their generic form, the
+/// records are parsed, extended and then stored in generic form again.
+async fn synthesize_svcb_hints(
+    zone: &dyn ReadableZone,
+    apex_name: &StoredName,
+    owner: &StoredName,
+    answer: &mut Answer,
+) -> Result<(), ServiceError> {
+    let AnswerContent::Data(rrset) = answer.content() else {
+        return Ok(());
+    };
+    if !matches!(rrset.rtype(), Rtype::SVCB | Rtype::HTTPS) {
+        return Ok(());
+    }
+
+    let mut synthesized = Rrset::new(rrset.rtype(), rrset.ttl());
+    let mut changed = false;
+    for data in rrset.data() {
+        let hinted = match data {
+            ZoneRecordData::Unknown(data) => {
+                add_svcb_hints(zone, apex_name, owner, data).await?
+            }
+            _ => None,
+        };
+        match hinted {
+            Some(hinted) => {
+                synthesized.push_data(ZoneRecordData::Unknown(hinted));
+                changed = true;
+            }
+            None => synthesized.push_data(data.clone()),
+        }
+    }
+
+    if changed {
+        answer.add_answer(synthesized.into_shared());
+    }
+    Ok(())
+}
+
+/// Returns the SVCB or HTTPS record data with address hints added.
+///
+/// Returns `None` if the record data should be left as is, e.g. because it
+/// is in AliasMode, already has both hints, or its target is outside the
+/// zone or has no address records.
+async fn add_svcb_hints(
+    zone: &dyn ReadableZone,
+    apex_name: &StoredName,
+    owner: &StoredName,
+    data: &UnknownRecordData<Bytes>,
+) -> Result<Option<UnknownRecordData<Bytes>>, ServiceError> {
+    let Ok(svcb) = Svcb::parse(&mut Parser::from_ref(data.data())) else {
+        debug!("Unable to parse {} record of '{owner}'", data.rtype());
+        return Ok(None);
+    };
+    let need_v4 = svcb.params().ipv4hint().is_none();
+    let need_v6 = svcb.params().ipv6hint().is_none();
+    if svcb.is_alias() || (!need_v4 && !need_v6) {
+        return Ok(None);
+    }
+
+    // In ServiceMode a target of "." stands for the owner name.
+    let target: StoredName = if svcb.target().is_root() {
+        owner.clone()
+    } else {
+        svcb.target().to_name()
+    };
+    if !target.ends_with(apex_name) {
+        trace!("SVCB target '{target}' is outside the zone");
+        return Ok(None);
+    }
+
+    let mut v4 = Vec::new();
+    if need_v4 {
+        let answer = query_zone(zone, target.clone(), Rtype::A).await?;
+        if let AnswerContent::Data(rrset) = answer.content() {
+            v4.extend(rrset.data().iter().filter_map(|data| match data {
+                ZoneRecordData::A(a) => Some(a.addr()),
+                _ => None,
+            }));
+        }
+    }
+    let mut v6 = Vec::new();
+    if need_v6 {
+        let answer = query_zone(zone, target, Rtype::AAAA).await?;
+        if let AnswerContent::Data(rrset) = answer.content() {
+            v6.extend(rrset.data().iter().filter_map(|data| match data {
+                ZoneRecordData::Aaaa(aaaa) => Some(aaaa.addr()),
+                _ => None,
+            }));
+        }
+    }
+    if v4.is_empty() && v6.is_empty() {
+        return Ok(None);
+    }
+
+    let Ok(mut params) =
+        SvcParamsBuilder::<Vec<u8>>::from_params(svcb.params())
+    else {
+        return Ok(None);
+    };
+    if !v4.is_empty() && params.ipv4hint(&v4).is_err() {
+        return Ok(None);
+    }
+    if !v6.is_empty() && params.ipv6hint(&v6).is_err() {
+        return Ok(None);
+    }
+    let params = params
+        .freeze::<Vec<u8>>()
+        .unwrap_or_else(|err| match err {});
+    let Ok(svcb) = Svcb::new(svcb.priority(), svcb.target().clone(), params)
+    else {
+        return Ok(None);
+    };
+
+    let mut octets = Vec::new();
+    if svcb.compose_rdata(&mut octets).is_err() {
+        return Ok(None);
+    }
+    Ok(UnknownRecordData::from_octets(data.rtype(), octets.into()).ok())
+}
+
+//--- Service
+
+impl<RequestOctets, RequestMeta, Upstream> Service<RequestOctets, RequestMeta>
+    for ZoneTreeService<Upstream>
+where
+    RequestOctets: Octets + Clone + Debug + Send + Sync + 'static,
+    RequestMeta: Clone + Default + Send + Sync + 'static,
+    Upstream: SendRequest<RequestMessage<RequestOctets>>
+        + Clone
+        + Send
+        + Sync
+        + 'static,
+{
+    type Target = Vec<u8>;
+    type Stream = Once<Ready<ServiceResult<Self::Target>>>;
+    type Future = Pin<Box<dyn Future<Output = Self::Stream> + Send>>;
+
+    fn call(
+        &self,
+        request: Request<RequestOctets, RequestMeta>,
+    ) -> Self::Future {
+        let (qname, qclass) = match request.message().sole_question() {
+            Ok(question) => (question.qname().to_bytes(), question.qclass()),
+            Err(err) => {
+                return Box::pin(ready(once(ready(Err(err.into())))));
+            }
+        };
+
+        let role = self.zones.find_zone(&qname, qclass).and_then(|zone| {
+            self.roles.get(&(zone.apex_name().clone(), zone.class()))
+        });
+
+        match role {
+            Some(ZoneRole::Forward(upstream)) => {
+                trace!("Forwarding query for '{qname}'");
+                let upstream = upstream.clone();
+                Box::pin(async move {
+                    once(ready(Self::forward(request, &upstream).await))
+                })
+            }
+
+            _ => {
+                let zones = self.zones.clone();
+                let out_of_zone = self.out_of_zone;
+                let additional_section = self.additional_section;
+                let svcb_hints = self.svcb_hints;
+                let zone_version = self.zone_version;
+                Box::pin(async move {
+                    once(ready(
+                        Self::answer_authoritatively(
+                            request,
+                            zones,
+                            out_of_zone,
+                            additional_section,
+                            svcb_hints,
+                            zone_version,
+                        )
+                        .await,
+                    ))
+                })
+            }
+        }
+    }
+}
+
+//------------ SplitService --------------------------------------------------
+
+/// A [`Service`] answering from local zones and forwarding everything else.
+///
+/// Queries for names within a zone of the [`ZoneTreeService`] are answered
+/// by it, i.e. authoritatively with the AA flag set unless the zone has been
+/// given a different [`ZoneRole`]. Queries for names outside of all zones
+/// are forwarded to the upstream client transport and answered with the AA
+/// flag cleared, rather than being answered as configured via
+/// [`ZoneTreeService::with_out_of_zone_response`].
+///
+/// This is the common deployment of a server that is authoritative for some
+/// local zones and forwards all other queries to a resolver.
+#[derive(Clone, Debug)]
+pub struct SplitService<Upstream> {
+    /// The service answering queries for names within its zones.
+    local: ZoneTreeService<Upstream>,
+
+    /// The upstream to forward queries for all other names to.
+    upstream: Upstream,
+}
+
+impl<Upstream> SplitService<Upstream> {
+    /// Creates a new service answering from the given local zones and
+    /// forwarding other queries to the given upstream.
+    #[must_use]
+    pub fn new(local: ZoneTreeService<Upstream>, upstream: Upstream) -> Self {
+        Self { local, upstream }
+    }
+
+    /// The service answering queries for names within its zones.
+    pub fn local(&self) -> &ZoneTreeService<Upstream> {
+        &self.local
+    }
+}
+
+//--- Service
+
+impl<RequestOctets, RequestMeta, Upstream> Service<RequestOctets, RequestMeta>
+    for SplitService<Upstream>
+where
+    RequestOctets: Octets + Clone + Debug + Send + Sync + 'static,
+    RequestMeta: Clone + Default + Send + Sync + 'static,
+    Upstream: SendRequest<RequestMessage<RequestOctets>>
+        + Clone
+        + Send
+        + Sync
+        + 'static,
+{
+    type Target = Vec<u8>;
+    type Stream = Once<Ready<ServiceResult<Self::Target>>>;
+    type Future = Pin<Box<dyn Future<Output = Self::Stream> + Send>>;
+
+    fn call(
+        &self,
+        request: Request<RequestOctets, RequestMeta>,
+    ) -> Self::Future {
+        let in_zone = match request.message().sole_question() {
+            Ok(question) => self
+                .local
+                .zones
+                .find_zone(&question.qname(), question.qclass())
+                .is_some(),
+            Err(err) => {
+                return Box::pin(ready(once(ready(Err(err.into())))));
+            }
+        };
+
+        if in_zone {
+            return self.local.call(request);
+        }
+
+        trace!("No local zone for query, forwarding");
+        let upstream = self.upstream.clone();
+        Box::pin(async move {
+            once(ready(ZoneTreeService::forward(request, &upstream).await))
+        })
+    }
+}
+
+//============ Tests =========================================================
+
+#[cfg(test)]
+mod tests {
+    use core::future::ready;
+    use core::pin::Pin;
+
+    use std::boxed::Box;
+    use std::io::BufReader;
+    use std::str::FromStr;
+    use std::string::{String, ToString};
+    use std::sync::Arc;
+    use std::vec::Vec;
+    use std::{format, vec};
+
+    use bytes::Bytes;
+    use futures_util::StreamExt;
+    use tokio::time::Instant;
+
+    use crate::base::iana::{Class, Rcode};
+    use crate::base::rdata::ComposeRecordData;
+    use crate::base::{
+        Message, MessageBuilder, Name, Rtype, Serial, ToName, Ttl,
+    };
+    use crate::net::client::request::{
+        Error, GetResponse, RequestMessage, SendRequest,
+    };
+    use crate::net::server::message::{Request, UdpTransportContext};
+    use crate::net::server::service::{CallResult, Service};
+    use crate::rdata::svcb::SvcParams;
+    use crate::rdata::{Cname, Dname, Https, Ns, Ptr, Soa, A};
+    use crate::utils::base16;
+    use crate::zonefile::inplace;
+    use crate::zonetree::{Zone, ZoneTree};
+
+    use super::{OutOfZoneResponse, SplitService, ZoneRole, ZoneTreeService};
+
+    #[tokio::test]
+    async fn authoritative_zone() {
+        let svc = ZoneTreeService::<MockUpstream>::new(mk_zones());
+
+        let response = process(&svc, "example.com").await;
+        assert_eq!(response.header().rcode(), Rcode::NOERROR);
+        assert!(response.header().aa());
+        assert_eq!(addrs(&response), [[192, 0, 2, 1]]);
+    }
+
+    #[tokio::test]
+    async fn in_zone_nxdomain_has_soa() {
+        let svc = ZoneTreeService::<MockUpstream>::new(mk_zones());
+
+        let response = process(&svc, "nonexistent.example.com").await;
+        assert_eq!(response.header().rcode(), Rcode::NXDOMAIN);
+        assert!(response.header().aa());
+        assert_eq!(soa_owners(&response), ["example.com"]);
+    }
+
+    #[tokio::test]
+    async fn negative_answer_soa_has_negative_ttl() {
+        let svc = ZoneTreeService::<MockUpstream>::new(mk_zones_from_str(
+            CNAME_ZONE,
+        ));
+
+        // NXDOMAIN and NODATA both use the SOA minimum of 300 rather than
+        // the SOA record's TTL of 3600.
+        for response in [
+            process(&svc, "nonexistent.example.org").await,
+            process_qtype(&svc, "ns1.example.org", Rtype::AAAA).await,
+        ] {
+            let ttls: Vec<_> = response
+                .authority()
+                .unwrap()
+                .limit_to::<Soa<_>>()
+                .map(|rr| rr.unwrap().ttl())
+                .collect();
+            assert_eq!(ttls, [Ttl::from_secs(300)]);
+        }
+    }
+
+    #[tokio::test]
+    async fn reverse_zone_ptr_answers() {
+        let svc = ZoneTreeService::<MockUpstream>::new(mk_zones_from_str(
+            REVERSE_ZONE,
+        ));
+
+        // An address with a PTR record.
+        let response =
+            process_qtype(&svc, "1.2.0.192.in-addr.arpa", Rtype::PTR).await;
+        assert_eq!(response.header().rcode(), Rcode::NOERROR);
+        assert!(response.header().aa());
+        assert_eq!(ptrs(&response), ["host1.example.com"]);
+
+        // An address delegated the RFC 2317 way, via an in-zone CNAME.
+        let response =
+            process_qtype(&svc, "2.2.0.192.in-addr.arpa", Rtype::PTR).await;
+        assert_eq!(response.header().rcode(), Rcode::NOERROR);
+        assert_eq!(ptrs(&response), ["host2.example.com"]);
+
+        // NODATA: names that exist without a PTR record, including the
+        // empty non-terminal for a network with only deeper entries, are
+        // answered with NOERROR and the SOA.
+        for (qname, qtype) in [
+            ("3.2.0.192.in-addr.arpa", Rtype::PTR),
+            ("1.2.0.192.in-addr.arpa", Rtype::A),
+            ("2.0.192.in-addr.arpa", Rtype::PTR),
+        ] {
+            let response = process_qtype(&svc, qname, qtype).await;
+            assert_eq!(response.header().rcode(), Rcode::NOERROR, "{qname}");
+            assert!(response.header().aa());
+            assert_eq!(response.header_counts().ancount(), 0, "{qname}");
+            assert_eq!(soa_owners(&response), ["0.192.in-addr.arpa"]);
+        }
+
+        // NXDOMAIN: neither the address nor anything below it exists.
+        for qname in ["4.2.0.192.in-addr.arpa", "5.0.192.in-addr.arpa"] {
+            let response = process_qtype(&svc, qname, Rtype::PTR).await;
+            assert_eq!(response.header().rcode(), Rcode::NXDOMAIN, "{qname}");
+            assert!(response.header().aa());
+            assert_eq!(soa_owners(&response), ["0.192.in-addr.arpa"]);
+        }
+    }
+
+    #[tokio::test]
+    async fn ip6_reverse_zone_ptr_answers() {
+        let svc = ZoneTreeService::<MockUpstream>::new(mk_zones_from_str(
+            IP6_REVERSE_ZONE,
+        ));
+        let apex = "8.b.d.0.1.0.0.2.ip6.arpa";
+        let host = format!("1.{}.{apex}", ["0"; 23].join("."));
+
+        let response = process_qtype(&svc, &host, Rtype::PTR).await;
+        assert_eq!(response.header().rcode(), Rcode::NOERROR);
+        assert_eq!(ptrs(&response), ["host1.example.com"]);
+
+        // Every nibble above the host is an empty non-terminal.
+        for len in [1, 12, 23] {
+            let qname = format!("{}.{apex}", ["0"; 24][..len].join("."));
+            let response = process_qtype(&svc, &qname, Rtype::PTR).await;
+            assert_eq!(response.header().rcode(), Rcode::NOERROR, "{qname}");
+            assert_eq!(response.header_counts().ancount(), 0, "{qname}");
+            assert_eq!(soa_owners(&response), [apex]);
+        }
+
+        // Sibling nibbles don't exist.
+        for qname in [
+            format!("2.{}.{apex}", ["0"; 23].join(".")),
+            format!("1.{apex}"),
+        ] {
+            let response = process_qtype(&svc, &qname, Rtype::PTR).await;
+            assert_eq!(response.header().rcode(), Rcode::NXDOMAIN, "{qname}");
+            assert_eq!(soa_owners(&response), [apex]);
+        }
+    }
+
+    #[tokio::test]
+    async fn out_of_zone_is_refused() {
+        let svc = ZoneTreeService::<MockUpstream>::new(mk_zones());
+
+        let response = process(&svc, "www.example.net").await;
+        assert_eq!(response.header().rcode(), Rcode::REFUSED);
+        assert!(!response.header().aa());
+        assert_eq!(response.header_counts().ancount(), 0);
+        assert!(soa_owners(&response).is_empty());
+    }
+
+    #[tokio::test]
+    async fn out_of_zone_nxdomain_can_be_configured() {
+        let svc = ZoneTreeService::<MockUpstream>::new(mk_zones())
+            .with_out_of_zone_response(OutOfZoneResponse::NxDomain);
+
+        let response = process(&svc, "www.example.net").await;
+        assert_eq!(response.header().rcode(), Rcode::NXDOMAIN);
+        assert!(response.header().aa());
+        assert!(soa_owners(&response).is_empty());
+    }
+
+    #[tokio::test]
+    async fn in_zone_cname_chain_is_followed() {
+        let svc = ZoneTreeService::<MockUpstream>::new(mk_zones_from_str(
+            CNAME_ZONE,
+        ));
+
+        let response = process(&svc, "www.example.org").await;
+        assert_eq!(response.header().rcode(), Rcode::NOERROR);
+        assert!(response.header().aa());
+        assert_eq!(
+            cnames(&response),
+            [
+                ("www.example.org".into(), "web.example.org".into()),
+                ("web.example.org".into(), "host.example.org".into()),
+            ]
+        );
+        let answer = response.answer().unwrap().limit_to::<A>();
+        let owners: Vec<_> =
+            answer.map(|rr| rr.unwrap().owner().to_string()).collect();
+        assert_eq!(owners, ["host.example.org"]);
+        assert_eq!(addrs(&response), [[192, 0, 2, 10]]);
+    }
+
+    #[tokio::test]
+    async fn cname_chain_stops_at_zone_boundary() {
+        let svc = ZoneTreeService::<MockUpstream>::new(mk_zones_from_str(
+            CNAME_ZONE,
+        ));
+
+        let response = process(&svc, "out.example.org").await;
+        assert_eq!(response.header().rcode(), Rcode::NOERROR);
+        assert_eq!(
+            cnames(&response),
+            [("out.example.org".into(), "www.example.net".into())]
+        );
+        assert!(addrs(&response).is_empty());
+    }
+
+    #[tokio::test]
+    async fn cname_loop_is_not_followed_forever() {
+        let svc = ZoneTreeService::<MockUpstream>::new(mk_zones_from_str(
+            CNAME_ZONE,
+        ));
+
+        let response = process(&svc, "loop1.example.org").await;
+        assert_eq!(response.header().rcode(), Rcode::NOERROR);
+        assert_eq!(
+            cnames(&response),
+            [
+                ("loop1.example.org".into(), "loop2.example.org".into()),
+                ("loop2.example.org".into(), "loop1.example.org".into()),
+            ]
+        );
+        assert!(addrs(&response).is_empty());
+    }
+
+    #[tokio::test]
+    async fn cname_is_synthesized_below_dname() {
+        let svc = ZoneTreeService::<MockUpstream>::new(mk_zones_from_str(
+            DNAME_ZONE,
+        ));
+
+        let response = process(&svc, "www.old.example.org").await;
+        assert_eq!(response.header().rcode(), Rcode::NOERROR);
+        assert!(response.header().aa());
+        assert_eq!(dnames(&response), ["old.example.org"]);
+        assert_eq!(
+            cnames(&response),
+            [("www.old.example.org".into(), "www.new.example.org".into())]
+        );
+        assert_eq!(addrs(&response), [[192, 0, 2, 20]]);
+
+        // The DNAME owner itself is not redirected.
+        let response =
+            process_qtype(&svc, "old.example.org", Rtype::DNAME).await;
+        assert_eq!(dnames(&response), ["old.example.org"]);
+        assert!(cnames(&response).is_empty());
+
+        // Names below the target that don't exist are still NXDOMAIN.
+        let response = process(&svc, "missing.old.example.org").await;
+        assert_eq!(response.header().rcode(), Rcode::NXDOMAIN);
+        assert_eq!(dnames(&response), ["old.example.org"]);
+        assert_eq!(soa_owners(&response), ["example.org"]);
+    }
+
+    #[tokio::test]
+    async fn out_of_zone_dname_target_is_not_followed() {
+        let svc = ZoneTreeService::<MockUpstream>::new(mk_zones_from_str(
+            DNAME_ZONE,
+        ));
+
+        let response = process(&svc, "a.b.away.example.org").await;
+        assert_eq!(response.header().rcode(), Rcode::NOERROR);
+        assert_eq!(dnames(&response), ["away.example.org"]);
+        assert_eq!(
+            cnames(&response),
+            [("a.b.away.example.org".into(), "a.b.example.net".into())]
+        );
+        assert!(addrs(&response).is_empty());
+    }
+
+    #[tokio::test]
+    async fn overlong_dname_substitution_is_yxdomain() {
+        let svc = ZoneTreeService::<MockUpstream>::new(mk_zones_from_str(
+            DNAME_ZONE,
+        ));
+
+        // The query name is 212 bytes long, the substituted name would be
+        // 278 bytes long.
+        let label = "a".repeat(63);
+        let qname = format!("{label}.{label}.{label}.x.long.example.org");
+        let response = process(&svc, &qname).await;
+        assert_eq!(response.header().rcode(), Rcode::YXDOMAIN);
+        assert_eq!(dnames(&response), ["long.example.org"]);
+        assert!(cnames(&response).is_empty());
+    }
+
+    #[tokio::test]
+    async fn referral_includes_only_in_bailiwick_glue() {
+        let svc = ZoneTreeService::<MockUpstream>::new(mk_zones_from_str(
+            DELEGATION_ZONE,
+        ));
+
+        let response = process(&svc, "www.child.example.org").await;
+        assert_eq!(response.header().rcode(), Rcode::NOERROR);
+        assert!(!response.header().tc());
+        assert!(response.answer().unwrap().next().is_none());
+
+        let mut nsdnames: Vec<_> = response
+            .authority()
+            .unwrap()
+            .limit_to::<Ns<_>>()
+            .map(|rr| rr.unwrap().data().nsdname().to_string())
+            .collect();
+        nsdnames.sort();
+        assert_eq!(
+            nsdnames,
+            [
+                "ns.example.net",
+                "ns1.child.example.org",
+                "sibling.example.org"
+            ]
+        );
+
+        assert_eq!(glue(&response), [("ns1.child.example.org".into(), 54)]);
+    }
+
+    #[tokio::test]
+    async fn glue_that_does_not_fit_sets_tc() {
+        let svc = ZoneTreeService::<MockUpstream>::new(mk_zones_from_str(
+            DELEGATION_ZONE,
+        ));
+
+        let full_len = process(&svc, "www.child.example.org")
+            .await
+            .as_slice()
+            .len();
+        let ctx = UdpTransportContext::new(Some((full_len - 1) as u16));
+        let response =
+            process_with_ctx(&svc, "www.child.example.org", ctx).await;
+        assert!(response.header().tc());
+        assert_eq!(response.header_counts().nscount(), 3);
+        assert!(glue(&response).is_empty());
+    }
+
+    #[tokio::test]
+    async fn additional_section_has_in_zone_target_addresses() {
+        let svc = ZoneTreeService::<MockUpstream>::new(mk_zones_from_str(
+            ADDITIONAL_ZONE,
+        ));
+
+        // Only the in-zone MX target gets its A and AAAA records added.
+        let response = process_qtype(&svc, "example.org", Rtype::MX).await;
+        assert_eq!(response.header().rcode(), Rcode::NOERROR);
+        assert_eq!(response.header_counts().ancount(), 2);
+        assert_eq!(glue(&response), [("mail.example.org".into(), 25)]);
+        assert_eq!(response.header_counts().arcount(), 2);
+
+        let response =
+            process_qtype(&svc, "_sip._udp.example.org", Rtype::SRV).await;
+        assert_eq!(glue(&response), [("sip.example.org".into(), 60)]);
+
+        let response = process_qtype(&svc, "example.org", Rtype::NS).await;
+        assert_eq!(glue(&response), [("ns1.example.org".into(), 53)]);
+
+        // Targets outside the zone or without addresses get nothing.
+        for qname in ["external.example.org", "noaddr.example.org"] {
+            let response = process_qtype(&svc, qname, Rtype::MX).await;
+            assert_eq!(response.header_counts().ancount(), 1);
+            assert_eq!(response.header_counts().arcount(), 0);
+        }
+    }
+
+    #[tokio::test]
+    async fn additional_section_can_be_disabled() {
+        let svc = ZoneTreeService::<MockUpstream>::new(mk_zones_from_str(
+            ADDITIONAL_ZONE,
+        ))
+        .with_additional_section(false);
+
+        let response = process_qtype(&svc, "example.org", Rtype::MX).await;
+        assert_eq!(response.header_counts().ancount(), 2);
+        assert_eq!(response.header_counts().arcount(), 0);
+    }
+
+    #[tokio::test]
+    async fn additional_records_that_do_not_fit_are_left_out() {
+        let svc = ZoneTreeService::<MockUpstream>::new(mk_zones_from_str(
+            ADDITIONAL_ZONE,
+        ));
+
+        let full = process_qtype(&svc, "many.example.org", Rtype::MX).await;
+        assert_eq!(glue(&full).len(), 10);
+
+        let ctx = UdpTransportContext::new(Some(
+            (full.as_slice().len() - 100) as u16,
+        ));
+        let response =
+            process_query(&svc, "many.example.org", Rtype::MX, ctx).await;
+        assert!(!response.header().tc());
+        assert_eq!(response.header_counts().ancount(), 1);
+        assert!(glue(&response).len() < 10);
+    }
+
+    #[tokio::test]
+    async fn forward_zone() {
+        let svc = ZoneTreeService::new(mk_zones()).with_zone_role(
+            &Name::<Vec<u8>>::from_str("example.com").unwrap(),
+            Class::IN,
+            ZoneRole::Forward(MockUpstream),
+        );
+
+        let response = process(&svc, "example.com").await;
+        assert_eq!(response.header().rcode(), Rcode::NOERROR);
+        assert_eq!(response.header().id(), 1234);
+        assert!(!response.header().aa());
+        assert_eq!(addrs(&response), [[198, 51, 100, 1]]);
+    }
+
+    #[tokio::test]
+    async fn split_service_answers_local_zones_and_forwards_others() {
+        let svc =
+            SplitService::new(ZoneTreeService::new(mk_zones()), MockUpstream);
+        let ctx = UdpTransportContext::default;
+
+        // In-zone names are answered authoritatively from the zone.
+        let response =
+            process_query(&svc, "example.com", Rtype::A, ctx()).await;
+        assert_eq!(response.header().rcode(), Rcode::NOERROR);
+        assert!(response.header().aa());
+        assert_eq!(addrs(&response), [[192, 0, 2, 1]]);
+
+        // Including non-existent ones.
+        let response =
+            process_query(&svc, "missing.example.com", Rtype::A, ctx()).await;
+        assert_eq!(response.header().rcode(), Rcode::NXDOMAIN);
+        assert!(response.header().aa());
+
+        // Out-of-zone names are forwarded and not authoritative.
+        let response =
+            process_query(&svc, "www.example.net", Rtype::A, ctx()).await;
+        assert_eq!(response.header().rcode(), Rcode::NOERROR);
+        assert_eq!(response.header().id(), 1234);
+        assert!(!response.header().aa());
+        assert_eq!(addrs(&response), [[198, 51, 100, 1]]);
+    }
+
+    #[tokio::test]
+    async fn zone_version_is_included_for_in_zone_answers() {
+        let svc = ZoneTreeService::<MockUpstream>::new(mk_zones());
+
+        // The label count of example.com excludes the root label.
+        let version = zone_version(&svc, "www.example.com").await.unwrap();
+        assert_eq!(version, (2, Serial(2020080302)));
+        let version = zone_version(&svc, "nonexistent.example.com").await;
+        assert_eq!(version, Some((2, Serial(2020080302))));
+
+        assert_eq!(zone_version(&svc, "www.example.org").await, None);
+
+        let svc = svc.with_zone_version(false);
+        assert_eq!(zone_version(&svc, "www.example.com").await, None);
+    }
+
+    #[tokio::test]
+    async fn svcb_hints_are_synthesized_for_in_zone_target() {
+        let svc = ZoneTreeService::<MockUpstream>::new(mk_svcb_zones())
+            .with_svcb_hint_synthesis(true);
+
+        let response =
+            process_qtype(&svc, "svc.example.org", Rtype::HTTPS).await;
+        assert_eq!(response.header().rcode(), Rcode::NOERROR);
+        assert_eq!(
+            hints(&response),
+            [(
+                "www.example.org".into(),
+                vec!["192.0.2.1".into(), "192.0.2.2".into()],
+                vec!["2001:db8::1".into()]
+            )]
+        );
+
+        // A target of "." stands for the owner name.
+        let response =
+            process_qtype(&svc, "www.example.org", Rtype::HTTPS).await;
+        assert_eq!(
+            hints(&response),
+            [(
+                ".".into(),
+                vec!["192.0.2.1".into(), "192.0.2.2".into()],
+                vec!["2001:db8::1".into()]
+            )]
+        );
+    }
+
+    #[tokio::test]
+    async fn svcb_hints_are_not_synthesized_for_out_of_zone_target() {
+        let svc = ZoneTreeService::<MockUpstream>::new(mk_svcb_zones())
+            .with_svcb_hint_synthesis(true);
+
+        let response =
+            process_qtype(&svc, "ext.example.org", Rtype::HTTPS).await;
+        assert_eq!(
+            hints(&response),
+            [("www.example.net".into(), vec![], vec![])]
+        );
+    }
+
+    #[tokio::test]
+    async fn svcb_hints_are_opt_in() {
+        let svc = ZoneTreeService::<MockUpstream>::new(mk_svcb_zones());
+
+        let response =
+            process_qtype(&svc, "svc.example.org", Rtype::HTTPS).await;
+        assert_eq!(
+            hints(&response),
+            [("www.example.org".into(), vec![], vec![])]
+        );
+    }
+
+    //------------ Helper functions ------------------------------------------
+
+    fn mk_zones() -> Arc<ZoneTree> {
+        let zone_bytes =
+            include_bytes!("../../../test-data/zonefiles/nsd-example.txt");
+        let mut zone_bytes = BufReader::new(&zone_bytes[..]);
+        let reader = inplace::Zonefile::load(&mut zone_bytes).unwrap();
+        let mut zones = ZoneTree::new();
+        zones.insert_zone(Zone::try_from(reader).unwrap()).unwrap();
+        Arc::new(zones)
+    }
+
+    fn mk_zones_from_str(zone: &str) -> Arc<ZoneTree> {
+        let mut zone_bytes = BufReader::new(zone.as_bytes());
+        let reader = inplace::Zonefile::load(&mut zone_bytes).unwrap();
+        let mut zones = ZoneTree::new();
+        zones.insert_zone(Zone::try_from(reader).unwrap()).unwrap();
+        Arc::new(zones)
+    }
+
+    /// Creates a zone with SVCB and HTTPS records in generic form.
+    fn mk_svcb_zones() -> Arc<ZoneTree> {
+        let https = |target: &str| {
+            let rdata = Https::new(
+                1,
+                Name::<Bytes>::from_str(target).unwrap(),
+                SvcParams::<Bytes>::from_values(|_| Ok(())).unwrap(),
+            )
+            .unwrap();
+            let mut octets = Vec::new();
+            rdata.compose_rdata(&mut octets).unwrap();
+            format!("\\# {} {}", octets.len(), base16::encode_string(&octets))
+        };
+        mk_zones_from_str(&format!(
+            "\
+$ORIGIN example.org.
+$TTL 3600
+@ IN SOA ns1 hostmaster 1 3600 900 86400 300
+@ IN NS ns1
+ns1 IN A 192.0.2.53
+svc IN HTTPS {}
+ext IN HTTPS {}
+www IN HTTPS {}
+www IN A 192.0.2.1
+www IN A 192.0.2.2
+www IN AAAA 2001:db8::1
+",
+            https("www.example.org."),
+            https("www.example.net."),
+            https("."),
+        ))
+    }
+
+    async fn process(
+        svc: &ZoneTreeService<MockUpstream>,
+        qname: &str,
+    ) -> Message<Vec<u8>> {
+        process_with_ctx(svc, qname, UdpTransportContext::default()).await
+    }
+
+    async fn process_qtype(
+        svc: &ZoneTreeService<MockUpstream>,
+        qname: &str,
+        qtype: Rtype,
+    ) -> Message<Vec<u8>> {
+        process_query(svc, qname, qtype, UdpTransportContext::default()).await
+    }
+
+    async fn process_with_ctx(
+        svc: &ZoneTreeService<MockUpstream>,
+        qname: &str,
+        ctx: UdpTransportContext,
+    ) -> Message<Vec<u8>> {
+        process_query(svc, qname, Rtype::A, ctx).await
+    }
+
+    async fn process_query(
+        svc: &impl Service<Vec<u8>, Target = Vec<u8>>,
+        qname: &str,
+        qtype: Rtype,
+        ctx: UdpTransportContext,
+    ) -> Message<Vec<u8>> {
+        let mut query = MessageBuilder::new_vec();
+        query.header_mut().set_id(1234);
+        let mut query = query.question();
+        query
+            .push((Name::<Vec<u8>>::from_str(qname).unwrap(), qtype))
+            .unwrap();
+        let request = Request::new(
+            "127.0.0.1:12345".parse().unwrap(),
+            Instant::now(),
+            query.into_message(),
+            ctx.into(),
+            (),
+        );
+
+        let mut stream = svc.call(request).await;
+        let call_result: CallResult<Vec<u8>> =
+            stream.next().await.unwrap().unwrap();
+        let response = call_result.into_inner().0.unwrap().finish();
+        Message::from_octets(response.as_dgram_slice().to_vec()).unwrap()
+    }
+
+    /// Queries with an empty ZONEVERSION option and returns the label count
+    /// and serial of the option in the response, if any.
+    async fn zone_version(
+        svc: &ZoneTreeService<MockUpstream>,
+        qname: &str,
+    ) -> Option<(u8, Serial)> {
+        let mut query = MessageBuilder::new_vec().question();
+        query
+            .push((Name::<Vec<u8>>::from_str(qname).unwrap(), Rtype::A))
+            .unwrap();
+        let mut query = query.additional();
+        query.opt(|opt| opt.client_zone_version()).unwrap();
+        let request = Request::new(
+            "127.0.0.1:12345".parse().unwrap(),
+            Instant::now(),
+            query.into_message(),
+            UdpTransportContext::default().into(),
+            (),
+        );
+
+        let mut stream = svc.call(request).await;
+        let call_result: CallResult<Vec<u8>> =
+            stream.next().await.unwrap().unwrap();
+        let response = call_result.into_inner().0.unwrap().finish();
+        let response =
+            Message::from_octets(response.as_dgram_slice().to_vec()).unwrap();
+        let opt = response.opt()?;
+        let version = opt.opt().zone_version()?;
+        Some((version.label_count()?, version.serial()?))
+    }
+
+    fn addrs(response: &Message<Vec<u8>>) -> Vec<[u8; 4]> {
+        response
+            .answer()
+            .unwrap()
+            .limit_to::<A>()
+            .map(|rr| rr.unwrap().data().addr().octets())
+            .collect()
+    }
+
+    /// Returns the target and address hints of each HTTPS answer record.
+    #[allow(clippy::type_complexity)]
+    fn hints(
+        response: &Message<Vec<u8>>,
+    ) -> Vec<(String, Vec<String>, Vec<String>)> {
+        response
+            .answer()
+            .unwrap()
+            .limit_to::<Https<_, _>>()
+            .map(|rr| {
+                let rr = rr.unwrap();
+                let params = rr.data().params();
+                (
+                    rr.data().target().to_name::<Vec<u8>>().to_string(),
+                    params
+                        .ipv4hint()
+                        .map(|hint| {
+                            hint.iter().map(|a| a.to_string()).collect()
+                        })
+                        .unwrap_or_default(),
+                    params
+                        .ipv6hint()
+                        .map(|hint| {
+                            hint.iter().map(|a| a.to_string()).collect()
+                        })
+                        .unwrap_or_default(),
+                )
+            })
+            .collect()
+    }
+
+    fn soa_owners(response: &Message<Vec<u8>>) -> Vec<String> {
+        response
+            .authority()
+            .unwrap()
+            .limit_to::<Soa<_>>()
+            .map(|rr| rr.unwrap().owner().to_string())
+            .collect()
+    }
+
+    fn ptrs(response: &Message<Vec<u8>>) -> Vec<String> {
+        response
+            .answer()
+            .unwrap()
+            .limit_to::<Ptr<_>>()
+            .map(|rr| rr.unwrap().data().ptrdname().to_string())
+            .collect()
+    }
+
+    fn cnames(response: &Message<Vec<u8>>) -> Vec<(String, String)> {
+        response
+            .answer()
+            .unwrap()
+            .limit_to::<Cname<_>>()
+            .map(|rr| {
+                let rr = rr.unwrap();
+                (rr.owner().to_string(), rr.data().cname().to_string())
+            })
+            .collect()
+    }
+
+    fn dnames(response: &Message<Vec<u8>>) -> Vec<String> {
+        response
+            .answer()
+            .unwrap()
+            .limit_to::<Dname<_>>()
+            .map(|rr| rr.unwrap().owner().to_string())
+            .collect()
+    }
+
+    fn glue(response: &Message<Vec<u8>>) -> Vec<(String, u8)> {
+        response
+            .additional()
+            .unwrap()
+            .limit_to::<A>()
+            .map(|rr| {
+                let rr = rr.unwrap();
+                (rr.owner().to_string(), rr.data().addr().octets()[3])
+            })
+            .collect()
+    }
+
+    /// A zone with MX, SRV and NS records with in-zone and out-of-zone
+    /// targets.
+    const ADDITIONAL_ZONE: &str = "\
+$ORIGIN example.org.
+$TTL 3600
+@ IN SOA ns1 hostmaster 1 3600 900 86400 300
+@ IN NS ns1
+@ IN NS ns.example.net.
+@ IN MX 10 mail
+@ IN MX 20 mail.example.net.
+ns1 IN A 192.0.2.53
+mail IN A 192.0.2.25
+mail IN AAAA 2001:db8::25
+_sip._udp IN SRV 0 0 5060 sip
+sip IN A 192.0.2.60
+external IN MX 10 mail.example.net.
+noaddr IN MX 10 nothing
+many IN MX 10 hosts
+hosts IN A 192.0.2.1
+hosts IN A 192.0.2.2
+hosts IN A 192.0.2.3
+hosts IN A 192.0.2.4
+hosts IN A 192.0.2.5
+hosts IN A 192.0.2.6
+hosts IN A 192.0.2.7
+hosts IN A 192.0.2.8
+hosts IN A 192.0.2.9
+hosts IN A 192.0.2.10
+";
+
+    /// A zone with in-zone, out-of-zone and looping CNAME chains.
+    const CNAME_ZONE: &str = "\
+$ORIGIN example.org.
+$TTL 3600
+@ IN SOA ns1 hostmaster 1 3600 900 86400 300
+@ IN NS ns1
+ns1 IN A 192.0.2.53
+www IN CNAME web
+web IN CNAME host
+host IN A 192.0.2.10
+out IN CNAME www.example.net.
+loop1 IN CNAME loop2
+loop2 IN CNAME loop1
+";
+
+    /// An IPv4 reverse zone with a classless (RFC 2317) sub-delegation
+    /// served from the zone itself.
+    const REVERSE_ZONE: &str = "\
+$ORIGIN 0.192.in-addr.arpa.
+$TTL 3600
+@ IN SOA ns1.example.com. hostmaster.example.com. 1 3600 900 86400 300
+@ IN NS ns1.example.com.
+1.2 IN PTR host1.example.com.
+2.2 IN CNAME 2.0/25.2
+2.0/25.2 IN PTR host2.example.com.
+3.2 IN TXT \"no PTR here\"
+";
+
+    /// An IPv6 reverse zone for 2001:db8::/32 with a single host.
+    const IP6_REVERSE_ZONE: &str = "\
+$ORIGIN 8.b.d.0.1.0.0.2.ip6.arpa.
+$TTL 3600
+@ IN SOA ns1.example.com. hostmaster.example.com. 1 3600 900 86400 300
+@ IN NS ns1.example.com.
+1.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0 IN PTR host1.example.com.
+";
+
+    /// A zone with DNAMEs redirecting to in-zone and out-of-zone targets.
+    const DNAME_ZONE: &str = "\
+$ORIGIN example.org.
+$TTL 3600
+@ IN SOA ns1 hostmaster 1 3600 900 86400 300
+@ IN NS ns1
+ns1 IN A 192.0.2.53
+old IN DNAME new
+www.new IN A 192.0.2.20
+away IN DNAME example.net.
+long IN DNAME a-much-longer-target-to-overflow-the-maximum-name-length.example.org.
+";
+
+    /// A zone delegating to a child zone with in-bailiwick, sibling and
+    /// out-of-bailiwick nameservers.
+    const DELEGATION_ZONE: &str = "\
+$ORIGIN example.org.
+$TTL 3600
+@ IN SOA ns1 hostmaster 1 3600 900 86400 300
+@ IN NS ns1
+ns1 IN A 192.0.2.53
+child IN NS ns1.child
+child IN NS sibling
+child IN NS ns.example.net.
+ns1.child IN A 192.0.2.54
+sibling IN A 192.0.2.55
+";
+
+    //------------ MockUpstream -----------------------------------------------
+
+    /// An upstream that answers every query with the same A record.
+    #[derive(Clone, Debug)]
+    struct MockUpstream;
+
+    impl SendRequest<RequestMessage<Vec<u8>>> for MockUpstream {
+        fn send_request(
+            &self,
+            _request_msg: RequestMessage<Vec<u8>>,
+        ) -> Box<dyn GetResponse + Send + Sync> {
+            Box::new(MockGetResponse)
+        }
+    }
+
+    #[derive(Debug)]
+    struct MockGetResponse;
+
+    impl GetResponse for MockGetResponse {
+        fn get_response(
+            &mut self,
+        ) -> Pin<
+            Box<
+                dyn core::future::Future<
+                        Output = Result<Message<Bytes>, Error>,
+                    > + Send
+                    + Sync
+                    + '_,
+            >,
+        > {
+            let mut builder = MessageBuilder::new_bytes();
+            builder.header_mut().set_qr(true);
+            builder.header_mut().set_aa(true);
+            let mut answer = builder.answer();
+            answer
+                .push((
+                    Name::<Vec<u8>>::from_str("example.com").unwrap(),
+                    Class::IN,
+                    3600,
+                    A::from_octets(198, 51, 100, 1),
+                ))
+                .unwrap();
+            Box::pin(ready(Ok(answer.into_message())))
+        }
+    }
+}
@@ -102,6 +102,19 @@ impl<N> Soa<N> {
         self.minimum
     }
 
+    /// The TTL to use for negative answers given the SOA record's own TTL.
+    ///
+    /// When an SOA record is included in the authority section of an
+    /// NXDOMAIN or NODATA response, its TTL must be the minimum of the TTL
+    /// of the SOA record and the `minimum` field as required by [RFC 2308
+    /// section 3]. Resolvers use this TTL for caching the negative answer.
+    ///
+    /// [RFC 2308 section 3]:
+    ///     https://www.rfc-editor.org/rfc/rfc2308#section-3
+    pub fn negative_ttl(&self, ttl: Ttl) -> Ttl {
+        ttl.min(self.minimum)
+    }
+
     pub(in crate::rdata) fn convert_octets<Target: OctetsFrom<N>>(
         self,
     ) -> Result<Soa<Target>, Target::Error> {
@@ -477,4 +490,31 @@ mod test {
             &rdata,
         );
     }
+
+    #[test]
+    fn negative_ttl() {
+        let soa = |minimum| {
+            Soa::new(
+                Name::root_vec(),
+                Name::root_vec(),
+                Serial(1),
+                Ttl::from_secs(3600),
+                Ttl::from_secs(900),
+                Ttl::from_secs(86400),
+                Ttl::from_secs(minimum),
+            )
+        };
+
+        // The minimum field is smaller than the record's TTL.
+        assert_eq!(
+            soa(300).negative_ttl(Ttl::from_secs(3600)),
+            Ttl::from_secs(300)
+        );
+
+        // The record's TTL is smaller than the minimum field.
+        assert_eq!(
+            soa(3600).negative_ttl(Ttl::from_secs(300)),
+            Ttl::from_secs(300)
+        );
+    }
 }
@@ -419,6 +419,8 @@ impl AsyncDgramSend for ClientServerChannel {
 // Dgram server socket.
 
 impl AsyncDgramSock for ClientServerChannel {
+    type Addr = SocketAddr;
+
     fn poll_send_to(
         &self,
         cx: &mut Context,
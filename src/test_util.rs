@@ -0,0 +1,360 @@
+//! Helpers for testing code that produces DNS messages.
+//!
+//! Comparing a DNS message produced by the code under test against an
+//! expected message byte for byte is brittle: the transaction ID is often
+//! random, names may legitimately differ in case and name compression may
+//! produce different but equivalent encodings. The helpers in this module
+//! instead compare messages section by section, comparing names case
+//! insensitively, and describe any differences in a readable form.
+//!
+//! ```
+//! use domain::base::{MessageBuilder, Name, Rtype};
+//! use domain::test_util::{assert_message_eq, MessageComparison};
+//!
+//! let mut expected = MessageBuilder::new_vec().question();
+//! expected.header_mut().set_id(1);
+//! expected.push((Name::vec_from_str("example.com").unwrap(), Rtype::A))
+//!     .unwrap();
+//!
+//! let mut actual = MessageBuilder::new_vec().question();
+//! actual.header_mut().set_id(2);
+//! actual.push((Name::vec_from_str("EXAMPLE.com").unwrap(), Rtype::A))
+//!     .unwrap();
+//!
+//! let compare = MessageComparison::new().ignore_id(true);
+//! compare.assert_eq(&actual.into_message(), &expected.into_message());
+//! ```
+#![cfg(feature = "test-util")]
+#![cfg_attr(docsrs, doc(cfg(feature = "test-util")))]
+
+use core::fmt::{self, Write};
+
+use std::string::String;
+use std::vec::Vec;
+
+use crate::base::iana::Rtype;
+use crate::base::message::RecordSection;
+use crate::base::{Message, ParsedName, Record, ToName};
+use crate::dep::octseq::Octets;
+use crate::rdata::AllRecordData;
+
+//------------ assert_message_eq ---------------------------------------------
+
+/// Asserts that two messages are equal.
+///
+/// This uses the default [`MessageComparison`], i.e. only the case of names
+/// is ignored. Use a [`MessageComparison`] directly to ignore further
+/// fields.
+///
+/// # Panics
+///
+/// Panics with a description of the differences if the messages differ.
+#[track_caller]
+pub fn assert_message_eq<Actual, Expected>(
+    actual: &Message<Actual>,
+    expected: &Message<Expected>,
+) where
+    Actual: Octets + ?Sized,
+    Expected: Octets + ?Sized,
+{
+    MessageComparison::new().assert_eq(actual, expected)
+}
+
+//------------ MessageComparison ---------------------------------------------
+
+/// A configurable comparison of two DNS messages.
+///
+/// Messages are compared section by section: the header, the question
+/// section and the records of the answer, authority and additional sections
+/// in order. Names, including those in record data, are compared case
+/// insensitively while everything else must match exactly unless ignored
+/// via one of the builder methods.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct MessageComparison {
+    /// Should the message ID be ignored?
+    ignore_id: bool,
+
+    /// Should the TTLs of records be ignored?
+    ignore_ttl: bool,
+}
+
+impl MessageComparison {
+    /// Creates a new comparison that ignores only the case of names.
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Ignore the message ID when comparing headers.
+    #[must_use]
+    pub fn ignore_id(mut self, ignore: bool) -> Self {
+        self.ignore_id = ignore;
+        self
+    }
+
+    /// Ignore the TTL of records.
+    ///
+    /// The TTL field of an OPT record holds the extended RCODE, EDNS
+    /// version and flags and so is compared even if this is set.
+    #[must_use]
+    pub fn ignore_ttl(mut self, ignore: bool) -> Self {
+        self.ignore_ttl = ignore;
+        self
+    }
+
+    /// Asserts that the two messages are equal.
+    ///
+    /// # Panics
+    ///
+    /// Panics with a description of the differences if the messages differ.
+    #[track_caller]
+    pub fn assert_eq<Actual, Expected>(
+        &self,
+        actual: &Message<Actual>,
+        expected: &Message<Expected>,
+    ) where
+        Actual: Octets + ?Sized,
+        Expected: Octets + ?Sized,
+    {
+        if let Some(diff) = self.diff(actual, expected) {
+            panic!("messages differ:\n{diff}");
+        }
+    }
+
+    /// Describes the differences between two messages.
+    ///
+    /// Returns `None` if the messages are equal.
+    pub fn diff<Actual, Expected>(
+        &self,
+        actual: &Message<Actual>,
+        expected: &Message<Expected>,
+    ) -> Option<String>
+    where
+        Actual: Octets + ?Sized,
+        Expected: Octets + ?Sized,
+    {
+        let mut diff = String::new();
+
+        let mut actual_header = actual.header();
+        let mut expected_header = expected.header();
+        if self.ignore_id {
+            actual_header.set_id(0);
+            expected_header.set_id(0);
+        }
+        if actual_header != expected_header {
+            write_diff(
+                &mut diff,
+                "header",
+                &[format!("{actual_header:?}")],
+                &[format!("{expected_header:?}")],
+            );
+        }
+
+        let actual_question = questions(actual);
+        let expected_question = questions(expected);
+        let question_eq = match (&actual_question, &expected_question) {
+            (Ok(a), Ok(e)) => {
+                a.len() == e.len()
+                    && a.iter().zip(e).all(|(a, e)| {
+                        a.qname().name_eq(&e.qname())
+                            && a.qtype() == e.qtype()
+                            && a.qclass() == e.qclass()
+                    })
+            }
+            _ => false,
+        };
+        if !question_eq {
+            write_diff(
+                &mut diff,
+                "question section",
+                &display_all(&actual_question),
+                &display_all(&expected_question),
+            );
+        }
+
+        let sections = [
+            ("answer section", actual.answer(), expected.answer()),
+            (
+                "authority section",
+                actual.authority(),
+                expected.authority(),
+            ),
+            (
+                "additional section",
+                actual.additional(),
+                expected.additional(),
+            ),
+        ];
+        for (label, actual_section, expected_section) in sections {
+            let actual_records = actual_section
+                .map_err(|err| format!("{err:?}"))
+                .and_then(records);
+            let expected_records = expected_section
+                .map_err(|err| format!("{err:?}"))
+                .and_then(records);
+            let section_eq = match (&actual_records, &expected_records) {
+                (Ok(a), Ok(e)) => {
+                    a.len() == e.len()
+                        && a.iter().zip(e).all(|(a, e)| self.record_eq(a, e))
+                }
+                _ => false,
+            };
+            if !section_eq {
+                write_diff(
+                    &mut diff,
+                    label,
+                    &display_all(&actual_records),
+                    &display_all(&expected_records),
+                );
+            }
+        }
+
+        if diff.is_empty() {
+            None
+        } else {
+            Some(diff)
+        }
+    }
+
+    /// Compares two records according to this comparison.
+    fn record_eq<A, E>(
+        &self,
+        actual: &Record<ParsedName<A>, AllRecordData<A, ParsedName<A>>>,
+        expected: &Record<ParsedName<E>, AllRecordData<E, ParsedName<E>>>,
+    ) -> bool
+    where
+        A: AsRef<[u8]>,
+        E: AsRef<[u8]>,
+    {
+        // Record's PartialEq compares the owner case insensitively, the
+        // class and the data, but not the TTL.
+        actual == expected
+            && (actual.ttl() == expected.ttl()
+                || (self.ignore_ttl && actual.rtype() != Rtype::OPT))
+    }
+}
+
+//------------ Helper functions ----------------------------------------------
+
+/// A question parsed from a message.
+type ParsedQuestion<'a, Octs> =
+    crate::base::Question<ParsedName<<Octs as Octets>::Range<'a>>>;
+
+/// A record parsed from a message.
+type ParsedRecord<Octs> =
+    Record<ParsedName<Octs>, AllRecordData<Octs, ParsedName<Octs>>>;
+
+/// Parses all questions of a message.
+fn questions<Octs: Octets + ?Sized>(
+    msg: &Message<Octs>,
+) -> Result<Vec<ParsedQuestion<'_, Octs>>, String> {
+    msg.question()
+        .collect::<Result<_, _>>()
+        .map_err(|err| format!("{err:?}"))
+}
+
+/// Parses all records of a section.
+fn records<'a, Octs: Octets + ?Sized>(
+    section: RecordSection<'a, Octs>,
+) -> Result<Vec<ParsedRecord<Octs::Range<'a>>>, String> {
+    section
+        .limit_to::<AllRecordData<_, _>>()
+        .collect::<Result<_, _>>()
+        .map_err(|err| format!("{err:?}"))
+}
+
+/// Formats each item, or the error if parsing failed.
+fn display_all<T: fmt::Display>(
+    items: &Result<Vec<T>, String>,
+) -> Vec<String> {
+    match items {
+        Ok(items) => items.iter().map(|item| format!("{item}")).collect(),
+        Err(err) => vec![format!("<parse error: {err}>")],
+    }
+}
+
+/// Writes the expected and actual content of a differing part.
+fn write_diff(
+    diff: &mut String,
+    label: &str,
+    actual: &[String],
+    expected: &[String],
+) {
+    let _ = writeln!(diff, "{label} differs:");
+    for (prefix, lines) in [("  expected:", expected), ("  actual:", actual)]
+    {
+        let _ = writeln!(diff, "{prefix}");
+        if lines.is_empty() {
+            let _ = writeln!(diff, "    <empty>");
+        }
+        for line in lines {
+            let _ = writeln!(diff, "    {line}");
+        }
+    }
+}
+
+//============ Tests =========================================================
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::base::iana::Rcode;
+    use crate::base::{MessageBuilder, Name};
+    use crate::rdata::{Cname, A};
+
+    fn mk_message(id: u16, qname: &str, ttl: u32) -> Message<Vec<u8>> {
+        let name = Name::<Vec<u8>>::vec_from_str(qname).unwrap();
+        let mut msg = MessageBuilder::new_vec().question();
+        msg.header_mut().set_id(id);
+        msg.header_mut().set_qr(true);
+        msg.push((&name, Rtype::A)).unwrap();
+        let mut msg = msg.answer();
+        msg.push((&name, ttl, Cname::new(&name))).unwrap();
+        msg.push((&name, ttl, A::from_octets(192, 0, 2, 1)))
+            .unwrap();
+        msg.into_message()
+    }
+
+    #[test]
+    fn equal_ignoring_case() {
+        assert_message_eq(
+            &mk_message(1, "www.EXAMPLE.com", 3600),
+            &mk_message(1, "WWW.example.COM", 3600),
+        );
+    }
+
+    #[test]
+    fn id_and_ttl_can_be_ignored() {
+        let actual = mk_message(1, "example.com", 60);
+        let expected = mk_message(2, "example.com", 3600);
+
+        let diff = MessageComparison::new().diff(&actual, &expected).unwrap();
+        assert!(diff.contains("header differs"));
+        assert!(diff.contains("answer section differs"));
+        assert!(!diff.contains("question section differs"));
+
+        MessageComparison::new()
+            .ignore_id(true)
+            .ignore_ttl(true)
+            .assert_eq(&actual, &expected);
+    }
+
+    #[test]
+    fn diff_shows_records() {
+        let actual = mk_message(1, "example.com", 3600);
+        let expected = mk_message(1, "example.net", 3600);
+        let diff = MessageComparison::new().diff(&actual, &expected).unwrap();
+        assert!(diff.contains("question section differs"));
+        assert!(diff.contains("example.net. 3600 IN A 192.0.2.1"));
+        assert!(diff.contains("example.com. 3600 IN A 192.0.2.1"));
+    }
+
+    #[test]
+    #[should_panic(expected = "header differs")]
+    fn rcode_mismatch_panics() {
+        let actual = mk_message(1, "example.com", 3600);
+        let mut expected = mk_message(1, "example.com", 3600);
+        expected.header_mut().set_rcode(Rcode::NXDOMAIN);
+        assert_message_eq(&actual, &expected);
+    }
+}
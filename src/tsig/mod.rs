@@ -1189,6 +1189,7 @@ impl<K: AsRef<Key>> SigningContext<K> {
             return Err(ServerError::unsigned(match err {
                 ValidationError::BadTrunc => TsigRcode::BADTRUNC,
                 ValidationError::BadKey => TsigRcode::BADKEY,
+                ValidationError::BadSig => TsigRcode::BADSIG,
                 _ => TsigRcode::FORMERR,
             }));
         }
@@ -1820,6 +1821,15 @@ impl<K: AsRef<Key>> ServerError<K> {
         Octs: Octets + ?Sized,
         Target: Composer,
     {
+        // RFC 8945, section 5.2: If the TSIG record is misplaced or can't
+        // be interpreted, the response is a plain FORMERR.
+        if let ServerErrorInner::Unsigned { .. } = self.0 {
+            if MessageTsig::from_message(msg).is_err() {
+                let builder = builder.start_answer(msg, Rcode::FORMERR)?;
+                return Ok(builder.additional());
+            }
+        }
+
         let builder = builder.start_answer(msg, Rcode::NOTAUTH)?;
         let mut builder = builder.additional();
         match self.0 {
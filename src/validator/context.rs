@@ -20,7 +20,7 @@ use super::utilities::{
     get_soa_state, make_ede, map_maybe_secure, rebuild_msg,
     star_closest_encloser, ttl_for_sig,
 };
-use crate::base::iana::{ExtendedErrorCode, OptRcode};
+use crate::base::iana::{DigestAlg, ExtendedErrorCode, OptRcode, SecAlg};
 use crate::base::message::ShortMessage;
 use crate::base::name::{Chain, Label};
 use crate::base::opt::ExtendedError;
@@ -31,19 +31,21 @@ use crate::base::{
 };
 use crate::dep::octseq::{Octets, OctetsFrom, OctetsInto};
 use crate::net::client::request::{
-    ComposeRequest, RequestMessage, SendRequest,
+    self, ComposeRequest, RequestMessage, SendRequest,
 };
 use crate::rdata::{AllRecordData, Dnskey, Ds, ZoneRecordData};
 use crate::utils::config::DefMinMax;
 use crate::validate::DnskeyExt;
 use crate::validate::{supported_algorithm, supported_digest};
 use crate::zonefile::inplace;
+use arc_swap::ArcSwap;
 use bytes::Bytes;
 use moka::future::Cache;
 use std::cmp::min;
 use std::collections::VecDeque;
 use std::fmt::Debug;
 use std::string::ToString;
+use std::sync::atomic::{AtomicU16, Ordering};
 use std::sync::Arc;
 use std::time::{Duration, Instant};
 use std::vec::Vec;
@@ -116,6 +118,22 @@ const NSEC3_ITER_BOGUS: DefMinMax<u16> = DefMinMax::new(500, 0, 500);
 /// the default as used in unbound is 11.
 const MAX_CNAME_DNAME: DefMinMax<u8> = DefMinMax::new(11, 0, 100);
 
+/// Maximum number of upstream queries for DS and DNSKEY records during the
+/// validation of a single message.
+///
+/// The minimum is 1, the maximum is 1000, the default is 64. Walking a
+/// chain of trust takes at most two queries per label below the trust
+/// anchor, so the default allows the maximum depth to be walked for a
+/// single signer.
+const MAX_UPSTREAM_QUERIES: DefMinMax<u16> = DefMinMax::new(64, 1, 1000);
+
+/// Maximum number of delegations that are walked to build a chain of
+/// trust.
+///
+/// The minimum is 1, the maximum is 127, because that is the maximum
+/// number of labels in a name, the default is 30.
+const MAX_VALIDATION_DEPTH: DefMinMax<u8> = DefMinMax::new(30, 1, 127);
+
 //------------ Config ---------------------------------------------------------
 
 /// Configuration of a validator.
@@ -153,6 +171,14 @@ pub struct Config {
     /// Maximum number of CNAME and DNAME records that are followed
     /// during validation.
     max_cname_dname: u8,
+
+    /// Maximum number of upstream queries during the validation of a
+    /// single message.
+    max_upstream_queries: u16,
+
+    /// Maximum number of labels below a trust anchor that are walked to
+    /// build a chain of trust.
+    max_validation_depth: u8,
 }
 
 impl Config {
@@ -286,6 +312,31 @@ impl Config {
     pub fn set_max_cname_dname(&mut self, value: u8) {
         self.max_cname_dname = MAX_CNAME_DNAME.limit(value)
     }
+
+    /// Set the maximum number of upstream queries for DS and DNSKEY
+    /// records during the validation of a single message.
+    ///
+    /// Once the limit is reached, the parts of the message that still need
+    /// a chain of trust are considered bogus.
+    ///
+    /// The value has to be at least one, at most one thousand and the
+    /// default is sixty-four.
+    pub fn set_max_upstream_queries(&mut self, value: u16) {
+        self.max_upstream_queries = MAX_UPSTREAM_QUERIES.limit(value)
+    }
+
+    /// Set the maximum number of delegations that are walked to build a
+    /// chain of trust.
+    ///
+    /// Only zone cuts count towards the limit, not the labels of names
+    /// within a zone. Names that need more delegations to be walked are
+    /// considered bogus.
+    ///
+    /// The value has to be at least one, at most 127 and the default is
+    /// thirty.
+    pub fn set_max_validation_depth(&mut self, value: u8) {
+        self.max_validation_depth = MAX_VALIDATION_DEPTH.limit(value)
+    }
 }
 
 impl Default for Config {
@@ -301,6 +352,8 @@ impl Default for Config {
             nsec3_iter_insecure: NSEC3_ITER_INSECURE.default(),
             nsec3_iter_bogus: NSEC3_ITER_BOGUS.default(),
             max_cname_dname: MAX_CNAME_DNAME.default(),
+            max_upstream_queries: MAX_UPSTREAM_QUERIES.default(),
+            max_validation_depth: MAX_VALIDATION_DEPTH.default(),
         }
     }
 }
@@ -308,9 +361,15 @@ impl Default for Config {
 //------------ ValidationContext ---------------------------------------------
 
 /// A DNSSEC validation context.
+///
+/// The trust anchors of a validation context can be replaced while it is
+/// in use via [`reload_trust_anchors()`]. See there for the semantics of a
+/// reload.
+///
+/// [`reload_trust_anchors()`]: Self::reload_trust_anchors
 pub struct ValidationContext<Upstream> {
-    /// DNSSEC trust anchors.
-    ta: TrustAnchors,
+    /// DNSSEC trust anchors and the nodes derived from them.
+    anchors: ArcSwap<AnchorState>,
 
     /// Upstream client transport.
     upstream: Upstream,
@@ -318,9 +377,6 @@ pub struct ValidationContext<Upstream> {
     /// Configuration varables.
     config: Config,
 
-    /// Cache of DNS delegations (with DNSSEC key material if required).
-    node_cache: Cache<Name<Bytes>, Arc<Node>>,
-
     /// Cache of NSEC3 hashes.
     nsec3_cache: Nsec3Cache,
 
@@ -344,9 +400,11 @@ impl<Upstream> ValidationContext<Upstream> {
         config: Config,
     ) -> Self {
         Self {
-            ta,
+            anchors: ArcSwap::from_pointee(AnchorState::new(
+                ta,
+                config.max_node_cache,
+            )),
             upstream,
-            node_cache: Cache::new(config.max_node_cache),
             nsec3_cache: Nsec3Cache::new(config.max_nsec3_cache),
             isig_cache: SigCache::new(config.max_isig_cache),
             usig_cache: SigCache::new(config.max_usig_cache),
@@ -354,6 +412,24 @@ impl<Upstream> ValidationContext<Upstream> {
         }
     }
 
+    /// Replace the trust anchors of this validation context.
+    ///
+    /// The new trust anchors are used by all validations started after
+    /// this method returns. Validations that are in progress continue to
+    /// use the trust anchors that were current when they started, so that
+    /// a single message is never validated against a mix of old and new
+    /// trust anchors.
+    ///
+    /// As the cached delegations are derived from the trust anchors, the
+    /// delegation cache is replaced by an empty one as well. The NSEC3 and
+    /// signature caches are kept.
+    pub fn reload_trust_anchors(&self, ta: TrustAnchors) {
+        self.anchors.store(Arc::new(AnchorState::new(
+            ta,
+            self.config.max_node_cache,
+        )));
+    }
+
     /// Validate a DNS reply message. An Error value will be returned if the
     /// message cannot be parsed or if there is any other message-related
     /// error.
@@ -392,10 +468,20 @@ impl<Upstream> ValidationContext<Upstream> {
 
         let mut fix_reply = false;
 
+        // Use the same trust anchors for all groups, even if they are
+        // reloaded in the meantime.
+        let anchors = self.anchors.load_full();
+
+        // The upstream queries of all groups count towards the same limit.
+        let budget = QueryBudget::new(self.config.max_upstream_queries);
+
         // Validate each group. We cannot use iter_mut because it requires a
         // reference with a lifetime that is too long.
         // Group can handle this by hiding the state behind a Mutex.
-        let mut answers = match self.validate_groups(&mut answers).await {
+        let mut answers = match self
+            .validate_groups(&anchors, &budget, &mut answers)
+            .await
+        {
             VGResult::Groups(vgs, needs_fix) => {
                 fix_reply |= needs_fix;
                 vgs
@@ -405,7 +491,7 @@ impl<Upstream> ValidationContext<Upstream> {
         };
 
         let mut authorities = match self
-            .validate_groups(&mut authorities)
+            .validate_groups(&anchors, &budget, &mut authorities)
             .await
         {
             VGResult::Groups(vgs, needs_fix) => {
@@ -734,6 +820,8 @@ impl<Upstream> ValidationContext<Upstream> {
     /// Get the apprioprate node for validating `name`.
     pub(crate) async fn get_node<Octs>(
         &self,
+        anchors: &AnchorState,
+        budget: &QueryBudget,
         name: &Name<Bytes>,
     ) -> Result<Arc<Node>, Error>
     where
@@ -742,12 +830,12 @@ impl<Upstream> ValidationContext<Upstream> {
         Upstream: SendRequest<RequestMessage<Octs>>,
     {
         // Check the cache first
-        if let Some(node) = self.cache_lookup(name).await {
+        if let Some(node) = anchors.cache_lookup(name).await {
             return Ok(node);
         }
 
         // Find a trust anchor.
-        let Some(ta) = self.ta.find(name) else {
+        let Some(ta) = anchors.ta.find(name) else {
             // Try to get an indeterminate node for the root
             let node = Node::indeterminate(
                 Name::root(),
@@ -758,35 +846,43 @@ impl<Upstream> ValidationContext<Upstream> {
                 self.config.max_node_validity,
             );
             let node = Arc::new(node);
-            self.node_cache.insert(Name::root(), node.clone()).await;
+            anchors.node_cache.insert(Name::root(), node.clone()).await;
             return Ok(node);
         };
 
         let ta_owner = ta.owner();
+
         if ta_owner.name_eq(name) {
             // The trust anchor is the same node we are looking for. Create
             // a node for the trust anchor.
             let node = Node::trust_anchor(
                 ta,
                 &self.upstream,
+                budget,
                 &self.isig_cache,
                 &self.config,
             )
             .await?;
             let node = Arc::new(node);
-            self.node_cache.insert(name.clone(), node.clone()).await;
+            anchors
+                .cache_insert(name.clone(), node.clone(), budget)
+                .await;
             return Ok(node);
         }
 
         // Walk from the parent of name back to trust anchor.
         // Keep a list of names we need to walk in the other direction.
-        let (mut node, mut names) =
-            self.find_closest_node(name, ta, ta_owner).await?;
+        let (mut node, mut names) = self
+            .find_closest_node(anchors, budget, name, ta, ta_owner)
+            .await?;
 
         // Assume that node is not an intermediate node. We have to make sure
         // in find_closest_node.
         let mut signer_node = node.clone();
 
+        // The number of zone cuts walked so far.
+        let mut depth = 0;
+
         // Walk from the closest node to name.
         loop {
             match node.validation_state() {
@@ -813,12 +909,36 @@ impl<Upstream> ValidationContext<Upstream> {
             // If this node is an intermediate node then get the node for
             // signer name.
             node = Arc::new(
-                self.create_child_node(child_name.clone(), &signer_node)
-                    .await?,
+                self.create_child_node(
+                    child_name.clone(),
+                    &signer_node,
+                    budget,
+                )
+                .await?,
             );
-            self.node_cache.insert(child_name, node.clone()).await;
+            anchors.cache_insert(child_name, node.clone(), budget).await;
             if !node.intermediate() {
                 signer_node = node.clone();
+
+                // Refuse to walk overly long chains. The node is not cached
+                // as the result depends on the configuration rather than on
+                // the data.
+                depth += 1;
+                if depth > self.config.max_validation_depth
+                    && node.validation_state() == ValidationState::Secure
+                {
+                    let ede = make_ede(
+                        ExtendedErrorCode::DNSSEC_BOGUS,
+                        "too many delegations below trust anchor",
+                    );
+                    return Ok(Arc::new(Node::new_delegation(
+                        name.clone(),
+                        ValidationState::Bogus,
+                        Vec::new(),
+                        ede,
+                        self.config.max_bogus_validity,
+                    )));
+                }
             }
 
             if names.is_empty() {
@@ -833,6 +953,8 @@ impl<Upstream> ValidationContext<Upstream> {
     /// in the cache.
     async fn find_closest_node<Octs>(
         &self,
+        anchors: &AnchorState,
+        budget: &QueryBudget,
         name: &Name<Bytes>,
         ta: &TrustAnchor,
         ta_owner: Name<Bytes>,
@@ -853,17 +975,18 @@ impl<Upstream> ValidationContext<Upstream> {
                 let node = Node::trust_anchor(
                     ta,
                     &self.upstream,
+                    budget,
                     &self.isig_cache,
                     &self.config,
                 )
                 .await?;
                 let node = Arc::new(node);
-                self.node_cache.insert(curr, node.clone()).await;
+                anchors.cache_insert(curr, node.clone(), budget).await;
                 return Ok((node, names));
             }
 
             // Try to find the node in the cache.
-            if let Some(node) = self.cache_lookup(&curr).await {
+            if let Some(node) = anchors.cache_lookup(&curr).await {
                 return Ok((node, names));
             }
 
@@ -883,6 +1006,7 @@ impl<Upstream> ValidationContext<Upstream> {
         &self,
         name: Name<Bytes>,
         node: &Node,
+        budget: &QueryBudget,
     ) -> Result<Node, Error>
     where
         Octs:
@@ -890,6 +1014,9 @@ impl<Upstream> ValidationContext<Upstream> {
         Upstream: SendRequest<RequestMessage<Octs>>,
     {
         // Start with a DS lookup.
+        if !budget.try_query() {
+            return Ok(budget.exceeded_node(name, &self.config));
+        }
         let (mut answers, mut authorities, ede) =
             request_as_groups(&self.upstream, &name, Rtype::DS).await?;
 
@@ -1120,25 +1247,18 @@ impl<Upstream> ValidationContext<Upstream> {
         // authenticated DS records using unknown or unsupported message
         // digest algorithms.
         let mut tmp_group = ds_group.clone();
-        let valid_algs = tmp_group
-            .rr_iter()
-            .map(|r| {
+        let unsupported =
+            unsupported_ds_algorithms(tmp_group.rr_iter().map(|r| {
                 if let AllRecordData::Ds(ds) = r.data() {
                     (ds.algorithm(), ds.digest_type())
                 } else {
                     panic!("DS record expected");
                 }
-            })
-            .any(|(alg, dig)| {
-                supported_algorithm(&alg) && supported_digest(&dig)
-            });
+            }));
 
-        if !valid_algs {
+        if let Some(code) = unsupported {
             // Delegation is insecure
-            let ede = make_ede(
-                ExtendedErrorCode::OTHER,
-                "No supported algorithm in DS RRset",
-            );
+            let ede = make_ede(code, "No supported algorithm in DS RRset");
             return Ok(Node::new_delegation(
                 name,
                 ValidationState::Insecure,
@@ -1149,6 +1269,9 @@ impl<Upstream> ValidationContext<Upstream> {
         }
 
         // Get the DNSKEY RRset.
+        if !budget.try_query() {
+            return Ok(budget.exceeded_node(name, &self.config));
+        }
         let (mut answers, _, ede) =
             request_as_groups(&self.upstream, &name, Rtype::DNSKEY).await?;
 
@@ -1320,15 +1443,6 @@ impl<Upstream> ValidationContext<Upstream> {
         ))
     }
 
-    /// Try to look up a name in the cache.
-    async fn cache_lookup(&self, name: &Name<Bytes>) -> Option<Arc<Node>> {
-        let ce = self.node_cache.get(name).await?;
-        if ce.expired() {
-            return None;
-        }
-        Some(ce)
-    }
-
     /// Return a reference to the NSEC3 cache.
     pub(crate) fn nsec3_cache(&self) -> &Nsec3Cache {
         &self.nsec3_cache
@@ -1341,7 +1455,12 @@ impl<Upstream> ValidationContext<Upstream> {
 
     /// Validate a GroupSet and return a list of ValidatedGroup objects
     /// or Bougs, or an error.
-    async fn validate_groups<Octs>(&self, groups: &mut GroupSet) -> VGResult
+    async fn validate_groups<Octs>(
+        &self,
+        anchors: &AnchorState,
+        budget: &QueryBudget,
+        groups: &mut GroupSet,
+    ) -> VGResult
     where
         Octs:
             AsRef<[u8]> + Debug + Octets + OctetsFrom<Vec<u8>> + Send + Sync,
@@ -1350,7 +1469,10 @@ impl<Upstream> ValidationContext<Upstream> {
         let mut fix_reply = false;
         let mut vgs = Vec::new();
         for g in groups.iter() {
-            let vg = match g.validated(self, &self.config).await {
+            let vg = match g
+                .validated(self, anchors, budget, &self.config)
+                .await
+            {
                 Ok(vg) => vg,
                 Err(err) => return VGResult::Err(err),
             };
@@ -1366,6 +1488,107 @@ impl<Upstream> ValidationContext<Upstream> {
     }
 }
 
+//------------ AnchorState ---------------------------------------------------
+
+/// The trust anchors of a validation context and the nodes derived from them.
+///
+/// These are kept together so that reloading the trust anchors also
+/// replaces the nodes that were derived from the old trust anchors.
+pub(crate) struct AnchorState {
+    /// DNSSEC trust anchors.
+    ta: TrustAnchors,
+
+    /// Cache of DNS delegations (with DNSSEC key material if required).
+    node_cache: Cache<Name<Bytes>, Arc<Node>>,
+}
+
+impl AnchorState {
+    /// Create a new state with an empty node cache.
+    fn new(ta: TrustAnchors, max_node_cache: u64) -> Self {
+        Self {
+            ta,
+            node_cache: Cache::new(max_node_cache),
+        }
+    }
+
+    /// Try to look up a name in the cache.
+    async fn cache_lookup(&self, name: &Name<Bytes>) -> Option<Arc<Node>> {
+        let ce = self.node_cache.get(name).await?;
+        if ce.expired() {
+            return None;
+        }
+        Some(ce)
+    }
+
+    /// Add a node to the cache.
+    ///
+    /// Once the query budget of a validation has been exceeded, the nodes
+    /// it creates are not cached. They may be the result of the limit
+    /// rather than of the data.
+    async fn cache_insert(
+        &self,
+        name: Name<Bytes>,
+        node: Arc<Node>,
+        budget: &QueryBudget,
+    ) {
+        if !budget.exceeded() {
+            self.node_cache.insert(name, node).await;
+        }
+    }
+}
+
+//------------ QueryBudget ---------------------------------------------------
+
+/// The number of upstream queries a single validation may still make.
+#[derive(Debug)]
+pub(crate) struct QueryBudget {
+    /// The maximum number of queries.
+    max: u16,
+
+    /// The number of queries requested so far, including refused ones.
+    used: AtomicU16,
+}
+
+impl QueryBudget {
+    /// Create a new budget allowing `max` queries.
+    fn new(max: u16) -> Self {
+        Self {
+            max,
+            used: AtomicU16::new(0),
+        }
+    }
+
+    /// Account for an upstream query.
+    ///
+    /// Returns whether the query can be made.
+    fn try_query(&self) -> bool {
+        self.used.fetch_add(1, Ordering::Relaxed) < self.max
+    }
+
+    /// Return whether a query has been refused.
+    fn exceeded(&self) -> bool {
+        self.used.load(Ordering::Relaxed) > self.max
+    }
+
+    /// Create the bogus node for a name whose node could not be created
+    /// because the budget was exceeded.
+    fn exceeded_node(&self, name: Name<Bytes>, config: &Config) -> Node {
+        let ede = make_ede(
+            ExtendedErrorCode::DNSSEC_BOGUS,
+            "too many upstream queries for validation",
+        );
+        Node::new_delegation(
+            name,
+            ValidationState::Bogus,
+            Vec::new(),
+            ede,
+            config.max_bogus_validity,
+        )
+    }
+}
+
+//------------ VGResult ------------------------------------------------------
+
 /// Enum that provides the return value of validate_groups.
 enum VGResult {
     /// A list of validated groups and boolean if any of the group needs
@@ -1464,6 +1687,7 @@ impl Node {
     async fn trust_anchor<Octs, Upstream>(
         ta: &TrustAnchor,
         upstream: &Upstream,
+        budget: &QueryBudget,
         sig_cache: &SigCache,
         config: &Config,
     ) -> Result<Self, Error>
@@ -1476,6 +1700,9 @@ impl Node {
         let ta_owner = ta.owner();
 
         // We expect a positive reply so the authority section can be ignored.
+        if !budget.try_query() {
+            return Ok(budget.exceeded_node(ta_owner, config));
+        }
         let (mut answers, _, _ede) =
             request_as_groups(upstream, &ta_owner, Rtype::DNSKEY).await?;
         // Get the DNSKEY group. We expect exactly one.
@@ -1749,6 +1976,28 @@ fn has_ds(
     find_key_for_ds(ds, dnskeys)
 }
 
+/// Check whether any record of a DS RRset can be used.
+///
+/// Takes the algorithm and digest type of the records. Returns `None` if
+/// at least one record uses both a supported algorithm and a supported
+/// digest type. Otherwise returns the extended error code that describes
+/// why none of them can be used.
+fn unsupported_ds_algorithms(
+    algs: impl Iterator<Item = (SecAlg, DigestAlg)>,
+) -> Option<ExtendedErrorCode> {
+    let mut code = ExtendedErrorCode::UNSUPPORTED_DNSKEY_ALGORITHM;
+    for (alg, digest) in algs {
+        if !supported_algorithm(&alg) {
+            continue;
+        }
+        if supported_digest(&digest) {
+            return None;
+        }
+        code = ExtendedErrorCode::UNSUPPORTED_DS_DIGEST_TYPE;
+    }
+    Some(code)
+}
+
 /// Find a match DNSKEY record for a given DS record. Return the record if it
 /// is found.
 #[allow(clippy::type_complexity)]
@@ -2225,7 +2474,12 @@ where
     req.set_dnssec_ok(true);
 
     let mut request = upstream.send_request(req);
-    let reply = request.get_response().await;
+    // Failing to get a reply says nothing about the data, don't treat it
+    // as bogus but let the caller try again later.
+    let reply = request
+        .get_response()
+        .await
+        .map_err(|err| Error::Upstream(Arc::new(err)))?;
 
     // If there is anything wrong with the reply then pretend that we
     // didn't get anything. Try to keep an ede around with the reason.
@@ -2236,49 +2490,40 @@ where
         ExtendedErrorCode::DNSSEC_BOGUS,
         "request for DS or DNSKEY failed, parse error",
     );
-    if let Ok(reply) = reply {
-        // Group the answer and authority sections.
-        // Rewrite using an iterator.
-        if let Ok(answer) = reply.answer() {
-            for rr in answer {
-                if let Some(e) = rr.map_or_else(
-                    |_e| parse_error_ede.clone(),
-                    |rr| {
-                        answers.add(rr).map_or_else(
-                            |_e| parse_error_ede.clone(),
-                            |_| None,
-                        )
-                    },
-                ) {
-                    ede = Some(e);
-                }
+    // Group the answer and authority sections.
+    // Rewrite using an iterator.
+    if let Ok(answer) = reply.answer() {
+        for rr in answer {
+            if let Some(e) = rr.map_or_else(
+                |_e| parse_error_ede.clone(),
+                |rr| {
+                    answers
+                        .add(rr)
+                        .map_or_else(|_e| parse_error_ede.clone(), |_| None)
+                },
+            ) {
+                ede = Some(e);
             }
-        } else {
-            ede.clone_from(&parse_error_ede);
-        }
-
-        if let Ok(authority) = reply.authority() {
-            for rr in authority {
-                if let Some(e) = rr.map_or_else(
-                    |_e| parse_error_ede.clone(),
-                    |rr| {
-                        authorities.add(rr).map_or_else(
-                            |_e| parse_error_ede.clone(),
-                            |_| None,
-                        )
-                    },
-                ) {
-                    ede = Some(e);
-                }
+        }
+    } else {
+        ede.clone_from(&parse_error_ede);
+    }
+
+    if let Ok(authority) = reply.authority() {
+        for rr in authority {
+            if let Some(e) = rr.map_or_else(
+                |_e| parse_error_ede.clone(),
+                |rr| {
+                    authorities
+                        .add(rr)
+                        .map_or_else(|_e| parse_error_ede.clone(), |_| None)
+                },
+            ) {
+                ede = Some(e);
             }
-        } else {
-            ede = parse_error_ede;
         }
     } else {
-        ede = make_ede(
-            ExtendedErrorCode::DNSSEC_BOGUS,
-            "request for DS or DNSKEY failed",
-        );
+        ede = parse_error_ede;
     }
     Ok((answers, authorities, ede))
 }
@@ -2315,6 +2560,12 @@ pub enum Error {
 
     /// DNS message is too short.
     ShortMessage,
+
+    /// A request to the upstream failed.
+    ///
+    /// The failure is likely transient and validation can be retried
+    /// later.
+    Upstream(Arc<request::Error>),
 }
 
 impl From<inplace::Error> for Error {
@@ -2358,6 +2609,7 @@ impl fmt::Display for Error {
             Error::PushNameError => write!(f, "PushNameError"),
             Error::ReadError(_) => write!(f, "FormError"),
             Error::ShortMessage => write!(f, "ShortMEssage"),
+            Error::Upstream(err) => write!(f, "Upstream: {err}"),
         }
     }
 }
@@ -2373,6 +2625,295 @@ impl error::Error for Error {
             Error::PushNameError => None,
             Error::ReadError(err) => Some(err),
             Error::ShortMessage => None,
+            Error::Upstream(err) => Some(err.as_ref()),
+        }
+    }
+}
+
+//============ Tests =========================================================
+
+#[cfg(test)]
+#[cfg(feature = "sign")]
+mod tests {
+    use core::pin::Pin;
+    use core::str::FromStr;
+    use core::time::Duration;
+
+    use std::boxed::Box;
+    use std::collections::HashSet;
+    use std::rc::Rc;
+    use std::vec::Vec;
+
+    use bytes::Bytes;
+    use futures_util::future::ready;
+    use mock_instant::thread_local::MockClock;
+    use ring::rand::SystemRandom;
+
+    use crate::base::iana::{Class, ExtendedErrorCode, Rcode};
+    use crate::base::{
+        Message, MessageBuilder, Name, Record, Rtype, ToName, Ttl,
+    };
+    use crate::net::client::request::{
+        ComposeRequest, Error, GetResponse, RequestMessage, SendRequest,
+    };
+    use crate::rdata::dnssec::{RtypeBitmap, Timestamp};
+    use crate::rdata::{Nsec, Ptr, Rrsig, A};
+    use crate::sign::key::SigningKey;
+    use crate::sign::records::{FamilyName, SortedRecords};
+    use crate::sign::ring::Key;
+    use crate::validator::anchor::TrustAnchors;
+
+    use super::{Config, ValidationContext, ValidationState};
+
+    #[tokio::test]
+    async fn deep_chain_is_cut_off() {
+        let upstream = SignedUpstream::new(&[]);
+        let mut config = Config::new();
+        config.set_max_validation_depth(2);
+        let vc = ValidationContext::with_config(
+            upstream.trust_anchors(),
+            upstream.clone(),
+            config,
+        );
+
+        // Four zone cuts below the root.
+        let mut msg = upstream.mk_reply("a.b.c.example.");
+        let (state, ede) = vc.validate_msg(&mut msg).await.unwrap();
+        assert_eq!(state, ValidationState::Bogus);
+        let ede = ede.unwrap();
+        assert_eq!(ede.code(), ExtendedErrorCode::DNSSEC_BOGUS);
+        assert_eq!(
+            ede.text_slice(),
+            Some(&b"too many delegations below trust anchor"[..])
+        );
+
+        // Within the limit the chain is walked.
+        let mut msg = upstream.mk_reply("b.example.");
+        let (state, ede) = vc.validate_msg(&mut msg).await.unwrap();
+        assert_eq!(state, ValidationState::Secure);
+        assert!(ede.is_none());
+    }
+
+    #[tokio::test]
+    async fn name_with_many_labels_below_few_delegations_is_walked() {
+        // Only the zone cuts down to the unsigned delegation are walked,
+        // not the remaining labels of the name.
+        let upstream = SignedUpstream::new(&["8.b.d.0.1.0.0.2.ip6.arpa."]);
+        let vc = ValidationContext::new(
+            upstream.trust_anchors(),
+            upstream.clone(),
+        );
+
+        let qname = Name::<Bytes>::from_str(
+            "1.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.\
+             0.0.0.0.0.0.0.0.8.b.d.0.1.0.0.2.ip6.arpa.",
+        )
+        .unwrap();
+        assert_eq!(qname.label_count(), 35);
+        let mut msg = MessageBuilder::new_bytes().question();
+        msg.header_mut().set_qr(true);
+        msg.push((&qname, Rtype::PTR)).unwrap();
+        let mut msg = msg.answer();
+        let target = Name::<Bytes>::from_str("host.example.").unwrap();
+        msg.push((&qname, Class::IN, 3600, Ptr::new(target)))
+            .unwrap();
+        let mut msg = msg.into_message();
+
+        let (state, ede) = vc.validate_msg(&mut msg).await.unwrap();
+        assert_eq!(state, ValidationState::Insecure);
+        assert!(ede.is_none());
+    }
+
+    //------------ SignedUpstream --------------------------------------------
+
+    type RrsigRecord = Record<Name<Bytes>, Rrsig<Bytes, Name<Bytes>>>;
+
+    /// An upstream serving a signed tree in which every name is a zone cut.
+    ///
+    /// All zones are signed with the same key. The delegations to the given
+    /// names are unsigned, which is proven by an NSEC record.
+    #[derive(Clone)]
+    struct SignedUpstream {
+        key: Rc<Key<'static>>,
+        insecure: Rc<HashSet<Name<Bytes>>>,
+    }
+
+    impl SignedUpstream {
+        fn new(insecure: &[&str]) -> Self {
+            MockClock::set_system_time(Duration::from_secs(1_000_000));
+            let rng = Box::leak(Box::new(SystemRandom::new()));
+            Self {
+                key: Rc::new(Key::throwaway_13(257, rng).unwrap()),
+                insecure: Rc::new(
+                    insecure
+                        .iter()
+                        .map(|name| Name::from_str(name).unwrap())
+                        .collect(),
+                ),
+            }
+        }
+
+        fn trust_anchors(&self) -> TrustAnchors {
+            let ds = self.key.ds(Name::root_ref()).unwrap();
+            let mut ta = TrustAnchors::empty();
+            ta.add_u8(format!(". 3600 IN DS {ds}").as_bytes()).unwrap();
+            ta
+        }
+
+        /// Creates a signed reply for an A query for the given name.
+        fn mk_reply(&self, qname: &str) -> Message<Bytes> {
+            let qname = Name::<Bytes>::from_str(qname).unwrap();
+            let mut msg = MessageBuilder::new_bytes().question();
+            msg.header_mut().set_qr(true);
+            msg.push((&qname, Rtype::A)).unwrap();
+            let mut msg = msg.answer();
+            let a = Record::new(
+                qname.clone(),
+                Class::IN,
+                Ttl::from_secs(3600),
+                A::from_octets(192, 0, 2, 1),
+            );
+            msg.push(a.clone()).unwrap();
+            for rrsig in self.sign(&qname, a) {
+                msg.push(rrsig).unwrap();
+            }
+            msg.into_message()
+        }
+
+        /// Creates the response to a query.
+        fn respond(
+            &self,
+            qname: Name<Bytes>,
+            qtype: Rtype,
+        ) -> Message<Bytes> {
+            let mut msg = MessageBuilder::new_bytes();
+            msg.header_mut().set_qr(true);
+            let mut msg = msg.question();
+            msg.push((&qname, qtype)).unwrap();
+
+            let Some(parent) = qname.parent() else {
+                // The root only has a DNSKEY.
+                let mut msg = msg.answer();
+                let dnskey = Record::new(
+                    qname.clone(),
+                    Class::IN,
+                    Ttl::from_secs(3600),
+                    self.key.dnskey().unwrap(),
+                );
+                msg.push(dnskey.clone()).unwrap();
+                for rrsig in self.sign(&qname, dnskey) {
+                    msg.push(rrsig).unwrap();
+                }
+                return msg.into_message();
+            };
+
+            match qtype {
+                Rtype::DS if self.insecure.contains(&qname) => {
+                    let mut types = RtypeBitmap::<Bytes>::builder();
+                    for rtype in [Rtype::NS, Rtype::RRSIG, Rtype::NSEC] {
+                        types.add(rtype).unwrap();
+                    }
+                    let nsec = Record::new(
+                        qname.clone(),
+                        Class::IN,
+                        Ttl::from_secs(3600),
+                        Nsec::new(qname.clone(), types.finalize()),
+                    );
+                    let mut msg = msg.authority();
+                    msg.push(nsec.clone()).unwrap();
+                    for rrsig in self.sign(&parent, nsec) {
+                        msg.push(rrsig).unwrap();
+                    }
+                    msg.into_message()
+                }
+                Rtype::DS => {
+                    let ds = Record::new(
+                        qname.clone(),
+                        Class::IN,
+                        Ttl::from_secs(3600),
+                        self.key.ds(&qname).unwrap(),
+                    );
+                    let mut msg = msg.answer();
+                    msg.push(ds.clone()).unwrap();
+                    for rrsig in self.sign(&parent, ds) {
+                        msg.push(rrsig).unwrap();
+                    }
+                    msg.into_message()
+                }
+                _ => {
+                    let dnskey = Record::new(
+                        qname.clone(),
+                        Class::IN,
+                        Ttl::from_secs(3600),
+                        self.key.dnskey().unwrap(),
+                    );
+                    let mut msg = msg.answer();
+                    msg.push(dnskey.clone()).unwrap();
+                    for rrsig in self.sign(&qname, dnskey) {
+                        msg.push(rrsig).unwrap();
+                    }
+                    msg.into_message()
+                }
+            }
+        }
+
+        /// Signs a record as part of the zone with the given apex.
+        fn sign<D>(
+            &self,
+            apex: &Name<Bytes>,
+            record: Record<Name<Bytes>, D>,
+        ) -> Vec<RrsigRecord>
+        where
+            D: crate::base::rdata::RecordData
+                + crate::base::rdata::ComposeRecordData
+                + crate::base::cmp::CanonicalOrd,
+        {
+            let mut records = SortedRecords::new();
+            records.insert(record).ok().unwrap();
+            records
+                .sign(
+                    &FamilyName::new(apex.clone(), Class::IN),
+                    Timestamp::from(2_000_000),
+                    Timestamp::from(0),
+                    self.key.as_ref(),
+                )
+                .unwrap()
+        }
+    }
+
+    impl SendRequest<RequestMessage<Vec<u8>>> for SignedUpstream {
+        fn send_request(
+            &self,
+            request_msg: RequestMessage<Vec<u8>>,
+        ) -> Box<dyn GetResponse + Send + Sync> {
+            let request = request_msg.to_message().unwrap();
+            let question = request.sole_question().unwrap();
+            let qname = question.qname().to_bytes();
+            Box::new(Response(Some(self.respond(qname, question.qtype()))))
+        }
+    }
+
+    #[derive(Debug)]
+    struct Response(Option<Message<Bytes>>);
+
+    impl GetResponse for Response {
+        fn get_response(
+            &mut self,
+        ) -> Pin<
+            Box<
+                dyn core::future::Future<
+                        Output = Result<Message<Bytes>, Error>,
+                    > + Send
+                    + Sync
+                    + '_,
+            >,
+        > {
+            let mut builder = MessageBuilder::new_bytes();
+            builder.header_mut().set_qr(true);
+            builder.header_mut().set_rcode(Rcode::SERVFAIL);
+            let response =
+                self.0.take().unwrap_or_else(|| builder.into_message());
+            Box::pin(ready(Ok(response)))
         }
     }
 }
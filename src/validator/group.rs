@@ -7,7 +7,8 @@
 //! signatures, sometimes there is a signature but no RRset.
 
 use super::context::{
-    Config, Error, Node, ValidationContext, ValidationState,
+    AnchorState, Config, Error, Node, QueryBudget, ValidationContext,
+    ValidationState,
 };
 use super::utilities::{make_ede, map_dname, ttl_for_sig};
 use crate::base::cmp::CanonicalOrd;
@@ -236,6 +237,8 @@ impl Group {
     pub async fn validated<Octs, Upstream>(
         &self,
         vc: &ValidationContext<Upstream>,
+        anchors: &AnchorState,
+        budget: &QueryBudget,
         config: &Config,
     ) -> Result<ValidatedGroup, Error>
     where
@@ -244,7 +247,7 @@ impl Group {
         Upstream: SendRequest<RequestMessage<Octs>>,
     {
         let (state, signer_name, wildcard, ede, adjust_ttl) =
-            self.validate_with_vc(vc, config).await?;
+            self.validate_with_vc(vc, anchors, budget, config).await?;
         Ok(ValidatedGroup::new(
             self.rr_set.clone(),
             self.sig_set.clone(),
@@ -326,6 +329,8 @@ impl Group {
     pub async fn validate_with_vc<Octs, Upstream>(
         &self,
         vc: &ValidationContext<Upstream>,
+        anchors: &AnchorState,
+        budget: &QueryBudget,
         config: &Config,
     ) -> Result<
         (
@@ -377,7 +382,7 @@ impl Group {
         } else {
             self.rr_set[0].owner()
         };
-        let node = vc.get_node(target).await?;
+        let node = vc.get_node(anchors, budget, target).await?;
         let state = node.validation_state();
         match state {
             ValidationState::Secure => (), // Continue validating
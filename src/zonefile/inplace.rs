@@ -21,7 +21,7 @@ use octseq::str::Str;
 
 use crate::base::charstr::CharStr;
 use crate::base::iana::{Class, Rtype};
-use crate::base::name::{Chain, Name, RelativeName, ToName};
+use crate::base::name::{Chain, FlattenInto, Name, RelativeName, ToName};
 use crate::base::record::Record;
 use crate::base::scan::{
     BadSymbol, ConvertSymbols, EntrySymbol, Scan, Scanner, ScannerError,
@@ -230,6 +230,126 @@ pub enum Entry {
     },
 }
 
+//------------ RecordParser --------------------------------------------------
+
+/// A parser for individual records in presentation format.
+///
+/// Where [`Zonefile`] scans a complete zonefile, this type parses a single
+/// record given as a line of presentation format, e.g. a record received via
+/// an API for provisioning a zone at runtime:
+///
+/// ```
+/// # use std::str::FromStr;
+/// # use domain::base::{Name, Rtype, Ttl};
+/// # use domain::zonefile::inplace::RecordParser;
+/// let parser = RecordParser::new()
+///     .with_origin(Name::from_str("example.com").unwrap())
+///     .with_default_ttl(Ttl::from_secs(300));
+///
+/// let record = parser.parse("www A 192.0.2.1").unwrap();
+/// assert_eq!(record.owner().to_string(), "www.example.com");
+/// assert_eq!(record.rtype(), Rtype::A);
+/// assert_eq!(record.ttl(), Ttl::from_secs(300));
+/// ```
+///
+/// Relative domain names, including an owner of `@`, are made absolute
+/// using the origin set via [`with_origin`][Self::with_origin]. Without
+/// one, they are rejected with a missing origin error. Records without a
+/// TTL receive the TTL set via [`with_default_ttl`][Self::with_default_ttl],
+/// records without a class are of class IN.
+///
+/// The text may span several lines through the use of parentheses and may
+/// contain comments but it must contain exactly one record. Directives such
+/// as `$ORIGIN` and `$TTL` are rejected, as are records without an owner
+/// name.
+#[derive(Clone, Debug)]
+pub struct RecordParser {
+    /// The origin for relative domain names.
+    origin: Option<Name<Bytes>>,
+
+    /// The TTL for records that don’t specify one.
+    default_ttl: Ttl,
+}
+
+impl RecordParser {
+    /// Creates a new parser without an origin and a default TTL of 3600.
+    pub fn new() -> Self {
+        RecordParser {
+            origin: None,
+            default_ttl: Ttl::from_secs(3600),
+        }
+    }
+
+    /// Sets the origin appended to relative domain names.
+    #[must_use]
+    pub fn with_origin(mut self, origin: Name<Bytes>) -> Self {
+        self.origin = Some(origin);
+        self
+    }
+
+    /// Sets the TTL of records that don’t specify one.
+    #[must_use]
+    pub fn with_default_ttl(mut self, ttl: Ttl) -> Self {
+        self.default_ttl = ttl;
+        self
+    }
+
+    /// Parses a single record from its presentation format.
+    ///
+    /// Returns an error if the text doesn’t contain exactly one valid
+    /// record.
+    #[allow(clippy::type_complexity)]
+    pub fn parse(
+        &self,
+        text: &str,
+    ) -> Result<Record<Name<Bytes>, ZoneRecordData<Bytes, Name<Bytes>>>, Error>
+    {
+        // The scanner expects entries to be terminated by a line feed.
+        let mut zonefile = Zonefile::with_capacity(text.len() + 1);
+        zonefile.extend_from_slice(text.as_bytes());
+        zonefile.extend_from_slice(b"\n");
+        zonefile.origin = self.origin.clone();
+        zonefile.last_ttl = self.default_ttl;
+
+        let record = loop {
+            match EntryScanner::new(&mut zonefile)?.scan_entry()? {
+                ScannedEntry::Entry(Entry::Record(record)) => break record,
+                ScannedEntry::Empty => {}
+                ScannedEntry::Eof => {
+                    return Err(zonefile
+                        .buf
+                        .error(EntryError::missing_record()))
+                }
+                _ => {
+                    return Err(zonefile
+                        .buf
+                        .error(EntryError::expected_record()))
+                }
+            }
+        };
+
+        loop {
+            match EntryScanner::new(&mut zonefile)?.scan_entry()? {
+                ScannedEntry::Empty => {}
+                ScannedEntry::Eof => break,
+                _ => {
+                    return Err(zonefile
+                        .buf
+                        .error(EntryError::trailing_entries()))
+                }
+            }
+        }
+
+        Ok(record.flatten_into())
+    }
+}
+
+impl Default for RecordParser {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 //------------ ScannedEntry --------------------------------------------------
 
 /// A raw scanned entry of a zonefile.
@@ -1456,6 +1576,18 @@ impl EntryError {
     fn unknown_control() -> Self {
         EntryError("unknown control")
     }
+
+    fn missing_record() -> Self {
+        EntryError("missing record")
+    }
+
+    fn expected_record() -> Self {
+        EntryError("expected record")
+    }
+
+    fn trailing_entries() -> Self {
+        EntryError("trailing entries")
+    }
 }
 
 impl ScannerError for EntryError {
@@ -1632,6 +1764,89 @@ mod test {
         TestCase::test(include_str!("../../test-data/zonefiles/strlen.yaml"));
     }
 
+    #[test]
+    fn parse_single_records() {
+        use crate::rdata::{Mx, Soa, Txt, A};
+
+        type Data = ZoneRecordData<Bytes, Name<Bytes>>;
+
+        let origin = Name::<Bytes>::from_str("example.com").unwrap();
+        let parser = RecordParser::new()
+            .with_origin(origin.clone())
+            .with_default_ttl(Ttl::from_secs(300));
+        let name = |s| Name::<Bytes>::from_str(s).unwrap();
+
+        // Absolute owner, explicit TTL and class.
+        let record = parser.parse("host.example.org. 60 IN A 192.0.2.1");
+        let record = record.unwrap();
+        assert_eq!(record.owner(), &name("host.example.org"));
+        assert_eq!(record.class(), Class::IN);
+        assert_eq!(record.ttl(), Ttl::from_secs(60));
+        assert_eq!(
+            record.data(),
+            &Data::A(A::from_str("192.0.2.1").unwrap())
+        );
+
+        // Relative names and the default TTL.
+        let record = parser.parse("@ MX 10 mail").unwrap();
+        assert_eq!(record.owner(), &origin);
+        assert_eq!(record.ttl(), Ttl::from_secs(300));
+        assert_eq!(
+            record.data(),
+            &Data::Mx(Mx::new(10, name("mail.example.com")))
+        );
+
+        let record = parser.parse("txt TXT \"hello world\"\n").unwrap();
+        assert_eq!(record.owner(), &name("txt.example.com"));
+        assert_eq!(
+            record.data(),
+            &Data::Txt(Txt::build_from_slice(b"hello world").unwrap())
+        );
+
+        // Multi-line records with comments.
+        let record = parser
+            .parse(
+                "@ 3600 SOA ns1 admin (\n\
+                 2024010101 ; serial\n\
+                 7200 3600 1209600 300 )",
+            )
+            .unwrap();
+        let ZoneRecordData::Soa(soa) = record.data() else {
+            panic!("expected SOA, got {:?}", record.data());
+        };
+        assert_eq!(
+            soa,
+            &Soa::new(
+                name("ns1.example.com"),
+                name("admin.example.com"),
+                2024010101.into(),
+                Ttl::from_secs(7200),
+                Ttl::from_secs(3600),
+                Ttl::from_secs(1209600),
+                Ttl::from_secs(300),
+            )
+        );
+    }
+
+    #[test]
+    fn parse_single_record_errors() {
+        let parser = RecordParser::new();
+        let err = |s| parser.parse(s).unwrap_err().err.0;
+
+        assert_eq!(err("www A 192.0.2.1"), "missing origin");
+        assert_eq!(err("@ A 192.0.2.1"), "missing origin");
+        assert_eq!(err(""), "missing record");
+        assert_eq!(err("; just a comment\n"), "missing record");
+        assert_eq!(err("$TTL 300"), "expected record");
+        assert_eq!(err("example.com. FOO 192.0.2.1"), "expected rtype");
+        assert_eq!(err("example.com. A 192.0.2"), "expected IPv4 address");
+        assert_eq!(err(" A 192.0.2.1"), "missing last owner");
+        assert_eq!(
+            err("a.example.com. A 192.0.2.1\nb.example.com. A 192.0.2.2"),
+            "trailing entries"
+        );
+    }
+
     #[test]
     #[should_panic(expected = "character string with more than 255 octets")]
     fn test_chrstr_overflow_decoding() {
@@ -3,11 +3,12 @@ use std::vec::Vec;
 
 use octseq::Octets;
 
-use crate::base::iana::Rcode;
-use crate::base::message_builder::AdditionalBuilder;
+use crate::base::iana::{Class, Rcode};
+use crate::base::message_builder::{AdditionalBuilder, AnswerBuilder};
 use crate::base::wire::Composer;
 use crate::base::MessageBuilder;
-use crate::base::{Message, Ttl};
+use crate::base::{Message, ToName, Ttl};
+use crate::rdata::ZoneRecordData;
 
 use super::types::{StoredName, StoredRecord, StoredRecordData};
 use super::{SharedRr, SharedRrset};
@@ -47,6 +48,9 @@ pub struct Answer {
 
     /// Should the answer be flagged as authoritative?
     authoritative: bool,
+
+    /// CNAME records followed to reach the name the content is for.
+    cname_chain: Vec<StoredRecord>,
 }
 
 impl Answer {
@@ -61,6 +65,7 @@ impl Answer {
             authority: Default::default(),
             additional: Default::default(),
             authoritative: false,
+            cname_chain: Vec::new(),
         }
     }
 
@@ -77,6 +82,7 @@ impl Answer {
             authority: Some(authority),
             additional: Default::default(),
             authoritative: false,
+            cname_chain: Vec::new(),
         }
     }
 
@@ -97,6 +103,20 @@ impl Answer {
         self.content = AnswerContent::Data(answer);
     }
 
+    /// Sets the CNAME records that lead from the query name to the content.
+    ///
+    /// When a CNAME chain within a zone has been followed to produce this
+    /// answer, the CNAME records visited, in order starting at the query
+    /// name, are placed in the answer section ahead of the content. The
+    /// content is then owned by the target of the last CNAME in the chain
+    /// rather than by the query name.
+    ///
+    /// The chain may also contain DNAME records, each followed by the CNAME
+    /// synthesized from it.
+    pub fn set_cname_chain(&mut self, chain: Vec<StoredRecord>) {
+        self.cname_chain = chain;
+    }
+
     /// Sets the content of the additional section.
     pub fn set_additional(&mut self, additional: AnswerAdditional) {
         self.additional = Some(additional)
@@ -130,36 +150,47 @@ impl Answer {
     ///
     /// </div>
     ///
+    /// Any push limit set on the given builder, e.g. to keep the response
+    /// within the maximum size supported by the client, is only applied to
+    /// the additional section. Discardable additional records that do not
+    /// fit are left out. If a required additional record, e.g. glue needed
+    /// to follow a referral, does not fit then it and the remaining
+    /// additional records are left out and the TC flag is set, as required
+    /// by [RFC 9471 section 3].
+    ///
     /// See also: [`MessageBuilder::start_answer`]
+    ///
+    /// [RFC 9471 section 3]: https://www.rfc-editor.org/rfc/rfc9471.html#section-3
     pub fn to_message<RequestOctets: Octets, Target: Composer>(
         &self,
         message: &Message<RequestOctets>,
-        builder: MessageBuilder<Target>,
+        mut builder: MessageBuilder<Target>,
     ) -> AdditionalBuilder<Target> {
         let question = message.sole_question().unwrap();
         let qname = question.qname();
         let qclass = question.qclass();
+        let push_limit = builder.push_limit();
+        builder.clear_push_limit();
         let mut builder = builder.start_answer(message, self.rcode).unwrap();
 
         if self.authoritative {
             builder.header_mut().set_aa(true);
         }
 
-        match self.content {
-            AnswerContent::Data(ref answer) => {
-                for item in answer.data() {
-                    // TODO: This will panic if too many answers were given,
-                    // rather than give the caller a way to push the rest into
-                    // another message.
-                    builder
-                        .push((qname, qclass, answer.ttl(), item))
-                        .unwrap();
-                }
+        for item in &self.cname_chain {
+            builder.push(item).unwrap();
+        }
+
+        let chain_target =
+            self.cname_chain.last().and_then(|rr| match rr.data() {
+                ZoneRecordData::Cname(cname) => Some(cname.cname()),
+                _ => None,
+            });
+        match chain_target {
+            Some(owner) => {
+                push_content(&mut builder, owner, qclass, &self.content)
             }
-            AnswerContent::Cname(ref cname) => builder
-                .push((qname, qclass, cname.ttl(), cname.data()))
-                .unwrap(),
-            AnswerContent::NoData => {}
+            None => push_content(&mut builder, qname, qclass, &self.content),
         }
 
         let mut builder = builder.authority();
@@ -201,17 +232,27 @@ impl Answer {
         }
 
         let mut builder = builder.additional();
+        if let Some(limit) = push_limit {
+            builder.set_push_limit(limit);
+        }
 
         if let Some(additional) = self.additional.as_ref() {
+            let mut required_fit = true;
             for item in &additional.required {
-                builder.push(item).unwrap();
-            }
-
-            for item in &additional.discardable {
                 if builder.push(item).is_err() {
+                    builder.header_mut().set_tc(true);
+                    required_fit = false;
                     break;
                 }
             }
+
+            if required_fit {
+                for item in &additional.discardable {
+                    if builder.push(item).is_err() {
+                        break;
+                    }
+                }
+            }
         }
 
         builder
@@ -233,6 +274,29 @@ impl Answer {
     }
 }
 
+/// Pushes the answer content owned by the given name to the answer section.
+fn push_content<Target: Composer>(
+    builder: &mut AnswerBuilder<Target>,
+    owner: impl ToName,
+    qclass: Class,
+    content: &AnswerContent,
+) {
+    match content {
+        AnswerContent::Data(ref answer) => {
+            for item in answer.data() {
+                // TODO: This will panic if too many answers were given,
+                // rather than give the caller a way to push the rest into
+                // another message.
+                builder.push((&owner, qclass, answer.ttl(), item)).unwrap();
+            }
+        }
+        AnswerContent::Cname(ref cname) => builder
+            .push((&owner, qclass, cname.ttl(), cname.data()))
+            .unwrap(),
+        AnswerContent::NoData => {}
+    }
+}
+
 //------------ AnswerContent -------------------------------------------------
 
 /// The content of the answer.
@@ -281,7 +345,7 @@ impl AnswerContent {
 
 //------------ AnswerAdditional ----------------------------------------------
 
-// The additional section of a query answer.
+/// The additional section of a query answer.
 #[derive(Clone, Default)]
 pub struct AnswerAdditional {
     /// Any required additional address records to include.
@@ -0,0 +1,567 @@
+//! Catalog zones.
+//!
+//! A catalog zone ([RFC 9432]) is a zone whose content is the list of member
+//! zones that the servers consuming it should serve. A primary server serves
+//! the catalog zone like any other zone and secondary servers transfer it,
+//! e.g. via AXFR, and provision or remove their member zones as the catalog
+//! changes.
+//!
+//! A catalog zone has the following structure, where `catz.example` is the
+//! apex of the catalog zone and `<unique-N>` is a label identifying a member
+//! that is unique within the catalog:
+//!
+//! ```text
+//! catz.example.                    0 SOA invalid. invalid. 1 3600 600 2147483646 0
+//! catz.example.                    0 NS  invalid.
+//! version.catz.example.            0 TXT "2"
+//! <unique-1>.zones.catz.example.   0 PTR example.com.
+//! <unique-2>.zones.catz.example.   0 PTR example.net.
+//! ```
+//!
+//! A [`CatalogZone`] holds the list of member zones of a catalog. On the
+//! producing side it can be turned into a [`Zone`] for serving via
+//! [`CatalogZone::to_zone()`]. On the consuming side a received catalog zone
+//! can be turned back into the list of member zones via
+//! [`CatalogZone::from_records()`] or [`CatalogZone::from_zone()`].
+//!
+//! Only schema version 2, the version defined by RFC 9432, and the member
+//! zone list are supported. Custom properties and the optional `group` and
+//! `coo` member properties are ignored when consuming a catalog.
+//!
+//! [RFC 9432]: https://www.rfc-editor.org/rfc/rfc9432.html
+use core::fmt;
+
+use std::boxed::Box;
+use std::sync::{Arc, Mutex};
+use std::vec::Vec;
+
+use bytes::Bytes;
+use tracing::warn;
+
+use crate::base::iana::{Class, Rtype};
+use crate::base::name::{Label, NameBuilder, OwnedLabel, PushNameError};
+use crate::base::{Serial, ToName, Ttl};
+use crate::rdata::{Ns, Ptr, Soa, Txt, ZoneRecordData};
+
+use super::error::OutOfZone;
+use super::types::{StoredName, StoredRecord};
+use super::{Rrset, Zone, ZoneBuilder};
+
+//------------ Constants -----------------------------------------------------
+
+/// The catalog zone schema version supported by this module.
+///
+/// This is the version defined by [RFC 9432 section 4.2.1].
+///
+/// [RFC 9432 section 4.2.1]:
+///     https://www.rfc-editor.org/rfc/rfc9432.html#section-4.2.1
+pub const CATALOG_VERSION: &[u8] = b"2";
+
+/// The TTL of the records of a produced catalog zone.
+///
+/// The records of a catalog zone are not meant to be looked up by resolvers
+/// so, as in the examples of RFC 9432, a TTL of zero is used.
+const CATALOG_TTL: Ttl = Ttl::ZERO;
+
+//------------ CatalogZone ---------------------------------------------------
+
+/// The list of member zones of a catalog zone.
+#[derive(Clone, Debug)]
+pub struct CatalogZone {
+    /// The apex of the catalog zone.
+    apex: StoredName,
+
+    /// The class of the catalog zone.
+    class: Class,
+
+    /// The member zones.
+    members: Vec<CatalogMember>,
+}
+
+impl CatalogZone {
+    /// Creates a new catalog zone without members.
+    #[must_use]
+    pub fn new(apex: StoredName, class: Class) -> Self {
+        Self {
+            apex,
+            class,
+            members: Vec::new(),
+        }
+    }
+
+    /// Returns the apex of the catalog zone.
+    pub fn apex(&self) -> &StoredName {
+        &self.apex
+    }
+
+    /// Returns the class of the catalog zone.
+    pub fn class(&self) -> Class {
+        self.class
+    }
+
+    /// Returns the member zones of the catalog.
+    pub fn members(&self) -> &[CatalogMember] {
+        &self.members
+    }
+
+    /// Adds a member zone to the catalog.
+    ///
+    /// The `unique_id` label identifies the member within the catalog. Per
+    /// RFC 9432 it should be unique for the member zone and stay unchanged
+    /// for as long as the zone is a member of the catalog, as changing it
+    /// signals consumers to reset the zone.
+    ///
+    /// Replaces any member with the same unique ID or the same zone name.
+    pub fn add_member(&mut self, unique_id: &Label, zone: StoredName) {
+        self.members.retain(|member| {
+            member.unique_id.as_label() != unique_id
+                && !member.zone.name_eq(&zone)
+        });
+        self.members.push(CatalogMember {
+            unique_id: OwnedLabel::from_label(unique_id),
+            zone,
+        });
+    }
+
+    /// Adds a member zone to the catalog.
+    ///
+    /// See [`add_member()`] for details.
+    ///
+    /// [`add_member()`]: Self::add_member
+    #[must_use]
+    pub fn with_member(
+        mut self,
+        unique_id: &Label,
+        zone: StoredName,
+    ) -> Self {
+        self.add_member(unique_id, zone);
+        self
+    }
+
+    /// Removes the member zone with the given name from the catalog.
+    ///
+    /// Returns whether the zone was a member.
+    pub fn remove_member(&mut self, zone: &impl ToName) -> bool {
+        let len = self.members.len();
+        self.members.retain(|member| !member.zone.name_eq(zone));
+        self.members.len() != len
+    }
+
+    /// Builds an in-memory [`Zone`] for serving this catalog.
+    ///
+    /// As required by RFC 9432 the zone has an SOA record with the given
+    /// serial, an NS record pointing to `invalid.`, a version property and a
+    /// PTR record for each member zone.
+    pub fn to_zone(&self, serial: Serial) -> Result<Zone, CatalogError> {
+        let mut builder = ZoneBuilder::new(self.apex.clone(), self.class);
+        let invalid =
+            StoredName::bytes_from_str("invalid").expect("valid name");
+
+        let mut soa = Rrset::new(Rtype::SOA, CATALOG_TTL);
+        soa.push_data(ZoneRecordData::Soa(Soa::new(
+            invalid.clone(),
+            invalid.clone(),
+            serial,
+            Ttl::from_secs(3600),
+            Ttl::from_secs(600),
+            Ttl::from_secs(2147483646),
+            Ttl::from_secs(0),
+        )));
+        builder.insert_rrset(&self.apex, soa.into_shared())?;
+
+        let mut ns = Rrset::new(Rtype::NS, CATALOG_TTL);
+        ns.push_data(ZoneRecordData::Ns(Ns::new(invalid)));
+        builder.insert_rrset(&self.apex, ns.into_shared())?;
+
+        let mut version = Rrset::new(Rtype::TXT, CATALOG_TTL);
+        version.push_data(ZoneRecordData::Txt(
+            Txt::build_from_slice(CATALOG_VERSION)
+                .expect("version fits into a TXT record"),
+        ));
+        builder.insert_rrset(&self.version_name()?, version.into_shared())?;
+
+        for member in &self.members {
+            let mut ptr = Rrset::new(Rtype::PTR, CATALOG_TTL);
+            ptr.push_data(ZoneRecordData::Ptr(Ptr::new(member.zone.clone())));
+            builder.insert_rrset(
+                &self.member_name(&member.unique_id)?,
+                ptr.into_shared(),
+            )?;
+        }
+
+        Ok(builder.build())
+    }
+
+    /// Reads the member zones from the records of a received catalog zone.
+    ///
+    /// The records can be in any order, records outside of the catalog zone
+    /// and records not describing the version or member zones are ignored.
+    ///
+    /// Fails if the catalog zone lacks a version property or has a version
+    /// other than [`CATALOG_VERSION`], in which case RFC 9432 requires that
+    /// the catalog is not processed. Members that are invalid, i.e. that
+    /// have more than one PTR record or whose zone is also listed by another
+    /// member, are skipped with a warning.
+    pub fn from_records(
+        apex: StoredName,
+        class: Class,
+        records: impl IntoIterator<Item = StoredRecord>,
+    ) -> Result<Self, CatalogError> {
+        let catalog = Self::new(apex, class);
+        let version_name = catalog.version_name()?;
+        let zones_name = catalog.zones_name()?;
+
+        let mut version = None;
+        let mut candidates: Vec<(OwnedLabel, Vec<StoredName>)> = Vec::new();
+        for record in records {
+            let owner = record.owner();
+            match record.data() {
+                ZoneRecordData::Txt(txt) if owner.name_eq(&version_name) => {
+                    if version.is_some() {
+                        return Err(CatalogError::MultipleVersions);
+                    }
+                    version = Some(txt.clone());
+                }
+                ZoneRecordData::Ptr(ptr)
+                    if owner.label_count()
+                        == zones_name.label_count() + 1
+                        && owner.ends_with(&zones_name) =>
+                {
+                    let unique_id = OwnedLabel::from_label(owner.first());
+                    let target = ptr.ptrdname().clone();
+                    match candidates
+                        .iter_mut()
+                        .find(|(id, _)| *id == unique_id)
+                    {
+                        Some((_, targets)) => targets.push(target),
+                        None => candidates.push((unique_id, vec![target])),
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        match version {
+            None => return Err(CatalogError::MissingVersion),
+            Some(txt) if txt.as_flat_slice() != Some(CATALOG_VERSION) => {
+                return Err(CatalogError::UnsupportedVersion(txt));
+            }
+            Some(_) => {}
+        }
+
+        let mut catalog = catalog;
+        for (unique_id, targets) in &candidates {
+            let [zone] = targets.as_slice() else {
+                warn!(
+                    "Ignoring catalog member {unique_id} with {} PTR records",
+                    targets.len()
+                );
+                continue;
+            };
+            let duplicates = candidates
+                .iter()
+                .filter(|(_, other)| other.iter().any(|o| o.name_eq(zone)))
+                .count();
+            if duplicates > 1 {
+                warn!(
+                    "Ignoring catalog member {unique_id}: zone {zone} is listed more than once"
+                );
+                continue;
+            }
+            catalog.members.push(CatalogMember {
+                unique_id: *unique_id,
+                zone: zone.clone(),
+            });
+        }
+
+        Ok(catalog)
+    }
+
+    /// Reads the member zones from a received catalog zone.
+    ///
+    /// See [`from_records()`] for details.
+    ///
+    /// [`from_records()`]: Self::from_records
+    pub fn from_zone(zone: &Zone) -> Result<Self, CatalogError> {
+        let records = Arc::new(Mutex::new(Vec::new()));
+        let walk_records = records.clone();
+        let class = zone.class();
+        zone.read()
+            .walk(Box::new(move |owner, rrset, _at_zone_cut| {
+                let mut records = walk_records.lock().unwrap();
+                for data in rrset.data() {
+                    records.push(StoredRecord::new(
+                        owner.clone(),
+                        class,
+                        rrset.ttl(),
+                        data.clone(),
+                    ));
+                }
+            }));
+        let records = core::mem::take(&mut *records.lock().unwrap());
+        Self::from_records(zone.apex_name().clone(), zone.class(), records)
+    }
+
+    /// Returns the owner name of the version property.
+    fn version_name(&self) -> Result<StoredName, CatalogError> {
+        self.name_below(&[b"version"])
+    }
+
+    /// Returns the name of the apex of the member zone list.
+    fn zones_name(&self) -> Result<StoredName, CatalogError> {
+        self.name_below(&[b"zones"])
+    }
+
+    /// Returns the owner name of the PTR record of a member zone.
+    fn member_name(
+        &self,
+        unique_id: &Label,
+    ) -> Result<StoredName, CatalogError> {
+        self.name_below(&[unique_id.as_slice(), b"zones"])
+    }
+
+    /// Returns the name formed by the given labels below the apex.
+    fn name_below(
+        &self,
+        labels: &[&[u8]],
+    ) -> Result<StoredName, CatalogError> {
+        let mut builder = NameBuilder::new_bytes();
+        for label in labels {
+            builder
+                .append_label(label)
+                .map_err(|_| CatalogError::LongName)?;
+        }
+        Ok(builder.append_origin(&self.apex)?)
+    }
+}
+
+//------------ CatalogMember -------------------------------------------------
+
+/// A member zone of a catalog zone.
+#[derive(Clone, Debug)]
+pub struct CatalogMember {
+    /// The label identifying the member within the catalog.
+    unique_id: OwnedLabel,
+
+    /// The name of the member zone.
+    zone: StoredName,
+}
+
+impl CatalogMember {
+    /// Returns the label identifying the member within the catalog.
+    pub fn unique_id(&self) -> &Label {
+        self.unique_id.as_label()
+    }
+
+    /// Returns the name of the member zone.
+    pub fn zone(&self) -> &StoredName {
+        &self.zone
+    }
+}
+
+//------------ CatalogError --------------------------------------------------
+
+/// A catalog zone could not be produced or consumed.
+#[derive(Clone, Debug)]
+pub enum CatalogError {
+    /// The catalog zone has no version property.
+    MissingVersion,
+
+    /// The catalog zone has more than one version property.
+    MultipleVersions,
+
+    /// The catalog zone has a schema version that is not supported.
+    UnsupportedVersion(Txt<Bytes>),
+
+    /// A name of the catalog zone would be too long.
+    LongName,
+}
+
+impl From<PushNameError> for CatalogError {
+    fn from(_: PushNameError) -> Self {
+        CatalogError::LongName
+    }
+}
+
+impl From<OutOfZone> for CatalogError {
+    fn from(_: OutOfZone) -> Self {
+        // All names are built below the apex so this cannot happen.
+        CatalogError::LongName
+    }
+}
+
+impl fmt::Display for CatalogError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CatalogError::MissingVersion => {
+                write!(f, "Catalog zone lacks a version property")
+            }
+            CatalogError::MultipleVersions => {
+                write!(f, "Catalog zone has multiple version properties")
+            }
+            CatalogError::UnsupportedVersion(version) => {
+                write!(f, "Unsupported catalog zone version {version}")
+            }
+            CatalogError::LongName => {
+                write!(f, "Catalog zone name too long")
+            }
+        }
+    }
+}
+
+//============ Tests =========================================================
+
+#[cfg(test)]
+mod tests {
+    use core::str::FromStr;
+
+    use std::string::ToString;
+    use std::vec::Vec;
+
+    use crate::base::iana::{Class, Rcode, Rtype};
+    use crate::base::name::{Label, Name};
+    use crate::base::{Serial, Ttl};
+    use crate::rdata::{Ns, Ptr, Txt, ZoneRecordData};
+    use crate::zonetree::types::StoredRecordData;
+    use crate::zonetree::{Answer, AnswerContent, StoredName, StoredRecord};
+
+    use super::{CatalogError, CatalogZone};
+
+    fn name(s: &str) -> StoredName {
+        Name::from_str(s).unwrap()
+    }
+
+    fn label(s: &str) -> &Label {
+        Label::from_slice(s.as_bytes()).unwrap()
+    }
+
+    fn catalog() -> CatalogZone {
+        CatalogZone::new(name("catz.example"), Class::IN)
+            .with_member(label("m1"), name("example.com"))
+            .with_member(label("m2"), name("example.net"))
+    }
+
+    fn answer_data(answer: &Answer) -> StoredRecordData {
+        let AnswerContent::Data(rrset) = answer.content() else {
+            panic!("expected data");
+        };
+        assert_eq!(rrset.data().len(), 1);
+        rrset.data()[0].clone()
+    }
+
+    fn txt(content: &[u8]) -> StoredRecordData {
+        ZoneRecordData::Txt(Txt::build_from_slice(content).unwrap())
+    }
+
+    #[test]
+    fn serves_catalog_structure() {
+        let zone = catalog().to_zone(Serial(1)).unwrap();
+        let read = zone.read();
+
+        let answer = read.query(name("catz.example"), Rtype::SOA).unwrap();
+        assert_eq!(answer.rcode(), Rcode::NOERROR);
+        let ZoneRecordData::Soa(soa) = answer_data(&answer) else {
+            panic!("expected SOA");
+        };
+        assert_eq!(soa.serial(), Serial(1));
+
+        let answer = read.query(name("catz.example"), Rtype::NS).unwrap();
+        assert_eq!(
+            answer_data(&answer),
+            StoredRecordData::Ns(Ns::new(name("invalid")))
+        );
+
+        let answer = read
+            .query(name("version.catz.example"), Rtype::TXT)
+            .unwrap();
+        assert_eq!(answer_data(&answer), txt(b"2"));
+
+        let answer = read
+            .query(name("m1.zones.catz.example"), Rtype::PTR)
+            .unwrap();
+        assert_eq!(
+            answer_data(&answer),
+            StoredRecordData::Ptr(Ptr::new(name("example.com")))
+        );
+
+        let answer = read
+            .query(name("m3.zones.catz.example"), Rtype::PTR)
+            .unwrap();
+        assert_eq!(answer.rcode(), Rcode::NXDOMAIN);
+    }
+
+    #[test]
+    fn consumes_served_catalog() {
+        let zone = catalog().to_zone(Serial(1)).unwrap();
+        let consumed = CatalogZone::from_zone(&zone).unwrap();
+        let mut members: Vec<_> = consumed
+            .members()
+            .iter()
+            .map(|m| (m.unique_id().to_string(), m.zone().to_string()))
+            .collect();
+        members.sort();
+        assert_eq!(
+            members,
+            [
+                ("m1".into(), "example.com".into()),
+                ("m2".into(), "example.net".into())
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_unsupported_version() {
+        let records = [StoredRecord::new(
+            name("version.catz.example"),
+            Class::IN,
+            Ttl::ZERO,
+            txt(b"1"),
+        )];
+        let res = CatalogZone::from_records(
+            name("catz.example"),
+            Class::IN,
+            records,
+        );
+        assert!(matches!(res, Err(CatalogError::UnsupportedVersion(_))));
+
+        let res =
+            CatalogZone::from_records(name("catz.example"), Class::IN, []);
+        assert!(matches!(res, Err(CatalogError::MissingVersion)));
+    }
+
+    #[test]
+    fn skips_invalid_members() {
+        let ptr = |owner: &str, target: &str| {
+            StoredRecord::new(
+                name(owner),
+                Class::IN,
+                Ttl::ZERO,
+                ZoneRecordData::Ptr(Ptr::new(name(target))),
+            )
+        };
+        let records = [
+            StoredRecord::new(
+                name("version.catz.example"),
+                Class::IN,
+                Ttl::ZERO,
+                txt(b"2"),
+            ),
+            ptr("ok.zones.catz.example", "example.com"),
+            ptr("two.zones.catz.example", "example.net"),
+            ptr("two.zones.catz.example", "example.org"),
+            ptr("dup1.zones.catz.example", "example.info"),
+            ptr("dup2.zones.catz.example", "EXAMPLE.info"),
+            // A property below a member is not a member.
+            ptr("coo.ok.zones.catz.example", "other.example"),
+        ];
+        let catalog = CatalogZone::from_records(
+            name("catz.example"),
+            Class::IN,
+            records,
+        )
+        .unwrap();
+        assert_eq!(catalog.members().len(), 1);
+        assert_eq!(catalog.members()[0].unique_id(), label("ok"));
+        assert_eq!(catalog.members()[0].zone(), &name("example.com"));
+    }
+}
@@ -8,6 +8,7 @@ use bytes::Bytes;
 use crate::base::iana::{Rcode, Rtype};
 use crate::base::name::Label;
 use crate::base::Name;
+use crate::rdata::ZoneRecordData;
 use crate::zonetree::answer::{Answer, AnswerAdditional, AnswerAuthority};
 use crate::zonetree::error::OutOfZone;
 use crate::zonetree::types::ZoneCut;
@@ -106,7 +107,7 @@ impl ReadZone {
                             Some(cut.ns.clone()),
                             cut.ds.as_ref().cloned(),
                         ),
-                        AnswerAdditional::new(cut.glue.clone()),
+                        referral_glue(cut),
                     )
                 }
             }
@@ -224,7 +225,7 @@ impl ReadZone {
                     Some(cut.ns.clone()),
                     cut.ds.as_ref().cloned(),
                 ),
-                AnswerAdditional::new(cut.glue.clone()),
+                referral_glue(cut),
             ),
         }
     }
@@ -313,6 +314,35 @@ impl ReadableZone for ReadZone {
     }
 }
 
+//------------ Helper functions ----------------------------------------------
+
+/// Selects the glue to include in a referral at the given zone cut.
+///
+/// Only address records for the names of the delegated nameservers that lie
+/// within the delegated zone itself are included. Resolvers cannot find the
+/// addresses of such nameservers without this glue, whereas the addresses
+/// of any other nameservers can be resolved independently.
+///
+/// See [RFC 9471 section 2.1].
+///
+/// [RFC 9471 section 2.1]:
+///     https://www.rfc-editor.org/rfc/rfc9471.html#section-2.1
+fn referral_glue(cut: &ZoneCut) -> AnswerAdditional {
+    let glue = cut
+        .glue
+        .iter()
+        .filter(|rr| {
+            rr.rtype().is_glue()
+                && rr.owner().ends_with(&cut.name)
+                && cut.ns.data().iter().any(|data| {
+                    matches!(data, ZoneRecordData::Ns(ns) if ns.nsdname() == rr.owner())
+                })
+        })
+        .cloned()
+        .collect();
+    AnswerAdditional::new(glue)
+}
+
 //------------ NodeAnswer ----------------------------------------------------
 
 /// An answer that includes instructions to the apex on what it needs to do.
@@ -387,6 +417,13 @@ impl NodeAnswer {
     fn into_answer(mut self, zone: &ReadZone) -> Answer {
         if self.add_soa {
             if let Some(soa) = zone.apex.get_soa(zone.version) {
+                let soa = match soa.data() {
+                    ZoneRecordData::Soa(data) => SharedRr::new(
+                        data.negative_ttl(soa.ttl()),
+                        soa.data().clone(),
+                    ),
+                    _ => soa,
+                };
                 self.answer.set_authority(AnswerAuthority::new(
                     zone.apex.name().clone(),
                     Some(soa),
@@ -102,6 +102,7 @@
 //! [`ZoneUpdater`]: update::ZoneUpdater
 
 mod answer;
+pub mod catalog;
 pub mod error;
 mod in_memory;
 pub mod parsed;
@@ -112,7 +113,9 @@ pub mod update;
 mod walk;
 mod zone;
 
-pub use self::answer::{Answer, AnswerAuthority, AnswerContent};
+pub use self::answer::{
+    Answer, AnswerAdditional, AnswerAuthority, AnswerContent,
+};
 pub use self::in_memory::ZoneBuilder;
 pub use self::traits::{
     ReadableZone, WritableZone, WritableZoneNode, ZoneDiff, ZoneDiffItem,
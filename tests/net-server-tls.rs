@@ -0,0 +1,190 @@
+#![cfg(feature = "tls")]
+
+use std::fs::File;
+use std::io::BufReader;
+use std::sync::Arc;
+use std::time::Duration;
+
+use domain::base::iana::Rcode;
+use domain::base::{Message, MessageBuilder, Name, Rtype};
+use domain::net::server::buf::VecBufSource;
+use domain::net::server::message::Request;
+use domain::net::server::service::{CallResult, ServiceResult};
+use domain::net::server::sock::RustlsTcpListener;
+use domain::net::server::stream::{Config, StreamServer};
+use domain::net::server::util::{mk_builder_for_target, service_fn};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::timeout;
+use tokio_rustls::rustls::pki_types::ServerName;
+use tokio_rustls::rustls::{ClientConfig, RootCertStore, ServerConfig};
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+fn my_service(req: Request<Vec<u8>>, _meta: ()) -> ServiceResult<Vec<u8>> {
+    let builder = mk_builder_for_target();
+    let answer = builder.start_answer(req.message(), Rcode::NOERROR)?;
+    Ok(CallResult::new(answer.additional()))
+}
+
+#[tokio::test]
+async fn tls_round_trip() {
+    let (server_config, client_config) = tls_configs();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let srv_addr = listener.local_addr().unwrap();
+    let listener = RustlsTcpListener::new(
+        listener,
+        TlsAcceptor::from(Arc::new(server_config)),
+    );
+    let srv = Arc::new(StreamServer::new(
+        listener,
+        VecBufSource::default(),
+        service_fn(my_service, ()),
+    ));
+    let srv_task = tokio::spawn({
+        let srv = srv.clone();
+        async move { srv.run().await }
+    });
+
+    let connector = TlsConnector::from(Arc::new(client_config));
+    let stream = TcpStream::connect(srv_addr).await.unwrap();
+    let server_name = ServerName::try_from("localhost").unwrap();
+    let mut stream = timeout(
+        Duration::from_secs(5),
+        connector.connect(server_name, stream),
+    )
+    .await
+    .unwrap()
+    .unwrap();
+
+    let mut query = MessageBuilder::new_stream_vec();
+    query.header_mut().set_id(4711);
+    let mut query = query.question();
+    query.push((Name::root_ref(), Rtype::A)).unwrap();
+    stream
+        .write_all(query.finish().as_stream_slice())
+        .await
+        .unwrap();
+
+    let len = timeout(Duration::from_secs(5), stream.read_u16())
+        .await
+        .unwrap()
+        .unwrap();
+    let mut buf = vec![0; len.into()];
+    stream.read_exact(&mut buf).await.unwrap();
+    let response = Message::from_octets(buf).unwrap();
+    assert_eq!(response.header().id(), 4711);
+    assert_eq!(response.header().rcode(), Rcode::NOERROR);
+
+    srv.shutdown().unwrap();
+    timeout(Duration::from_secs(5), srv_task)
+        .await
+        .unwrap()
+        .unwrap();
+}
+
+#[tokio::test]
+async fn stalled_handshake_is_closed() {
+    let (server_config, _) = tls_configs();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let srv_addr = listener.local_addr().unwrap();
+    let listener = RustlsTcpListener::new(
+        listener,
+        TlsAcceptor::from(Arc::new(server_config)),
+    )
+    .with_handshake_timeout(Duration::from_millis(100));
+    let srv = Arc::new(StreamServer::new(
+        listener,
+        VecBufSource::default(),
+        service_fn(my_service, ()),
+    ));
+    let srv_task = tokio::spawn({
+        let srv = srv.clone();
+        async move { srv.run().await }
+    });
+
+    // Connect but never start the handshake. The server gives up on the
+    // connection and closes it.
+    let mut stream = TcpStream::connect(srv_addr).await.unwrap();
+    let mut buf = [0; 1];
+    let read = timeout(Duration::from_secs(5), stream.read(&mut buf))
+        .await
+        .unwrap();
+    assert!(matches!(read, Ok(0) | Err(_)));
+
+    srv.shutdown().unwrap();
+    timeout(Duration::from_secs(5), srv_task)
+        .await
+        .unwrap()
+        .unwrap();
+}
+
+#[tokio::test]
+async fn pending_handshakes_count_towards_connection_limit() {
+    let (server_config, client_config) = tls_configs();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let srv_addr = listener.local_addr().unwrap();
+    let listener = RustlsTcpListener::new(
+        listener,
+        TlsAcceptor::from(Arc::new(server_config)),
+    );
+    let mut config = Config::new();
+    config.set_max_concurrent_connections(1);
+    let srv = Arc::new(StreamServer::with_config(
+        listener,
+        VecBufSource::default(),
+        service_fn(my_service, ()),
+        config,
+    ));
+    let srv_task = tokio::spawn({
+        let srv = srv.clone();
+        async move { srv.run().await }
+    });
+
+    // Occupy the only connection slot with a handshake that never starts.
+    let _stalled = TcpStream::connect(srv_addr).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    // Further connections are closed without completing a handshake.
+    let connector = TlsConnector::from(Arc::new(client_config));
+    let stream = TcpStream::connect(srv_addr).await.unwrap();
+    let server_name = ServerName::try_from("localhost").unwrap();
+    let res = timeout(
+        Duration::from_secs(5),
+        connector.connect(server_name, stream),
+    )
+    .await
+    .unwrap();
+    assert!(res.is_err());
+
+    srv.shutdown().unwrap();
+    timeout(Duration::from_secs(5), srv_task)
+        .await
+        .unwrap()
+        .unwrap();
+}
+
+/// Returns server and client configs using the sample certificate.
+fn tls_configs() -> (ServerConfig, ClientConfig) {
+    // The sample certificate chain ends in a self-signed test CA.
+    let certs = rustls_pemfile::certs(&mut BufReader::new(
+        File::open("examples/sample.pem").unwrap(),
+    ))
+    .collect::<Result<Vec<_>, _>>()
+    .unwrap();
+    let key = rustls_pemfile::private_key(&mut BufReader::new(
+        File::open("examples/sample.rsa").unwrap(),
+    ))
+    .unwrap()
+    .unwrap();
+    let mut roots = RootCertStore::empty();
+    roots.add(certs.last().unwrap().clone()).unwrap();
+
+    let server_config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .unwrap();
+    let client_config = ClientConfig::builder()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    (server_config, client_config)
+}
@@ -0,0 +1,75 @@
+#![cfg(all(unix, feature = "unstable-server-transport"))]
+
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use domain::base::iana::Rcode;
+use domain::base::{Message, MessageBuilder, Name, Rtype};
+use domain::net::server::buf::VecBufSource;
+use domain::net::server::dgram::UnixDgramServer;
+use domain::net::server::message::Request;
+use domain::net::server::service::{CallResult, ServiceResult};
+use domain::net::server::sock::UnixDgramSock;
+use domain::net::server::util::{mk_builder_for_target, service_fn};
+use tokio::net::UnixDatagram;
+use tokio::time::timeout;
+
+fn my_service(req: Request<Vec<u8>>, _meta: ()) -> ServiceResult<Vec<u8>> {
+    let builder = mk_builder_for_target();
+    let answer = builder.start_answer(req.message(), Rcode::NOERROR)?;
+    Ok(CallResult::new(answer.additional()))
+}
+
+/// Returns a socket path in the temporary directory unique to this test.
+fn socket_path(name: &str) -> PathBuf {
+    let path = std::env::temp_dir()
+        .join(format!("domain-{}-{name}.sock", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    path
+}
+
+#[tokio::test]
+async fn unix_dgram_round_trip() {
+    let srv_path = socket_path("server");
+    let client_path = socket_path("client");
+
+    let sock = UnixDgramSock::bind(&srv_path).unwrap();
+    let srv = Arc::new(UnixDgramServer::new(
+        sock,
+        VecBufSource::default(),
+        service_fn(my_service, ()),
+    ));
+    let srv_task = tokio::spawn({
+        let srv = srv.clone();
+        async move { srv.run().await }
+    });
+
+    let client = UnixDatagram::bind(&client_path).unwrap();
+    client.connect(&srv_path).unwrap();
+    let mut query = MessageBuilder::new_vec();
+    query.header_mut().set_id(4711);
+    let mut query = query.question();
+    query.push((Name::root_ref(), Rtype::A)).unwrap();
+    client.send(&query.finish()).await.unwrap();
+
+    let mut buf = vec![0; 512];
+    let len = timeout(Duration::from_secs(5), client.recv(&mut buf))
+        .await
+        .unwrap()
+        .unwrap();
+    let response = Message::from_octets(&buf[..len]).unwrap();
+    assert!(response.header().qr());
+    assert_eq!(response.header().id(), 4711);
+    assert_eq!(response.header().rcode(), Rcode::NOERROR);
+
+    srv.shutdown().unwrap();
+    timeout(Duration::from_secs(5), srv_task)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(srv.metrics().num_sent_responses(), 1);
+
+    let _ = std::fs::remove_file(&srv_path);
+    let _ = std::fs::remove_file(&client_path);
+}